@@ -10,18 +10,23 @@
 //! Common traits and structs for vhost-kern and vhost-user backend drivers.
 
 use std::cell::RefCell;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsFd, BorrowedFd};
 use std::sync::RwLock;
 
+use vm_memory::{Address, GuestMemoryRegion, MemoryRegionAddress};
 use vmm_sys_util::eventfd::EventFd;
 
-use super::Result;
+use super::{Error, Result};
 
 /// Maximum number of memory regions supported.
 pub const VHOST_MAX_MEMORY_REGIONS: usize = 255;
 
+// Vring flag to enable logging of writes to the used ring.
+const VRING_F_LOG: u32 = 0x1;
+
 /// Vring configuration data.
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VringConfigData {
     /// Maximum queue size supported by the driver.
     pub queue_max_size: u16,
@@ -42,7 +47,7 @@ pub struct VringConfigData {
 impl VringConfigData {
     /// Check whether the log (flag, address) pair is valid.
     pub fn is_log_addr_valid(&self) -> bool {
-        if self.flags & 0x1 != 0 && self.log_addr.is_none() {
+        if self.flags & VRING_F_LOG != 0 && self.log_addr.is_none() {
             return false;
         }
 
@@ -51,17 +56,37 @@ impl VringConfigData {
 
     /// Get the log address, default to zero if not available.
     pub fn get_log_addr(&self) -> u64 {
-        if self.flags & 0x1 != 0 && self.log_addr.is_some() {
+        if self.flags & VRING_F_LOG != 0 && self.log_addr.is_some() {
             self.log_addr.unwrap()
         } else {
             0
         }
     }
+
+    /// Enable dirty page logging for the vring.
+    ///
+    /// The backend only writes to the used ring, so the log address is the address of the used
+    /// ring, used to locate its pages in the dirty page log.
+    ///
+    /// # Arguments
+    /// * `used_ring_gpa` - Guest physical address of the used ring. Defaults to
+    ///   `used_ring_addr`, which is only correct when the ring addresses are guest physical
+    ///   addresses rather than userspace addresses.
+    pub fn enable_log(&mut self, used_ring_gpa: Option<u64>) {
+        self.flags |= VRING_F_LOG;
+        self.log_addr = Some(used_ring_gpa.unwrap_or(self.used_ring_addr));
+    }
+
+    /// Disable dirty page logging for the vring.
+    pub fn disable_log(&mut self) {
+        self.flags &= !VRING_F_LOG;
+        self.log_addr = None;
+    }
 }
 
 /// Memory region configuration data.
-#[derive(Default, Clone, Copy)]
-pub struct VhostUserMemoryRegionInfo {
+#[derive(Default, Clone, Copy, Debug)]
+pub struct VhostUserMemoryRegionInfo<'a> {
     /// Guest physical address of the memory region.
     pub guest_phys_addr: u64,
     /// Size of the memory region.
@@ -71,18 +96,71 @@ pub struct VhostUserMemoryRegionInfo {
     /// Optional offset where region starts in the mapped memory.
     pub mmap_offset: u64,
     /// Optional file descriptor for mmap.
-    pub mmap_handle: RawFd,
+    pub mmap_handle: Option<BorrowedFd<'a>>,
+    /// Xen specific flags describing how the region must be mapped.
+    #[cfg(feature = "xen")]
+    pub xen_mmap_flags: u32,
+    /// Xen domain id owning the memory region.
+    #[cfg(feature = "xen")]
+    pub xen_domid: u32,
+}
+
+impl<'a> VhostUserMemoryRegionInfo<'a> {
+    /// Create a new instance.
+    pub fn new(
+        guest_phys_addr: u64,
+        memory_size: u64,
+        userspace_addr: u64,
+        mmap_offset: u64,
+        mmap_handle: Option<BorrowedFd<'a>>,
+    ) -> Self {
+        VhostUserMemoryRegionInfo {
+            guest_phys_addr,
+            memory_size,
+            userspace_addr,
+            mmap_offset,
+            mmap_handle,
+            #[cfg(feature = "xen")]
+            xen_mmap_flags: 0,
+            #[cfg(feature = "xen")]
+            xen_domid: 0,
+        }
+    }
+
+    /// Describe a file backed guest memory region, so it can be shared with a vhost-user slave.
+    ///
+    /// The file descriptor of the region stays owned by `region`, which the returned descriptor
+    /// borrows.
+    ///
+    /// # Return:
+    /// * - `Error::InvalidGuestMemoryRegion`: the region isn't backed by a file or isn't mapped.
+    pub fn from_guest_region<R: GuestMemoryRegion>(region: &'a R) -> Result<Self> {
+        let file_offset = region
+            .file_offset()
+            .ok_or(Error::InvalidGuestMemoryRegion)?;
+        let userspace_addr = region
+            .get_host_address(MemoryRegionAddress(0))
+            .map_err(|_| Error::InvalidGuestMemoryRegion)?;
+
+        Ok(VhostUserMemoryRegionInfo::new(
+            region.start_addr().raw_value(),
+            region.len(),
+            userspace_addr as u64,
+            file_offset.start(),
+            Some(file_offset.file().as_fd()),
+        ))
+    }
 }
 
 /// Shared memory region data for logging dirty pages
-#[derive(Default, Clone, Copy)]
-pub struct VhostUserDirtyLogRegion {
+#[derive(Clone, Copy)]
+pub struct VhostUserDirtyLogRegion<'a> {
     /// Size of the shared memory region for logging dirty pages
     pub mmap_size: u64,
     /// Offset where region starts
     pub mmap_offset: u64,
     /// File descriptor for mmap
-    pub mmap_handle: RawFd,
+    pub mmap_handle: BorrowedFd<'a>,
 }
 
 /// An interface for setting up vhost-based backend drivers with interior mutability.
@@ -96,7 +174,23 @@ pub struct VhostUserDirtyLogRegion {
 /// VMM process. Typically fast paths for IO operations are delegated to the dedicated IO service
 /// processes, and slow path for device configuration are still handled by the VMM process. It may
 /// also be used to control access permissions of virtio backend devices.
-pub trait VhostBackend: std::marker::Sized {
+///
+/// The operations are grouped into the [`VhostFeatureOps`], [`VhostMemOps`], [`VhostVringOps`]
+/// and [`VhostLogOps`] capability traits, so a backend only supporting a subset of them may
+/// implement just those. `VhostBackend` is implemented automatically for any type implementing
+/// all of them.
+pub trait VhostBackend:
+    VhostFeatureOps + VhostMemOps + VhostVringOps + VhostLogOps + std::marker::Sized
+{
+}
+
+impl<T> VhostBackend for T where
+    T: VhostFeatureOps + VhostMemOps + VhostVringOps + VhostLogOps + std::marker::Sized
+{
+}
+
+/// Feature negotiation and ownership operations of a vhost backend.
+pub trait VhostFeatureOps {
     /// Get a bitmask of supported virtio/vhost features.
     fn get_features(&self) -> Result<u64>;
 
@@ -107,6 +201,22 @@ pub trait VhostBackend: std::marker::Sized {
     /// * `features` - Bitmask of features to set.
     fn set_features(&self, features: u64) -> Result<()>;
 
+    /// Inform the vhost subsystem which features to enable, after checking that all of them
+    /// are supported.
+    ///
+    /// Returns `Error::UnsupportedFeatures` with the bits not offered by get_features() instead
+    /// of letting the backend reject the request without telling which feature is the problem.
+    ///
+    /// # Arguments
+    /// * `features` - Bitmask of features to set.
+    fn set_features_checked(&self, features: u64) -> Result<()> {
+        let unsupported = features & !self.get_features()?;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFeatures(unsupported));
+        }
+        self.set_features(features)
+    }
+
     /// Set the current process as the owner of the vhost backend.
     /// This must be run before any other vhost commands.
     fn set_owner(&self) -> Result<()>;
@@ -114,16 +224,25 @@ pub trait VhostBackend: std::marker::Sized {
     /// Used to be sent to request disabling all rings
     /// This is no longer used.
     fn reset_owner(&self) -> Result<()>;
+}
 
+/// Guest memory table operations of a vhost backend.
+pub trait VhostMemOps {
     /// Set the guest memory mappings for vhost to use.
     fn set_mem_table(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()>;
+}
 
+/// Dirty page logging operations of a vhost backend.
+pub trait VhostLogOps {
     /// Set base address for page modification logging.
     fn set_log_base(&self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()>;
 
     /// Specify an eventfd file descriptor to signal on log write.
-    fn set_log_fd(&self, fd: RawFd) -> Result<()>;
+    fn set_log_fd(&self, fd: BorrowedFd) -> Result<()>;
+}
 
+/// Per-vring operations of a vhost backend.
+pub trait VhostVringOps {
     /// Set the number of descriptors in the vring.
     ///
     /// # Arguments
@@ -152,23 +271,79 @@ pub trait VhostBackend: std::marker::Sized {
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd to trigger.
-    fn set_vring_call(&self, queue_index: usize, fd: &EventFd) -> Result<()>;
+    /// * `fd` - EventFd to trigger, or `None` to stop signaling used buffers.
+    fn set_vring_call(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()>;
 
     /// Set the eventfd that will be signaled by the guest when buffers are
     /// available for the host to process.
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from guest.
-    fn set_vring_kick(&self, queue_index: usize, fd: &EventFd) -> Result<()>;
+    /// * `fd` - EventFd that will be signaled from guest, or `None` to poll the vring instead.
+    fn set_vring_kick(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()>;
 
     /// Set the eventfd that will be signaled by the guest when error happens.
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from guest.
-    fn set_vring_err(&self, queue_index: usize, fd: &EventFd) -> Result<()>;
+    /// * `fd` - EventFd that will be signaled from guest, or `None` to stop reporting errors.
+    fn set_vring_err(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()>;
+}
+
+/// Everything needed to configure a vring with [`VhostDevice::setup_vring()`].
+pub struct VringSetup<'a> {
+    /// Size, addresses and optional log address of the vring.
+    pub config: VringConfigData,
+    /// Index of the first available descriptor.
+    pub base: u16,
+    /// EventFd that will be signaled by the guest when buffers are available.
+    pub kick: &'a EventFd,
+    /// EventFd to trigger when buffers have been used by the host.
+    pub call: &'a EventFd,
+}
+
+/// A vhost device, independently of the backend accelerating it.
+///
+/// Unlike [`VhostBackend`], this trait is object safe, so a device model may hold a
+/// `Box<dyn VhostDevice>` and be written once for the in-kernel and the vhost-user backends,
+/// picking one of them at runtime. It is implemented by the vhost-user `Master` and by every
+/// in-kernel backend.
+pub trait VhostDevice: VhostFeatureOps + VhostMemOps + VhostVringOps + VhostLogOps {
+    /// Set the status of the virtio device.
+    ///
+    /// Backends which don't track the device status fail with `Error::InvalidOperation`.
+    fn set_status(&self, _status: u8) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+
+    /// Get the status of the virtio device.
+    ///
+    /// Backends which don't track the device status fail with `Error::InvalidOperation`.
+    fn get_status(&self) -> Result<u8> {
+        Err(Error::InvalidOperation)
+    }
+
+    /// Configure a vring with a single call.
+    ///
+    /// Set the size, base and addresses of the vring, then its call and kick eventfds, in order.
+    /// If setting the kick eventfd fails, the call eventfd is unbound again before returning the
+    /// error.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to configure.
+    /// * `setup` - Configuration of the vring.
+    fn setup_vring(&self, queue_index: usize, setup: &VringSetup) -> Result<()> {
+        self.set_vring_num(queue_index, setup.config.queue_size)?;
+        self.set_vring_base(queue_index, setup.base)?;
+        self.set_vring_addr(queue_index, &setup.config)?;
+        self.set_vring_call(queue_index, Some(setup.call))?;
+        if let Err(e) = self.set_vring_kick(queue_index, Some(setup.kick)) {
+            let _ = self.set_vring_call(queue_index, None);
+            return Err(e);
+        }
+
+        Ok(())
+    }
 }
 
 /// An interface for setting up vhost-based backend drivers.
@@ -208,7 +383,7 @@ pub trait VhostBackendMut: std::marker::Sized {
     fn set_log_base(&mut self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()>;
 
     /// Specify an eventfd file descriptor to signal on log write.
-    fn set_log_fd(&mut self, fd: RawFd) -> Result<()>;
+    fn set_log_fd(&mut self, fd: BorrowedFd) -> Result<()>;
 
     /// Set the number of descriptors in the vring.
     ///
@@ -238,26 +413,26 @@ pub trait VhostBackendMut: std::marker::Sized {
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd to trigger.
-    fn set_vring_call(&mut self, queue_index: usize, fd: &EventFd) -> Result<()>;
+    /// * `fd` - EventFd to trigger, or `None` to stop signaling used buffers.
+    fn set_vring_call(&mut self, queue_index: usize, fd: Option<&EventFd>) -> Result<()>;
 
     /// Set the eventfd that will be signaled by the guest when buffers are
     /// available for the host to process.
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from guest.
-    fn set_vring_kick(&mut self, queue_index: usize, fd: &EventFd) -> Result<()>;
+    /// * `fd` - EventFd that will be signaled from guest, or `None` to poll the vring instead.
+    fn set_vring_kick(&mut self, queue_index: usize, fd: Option<&EventFd>) -> Result<()>;
 
     /// Set the eventfd that will be signaled by the guest when error happens.
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from guest.
-    fn set_vring_err(&mut self, queue_index: usize, fd: &EventFd) -> Result<()>;
+    /// * `fd` - EventFd that will be signaled from guest, or `None` to stop reporting errors.
+    fn set_vring_err(&mut self, queue_index: usize, fd: Option<&EventFd>) -> Result<()>;
 }
 
-impl<T: VhostBackendMut> VhostBackend for RwLock<T> {
+impl<T: VhostBackendMut> VhostFeatureOps for RwLock<T> {
     fn get_features(&self) -> Result<u64> {
         self.write().unwrap().get_features()
     }
@@ -273,19 +448,25 @@ impl<T: VhostBackendMut> VhostBackend for RwLock<T> {
     fn reset_owner(&self) -> Result<()> {
         self.write().unwrap().reset_owner()
     }
+}
 
+impl<T: VhostBackendMut> VhostMemOps for RwLock<T> {
     fn set_mem_table(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
         self.write().unwrap().set_mem_table(regions)
     }
+}
 
+impl<T: VhostBackendMut> VhostLogOps for RwLock<T> {
     fn set_log_base(&self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
         self.write().unwrap().set_log_base(base, region)
     }
 
-    fn set_log_fd(&self, fd: RawFd) -> Result<()> {
+    fn set_log_fd(&self, fd: BorrowedFd) -> Result<()> {
         self.write().unwrap().set_log_fd(fd)
     }
+}
 
+impl<T: VhostBackendMut> VhostVringOps for RwLock<T> {
     fn set_vring_num(&self, queue_index: usize, num: u16) -> Result<()> {
         self.write().unwrap().set_vring_num(queue_index, num)
     }
@@ -304,20 +485,20 @@ impl<T: VhostBackendMut> VhostBackend for RwLock<T> {
         self.write().unwrap().get_vring_base(queue_index)
     }
 
-    fn set_vring_call(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    fn set_vring_call(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         self.write().unwrap().set_vring_call(queue_index, fd)
     }
 
-    fn set_vring_kick(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    fn set_vring_kick(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         self.write().unwrap().set_vring_kick(queue_index, fd)
     }
 
-    fn set_vring_err(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    fn set_vring_err(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         self.write().unwrap().set_vring_err(queue_index, fd)
     }
 }
 
-impl<T: VhostBackendMut> VhostBackend for RefCell<T> {
+impl<T: VhostBackendMut> VhostFeatureOps for RefCell<T> {
     fn get_features(&self) -> Result<u64> {
         self.borrow_mut().get_features()
     }
@@ -333,19 +514,25 @@ impl<T: VhostBackendMut> VhostBackend for RefCell<T> {
     fn reset_owner(&self) -> Result<()> {
         self.borrow_mut().reset_owner()
     }
+}
 
+impl<T: VhostBackendMut> VhostMemOps for RefCell<T> {
     fn set_mem_table(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
         self.borrow_mut().set_mem_table(regions)
     }
+}
 
+impl<T: VhostBackendMut> VhostLogOps for RefCell<T> {
     fn set_log_base(&self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
         self.borrow_mut().set_log_base(base, region)
     }
 
-    fn set_log_fd(&self, fd: RawFd) -> Result<()> {
+    fn set_log_fd(&self, fd: BorrowedFd) -> Result<()> {
         self.borrow_mut().set_log_fd(fd)
     }
+}
 
+impl<T: VhostBackendMut> VhostVringOps for RefCell<T> {
     fn set_vring_num(&self, queue_index: usize, num: u16) -> Result<()> {
         self.borrow_mut().set_vring_num(queue_index, num)
     }
@@ -362,23 +549,31 @@ impl<T: VhostBackendMut> VhostBackend for RefCell<T> {
         self.borrow_mut().get_vring_base(queue_index)
     }
 
-    fn set_vring_call(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    fn set_vring_call(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         self.borrow_mut().set_vring_call(queue_index, fd)
     }
 
-    fn set_vring_kick(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    fn set_vring_kick(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         self.borrow_mut().set_vring_kick(queue_index, fd)
     }
 
-    fn set_vring_err(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    fn set_vring_err(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         self.borrow_mut().set_vring_err(queue_index, fd)
     }
 }
+impl<T: VhostBackendMut> VhostDevice for RwLock<T> {}
+
+impl<T: VhostBackendMut> VhostDevice for RefCell<T> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::io::{AsRawFd, RawFd};
 
-    struct MockBackend {}
+    struct MockBackend {
+        // Raw fd of the log passed in by the test.
+        log_fd: RawFd,
+    }
 
     impl VhostBackendMut for MockBackend {
         fn get_features(&mut self) -> Result<u64> {
@@ -411,12 +606,12 @@ mod tests {
             let region = region.unwrap();
             assert_eq!(region.mmap_size, 0x1000);
             assert_eq!(region.mmap_offset, 0x10);
-            assert_eq!(region.mmap_handle, 100);
+            assert_eq!(region.mmap_handle.as_raw_fd(), self.log_fd);
             Ok(())
         }
 
-        fn set_log_fd(&mut self, fd: RawFd) -> Result<()> {
-            assert_eq!(fd, 100);
+        fn set_log_fd(&mut self, fd: BorrowedFd) -> Result<()> {
+            assert_eq!(fd.as_raw_fd(), self.log_fd);
             Ok(())
         }
 
@@ -429,9 +624,12 @@ mod tests {
         fn set_vring_addr(
             &mut self,
             queue_index: usize,
-            _config_data: &VringConfigData,
+            config_data: &VringConfigData,
         ) -> Result<()> {
             assert_eq!(queue_index, 1);
+            if config_data.flags & VRING_F_LOG != 0 {
+                assert_eq!(config_data.get_log_addr(), 0x7000);
+            }
             Ok(())
         }
 
@@ -446,17 +644,17 @@ mod tests {
             Ok(2)
         }
 
-        fn set_vring_call(&mut self, queue_index: usize, _fd: &EventFd) -> Result<()> {
+        fn set_vring_call(&mut self, queue_index: usize, _fd: Option<&EventFd>) -> Result<()> {
             assert_eq!(queue_index, 1);
             Ok(())
         }
 
-        fn set_vring_kick(&mut self, queue_index: usize, _fd: &EventFd) -> Result<()> {
+        fn set_vring_kick(&mut self, queue_index: usize, _fd: Option<&EventFd>) -> Result<()> {
             assert_eq!(queue_index, 1);
             Ok(())
         }
 
-        fn set_vring_err(&mut self, queue_index: usize, _fd: &EventFd) -> Result<()> {
+        fn set_vring_err(&mut self, queue_index: usize, _fd: Option<&EventFd>) -> Result<()> {
             assert_eq!(queue_index, 1);
             Ok(())
         }
@@ -464,7 +662,12 @@ mod tests {
 
     #[test]
     fn test_vring_backend_mut() {
-        let b = RwLock::new(MockBackend {});
+        let log = EventFd::new(0).unwrap();
+        let b = RwLock::new(MockBackend {
+            log_fd: log.as_raw_fd(),
+        });
+        // Safe because the eventfd outlives the borrowed fd.
+        let log_fd = unsafe { BorrowedFd::borrow_raw(log.as_raw_fd()) };
 
         assert_eq!(b.get_features().unwrap(), 0x1);
         b.set_features(0x1).unwrap();
@@ -476,11 +679,11 @@ mod tests {
             Some(VhostUserDirtyLogRegion {
                 mmap_size: 0x1000,
                 mmap_offset: 0x10,
-                mmap_handle: 100,
+                mmap_handle: log_fd,
             }),
         )
         .unwrap();
-        b.set_log_fd(100).unwrap();
+        b.set_log_fd(log_fd).unwrap();
         b.set_vring_num(1, 256).unwrap();
 
         let config = VringConfigData {
@@ -498,9 +701,78 @@ mod tests {
         assert_eq!(b.get_vring_base(1).unwrap(), 2);
 
         let eventfd = EventFd::new(0).unwrap();
-        b.set_vring_call(1, &eventfd).unwrap();
-        b.set_vring_kick(1, &eventfd).unwrap();
-        b.set_vring_err(1, &eventfd).unwrap();
+        b.set_vring_call(1, Some(&eventfd)).unwrap();
+        b.set_vring_kick(1, Some(&eventfd)).unwrap();
+        b.set_vring_err(1, Some(&eventfd)).unwrap();
+    }
+
+    struct MockFeatureOnly {}
+
+    impl VhostFeatureOps for MockFeatureOnly {
+        fn get_features(&self) -> Result<u64> {
+            Ok(0x3)
+        }
+
+        fn set_features(&self, _features: u64) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_owner(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn reset_owner(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn negotiate<B: VhostFeatureOps>(backend: &B, wanted: u64) -> Result<u64> {
+        let features = backend.get_features()? & wanted;
+        backend.set_features(features)?;
+        Ok(features)
+    }
+
+    #[test]
+    fn test_partial_backend() {
+        let b = MockFeatureOnly {};
+        assert_eq!(negotiate(&b, 0x6).unwrap(), 0x2);
+        b.set_features_checked(0x2).unwrap();
+        match b.set_features_checked(0xe) {
+            Err(Error::UnsupportedFeatures(0xc)) => {}
+            _ => panic!("unexpected result"),
+        }
+
+        let b = RefCell::new(MockBackend { log_fd: -1 });
+        assert_eq!(negotiate(&b, 0x1).unwrap(), 0x1);
+    }
+
+    #[test]
+    fn test_vhost_device() {
+        let devices: Vec<Box<dyn VhostDevice>> = vec![
+            Box::new(RwLock::new(MockBackend { log_fd: -1 })),
+            Box::new(RefCell::new(MockBackend { log_fd: -1 })),
+        ];
+        let eventfd = EventFd::new(0).unwrap();
+        let setup = VringSetup {
+            config: VringConfigData {
+                queue_max_size: 0x1000,
+                queue_size: 256,
+                flags: VRING_F_LOG,
+                log_addr: Some(0x7000),
+                ..Default::default()
+            },
+            base: 2,
+            kick: &eventfd,
+            call: &eventfd,
+        };
+
+        for device in devices.iter() {
+            assert_eq!(device.get_features().unwrap(), 0x1);
+            device.setup_vring(1, &setup).unwrap();
+            assert_eq!(device.get_vring_base(1).unwrap(), 2);
+            device.set_status(0xf).unwrap_err();
+            device.get_status().unwrap_err();
+        }
     }
 
     #[test]
@@ -529,5 +801,15 @@ mod tests {
         config.flags = 0x0;
         assert_eq!(config.is_log_addr_valid(), true);
         assert_eq!(config.get_log_addr(), 0);
+
+        config.enable_log(None);
+        assert_eq!(config.flags, 0x1);
+        assert_eq!(config.get_log_addr(), 0x5000);
+        config.enable_log(Some(0x8000));
+        assert_eq!(config.get_log_addr(), 0x8000);
+        config.disable_log();
+        assert_eq!(config.flags, 0x0);
+        assert_eq!(config.log_addr, None);
+        assert_eq!(config.get_log_addr(), 0);
     }
 }
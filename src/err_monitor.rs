@@ -0,0 +1,157 @@
+// Copyright (C) 2021 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Monitor for the vring error eventfds registered through `set_vring_err()`.
+//!
+//! A vhost backend signals the error eventfd of a vring when it fails to process the vring. The
+//! [`VringErrMonitor`] watches a set of such eventfds from a dedicated thread and invokes a user
+//! callback with the index of the failing queue, so the VMM can react to vring errors.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::thread::{self, JoinHandle};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::backend::VhostVringOps;
+use crate::{Error, Result};
+
+/// Watch vring error eventfds and report failing queues to a callback.
+pub struct VringErrMonitor {
+    exit_evt: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VringErrMonitor {
+    /// Start monitoring a set of error eventfds.
+    ///
+    /// # Arguments
+    /// * `fds` - Pairs of queue index and the error eventfd registered for that queue.
+    /// * `callback` - Invoked with the queue index each time its error eventfd is signaled.
+    pub fn start<F>(fds: Vec<(usize, EventFd)>, callback: F) -> Result<Self>
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        let exit_evt = EventFd::new(0).map_err(Error::IOError)?;
+        let thread_exit_evt = exit_evt.try_clone().map_err(Error::IOError)?;
+        let thread = thread::Builder::new()
+            .name("vring_err_monitor".to_string())
+            .spawn(move || Self::run(fds, thread_exit_evt, callback))
+            .map_err(Error::IOError)?;
+
+        Ok(VringErrMonitor {
+            exit_evt,
+            thread: Some(thread),
+        })
+    }
+
+    /// Create an error eventfd for each queue, register it with the backend and start
+    /// monitoring them.
+    ///
+    /// # Arguments
+    /// * `backend` - Backend to register the error eventfds with.
+    /// * `queues` - Indexes of the queues to monitor.
+    /// * `callback` - Invoked with the queue index each time a queue reports an error.
+    pub fn register<B, F>(backend: &B, queues: &[usize], callback: F) -> Result<Self>
+    where
+        B: VhostVringOps,
+        F: FnMut(usize) + Send + 'static,
+    {
+        let mut fds = Vec::with_capacity(queues.len());
+        for &queue_index in queues {
+            let fd = EventFd::new(0).map_err(Error::IOError)?;
+            backend.set_vring_err(queue_index, Some(&fd))?;
+            fds.push((queue_index, fd));
+        }
+
+        Self::start(fds, callback)
+    }
+
+    /// Stop the monitor thread and wait for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            // If the exit event can't be signaled the thread would never terminate,
+            // so don't wait for it in that case.
+            if self.exit_evt.write(1).is_ok() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn run<F: FnMut(usize)>(fds: Vec<(usize, EventFd)>, exit_evt: EventFd, mut callback: F) {
+        let mut pollfds: Vec<libc::pollfd> = std::iter::once(exit_evt.as_raw_fd())
+            .chain(fds.iter().map(|(_, fd)| fd.as_raw_fd()))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        loop {
+            // The pollfd array is valid for the duration of the call and the return value
+            // is checked.
+            let ret =
+                unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+
+            if pollfds[0].revents != 0 {
+                return;
+            }
+
+            for (pollfd, (queue_index, fd)) in pollfds[1..].iter_mut().zip(fds.iter()) {
+                if pollfd.revents & libc::POLLIN != 0 {
+                    let _ = fd.read();
+                    callback(*queue_index);
+                }
+                // A broken fd would wake the thread up forever, so stop watching it: poll()
+                // ignores the negative fds.
+                if pollfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                    pollfd.fd = -1;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for VringErrMonitor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_vring_err_monitor() {
+        let fd0 = EventFd::new(0).unwrap();
+        let fd1 = EventFd::new(0).unwrap();
+        let (tx, rx) = channel();
+
+        let monitor = VringErrMonitor::start(
+            vec![(0, fd0.try_clone().unwrap()), (1, fd1.try_clone().unwrap())],
+            move |queue_index| tx.send(queue_index).unwrap(),
+        )
+        .unwrap();
+
+        fd1.write(1).unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+        fd0.write(1).unwrap();
+        assert_eq!(rx.recv().unwrap(), 0);
+
+        monitor.stop();
+        assert!(rx.recv().is_err());
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright (C) 2021 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Wire vring notifications directly into KVM.
+//!
+//! A VMM usually doesn't want to relay vring notifications through its own threads. Instead the
+//! kick eventfd of each queue is registered with KVM as an ioeventfd on the queue doorbell, so a
+//! guest write to the doorbell signals the backend, and the call eventfd is registered as an
+//! irqfd, so the backend can inject the queue interrupt into the guest. [`KvmQueueNotifiers`]
+//! creates those eventfds, registers them with a KVM `VmFd` and hands them to any vhost backend
+//! implementing [`VhostVringOps`], which covers both the kernel and the vhost-user backends.
+
+use std::io;
+
+use kvm_ioctls::{IoEventAddress, NoDatamatch, VmFd};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::backend::VhostVringOps;
+use crate::{Error, Result};
+
+/// Offset of the QueueNotify register in the virtio-mmio register layout.
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x50;
+
+/// Guest address of a queue doorbell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DoorbellAddress {
+    /// Address in the programmable I/O space.
+    Pio(u64),
+    /// Address in the memory mapped I/O space.
+    Mmio(u64),
+}
+
+impl From<DoorbellAddress> for IoEventAddress {
+    fn from(addr: DoorbellAddress) -> Self {
+        match addr {
+            DoorbellAddress::Pio(addr) => IoEventAddress::Pio(addr),
+            DoorbellAddress::Mmio(addr) => IoEventAddress::Mmio(addr),
+        }
+    }
+}
+
+/// Routing of the notifications of a queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueWiring {
+    /// Index of the queue.
+    pub queue_index: usize,
+    /// Global system interrupt raised when the backend signals the call eventfd.
+    pub gsi: u32,
+    /// Guest address of the doorbell the driver writes to kick the queue.
+    pub doorbell: DoorbellAddress,
+    /// Value the driver writes to the doorbell, when the doorbell is shared between queues.
+    pub datamatch: Option<u32>,
+}
+
+impl QueueWiring {
+    /// Routing for a queue of a virtio-mmio device.
+    ///
+    /// All queues of a virtio-mmio device share the QueueNotify register, and the driver writes
+    /// the queue index to it.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue.
+    /// * `gsi` - Interrupt of the device.
+    /// * `mmio_base` - Guest physical address of the device register window.
+    pub fn mmio(queue_index: usize, gsi: u32, mmio_base: u64) -> Self {
+        QueueWiring {
+            queue_index,
+            gsi,
+            doorbell: DoorbellAddress::Mmio(mmio_base + VIRTIO_MMIO_QUEUE_NOTIFY),
+            datamatch: Some(queue_index as u32),
+        }
+    }
+
+    /// Routing for a queue of a virtio-pci device.
+    ///
+    /// The doorbell is computed from the notification capability as
+    /// `notify_base + queue_notify_off * notify_off_multiplier`, assuming the device reports the
+    /// queue index as `queue_notify_off`. A zero multiplier makes all queues share one doorbell,
+    /// in which case the driver writes the queue index to it.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue.
+    /// * `gsi` - Interrupt routed to the MSI-X vector of the queue.
+    /// * `notify_base` - Guest physical address of the notification structure.
+    /// * `notify_off_multiplier` - Multiplier reported by the notification capability.
+    pub fn pci(queue_index: usize, gsi: u32, notify_base: u64, notify_off_multiplier: u32) -> Self {
+        let offset = queue_index as u64 * u64::from(notify_off_multiplier);
+        QueueWiring {
+            queue_index,
+            gsi,
+            doorbell: DoorbellAddress::Mmio(notify_base + offset),
+            datamatch: if notify_off_multiplier == 0 {
+                Some(queue_index as u32)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+struct QueueNotifier {
+    wiring: QueueWiring,
+    call: EventFd,
+    kick: EventFd,
+}
+
+impl QueueNotifier {
+    fn new(wiring: QueueWiring) -> Result<Self> {
+        Ok(QueueNotifier {
+            wiring,
+            call: EventFd::new(0).map_err(Error::IOError)?,
+            kick: EventFd::new(0).map_err(Error::IOError)?,
+        })
+    }
+
+    fn register_irqfd(&self, vm: &VmFd) -> Result<()> {
+        vm.register_irqfd(&self.call, self.wiring.gsi)
+            .map_err(kvm_error)
+    }
+
+    fn unregister_irqfd(&self, vm: &VmFd) -> Result<()> {
+        vm.unregister_irqfd(&self.call, self.wiring.gsi)
+            .map_err(kvm_error)
+    }
+
+    fn register_ioevent(&self, vm: &VmFd) -> Result<()> {
+        let addr = IoEventAddress::from(self.wiring.doorbell);
+        match self.wiring.datamatch {
+            Some(value) => vm.register_ioevent(&self.kick, &addr, value),
+            None => vm.register_ioevent(&self.kick, &addr, NoDatamatch),
+        }
+        .map_err(kvm_error)
+    }
+
+    fn unregister_ioevent(&self, vm: &VmFd) -> Result<()> {
+        let addr = IoEventAddress::from(self.wiring.doorbell);
+        match self.wiring.datamatch {
+            Some(value) => vm.unregister_ioevent(&self.kick, &addr, value),
+            None => vm.unregister_ioevent(&self.kick, &addr, NoDatamatch),
+        }
+        .map_err(kvm_error)
+    }
+
+    fn register<B: VhostVringOps>(&self, vm: &VmFd, backend: &B) -> Result<()> {
+        self.register_irqfd(vm)?;
+        if let Err(e) = self.register_ioevent(vm) {
+            let _ = self.unregister_irqfd(vm);
+            return Err(e);
+        }
+
+        let queue_index = self.wiring.queue_index;
+        let res = backend
+            .set_vring_call(queue_index, Some(&self.call))
+            .and_then(|_| backend.set_vring_kick(queue_index, Some(&self.kick)));
+        if res.is_err() {
+            let _ = self.unregister(vm);
+        }
+        res
+    }
+
+    fn unregister(&self, vm: &VmFd) -> Result<()> {
+        let res = self.unregister_ioevent(vm);
+        self.unregister_irqfd(vm).and(res)
+    }
+}
+
+fn kvm_error(e: kvm_ioctls::Error) -> Error {
+    Error::IOError(io::Error::from_raw_os_error(e.errno()))
+}
+
+/// Call and kick eventfds of a set of queues, registered with KVM and a vhost backend.
+pub struct KvmQueueNotifiers {
+    notifiers: Vec<QueueNotifier>,
+}
+
+impl KvmQueueNotifiers {
+    /// Create the call and kick eventfds of each queue, register them with KVM and the backend.
+    ///
+    /// The call eventfd of each queue is registered as an irqfd for its `gsi` and the kick
+    /// eventfd as an ioeventfd on its doorbell, then both are passed to the backend through
+    /// `set_vring_call()` and `set_vring_kick()`. If any queue fails, the KVM registrations
+    /// already made are undone before returning the error.
+    ///
+    /// # Arguments
+    /// * `vm` - KVM VM the guest runs in.
+    /// * `backend` - Backend to register the eventfds with.
+    /// * `queues` - Notification routing of the queues to wire.
+    pub fn register<B: VhostVringOps>(
+        vm: &VmFd,
+        backend: &B,
+        queues: &[QueueWiring],
+    ) -> Result<Self> {
+        let mut notifiers: Vec<QueueNotifier> = Vec::with_capacity(queues.len());
+        for wiring in queues {
+            let res = QueueNotifier::new(*wiring)
+                .and_then(|notifier| notifier.register(vm, backend).map(|_| notifier));
+            match res {
+                Ok(notifier) => notifiers.push(notifier),
+                Err(e) => {
+                    for registered in notifiers.iter() {
+                        let _ = registered.unregister(vm);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(KvmQueueNotifiers { notifiers })
+    }
+
+    /// Get the call eventfd of a queue.
+    pub fn call_fd(&self, queue_index: usize) -> Option<&EventFd> {
+        self.find(queue_index).map(|n| &n.call)
+    }
+
+    /// Get the kick eventfd of a queue.
+    pub fn kick_fd(&self, queue_index: usize) -> Option<&EventFd> {
+        self.find(queue_index).map(|n| &n.kick)
+    }
+
+    /// Remove the irqfd and ioeventfd registrations of all queues from KVM.
+    ///
+    /// All queues are unregistered even if some of them fail, and the first error is returned.
+    pub fn unregister(self, vm: &VmFd) -> Result<()> {
+        let mut res = Ok(());
+        for notifier in self.notifiers.iter() {
+            let ret = notifier.unregister(vm);
+            if res.is_ok() {
+                res = ret;
+            }
+        }
+        res
+    }
+
+    fn find(&self, queue_index: usize) -> Option<&QueueNotifier> {
+        self.notifiers
+            .iter()
+            .find(|n| n.wiring.queue_index == queue_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_wiring() {
+        let wiring = QueueWiring::mmio(1, 5, 0xd000_0000);
+        assert_eq!(wiring.doorbell, DoorbellAddress::Mmio(0xd000_0050));
+        assert_eq!(wiring.datamatch, Some(1));
+        assert_eq!(wiring.gsi, 5);
+
+        let wiring = QueueWiring::pci(3, 40, 0xe000_0000, 4);
+        assert_eq!(wiring.doorbell, DoorbellAddress::Mmio(0xe000_000c));
+        assert_eq!(wiring.datamatch, None);
+
+        let wiring = QueueWiring::pci(3, 40, 0xe000_0000, 0);
+        assert_eq!(wiring.doorbell, DoorbellAddress::Mmio(0xe000_0000));
+        assert_eq!(wiring.datamatch, Some(3));
+    }
+}
@@ -40,6 +40,12 @@ extern crate vmm_sys_util;
 mod backend;
 pub use backend::*;
 
+pub mod err_monitor;
+
+#[cfg(feature = "kvm")]
+pub mod kvm;
+#[cfg(feature = "vhost-net")]
+pub mod net;
 #[cfg(feature = "vhost-kern")]
 pub mod vhost_kern;
 #[cfg(feature = "vhost-user")]
@@ -66,6 +72,8 @@ pub enum Error {
     AvailAddress,
     /// Invalid log address.
     LogAddress,
+    /// Features requested but not offered by the backend.
+    UnsupportedFeatures(u64),
     #[cfg(feature = "vhost-kern")]
     /// Error opening the vhost backend driver.
     VhostOpen(std::io::Error),
@@ -77,6 +85,9 @@ pub enum Error {
     #[cfg(feature = "vhost-user")]
     /// Error from the vhost-user subsystem.
     VhostUserProtocol(vhost_user::Error),
+    #[cfg(feature = "vhost-user")]
+    /// Failure of a request configuring the vring of the given index.
+    VringSetup(usize, vhost_user::message::MasterReq, Box<Error>),
 }
 
 impl std::fmt::Display for Error {
@@ -92,6 +103,9 @@ impl std::fmt::Display for Error {
             Error::UsedAddress => write!(f, "invalid virtqueue used table address"),
             Error::AvailAddress => write!(f, "invalid virtqueue available table address"),
             Error::LogAddress => write!(f, "invalid virtqueue log address"),
+            Error::UnsupportedFeatures(features) => {
+                write!(f, "unsupported features: {:#x}", features)
+            }
             Error::IOError(e) => write!(f, "IO error: {}", e),
             #[cfg(feature = "vhost-kern")]
             Error::VhostOpen(e) => write!(f, "failure in opening vhost file: {}", e),
@@ -99,6 +113,14 @@ impl std::fmt::Display for Error {
             Error::IoctlError(e) => write!(f, "failure in vhost ioctl: {}", e),
             #[cfg(feature = "vhost-user")]
             Error::VhostUserProtocol(e) => write!(f, "vhost-user: {}", e),
+            #[cfg(feature = "vhost-user")]
+            Error::VringSetup(index, request, e) => {
+                write!(
+                    f,
+                    "failed to set up vring {} with {:?}: {}",
+                    index, request, e
+                )
+            }
         }
     }
 }
@@ -150,6 +172,10 @@ mod tests {
             format!("{}", Error::LogAddress),
             "invalid virtqueue log address"
         );
+        assert_eq!(
+            format!("{}", Error::UnsupportedFeatures(0x3)),
+            "unsupported features: 0x3"
+        );
 
         assert_eq!(format!("{:?}", Error::AvailAddress), "AvailAddress");
     }
@@ -161,4 +187,19 @@ mod tests {
 
         assert_eq!(format!("{}", e), "vhost-user: oversized message");
     }
+
+    #[cfg(feature = "vhost-user")]
+    #[test]
+    fn test_vring_setup_error() {
+        let e = Error::VringSetup(
+            1,
+            vhost_user::message::MasterReq::SET_VRING_BASE,
+            Box::new(vhost_user::Error::SlaveInternalError.into()),
+        );
+
+        assert_eq!(
+            format!("{}", e),
+            "failed to set up vring 1 with SET_VRING_BASE: vhost-user: slave internal error"
+        );
+    }
 }
@@ -0,0 +1,121 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Capability report of in-kernel vhost devices.
+
+use std::sync::Mutex;
+
+use super::vhost_binding::{
+    VHOST_BACKEND_F_IOTLB_BATCH, VHOST_BACKEND_F_IOTLB_MSG_V2, VHOST_F_LOG_ALL,
+};
+use super::{Error, Result, VhostKernBackend};
+use crate::VhostFeatureOps;
+
+// Virtio feature bits reserved for transport features, [28, 38).
+const VIRTIO_TRANSPORT_F_START: u64 = 28;
+const VIRTIO_TRANSPORT_F_END: u64 = 38;
+// Virtio feature: the device is behind an IOMMU.
+const VIRTIO_F_IOMMU_PLATFORM: u64 = 33;
+
+/// Capabilities reported by an in-kernel vhost device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VhostKernCapabilities {
+    /// Bitmask of supported virtio/vhost features, from VHOST_GET_FEATURES.
+    pub features: u64,
+    /// Bitmask of supported backend features, from VHOST_GET_BACKEND_FEATURES. Zero if the
+    /// kernel doesn't support the ioctl.
+    pub backend_features: u64,
+    /// Maximum number of queues handled by the device, if the device type has a fixed number.
+    pub max_queues: Option<usize>,
+}
+
+impl VhostKernCapabilities {
+    /// Query the capabilities of a device.
+    pub fn query<B: VhostKernBackend>(backend: &B) -> Result<Self> {
+        let features = backend.get_features()?;
+        let backend_features = match backend.get_backend_features() {
+            Ok(v) => v,
+            // Kernels older than v5.3 don't know about VHOST_GET_BACKEND_FEATURES.
+            Err(Error::IoctlError(e)) if e.raw_os_error() == Some(libc::ENOTTY) => 0,
+            Err(e) => return Err(e),
+        };
+
+        Ok(VhostKernCapabilities {
+            features,
+            backend_features,
+            max_queues: backend.max_queues(),
+        })
+    }
+
+    /// Get the supported virtio transport features.
+    pub fn transport_features(&self) -> u64 {
+        let mask = (1u64 << VIRTIO_TRANSPORT_F_END) - (1u64 << VIRTIO_TRANSPORT_F_START);
+        self.features & mask
+    }
+
+    /// Check whether dirty page logging through VHOST_F_LOG_ALL is supported.
+    pub fn log_all(&self) -> bool {
+        self.features & (1 << VHOST_F_LOG_ALL) != 0
+    }
+
+    /// Check whether the device supports IOTLB, that is VIRTIO_F_IOMMU_PLATFORM.
+    pub fn iotlb(&self) -> bool {
+        self.features & (1 << VIRTIO_F_IOMMU_PLATFORM) != 0
+    }
+
+    /// Check whether the device supports the v2 IOTLB message format.
+    pub fn iotlb_msg_v2(&self) -> bool {
+        self.backend_features & (1 << VHOST_BACKEND_F_IOTLB_MSG_V2) != 0
+    }
+
+    /// Check whether the device supports batched IOTLB updates.
+    pub fn iotlb_batch(&self) -> bool {
+        self.backend_features & (1 << VHOST_BACKEND_F_IOTLB_BATCH) != 0
+    }
+}
+
+/// Cache the capabilities of a device, so the ioctls are only issued once.
+#[derive(Default)]
+pub struct CapabilitiesCache {
+    caps: Mutex<Option<VhostKernCapabilities>>,
+}
+
+impl CapabilitiesCache {
+    /// Get the cached capabilities of `backend`, querying them on first use.
+    pub fn get<B: VhostKernBackend>(&self, backend: &B) -> Result<VhostKernCapabilities> {
+        let mut caps = self.caps.lock().unwrap();
+        match *caps {
+            Some(v) => Ok(v),
+            None => {
+                let v = VhostKernCapabilities::query(backend)?;
+                *caps = Some(v);
+                Ok(v)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let caps = VhostKernCapabilities {
+            features: 1 << VHOST_F_LOG_ALL | 1 << 32 | 1 << VIRTIO_F_IOMMU_PLATFORM | 1 << 40,
+            backend_features: 1 << VHOST_BACKEND_F_IOTLB_MSG_V2,
+            max_queues: Some(2),
+        };
+
+        assert_eq!(caps.transport_features(), 1 << 32 | 1 << 33);
+        assert!(caps.log_all());
+        assert!(caps.iotlb());
+        assert!(caps.iotlb_msg_v2());
+        assert!(!caps.iotlb_batch());
+
+        let caps = VhostKernCapabilities::default();
+        assert_eq!(caps.transport_features(), 0);
+        assert!(!caps.log_all());
+        assert!(!caps.iotlb());
+    }
+}
@@ -11,23 +11,46 @@
 //! communicate with userspace applications. This sub module provides ioctl based interfaces to
 //! control the in-kernel net, scsi, vsock vhost drivers.
 
-use std::os::unix::io::{AsRawFd, RawFd};
-
-use vm_memory::{Address, GuestAddress, GuestAddressSpace, GuestMemory, GuestUsize};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::os::raw::c_ulong;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+use vm_memory::{
+    Address, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryRegion, GuestUsize,
+    MemoryRegionAddress,
+};
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
 
 use super::{
-    Error, Result, VhostBackend, VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo,
-    VringConfigData, VHOST_MAX_MEMORY_REGIONS,
+    Error, Result, VhostDevice, VhostFeatureOps, VhostLogOps, VhostMemOps, VhostUserDirtyLogRegion,
+    VhostUserMemoryRegionInfo, VhostVringOps, VringConfigData, VHOST_MAX_MEMORY_REGIONS,
 };
 
 pub mod vhost_binding;
 use self::vhost_binding::*;
 
+mod capabilities;
+pub use self::capabilities::{CapabilitiesCache, VhostKernCapabilities};
+
+#[cfg(feature = "vhost-net")]
+pub mod net;
 #[cfg(feature = "vhost-vsock")]
 pub mod vsock;
 
+// Open a vhost device node. O_CLOEXEC is always added to `flags`, so the vhost fd doesn't leak
+// into child processes.
+fn open_device(path: &str, flags: i32) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(flags | libc::O_CLOEXEC)
+        .open(path)
+        .map_err(Error::VhostOpen)
+}
+
 #[inline]
 fn ioctl_result<T>(rc: i32, res: T) -> Result<T> {
     if rc < 0 {
@@ -37,6 +60,36 @@ fn ioctl_result<T>(rc: i32, res: T) -> Result<T> {
     }
 }
 
+/// Endianness of a vring, used to run legacy cross-endian guests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VringEndian {
+    /// The vring is accessed in little-endian format.
+    Little,
+    /// The vring is accessed in big-endian format.
+    Big,
+}
+
+impl From<VringEndian> for u32 {
+    fn from(endian: VringEndian) -> Self {
+        match endian {
+            VringEndian::Little => VHOST_VRING_LITTLE_ENDIAN,
+            VringEndian::Big => VHOST_VRING_BIG_ENDIAN,
+        }
+    }
+}
+
+impl TryFrom<u32> for VringEndian {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            VHOST_VRING_LITTLE_ENDIAN => Ok(VringEndian::Little),
+            VHOST_VRING_BIG_ENDIAN => Ok(VringEndian::Big),
+            _ => Err(Error::InvalidOperation),
+        }
+    }
+}
+
 /// Represent an in-kernel vhost device backend.
 pub trait VhostKernBackend: AsRawFd {
     /// Associated type to access guest memory.
@@ -80,9 +133,165 @@ pub trait VhostKernBackend: AsRawFd {
 
         config_data.is_log_addr_valid()
     }
+
+    /// Get a bitmask of supported vhost backend features.
+    fn get_backend_features(&self) -> Result<u64>
+    where
+        Self: Sized,
+    {
+        let mut avail_features: u64 = 0;
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret =
+            unsafe { ioctl_with_mut_ref(self, VHOST_GET_BACKEND_FEATURES(), &mut avail_features) };
+        ioctl_result(ret, avail_features)
+    }
+
+    /// Inform the vhost subsystem which backend features to enable. This should be a subset
+    /// of supported features from VHOST_GET_BACKEND_FEATURES.
+    ///
+    /// # Arguments
+    /// * `features` - Bitmask of backend features to set.
+    fn set_backend_features(&self, features: u64) -> Result<()>
+    where
+        Self: Sized,
+    {
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_SET_BACKEND_FEATURES(), &features) };
+        ioctl_result(ret, ())
+    }
+
+    /// Issue an arbitrary ioctl on the vhost fd.
+    ///
+    /// This is an escape hatch for ioctls not supported by this crate yet, see
+    /// [`vhost_binding::VHOST`] for defining their numbers. Returns the non-negative value
+    /// returned by the ioctl.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `req` is a valid request for the device and that `arg` has
+    /// the layout the kernel expects for it, as the kernel reads and writes through it.
+    unsafe fn raw_ioctl<T>(&self, req: c_ulong, arg: &mut T) -> Result<i32>
+    where
+        Self: Sized,
+    {
+        let ret = ioctl_with_mut_ref(self, req, arg);
+        ioctl_result(ret, ret)
+    }
+
+    /// Maximum number of queues handled by the device, if the device type has a fixed number.
+    fn max_queues(&self) -> Option<usize> {
+        None
+    }
+
+    /// Save the index of the next available descriptor of each vring, for migration.
+    ///
+    /// The backend must have been stopped before taking the snapshot, for example with
+    /// `VhostVsock::stop()`, so that the indexes don't move anymore.
+    ///
+    /// # Arguments
+    /// * `n_queues` - Number of queues of the device.
+    fn snapshot_vrings(&self, n_queues: usize) -> Result<Vec<u16>>
+    where
+        Self: Sized,
+    {
+        (0..n_queues)
+            .map(|queue_index| self.get_vring_base(queue_index).map(|base| base as u16))
+            .collect()
+    }
+
+    /// Restore the vring indexes saved by `snapshot_vrings()`.
+    ///
+    /// This must be done before the backend is started on the destination.
+    ///
+    /// # Arguments
+    /// * `bases` - Index of the next available descriptor of each vring.
+    fn restore_vrings(&self, bases: &[u16]) -> Result<()>
+    where
+        Self: Sized,
+    {
+        for (queue_index, base) in bases.iter().enumerate() {
+            self.set_vring_base(queue_index, *base)?;
+        }
+        Ok(())
+    }
+
+    /// Set the endianness of a vring for legacy cross-endian guests.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `endian` - Endianness of the vring.
+    fn set_vring_endian(&self, queue_index: usize, endian: VringEndian) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: endian.into(),
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_ENDIAN(), &vring_state) };
+        ioctl_result(ret, ())
+    }
+
+    /// Get the endianness of a vring.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to query.
+    fn get_vring_endian(&self, queue_index: usize) -> Result<VringEndian>
+    where
+        Self: Sized,
+    {
+        let mut vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: 0,
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(self, VHOST_GET_VRING_ENDIAN(), &mut vring_state) };
+        ioctl_result(ret, ())?;
+        VringEndian::try_from(vring_state.num)
+    }
+
+    /// Hook invoked once `update_mem_table()` has programmed a new memory table.
+    ///
+    /// Vring configurations validated against the previous memory layout may be stale now, so
+    /// implementations caching validation results should drop them here.
+    fn mem_table_updated(&self) {}
+
+    /// Snapshot the current guest memory and program it into the vhost device.
+    ///
+    /// The address space returned by `mem()` may be updated after the backend has been created,
+    /// for example when using `GuestMemoryAtomic`, so this should be called whenever the guest
+    /// memory layout changes.
+    fn update_mem_table(&self) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let m = self.mem().memory();
+        let mut regions = Vec::with_capacity(m.num_regions());
+        for region in m.iter() {
+            let userspace_addr = region
+                .get_host_address(MemoryRegionAddress(0))
+                .map_err(|_| Error::InvalidGuestMemoryRegion)?;
+            regions.push(VhostUserMemoryRegionInfo::new(
+                region.start_addr().raw_value(),
+                region.len(),
+                userspace_addr as u64,
+                0,
+                None,
+            ));
+        }
+
+        self.set_mem_table(&regions)?;
+        self.mem_table_updated();
+        Ok(())
+    }
 }
 
-impl<T: VhostKernBackend> VhostBackend for T {
+impl<T: VhostKernBackend> VhostDevice for T {}
+
+impl<T: VhostKernBackend> VhostFeatureOps for T {
     /// Get a bitmask of supported virtio/vhost features.
     fn get_features(&self) -> Result<u64> {
         let mut avail_features: u64 = 0;
@@ -115,7 +324,9 @@ impl<T: VhostKernBackend> VhostBackend for T {
         let ret = unsafe { ioctl(self, VHOST_RESET_OWNER()) };
         ioctl_result(ret, ())
     }
+}
 
+impl<T: VhostKernBackend> VhostMemOps for T {
     /// Set the guest memory mappings for vhost to use.
     fn set_mem_table(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
         if regions.is_empty() || regions.len() > VHOST_MAX_MEMORY_REGIONS {
@@ -141,7 +352,9 @@ impl<T: VhostKernBackend> VhostBackend for T {
         let ret = unsafe { ioctl_with_ptr(self, VHOST_SET_MEM_TABLE(), vhost_memory.as_ptr()) };
         ioctl_result(ret, ())
     }
+}
 
+impl<T: VhostKernBackend> VhostLogOps for T {
     /// Set base address for page modification logging.
     ///
     /// # Arguments
@@ -157,13 +370,15 @@ impl<T: VhostKernBackend> VhostBackend for T {
     }
 
     /// Specify an eventfd file descriptor to signal on log write.
-    fn set_log_fd(&self, fd: RawFd) -> Result<()> {
+    fn set_log_fd(&self, fd: BorrowedFd) -> Result<()> {
         // This ioctl is called on a valid vhost fd and has its return value checked.
-        let val: i32 = fd;
+        let val: i32 = fd.as_raw_fd();
         let ret = unsafe { ioctl_with_ref(self, VHOST_SET_LOG_FD(), &val) };
         ioctl_result(ret, ())
     }
+}
 
+impl<T: VhostKernBackend> VhostVringOps for T {
     /// Set the number of descriptors in the vring.
     ///
     /// # Arguments
@@ -236,11 +451,11 @@ impl<T: VhostKernBackend> VhostBackend for T {
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd to trigger.
-    fn set_vring_call(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    /// * `fd` - EventFd to trigger, or `None` to unbind the current one.
+    fn set_vring_call(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         let vring_file = vhost_vring_file {
             index: queue_index as u32,
-            fd: fd.as_raw_fd(),
+            fd: fd.map_or(-1, |fd| fd.as_raw_fd()),
         };
 
         // This ioctl is called on a valid vhost fd and has its return value checked.
@@ -253,11 +468,11 @@ impl<T: VhostKernBackend> VhostBackend for T {
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from guest.
-    fn set_vring_kick(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    /// * `fd` - EventFd that will be signaled from guest, or `None` to unbind the current one.
+    fn set_vring_kick(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         let vring_file = vhost_vring_file {
             index: queue_index as u32,
-            fd: fd.as_raw_fd(),
+            fd: fd.map_or(-1, |fd| fd.as_raw_fd()),
         };
 
         // This ioctl is called on a valid vhost fd and has its return value checked.
@@ -269,11 +484,12 @@ impl<T: VhostKernBackend> VhostBackend for T {
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from the backend.
-    fn set_vring_err(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+    /// * `fd` - EventFd that will be signaled from the backend, or `None` to unbind the current
+    ///   one.
+    fn set_vring_err(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
         let vring_file = vhost_vring_file {
             index: queue_index as u32,
-            fd: fd.as_raw_fd(),
+            fd: fd.map_or(-1, |fd| fd.as_raw_fd()),
         };
 
         // This ioctl is called on a valid vhost fd and has its return value checked.
@@ -281,3 +497,17 @@ impl<T: VhostKernBackend> VhostBackend for T {
         ioctl_result(ret, ())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vring_endian() {
+        assert_eq!(u32::from(VringEndian::Little), VHOST_VRING_LITTLE_ENDIAN);
+        assert_eq!(u32::from(VringEndian::Big), VHOST_VRING_BIG_ENDIAN);
+        assert_eq!(VringEndian::try_from(0).unwrap(), VringEndian::Little);
+        assert_eq!(VringEndian::try_from(1).unwrap(), VringEndian::Big);
+        VringEndian::try_from(2).unwrap_err();
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Kernel-based vhost-net backend.
+
+use std::fs::File;
+use std::os::raw;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::ioctl::ioctl_with_ref;
+
+use super::vhost_binding::{
+    vhost_vring_file, TUNSETVNETHDRSZ, VHOST_NET_F_VIRTIO_NET_HDR, VHOST_NET_SET_BACKEND,
+};
+use super::{
+    ioctl_result, open_device, CapabilitiesCache, Result, VhostKernBackend, VhostKernCapabilities,
+};
+use crate::net::VhostNet;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+/// Virtio-net feature: driver can merge receive buffers.
+pub const VIRTIO_NET_F_MRG_RXBUF: u64 = 15;
+/// Virtio feature: device complies with the virtio 1.0 specification.
+pub const VIRTIO_F_VERSION_1: u64 = 32;
+
+/// Size of the legacy virtio-net header, without the `num_buffers` field.
+const VIRTIO_NET_HDR_LEN: usize = 10;
+/// Size of the virtio-net header with the `num_buffers` field.
+const VIRTIO_NET_HDR_MRG_RXBUF_LEN: usize = 12;
+
+/// Get the length of the virtio-net header implied by the acked features.
+///
+/// The header carries the `num_buffers` field when either VIRTIO_NET_F_MRG_RXBUF or
+/// VIRTIO_F_VERSION_1 has been negotiated.
+pub fn vnet_hdr_len(acked_features: u64) -> usize {
+    if acked_features & (1 << VIRTIO_NET_F_MRG_RXBUF | 1 << VIRTIO_F_VERSION_1) != 0 {
+        VIRTIO_NET_HDR_MRG_RXBUF_LEN
+    } else {
+        VIRTIO_NET_HDR_LEN
+    }
+}
+
+/// Handle for running VHOST_NET ioctls.
+pub struct Net<AS: GuestAddressSpace> {
+    fd: File,
+    mem: AS,
+    caps: CapabilitiesCache,
+}
+
+impl<AS: GuestAddressSpace> Net<AS> {
+    /// Open a handle to a new VHOST-NET instance.
+    pub fn new(mem: AS) -> Result<Self> {
+        Self::with_flags(mem, libc::O_NONBLOCK)
+    }
+
+    /// Open a handle to a new VHOST-NET instance with custom open flags.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest memory of the device.
+    /// * `flags` - Flags to open the device node with, e.g. `libc::O_NONBLOCK` or 0 for a
+    ///   blocking fd. O_CLOEXEC is always set.
+    pub fn with_flags(mem: AS, flags: i32) -> Result<Self> {
+        Ok(Net {
+            fd: open_device(VHOST_NET_PATH, flags)?,
+            mem,
+            caps: CapabilitiesCache::default(),
+        })
+    }
+
+    /// Get the capabilities of the device.
+    ///
+    /// The capabilities are queried from the kernel on first use and cached afterwards.
+    pub fn capabilities(&self) -> Result<VhostKernCapabilities> {
+        self.caps.get(self)
+    }
+
+    /// Attach a TAP device as the backend of a queue.
+    ///
+    /// Unless the vhost driver has been asked to insert the virtio-net header itself through
+    /// VHOST_NET_F_VIRTIO_NET_HDR, the TAP device is configured with the header length implied
+    /// by the acked features before being attached. The TAP device must have been created
+    /// with IFF_VNET_HDR.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the virtqueue.
+    /// * `tap` - The TAP device.
+    /// * `acked_features` - Features acked through `set_features()`.
+    pub fn attach_tap(&self, queue_index: usize, tap: &File, acked_features: u64) -> Result<()> {
+        if acked_features & (1 << VHOST_NET_F_VIRTIO_NET_HDR) == 0 {
+            let len = vnet_hdr_len(acked_features) as raw::c_int;
+            // This ioctl is called on a valid tap fd and has its return value checked.
+            let ret = unsafe { ioctl_with_ref(tap, TUNSETVNETHDRSZ(), &len) };
+            ioctl_result(ret, ())?;
+        }
+
+        self.set_backend(queue_index, Some(tap))
+    }
+}
+
+impl<AS: GuestAddressSpace> VhostNet for Net<AS> {
+    fn set_backend(&self, queue_index: usize, fd: Option<&File>) -> Result<()> {
+        let vring_file = vhost_vring_file {
+            index: queue_index as u32,
+            fd: fd.map_or(-1, |v| v.as_raw_fd()),
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_NET_SET_BACKEND(), &vring_file) };
+        ioctl_result(ret, ())
+    }
+}
+
+impl<AS: GuestAddressSpace> VhostKernBackend for Net<AS> {
+    type AS = AS;
+
+    fn mem(&self) -> &Self::AS {
+        &self.mem
+    }
+
+    fn max_queues(&self) -> Option<usize> {
+        Some(2)
+    }
+}
+
+impl<AS: GuestAddressSpace> AsRawFd for Net<AS> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+    use super::*;
+    use crate::VhostFeatureOps;
+
+    #[test]
+    fn test_vnet_hdr_len() {
+        assert_eq!(vnet_hdr_len(0), 10);
+        assert_eq!(vnet_hdr_len(1 << VIRTIO_NET_F_MRG_RXBUF), 12);
+        assert_eq!(vnet_hdr_len(1 << VIRTIO_F_VERSION_1), 12);
+    }
+
+    #[test]
+    fn test_net_new_device() {
+        let m = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let net = Net::new(&m).unwrap();
+
+        assert!(net.as_raw_fd() >= 0);
+        assert!(net.mem().find_region(GuestAddress(0x100)).is_some());
+
+        let features = net.get_features().unwrap();
+        net.set_features(features).unwrap();
+        net.set_owner().unwrap();
+        net.set_backend(0, None).unwrap();
+    }
+}
@@ -18,6 +18,20 @@
 use crate::{Error, Result};
 use std::os::raw;
 
+/// Type of the vhost ioctls.
+///
+/// Ioctls not covered by this crate yet may be defined against it with the `ioctl_*_nr!`
+/// macros from `vmm_sys_util`, and then issued with `VhostKernBackend::raw_ioctl()`.
+///
+/// ```
+/// # #[macro_use] extern crate vmm_sys_util;
+/// use vhost::vhost_kern::vhost_binding::VHOST;
+///
+/// ioctl_ior_nr!(VHOST_NEW_FEATURE, VHOST, 0x7f, ::std::os::raw::c_ulonglong);
+/// # fn main() {
+/// #     let _ = VHOST_NEW_FEATURE();
+/// # }
+/// ```
 pub const VHOST: raw::c_uint = 0xaf;
 pub const VHOST_VRING_F_LOG: raw::c_uint = 0;
 pub const VHOST_ACCESS_RO: raw::c_uint = 1;
@@ -33,8 +47,11 @@ pub const VHOST_VIRTIO: raw::c_uint = 175;
 pub const VHOST_VRING_LITTLE_ENDIAN: raw::c_uint = 0;
 pub const VHOST_VRING_BIG_ENDIAN: raw::c_uint = 1;
 pub const VHOST_F_LOG_ALL: raw::c_uint = 26;
+pub const VHOST_BACKEND_F_IOTLB_MSG_V2: raw::c_uint = 1;
+pub const VHOST_BACKEND_F_IOTLB_BATCH: raw::c_uint = 2;
 pub const VHOST_NET_F_VIRTIO_NET_HDR: raw::c_uint = 27;
 pub const VHOST_SCSI_ABI_VERSION: raw::c_uint = 1;
+pub const TUNTAP: raw::c_uint = 0x54;
 
 ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST, 0x00, raw::c_ulonglong);
 ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST, 0x00, raw::c_ulonglong);
@@ -47,9 +64,13 @@ ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, vhost_vring_state);
 ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, vhost_vring_addr);
 ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, vhost_vring_state);
 ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST, 0x12, vhost_vring_state);
+ioctl_iow_nr!(VHOST_SET_VRING_ENDIAN, VHOST, 0x13, vhost_vring_state);
+ioctl_iow_nr!(VHOST_GET_VRING_ENDIAN, VHOST, 0x14, vhost_vring_state);
 ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, vhost_vring_file);
 ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, vhost_vring_file);
 ioctl_iow_nr!(VHOST_SET_VRING_ERR, VHOST, 0x22, vhost_vring_file);
+ioctl_iow_nr!(VHOST_SET_BACKEND_FEATURES, VHOST, 0x25, raw::c_ulonglong);
+ioctl_ior_nr!(VHOST_GET_BACKEND_FEATURES, VHOST, 0x26, raw::c_ulonglong);
 ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, vhost_vring_file);
 ioctl_iow_nr!(VHOST_SCSI_SET_ENDPOINT, VHOST, 0x40, vhost_scsi_target);
 ioctl_iow_nr!(VHOST_SCSI_CLEAR_ENDPOINT, VHOST, 0x41, vhost_scsi_target);
@@ -58,6 +79,7 @@ ioctl_iow_nr!(VHOST_SCSI_SET_EVENTS_MISSED, VHOST, 0x43, raw::c_uint);
 ioctl_iow_nr!(VHOST_SCSI_GET_EVENTS_MISSED, VHOST, 0x44, raw::c_uint);
 ioctl_iow_nr!(VHOST_VSOCK_SET_GUEST_CID, VHOST, 0x60, raw::c_ulonglong);
 ioctl_iow_nr!(VHOST_VSOCK_SET_RUNNING, VHOST, 0x61, raw::c_int);
+ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 0xd8, raw::c_int);
 
 #[repr(C)]
 #[derive(Default)]
@@ -7,39 +7,84 @@
 
 //! Kernel-based vhost-vsock backend.
 
-use std::fs::{File, OpenOptions};
-use std::os::unix::fs::OpenOptionsExt;
+use std::fs::File;
+use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 use vm_memory::GuestAddressSpace;
 use vmm_sys_util::ioctl::ioctl_with_ref;
 
 use super::vhost_binding::{VHOST_VSOCK_SET_GUEST_CID, VHOST_VSOCK_SET_RUNNING};
-use super::{ioctl_result, Error, Result, VhostKernBackend};
+use super::{
+    ioctl_result, open_device, CapabilitiesCache, Error, Result, VhostKernBackend,
+    VhostKernCapabilities,
+};
 use crate::vsock::VhostVsock;
 
 const VHOST_PATH: &str = "/dev/vhost-vsock";
+// CIDs up to VMADDR_CID_HOST are reserved and can't be assigned to guests.
+const VMADDR_CID_HOST: u64 = 2;
+// VMADDR_CID_ANY, the upper 32 bits are reserved as well.
+const VMADDR_CID_ANY: u64 = 0xffff_ffff;
 
 /// Handle for running VHOST_VSOCK ioctls.
 pub struct Vsock<AS: GuestAddressSpace> {
     fd: File,
     mem: AS,
+    caps: CapabilitiesCache,
 }
 
 impl<AS: GuestAddressSpace> Vsock<AS> {
     /// Open a handle to a new VHOST-VSOCK instance.
     pub fn new(mem: AS) -> Result<Self> {
+        Self::with_flags(mem, libc::O_NONBLOCK)
+    }
+
+    /// Open a handle to a new VHOST-VSOCK instance with custom open flags.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest memory of the device.
+    /// * `flags` - Flags to open the device node with, e.g. `libc::O_NONBLOCK` or 0 for a
+    ///   blocking fd. O_CLOEXEC is always set.
+    pub fn with_flags(mem: AS, flags: i32) -> Result<Self> {
         Ok(Vsock {
-            fd: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
-                .open(VHOST_PATH)
-                .map_err(Error::VhostOpen)?,
+            fd: open_device(VHOST_PATH, flags)?,
             mem,
+            caps: CapabilitiesCache::default(),
         })
     }
 
+    /// Get the capabilities of the device.
+    ///
+    /// The capabilities are queried from the kernel on first use and cached afterwards.
+    pub fn capabilities(&self) -> Result<VhostKernCapabilities> {
+        self.caps.get(self)
+    }
+
+    /// Assign the first CID from `candidates` which isn't in use by another guest.
+    ///
+    /// The kernel rejects CIDs already assigned to other guests with EADDRINUSE, so each
+    /// candidate is tried in turn. Reserved CIDs are skipped. Returns the assigned CID.
+    ///
+    /// # Arguments
+    /// * `candidates` - CIDs to try, in order of preference.
+    pub fn set_free_guest_cid<I: IntoIterator<Item = u64>>(&self, candidates: I) -> Result<u64> {
+        for cid in candidates {
+            if cid <= VMADDR_CID_HOST || cid >= VMADDR_CID_ANY {
+                continue;
+            }
+            match self.set_guest_cid(cid) {
+                Ok(()) => return Ok(cid),
+                Err(Error::IoctlError(e)) if e.raw_os_error() == Some(libc::EADDRINUSE) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(Error::IoctlError(io::Error::from_raw_os_error(
+            libc::EADDRINUSE,
+        )))
+    }
+
     fn set_running(&self, running: bool) -> Result<()> {
         let on: ::std::os::raw::c_int = if running { 1 } else { 0 };
         let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_VSOCK_SET_RUNNING(), &on) };
@@ -68,6 +113,10 @@ impl<AS: GuestAddressSpace> VhostKernBackend for Vsock<AS> {
     fn mem(&self) -> &Self::AS {
         &self.mem
     }
+
+    fn max_queues(&self) -> Option<usize> {
+        Some(2)
+    }
 }
 
 impl<AS: GuestAddressSpace> AsRawFd for Vsock<AS> {
@@ -78,12 +127,16 @@ impl<AS: GuestAddressSpace> AsRawFd for Vsock<AS> {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::io::BorrowedFd;
+
     use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
     use vmm_sys_util::eventfd::EventFd;
 
+    use super::super::vhost_binding::VHOST_GET_FEATURES;
     use super::*;
     use crate::{
-        VhostBackend, VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo, VringConfigData,
+        VhostDevice, VhostFeatureOps, VhostLogOps, VhostMemOps, VhostUserDirtyLogRegion,
+        VhostUserMemoryRegionInfo, VhostVringOps, VringConfigData, VringSetup,
     };
 
     #[test]
@@ -94,6 +147,12 @@ mod tests {
         assert!(vsock.as_raw_fd() >= 0);
         assert!(vsock.mem().find_region(GuestAddress(0x100)).is_some());
         assert!(vsock.mem().find_region(GuestAddress(0x10_0000)).is_none());
+
+        let vsock = Vsock::with_flags(&m, 0).unwrap();
+        let flags = unsafe { libc::fcntl(vsock.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+        let fd_flags = unsafe { libc::fcntl(vsock.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(fd_flags & libc::FD_CLOEXEC, 0);
     }
 
     #[test]
@@ -127,45 +186,49 @@ mod tests {
 
         let features = vsock.get_features().unwrap();
         vsock.set_features(features).unwrap();
+        let mut raw_features: u64 = 0;
+        let ret = unsafe { vsock.raw_ioctl(VHOST_GET_FEATURES(), &mut raw_features) };
+        assert_eq!(ret.unwrap(), 0);
+        assert_eq!(raw_features, features);
+        let caps = vsock.capabilities().unwrap();
+        assert_eq!(caps.features, features);
+        assert_eq!(caps.max_queues, Some(2));
 
         vsock.set_owner().unwrap();
 
         vsock.set_mem_table(&[]).unwrap_err();
 
         /*
-        let region = VhostUserMemoryRegionInfo {
-            guest_phys_addr: 0x0,
-            memory_size: 0x10_0000,
-            userspace_addr: 0,
-            mmap_offset: 0,
-            mmap_handle: -1,
-        };
+        let region = VhostUserMemoryRegionInfo::new(0x0, 0x10_0000, 0, 0, None);
         vsock.set_mem_table(&[region]).unwrap_err();
          */
 
-        let region = VhostUserMemoryRegionInfo {
-            guest_phys_addr: 0x0,
-            memory_size: 0x10_0000,
-            userspace_addr: m.get_host_address(GuestAddress(0x0)).unwrap() as u64,
-            mmap_offset: 0,
-            mmap_handle: -1,
-        };
+        let region = VhostUserMemoryRegionInfo::new(
+            0x0,
+            0x10_0000,
+            m.get_host_address(GuestAddress(0x0)).unwrap() as u64,
+            0,
+            None,
+        );
         vsock.set_mem_table(&[region]).unwrap();
+        vsock.update_mem_table().unwrap();
 
+        let eventfd = EventFd::new(0).unwrap();
+        // Safe because the eventfd outlives the borrowed fd.
+        let log_fd = unsafe { BorrowedFd::borrow_raw(eventfd.as_raw_fd()) };
         vsock
             .set_log_base(
                 0x4000,
                 Some(VhostUserDirtyLogRegion {
                     mmap_size: 0x1000,
                     mmap_offset: 0x10,
-                    mmap_handle: 1,
+                    mmap_handle: log_fd,
                 }),
             )
             .unwrap_err();
         vsock.set_log_base(0x4000, None).unwrap();
 
-        let eventfd = EventFd::new(0).unwrap();
-        vsock.set_log_fd(eventfd.as_raw_fd()).unwrap();
+        vsock.set_log_fd(log_fd).unwrap();
 
         vsock.set_vring_num(0, 32).unwrap();
 
@@ -180,11 +243,24 @@ mod tests {
         };
         vsock.set_vring_addr(0, &config).unwrap();
         vsock.set_vring_base(0, 1).unwrap();
-        vsock.set_vring_call(0, &eventfd).unwrap();
-        vsock.set_vring_kick(0, &eventfd).unwrap();
-        vsock.set_vring_err(0, &eventfd).unwrap();
+        vsock.set_vring_call(0, Some(&eventfd)).unwrap();
+        vsock.set_vring_kick(0, Some(&eventfd)).unwrap();
+        vsock.set_vring_err(0, Some(&eventfd)).unwrap();
         assert_eq!(vsock.get_vring_base(0).unwrap(), 1);
+
+        let setup = VringSetup {
+            config,
+            base: 0,
+            kick: &eventfd,
+            call: &eventfd,
+        };
+        vsock.setup_vring(1, &setup).unwrap();
+
+        let bases = vsock.snapshot_vrings(2).unwrap();
+        assert_eq!(bases, vec![1, 0]);
+        vsock.restore_vrings(&bases).unwrap();
         vsock.set_guest_cid(0xdead).unwrap();
+        assert_eq!(vsock.set_free_guest_cid(0..0x1000).unwrap(), 3);
         //vsock.start().unwrap();
         //vsock.stop().unwrap();
     }
@@ -0,0 +1,668 @@
+// Copyright (C) 2021 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Async vhost-user master, driven by the tokio runtime.
+//!
+//! [`AsyncMaster`] offers the same request/reply exchanges as [`Master`](super::Master), but its
+//! socket is registered with the reactor of the tokio runtime, so waiting for the slave doesn't
+//! block the thread running the task.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::slice;
+
+use tokio::io::unix::AsyncFd;
+use vm_memory::ByteValued;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::connection::Endpoint;
+use super::message::*;
+use super::{Error as VhostUserError, Result as VhostUserResult};
+use crate::backend::{VhostUserMemoryRegionInfo, VringConfigData};
+use crate::{Error, Result};
+
+fn error_code<T>(err: VhostUserError) -> Result<T> {
+    Err(Error::VhostUserProtocol(err))
+}
+
+// Get the raw bytes of a vhost-user message structure.
+fn as_bytes<T: Sized>(val: &T) -> &[u8] {
+    // Safe because vhost-user messages are plain data structures, and the slice can't outlive
+    // `val`.
+    unsafe { slice::from_raw_parts(val as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+// Build a vhost-user message structure from the bytes received from the socket.
+fn from_bytes<T: Sized>(buf: &[u8]) -> T {
+    assert!(buf.len() >= mem::size_of::<T>());
+    // Safe because the buffer is large enough and vhost-user messages are plain data
+    // structures, for which any bit pattern is valid.
+    unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const T) }
+}
+
+// Report the socket operations which would block to the reactor, so it waits for the socket to
+// become ready again.
+fn would_block<T>(res: VhostUserResult<T>) -> io::Result<VhostUserResult<T>> {
+    match res {
+        Err(VhostUserError::SocketRetry(e)) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
+        res => Ok(res),
+    }
+}
+
+/// Async vhost-user master endpoint, for VMMs running on the tokio runtime.
+///
+/// The master must be created from within the runtime. Requests are exchanged one at a time,
+/// every method waits for the reply of its request before returning.
+pub struct AsyncMaster {
+    // Used to send requests to the slave.
+    sock: AsyncFd<Endpoint<MasterReq>>,
+    // Cached virtio features from the slave.
+    virtio_features: u64,
+    // Cached acked virtio features from the driver.
+    acked_virtio_features: u64,
+    // Cached vhost-user protocol features from the slave.
+    protocol_features: u64,
+    // Cached vhost-user protocol features.
+    acked_protocol_features: u64,
+    // Cached maxinum number of queues supported from the slave.
+    max_queue_num: u64,
+    // List of header flags.
+    hdr_flags: VhostUserHeaderFlag,
+    // End of the device configuration space accessed by GET_CONFIG and SET_CONFIG.
+    config_size: u32,
+}
+
+impl AsyncMaster {
+    /// Create a new instance from a Unix stream socket.
+    ///
+    /// The socket is switched to non-blocking mode and registered with the reactor of the
+    /// current tokio runtime.
+    pub fn from_stream(sock: UnixStream, max_queue_num: u64) -> Result<Self> {
+        sock.set_nonblocking(true).map_err(Error::IOError)?;
+        let sock = AsyncFd::new(Endpoint::from_stream(sock)).map_err(Error::IOError)?;
+
+        Ok(AsyncMaster {
+            sock,
+            virtio_features: 0,
+            acked_virtio_features: 0,
+            protocol_features: 0,
+            acked_protocol_features: 0,
+            max_queue_num,
+            hdr_flags: VhostUserHeaderFlag::empty(),
+            config_size: VHOST_USER_CONFIG_SIZE,
+        })
+    }
+
+    /// Create a new vhost-user master endpoint.
+    ///
+    /// # Arguments
+    /// * `path` - path of Unix domain socket listener to connect to
+    pub async fn connect<P: AsRef<Path>>(path: P, max_queue_num: u64) -> Result<Self> {
+        let sock = tokio::net::UnixStream::connect(path)
+            .await
+            .map_err(VhostUserError::SocketConnect)?;
+        Self::from_stream(sock.into_std().map_err(Error::IOError)?, max_queue_num)
+    }
+
+    /// Set the header flags that should be applied to all following messages.
+    pub fn set_hdr_flags(&mut self, flags: VhostUserHeaderFlag) {
+        self.hdr_flags = flags;
+    }
+
+    /// Set the end of the device configuration space, see `Master::set_config_size()`.
+    pub fn set_config_size(&mut self, config_size: u32) -> Result<()> {
+        if config_size <= VHOST_USER_CONFIG_OFFSET {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        self.config_size = config_size;
+        Ok(())
+    }
+
+    /// Get from the underlying vhost implementation the feature bitmask.
+    pub async fn get_features(&mut self) -> Result<u64> {
+        let hdr = self
+            .send_request(MasterReq::GET_FEATURES, &[], None)
+            .await?;
+        let val = self.recv_reply::<VhostUserU64>(&hdr).await?;
+        self.virtio_features = val.value;
+        Ok(self.virtio_features)
+    }
+
+    /// Enable features in the underlying vhost implementation using a bitmask.
+    pub async fn set_features(&mut self, features: u64) -> Result<()> {
+        let val = VhostUserU64::new(features);
+        let hdr = self
+            .send_request(MasterReq::SET_FEATURES, as_bytes(&val), None)
+            .await?;
+        self.acked_virtio_features = features & self.virtio_features;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    /// Set the current master as an owner of the session.
+    pub async fn set_owner(&mut self) -> Result<()> {
+        let hdr = self.send_request(MasterReq::SET_OWNER, &[], None).await?;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    /// Used to be sent to request disabling all rings.
+    pub async fn reset_owner(&mut self) -> Result<()> {
+        let hdr = self.send_request(MasterReq::RESET_OWNER, &[], None).await?;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    /// Get the protocol feature bitmask from the underlying vhost implementation.
+    pub async fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures> {
+        let flag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        if self.virtio_features & flag == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        let hdr = self
+            .send_request(MasterReq::GET_PROTOCOL_FEATURES, &[], None)
+            .await?;
+        let val = self.recv_reply::<VhostUserU64>(&hdr).await?;
+        self.protocol_features = val.value;
+        match VhostUserProtocolFeatures::from_bits(self.protocol_features) {
+            Some(val) => Ok(val),
+            None => error_code(VhostUserError::InvalidMessage),
+        }
+    }
+
+    /// Enable protocol features in the underlying vhost implementation.
+    pub async fn set_protocol_features(
+        &mut self,
+        features: VhostUserProtocolFeatures,
+    ) -> Result<()> {
+        let flag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        if self.virtio_features & flag == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        let val = VhostUserU64::new(features.bits());
+        let hdr = self
+            .send_request(MasterReq::SET_PROTOCOL_FEATURES, as_bytes(&val), None)
+            .await?;
+        self.acked_protocol_features = features.bits();
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    /// Query how many queues the backend supports.
+    pub async fn get_queue_num(&mut self) -> Result<u64> {
+        if self.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() == 0 {
+            return error_code(VhostUserError::ProtocolFeatureNotNegotiated(
+                VhostUserProtocolFeatures::MQ,
+            ));
+        }
+        let hdr = self
+            .send_request(MasterReq::GET_QUEUE_NUM, &[], None)
+            .await?;
+        let val = self.recv_reply::<VhostUserU64>(&hdr).await?;
+        if val.value > VHOST_USER_MAX_VRINGS {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+        self.max_queue_num = val.value;
+        Ok(self.max_queue_num)
+    }
+
+    /// Set the memory map regions on the slave so it can translate the vring addresses.
+    pub async fn set_mem_table(&mut self, regions: &[VhostUserMemoryRegionInfo<'_>]) -> Result<()> {
+        if regions.is_empty() || regions.len() > MAX_ATTACHED_FD_ENTRIES {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        #[cfg(feature = "xen")]
+        {
+            if self.acked_protocol_features & VhostUserProtocolFeatures::XEN_MMAP.bits() == 0 {
+                return error_code(VhostUserError::ProtocolFeatureNotNegotiated(
+                    VhostUserProtocolFeatures::XEN_MMAP,
+                ));
+            }
+        }
+
+        let body = VhostUserMemory::new(regions.len() as u32);
+        let mut buf = as_bytes(&body).to_vec();
+        let mut fds = Vec::with_capacity(regions.len());
+        for region in regions.iter() {
+            let fd = match region.mmap_handle {
+                Some(fd) if region.memory_size != 0 => fd,
+                _ => return error_code(VhostUserError::InvalidParam),
+            };
+            buf.extend_from_slice(VhostUserMemoryRegion::from(region).as_slice());
+            fds.push(fd.as_raw_fd());
+        }
+
+        let hdr = self
+            .send_request(MasterReq::SET_MEM_TABLE, &buf, Some(&fds))
+            .await?;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    /// Set the size of the queue.
+    pub async fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        let val = VhostUserVringState::new(queue_index as u32, num.into());
+        self.send_vring_request(MasterReq::SET_VRING_NUM, queue_index, as_bytes(&val))
+            .await
+    }
+
+    /// Set the addresses of the different aspects of the vring.
+    pub async fn set_vring_addr(
+        &mut self,
+        queue_index: usize,
+        config_data: &VringConfigData,
+    ) -> Result<()> {
+        if config_data.flags & !(VhostUserVringAddrFlags::all().bits()) != 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let val = VhostUserVringAddr::from_config_data(queue_index as u32, config_data);
+        self.send_vring_request(MasterReq::SET_VRING_ADDR, queue_index, as_bytes(&val))
+            .await
+    }
+
+    /// Set the base offset in the available vring.
+    pub async fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<()> {
+        let val = VhostUserVringState::new(queue_index as u32, base.into());
+        self.send_vring_request(MasterReq::SET_VRING_BASE, queue_index, as_bytes(&val))
+            .await
+    }
+
+    /// Get the available vring base offset.
+    pub async fn get_vring_base(&mut self, queue_index: usize) -> Result<u32> {
+        if queue_index as u64 >= self.max_queue_num {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let req = VhostUserVringState::new(queue_index as u32, 0);
+        let hdr = self
+            .send_request(MasterReq::GET_VRING_BASE, as_bytes(&req), None)
+            .await?;
+        let reply = self.recv_reply::<VhostUserVringState>(&hdr).await?;
+        Ok(reply.num)
+    }
+
+    /// Set the position of a packed vring.
+    pub async fn set_vring_base_packed(
+        &mut self,
+        queue_index: usize,
+        base: VringPackedBase,
+    ) -> Result<()> {
+        let num = match base.to_num() {
+            Some(num) => num,
+            None => return error_code(VhostUserError::InvalidParam),
+        };
+        let val = VhostUserVringState::new(queue_index as u32, num);
+        self.send_vring_request(MasterReq::SET_VRING_BASE, queue_index, as_bytes(&val))
+            .await
+    }
+
+    /// Stop a packed vring and get its position.
+    pub async fn get_vring_base_packed(&mut self, queue_index: usize) -> Result<VringPackedBase> {
+        let num = self.get_vring_base(queue_index).await?;
+        Ok(VringPackedBase::from_num(num))
+    }
+
+    /// Set the event file descriptor to signal when buffers are used.
+    pub async fn set_vring_call(&mut self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
+        let fd = fd.map(|fd| fd.as_raw_fd());
+        self.send_fd_for_vring(MasterReq::SET_VRING_CALL, queue_index, fd)
+            .await
+    }
+
+    /// Set the event file descriptor for adding buffers to the vring.
+    pub async fn set_vring_kick(&mut self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
+        let fd = fd.map(|fd| fd.as_raw_fd());
+        self.send_fd_for_vring(MasterReq::SET_VRING_KICK, queue_index, fd)
+            .await
+    }
+
+    /// Set the event file descriptor to signal when error occurs.
+    pub async fn set_vring_err(&mut self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
+        let fd = fd.map(|fd| fd.as_raw_fd());
+        self.send_fd_for_vring(MasterReq::SET_VRING_ERR, queue_index, fd)
+            .await
+    }
+
+    /// Enable or disable a vring, once PROTOCOL_FEATURES has been negotiated.
+    pub async fn set_vring_enable(&mut self, queue_index: usize, enable: bool) -> Result<()> {
+        if self.acked_virtio_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        let val = VhostUserVringState::new(queue_index as u32, enable.into());
+        self.send_vring_request(MasterReq::SET_VRING_ENABLE, queue_index, as_bytes(&val))
+            .await
+    }
+
+    /// Fetch the contents of the virtio device configuration space.
+    pub async fn get_config(
+        &mut self,
+        offset: u32,
+        size: u32,
+        flags: VhostUserConfigFlags,
+        buf: &[u8],
+    ) -> Result<(VhostUserConfig, VhostUserConfigPayload)> {
+        let in_range = match offset.checked_add(size) {
+            Some(end) => end <= self.config_size,
+            None => false,
+        };
+        if size == 0
+            || size > VhostUserConfig::max_access_size(self.config_size)
+            || !in_range
+            || buf.len() != size as usize
+        {
+            return error_code(VhostUserError::InvalidConfigRange(offset, size));
+        }
+        let body = VhostUserConfig::new(offset, size, flags);
+        if !body.is_valid_for(self.config_size) {
+            return error_code(VhostUserError::InvalidParam);
+        } else if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
+            return error_code(VhostUserError::ProtocolFeatureNotNegotiated(
+                VhostUserProtocolFeatures::CONFIG,
+            ));
+        }
+
+        let mut req = as_bytes(&body).to_vec();
+        req.extend_from_slice(buf);
+        let hdr = self.send_request(MasterReq::GET_CONFIG, &req, None).await?;
+        let (reply, body_reply, buf_reply, files) = self.recv_message::<VhostUserConfig>().await?;
+        if !reply.is_reply_for(&hdr) || files.is_some() {
+            return error_code(VhostUserError::InvalidMessage);
+        } else if body_reply.size == 0 {
+            return error_code(VhostUserError::SlaveInternalError);
+        } else if body_reply.size != size || body_reply.offset != offset {
+            return error_code(VhostUserError::ConfigReplyMismatch(
+                body_reply.offset,
+                body_reply.size,
+            ));
+        } else if buf_reply.len() != size as usize {
+            return error_code(VhostUserError::ConfigReplyLength(
+                buf_reply.len(),
+                size as usize,
+            ));
+        }
+
+        Ok((body_reply, buf_reply))
+    }
+
+    /// Change the virtio device configuration space.
+    pub async fn set_config(
+        &mut self,
+        offset: u32,
+        flags: VhostUserConfigFlags,
+        buf: &[u8],
+    ) -> Result<()> {
+        if buf.len() > MAX_MSG_SIZE {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let body = VhostUserConfig::new(offset, buf.len() as u32, flags);
+        if !body.is_valid_for(self.config_size) {
+            return error_code(VhostUserError::InvalidParam);
+        } else if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
+            return error_code(VhostUserError::ProtocolFeatureNotNegotiated(
+                VhostUserProtocolFeatures::CONFIG,
+            ));
+        }
+
+        let mut req = as_bytes(&body).to_vec();
+        req.extend_from_slice(buf);
+        let hdr = self.send_request(MasterReq::SET_CONFIG, &req, None).await?;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    /// Setup slave communication channel.
+    pub async fn set_slave_request_fd(&mut self, fd: &dyn AsFd) -> Result<()> {
+        if self.acked_protocol_features & VhostUserProtocolFeatures::SLAVE_REQ.bits() == 0 {
+            return error_code(VhostUserError::ProtocolFeatureNotNegotiated(
+                VhostUserProtocolFeatures::SLAVE_REQ,
+            ));
+        }
+        let fds = [fd.as_fd().as_raw_fd()];
+        let hdr = self
+            .send_request(MasterReq::SET_SLAVE_REQ_FD, &[], Some(&fds))
+            .await?;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    async fn send_vring_request(
+        &mut self,
+        code: MasterReq,
+        queue_index: usize,
+        body: &[u8],
+    ) -> Result<()> {
+        if queue_index as u64 >= self.max_queue_num {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let hdr = self.send_request(code, body, None).await?;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    async fn send_fd_for_vring(
+        &mut self,
+        code: MasterReq,
+        queue_index: usize,
+        fd: Option<RawFd>,
+    ) -> Result<()> {
+        if queue_index as u64 >= self.max_queue_num {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        // Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag.
+        let msg = match fd {
+            Some(_) => VhostUserU64::new(queue_index as u64),
+            None => VhostUserU64::new(queue_index as u64 | VHOST_USER_VRING_NOFD_MASK),
+        };
+        let fds = fd.as_ref().map(std::slice::from_ref);
+        let hdr = self.send_request(code, as_bytes(&msg), fds).await?;
+        self.wait_for_ack(&hdr).await.map_err(|e| e.into())
+    }
+
+    async fn send_request(
+        &mut self,
+        code: MasterReq,
+        body: &[u8],
+        fds: Option<&[RawFd]>,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        if body.len() > MAX_MSG_SIZE {
+            return Err(VhostUserError::InvalidParam);
+        }
+        if let Some(fd_arr) = fds {
+            if fd_arr.len() > MAX_ATTACHED_FD_ENTRIES {
+                return Err(VhostUserError::InvalidParam);
+            }
+        }
+
+        let hdr = VhostUserMsgHeader::new(code, self.hdr_flags.bits() | 0x1, body.len() as u32);
+        let mut buf = as_bytes(&hdr).to_vec();
+        buf.extend_from_slice(body);
+        self.send_bytes(&buf, fds).await?;
+        Ok(hdr)
+    }
+
+    async fn recv_reply<T: ByteValued + Sized + VhostUserMsgValidator>(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<T> {
+        let (reply, body, payload, files) = self.recv_message::<T>().await?;
+        if !reply.is_reply_for(hdr) || !payload.is_empty() || files.is_some() || !body.is_valid() {
+            return Err(VhostUserError::InvalidMessage);
+        }
+        Ok(body)
+    }
+
+    async fn wait_for_ack(&mut self, hdr: &VhostUserMsgHeader<MasterReq>) -> VhostUserResult<()> {
+        if self.acked_protocol_features & VhostUserProtocolFeatures::REPLY_ACK.bits() == 0
+            || !hdr.is_need_reply()
+        {
+            return Ok(());
+        }
+        let body = self.recv_reply::<VhostUserU64>(hdr).await?;
+        if body.value != 0 {
+            return Err(VhostUserError::SlaveInternalError);
+        }
+        Ok(())
+    }
+
+    // Receive a message made of a header, a body of type `T` and an optional payload.
+    async fn recv_message<T: ByteValued + Sized>(
+        &mut self,
+    ) -> VhostUserResult<(VhostUserMsgHeader<MasterReq>, T, Vec<u8>, Option<Vec<File>>)> {
+        let hdr_size = mem::size_of::<VhostUserMsgHeader<MasterReq>>();
+        let (buf, files) = self.recv_bytes(hdr_size).await?;
+        let hdr: VhostUserMsgHeader<MasterReq> = from_bytes(&buf);
+        let size = hdr.get_size() as usize;
+        if !hdr.is_valid() || size < mem::size_of::<T>() || size > MAX_MSG_SIZE {
+            return Err(VhostUserError::InvalidMessage);
+        }
+
+        // Attached file descriptors are received along with the header.
+        let (buf, body_files) = self.recv_bytes(size).await?;
+        if body_files.is_some() {
+            return Err(VhostUserError::InvalidMessage);
+        }
+        let body: T = from_bytes(&buf);
+        let payload = buf[mem::size_of::<T>()..].to_vec();
+
+        Ok((hdr, body, payload, files))
+    }
+
+    async fn send_bytes(&mut self, buf: &[u8], fds: Option<&[RawFd]>) -> VhostUserResult<()> {
+        let mut sent = 0;
+        while sent < buf.len() {
+            let mut guard = self
+                .sock
+                .writable_mut()
+                .await
+                .map_err(VhostUserError::SocketError)?;
+            // File descriptors are attached to the first bytes of the message.
+            let sfds = if sent == 0 { fds } else { None };
+            let res = match guard
+                .try_io(|sock| would_block(sock.get_mut().send_iovec(&[&buf[sent..]], sfds)))
+            {
+                Ok(res) => res.map_err(VhostUserError::SocketError)?,
+                Err(_would_block) => continue,
+            };
+            match res {
+                Ok(0) => return Err(VhostUserError::PartialMessage),
+                Ok(n) => sent += n,
+                Err(VhostUserError::SocketRetry(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Receive exactly `len` bytes, without reading past the end of the message so the file
+    // descriptors attached to the next one are not lost.
+    async fn recv_bytes(&mut self, len: usize) -> VhostUserResult<(Vec<u8>, Option<Vec<File>>)> {
+        let mut buf = Vec::with_capacity(len);
+        let mut files = None;
+        while buf.len() < len {
+            let mut guard = self
+                .sock
+                .readable_mut()
+                .await
+                .map_err(VhostUserError::SocketError)?;
+            let want = len - buf.len();
+            let res = match guard.try_io(|sock| would_block(sock.get_mut().recv_into_buf(want))) {
+                Ok(res) => res.map_err(VhostUserError::SocketError)?,
+                Err(_would_block) => continue,
+            };
+            match res {
+                Ok((0, _, _)) => return Err(VhostUserError::PartialMessage),
+                Ok((n, data, rfds)) => {
+                    if buf.is_empty() {
+                        files = rfds;
+                    }
+                    buf.extend_from_slice(&data[..n]);
+                }
+                Err(VhostUserError::SocketRetry(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((buf, files))
+    }
+}
+
+impl AsRawFd for AsyncMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn create_pair() -> (UnixStream, Endpoint<MasterReq>) {
+        let (master, slave) = UnixStream::pair().unwrap();
+        (master, Endpoint::<MasterReq>::from_stream(slave))
+    }
+
+    #[test]
+    fn test_async_master_features() {
+        let (sock, mut peer) = create_pair();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
+            .unwrap();
+
+        block_on(async {
+            let mut master = AsyncMaster::from_stream(sock, 1).unwrap();
+            master.set_owner().await.unwrap();
+            master.get_protocol_features().await.unwrap_err();
+            assert_eq!(master.get_features().await.unwrap(), 0x15);
+            master.set_features(0x15).await.unwrap();
+            master
+                .set_features(VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits())
+                .await
+                .unwrap();
+        });
+
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+        assert!(rfds.is_none());
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+        let (hdr, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_FEATURES);
+        assert_eq!({ msg.value }, 0x15);
+        let (_, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(
+            { msg.value },
+            VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+        );
+    }
+
+    #[test]
+    fn test_async_master_vring() {
+        let (sock, mut peer) = create_pair();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_VRING_BASE, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserVringState::new(0, 0x20), None)
+            .unwrap();
+
+        let eventfd = EventFd::new(0).unwrap();
+        block_on(async {
+            let mut master = AsyncMaster::from_stream(sock, 1).unwrap();
+            master.set_vring_num(1, 0x100).await.unwrap_err();
+            master.set_vring_num(0, 0x100).await.unwrap();
+            master.set_vring_kick(0, Some(&eventfd)).await.unwrap();
+            assert_eq!(master.get_vring_base(0).await.unwrap(), 0x20);
+        });
+
+        let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_NUM);
+        assert_eq!({ msg.num }, 0x100);
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_KICK);
+        assert_eq!({ msg.value }, 0);
+        assert_eq!(rfds.unwrap().len(), 1);
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_VRING_BASE);
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (C) 2021 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Async server of the slave communication channel, driven by the tokio runtime.
+
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use tokio::io::unix::AsyncFd;
+
+use super::connection::peek_message;
+use super::master_req_handler::{MasterReqHandler, VhostUserMasterReqHandler};
+use super::message::SlaveReq;
+use super::{Error, Result};
+
+/// Async variant of [MasterReqHandler], for VMMs running on the tokio runtime.
+///
+/// The slave communication channel is registered with the reactor of the tokio runtime, and
+/// [Self::handle_request()] waits for a whole request of the slave to be received before
+/// serving it with the wrapped [MasterReqHandler]. Serving a request therefore never waits for
+/// the slave, and no thread needs to be dedicated to the channel.
+///
+/// [MasterReqHandler]: struct.MasterReqHandler.html
+/// [Self::handle_request()]: struct.AsyncMasterReqHandler.html#method.handle_request
+pub struct AsyncMasterReqHandler<S: VhostUserMasterReqHandler> {
+    handler: AsyncFd<MasterReqHandler<S>>,
+}
+
+impl<S: VhostUserMasterReqHandler> AsyncMasterReqHandler<S> {
+    /// Create a server to handle service requests from slaves on the slave communication channel.
+    ///
+    /// The handler must be created from within the runtime, see [MasterReqHandler::new()].
+    ///
+    /// [MasterReqHandler::new()]: struct.MasterReqHandler.html#method.new
+    pub fn new(backend: Arc<S>) -> Result<Self> {
+        Self::from_handler(MasterReqHandler::new(backend)?)
+    }
+
+    /// Create a server from `handler`, registering its channel with the current tokio runtime.
+    pub fn from_handler(handler: MasterReqHandler<S>) -> Result<Self> {
+        let handler = AsyncFd::new(handler).map_err(Error::SocketError)?;
+        Ok(AsyncMasterReqHandler { handler })
+    }
+
+    /// Get the wrapped handler, to configure it or get the socket of the slave.
+    pub fn get_ref(&self) -> &MasterReqHandler<S> {
+        self.handler.get_ref()
+    }
+
+    /// Get the wrapped handler mutably, to configure it.
+    pub fn get_mut(&mut self) -> &mut MasterReqHandler<S> {
+        self.handler.get_mut()
+    }
+
+    /// Wait for a service request from the slave and serve it.
+    ///
+    /// The result is the result of [MasterReqHandler::handle_request()], so the caller decides
+    /// what to do on errors as with the blocking handler.
+    ///
+    /// [MasterReqHandler::handle_request()]: struct.MasterReqHandler.html#method.handle_request
+    pub async fn handle_request(&mut self) -> Result<u64> {
+        loop {
+            let mut guard = self
+                .handler
+                .readable_mut()
+                .await
+                .map_err(Error::SocketError)?;
+            match guard.try_io(|handler| peek_message::<SlaveReq>(handler.get_ref().as_raw_fd())) {
+                Ok(_) => break,
+                Err(_would_block) => continue,
+            }
+        }
+        self.handler.get_mut().handle_request()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::HandlerResult;
+
+    #[derive(Default)]
+    struct MockConfigChangeHandler {
+        changes: AtomicUsize,
+    }
+
+    impl VhostUserMasterReqHandler for MockConfigChangeHandler {
+        fn handle_config_change(&self) -> HandlerResult<u64> {
+            self.changes.fetch_add(1, Ordering::SeqCst);
+            Ok(0)
+        }
+    }
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn request(code: SlaveReq, size: u32) -> Vec<u8> {
+        [code as u32, 0x1, size]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_async_master_req_handler() {
+        let backend = Arc::new(MockConfigChangeHandler::default());
+
+        block_on(async {
+            let mut handler = AsyncMasterReqHandler::new(backend.clone()).unwrap();
+            let tx = handler.get_ref().get_tx_fd().try_clone_to_owned().unwrap();
+            let mut slave = UnixStream::from(tx);
+            slave
+                .write_all(&request(SlaveReq::CONFIG_CHANGE_MSG, 0))
+                .unwrap();
+            assert_eq!(handler.handle_request().await.unwrap(), 0);
+            assert_eq!(backend.changes.load(Ordering::SeqCst), 1);
+
+            // The handler waits for the body of the request before serving it.
+            let sender = thread::spawn(move || {
+                let req = request(SlaveReq::FS_UNMAP, 8);
+                slave.write_all(&req).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                slave.write_all(&[0u8; 8]).unwrap();
+            });
+            handler.handle_request().await.unwrap_err();
+            sender.join().unwrap();
+        });
+        assert_eq!(backend.changes.load(Ordering::SeqCst), 1);
+    }
+}
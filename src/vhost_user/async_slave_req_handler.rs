@@ -0,0 +1,282 @@
+// Copyright (C) 2021 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Async server of the master communication channel, driven by the tokio runtime.
+
+use std::future::{self, Future};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::io::unix::AsyncFd;
+
+use super::connection::peek_message;
+use super::message::*;
+use super::slave_req_handler::{
+    SlaveReqHandler, VhostUserSlaveReqHandler, VhostUserSlaveReqHandlerMut,
+};
+use super::{Error, Result};
+
+/// Future returned by the services of [VhostUserAsyncSlaveReqHandler].
+///
+/// [VhostUserAsyncSlaveReqHandler]: trait.VhostUserAsyncSlaveReqHandler.html
+pub type HandlerFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Services of [VhostUserSlaveReqHandler] which the slave may provide asynchronously, for
+/// backends waiting for the network or the disk to serve them.
+///
+/// The [AsyncSlaveReqHandler] awaits these services instead of calling their blocking
+/// counterparts. The default implementations call the blocking services, so backends only
+/// override the ones they need.
+///
+/// [VhostUserSlaveReqHandler]: trait.VhostUserSlaveReqHandler.html
+/// [AsyncSlaveReqHandler]: struct.AsyncSlaveReqHandler.html
+pub trait VhostUserAsyncSlaveReqHandler: VhostUserSlaveReqHandler + Send + Sync {
+    /// Read `size` bytes of the device configuration space at `offset`.
+    fn get_config_async(
+        &self,
+        offset: u32,
+        size: u32,
+        flags: VhostUserConfigFlags,
+    ) -> HandlerFuture<'_, Vec<u8>> {
+        Box::pin(future::ready(self.get_config(offset, size, flags)))
+    }
+    /// Write `buf` to the device configuration space at `offset`.
+    fn set_config_async<'a>(
+        &'a self,
+        offset: u32,
+        buf: &'a [u8],
+        flags: VhostUserConfigFlags,
+    ) -> HandlerFuture<'a, ()> {
+        Box::pin(future::ready(self.set_config(offset, buf, flags)))
+    }
+    /// Reset the device to its initial state, see `VhostUserSlaveReqHandler::reset_device()`.
+    fn reset_device_async(&self) -> HandlerFuture<'_, ()> {
+        Box::pin(future::ready(self.reset_device()))
+    }
+    /// Set the virtio device status.
+    fn set_status_async(&self, status: u8) -> HandlerFuture<'_, ()> {
+        Box::pin(future::ready(self.set_status(status)))
+    }
+    /// Get the virtio device status.
+    fn get_status_async(&self) -> HandlerFuture<'_, u8> {
+        Box::pin(future::ready(self.get_status()))
+    }
+    /// Report whether the device state transfer succeeded.
+    fn check_device_state_async(&self) -> HandlerFuture<'_, ()> {
+        Box::pin(future::ready(self.check_device_state()))
+    }
+}
+
+impl<T: VhostUserSlaveReqHandlerMut + Send> VhostUserAsyncSlaveReqHandler for Mutex<T> {}
+
+/// Async variant of [SlaveReqHandler], for slaves running on the tokio runtime.
+///
+/// The master communication channel is registered with the reactor of the tokio runtime, and
+/// [Self::handle_request()] waits for a whole request of the master to be received before
+/// serving it. The requests served by [VhostUserAsyncSlaveReqHandler] are awaited, the others
+/// are served by the wrapped [SlaveReqHandler], so the message loop can run as a tokio task.
+///
+/// [SlaveReqHandler]: struct.SlaveReqHandler.html
+/// [VhostUserAsyncSlaveReqHandler]: trait.VhostUserAsyncSlaveReqHandler.html
+/// [Self::handle_request()]: struct.AsyncSlaveReqHandler.html#method.handle_request
+pub struct AsyncSlaveReqHandler<S: VhostUserAsyncSlaveReqHandler> {
+    handler: AsyncFd<SlaveReqHandler<S>>,
+}
+
+impl<S: VhostUserAsyncSlaveReqHandler> AsyncSlaveReqHandler<S> {
+    /// Create a server from a socket connected to the master, with the current tokio runtime.
+    pub fn from_stream(socket: UnixStream, backend: Arc<S>) -> Result<Self> {
+        Self::from_handler(SlaveReqHandler::from_stream(socket, backend))
+    }
+
+    /// Create a server from `handler`, registering its channel with the current tokio runtime.
+    pub fn from_handler(handler: SlaveReqHandler<S>) -> Result<Self> {
+        let handler = AsyncFd::new(handler).map_err(Error::SocketError)?;
+        Ok(AsyncSlaveReqHandler { handler })
+    }
+
+    /// Get the wrapped handler, to get the guest memory it mapped.
+    pub fn get_ref(&self) -> &SlaveReqHandler<S> {
+        self.handler.get_ref()
+    }
+
+    /// Get the wrapped handler mutably, to configure it.
+    pub fn get_mut(&mut self) -> &mut SlaveReqHandler<S> {
+        self.handler.get_mut()
+    }
+
+    /// Wait for a request from the master and serve it.
+    ///
+    /// Errors are reported as by [SlaveReqHandler::handle_request()], so the caller decides
+    /// what to do on errors as with the blocking handler.
+    ///
+    /// [SlaveReqHandler::handle_request()]: struct.SlaveReqHandler.html#method.handle_request
+    pub async fn handle_request(&mut self) -> Result<()> {
+        loop {
+            let mut guard = self
+                .handler
+                .readable_mut()
+                .await
+                .map_err(Error::SocketError)?;
+            match guard.try_io(|handler| peek_message::<MasterReq>(handler.get_ref().as_raw_fd())) {
+                Ok(_) => break,
+                Err(_would_block) => continue,
+            }
+        }
+
+        let handler = self.handler.get_mut();
+        let (hdr, size, buf, files) = handler.recv_request()?;
+        if hdr.is_custom() {
+            return handler.dispatch_request(hdr, size, buf, files);
+        }
+        let backend = handler.backend().clone();
+        let start = Instant::now();
+        let res = async {
+            match hdr.get_code() {
+                MasterReq::GET_CONFIG => {
+                    let (msg, flags) = handler.config_request(&hdr, size, &buf)?;
+                    if let Err(e) = handler.check_config_access(&msg, flags, false) {
+                        handler.send_config_reply(&hdr, &msg, flags, Err(Error::InvalidParam))?;
+                        return Err(e);
+                    }
+                    let res = backend.get_config_async(msg.offset, msg.size, flags).await;
+                    handler.send_config_reply(&hdr, &msg, flags, res)
+                }
+                MasterReq::SET_CONFIG => {
+                    let res =
+                        match handler
+                            .config_request(&hdr, size, &buf)
+                            .and_then(|(msg, flags)| {
+                                handler.check_config_access(&msg, flags, true)?;
+                                Ok((msg, flags))
+                            }) {
+                            Ok((msg, flags)) => {
+                                let payload = &buf[std::mem::size_of::<VhostUserConfig>()..];
+                                backend.set_config_async(msg.offset, payload, flags).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                    handler.ack_request(&hdr, res)
+                }
+                MasterReq::RESET_DEVICE => {
+                    let res = match handler
+                        .check_protocol_feature(VhostUserProtocolFeatures::RESET_DEVICE)
+                        .and_then(|_| handler.check_request_size(&hdr, size, 0))
+                    {
+                        Ok(_) => backend.reset_device_async().await,
+                        Err(e) => Err(e),
+                    };
+                    handler.ack_request(&hdr, res)
+                }
+                MasterReq::SET_STATUS => {
+                    let res = match handler
+                        .check_protocol_feature(VhostUserProtocolFeatures::STATUS)
+                        .and_then(|_| {
+                            handler.extract_request_body::<VhostUserU64>(&hdr, size, &buf)
+                        }) {
+                        Ok(msg) if msg.value > u64::from(u8::MAX) => Err(Error::InvalidParam),
+                        Ok(msg) => backend.set_status_async(msg.value as u8).await,
+                        Err(e) => Err(e),
+                    };
+                    handler.ack_request(&hdr, res)
+                }
+                MasterReq::GET_STATUS => {
+                    handler.check_protocol_feature(VhostUserProtocolFeatures::STATUS)?;
+                    handler.check_request_size(&hdr, size, 0)?;
+                    let status = backend.get_status_async().await?;
+                    handler.send_reply_message(&hdr, &VhostUserU64::new(u64::from(status)))
+                }
+                MasterReq::CHECK_DEVICE_STATE => {
+                    handler.check_protocol_feature(VhostUserProtocolFeatures::DEVICE_STATE)?;
+                    handler.check_request_size(&hdr, size, 0)?;
+                    let res = backend.check_device_state_async().await;
+                    handler.send_result_message(&hdr, res)
+                }
+                _ => handler.serve_dispatched(hdr, size, buf, files),
+            }
+        }
+        .await;
+        handler.record_request(&hdr, start.elapsed(), res.is_err());
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhost_user::connection::Endpoint;
+    use crate::vhost_user::dummy_slave::{DummySlaveConfig, DummySlaveReqHandler};
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn test_async_slave_req_handler() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let config = DummySlaveConfig::new().config_space(&[1, 2, 3, 4]);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::with_config(&config)));
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+
+        block_on(async {
+            let mut handler = AsyncSlaveReqHandler::from_stream(p1, backend.clone()).unwrap();
+            // The message loop can be spawned as a task of a multi-threaded runtime.
+            assert_send(&handler.handle_request());
+
+            // Requests without an async service are served by the blocking handler.
+            let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+            master.send_header(&hdr, None).unwrap();
+            handler.handle_request().await.unwrap();
+            let features = VhostUserProtocolFeatures::CONFIG.bits();
+            let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x1, 8);
+            master
+                .send_message(&hdr, &VhostUserU64::new(features), None)
+                .unwrap();
+            handler.handle_request().await.unwrap();
+
+            let flags = VhostUserConfigFlags::WRITABLE;
+            let msg = VhostUserConfig::new(VHOST_USER_CONFIG_OFFSET + 2, 2, flags);
+            let hdr = VhostUserMsgHeader::new(MasterReq::SET_CONFIG, 0x1, 14);
+            master
+                .send_message_with_payload(&hdr, &msg, &[5, 6], None)
+                .unwrap();
+            handler.handle_request().await.unwrap();
+
+            let msg = VhostUserConfig::new(VHOST_USER_CONFIG_OFFSET, 4, flags);
+            let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x1, 16);
+            master
+                .send_message_with_payload(&hdr, &msg, &[0; 4], None)
+                .unwrap();
+            handler.handle_request().await.unwrap();
+            let (hdr, _) = master.recv_header().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::GET_CONFIG);
+            let (_, buf) = master.recv_data(hdr.get_size() as usize).unwrap();
+            assert_eq!(&buf[12..], &[1, 2, 5, 6]);
+
+            // Services not negotiated fail as with the blocking handler.
+            let hdr = VhostUserMsgHeader::new(MasterReq::GET_STATUS, 0x1, 0);
+            master.send_header(&hdr, None).unwrap();
+            match handler.handle_request().await.unwrap_err() {
+                Error::ProtocolFeatureNotNegotiated(f) => {
+                    assert_eq!(f, VhostUserProtocolFeatures::STATUS)
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+
+            // The requests served asynchronously are accounted for along with the others.
+            let stats = handler.get_ref().stats();
+            assert_eq!(stats[&(MasterReq::SET_OWNER as u32)].count, 1);
+            assert_eq!(stats[&(MasterReq::SET_CONFIG as u32)].count, 1);
+            assert_eq!(stats[&(MasterReq::GET_STATUS as u32)].failures, 1);
+        });
+    }
+}
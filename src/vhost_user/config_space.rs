@@ -0,0 +1,149 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Layout of the device configuration space declared by slaves, to validate the accesses of the
+//! master before they reach the backend.
+
+use std::ops::Range;
+
+use super::message::{VhostUserConfigFlags, VHOST_USER_CONFIG_OFFSET};
+use super::{Error, Result};
+
+/// Layout of the device configuration space of a slave, see
+/// [SlaveReqHandler::set_config_space()].
+///
+/// Offsets are relative to the start of the device configuration space, which is at
+/// `VHOST_USER_CONFIG_OFFSET` in the GET_CONFIG and SET_CONFIG requests.
+///
+/// [SlaveReqHandler::set_config_space()]: struct.SlaveReqHandler.html#method.set_config_space
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigSpace {
+    size: u32,
+    // Sorted ranges written by the driver, adjacent ranges being merged.
+    writable: Vec<Range<u32>>,
+}
+
+impl ConfigSpace {
+    /// Declare a read-only configuration space of `size` bytes.
+    pub fn new(size: u32) -> Self {
+        ConfigSpace {
+            size,
+            writable: Vec::new(),
+        }
+    }
+
+    /// Let the driver write the `len` bytes at `offset` with SET_CONFIG.
+    pub fn writable(mut self, offset: u32, len: u32) -> Self {
+        let end = offset.saturating_add(len);
+        let pos = self
+            .writable
+            .iter()
+            .position(|range| range.start > offset)
+            .unwrap_or(self.writable.len());
+        self.writable.insert(pos, offset..end);
+
+        let mut merged: Vec<Range<u32>> = Vec::with_capacity(self.writable.len());
+        for range in self.writable.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.writable = merged;
+        self
+    }
+
+    /// Get the size of the configuration space.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Check whether the driver may write the `len` bytes at `offset`.
+    pub fn is_writable(&self, offset: u32, len: u32) -> bool {
+        let end = match offset.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        self.writable
+            .iter()
+            .any(|range| range.start <= offset && end <= range.end)
+    }
+
+    // Check that the writable ranges are within the configuration space.
+    pub(super) fn is_valid(&self) -> bool {
+        self.size > 0
+            && self
+                .writable
+                .last()
+                .iter()
+                .all(|range| range.end <= self.size)
+    }
+
+    /// Check an access of the master to the `size` bytes at `offset`, as carried by GET_CONFIG
+    /// or SET_CONFIG when `write` is set.
+    ///
+    /// The whole configuration space is written when migrating the device, as indicated by the
+    /// `LIVE_MIGRATION` flag, otherwise SET_CONFIG is limited to the writable ranges.
+    ///
+    /// # Return:
+    /// * - InvalidParam: the access is beyond the configuration space, or writes a read-only
+    ///     range.
+    pub fn check_access(
+        &self,
+        offset: u32,
+        size: u32,
+        flags: VhostUserConfigFlags,
+        write: bool,
+    ) -> Result<()> {
+        let start = offset
+            .checked_sub(VHOST_USER_CONFIG_OFFSET)
+            .ok_or(Error::InvalidParam)?;
+        match start.checked_add(size) {
+            Some(end) if size > 0 && end <= self.size => {}
+            _ => return Err(Error::InvalidParam),
+        }
+        if write
+            && !flags.contains(VhostUserConfigFlags::LIVE_MIGRATION)
+            && !self.is_writable(start, size)
+        {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert!(!ConfigSpace::new(0).is_valid());
+        assert!(!ConfigSpace::new(0x10).writable(0xc, 8).is_valid());
+
+        let space = ConfigSpace::new(0x40)
+            .writable(0x20, 4)
+            .writable(0x8, 4)
+            .writable(0xc, 4)
+            .writable(0x22, 8);
+        assert!(space.is_valid());
+        assert_eq!(space.size(), 0x40);
+        assert_eq!(space.writable, vec![0x8..0x10, 0x20..0x2a]);
+        assert!(space.is_writable(0x8, 8));
+        assert!(!space.is_writable(0x8, 9));
+        assert!(!space.is_writable(u32::MAX, 2));
+
+        let offset = VHOST_USER_CONFIG_OFFSET;
+        let flags = VhostUserConfigFlags::WRITABLE;
+        space.check_access(offset, 0x40, flags, false).unwrap();
+        space.check_access(offset + 0x20, 0xa, flags, true).unwrap();
+        space.check_access(offset, 0x41, flags, false).unwrap_err();
+        space.check_access(offset, 0, flags, false).unwrap_err();
+        space.check_access(offset - 1, 1, flags, false).unwrap_err();
+        space.check_access(u32::MAX, 2, flags, false).unwrap_err();
+        space.check_access(offset, 4, flags, true).unwrap_err();
+        // Migrating the device restores the whole configuration space.
+        let flags = VhostUserConfigFlags::LIVE_MIGRATION;
+        space.check_access(offset, 0x40, flags, true).unwrap();
+    }
+}
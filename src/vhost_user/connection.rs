@@ -8,9 +8,12 @@
 use std::fs::File;
 use std::io::ErrorKind;
 use std::marker::PhantomData;
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::net::Shutdown;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 use std::{mem, slice};
 
 use libc::{c_void, iovec};
@@ -20,10 +23,17 @@ use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 use super::message::*;
 use super::{Error, Result};
 
+// First file descriptor passed by systemd with socket activation, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Serializes taking the sockets passed by systemd, so a socket is never owned twice.
+static SYSTEMD_FDS_LOCK: Mutex<()> = Mutex::new(());
+
 /// Unix domain socket listener for accepting incoming connections.
 pub struct Listener {
     fd: UnixListener,
-    path: PathBuf,
+    // path of the socket, removed with the listener unless it belongs to systemd
+    path: Option<PathBuf>,
 }
 
 impl Listener {
@@ -39,7 +49,72 @@ impl Listener {
         let fd = UnixListener::bind(&path).map_err(Error::SocketError)?;
         Ok(Listener {
             fd,
-            path: path.as_ref().to_owned(),
+            path: Some(path.as_ref().to_owned()),
+        })
+    }
+
+    /// Create a listener from a socket passed by systemd with socket activation, so the slave
+    /// accepts the connection of the master even if the master starts first.
+    ///
+    /// The sockets passed by systemd are described by the LISTEN_PID, LISTEN_FDS and
+    /// LISTEN_FDNAMES environment variables, see sd_listen_fds(3). The socket named `name` by
+    /// the `FileDescriptorName=` setting of the socket unit is used, or the first socket with
+    /// `None`. The listener owns the socket, so each socket is taken at most once per process
+    /// and the socket file is left in place when the listener is dropped. The sockets are passed
+    /// without `FD_CLOEXEC`, which the listener sets to mark the socket as taken, so they must
+    /// not be used otherwise by the process.
+    ///
+    /// # Return:
+    /// * - the new Listener object on success.
+    /// * - InvalidParam: no such socket was passed to the process, the socket is already owned
+    ///     by a listener, or the socket isn't a listening unix domain stream socket.
+    /// * - SocketError: failed to get the socket options.
+    pub fn from_systemd(name: Option<&str>) -> Result<Self> {
+        let var = |key| std::env::var(key).ok();
+        let fd = systemd_listen_fd(
+            var("LISTEN_PID").as_deref(),
+            var("LISTEN_FDS").as_deref(),
+            var("LISTEN_FDNAMES").as_deref(),
+            std::process::id(),
+            name,
+        )?;
+        Self::take_listen_fd(fd)
+    }
+
+    // Create a listener owning the socket `fd` passed by systemd, unless a listener already took
+    // it and set its FD_CLOEXEC flag.
+    fn take_listen_fd(fd: RawFd) -> Result<Self> {
+        let _guard = SYSTEMD_FDS_LOCK.lock().unwrap();
+        // Safe because the call has no side effect and the return value is checked.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(Error::SocketError(std::io::Error::last_os_error()));
+        }
+        if flags & libc::FD_CLOEXEC != 0 {
+            return Err(Error::InvalidParam);
+        }
+        Self::from_listen_fd(fd)
+    }
+
+    // Create a listener owning the listening socket `fd`, which is left open if it isn't a
+    // listening unix domain stream socket.
+    fn from_listen_fd(fd: RawFd) -> Result<Self> {
+        let domain = get_sockopt_int(fd, libc::SO_DOMAIN)?;
+        let kind = get_sockopt_int(fd, libc::SO_TYPE)?;
+        let listening = get_sockopt_int(fd, libc::SO_ACCEPTCONN)?;
+        if domain != libc::AF_UNIX || kind != libc::SOCK_STREAM || listening == 0 {
+            return Err(Error::InvalidParam);
+        }
+        // The sockets passed by systemd are inherited across exec, unlike the ones created by
+        // the crate.
+        // Safe because the return value is checked.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(Error::SocketError(std::io::Error::last_os_error()));
+        }
+        Ok(Listener {
+            // Safe because the caller passes the ownership of the socket.
+            fd: unsafe { UnixListener::from_raw_fd(fd) },
+            path: None,
         })
     }
 
@@ -84,15 +159,227 @@ impl AsRawFd for Listener {
     }
 }
 
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd {
+        self.fd.as_fd()
+    }
+}
+
 impl Drop for Listener {
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.path);
+        if let Some(path) = self.path.as_ref() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Get the socket named `name`, or the first one, among the sockets passed by systemd to the
+// process `pid`, given the values of the LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES variables.
+fn systemd_listen_fd(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+    name: Option<&str>,
+) -> Result<RawFd> {
+    // The variables may have been inherited from the parent process.
+    match listen_pid.and_then(|v| v.parse::<u32>().ok()) {
+        Some(listen_pid) if listen_pid == pid => {}
+        _ => return Err(Error::InvalidParam),
+    }
+    let count = listen_fds
+        .and_then(|v| v.parse::<RawFd>().ok())
+        .ok_or(Error::InvalidParam)?;
+    let index = match name {
+        Some(name) => listen_fdnames
+            .and_then(|names| names.split(':').position(|n| n == name))
+            .ok_or(Error::InvalidParam)? as RawFd,
+        None => 0,
+    };
+    if index >= count {
+        return Err(Error::InvalidParam);
+    }
+    Ok(SD_LISTEN_FDS_START + index)
+}
+
+// Get the integer socket option `opt` of the socket `fd`.
+fn get_sockopt_int(fd: RawFd, opt: libc::c_int) -> Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safe because the kernel writes at most `len` bytes to `val`, and the return value is
+    // checked.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &mut val as *mut libc::c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::SocketError(std::io::Error::last_os_error()));
+    }
+    Ok(val)
+}
+
+/// Credentials of the process at the other end of a connection, from SO_PEERCRED and SO_PEERSEC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Process id of the peer when it connected.
+    pub pid: i32,
+    /// Effective user id of the peer when it connected.
+    pub uid: u32,
+    /// Effective group id of the peer when it connected.
+    pub gid: u32,
+    /// Security context of the peer, such as its SELinux label, if a Linux security module
+    /// labels the socket.
+    pub security_context: Option<String>,
+}
+
+impl PeerCredentials {
+    /// Get the credentials of the peer of a connected socket.
+    ///
+    /// # Return:
+    /// * - SocketError: failed to get the socket options.
+    pub fn from_stream(sock: &UnixStream) -> Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // Safe because the kernel writes at most `len` bytes to `cred`, and the return value is
+        // checked.
+        let ret = unsafe {
+            libc::getsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SocketError(std::io::Error::last_os_error()));
+        }
+
+        Ok(PeerCredentials {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+            security_context: Self::security_context(sock)?,
+        })
+    }
+
+    // Get the security context of the peer, or `None` without a Linux security module labeling
+    // the socket.
+    fn security_context(sock: &UnixStream) -> Result<Option<String>> {
+        let mut buf = vec![0u8; 256];
+        loop {
+            let mut len = buf.len() as libc::socklen_t;
+            // Safe because the kernel writes at most `len` bytes to `buf`, and the return value
+            // is checked.
+            let ret = unsafe {
+                libc::getsockopt(
+                    sock.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PEERSEC,
+                    buf.as_mut_ptr() as *mut c_void,
+                    &mut len,
+                )
+            };
+            if ret == 0 {
+                buf.truncate(len as usize);
+                break;
+            }
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                // The kernel reports the size needed in `len`.
+                Some(libc::ERANGE) if len as usize > buf.len() => buf.resize(len as usize, 0),
+                Some(libc::ENOPROTOOPT) => return Ok(None),
+                _ => return Err(Error::SocketError(err)),
+            }
+        }
+        // The context may be NUL terminated.
+        while buf.last() == Some(&0) {
+            buf.pop();
+        }
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+    }
+}
+
+/// Callback deciding whether to accept a peer, given its credentials.
+pub type PeerCheck = Box<dyn Fn(&PeerCredentials) -> bool + Send + Sync>;
+
+/// Policy of the processes allowed to connect, checked against their [PeerCredentials].
+///
+/// A peer is accepted if its credentials match all the expected ids set, and the callback set by
+/// [Self::check()] accepts it. The default policy accepts any peer.
+///
+/// [PeerCredentials]: struct.PeerCredentials.html
+/// [Self::check()]: struct.PeerPolicy.html#method.check
+#[derive(Default)]
+pub struct PeerPolicy {
+    pid: Option<i32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    check: Option<PeerCheck>,
+}
+
+impl PeerPolicy {
+    /// Create a policy accepting any peer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accept the process `pid`.
+    pub fn pid(mut self, pid: i32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Only accept processes running as the user `uid`.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Only accept processes running as the group `gid`.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Only accept the processes accepted by `check`, such as by their security context.
+    pub fn check(mut self, check: PeerCheck) -> Self {
+        self.check = Some(check);
+        self
+    }
+
+    /// Check whether the policy accepts a peer.
+    ///
+    /// # Return:
+    /// * - PeerRejected: the peer isn't accepted.
+    pub fn verify(&self, cred: &PeerCredentials) -> Result<()> {
+        let accepted = self.pid.iter().all(|pid| *pid == cred.pid)
+            && self.uid.iter().all(|uid| *uid == cred.uid)
+            && self.gid.iter().all(|gid| *gid == cred.gid)
+            && self.check.iter().all(|check| check(cred));
+        if !accepted {
+            return Err(Error::PeerRejected(cred.clone()));
+        }
+        Ok(())
     }
 }
 
 /// Unix domain socket endpoint for vhost-user connection.
 pub(super) struct Endpoint<R: Req> {
     sock: UnixStream,
+    timeout: Option<Duration>,
     _r: PhantomData<R>,
 }
 
@@ -111,10 +398,124 @@ impl<R: Req> Endpoint<R> {
     pub fn from_stream(sock: UnixStream) -> Self {
         Endpoint {
             sock,
+            timeout: None,
             _r: PhantomData,
         }
     }
 
+    /// Create a new endpoint sharing the socket of this one.
+    ///
+    /// # Return:
+    /// * - the new Endpoint object on success.
+    /// * - SocketError: failed to duplicate the socket.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Endpoint {
+            sock: self.sock.try_clone().map_err(Error::SocketError)?,
+            timeout: self.timeout,
+            _r: PhantomData,
+        })
+    }
+
+    /// Shut down both directions of the socket, so the peer and the pending operations on the
+    /// endpoint see the connection closed.
+    ///
+    /// # Return:
+    /// * - SocketError: failure from shutdown().
+    pub fn shutdown(&self) -> Result<()> {
+        self.sock
+            .shutdown(Shutdown::Both)
+            .map_err(Error::SocketError)
+    }
+
+    /// Change blocking status on the endpoint.
+    ///
+    /// # Return:
+    /// * - () on success.
+    /// * - SocketError: failure from set_nonblocking().
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.sock
+            .set_nonblocking(nonblocking)
+            .map_err(Error::SocketError)
+    }
+
+    /// Set the timeout of socket send and receive operations, or make them block forever if
+    /// `timeout` is `None`.
+    ///
+    /// # Return:
+    /// * - InvalidParam: `timeout` is zero.
+    /// * - SocketError: failed to set the socket options.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidParam);
+        }
+        self.sock
+            .set_read_timeout(timeout)
+            .and_then(|_| self.sock.set_write_timeout(timeout))
+            .map_err(Error::SocketError)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Get the timeout of socket send and receive operations.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Get the credentials of the peer.
+    ///
+    /// # Return:
+    /// * - SocketError: failed to get the socket options.
+    pub fn peer_credentials(&self) -> Result<PeerCredentials> {
+        PeerCredentials::from_stream(&self.sock)
+    }
+
+    /// Check whether `policy` accepts the peer, returning its credentials if so.
+    ///
+    /// # Return:
+    /// * - PeerRejected: the peer isn't accepted.
+    /// * - SocketError: failed to get the socket options.
+    pub fn check_peer(&self, policy: &PeerPolicy) -> Result<PeerCredentials> {
+        let cred = self.peer_credentials()?;
+        policy.verify(&cred)?;
+        Ok(cred)
+    }
+
+    /// Discard all data already queued on the socket, including attached file descriptors.
+    ///
+    /// # Return:
+    /// * - SocketBroken: the underline socket is broken.
+    /// * - SocketError: other socket related errors.
+    pub fn drain(&mut self) -> Result<()> {
+        self.sock
+            .set_nonblocking(true)
+            .map_err(Error::SocketError)?;
+        let res = loop {
+            match self.recv_into_buf(MAX_MSG_SIZE) {
+                Ok((0, _, _)) => break Ok(()),
+                Ok(_) => {}
+                Err(Error::SocketRetry(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Err(Error::SocketRetry(_)) | Err(Error::Timeout) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.sock
+            .set_nonblocking(false)
+            .map_err(Error::SocketError)?;
+        res
+    }
+
+    // A send or receive operation timed out if it would block on a socket with a timeout.
+    fn check_timeout(&self, err: Error) -> Error {
+        match err {
+            Error::SocketRetry(e)
+                if self.timeout.is_some() && e.kind() == ErrorKind::WouldBlock =>
+            {
+                Error::Timeout
+            }
+            e => e,
+        }
+    }
+
     /// Sends bytes from scatter-gather vectors over the socket with optional attached file
     /// descriptors.
     ///
@@ -122,13 +523,16 @@ impl<R: Req> Endpoint<R> {
     /// * - number of bytes sent on success
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     pub fn send_iovec(&mut self, iovs: &[&[u8]], fds: Option<&[RawFd]>) -> Result<usize> {
         let rfds = match fds {
             Some(rfds) => rfds,
             _ => &[],
         };
-        self.sock.send_with_fds(iovs, rfds).map_err(Into::into)
+        self.sock
+            .send_with_fds(iovs, rfds)
+            .map_err(|e| self.check_timeout(e.into()))
     }
 
     /// Sends all bytes from scatter-gather vectors over the socket with optional attached file
@@ -137,6 +541,7 @@ impl<R: Req> Endpoint<R> {
     /// # Return:
     /// * - number of bytes sent on success
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     pub fn send_iovec_all(&mut self, iovs: &[&[u8]], fds: Option<&[RawFd]>) -> Result<usize> {
         let mut data_sent = 0;
@@ -172,6 +577,7 @@ impl<R: Req> Endpoint<R> {
     /// * - number of bytes sent on success
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     pub fn send_slice(&mut self, data: &[u8], fds: Option<&[RawFd]>) -> Result<usize> {
         self.send_iovec(&[data], fds)
@@ -183,6 +589,7 @@ impl<R: Req> Endpoint<R> {
     /// * - number of bytes sent on success
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     /// * - PartialMessage: received a partial message.
     pub fn send_header(
@@ -211,6 +618,7 @@ impl<R: Req> Endpoint<R> {
     /// * - number of bytes sent on success
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     /// * - PartialMessage: received a partial message.
     pub fn send_message<T: Sized>(
@@ -246,6 +654,7 @@ impl<R: Req> Endpoint<R> {
     /// * - number of bytes sent on success
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     /// * - OversizedMsg: message size is too big.
     /// * - PartialMessage: received a partial message.
@@ -295,6 +704,7 @@ impl<R: Req> Endpoint<R> {
     /// * - (number of bytes received, buf) on success
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     pub fn recv_data(&mut self, len: usize) -> Result<(usize, Vec<u8>)> {
         let mut rbuf = vec![0u8; len];
@@ -302,7 +712,8 @@ impl<R: Req> Endpoint<R> {
             iov_base: rbuf.as_mut_ptr() as *mut c_void,
             iov_len: len,
         }];
-        let (bytes, _) = self.sock.recv_with_fds(&mut iovs, &mut [])?;
+        let res = self.sock.recv_with_fds(&mut iovs, &mut []);
+        let (bytes, _) = res.map_err(|e| self.check_timeout(e.into()))?;
         Ok((bytes, rbuf))
     }
 
@@ -323,10 +734,12 @@ impl<R: Req> Endpoint<R> {
     /// * - (number of bytes received, [received files]) on success
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     pub fn recv_into_iovec(&mut self, iovs: &mut [iovec]) -> Result<(usize, Option<Vec<File>>)> {
         let mut fd_array = vec![0; MAX_ATTACHED_FD_ENTRIES];
-        let (bytes, fds) = self.sock.recv_with_fds(iovs, &mut fd_array)?;
+        let res = self.sock.recv_with_fds(iovs, &mut fd_array);
+        let (bytes, fds) = res.map_err(|e| self.check_timeout(e.into()))?;
 
         let files = match fds {
             0 => None,
@@ -362,6 +775,7 @@ impl<R: Req> Endpoint<R> {
     /// # Return:
     /// * - (number of bytes received, [received fds]) on success
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     pub fn recv_into_iovec_all(
         &mut self,
@@ -413,6 +827,7 @@ impl<R: Req> Endpoint<R> {
     /// * - (number of bytes received, buf, [received files]) on success.
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     pub fn recv_into_buf(
         &mut self,
@@ -437,6 +852,7 @@ impl<R: Req> Endpoint<R> {
     /// * - (message header, [received files]) on success.
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     /// * - PartialMessage: received a partial message.
     /// * - InvalidMessage: received a invalid message.
@@ -465,6 +881,7 @@ impl<R: Req> Endpoint<R> {
     /// * - (message header, message body, [received files]) on success.
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     /// * - PartialMessage: received a partial message.
     /// * - InvalidMessage: received a invalid message.
@@ -506,6 +923,7 @@ impl<R: Req> Endpoint<R> {
     /// * - (message header, message size, [received files]) on success.
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     /// * - PartialMessage: received a partial message.
     /// * - InvalidMessage: received a invalid message.
@@ -543,6 +961,7 @@ impl<R: Req> Endpoint<R> {
     /// * - (message header, message body, size of payload, [received files]) on success.
     /// * - SocketRetry: temporary error caused by signals or short of resources.
     /// * - SocketBroken: the underline socket is broken.
+    /// * - Timeout: the socket operation timed out.
     /// * - SocketError: other socket related errors.
     /// * - PartialMessage: received a partial message.
     /// * - InvalidMessage: received a invalid message.
@@ -586,6 +1005,56 @@ impl<T: Req> AsRawFd for Endpoint<T> {
     }
 }
 
+impl<T: Req> AsFd for Endpoint<T> {
+    fn as_fd(&self) -> BorrowedFd {
+        self.sock.as_fd()
+    }
+}
+
+// Check whether a whole message is ready to be received from `fd`, without consuming it, for
+// the async request handlers to wait for it before receiving it with a blocking `Endpoint`.
+//
+// Errors other than `WouldBlock`, and the end of the stream, are left for the handler to report
+// when it receives the message.
+#[cfg(any(
+    feature = "vhost-user-master-async",
+    feature = "vhost-user-slave-async"
+))]
+pub(super) fn peek_message<R: Req>(fd: RawFd) -> std::io::Result<()> {
+    let hdr_size = mem::size_of::<VhostUserMsgHeader<R>>();
+    let mut buf = vec![0u8; hdr_size + MAX_MSG_SIZE];
+    // Safe because the buffer is large enough, and the return value is checked.
+    let ret = unsafe {
+        libc::recv(
+            fd,
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.kind() {
+            ErrorKind::WouldBlock => Err(err),
+            _ => Ok(()),
+        };
+    }
+    let len = ret as usize;
+    if len == 0 {
+        return Ok(());
+    }
+    // The size of the body is the last field of the header.
+    if len >= hdr_size {
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&buf[hdr_size - 4..hdr_size]);
+        let size = u32::from_ne_bytes(size) as usize;
+        if size > MAX_MSG_SIZE || len >= hdr_size + size {
+            return Ok(());
+        }
+    }
+    Err(std::io::Error::from_raw_os_error(libc::EWOULDBLOCK))
+}
+
 // Given a slice of sizes and the `skip_size`, return the offset of `skip_size` in the slice.
 // For example:
 //     let iov_lens = vec![4, 4, 5];
@@ -610,6 +1079,7 @@ fn get_sub_iovs_offset(iov_lens: &[usize], skip_size: usize) -> (usize, usize) {
 mod tests {
     use super::*;
     use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::IntoRawFd;
     use vmm_sys_util::rand::rand_alphanumerics;
     use vmm_sys_util::tempfile::TempFile;
 
@@ -628,6 +1098,62 @@ mod tests {
         assert!(listener.as_raw_fd() > 0);
     }
 
+    #[test]
+    fn systemd_listener() {
+        let pid = std::process::id();
+        let pid_str = pid.to_string();
+        let listen_pid = Some(pid_str.as_str());
+        let names = Some("ctl:vhost");
+        assert_eq!(
+            systemd_listen_fd(listen_pid, Some("2"), names, pid, None).unwrap(),
+            3
+        );
+        assert_eq!(
+            systemd_listen_fd(listen_pid, Some("2"), names, pid, Some("vhost")).unwrap(),
+            4
+        );
+        systemd_listen_fd(listen_pid, Some("1"), names, pid, Some("vhost")).unwrap_err();
+        systemd_listen_fd(listen_pid, Some("2"), names, pid, Some("net")).unwrap_err();
+        systemd_listen_fd(listen_pid, Some("2"), None, pid, Some("ctl")).unwrap_err();
+        systemd_listen_fd(listen_pid, Some("0"), None, pid, None).unwrap_err();
+        systemd_listen_fd(listen_pid, None, None, pid, None).unwrap_err();
+        // The sockets were passed to another process.
+        systemd_listen_fd(Some("1"), Some("2"), names, pid, None).unwrap_err();
+        systemd_listen_fd(None, Some("2"), names, pid, None).unwrap_err();
+
+        // Only listening sockets are accepted.
+        let (p1, _p2) = UnixStream::pair().unwrap();
+        match Listener::from_listen_fd(p1.as_raw_fd()) {
+            Err(Error::InvalidParam) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        let file = TempFile::new().unwrap();
+        assert!(Listener::from_listen_fd(file.as_file().as_raw_fd()).is_err());
+
+        let path = temp_path();
+        let sock = UnixListener::bind(&path).unwrap();
+        let fd = sock.into_raw_fd();
+        // The sockets created by the process aren't inherited, unlike the ones passed by systemd.
+        assert!(Listener::take_listen_fd(fd).is_err());
+        // Safe because the call only clears the FD_CLOEXEC flag of the socket.
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }, 0);
+        let listener = Listener::take_listen_fd(fd).unwrap();
+        // A socket is owned by a single listener.
+        match Listener::take_listen_fd(fd) {
+            Err(Error::InvalidParam) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        // Safe because the call has no side effect on the listener.
+        let flags = unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+        let _stream = UnixStream::connect(&path).unwrap();
+        assert!(listener.accept().unwrap().is_some());
+        // The socket file belongs to systemd.
+        drop(listener);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn accept_connection() {
         let path = temp_path();
@@ -639,6 +1165,28 @@ mod tests {
         assert!(conn.is_none());
     }
 
+    #[test]
+    fn peer_credentials() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let cred = endpoint.peer_credentials().unwrap();
+        assert_eq!(cred, PeerCredentials::from_stream(&p2).unwrap());
+        assert_eq!(cred.pid, std::process::id() as i32);
+        // Safe because the calls have no side effects.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        assert_eq!((cred.uid, cred.gid), (uid, gid));
+
+        endpoint.check_peer(&PeerPolicy::new()).unwrap();
+        let policy = PeerPolicy::new().pid(cred.pid).uid(uid).gid(gid);
+        assert_eq!(endpoint.check_peer(&policy).unwrap(), cred);
+        match endpoint.check_peer(&PeerPolicy::new().uid(uid.wrapping_add(1))) {
+            Err(Error::PeerRejected(c)) => assert_eq!(c, cred),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let policy = PeerPolicy::new().uid(uid).check(Box::new(|_| false));
+        endpoint.check_peer(&policy).unwrap_err();
+    }
+
     #[test]
     fn send_data() {
         let path = temp_path();
@@ -0,0 +1,242 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dirty page log shared with vhost-user slaves during live migration.
+
+use std::fs::File;
+use std::io::Error as IOError;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd};
+use std::ptr::null_mut;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use vm_memory::{Address, GuestAddress, GuestMemory};
+
+use super::message::VHOST_LOG_PAGE;
+use crate::backend::{VhostLogOps, VhostUserDirtyLogRegion};
+use crate::{Error, Result};
+
+const BITS_PER_WORD: u64 = 64;
+
+/// Memfd backed dirty page log shared with a vhost-user slave.
+///
+/// Bit `n` of the log is set by the slave when it writes to the guest page starting at
+/// `n * VHOST_LOG_PAGE`. The bits are read and cleared atomically, so the log may be harvested
+/// while the slave keeps logging.
+pub struct DirtyLog {
+    file: File,
+    addr: *mut AtomicU64,
+    words: usize,
+}
+
+// Safe because the mapping is only accessed through atomic operations.
+unsafe impl Send for DirtyLog {}
+unsafe impl Sync for DirtyLog {}
+
+impl DirtyLog {
+    /// Create a log tracking all pages of the guest memory `mem`.
+    pub fn new<M: GuestMemory>(mem: &M) -> Result<Self> {
+        if mem.num_regions() == 0 {
+            return Err(Error::InvalidGuestMemory);
+        }
+        Self::with_size(mem.last_addr().raw_value() + 1)
+    }
+
+    /// Create a log tracking the first `mem_size` bytes of guest memory.
+    pub fn with_size(mem_size: u64) -> Result<Self> {
+        if mem_size == 0 {
+            return Err(Error::InvalidGuestMemory);
+        }
+        let pages = (mem_size - 1) / VHOST_LOG_PAGE + 1;
+        let words = ((pages - 1) / BITS_PER_WORD + 1) as usize;
+        let size = words * std::mem::size_of::<u64>();
+
+        // Safe because the name is a valid C string and the return value is checked.
+        let fd = unsafe { libc::memfd_create("vhost_log\0".as_ptr() as *const libc::c_char, 0) };
+        if fd < 0 {
+            return Err(Error::IOError(IOError::last_os_error()));
+        }
+        // Safe because the fd was just created and is owned by nobody else.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size as u64).map_err(Error::IOError)?;
+
+        // Safe because the file is big enough for the mapping and the return value is checked.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::IOError(IOError::last_os_error()));
+        }
+
+        Ok(DirtyLog {
+            file,
+            addr: addr as *mut AtomicU64,
+            words,
+        })
+    }
+
+    /// Size of the log in bytes.
+    pub fn size(&self) -> u64 {
+        (self.words * std::mem::size_of::<u64>()) as u64
+    }
+
+    /// Get the file backing the log.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Get the region description to pass to `VhostLogOps::set_log_base()`.
+    pub fn region(&self) -> VhostUserDirtyLogRegion {
+        VhostUserDirtyLogRegion {
+            mmap_size: self.size(),
+            mmap_offset: 0,
+            mmap_handle: self.file.as_fd(),
+        }
+    }
+
+    /// Share the log with the slave behind `backend` with a SET_LOG_BASE request.
+    pub fn set_log_base<B: VhostLogOps>(&self, backend: &B) -> Result<()> {
+        backend.set_log_base(0, Some(self.region()))
+    }
+
+    /// Check whether the page containing `gpa` is dirty, without clearing it.
+    pub fn is_dirty(&self, gpa: GuestAddress) -> bool {
+        let page = gpa.raw_value() / VHOST_LOG_PAGE;
+        match self.bitmap().get((page / BITS_PER_WORD) as usize) {
+            Some(word) => word.load(Ordering::SeqCst) & (1 << (page % BITS_PER_WORD)) != 0,
+            None => false,
+        }
+    }
+
+    /// Read and clear the dirty bits of the pages in `[start, start + len)`.
+    ///
+    /// Return the guest addresses of the dirty pages, pages beyond the end of the log are
+    /// ignored.
+    pub fn take_dirty_pages(&self, start: GuestAddress, len: u64) -> Vec<GuestAddress> {
+        let mut pages = Vec::new();
+        if len == 0 {
+            return pages;
+        }
+        let first = start.raw_value() / VHOST_LOG_PAGE;
+        let last = start
+            .raw_value()
+            .saturating_add(len - 1)
+            .min(self.size() * 8 * VHOST_LOG_PAGE - 1)
+            / VHOST_LOG_PAGE;
+        let mut page = first;
+        while page <= last {
+            let idx = page / BITS_PER_WORD;
+            let lo = page % BITS_PER_WORD;
+            let hi = (last - idx * BITS_PER_WORD).min(BITS_PER_WORD - 1);
+            let mask = (u64::MAX >> (BITS_PER_WORD - 1 - hi)) & (u64::MAX << lo);
+            let mut bits = self.bitmap()[idx as usize].fetch_and(!mask, Ordering::SeqCst) & mask;
+            while bits != 0 {
+                let bit = u64::from(bits.trailing_zeros());
+                pages.push(GuestAddress((idx * BITS_PER_WORD + bit) * VHOST_LOG_PAGE));
+                bits &= bits - 1;
+            }
+            page = (idx + 1) * BITS_PER_WORD;
+        }
+        pages
+    }
+
+    /// Read and clear the whole log.
+    ///
+    /// Return the bitmap with one bit per page, in the layout shared with the slave.
+    pub fn take_bitmap(&self) -> Vec<u64> {
+        self.bitmap()
+            .iter()
+            .map(|word| word.swap(0, Ordering::SeqCst))
+            .collect()
+    }
+
+    fn bitmap(&self) -> &[AtomicU64] {
+        // Safe because the mapping covers `words` words and lives as long as `self`.
+        unsafe { slice::from_raw_parts(self.addr, self.words) }
+    }
+}
+
+impl Drop for DirtyLog {
+    fn drop(&mut self) {
+        // Safe because the mapping was created in `with_size()` and is not used anymore.
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size() as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestMemoryMmap;
+
+    #[test]
+    fn test_dirty_log_size() {
+        assert!(DirtyLog::with_size(0).is_err());
+        assert_eq!(DirtyLog::with_size(1).unwrap().size(), 8);
+        assert_eq!(DirtyLog::with_size(64 * VHOST_LOG_PAGE).unwrap().size(), 8);
+        assert_eq!(
+            DirtyLog::with_size(64 * VHOST_LOG_PAGE + 1).unwrap().size(),
+            16
+        );
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x100_0000), 0x10_0000),
+        ])
+        .unwrap();
+        let log = DirtyLog::new(&mem).unwrap();
+        // 0x110_0000 bytes of guest memory make 0x1100 pages.
+        assert_eq!(log.size(), 0x1100 / 8);
+        let region = log.region();
+        assert_eq!(region.mmap_size, log.size());
+        assert_eq!(region.mmap_offset, 0);
+        assert_eq!(region.mmap_handle.as_raw_fd(), log.file().as_raw_fd());
+        assert_eq!(log.file().metadata().unwrap().len(), log.size());
+    }
+
+    #[test]
+    fn test_dirty_log_take() {
+        let log = DirtyLog::with_size(256 * VHOST_LOG_PAGE).unwrap();
+        // Pages 0, 3, 63, 64 and 200 written by the slave.
+        log.bitmap()[0].store(1 | 1 << 3 | 1 << 63, Ordering::SeqCst);
+        log.bitmap()[1].store(1, Ordering::SeqCst);
+        log.bitmap()[3].store(1 << 8, Ordering::SeqCst);
+
+        assert!(log.is_dirty(GuestAddress(3 * VHOST_LOG_PAGE + 0x10)));
+        assert!(!log.is_dirty(GuestAddress(4 * VHOST_LOG_PAGE)));
+        assert!(!log.is_dirty(GuestAddress(0x1000 * VHOST_LOG_PAGE)));
+
+        let pages = log.take_dirty_pages(GuestAddress(0x1000), 64 * VHOST_LOG_PAGE);
+        assert_eq!(
+            pages,
+            vec![
+                GuestAddress(3 * VHOST_LOG_PAGE),
+                GuestAddress(63 * VHOST_LOG_PAGE),
+                GuestAddress(64 * VHOST_LOG_PAGE)
+            ]
+        );
+        assert!(log.take_dirty_pages(GuestAddress(0x1000), 0).is_empty());
+        assert!(log
+            .take_dirty_pages(GuestAddress(0x1000), 64 * VHOST_LOG_PAGE)
+            .is_empty());
+        assert!(log.is_dirty(GuestAddress(0)));
+
+        let pages = log.take_dirty_pages(GuestAddress(0), u64::MAX);
+        assert_eq!(
+            pages,
+            vec![GuestAddress(0), GuestAddress(200 * VHOST_LOG_PAGE)]
+        );
+
+        log.bitmap()[2].store(0xa5, Ordering::SeqCst);
+        assert_eq!(log.take_bitmap(), vec![0, 0, 0xa5, 0]);
+        assert_eq!(log.take_bitmap(), vec![0; 4]);
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dirty page log shared by the master with SET_LOG_BASE, written by the slave while the guest
+//! is migrated.
+
+use std::fs::File;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use super::mapped_memory::MappedRegion;
+use super::message::{
+    VhostUserLog, VhostUserMsgValidator, VhostUserSingleMemoryRegion, VHOST_LOG_PAGE,
+};
+use super::{Error, Result};
+
+/// Writer of the dirty page log of the master, marking the guest memory written by the slave.
+///
+/// Bit `n % 8` of byte `n / 8` of the log tracks the guest page at `n * VHOST_LOG_PAGE`. The master
+/// harvests the log while the slave writes it, so the bits are set with atomic operations and
+/// never cleared by the slave.
+pub struct DirtyLogWriter {
+    // Mapping of the log, as a region of `size` bytes at guest address 0.
+    region: MappedRegion,
+}
+
+impl DirtyLogWriter {
+    /// Map the log described by `log` from `file`.
+    pub fn new(log: &VhostUserLog, file: File) -> Result<Self> {
+        if !log.is_valid() {
+            return Err(Error::InvalidParam);
+        }
+        let region = VhostUserSingleMemoryRegion::new(0, log.mmap_size, 0, log.mmap_offset);
+        Ok(DirtyLogWriter {
+            region: MappedRegion::new(&region, file)?,
+        })
+    }
+
+    /// Get the file backing the log.
+    pub fn file(&self) -> &File {
+        self.region.file()
+    }
+
+    /// Get the size of the log in bytes, each tracking 8 guest pages.
+    pub fn size(&self) -> u64 {
+        self.region.memory_size()
+    }
+
+    /// Mark the `len` bytes of guest memory at `gpa` dirty.
+    ///
+    /// # Return:
+    /// * - InvalidParam: the range extends beyond the guest memory tracked by the log.
+    pub fn mark_dirty(&self, gpa: u64, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let last = gpa.checked_add(len - 1).ok_or(Error::InvalidParam)?;
+        let last_page = last / VHOST_LOG_PAGE;
+        if last_page / 8 >= self.size() {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut page = gpa / VHOST_LOG_PAGE;
+        while page <= last_page {
+            let index = page / 8;
+            let first_bit = page % 8;
+            let last_bit = (last_page - index * 8).min(7);
+            let mask = (0xffu8 << first_bit) & (0xffu8 >> (7 - last_bit));
+            self.byte(index).fetch_or(mask, Ordering::SeqCst);
+            page = (index + 1) * 8;
+        }
+        Ok(())
+    }
+
+    /// Mark the `len` bytes written at `offset` in the used ring of a vring dirty, `log_addr`
+    /// being the guest address of the used ring passed with SET_VRING_ADDR.
+    pub fn mark_used(&self, log_addr: u64, offset: u64, len: u64) -> Result<()> {
+        let gpa = log_addr.checked_add(offset).ok_or(Error::InvalidParam)?;
+        self.mark_dirty(gpa, len)
+    }
+
+    fn byte(&self, index: u64) -> &AtomicU8 {
+        // Safe because the callers check that the byte is within the log, which stays mapped for
+        // the lifetime of self.
+        unsafe { &*((self.region.host_addr() + index) as *const AtomicU8) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn log_file(size: u64) -> File {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(size).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_dirty_log_writer() {
+        let file = log_file(0x1000);
+        let log = VhostUserLog::new(0, 0);
+        assert!(DirtyLogWriter::new(&log, file.try_clone().unwrap()).is_err());
+
+        // The log doesn't need to start at a page boundary of the file.
+        let log = VhostUserLog::new(0x40, 0x10);
+        let writer = DirtyLogWriter::new(&log, file.try_clone().unwrap()).unwrap();
+        assert_eq!(writer.size(), 0x40);
+
+        writer.mark_dirty(0, 0).unwrap();
+        writer.mark_dirty(0x10, 1).unwrap();
+        writer.mark_dirty(0x1fff, 2).unwrap();
+        // Pages 7 to 17 span three bytes of the log.
+        writer
+            .mark_dirty(7 * VHOST_LOG_PAGE + 0x800, 10 * VHOST_LOG_PAGE)
+            .unwrap();
+        // Page 0x101 is dirtied by a write to the used ring.
+        writer.mark_used(0x10_0000, 0x1000, 8).unwrap();
+
+        let mut buf = [0u8; 0x50];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[0x10], 0b1000_0111);
+        assert_eq!(buf[0x11], 0xff);
+        assert_eq!(buf[0x12], 0b0000_0011);
+        assert_eq!(buf[0x10 + 0x20], 0b0000_0010);
+        let dirty = buf.iter().filter(|b| **b != 0).count();
+        assert_eq!(dirty, 4);
+
+        // The log tracks 0x200 pages.
+        writer
+            .mark_dirty(0x1ff * VHOST_LOG_PAGE, VHOST_LOG_PAGE)
+            .unwrap();
+        writer
+            .mark_dirty(0x1ff * VHOST_LOG_PAGE, VHOST_LOG_PAGE + 1)
+            .unwrap_err();
+        writer.mark_dirty(u64::MAX, 2).unwrap_err();
+        writer.mark_used(u64::MAX, 1, 1).unwrap_err();
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[0x4f], 0x80);
+    }
+}
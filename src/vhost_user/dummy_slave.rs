@@ -1,44 +1,295 @@
 // Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+//! Configurable fake slave, recording the requests of the master, for the tests of the crate and
+//! of the crates built on it.
+
 use std::fs::File;
+use std::io::{Error as IOError, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::sync::Arc;
 
 use super::message::*;
 use super::*;
 
+/// Default number of queues of a [DummySlaveReqHandler].
+///
+/// [DummySlaveReqHandler]: struct.DummySlaveReqHandler.html
 pub const MAX_QUEUE_NUM: usize = 2;
+/// Maximum size of the queues of a [DummySlaveReqHandler].
+///
+/// [DummySlaveReqHandler]: struct.DummySlaveReqHandler.html
 pub const MAX_VRING_NUM: usize = 256;
+/// Maximum number of memory slots of a [DummySlaveReqHandler].
+///
+/// [DummySlaveReqHandler]: struct.DummySlaveReqHandler.html
 pub const MAX_MEM_SLOTS: usize = 32;
+/// Default virtio features of a [DummySlaveReqHandler].
+///
+/// [DummySlaveReqHandler]: struct.DummySlaveReqHandler.html
 pub const VIRTIO_FEATURES: u64 = 0x40000003;
+/// Code of the custom request echoed back by a [DummySlaveReqHandler].
+///
+/// [DummySlaveReqHandler]: struct.DummySlaveReqHandler.html
+pub const CUSTOM_ECHO_REQ: u32 = 0x1000;
+
+/// Configuration of a [DummySlaveReqHandler].
+///
+/// [DummySlaveReqHandler]: struct.DummySlaveReqHandler.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DummySlaveConfig {
+    queue_num: usize,
+    virtio_features: u64,
+    protocol_features: VhostUserProtocolFeatures,
+    config_space: Vec<u8>,
+}
+
+impl DummySlaveConfig {
+    /// Create the configuration of a slave of MAX_QUEUE_NUM queues, offering VIRTIO_FEATURES and
+    /// all the protocol features, with a configuration space filled with 0xa5.
+    pub fn new() -> Self {
+        DummySlaveConfig {
+            queue_num: MAX_QUEUE_NUM,
+            virtio_features: VIRTIO_FEATURES,
+            protocol_features: VhostUserProtocolFeatures::all(),
+            config_space: vec![0xa5; (VHOST_USER_CONFIG_SIZE - VHOST_USER_CONFIG_OFFSET) as usize],
+        }
+    }
+
+    /// Set the number of queues of the slave.
+    pub fn queue_num(mut self, queue_num: usize) -> Self {
+        self.queue_num = queue_num;
+        self
+    }
+
+    /// Set the virtio features offered by the slave.
+    pub fn virtio_features(mut self, features: u64) -> Self {
+        self.virtio_features = features;
+        self
+    }
+
+    /// Set the protocol features offered by the slave.
+    pub fn protocol_features(mut self, features: VhostUserProtocolFeatures) -> Self {
+        self.protocol_features = features;
+        self
+    }
+
+    /// Set the contents of the configuration space of the slave, which starts at offset
+    /// VHOST_USER_CONFIG_OFFSET and is truncated to VHOST_USER_CONFIG_SIZE.
+    pub fn config_space(mut self, config_space: &[u8]) -> Self {
+        let max = (VHOST_USER_CONFIG_SIZE - VHOST_USER_CONFIG_OFFSET) as usize;
+        self.config_space = config_space[..config_space.len().min(max)].to_vec();
+        self
+    }
+}
 
-#[derive(Default)]
+impl Default for DummySlaveConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fake slave serving the requests of the master without a device, recording their arguments
+/// in its fields.
+///
+/// Failures of the requests can be scripted with [Self::inject_error()].
+///
+/// [Self::inject_error()]: struct.DummySlaveReqHandler.html#method.inject_error
 pub struct DummySlaveReqHandler {
+    /// Whether the master owns the slave.
     pub owned: bool,
+    /// Whether the virtio features have been acked.
     pub features_acked: bool,
+    /// Virtio features acked by the master.
     pub acked_features: u64,
+    /// Protocol features acked by the master.
     pub acked_protocol_features: u64,
+    /// Number of queues of the slave.
     pub queue_num: usize,
-    pub vring_num: [u32; MAX_QUEUE_NUM],
-    pub vring_base: [u32; MAX_QUEUE_NUM],
-    pub call_fd: [Option<File>; MAX_QUEUE_NUM],
-    pub kick_fd: [Option<File>; MAX_QUEUE_NUM],
-    pub err_fd: [Option<File>; MAX_QUEUE_NUM],
-    pub vring_started: [bool; MAX_QUEUE_NUM],
-    pub vring_enabled: [bool; MAX_QUEUE_NUM],
+    /// Size of each queue.
+    pub vring_num: Vec<u32>,
+    /// Base of each queue.
+    pub vring_base: Vec<VringBase>,
+    /// Call eventfd of each queue.
+    pub call_fd: Vec<Option<File>>,
+    /// Kick eventfd of each queue.
+    pub kick_fd: Vec<Option<File>>,
+    /// Error eventfd of each queue.
+    pub err_fd: Vec<Option<File>>,
+    /// Whether each queue is started.
+    pub vring_started: Vec<bool>,
+    /// Whether each queue is enabled.
+    pub vring_enabled: Vec<bool>,
+    /// Inflight I/O tracking buffer returned by GET_INFLIGHT_FD.
     pub inflight_file: Option<File>,
+    /// Id of the connection of the master, from `master_connected()`.
+    pub master_id: Option<u64>,
+    /// Whether the postcopy migration is listening.
+    pub postcopy_listening: bool,
+    /// Regions mapped for the postcopy migration.
+    pub postcopy_regions: Vec<PostcopyRegion>,
+    /// Regions mapped by the [SlaveReqHandler].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    pub mapped_regions: Vec<Arc<MappedRegion>>,
+    /// Inflight I/O tracking buffer mapped by the [SlaveReqHandler].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    pub inflight_region: Option<Arc<InflightRegion>>,
+    /// Dirty page log mapped by the [SlaveReqHandler].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    pub dirty_log: Option<Arc<DirtyLogWriter>>,
+    /// Device status.
+    pub status: u8,
+    /// Internal device state, saved and loaded by the device state transfers.
+    pub device_state: Vec<u8>,
+    /// Pipe the device state is loaded from.
+    pub device_state_load: Option<File>,
+    /// Virtio features offered.
+    pub virtio_features: u64,
+    /// Protocol features offered.
+    pub protocol_features: VhostUserProtocolFeatures,
+    /// Contents of the configuration space, from offset VHOST_USER_CONFIG_OFFSET.
+    pub config_space: Vec<u8>,
+    // errno of the next failures of the requests
+    errors: Vec<(MasterReq, i32)>,
 }
 
 impl DummySlaveReqHandler {
+    /// Create a slave with the default configuration of [DummySlaveConfig].
+    ///
+    /// [DummySlaveConfig]: struct.DummySlaveConfig.html
     pub fn new() -> Self {
+        Self::with_config(&DummySlaveConfig::new())
+    }
+
+    /// Create a slave with the configuration `config`.
+    pub fn with_config(config: &DummySlaveConfig) -> Self {
+        let queue_num = config.queue_num;
         DummySlaveReqHandler {
-            queue_num: MAX_QUEUE_NUM,
-            ..Default::default()
+            owned: false,
+            features_acked: false,
+            acked_features: 0,
+            acked_protocol_features: 0,
+            queue_num,
+            vring_num: vec![0; queue_num],
+            vring_base: vec![VringBase::default(); queue_num],
+            call_fd: (0..queue_num).map(|_| None).collect(),
+            kick_fd: (0..queue_num).map(|_| None).collect(),
+            err_fd: (0..queue_num).map(|_| None).collect(),
+            vring_started: vec![false; queue_num],
+            vring_enabled: vec![false; queue_num],
+            inflight_file: None,
+            master_id: None,
+            postcopy_listening: false,
+            postcopy_regions: Vec::new(),
+            mapped_regions: Vec::new(),
+            inflight_region: None,
+            dirty_log: None,
+            status: 0,
+            device_state: Vec::new(),
+            device_state_load: None,
+            virtio_features: config.virtio_features,
+            protocol_features: config.protocol_features,
+            config_space: config.config_space.clone(),
+            errors: Vec::new(),
         }
     }
+
+    /// Make the next request `req` fail with `errno`.
+    ///
+    /// Injecting errors for the same request several times fails as many successive requests.
+    pub fn inject_error(&mut self, req: MasterReq, errno: i32) {
+        self.errors.push((req, errno));
+    }
+
+    // Fail with the first error injected for `req`, if any.
+    fn check_injected(&mut self, req: MasterReq) -> Result<()> {
+        match self.errors.iter().position(|(r, _)| *r == req) {
+            Some(index) => {
+                let (_, errno) = self.errors.remove(index);
+                Err(Error::ReqHandlerError(IOError::from_raw_os_error(errno)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Get the range of the configuration space accessed by `size` bytes at `offset`.
+    fn config_range(&self, offset: u32, size: u32) -> Result<std::ops::Range<usize>> {
+        if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
+            return Err(Error::InvalidOperation);
+        }
+        let start = offset
+            .checked_sub(VHOST_USER_CONFIG_OFFSET)
+            .ok_or(Error::InvalidParam)? as usize;
+        let end = start + size as usize;
+        if end > self.config_space.len() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(start..end)
+    }
+
+    // Map a region of anonymous memory, which may be registered with a userfaultfd.
+    fn map_postcopy_region(&mut self, size: u64) -> Result<u64> {
+        let size = size as usize;
+        // Safe because the mapping is anonymous, and unmapped on drop.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::InvalidParam);
+        }
+        self.postcopy_regions.push(PostcopyRegion {
+            addr: addr as u64,
+            size,
+        });
+        Ok(addr as u64)
+    }
+}
+
+impl Default for DummySlaveReqHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create a pipe, returning its read and write ends.
+pub fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    // Safe because the fds are written by the kernel, and the return value is checked.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(Error::ReqHandlerError(std::io::Error::last_os_error()));
+    }
+    // Safe because the fds were just created and are owned by nobody else.
+    unsafe { Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))) }
+}
+
+/// Region of anonymous memory mapped for the postcopy migration.
+pub struct PostcopyRegion {
+    /// Address of the region.
+    pub addr: u64,
+    /// Size of the region.
+    pub size: usize,
+}
+
+impl Drop for PostcopyRegion {
+    fn drop(&mut self) {
+        // Safe because the region was mapped by map_postcopy_region().
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.size) };
+    }
 }
 
 impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     fn set_owner(&mut self) -> Result<()> {
+        self.check_injected(MasterReq::SET_OWNER)?;
         if self.owned {
             return Err(Error::InvalidOperation);
         }
@@ -47,6 +298,7 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn reset_owner(&mut self) -> Result<()> {
+        self.check_injected(MasterReq::RESET_OWNER)?;
         self.owned = false;
         self.features_acked = false;
         self.acked_features = 0;
@@ -55,13 +307,15 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn get_features(&mut self) -> Result<u64> {
-        Ok(VIRTIO_FEATURES)
+        self.check_injected(MasterReq::GET_FEATURES)?;
+        Ok(self.virtio_features)
     }
 
     fn set_features(&mut self, features: u64) -> Result<()> {
+        self.check_injected(MasterReq::SET_FEATURES)?;
         if !self.owned || self.features_acked {
             return Err(Error::InvalidOperation);
-        } else if (features & !VIRTIO_FEATURES) != 0 {
+        } else if (features & !self.virtio_features) != 0 {
             return Err(Error::InvalidParam);
         }
 
@@ -85,10 +339,12 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn set_mem_table(&mut self, _ctx: &[VhostUserMemoryRegion], _files: Vec<File>) -> Result<()> {
+        self.check_injected(MasterReq::SET_MEM_TABLE)?;
         Ok(())
     }
 
     fn set_vring_num(&mut self, index: u32, num: u32) -> Result<()> {
+        self.check_injected(MasterReq::SET_VRING_NUM)?;
         if index as usize >= self.queue_num || num == 0 || num as usize > MAX_VRING_NUM {
             return Err(Error::InvalidParam);
         }
@@ -105,21 +361,31 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
         _available: u64,
         _log: u64,
     ) -> Result<()> {
+        self.check_injected(MasterReq::SET_VRING_ADDR)?;
         if index as usize >= self.queue_num {
             return Err(Error::InvalidParam);
         }
         Ok(())
     }
 
-    fn set_vring_base(&mut self, index: u32, base: u32) -> Result<()> {
-        if index as usize >= self.queue_num || base as usize >= MAX_VRING_NUM {
+    fn set_vring_base(&mut self, index: u32, base: VringBase) -> Result<()> {
+        self.check_injected(MasterReq::SET_VRING_BASE)?;
+        let valid = match base {
+            VringBase::Split(idx) => (idx as usize) < MAX_VRING_NUM,
+            VringBase::Packed(base) => {
+                (base.last_avail_idx as usize) < MAX_VRING_NUM
+                    && (base.last_used_idx as usize) < MAX_VRING_NUM
+            }
+        };
+        if index as usize >= self.queue_num || !valid {
             return Err(Error::InvalidParam);
         }
         self.vring_base[index as usize] = base;
         Ok(())
     }
 
-    fn get_vring_base(&mut self, index: u32) -> Result<VhostUserVringState> {
+    fn get_vring_base(&mut self, index: u32) -> Result<VringBase> {
+        self.check_injected(MasterReq::GET_VRING_BASE)?;
         if index as usize >= self.queue_num {
             return Err(Error::InvalidParam);
         }
@@ -129,13 +395,11 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
         // VHOST_USER_SET_VRING_KICK, and stop ring upon receiving
         // VHOST_USER_GET_VRING_BASE.
         self.vring_started[index as usize] = false;
-        Ok(VhostUserVringState::new(
-            index,
-            self.vring_base[index as usize],
-        ))
+        Ok(self.vring_base[index as usize])
     }
 
     fn set_vring_kick(&mut self, index: u8, fd: Option<File>) -> Result<()> {
+        self.check_injected(MasterReq::SET_VRING_KICK)?;
         if index as usize >= self.queue_num || index as usize > self.queue_num {
             return Err(Error::InvalidParam);
         }
@@ -153,6 +417,7 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn set_vring_call(&mut self, index: u8, fd: Option<File>) -> Result<()> {
+        self.check_injected(MasterReq::SET_VRING_CALL)?;
         if index as usize >= self.queue_num || index as usize > self.queue_num {
             return Err(Error::InvalidParam);
         }
@@ -161,6 +426,7 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn set_vring_err(&mut self, index: u8, fd: Option<File>) -> Result<()> {
+        self.check_injected(MasterReq::SET_VRING_ERR)?;
         if index as usize >= self.queue_num || index as usize > self.queue_num {
             return Err(Error::InvalidParam);
         }
@@ -169,10 +435,12 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures> {
-        Ok(VhostUserProtocolFeatures::all())
+        self.check_injected(MasterReq::GET_PROTOCOL_FEATURES)?;
+        Ok(self.protocol_features)
     }
 
     fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.check_injected(MasterReq::SET_PROTOCOL_FEATURES)?;
         // Note: slave that reported VHOST_USER_F_PROTOCOL_FEATURES must
         // support this message even before VHOST_USER_SET_FEATURES was
         // called.
@@ -184,10 +452,12 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn get_queue_num(&mut self) -> Result<u64> {
-        Ok(MAX_QUEUE_NUM as u64)
+        self.check_injected(MasterReq::GET_QUEUE_NUM)?;
+        Ok(self.queue_num as u64)
     }
 
     fn set_vring_enable(&mut self, index: u32, enable: bool) -> Result<()> {
+        self.check_injected(MasterReq::SET_VRING_ENABLE)?;
         // This request should be handled only when VHOST_USER_F_PROTOCOL_FEATURES
         // has been negotiated.
         if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
@@ -210,27 +480,15 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
         size: u32,
         _flags: VhostUserConfigFlags,
     ) -> Result<Vec<u8>> {
-        if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
-            return Err(Error::InvalidOperation);
-        } else if !(VHOST_USER_CONFIG_OFFSET..VHOST_USER_CONFIG_SIZE).contains(&offset)
-            || size > VHOST_USER_CONFIG_SIZE - VHOST_USER_CONFIG_OFFSET
-            || size + offset > VHOST_USER_CONFIG_SIZE
-        {
-            return Err(Error::InvalidParam);
-        }
-        Ok(vec![0xa5; size as usize])
+        self.check_injected(MasterReq::GET_CONFIG)?;
+        let range = self.config_range(offset, size)?;
+        Ok(self.config_space[range].to_vec())
     }
 
     fn set_config(&mut self, offset: u32, buf: &[u8], _flags: VhostUserConfigFlags) -> Result<()> {
-        let size = buf.len() as u32;
-        if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
-            return Err(Error::InvalidOperation);
-        } else if !(VHOST_USER_CONFIG_OFFSET..VHOST_USER_CONFIG_SIZE).contains(&offset)
-            || size > VHOST_USER_CONFIG_SIZE - VHOST_USER_CONFIG_OFFSET
-            || size + offset > VHOST_USER_CONFIG_SIZE
-        {
-            return Err(Error::InvalidParam);
-        }
+        self.check_injected(MasterReq::SET_CONFIG)?;
+        let range = self.config_range(offset, buf.len() as u32)?;
+        self.config_space[range].copy_from_slice(buf);
         Ok(())
     }
 
@@ -238,8 +496,20 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
         &mut self,
         inflight: &VhostUserInflight,
     ) -> Result<(VhostUserInflight, File)> {
-        let file = tempfile::tempfile().unwrap();
-        self.inflight_file = Some(file.try_clone().unwrap());
+        self.check_injected(MasterReq::GET_INFLIGHT_FD)?;
+        // Safe because the name is a valid C string and the return value is checked.
+        let fd = unsafe {
+            libc::memfd_create(
+                "dummy_inflight\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::ReqHandlerError(IOError::last_os_error()));
+        }
+        // Safe because the fd was just created and is owned by nobody else.
+        let file = unsafe { File::from_raw_fd(fd) };
+        self.inflight_file = Some(file.try_clone().map_err(Error::ReqHandlerError)?);
         Ok((
             VhostUserInflight {
                 mmap_size: 0x1000,
@@ -252,18 +522,208 @@ impl VhostUserSlaveReqHandlerMut for DummySlaveReqHandler {
     }
 
     fn set_inflight_fd(&mut self, _inflight: &VhostUserInflight, _file: File) -> Result<()> {
+        self.check_injected(MasterReq::SET_INFLIGHT_FD)?;
         Ok(())
     }
 
     fn get_max_mem_slots(&mut self) -> Result<u64> {
+        self.check_injected(MasterReq::GET_MAX_MEM_SLOTS)?;
         Ok(MAX_MEM_SLOTS as u64)
     }
 
     fn add_mem_region(&mut self, _region: &VhostUserSingleMemoryRegion, _fd: File) -> Result<()> {
+        self.check_injected(MasterReq::ADD_MEM_REG)?;
         Ok(())
     }
 
     fn remove_mem_region(&mut self, _region: &VhostUserSingleMemoryRegion) -> Result<()> {
+        self.check_injected(MasterReq::REM_MEM_REG)?;
+        Ok(())
+    }
+
+    fn custom_request(
+        &mut self,
+        code: u32,
+        payload: &[u8],
+        files: Option<Vec<File>>,
+    ) -> Result<(Vec<u8>, Option<Vec<File>>)> {
+        if code != CUSTOM_ECHO_REQ {
+            return Err(Error::InvalidOperation);
+        }
+        Ok((payload.to_vec(), files))
+    }
+
+    fn set_mapped_mem_table(&mut self, regions: &[Arc<MappedRegion>]) -> Result<()> {
+        self.check_injected(MasterReq::SET_MEM_TABLE)?;
+        self.mapped_regions = regions.to_vec();
+        Ok(())
+    }
+
+    fn add_mapped_mem_region(&mut self, region: &Arc<MappedRegion>) -> Result<()> {
+        self.check_injected(MasterReq::ADD_MEM_REG)?;
+        self.mapped_regions.push(region.clone());
+        Ok(())
+    }
+
+    fn remove_mapped_mem_region(&mut self, region: &Arc<MappedRegion>) -> Result<()> {
+        self.check_injected(MasterReq::REM_MEM_REG)?;
+        self.mapped_regions.retain(|r| !Arc::ptr_eq(r, region));
         Ok(())
     }
+
+    fn set_inflight_region(&mut self, region: Arc<InflightRegion>) -> Result<()> {
+        self.inflight_region = Some(region);
+        Ok(())
+    }
+
+    fn set_dirty_log(&mut self, log: Arc<DirtyLogWriter>) -> Result<()> {
+        self.dirty_log = Some(log);
+        Ok(())
+    }
+
+    fn postcopy_advise(&mut self, _uffd: &Userfaultfd) -> Result<()> {
+        self.check_injected(MasterReq::POSTCOPY_ADVISE)?;
+        Ok(())
+    }
+
+    fn postcopy_listen(&mut self) -> Result<()> {
+        self.check_injected(MasterReq::POSTCOPY_LISTEN)?;
+        self.postcopy_listening = true;
+        Ok(())
+    }
+
+    fn postcopy_end(&mut self) -> Result<()> {
+        self.check_injected(MasterReq::POSTCOPY_END)?;
+        self.postcopy_listening = false;
+        Ok(())
+    }
+
+    fn set_mem_table_postcopy(
+        &mut self,
+        ctx: &[VhostUserMemoryRegion],
+        _files: Vec<File>,
+    ) -> Result<Vec<u64>> {
+        ctx.iter()
+            .map(|region| self.map_postcopy_region(region.memory_size))
+            .collect()
+    }
+
+    fn add_mem_region_postcopy(
+        &mut self,
+        region: &VhostUserSingleMemoryRegion,
+        _fd: File,
+    ) -> Result<u64> {
+        self.map_postcopy_region(region.memory_size)
+    }
+
+    fn reset_device(&mut self) -> Result<()> {
+        self.check_injected(MasterReq::RESET_DEVICE)?;
+        self.features_acked = false;
+        self.acked_features = 0;
+        self.status = 0;
+        for index in 0..self.queue_num {
+            self.vring_started[index] = false;
+            self.vring_enabled[index] = false;
+            self.vring_base[index] = VringBase::default();
+            self.call_fd[index] = None;
+            self.kick_fd[index] = None;
+            self.err_fd[index] = None;
+        }
+        self.inflight_file = None;
+        Ok(())
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<()> {
+        self.check_injected(MasterReq::SET_STATUS)?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> Result<u8> {
+        self.check_injected(MasterReq::GET_STATUS)?;
+        Ok(self.status)
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        _phase: VhostTransferStatePhase,
+        mut file: File,
+    ) -> Result<Option<File>> {
+        self.check_injected(MasterReq::SET_DEVICE_STATE_FD)?;
+        match direction {
+            VhostTransferStateDirection::SAVE => {
+                file.write_all(&self.device_state)
+                    .map_err(Error::ReqHandlerError)?;
+                Ok(None)
+            }
+            VhostTransferStateDirection::LOAD => {
+                // Load the state through a pipe of the slave.
+                let (rx, tx) = pipe()?;
+                self.device_state_load = Some(rx);
+                Ok(Some(tx))
+            }
+        }
+    }
+
+    fn check_device_state(&mut self) -> Result<()> {
+        self.check_injected(MasterReq::CHECK_DEVICE_STATE)?;
+        if let Some(mut rx) = self.device_state_load.take() {
+            self.device_state.clear();
+            rx.read_to_end(&mut self.device_state)
+                .map_err(Error::ReqHandlerError)?;
+        }
+        Ok(())
+    }
+
+    fn master_connected(&mut self, id: u64) -> Result<()> {
+        self.master_id = Some(id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dummy_slave_config() {
+        let features = VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::MQ;
+        let config = DummySlaveConfig::new()
+            .queue_num(4)
+            .virtio_features(0x1)
+            .protocol_features(features)
+            .config_space(&[1, 2, 3, 4]);
+        let mut slave = DummySlaveReqHandler::with_config(&config);
+        assert_eq!(slave.get_queue_num().unwrap(), 4);
+        assert_eq!(slave.get_features().unwrap(), 0x1);
+        assert_eq!(slave.get_protocol_features().unwrap(), features);
+        slave.set_vring_num(3, 16).unwrap();
+        slave.set_vring_num(4, 16).unwrap_err();
+
+        let flags = VhostUserConfigFlags::empty();
+        slave.get_config(0x100, 4, flags).unwrap_err();
+        slave.set_protocol_features(features.bits()).unwrap();
+        assert_eq!(slave.get_config(0x101, 2, flags).unwrap(), vec![2, 3]);
+        slave.get_config(0x102, 3, flags).unwrap_err();
+        slave.set_config(0x103, &[5], flags).unwrap();
+        assert_eq!(slave.config_space, vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn test_dummy_slave_inject_error() {
+        let mut slave = DummySlaveReqHandler::new();
+        slave.inject_error(MasterReq::SET_OWNER, libc::EBUSY);
+        slave.inject_error(MasterReq::GET_FEATURES, libc::EIO);
+        slave.inject_error(MasterReq::SET_OWNER, libc::EPERM);
+        for errno in [libc::EBUSY, libc::EPERM].iter() {
+            match slave.set_owner().unwrap_err() {
+                Error::ReqHandlerError(e) => assert_eq!(e.raw_os_error(), Some(*errno)),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+        slave.set_owner().unwrap();
+        slave.get_features().unwrap_err();
+        assert_eq!(slave.get_features().unwrap(), VIRTIO_FEATURES);
+    }
 }
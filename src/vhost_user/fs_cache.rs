@@ -0,0 +1,249 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-fs DAX cache window, mapped by the slave through the slave communication channel.
+
+use std::io::{Error as IOError, Result as IOResult};
+use std::os::unix::io::{AsFd, AsRawFd};
+
+use super::master_req_handler::VhostUserMasterReqHandler;
+use super::message::{VhostUserFSSlaveMsg, VhostUserFSSlaveMsgFlags, VHOST_USER_FS_SLAVE_ENTRIES};
+use super::HandlerResult;
+
+/// Length of an unmap entry covering the whole cache window.
+pub const VHOST_USER_FS_UNMAP_ALL: u64 = u64::MAX;
+
+/// DAX cache window of a virtio-fs device, serving the FS_MAP, FS_UNMAP, FS_SYNC and FS_IO
+/// requests of the slave.
+///
+/// The window is a range of the VMM address space provided by the caller, usually a `PROT_NONE`
+/// reservation exposed to the guest as the shared memory region of the device. FS_MAP requests
+/// map the files sent by the slave at offsets of the window, and FS_UNMAP requests replace the
+/// mappings by a `PROT_NONE` reservation again, so the window stays reserved.
+///
+/// The window may be used as the backend of a [MasterReqHandler] for virtio-fs devices, or called
+/// by a backend handling other requests too.
+///
+/// [MasterReqHandler]: struct.MasterReqHandler.html
+pub struct FsCacheWindow {
+    addr: *mut u8,
+    size: u64,
+}
+
+// Safe because the window is only accessed through system calls working on its address range.
+unsafe impl Send for FsCacheWindow {}
+unsafe impl Sync for FsCacheWindow {}
+
+impl FsCacheWindow {
+    /// Create a cache window covering `size` bytes at `addr`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the page aligned mapping `[addr, addr + size)` for the lifetime of the
+    /// window, and must not rely on its content since the slave replaces its mappings.
+    pub unsafe fn new(addr: *mut u8, size: u64) -> Self {
+        FsCacheWindow { addr, size }
+    }
+
+    /// Get the start address of the window.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Get the size of the window.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Get the address of `[offset, offset + len)` in the window.
+    fn range(&self, offset: u64, len: u64) -> IOResult<*mut libc::c_void> {
+        match offset.checked_add(len) {
+            // Safe because the offset is within the window.
+            Some(end) if end <= self.size => Ok(unsafe { self.addr.add(offset as usize) } as _),
+            _ => Err(IOError::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    // Get the entries of `fs` to handle, the entries with a null length are unused.
+    fn entries(fs: &VhostUserFSSlaveMsg) -> impl Iterator<Item = usize> + '_ {
+        (0..VHOST_USER_FS_SLAVE_ENTRIES).filter(move |&i| fs.len[i] != 0)
+    }
+
+    fn reserve(&self, offset: u64, len: u64) -> IOResult<()> {
+        let addr = self.range(offset, len)?;
+        // Safe because the range belongs to the window, and the return value is checked.
+        let ret = unsafe {
+            libc::mmap(
+                addr,
+                len as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(IOError::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl VhostUserMasterReqHandler for FsCacheWindow {
+    fn fs_slave_map(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
+        for i in Self::entries(fs) {
+            let (offset, len) = (fs.cache_offset[i], fs.len[i]);
+            let addr = self.range(offset, len)?;
+            let flags = fs.flags[i];
+            let mut prot = 0;
+            if flags.contains(VhostUserFSSlaveMsgFlags::MAP_R) {
+                prot |= libc::PROT_READ;
+            }
+            if flags.contains(VhostUserFSSlaveMsgFlags::MAP_W) {
+                prot |= libc::PROT_WRITE;
+            }
+            // Safe because the range belongs to the window, and the return value is checked.
+            let ret = unsafe {
+                libc::mmap(
+                    addr,
+                    len as usize,
+                    prot,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    fd.as_fd().as_raw_fd(),
+                    fs.fd_offset[i] as libc::off_t,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(IOError::last_os_error());
+            }
+        }
+        Ok(0)
+    }
+
+    fn fs_slave_unmap(&self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        for i in Self::entries(fs) {
+            let offset = fs.cache_offset[i];
+            let len = match fs.len[i] {
+                VHOST_USER_FS_UNMAP_ALL => self.size.saturating_sub(offset),
+                len => len,
+            };
+            self.reserve(offset, len)?;
+        }
+        Ok(0)
+    }
+
+    fn fs_slave_sync(&self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        for i in Self::entries(fs) {
+            let len = fs.len[i];
+            let addr = self.range(fs.cache_offset[i], len)?;
+            // Safe because the range belongs to the window, and the return value is checked.
+            if unsafe { libc::msync(addr, len as usize, libc::MS_SYNC) } < 0 {
+                return Err(IOError::last_os_error());
+            }
+        }
+        Ok(0)
+    }
+
+    /// Copy data between the file and the window: from the file to the window with `MAP_R`, and
+    /// from the window to the file with `MAP_W`. Return the number of bytes copied.
+    fn fs_slave_io(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
+        let fd = fd.as_fd().as_raw_fd();
+        let mut done = 0;
+        for i in Self::entries(fs) {
+            let (len, fd_offset) = (fs.len[i], fs.fd_offset[i]);
+            let addr = self.range(fs.cache_offset[i], len)?;
+            let flags = fs.flags[i];
+            // Safe because the range belongs to the window, and the return value is checked.
+            let ret = if flags.contains(VhostUserFSSlaveMsgFlags::MAP_R) {
+                unsafe { libc::pread(fd, addr, len as usize, fd_offset as libc::off_t) }
+            } else if flags.contains(VhostUserFSSlaveMsgFlags::MAP_W) {
+                unsafe { libc::pwrite(fd, addr, len as usize, fd_offset as libc::off_t) }
+            } else {
+                return Err(IOError::from_raw_os_error(libc::EINVAL));
+            };
+            if ret < 0 {
+                return Err(IOError::last_os_error());
+            }
+            done += ret as u64;
+            if (ret as u64) < len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use vmm_sys_util::tempfile::TempFile;
+
+    const PAGE: u64 = 0x1000;
+
+    fn map_entry(fd_offset: u64, cache_offset: u64, len: u64) -> VhostUserFSSlaveMsg {
+        let mut fs = VhostUserFSSlaveMsg::default();
+        fs.fd_offset[0] = fd_offset;
+        fs.cache_offset[0] = cache_offset;
+        fs.len[0] = len;
+        fs.flags[0] = VhostUserFSSlaveMsgFlags::MAP_R | VhostUserFSSlaveMsgFlags::MAP_W;
+        fs
+    }
+
+    #[test]
+    fn test_fs_cache_window() {
+        let size = 4 * PAGE;
+        // Safe because the reservation is checked and only used by the window.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let window = unsafe { FsCacheWindow::new(addr as *mut u8, size) };
+        assert_eq!(window.size(), size);
+
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&[0xa5; 2 * PAGE as usize]).unwrap();
+
+        window
+            .fs_slave_map(&map_entry(PAGE, PAGE, PAGE), &file)
+            .unwrap();
+        let data = unsafe { window.as_ptr().add(PAGE as usize) };
+        assert_eq!(unsafe { *data }, 0xa5);
+        unsafe { *data = 0x5a };
+        window.fs_slave_sync(&map_entry(0, PAGE, PAGE)).unwrap();
+        let mut buf = [0u8; 1];
+        file.seek(SeekFrom::Start(PAGE)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], 0x5a);
+
+        // Entries beyond the window are rejected.
+        window
+            .fs_slave_map(&map_entry(0, 3 * PAGE, 2 * PAGE), &file)
+            .unwrap_err();
+        window.fs_slave_sync(&map_entry(0, size, 1)).unwrap_err();
+
+        // Read the first byte of the file into the mapping, then write it back elsewhere.
+        let mut io = map_entry(0, PAGE, 1);
+        io.flags[0] = VhostUserFSSlaveMsgFlags::MAP_R;
+        assert_eq!(window.fs_slave_io(&io, &file).unwrap(), 1);
+        assert_eq!(unsafe { *data }, 0xa5);
+        let mut io = map_entry(2 * PAGE - 1, PAGE, 1);
+        io.flags[0] = VhostUserFSSlaveMsgFlags::MAP_W;
+        assert_eq!(window.fs_slave_io(&io, &file).unwrap(), 1);
+
+        let mut unmap = map_entry(0, 0, VHOST_USER_FS_UNMAP_ALL);
+        window.fs_slave_unmap(&unmap).unwrap();
+        unmap.len[0] = 1;
+        unmap.cache_offset[0] = size;
+        window.fs_slave_unmap(&unmap).unwrap_err();
+
+        unsafe { libc::munmap(addr, size as usize) };
+    }
+}
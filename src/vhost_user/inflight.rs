@@ -0,0 +1,822 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Inflight I/O tracking buffer shared by the slave with the master, to resubmit the requests
+//! in flight when the slave crashes.
+
+use std::fs::File;
+use std::io::Error as IOError;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use super::message::{DescStatePacked, QueueRegionPacked, QueueRegionSplit, VhostUserInflight};
+use super::{Error, Result};
+
+/// Memfd backed buffer tracking the inflight I/O of each queue of the slave.
+///
+/// The buffer is made of one [QueueRegionSplit] per queue for split virtqueues, or one
+/// [QueueRegionPacked] for packed virtqueues, each aligned to `INFLIGHT_ALIGNMENT`. The master
+/// keeps the buffer across reconnections of the slave, so the state of a crashed slave is passed
+/// to its successor.
+///
+/// [QueueRegionSplit]: message/struct.QueueRegionSplit.html
+/// [QueueRegionPacked]: message/struct.QueueRegionPacked.html
+pub struct InflightRegion {
+    file: File,
+    addr: u64,
+    mmap_size: usize,
+    num_queues: u16,
+    queue_size: u16,
+    packed: bool,
+}
+
+impl InflightRegion {
+    /// Allocate a zeroed buffer tracking `num_queues` queues of `queue_size` descriptors.
+    pub fn new(num_queues: u16, queue_size: u16, packed: bool) -> Result<Self> {
+        let inflight = VhostUserInflight::new(0, 0, num_queues, queue_size);
+        let size = Self::required_size(&inflight, packed)?;
+
+        // Safe because the name is a valid C string and the return value is checked.
+        let fd = unsafe {
+            libc::memfd_create(
+                "vhost_inflight\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::ReqHandlerError(IOError::last_os_error()));
+        }
+        // Safe because the fd was just created and is owned by nobody else.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size).map_err(Error::ReqHandlerError)?;
+
+        let inflight = VhostUserInflight::new(size, 0, num_queues, queue_size);
+        let region = Self::map(&inflight, file, packed)?;
+        for index in 0..num_queues {
+            region.init_queue(index);
+        }
+        Ok(region)
+    }
+
+    /// Map the buffer described by `inflight` from `file`.
+    pub fn from_file(inflight: &VhostUserInflight, file: File, packed: bool) -> Result<Self> {
+        if inflight.mmap_size < Self::required_size(inflight, packed)? {
+            return Err(Error::InvalidParam);
+        }
+        Self::map(inflight, file, packed)
+    }
+
+    /// Get the description of the buffer, as replied to GET_INFLIGHT_FD.
+    pub fn inflight(&self) -> VhostUserInflight {
+        VhostUserInflight::new(self.mmap_size as u64, 0, self.num_queues, self.queue_size)
+    }
+
+    /// Get the file backing the buffer.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Get the number of queues tracked by the buffer.
+    pub fn num_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    /// Get the number of descriptors of each queue.
+    pub fn queue_size(&self) -> u16 {
+        self.queue_size
+    }
+
+    /// Check whether the buffer tracks packed virtqueues.
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+
+    /// Get the address of the region tracking queue `index`, a [QueueRegionSplit] or a
+    /// [QueueRegionPacked] according to [Self::is_packed()].
+    ///
+    /// [QueueRegionSplit]: message/struct.QueueRegionSplit.html
+    /// [QueueRegionPacked]: message/struct.QueueRegionPacked.html
+    /// [Self::is_packed()]: struct.InflightRegion.html#method.is_packed
+    pub fn queue_region_addr(&self, index: u16) -> Option<u64> {
+        if index >= self.num_queues {
+            return None;
+        }
+        Some(self.addr + u64::from(index) * self.queue_region_size())
+    }
+
+    fn queue_region_size(&self) -> u64 {
+        if self.packed {
+            QueueRegionPacked::region_size(self.queue_size)
+        } else {
+            QueueRegionSplit::region_size(self.queue_size)
+        }
+    }
+
+    fn required_size(inflight: &VhostUserInflight, packed: bool) -> Result<u64> {
+        if inflight.num_queues == 0 || inflight.queue_size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        if packed {
+            Ok(inflight.packed_mmap_size())
+        } else {
+            Ok(inflight.split_mmap_size())
+        }
+    }
+
+    fn map(inflight: &VhostUserInflight, file: File, packed: bool) -> Result<Self> {
+        let mmap_size = inflight.mmap_size as usize;
+        // Safe because a new mapping is created, and the return value is checked.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                mmap_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                inflight.mmap_offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::ReqHandlerError(IOError::last_os_error()));
+        }
+        Ok(InflightRegion {
+            file,
+            addr: addr as u64,
+            mmap_size,
+            num_queues: inflight.num_queues,
+            queue_size: inflight.queue_size,
+            packed,
+        })
+    }
+
+    // Write the header of the region of queue `index` in a zeroed buffer. The `desc` field of the
+    // header marks the start of the descriptor states, so writing zero there leaves them zeroed.
+    // The descriptor states of packed virtqueues are linked in a list of free entries, and the
+    // wrap counters start set, as the device ring wrap counter.
+    fn init_queue(&self, index: u16) {
+        let addr = self.queue_region_addr(index).unwrap();
+        // Safe because the region of the queue is within the mapping, and the header and the
+        // links are written without alignment requirements.
+        unsafe {
+            if self.packed {
+                let mut region = QueueRegionPacked::new(0, self.queue_size);
+                region.used_wrap_counter = 1;
+                region.old_used_wrap_counter = 1;
+                ptr::write_unaligned(addr as *mut QueueRegionPacked, region);
+                for desc in 0..self.queue_size {
+                    let next = addr + PACKED_DESC + u64::from(desc) * DESC_PACKED_SIZE;
+                    ptr::write_unaligned((next + DESC_PACKED_NEXT) as *mut u16, desc + 1);
+                }
+            } else {
+                let region = QueueRegionSplit::new(0, self.queue_size);
+                ptr::write_unaligned(addr as *mut QueueRegionSplit, region);
+            }
+        }
+    }
+}
+
+impl Drop for InflightRegion {
+    fn drop(&mut self) {
+        // Safe because the mapping was created by map() and isn't used anymore.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.mmap_size) };
+    }
+}
+
+// Offsets of the fields of QueueRegionSplit and DescStateSplit updated while tracking requests.
+const SPLIT_LAST_BATCH_HEAD: u64 = 12;
+const SPLIT_USED_IDX: u64 = 14;
+const SPLIT_DESC: u64 = 16;
+const DESC_SPLIT_SIZE: u64 = 16;
+const DESC_SPLIT_INFLIGHT: u64 = 0;
+const DESC_SPLIT_COUNTER: u64 = 8;
+
+/// Inflight I/O tracking of a split virtqueue, in the region of the queue in an
+/// [InflightRegion].
+///
+/// The queue is processed by a single thread, which records the requests as follows:
+/// - [Self::start_request()] when the descriptor chain of the request is popped from the
+///   available ring.
+/// - [Self::prepare_complete()] before the descriptor chain is pushed to the used ring.
+/// - [Self::complete_request()] once the index of the used ring has been updated.
+///
+/// After a reconnection, [Self::resubmit_list()] returns the requests to process again.
+///
+/// [InflightRegion]: struct.InflightRegion.html
+/// [Self::start_request()]: struct.InflightQueueSplit.html#method.start_request
+/// [Self::prepare_complete()]: struct.InflightQueueSplit.html#method.prepare_complete
+/// [Self::complete_request()]: struct.InflightQueueSplit.html#method.complete_request
+/// [Self::resubmit_list()]: struct.InflightQueueSplit.html#method.resubmit_list
+pub struct InflightQueueSplit {
+    region: Arc<InflightRegion>,
+    addr: u64,
+    counter: u64,
+}
+
+impl InflightQueueSplit {
+    /// Track the requests of queue `index` in `region`.
+    pub fn new(region: Arc<InflightRegion>, index: u16) -> Result<Self> {
+        if region.is_packed() {
+            return Err(Error::InvalidParam);
+        }
+        let addr = region.queue_region_addr(index).ok_or(Error::InvalidParam)?;
+        let mut queue = InflightQueueSplit {
+            region,
+            addr,
+            counter: 0,
+        };
+        queue.counter = queue.next_counter();
+        Ok(queue)
+    }
+
+    /// Record the request of the descriptor chain starting at `head` as in flight.
+    pub fn start_request(&mut self, head: u16) -> Result<()> {
+        self.desc_counter(head)?
+            .store(self.counter, Ordering::Release);
+        self.desc_inflight(head)?.store(1, Ordering::Release);
+        self.counter += 1;
+        Ok(())
+    }
+
+    /// Record the descriptor chain starting at `head` as the next to be pushed to the used ring.
+    pub fn prepare_complete(&self, head: u16) -> Result<()> {
+        self.check_head(head)?;
+        self.header_u16(SPLIT_LAST_BATCH_HEAD)
+            .store(head, Ordering::Release);
+        Ok(())
+    }
+
+    /// Record the request of the descriptor chain starting at `head` as completed, once the index
+    /// of the used ring has been updated to `used_idx`.
+    pub fn complete_request(&self, head: u16, used_idx: u16) -> Result<()> {
+        self.desc_inflight(head)?.store(0, Ordering::Release);
+        fence(Ordering::SeqCst);
+        self.header_u16(SPLIT_USED_IDX)
+            .store(used_idx, Ordering::Release);
+        Ok(())
+    }
+
+    /// Check whether the request of the descriptor chain starting at `head` is in flight.
+    pub fn is_inflight(&self, head: u16) -> bool {
+        match self.desc_inflight(head) {
+            Ok(inflight) => inflight.load(Ordering::Acquire) != 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Get the heads of the descriptor chains of the requests in flight when the previous slave
+    /// stopped, in the order the requests were started.
+    ///
+    /// `used_idx` is the current index of the used ring, which tells whether the last completion
+    /// was interrupted between updating the used ring and recording it.
+    pub fn resubmit_list(&mut self, used_idx: u16) -> Vec<u16> {
+        let used = self.header_u16(SPLIT_USED_IDX);
+        if used.load(Ordering::Acquire) != used_idx {
+            let head = self
+                .header_u16(SPLIT_LAST_BATCH_HEAD)
+                .load(Ordering::Acquire);
+            if let Ok(inflight) = self.desc_inflight(head) {
+                inflight.store(0, Ordering::Release);
+            }
+            fence(Ordering::SeqCst);
+            used.store(used_idx, Ordering::Release);
+        }
+
+        let mut heads: Vec<(u64, u16)> = (0..self.region.queue_size())
+            .filter(|head| self.is_inflight(*head))
+            .map(|head| {
+                let counter = self.desc_counter(head).unwrap().load(Ordering::Acquire);
+                (counter, head)
+            })
+            .collect();
+        heads.sort_unstable();
+        self.counter = self.next_counter();
+        heads.into_iter().map(|(_, head)| head).collect()
+    }
+
+    fn next_counter(&self) -> u64 {
+        (0..self.region.queue_size())
+            .filter(|head| self.is_inflight(*head))
+            .map(|head| self.desc_counter(head).unwrap().load(Ordering::Acquire) + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn check_head(&self, head: u16) -> Result<()> {
+        if head >= self.region.queue_size() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    fn header_u16(&self, offset: u64) -> &AtomicU16 {
+        // Safe because the field is within the region of the queue, which is 64 bytes aligned.
+        unsafe { &*((self.addr + offset) as *const AtomicU16) }
+    }
+
+    fn desc_inflight(&self, head: u16) -> Result<&AtomicU8> {
+        self.check_head(head)?;
+        let addr = self.addr + SPLIT_DESC + u64::from(head) * DESC_SPLIT_SIZE + DESC_SPLIT_INFLIGHT;
+        // Safe because the descriptor state is within the region of the queue.
+        Ok(unsafe { &*(addr as *const AtomicU8) })
+    }
+
+    fn desc_counter(&self, head: u16) -> Result<&AtomicU64> {
+        self.check_head(head)?;
+        let addr = self.addr + SPLIT_DESC + u64::from(head) * DESC_SPLIT_SIZE + DESC_SPLIT_COUNTER;
+        // Safe because the descriptor state is within the region of the queue, and 8 bytes
+        // aligned as the descriptor states are 16 bytes each.
+        Ok(unsafe { &*(addr as *const AtomicU64) })
+    }
+}
+
+// Offsets of the fields of QueueRegionPacked and DescStatePacked updated while tracking requests.
+// The descriptor states start 8 bytes aligned after the header, as in the C layout of the region.
+const PACKED_FREE_HEAD: u64 = 12;
+const PACKED_OLD_FREE_HEAD: u64 = 14;
+const PACKED_USED_IDX: u64 = 16;
+const PACKED_OLD_USED_IDX: u64 = 18;
+const PACKED_USED_WRAP_COUNTER: u64 = 20;
+const PACKED_OLD_USED_WRAP_COUNTER: u64 = 21;
+const PACKED_DESC: u64 = 32;
+const DESC_PACKED_SIZE: u64 = 32;
+const DESC_PACKED_INFLIGHT: u64 = 0;
+const DESC_PACKED_NEXT: u64 = 2;
+const DESC_PACKED_LAST: u64 = 4;
+const DESC_PACKED_NUM: u64 = 6;
+const DESC_PACKED_COUNTER: u64 = 8;
+const DESC_PACKED_ID: u64 = 16;
+const DESC_PACKED_FLAGS: u64 = 18;
+const DESC_PACKED_LEN: u64 = 20;
+const DESC_PACKED_ADDR: u64 = 24;
+
+/// Inflight I/O tracking of a packed virtqueue, in the region of the queue in an
+/// [InflightRegion].
+///
+/// The descriptors of each request are recorded in entries taken from a list of free entries, the
+/// request being identified by its first entry. The queue is processed by a single thread, which
+/// records the requests as follows:
+/// - [Self::start_request()] when the descriptor chain of the request is read from the
+///   descriptor ring.
+/// - [Self::prepare_complete()] before the flags of the used descriptors are written to the
+///   descriptor ring.
+/// - [Self::complete_request()] once the flags have been written.
+///
+/// After a reconnection, [Self::resubmit_list()] returns the requests to process again, and
+/// [Self::chain()] the descriptors of each of them.
+///
+/// [InflightRegion]: struct.InflightRegion.html
+/// [Self::start_request()]: struct.InflightQueuePacked.html#method.start_request
+/// [Self::prepare_complete()]: struct.InflightQueuePacked.html#method.prepare_complete
+/// [Self::complete_request()]: struct.InflightQueuePacked.html#method.complete_request
+/// [Self::resubmit_list()]: struct.InflightQueuePacked.html#method.resubmit_list
+/// [Self::chain()]: struct.InflightQueuePacked.html#method.chain
+pub struct InflightQueuePacked {
+    region: Arc<InflightRegion>,
+    addr: u64,
+    counter: u64,
+}
+
+impl InflightQueuePacked {
+    /// Track the requests of queue `index` in `region`.
+    pub fn new(region: Arc<InflightRegion>, index: u16) -> Result<Self> {
+        if !region.is_packed() {
+            return Err(Error::InvalidParam);
+        }
+        let addr = region.queue_region_addr(index).ok_or(Error::InvalidParam)?;
+        let mut queue = InflightQueuePacked {
+            region,
+            addr,
+            counter: 0,
+        };
+        queue.counter = queue.next_counter();
+        Ok(queue)
+    }
+
+    /// Record the request of buffer `id` as in flight, `chain` holding the address, length and
+    /// flags of each descriptor of the request.
+    ///
+    /// Return the entry identifying the request.
+    ///
+    /// # Return:
+    /// * - InvalidParam: the chain is empty, or longer than the free entries.
+    pub fn start_request(&mut self, id: u16, chain: &[(u64, u32, u16)]) -> Result<u16> {
+        if chain.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let free_head = self.header_u16(PACKED_FREE_HEAD);
+        let head = free_head.load(Ordering::Acquire);
+        self.check_entry(head)?;
+        self.desc_u16(head, DESC_PACKED_NUM)?
+            .store(0, Ordering::Release);
+        self.desc_counter(head)?
+            .store(self.counter, Ordering::Release);
+        self.desc_inflight(head)?.store(1, Ordering::Release);
+
+        for (index, (addr, len, flags)) in chain.iter().enumerate() {
+            let entry = free_head.load(Ordering::Acquire);
+            self.check_entry(entry)?;
+            if index == chain.len() - 1 {
+                self.desc_u16(head, DESC_PACKED_LAST)?
+                    .store(entry, Ordering::Release);
+            }
+            self.desc_u16(head, DESC_PACKED_NUM)?
+                .fetch_add(1, Ordering::AcqRel);
+            self.desc_u64(entry, DESC_PACKED_ADDR)?
+                .store(*addr, Ordering::Release);
+            self.desc_u32(entry, DESC_PACKED_LEN)?
+                .store(*len, Ordering::Release);
+            self.desc_u16(entry, DESC_PACKED_FLAGS)?
+                .store(*flags, Ordering::Release);
+            self.desc_u16(entry, DESC_PACKED_ID)?
+                .store(id, Ordering::Release);
+            let next = self
+                .desc_u16(entry, DESC_PACKED_NEXT)?
+                .load(Ordering::Acquire);
+            free_head.store(next, Ordering::Release);
+        }
+        fence(Ordering::SeqCst);
+        self.header_u16(PACKED_OLD_FREE_HEAD)
+            .store(free_head.load(Ordering::Acquire), Ordering::Release);
+        self.counter += 1;
+        Ok(head)
+    }
+
+    /// Return the entries of the requests identified by `heads` to the free list, before the
+    /// flags of their descriptors are written to the descriptor ring with the used index advanced
+    /// to `used_idx` and the wrap counter to `used_wrap_counter`.
+    pub fn prepare_complete(
+        &self,
+        heads: &[u16],
+        used_idx: u16,
+        used_wrap_counter: bool,
+    ) -> Result<()> {
+        for head in heads {
+            self.check_entry(*head)?;
+        }
+        let free_head = self.header_u16(PACKED_FREE_HEAD);
+        for head in heads {
+            let last = self
+                .desc_u16(*head, DESC_PACKED_LAST)?
+                .load(Ordering::Acquire);
+            self.desc_u16(last, DESC_PACKED_NEXT)?
+                .store(free_head.load(Ordering::Acquire), Ordering::Release);
+            free_head.store(*head, Ordering::Release);
+        }
+        self.header_u16(PACKED_USED_IDX)
+            .store(used_idx, Ordering::Release);
+        self.header_u8(PACKED_USED_WRAP_COUNTER)
+            .store(used_wrap_counter as u8, Ordering::Release);
+        Ok(())
+    }
+
+    /// Record the requests identified by `heads` as completed, once the flags of their
+    /// descriptors have been written to the descriptor ring.
+    pub fn complete_request(&self, heads: &[u16]) -> Result<()> {
+        for head in heads {
+            self.desc_inflight(*head)?.store(0, Ordering::Release);
+        }
+        fence(Ordering::SeqCst);
+        self.commit();
+        Ok(())
+    }
+
+    /// Check whether the request identified by entry `head` is in flight.
+    pub fn is_inflight(&self, head: u16) -> bool {
+        match self.desc_inflight(head) {
+            Ok(inflight) => inflight.load(Ordering::Acquire) != 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Get the descriptors of the request identified by entry `head`, as recorded by
+    /// [Self::start_request()].
+    ///
+    /// [Self::start_request()]: struct.InflightQueuePacked.html#method.start_request
+    pub fn chain(&self, head: u16) -> Result<Vec<DescStatePacked>> {
+        let num = self
+            .desc_u16(head, DESC_PACKED_NUM)?
+            .load(Ordering::Acquire);
+        if num > self.region.queue_size() {
+            return Err(Error::InvalidParam);
+        }
+        let mut chain = Vec::with_capacity(usize::from(num));
+        let mut entry = head;
+        for _ in 0..num {
+            let mut desc = DescStatePacked::new();
+            desc.next = self
+                .desc_u16(entry, DESC_PACKED_NEXT)?
+                .load(Ordering::Acquire);
+            desc.id = self
+                .desc_u16(entry, DESC_PACKED_ID)?
+                .load(Ordering::Acquire);
+            desc.flags = self
+                .desc_u16(entry, DESC_PACKED_FLAGS)?
+                .load(Ordering::Acquire);
+            desc.len = self
+                .desc_u32(entry, DESC_PACKED_LEN)?
+                .load(Ordering::Acquire);
+            desc.addr = self
+                .desc_u64(entry, DESC_PACKED_ADDR)?
+                .load(Ordering::Acquire);
+            entry = desc.next;
+            chain.push(desc);
+        }
+        Ok(chain)
+    }
+
+    /// Get the entries identifying the requests in flight when the previous slave stopped, in
+    /// the order the requests were started.
+    ///
+    /// A completion interrupted before being recorded is rolled back, unless `is_used` tells it
+    /// reached the driver: `is_used` is passed the index of the first descriptor of the
+    /// completion in the descriptor ring and the wrap counter, and checks whether the flags of
+    /// the descriptor mark it used.
+    pub fn resubmit_list<F>(&mut self, is_used: F) -> Vec<u16>
+    where
+        F: FnOnce(u16, bool) -> bool,
+    {
+        let used_idx = self.header_u16(PACKED_USED_IDX).load(Ordering::Acquire);
+        let old_used_idx = self.header_u16(PACKED_OLD_USED_IDX).load(Ordering::Acquire);
+        if used_idx != old_used_idx {
+            let wrap_counter = self
+                .header_u8(PACKED_OLD_USED_WRAP_COUNTER)
+                .load(Ordering::Acquire);
+            if is_used(old_used_idx, wrap_counter != 0) {
+                self.commit();
+            }
+        }
+        self.rollback();
+
+        // The entries of the requests interrupted while being started are free.
+        let mut entry = self.header_u16(PACKED_FREE_HEAD).load(Ordering::Acquire);
+        for _ in 0..self.region.queue_size() {
+            match self.desc_inflight(entry) {
+                Ok(inflight) => inflight.store(0, Ordering::Release),
+                Err(_) => break,
+            }
+            entry = self
+                .desc_u16(entry, DESC_PACKED_NEXT)
+                .unwrap()
+                .load(Ordering::Acquire);
+        }
+
+        let mut heads: Vec<(u64, u16)> = (0..self.region.queue_size())
+            .filter(|head| self.is_inflight(*head))
+            .map(|head| {
+                let counter = self.desc_counter(head).unwrap().load(Ordering::Acquire);
+                (counter, head)
+            })
+            .collect();
+        heads.sort_unstable();
+        self.counter = self.next_counter();
+        heads.into_iter().map(|(_, head)| head).collect()
+    }
+
+    // Record the current free list and used index as the last consistent state.
+    fn commit(&self) {
+        let pairs = [
+            (PACKED_FREE_HEAD, PACKED_OLD_FREE_HEAD),
+            (PACKED_USED_IDX, PACKED_OLD_USED_IDX),
+        ];
+        for (current, old) in pairs.iter() {
+            let value = self.header_u16(*current).load(Ordering::Acquire);
+            self.header_u16(*old).store(value, Ordering::Release);
+        }
+        let wrap_counter = self
+            .header_u8(PACKED_USED_WRAP_COUNTER)
+            .load(Ordering::Acquire);
+        self.header_u8(PACKED_OLD_USED_WRAP_COUNTER)
+            .store(wrap_counter, Ordering::Release);
+    }
+
+    // Restore the free list and used index of the last consistent state.
+    fn rollback(&self) {
+        let pairs = [
+            (PACKED_FREE_HEAD, PACKED_OLD_FREE_HEAD),
+            (PACKED_USED_IDX, PACKED_OLD_USED_IDX),
+        ];
+        for (current, old) in pairs.iter() {
+            let value = self.header_u16(*old).load(Ordering::Acquire);
+            self.header_u16(*current).store(value, Ordering::Release);
+        }
+        let wrap_counter = self
+            .header_u8(PACKED_OLD_USED_WRAP_COUNTER)
+            .load(Ordering::Acquire);
+        self.header_u8(PACKED_USED_WRAP_COUNTER)
+            .store(wrap_counter, Ordering::Release);
+    }
+
+    fn next_counter(&self) -> u64 {
+        (0..self.region.queue_size())
+            .filter(|head| self.is_inflight(*head))
+            .map(|head| self.desc_counter(head).unwrap().load(Ordering::Acquire) + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn check_entry(&self, entry: u16) -> Result<()> {
+        if entry >= self.region.queue_size() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    fn header_u8(&self, offset: u64) -> &AtomicU8 {
+        // Safe because the field is within the region of the queue.
+        unsafe { &*((self.addr + offset) as *const AtomicU8) }
+    }
+
+    fn header_u16(&self, offset: u64) -> &AtomicU16 {
+        // Safe because the field is within the region of the queue, which is 64 bytes aligned.
+        unsafe { &*((self.addr + offset) as *const AtomicU16) }
+    }
+
+    fn desc_addr(&self, entry: u16, offset: u64) -> Result<u64> {
+        self.check_entry(entry)?;
+        Ok(self.addr + PACKED_DESC + u64::from(entry) * DESC_PACKED_SIZE + offset)
+    }
+
+    fn desc_inflight(&self, entry: u16) -> Result<&AtomicU8> {
+        let addr = self.desc_addr(entry, DESC_PACKED_INFLIGHT)?;
+        // Safe because the descriptor state is within the region of the queue.
+        Ok(unsafe { &*(addr as *const AtomicU8) })
+    }
+
+    fn desc_u16(&self, entry: u16, offset: u64) -> Result<&AtomicU16> {
+        let addr = self.desc_addr(entry, offset)?;
+        // Safe because the descriptor state is within the region of the queue, and the field is
+        // aligned as the descriptor states are 32 bytes each.
+        Ok(unsafe { &*(addr as *const AtomicU16) })
+    }
+
+    fn desc_u32(&self, entry: u16, offset: u64) -> Result<&AtomicU32> {
+        let addr = self.desc_addr(entry, offset)?;
+        // Safe because the descriptor state is within the region of the queue, and the field is
+        // aligned as the descriptor states are 32 bytes each.
+        Ok(unsafe { &*(addr as *const AtomicU32) })
+    }
+
+    fn desc_u64(&self, entry: u16, offset: u64) -> Result<&AtomicU64> {
+        let addr = self.desc_addr(entry, offset)?;
+        // Safe because the descriptor state is within the region of the queue, and the field is
+        // aligned as the descriptor states are 32 bytes each.
+        Ok(unsafe { &*(addr as *const AtomicU64) })
+    }
+
+    fn desc_counter(&self, head: u16) -> Result<&AtomicU64> {
+        self.desc_u64(head, DESC_PACKED_COUNTER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    use super::super::message::DescStateSplit;
+
+    #[test]
+    fn test_inflight_region() {
+        assert!(InflightRegion::new(0, 256, false).is_err());
+
+        let region = InflightRegion::new(2, 256, false).unwrap();
+        let inflight = region.inflight();
+        assert_eq!(inflight.mmap_size, inflight.split_mmap_size());
+        assert_eq!(region.file().metadata().unwrap().len(), inflight.mmap_size);
+        let addr = region.queue_region_addr(1).unwrap();
+        assert_eq!(
+            addr - region.queue_region_addr(0).unwrap(),
+            QueueRegionSplit::region_size(256)
+        );
+        assert!(region.queue_region_addr(2).is_none());
+
+        // Safe because the header of the queue region is within the mapping.
+        let header = unsafe { ptr::read_unaligned(addr as *const QueueRegionSplit) };
+        assert_eq!({ header.version }, 1);
+        assert_eq!({ header.desc_num }, 256);
+
+        // A buffer mapped again, as after a reconnection, shares the state.
+        let file = region.file().try_clone().unwrap();
+        let mapped = InflightRegion::from_file(&inflight, file, false).unwrap();
+        let addr = mapped.queue_region_addr(1).unwrap();
+        // Safe because the header of the queue region is within the mapping.
+        let header = unsafe { ptr::read_unaligned(addr as *const QueueRegionSplit) };
+        assert_eq!({ header.desc_num }, 256);
+
+        // The buffer is too small for packed virtqueues.
+        let file = region.file().try_clone().unwrap();
+        assert!(InflightRegion::from_file(&inflight, file, true).is_err());
+
+        let region = InflightRegion::new(1, 128, true).unwrap();
+        assert!(region.is_packed());
+        assert_eq!(
+            region.inflight().mmap_size,
+            QueueRegionPacked::region_size(128)
+        );
+    }
+
+    #[test]
+    fn test_inflight_queue_split() {
+        assert_eq!(mem::size_of::<DescStateSplit>() as u64, DESC_SPLIT_SIZE);
+        assert_eq!(mem::size_of::<QueueRegionSplit>() as u64 - 8, SPLIT_DESC);
+        let region = Arc::new(InflightRegion::new(2, 8, false).unwrap());
+        assert!(InflightQueueSplit::new(region.clone(), 2).is_err());
+        let mut queue = InflightQueueSplit::new(region.clone(), 1).unwrap();
+        queue.start_request(8).unwrap_err();
+
+        queue.start_request(5).unwrap();
+        queue.start_request(2).unwrap();
+        queue.start_request(7).unwrap();
+        assert!(queue.is_inflight(2));
+        queue.prepare_complete(2).unwrap();
+        queue.complete_request(2, 1).unwrap();
+        assert!(!queue.is_inflight(2));
+
+        // The slave crashed after pushing head 7 to the used ring, but before recording it.
+        queue.prepare_complete(7).unwrap();
+        let file = region.file().try_clone().unwrap();
+        let region = Arc::new(InflightRegion::from_file(&region.inflight(), file, false).unwrap());
+        let mut queue = InflightQueueSplit::new(region.clone(), 1).unwrap();
+        assert_eq!(queue.resubmit_list(1), vec![5, 7]);
+        assert_eq!(queue.resubmit_list(2), vec![5]);
+
+        // New requests are ordered after the resubmitted ones.
+        queue.start_request(0).unwrap();
+        assert_eq!(queue.resubmit_list(2), vec![5, 0]);
+
+        // The queues are tracked independently.
+        let mut other = InflightQueueSplit::new(region, 0).unwrap();
+        assert!(other.resubmit_list(0).is_empty());
+    }
+    #[test]
+    fn test_inflight_queue_packed() {
+        assert_eq!(mem::size_of::<DescStatePacked>() as u64, DESC_PACKED_SIZE);
+        assert!(QueueRegionPacked::region_size(8) >= PACKED_DESC + 8 * DESC_PACKED_SIZE);
+        let split = Arc::new(InflightRegion::new(1, 8, false).unwrap());
+        assert!(InflightQueuePacked::new(split, 0).is_err());
+        let region = Arc::new(InflightRegion::new(2, 8, true).unwrap());
+        assert!(InflightQueuePacked::new(region.clone(), 2).is_err());
+        let mut queue = InflightQueuePacked::new(region.clone(), 1).unwrap();
+        queue.start_request(0, &[]).unwrap_err();
+
+        let first = queue
+            .start_request(3, &[(0x1000, 16, 0), (0x2000, 32, 0)])
+            .unwrap();
+        let second = queue.start_request(1, &[(0x3000, 8, 0)]).unwrap();
+        let third = queue.start_request(2, &[(0x4000, 8, 0)]).unwrap();
+        let chain = queue.chain(first).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(({ chain[0].addr }, { chain[0].len }), (0x1000, 16));
+        assert_eq!(({ chain[1].addr }, { chain[1].id }), (0x2000, 3));
+        assert!(queue.is_inflight(second));
+        queue.prepare_complete(&[second], 1, true).unwrap();
+        queue.complete_request(&[second]).unwrap();
+        assert!(!queue.is_inflight(second));
+
+        // The entries of a completed request are reused.
+        let fourth = queue.start_request(4, &[(0x5000, 8, 0)]).unwrap();
+        assert_eq!(fourth, second);
+
+        // The slave crashed while completing the third request, before the driver saw it, and
+        // while starting a request.
+        queue.prepare_complete(&[third], 2, true).unwrap();
+        let free_head = queue
+            .header_u16(PACKED_OLD_FREE_HEAD)
+            .load(Ordering::Acquire);
+        queue
+            .desc_inflight(free_head)
+            .unwrap()
+            .store(1, Ordering::Release);
+        let file = region.file().try_clone().unwrap();
+        let region = Arc::new(InflightRegion::from_file(&region.inflight(), file, true).unwrap());
+        let mut queue = InflightQueuePacked::new(region.clone(), 1).unwrap();
+        assert_eq!(
+            queue.resubmit_list(|_, _| false),
+            vec![first, third, fourth]
+        );
+        assert_eq!({ queue.chain(fourth).unwrap()[0].addr }, 0x5000);
+
+        // The completion reached the driver this time.
+        queue.prepare_complete(&[third], 2, true).unwrap();
+        assert_eq!(
+            queue.resubmit_list(|index, wrap_counter| {
+                assert_eq!((index, wrap_counter), (1, true));
+                true
+            }),
+            vec![first, fourth]
+        );
+
+        // New requests are ordered after the resubmitted ones.
+        let fifth = queue.start_request(5, &[(0x6000, 8, 0)]).unwrap();
+        assert_eq!(
+            queue.resubmit_list(|_, _| false),
+            vec![first, fourth, fifth]
+        );
+
+        // The queues are tracked independently.
+        let mut other = InflightQueuePacked::new(region, 0).unwrap();
+        assert!(other.resubmit_list(|_, _| false).is_empty());
+    }
+}
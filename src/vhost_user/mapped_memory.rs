@@ -0,0 +1,732 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest memory regions mapped by the slave, with the translation of the master addresses.
+
+use std::fs::File;
+use std::io::Error as IOError;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use super::message::{VhostUserMemoryRegion, VhostUserSingleMemoryRegion};
+use super::userfaultfd::Userfaultfd;
+use super::{Error, Result};
+
+// Type of the hugetlbfs filesystem in struct statfs.
+const HUGETLBFS_MAGIC: i64 = 0x958458f6;
+
+/// Mapping of the guest memory regions requiring Xen foreign or grant mappings, which the
+/// [MemoryMappingManager] can't map with mmap().
+///
+/// [MemoryMappingManager]: struct.MemoryMappingManager.html
+#[cfg(feature = "xen")]
+pub trait XenMmap: Send + Sync {
+    /// Map `region` from `file` as described by its Xen mmap flags and domain, returning the
+    /// address of the region in the address space of the slave.
+    fn map(&self, region: &VhostUserSingleMemoryRegion, file: &File) -> Result<u64>;
+
+    /// Unmap `region`, mapped at `addr` by [Self::map()].
+    ///
+    /// [Self::map()]: trait.XenMmap.html#tymethod.map
+    fn unmap(&self, region: &VhostUserSingleMemoryRegion, addr: u64);
+}
+
+// How a region was mapped, to unmap it.
+enum Mapping {
+    // mmap() of `size` bytes at `addr`
+    Mmap {
+        addr: u64,
+        size: usize,
+    },
+    #[cfg(feature = "xen")]
+    Xen {
+        mmap: Arc<dyn XenMmap>,
+        region: VhostUserSingleMemoryRegion,
+    },
+}
+
+/// Guest memory region mapped in the address space of the slave.
+///
+/// The region is unmapped when dropped, so backends may keep the region in use after the master
+/// removed it by holding a reference to it.
+pub struct MappedRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    user_addr: u64,
+    mmap_offset: u64,
+    host_addr: u64,
+    page_size: u64,
+    mapping: Mapping,
+    file: File,
+}
+
+impl MappedRegion {
+    /// Map the region described by `region` from `file`, shared and writable.
+    pub fn new(region: &VhostUserSingleMemoryRegion, file: File) -> Result<Self> {
+        Self::mmap(region, file, 0, false)
+    }
+
+    // Map the region with the additional mmap() `flags`, starting at the page containing
+    // `mmap_offset`, failing if `aligned` and the region doesn't span whole pages.
+    fn mmap(
+        region: &VhostUserSingleMemoryRegion,
+        file: File,
+        flags: i32,
+        aligned: bool,
+    ) -> Result<Self> {
+        let page_size = Self::file_page_size(&file)?;
+        let mask = page_size - 1;
+        if aligned
+            && (region.guest_phys_addr | region.memory_size | region.user_addr | region.mmap_offset)
+                & mask
+                != 0
+        {
+            return Err(Error::InvalidParam);
+        }
+        let delta = region.mmap_offset & mask;
+        // Hugetlbfs mappings are unmapped by whole huge pages.
+        let mmap_size = region
+            .memory_size
+            .checked_add(delta)
+            .and_then(|size| size.checked_add(mask))
+            .ok_or(Error::InvalidParam)?
+            & !mask;
+
+        // Safe because a new mapping is created, and the return value is checked.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mmap_size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | flags,
+                file.as_raw_fd(),
+                (region.mmap_offset - delta) as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::ReqHandlerError(IOError::last_os_error()));
+        }
+
+        Ok(MappedRegion {
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            user_addr: region.user_addr,
+            mmap_offset: region.mmap_offset,
+            host_addr: addr as u64 + delta,
+            page_size,
+            mapping: Mapping::Mmap {
+                addr: addr as u64,
+                size: mmap_size as usize,
+            },
+            file,
+        })
+    }
+
+    #[cfg(feature = "xen")]
+    fn xen(
+        region: &VhostUserSingleMemoryRegion,
+        file: File,
+        mmap: Arc<dyn XenMmap>,
+    ) -> Result<Self> {
+        let host_addr = mmap.map(region, &file)?;
+        Ok(MappedRegion {
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            user_addr: region.user_addr,
+            mmap_offset: region.mmap_offset,
+            host_addr,
+            page_size: Self::file_page_size(&file)?,
+            mapping: Mapping::Xen {
+                mmap,
+                region: *region,
+            },
+            file,
+        })
+    }
+
+    // Get the size of the pages backing `file`, huge pages for hugetlbfs files.
+    fn file_page_size(file: &File) -> Result<u64> {
+        // Safe because statfs is plain data.
+        let mut stat: libc::statfs = unsafe { mem::zeroed() };
+        // Safe because the kernel only writes to `stat`, and the return value is checked.
+        let ret = unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) };
+        if ret < 0 {
+            return Err(Error::ReqHandlerError(IOError::last_os_error()));
+        }
+        if stat.f_type as i64 == HUGETLBFS_MAGIC {
+            return Ok(stat.f_bsize as u64);
+        }
+        // Safe because sysconf() doesn't access memory.
+        Ok(unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64)
+    }
+
+    /// Get the guest physical address of the region.
+    pub fn guest_phys_addr(&self) -> u64 {
+        self.guest_phys_addr
+    }
+
+    /// Get the size of the region.
+    pub fn memory_size(&self) -> u64 {
+        self.memory_size
+    }
+
+    /// Get the address of the region in the address space of the master.
+    pub fn user_addr(&self) -> u64 {
+        self.user_addr
+    }
+
+    /// Get the offset of the region in its file.
+    pub fn mmap_offset(&self) -> u64 {
+        self.mmap_offset
+    }
+
+    /// Get the address of the region in the address space of the slave.
+    pub fn host_addr(&self) -> u64 {
+        self.host_addr
+    }
+
+    /// Get the size of the pages backing the region, huge pages for hugetlbfs files.
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Get the file backing the region.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Get the address and size of the mmap() mapping holding the region, which starts at the
+    /// page containing `mmap_offset()` and spans whole pages, or None if the region was mapped
+    /// by the Xen mapping hooks.
+    pub fn mmap_range(&self) -> Option<(u64, u64)> {
+        match &self.mapping {
+            Mapping::Mmap { addr, size } => Some((*addr, *size as u64)),
+            #[cfg(feature = "xen")]
+            Mapping::Xen { .. } => None,
+        }
+    }
+
+    /// Translate the guest physical address `gpa` to an offset in the file backing the region,
+    /// to map part of the region again or share it with a device.
+    pub fn gpa_to_file_offset(&self, gpa: u64) -> Option<u64> {
+        match gpa.checked_sub(self.guest_phys_addr) {
+            Some(offset) if offset < self.memory_size => Some(self.mmap_offset + offset),
+            _ => None,
+        }
+    }
+
+    /// Translate the guest physical address `gpa` to an address of the slave.
+    pub fn gpa_to_hva(&self, gpa: u64) -> Option<u64> {
+        match gpa.checked_sub(self.guest_phys_addr) {
+            Some(offset) if offset < self.memory_size => Some(self.host_addr() + offset),
+            _ => None,
+        }
+    }
+
+    /// Translate the address `hva` of the slave to a guest physical address.
+    pub fn hva_to_gpa(&self, hva: u64) -> Option<u64> {
+        match hva.checked_sub(self.host_addr) {
+            Some(offset) if offset < self.memory_size => Some(self.guest_phys_addr + offset),
+            _ => None,
+        }
+    }
+
+    /// Translate the address `addr` of the master to an address of the slave.
+    pub fn vmm_va_to_hva(&self, addr: u64) -> Option<u64> {
+        match addr.checked_sub(self.user_addr) {
+            Some(offset) if offset < self.memory_size => Some(self.host_addr() + offset),
+            _ => None,
+        }
+    }
+
+    /// Translate the `len` bytes at the guest physical address `gpa` to an address of the slave,
+    /// if they are all within the region.
+    pub fn gpa_range_to_hva(&self, gpa: u64, len: u64) -> Option<u64> {
+        Self::range_offset(self.guest_phys_addr, self.memory_size, gpa, len)
+            .map(|offset| self.host_addr() + offset)
+    }
+
+    /// Translate the `len` bytes at the address `addr` of the master to an address of the
+    /// slave, if they are all within the region.
+    pub fn vmm_va_range_to_hva(&self, addr: u64, len: u64) -> Option<u64> {
+        Self::range_offset(self.user_addr, self.memory_size, addr, len)
+            .map(|offset| self.host_addr() + offset)
+    }
+
+    fn range_offset(start: u64, size: u64, addr: u64, len: u64) -> Option<u64> {
+        let offset = addr.checked_sub(start)?;
+        if offset.checked_add(len)? <= size {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn overlaps(&self, other: &MappedRegion) -> bool {
+        self.guest_phys_addr < other.guest_phys_addr + other.memory_size
+            && other.guest_phys_addr < self.guest_phys_addr + self.memory_size
+    }
+}
+
+impl Drop for MappedRegion {
+    fn drop(&mut self) {
+        match &self.mapping {
+            Mapping::Mmap { addr, size } => {
+                // Safe because the mapping was created by mmap() and isn't used anymore.
+                unsafe { libc::munmap(*addr as *mut libc::c_void, *size) };
+            }
+            #[cfg(feature = "xen")]
+            Mapping::Xen { mmap, region } => mmap.unmap(region, self.host_addr),
+        }
+    }
+}
+
+/// Table of the guest memory regions mapped by the slave.
+#[derive(Clone, Default)]
+pub struct MappedMemory {
+    regions: Vec<Arc<MappedRegion>>,
+}
+
+impl MappedMemory {
+    /// Get the regions of the table.
+    pub fn regions(&self) -> &[Arc<MappedRegion>] {
+        &self.regions
+    }
+
+    /// Translate the guest physical address `gpa` to an address of the slave.
+    pub fn gpa_to_hva(&self, gpa: u64) -> Option<u64> {
+        self.regions.iter().find_map(|r| r.gpa_to_hva(gpa))
+    }
+
+    /// Translate the address `hva` of the slave to a guest physical address.
+    pub fn hva_to_gpa(&self, hva: u64) -> Option<u64> {
+        self.regions.iter().find_map(|r| r.hva_to_gpa(hva))
+    }
+
+    /// Translate the address `addr` of the master, such as a vring address, to an address of
+    /// the slave.
+    pub fn vmm_va_to_hva(&self, addr: u64) -> Option<u64> {
+        self.regions.iter().find_map(|r| r.vmm_va_to_hva(addr))
+    }
+
+    /// Translate the `len` bytes at the guest physical address `gpa`, such as a buffer of a
+    /// descriptor, to an address of the slave if they are all within a region.
+    pub fn gpa_range_to_hva(&self, gpa: u64, len: u64) -> Option<u64> {
+        self.regions
+            .iter()
+            .find_map(|r| r.gpa_range_to_hva(gpa, len))
+    }
+
+    /// Translate the `len` bytes at the address `addr` of the master to an address of the slave
+    /// if they are all within a region.
+    pub fn vmm_va_range_to_hva(&self, addr: u64, len: u64) -> Option<u64> {
+        self.regions
+            .iter()
+            .find_map(|r| r.vmm_va_range_to_hva(addr, len))
+    }
+
+    /// Add `region` to the table, failing if it overlaps a region of the table.
+    pub fn add(&mut self, region: Arc<MappedRegion>) -> Result<()> {
+        if self.regions.iter().any(|r| r.overlaps(&region)) {
+            return Err(Error::InvalidParam);
+        }
+        self.regions.push(region);
+        Ok(())
+    }
+
+    /// Remove the region of `size` bytes at `guest_phys_addr` from the table.
+    pub fn remove(&mut self, guest_phys_addr: u64, size: u64) -> Option<Arc<MappedRegion>> {
+        let index = self
+            .regions
+            .iter()
+            .position(|r| r.guest_phys_addr == guest_phys_addr && r.memory_size == size)?;
+        Some(self.regions.remove(index))
+    }
+}
+
+/// Mapper of the guest memory regions sent by SET_MEM_TABLE and ADD_MEM_REG, holding the table
+/// of the regions mapped.
+///
+/// Regions are mapped shared and writable, aligned to the page size of their file, which is the
+/// huge page size for hugetlbfs files. Regions requiring Xen foreign or grant mappings are mapped
+/// by the [XenMmap] hooks, if set.
+///
+/// During a postcopy migration, the regions are registered with the [Userfaultfd] set by
+/// [Self::set_userfaultfd()] as they are mapped, before the backend can touch them, and
+/// unregistered as they are removed from the table.
+///
+/// [XenMmap]: trait.XenMmap.html
+/// [Userfaultfd]: struct.Userfaultfd.html
+/// [Self::set_userfaultfd()]: struct.MemoryMappingManager.html#method.set_userfaultfd
+#[derive(Clone, Default)]
+pub struct MemoryMappingManager {
+    memory: MappedMemory,
+    populate: bool,
+    noreserve: bool,
+    aligned: bool,
+    uffd: Option<Arc<Userfaultfd>>,
+    #[cfg(feature = "xen")]
+    xen: Option<Arc<dyn XenMmap>>,
+}
+
+impl MemoryMappingManager {
+    /// Create a manager with an empty table, mapping the regions lazily.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prefault the regions when mapping them, with MAP_POPULATE.
+    pub fn populate(mut self, enable: bool) -> Self {
+        self.populate = enable;
+        self
+    }
+
+    /// Don't reserve swap space for the regions, with MAP_NORESERVE.
+    pub fn noreserve(mut self, enable: bool) -> Self {
+        self.noreserve = enable;
+        self
+    }
+
+    /// Reject the regions whose guest address, size, master address or file offset isn't
+    /// aligned to the page size of their file.
+    pub fn aligned(mut self, enable: bool) -> Self {
+        self.aligned = enable;
+        self
+    }
+
+    /// Map the regions requiring Xen foreign or grant mappings with `mmap`.
+    #[cfg(feature = "xen")]
+    pub fn xen_mmap(mut self, mmap: Arc<dyn XenMmap>) -> Self {
+        self.xen = Some(mmap);
+        self
+    }
+
+    /// Get the table of the regions mapped.
+    pub fn memory(&self) -> &MappedMemory {
+        &self.memory
+    }
+
+    /// Replace the table of the regions mapped by `memory`.
+    ///
+    /// The regions of the previous table are unmapped once not referenced anymore.
+    pub fn set_memory(&mut self, memory: MappedMemory) {
+        let prev = mem::replace(&mut self.memory, memory);
+        for region in prev.regions() {
+            if !self.memory.regions().iter().any(|r| Arc::ptr_eq(r, region)) {
+                self.unregister(region);
+            }
+        }
+    }
+
+    /// Register the regions mapped afterwards with `uffd`, for a postcopy migration, or stop
+    /// registering them with `None`.
+    ///
+    /// The regions already mapped aren't registered, the master sends the table again once it
+    /// listens to the faults of the slave.
+    pub fn set_userfaultfd(&mut self, uffd: Option<Arc<Userfaultfd>>) {
+        self.uffd = uffd;
+    }
+
+    /// Get the userfaultfd the regions are registered with, if any.
+    pub fn userfaultfd(&self) -> Option<&Arc<Userfaultfd>> {
+        self.uffd.as_ref()
+    }
+
+    // Register the whole mapping of `region` with the userfaultfd, if any.
+    fn register(&self, region: &MappedRegion) -> Result<()> {
+        if let Some(uffd) = self.uffd.as_ref() {
+            let (addr, size) = region.mmap_range().ok_or(Error::InvalidOperation)?;
+            uffd.register(addr, size).map_err(Error::ReqHandlerError)?;
+        }
+        Ok(())
+    }
+
+    // Unregister `region` from the userfaultfd, if any.
+    fn unregister(&self, region: &MappedRegion) {
+        if let (Some(uffd), Some((addr, size))) = (self.uffd.as_ref(), region.mmap_range()) {
+            // The range is unregistered by the kernel anyway once unmapped.
+            let _ = uffd.unregister(addr, size);
+        }
+    }
+
+    /// Map `region` from `file`, without adding it to the table.
+    ///
+    /// The region is registered with the userfaultfd, if any.
+    pub fn map_region(
+        &self,
+        region: &VhostUserSingleMemoryRegion,
+        file: File,
+    ) -> Result<Arc<MappedRegion>> {
+        #[cfg(feature = "xen")]
+        {
+            if region.xen_mmap_flags != 0 {
+                let mmap = self.xen.clone().ok_or(Error::InvalidOperation)?;
+                let mapped = MappedRegion::xen(region, file, mmap)?;
+                // Xen mappings can't be registered, failing during a postcopy migration.
+                self.register(&mapped)?;
+                return Ok(Arc::new(mapped));
+            }
+        }
+        let mut flags = 0;
+        if self.populate {
+            flags |= libc::MAP_POPULATE;
+        }
+        if self.noreserve {
+            flags |= libc::MAP_NORESERVE;
+        }
+        let mapped = MappedRegion::mmap(region, file, flags, self.aligned)?;
+        self.register(&mapped)?;
+        Ok(Arc::new(mapped))
+    }
+
+    /// Map the regions of a SET_MEM_TABLE request from their `files` into a new table, without
+    /// replacing the table of the manager.
+    pub fn map_mem_table(
+        &self,
+        regions: &[VhostUserMemoryRegion],
+        files: Vec<File>,
+    ) -> Result<MappedMemory> {
+        if regions.len() != files.len() {
+            return Err(Error::InvalidParam);
+        }
+        let mut memory = MappedMemory::default();
+        for (region, file) in regions.iter().zip(files) {
+            let region = VhostUserSingleMemoryRegion::from(region);
+            memory.add(self.map_region(&region, file)?)?;
+        }
+        Ok(memory)
+    }
+
+    /// Map `region` from `file` and add it to the table, failing if it overlaps a region of the
+    /// table.
+    pub fn add_region(
+        &mut self,
+        region: &VhostUserSingleMemoryRegion,
+        file: File,
+    ) -> Result<Arc<MappedRegion>> {
+        let mapped = self.map_region(region, file)?;
+        self.memory.add(mapped.clone())?;
+        Ok(mapped)
+    }
+
+    /// Remove the region described by `region` from the table, unregistering it from the
+    /// userfaultfd, if any.
+    pub fn remove_region(
+        &mut self,
+        region: &VhostUserSingleMemoryRegion,
+    ) -> Option<Arc<MappedRegion>> {
+        let removed = self
+            .memory
+            .remove(region.guest_phys_addr, region.memory_size)?;
+        self.unregister(&removed);
+        Some(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn region_file(size: u64) -> File {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(size).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_mapped_memory() {
+        let file = region_file(0x3000);
+        let desc = VhostUserSingleMemoryRegion::new(0x10_0000, 0x2000, 0x7000_0000, 0x1000);
+        let region = Arc::new(MappedRegion::new(&desc, file.try_clone().unwrap()).unwrap());
+        assert_eq!(region.guest_phys_addr(), 0x10_0000);
+        assert_eq!(region.memory_size(), 0x2000);
+        assert_eq!(region.user_addr(), 0x7000_0000);
+        assert_eq!(region.mmap_offset(), 0x1000);
+        assert_eq!(region.mmap_range(), Some((region.host_addr(), 0x2000)));
+        assert_eq!(region.gpa_to_file_offset(0x10_1ff0), Some(0x2ff0));
+        assert!(region.gpa_to_file_offset(0x10_2000).is_none());
+
+        let mut memory = MappedMemory::default();
+        memory.add(region.clone()).unwrap();
+        let overlap = VhostUserSingleMemoryRegion::new(0x10_1000, 0x2000, 0x8000_0000, 0);
+        let overlap = MappedRegion::new(&overlap, file.try_clone().unwrap()).unwrap();
+        memory.add(Arc::new(overlap)).unwrap_err();
+
+        // Both the guest and the master addresses translate to the mapping.
+        let hva = memory.gpa_to_hva(0x10_0010).unwrap();
+        assert_eq!(memory.vmm_va_to_hva(0x7000_0010), Some(hva));
+        assert_eq!(hva, region.host_addr() + 0x10);
+        assert!(memory.gpa_to_hva(0x10_2000).is_none());
+        assert!(memory.vmm_va_to_hva(0x6fff_ffff).is_none());
+        assert_eq!(
+            memory.gpa_range_to_hva(0x10_1000, 0x1000),
+            Some(hva + 0xff0)
+        );
+        assert!(memory.gpa_range_to_hva(0x10_1000, 0x1001).is_none());
+        assert_eq!(memory.vmm_va_range_to_hva(0x7000_0010, 0x10), Some(hva));
+        assert!(memory.vmm_va_range_to_hva(0x7000_0010, u64::MAX).is_none());
+
+        // The mapping shares the file at the region offset.
+        // Safe because the address is within the mapping, which outlives the write.
+        unsafe { *(hva as *mut u8) = 0xa5 };
+        let mut buf = [0u8; 1];
+        std::os::unix::fs::FileExt::read_exact_at(&file, &mut buf, 0x1010).unwrap();
+        assert_eq!(buf[0], 0xa5);
+
+        assert_eq!(memory.hva_to_gpa(hva + 0x1fe0), Some(0x10_1ff0));
+        assert!(memory.hva_to_gpa(hva + 0x1ff0).is_none());
+        assert!(memory.remove(0x10_0000, 0x1000).is_none());
+        assert!(memory.remove(0x10_0000, 0x2000).is_some());
+        assert!(memory.regions().is_empty());
+    }
+
+    #[test]
+    fn test_memory_mapping_manager() {
+        let file = region_file(0x4000);
+        let mut manager = MemoryMappingManager::new()
+            .populate(true)
+            .noreserve(true)
+            .aligned(true);
+
+        // Unaligned regions are rejected.
+        let unaligned = VhostUserSingleMemoryRegion::new(0, 0x1000, 0x7000_0000, 0x800);
+        assert!(manager
+            .add_region(&unaligned, file.try_clone().unwrap())
+            .is_err());
+        let desc = VhostUserSingleMemoryRegion::new(0, 0x2000, 0x7000_0000, 0x1000);
+        let region = manager
+            .add_region(&desc, file.try_clone().unwrap())
+            .unwrap();
+        assert_eq!(region.page_size(), 0x1000);
+        assert!(region.mmap_range().is_some());
+        assert_eq!(manager.memory().gpa_to_hva(0), Some(region.host_addr()));
+        assert!(manager
+            .add_region(&desc, file.try_clone().unwrap())
+            .is_err());
+        assert!(manager.remove_region(&desc).is_some());
+        assert!(manager.memory().regions().is_empty());
+
+        // Tables are mapped without replacing the table of the manager.
+        let regions = [
+            VhostUserMemoryRegion::new(0, 0x1000, 0x7000_0000, 0),
+            VhostUserMemoryRegion::new(0x1000, 0x1000, 0x7000_1000, 0x1000),
+        ];
+        assert!(manager
+            .map_mem_table(&regions, vec![file.try_clone().unwrap()])
+            .is_err());
+        let files = vec![file.try_clone().unwrap(), file.try_clone().unwrap()];
+        let memory = manager.map_mem_table(&regions, files).unwrap();
+        assert!(manager.memory().regions().is_empty());
+        manager.set_memory(memory);
+        assert_eq!(manager.memory().regions().len(), 2);
+        assert!(manager.memory().vmm_va_to_hva(0x7000_1fff).is_some());
+    }
+
+    // Check whether the mapping at `addr` is registered with a userfaultfd.
+    fn is_uffd_registered(addr: u64) -> bool {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let start = format!("{:08x}-", addr);
+        let flags = smaps
+            .lines()
+            .skip_while(|line| !line.starts_with(&start))
+            .find(|line| line.starts_with("VmFlags:"))
+            .unwrap();
+        flags.split_whitespace().any(|flag| flag == "um")
+    }
+
+    #[test]
+    fn test_memory_mapping_manager_userfaultfd() {
+        // Userfaultfd may be restricted to privileged processes.
+        let uffd = match Userfaultfd::new() {
+            Ok(uffd) => Arc::new(uffd),
+            Err(_) => return,
+        };
+        // Missing pages of shared mappings are only reported for shmem files.
+        // Safe because the name is NUL terminated, and the return value is checked.
+        let fd = unsafe { libc::memfd_create(b"guest\0".as_ptr() as *const libc::c_char, 0) };
+        assert!(fd >= 0);
+        // Safe because the fd was just created and is owned by nobody else.
+        let file = unsafe { <File as std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+        file.set_len(0x4000).unwrap();
+
+        let mut manager = MemoryMappingManager::new();
+        let desc = VhostUserSingleMemoryRegion::new(0, 0x2000, 0x7000_0000, 0);
+        let before = manager
+            .add_region(&desc, file.try_clone().unwrap())
+            .unwrap();
+        assert!(!is_uffd_registered(before.host_addr()));
+        manager.remove_region(&desc).unwrap();
+
+        // Regions are registered as they are mapped, until the userfaultfd is unset.
+        manager.set_userfaultfd(Some(uffd.clone()));
+        assert!(manager.userfaultfd().is_some());
+        let region = manager
+            .add_region(&desc, file.try_clone().unwrap())
+            .unwrap();
+        assert!(is_uffd_registered(region.host_addr()));
+        let regions = [VhostUserMemoryRegion::new(
+            0x2000,
+            0x2000,
+            0x7000_2000,
+            0x2000,
+        )];
+        let memory = manager
+            .map_mem_table(&regions, vec![file.try_clone().unwrap()])
+            .unwrap();
+        let table = memory.regions()[0].clone();
+        assert!(is_uffd_registered(table.host_addr()));
+
+        // Regions removed from the table are unregistered, even if still mapped.
+        manager.remove_region(&desc).unwrap();
+        assert!(!is_uffd_registered(region.host_addr()));
+        let added = manager.add_region(&desc, file).unwrap();
+        manager.set_memory(memory);
+        assert!(!is_uffd_registered(added.host_addr()));
+        assert!(is_uffd_registered(table.host_addr()));
+
+        manager.set_userfaultfd(None);
+        manager.set_memory(MappedMemory::default());
+        assert!(is_uffd_registered(table.host_addr()));
+    }
+
+    #[cfg(feature = "xen")]
+    #[test]
+    fn test_memory_mapping_manager_xen() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct FakeXenMmap {
+            mapped: Mutex<Vec<u64>>,
+        }
+
+        impl XenMmap for FakeXenMmap {
+            fn map(&self, region: &VhostUserSingleMemoryRegion, _file: &File) -> Result<u64> {
+                self.mapped.lock().unwrap().push(region.guest_phys_addr);
+                Ok(0x1000_0000 + region.guest_phys_addr)
+            }
+
+            fn unmap(&self, region: &VhostUserSingleMemoryRegion, addr: u64) {
+                assert_eq!(addr, 0x1000_0000 + region.guest_phys_addr);
+                self.mapped
+                    .lock()
+                    .unwrap()
+                    .retain(|&gpa| gpa != region.guest_phys_addr);
+            }
+        }
+
+        let mut desc = VhostUserSingleMemoryRegion::new(0x2000, 0x1000, 0x7000_0000, 0);
+        desc.xen_mmap_flags = super::super::message::VhostUserXenMmapFlags::GRANT.bits();
+        let manager = MemoryMappingManager::new();
+        assert!(manager.map_region(&desc, region_file(0x1000)).is_err());
+
+        let xen = Arc::new(FakeXenMmap::default());
+        let manager = manager.xen_mmap(xen.clone());
+        let region = manager.map_region(&desc, region_file(0x1000)).unwrap();
+        assert_eq!(region.gpa_to_hva(0x2010), Some(0x1000_2010));
+        assert_eq!(*xen.mapped.lock().unwrap(), vec![0x2000]);
+        drop(region);
+        assert!(xen.mapped.lock().unwrap().is_empty());
+    }
+}
@@ -3,21 +3,26 @@
 
 //! Traits and Struct for vhost-user master.
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::mem;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::path::Path;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use vm_memory::ByteValued;
+use vm_memory::{ByteValued, GuestMemory, GuestMemoryRegion};
 use vmm_sys_util::eventfd::EventFd;
 
-use super::connection::Endpoint;
+use super::connection::{Endpoint, Listener};
+use super::master_req_handler::{MasterReqHandler, VhostUserMasterReqHandler};
 use super::message::*;
 use super::{take_single_file, Error as VhostUserError, Result as VhostUserResult};
 use crate::backend::{
-    VhostBackend, VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo, VringConfigData,
+    VhostBackend, VhostDevice, VhostFeatureOps, VhostLogOps, VhostMemOps, VhostUserDirtyLogRegion,
+    VhostUserMemoryRegionInfo, VhostVringOps, VringConfigData,
 };
 use crate::{Error, Result};
 
@@ -30,6 +35,9 @@ pub trait VhostUserMaster: VhostBackend {
     fn set_protocol_features(&mut self, features: VhostUserProtocolFeatures) -> Result<()>;
 
     /// Query how many queues the backend supports.
+    ///
+    /// The number of queues is only queried once after the protocol features have been
+    /// negotiated, and the queue index of the vring requests is checked against it.
     fn get_queue_num(&mut self) -> Result<u64>;
 
     /// Signal slave to enable or disable corresponding vring.
@@ -52,19 +60,69 @@ pub trait VhostUserMaster: VhostBackend {
     /// destination host to set readonly configuration space fields.
     fn set_config(&mut self, offset: u32, flags: VhostUserConfigFlags, buf: &[u8]) -> Result<()>;
 
+    /// Read a device configuration structure at `offset` in the configuration space.
+    fn get_config_struct<T: ByteValued>(
+        &mut self,
+        offset: u32,
+        flags: VhostUserConfigFlags,
+    ) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let size = mem::size_of::<T>();
+        if size == 0 || size > MAX_MSG_SIZE {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let mut val = T::default();
+        let (_, buf) = self.get_config(offset, size as u32, flags, val.as_slice())?;
+        if buf.len() != size {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+        val.as_mut_slice().copy_from_slice(&buf);
+        Ok(val)
+    }
+
+    /// Write a device configuration structure at `offset` in the configuration space.
+    fn set_config_struct<T: ByteValued>(
+        &mut self,
+        offset: u32,
+        flags: VhostUserConfigFlags,
+        val: &T,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let size = mem::size_of::<T>();
+        if size == 0 || size > MAX_MSG_SIZE {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        self.set_config(offset, flags, val.as_slice())
+    }
+
     /// Setup slave communication channel.
-    fn set_slave_request_fd(&mut self, fd: &dyn AsRawFd) -> Result<()>;
+    fn set_slave_request_fd(&mut self, fd: &dyn AsFd) -> Result<()>;
 
     /// Retrieve shared buffer for inflight I/O tracking.
+    ///
+    /// The slave allocates the buffer according to the number and size of the queues in
+    /// `inflight`, and returns it along with its size and offset in the returned file. The
+    /// buffer is laid out as an array of `QueueRegionSplit` or `QueueRegionPacked` regions.
+    /// The master must keep it to hand it back with `set_inflight_fd()` when reconnecting to
+    /// the slave.
     fn get_inflight_fd(
         &mut self,
         inflight: &VhostUserInflight,
     ) -> Result<(VhostUserInflight, File)>;
 
     /// Set shared buffer for inflight I/O tracking.
-    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: RawFd) -> Result<()>;
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: BorrowedFd) -> Result<()>;
 
     /// Query the maximum amount of memory slots supported by the backend.
+    ///
+    /// Requires the CONFIGURE_MEM_SLOTS protocol feature. The result tells how many regions may
+    /// be added with `add_mem_region()`, whereas `set_mem_table()` is limited to
+    /// MAX_ATTACHED_FD_ENTRIES regions.
     fn get_max_mem_slots(&mut self) -> Result<u64>;
 
     /// Add a new guest memory mapping for vhost to use.
@@ -72,16 +130,368 @@ pub trait VhostUserMaster: VhostBackend {
 
     /// Remove a guest memory mapping from vhost.
     fn remove_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()>;
+
+    /// Add a new guest memory mapping for vhost to use while the slave is in postcopy mode.
+    ///
+    /// In postcopy mode the slave replies with the region as mapped in its own address space
+    /// instead of an ack. Return the slave's userspace address of the region, which is needed
+    /// to handle the page faults reported through the userfaultfd.
+    fn add_mem_region_postcopy(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<u64>;
+
+    /// Advise the slave that a migration with postcopy enabled is underway.
+    ///
+    /// Return the userfaultfd opened by the slave, which reports the page faults on guest
+    /// memory not migrated yet.
+    fn postcopy_advise(&mut self) -> Result<File>;
+
+    /// Advise the slave that the migration has switched to postcopy mode.
+    ///
+    /// Memory table updates must use `set_mem_table_postcopy()` and
+    /// `add_mem_region_postcopy()` until `postcopy_end()` is called.
+    fn postcopy_listen(&mut self) -> Result<()>;
+
+    /// Advise the slave that the postcopy migration has completed.
+    fn postcopy_end(&mut self) -> Result<()>;
+
+    /// Set the memory map regions on the slave while it is in postcopy mode.
+    ///
+    /// The slave replies with the regions as mapped in its own address space, which is
+    /// acknowledged once received. Return the slave's userspace address of each region, in
+    /// the same order as `regions`.
+    fn set_mem_table_postcopy(&mut self, regions: &[VhostUserMemoryRegionInfo])
+        -> Result<Vec<u64>>;
+
+    /// Begin the transfer of the internal device state through a pipe.
+    ///
+    /// `fd` is the end of the pipe the slave should write the state to (`SAVE`) or read the
+    /// state from (`LOAD`). The slave may instead return its own pipe end, in which case the
+    /// state must be transferred through the returned file and `fd` must be closed.
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        fd: &dyn AsFd,
+    ) -> Result<Option<File>>;
+
+    /// Check whether the slave has successfully completed the device state transfer.
+    ///
+    /// Must be called once the pipe has been closed by the writer and drained by the reader.
+    fn check_device_state(&mut self) -> Result<()>;
+
+    /// Notify the slave of the virtio device status as defined in the VIRTIO specification.
+    fn set_status(&mut self, status: u8) -> Result<()>;
+
+    /// Query the slave for the virtio device status as defined in the VIRTIO specification.
+    fn get_status(&mut self) -> Result<u8>;
+
+    /// Reset the device state of the slave.
+    ///
+    /// Unlike `reset_owner()`, the slave disables all rings and resets all its internal device
+    /// state to the initial state while retaining the ownership of the session, so the device
+    /// may be reinitialized afterwards.
+    fn reset_device(&mut self) -> Result<()>;
+
+    /// Retrieve the virtio shared object identified by `uuid` exported by the slave.
+    ///
+    /// Return the file descriptor of the object, such as a dmabuf.
+    fn get_shared_object(&mut self, uuid: &VhostUserShared) -> Result<File>;
+
+    /// Create a crypto session on the slave with the parameters of `session`.
+    ///
+    /// Return the id of the new session.
+    fn create_crypto_session(&mut self, session: &VhostUserCryptoSession) -> Result<i64>;
+
+    /// Close the crypto session `session_id` previously created on the slave.
+    fn close_crypto_session(&mut self, session_id: i64) -> Result<()>;
+
+    /// Get the VIRTIO shared memory regions of the device.
+    ///
+    /// Return the id and size of each region. The slave maps files into the regions with
+    /// SHMEM_MAP requests on the slave communication channel.
+    fn get_shmem_config(&mut self) -> Result<Vec<(u8, u64)>>;
+
+    /// Set the position of a packed vring.
+    ///
+    /// Fail with `InvalidParam` if an index of `base` doesn't fit in 15 bits.
+    fn set_vring_base_packed(&mut self, queue_index: usize, base: VringPackedBase) -> Result<()>;
+
+    /// Stop a packed vring and get its position.
+    fn get_vring_base_packed(&mut self, queue_index: usize) -> Result<VringPackedBase>;
+}
+
+/// Policy to send again the requests which timed out or failed with a temporary socket error.
+///
+/// The slave may receive a request more than once, so retrying should only be used for requests
+/// which are safe to repeat.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of times a failed request is sent again.
+    pub max_retries: u32,
+    /// Delay before sending a request again.
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Create a new retry policy.
+    pub fn new(max_retries: u32, delay: Duration) -> Self {
+        RetryPolicy { max_retries, delay }
+    }
+}
+
+/// Policy to wait for the slave socket to become available when connecting.
+///
+/// The delay between attempts starts at `initial_delay` and doubles after each attempt, up to
+/// `max_delay`. No attempt is made once `deadline` has elapsed since the first one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectPolicy {
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_delay: Duration,
+    /// Overall time allowed to connect.
+    pub deadline: Duration,
+}
+
+impl ConnectPolicy {
+    /// Create a new connect policy.
+    pub fn new(initial_delay: Duration, max_delay: Duration, deadline: Duration) -> Self {
+        ConnectPolicy {
+            initial_delay,
+            max_delay,
+            deadline,
+        }
+    }
+}
+
+impl Default for ConnectPolicy {
+    fn default() -> Self {
+        ConnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of the connection to the slave, reported by `Master::check_connection()`.
+#[derive(Debug)]
+pub enum ConnectionStatus {
+    /// The slave is still connected.
+    Connected,
+    /// The slave closed the connection, or exited.
+    Hangup,
+    /// The socket reported an error, such as the connection being reset.
+    Error(std::io::Error),
+}
+
+impl ConnectionStatus {
+    /// Whether the connection to the slave is lost.
+    pub fn is_disconnected(&self) -> bool {
+        !matches!(self, ConnectionStatus::Connected)
+    }
+}
+
+/// Summary of a message exchanged with the slave, reported to a [MasterTracer].
+///
+/// [MasterTracer]: trait.MasterTracer.html
+#[derive(Clone, Copy, Debug)]
+pub struct MessageTrace<'a> {
+    /// Request code of the message.
+    pub code: MasterReq,
+    /// Flags of the message header.
+    pub flags: u32,
+    /// Message body and payload.
+    pub payload: &'a [u8],
+    /// Number of file descriptors attached to the message.
+    pub fds: usize,
+    /// Time elapsed between sending the request and receiving its reply, only set for replies.
+    pub latency: Option<Duration>,
+}
+
+/// Hooks invoked for each message exchanged with the slave, to debug protocol issues.
+pub trait MasterTracer: Send + Sync {
+    /// Called after a request has been sent to the slave.
+    fn on_send(&self, _msg: &MessageTrace) {}
+
+    /// Called after a reply has been received from the slave.
+    fn on_recv(&self, _msg: &MessageTrace) {}
+}
+
+/// Features a device needs from the slave, negotiated by `Master::negotiate()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceRequirements {
+    /// Virtio features the device can't work without.
+    pub required_features: u64,
+    /// Virtio features the device uses when the slave offers them.
+    pub optional_features: u64,
+    /// Protocol features the device can't work without.
+    pub required_protocol_features: VhostUserProtocolFeatures,
+    /// Protocol features the device uses when the slave offers them.
+    pub optional_protocol_features: VhostUserProtocolFeatures,
+}
+
+impl Default for DeviceRequirements {
+    fn default() -> Self {
+        DeviceRequirements {
+            required_features: 0,
+            optional_features: 0,
+            required_protocol_features: VhostUserProtocolFeatures::empty(),
+            optional_protocol_features: VhostUserProtocolFeatures::empty(),
+        }
+    }
+}
+
+/// Outcome of the negotiation performed by `Master::negotiate()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NegotiatedFeatures {
+    /// Virtio features acked to the slave.
+    pub features: u64,
+    /// Protocol features acked to the slave.
+    pub protocol_features: VhostUserProtocolFeatures,
+    /// Maximum number of queues supported by the slave.
+    pub queue_num: u64,
+}
+
+/// Capabilities negotiated with the slave, reported by `Master::capabilities()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MasterCapabilities {
+    /// Virtio features acked to the slave.
+    pub features: u64,
+    /// Protocol features acked to the slave.
+    pub protocol_features: VhostUserProtocolFeatures,
+    /// Maximum number of queues supported by the slave.
+    pub max_queues: u64,
+    /// Maximum number of memory regions the slave may map.
+    pub max_mem_slots: u64,
+}
+
+/// Configuration of a vring, set up by `Master::setup_queues()`.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueSetup<'a> {
+    /// Index of the vring.
+    pub queue_index: usize,
+    /// Size of the vring.
+    pub num: u16,
+    /// Addresses of the vring.
+    pub config_data: VringConfigData,
+    /// Base of the vring.
+    pub base: u16,
+    /// Eventfd to be notified by the guest, or `None` if the slave should poll the vring.
+    pub kick: Option<&'a EventFd>,
+    /// Eventfd to signal the guest, or `None` if the guest polls the vring.
+    pub call: Option<&'a EventFd>,
+    /// Eventfd to signal vring errors, not set if `None`.
+    pub err: Option<&'a EventFd>,
+    /// Whether to enable the vring, ignored unless PROTOCOL_FEATURES has been negotiated.
+    pub enable: bool,
+}
+
+/// Vring configuration recorded in a `MasterSnapshot`.
+///
+/// The eventfds are `-1` when the vring was configured without one, and `None` when it was never
+/// configured.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VringSnapshot {
+    /// Index of the vring.
+    pub queue_index: usize,
+    /// Size of the vring.
+    pub num: Option<u16>,
+    /// Addresses of the vring.
+    pub addr: Option<VringConfigData>,
+    /// Base of the vring, as last set by the master, in the packed encoding for packed vrings.
+    pub base: Option<u32>,
+    /// Eventfd to signal the guest.
+    pub call: Option<RawFd>,
+    /// Eventfd to be notified by the guest.
+    pub kick: Option<RawFd>,
+    /// Eventfd to signal vring errors.
+    pub err: Option<RawFd>,
+    /// Whether the vring is enabled.
+    pub enable: Option<bool>,
+}
+
+/// Memory region recorded in a `MasterSnapshot`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemRegionSnapshot {
+    /// Guest physical address of the memory region.
+    pub guest_phys_addr: u64,
+    /// Size of the memory region.
+    pub memory_size: u64,
+    /// Virtual address in the process taking the snapshot.
+    pub userspace_addr: u64,
+    /// Offset where region starts in the mapped memory.
+    pub mmap_offset: u64,
+    /// File descriptor for mmap.
+    pub mmap_handle: RawFd,
+    /// Xen specific flags describing how the region must be mapped.
+    #[cfg(feature = "xen")]
+    pub xen_mmap_flags: u32,
+    /// Xen domain id owning the memory region.
+    #[cfg(feature = "xen")]
+    pub xen_domid: u32,
+}
+
+/// State of a master negotiated with the slave, to let another process adopt the connection.
+///
+/// Produced by `Master::snapshot()` and consumed by `Master::restore()`. The file descriptors
+/// are the ones of the process taking the snapshot, they must be passed to the new process with
+/// the same numbers, for instance by inheriting them across `exec()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MasterSnapshot {
+    /// Socket connected to the slave.
+    pub sock_fd: RawFd,
+    /// Path of the slave socket, to reconnect to it.
+    pub path: Option<PathBuf>,
+    /// Virtio features offered by the slave.
+    pub features: u64,
+    /// Virtio features acked to the slave.
+    pub acked_features: u64,
+    /// Protocol features offered by the slave.
+    pub protocol_features: u64,
+    /// Protocol features acked to the slave, if they have been negotiated.
+    pub acked_protocol_features: Option<u64>,
+    /// Maximum number of queues supported by the slave.
+    pub max_queue_num: u64,
+    /// Memory table of the slave.
+    pub mem_regions: Vec<MemRegionSnapshot>,
+    /// Configuration of the vrings.
+    pub vrings: Vec<VringSnapshot>,
 }
 
+/// Hook invoked by [Master] when a request finds the connection to the slave broken.
+///
+/// The hook is called with the error which broke the connection, while the master is locked, so
+/// it must not use the master. It may notify the VMM to fail the device or to reconnect.
+///
+/// [Master]: struct.Master.html
+pub type DisconnectHandler = Box<dyn Fn(&VhostUserError) + Send>;
+
 fn error_code<T>(err: VhostUserError) -> Result<T> {
     Err(Error::VhostUserProtocol(err))
 }
 
+fn as_bytes<T: Sized>(msg: &T) -> &[u8] {
+    // Safe because the slice covers exactly the memory of `msg`.
+    unsafe { std::slice::from_raw_parts(msg as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn try_clone_eventfd(fd: Option<&EventFd>) -> VhostUserResult<Option<EventFd>> {
+    fd.map(|fd| fd.try_clone().map_err(|_| VhostUserError::InvalidParam))
+        .transpose()
+}
+
 /// Struct for the vhost-user master endpoint.
+///
+/// The master may be cloned and shared between threads. The lock protecting the connection is
+/// only held while sending a request and while receiving its reply, so other threads may send
+/// their own requests while a reply is pending.
 #[derive(Clone)]
 pub struct Master {
     node: Arc<Mutex<MasterInternal>>,
+    replies: Arc<ReplyQueue>,
 }
 
 impl Master {
@@ -96,9 +506,28 @@ impl Master {
                 acked_protocol_features: 0,
                 protocol_features_ready: false,
                 max_queue_num,
+                queue_num: None,
+                max_mem_slots: None,
+                config_size: VHOST_USER_CONFIG_SIZE,
+                disconnected: false,
                 error: None,
+                disconnect_handler: None,
                 hdr_flags: VhostUserHeaderFlag::empty(),
+                auto_reply_ack: false,
+                split_mem_table: false,
+                strict_order: false,
+                handshake: Handshake::default(),
+                path: None,
+                state_log: None,
+                retry_policy: RetryPolicy::default(),
+                next_ticket: 0,
+                connection_id: 0,
+                lost_replies: 0,
+                tracer: None,
+                last_send: None,
+                reply_send: None,
             })),
+            replies: Arc::new(ReplyQueue::default()),
         }
     }
 
@@ -106,6 +535,89 @@ impl Master {
         self.node.lock().unwrap()
     }
 
+    // Receive the reply to the request just sent with `node`, by running `f` once the replies to
+    // the previous requests have been received. The lock is released while waiting.
+    fn wait_reply<T, F>(&self, mut node: MutexGuard<MasterInternal>, f: F) -> VhostUserResult<T>
+    where
+        F: FnOnce(&mut MasterInternal) -> VhostUserResult<T>,
+    {
+        let pending = node.pending_reply();
+        drop(node);
+        self.recv_pending(pending, f)
+    }
+
+    // Receive a reply by running `f`, once the replies to the requests sent before have been
+    // received.
+    //
+    // The slave replies to every request, so the reply of a request which timed out is still
+    // expected: it's discarded once received, so the next replies aren't mismatched.
+    fn recv_pending<T, F>(&self, pending: PendingReply, f: F) -> VhostUserResult<T>
+    where
+        F: FnOnce(&mut MasterInternal) -> VhostUserResult<T>,
+    {
+        self.replies.wait_turn(pending.ticket);
+        // Let the next reply be received even if `f` panics.
+        let _turn = ReplyTurn(&self.replies);
+        loop {
+            // The socket is only used under the lock, as the master may reconnect meanwhile.
+            let (fd, timeout) = {
+                let node = self.node();
+                node.check_connection(pending.connection_id)?;
+                (node.main_sock.as_raw_fd(), node.main_sock.timeout())
+            };
+            let ready = wait_readable(fd, timeout);
+            let mut node = self.node();
+            node.check_connection(pending.connection_id)?;
+            if let Err(e) = ready {
+                if let VhostUserError::Timeout = e {
+                    node.lost_replies += 1;
+                }
+                return Err(e);
+            }
+            if node.lost_replies > 0 {
+                node.discard_reply().map_err(|e| node.poison(e))?;
+                node.lost_replies -= 1;
+                continue;
+            }
+            node.reply_send = pending.sent;
+            return f(&mut node).map_err(|e| match e {
+                // The reply was only partially received before the socket timed out.
+                VhostUserError::Timeout => node.poison(VhostUserError::PartialMessage),
+                e => node.poison(e),
+            });
+        }
+    }
+
+    // Lock the master once the replies of all the requests sent have been received, so a
+    // request may be sent and its reply received without releasing the lock.
+    fn idle_node(&self) -> VhostUserResult<MutexGuard<MasterInternal>> {
+        loop {
+            let mut node = self.node();
+            let ticket = node.next_ticket;
+            if self.replies.is_served(ticket) {
+                while node.lost_replies > 0 {
+                    node.discard_reply().map_err(|e| node.poison(e))?;
+                    node.lost_replies -= 1;
+                }
+                return Ok(node);
+            }
+            drop(node);
+            self.replies.wait_served(ticket);
+        }
+    }
+
+    fn wait_for_ack(
+        &self,
+        node: MutexGuard<MasterInternal>,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> Result<()> {
+        if !node.expects_ack(hdr) {
+            return Ok(());
+        }
+        self.wait_reply(node, |node| node.recv_ack(hdr))
+            .map_err(|e| e.into())
+    }
+
     /// Create a new instance from a Unix stream socket.
     pub fn from_stream(sock: UnixStream, max_queue_num: u64) -> Self {
         Self::new(Endpoint::<MasterReq>::from_stream(sock), max_queue_num)
@@ -118,6 +630,51 @@ impl Master {
     /// # Arguments
     /// * `path` - path of Unix domain socket listener to connect to
     pub fn connect<P: AsRef<Path>>(path: P, max_queue_num: u64) -> Result<Self> {
+        let endpoint = Self::connect_endpoint(&path)?;
+        let master = Self::new(endpoint, max_queue_num);
+        master.node().path = Some(path.as_ref().to_owned());
+
+        Ok(master)
+    }
+
+    /// Create a new vhost-user master endpoint, waiting for the slave to start listening.
+    ///
+    /// Connection attempts failing because the socket doesn't exist yet or nobody listens on it
+    /// are retried according to `policy`. Other errors, and the last error once the deadline
+    /// has elapsed, are returned to the caller.
+    ///
+    /// # Arguments
+    /// * `path` - path of Unix domain socket listener to connect to
+    /// * `max_queue_num` - maximum number of queues supported by the master
+    /// * `policy` - backoff and deadline of the connection attempts
+    pub fn connect_with_retry<P: AsRef<Path>>(
+        path: P,
+        max_queue_num: u64,
+        policy: ConnectPolicy,
+    ) -> Result<Self> {
+        let deadline = Instant::now() + policy.deadline;
+        let mut delay = policy.initial_delay;
+        let endpoint = loop {
+            match Endpoint::<MasterReq>::connect(&path) {
+                Ok(endpoint) => break endpoint,
+                Err(VhostUserError::SocketConnect(why)) if is_connect_retryable(&why) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(VhostUserError::SocketConnect(why).into());
+                    }
+                    std::thread::sleep(std::cmp::min(delay, deadline - now));
+                    delay = std::cmp::min(delay * 2, policy.max_delay);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let master = Self::new(endpoint, max_queue_num);
+        master.node().path = Some(path.as_ref().to_owned());
+
+        Ok(master)
+    }
+
+    fn connect_endpoint<P: AsRef<Path>>(path: P) -> Result<Endpoint<MasterReq>> {
         let mut retry_count = 5;
         let endpoint = loop {
             match Endpoint::<MasterReq>::connect(&path) {
@@ -137,1000 +694,4596 @@ impl Master {
             }
         }?;
 
-        Ok(Self::new(endpoint, max_queue_num))
-    }
-
-    /// Set the header flags that should be applied to all following messages.
-    pub fn set_hdr_flags(&self, flags: VhostUserHeaderFlag) {
-        let mut node = self.node();
-        node.hdr_flags = flags;
-    }
-}
-
-impl VhostBackend for Master {
-    /// Get from the underlying vhost implementation the feature bitmask.
-    fn get_features(&self) -> Result<u64> {
-        let mut node = self.node();
-        let hdr = node.send_request_header(MasterReq::GET_FEATURES, None)?;
-        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
-        node.virtio_features = val.value;
-        Ok(node.virtio_features)
+        Ok(endpoint)
     }
 
-    /// Enable features in the underlying vhost implementation using a bitmask.
-    fn set_features(&self, features: u64) -> Result<()> {
-        let mut node = self.node();
-        let val = VhostUserU64::new(features);
-        let hdr = node.send_request_with_body(MasterReq::SET_FEATURES, &val, None)?;
-        node.acked_virtio_features = features & node.virtio_features;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Check whether the slave is still connected, without waiting.
+    ///
+    /// The socket returned by `as_raw_fd()` may be registered in the epoll loop of the VMM with
+    /// `EPOLLRDHUP`, so that the death of the slave is noticed as soon as it happens rather than
+    /// by the next request. `EPOLLIN` is also reported while a reply is pending, so the epoll
+    /// events are only a hint and this method tells whether the connection is actually lost.
+    /// No reply is consumed from the socket.
+    ///
+    /// The socket changes when reconnecting, so it must be registered again afterwards.
+    pub fn check_connection(&self) -> Result<ConnectionStatus> {
+        let fd = self.as_raw_fd();
+        poll_connection(fd).map_err(Error::from)
     }
 
-    /// Set the current Master as an owner of the session.
-    fn set_owner(&self) -> Result<()> {
-        // We unwrap() the return value to assert that we are not expecting threads to ever fail
-        // while holding the lock.
-        let mut node = self.node();
-        let hdr = node.send_request_header(MasterReq::SET_OWNER, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
-    }
+    /// Get the capabilities negotiated with the slave, once the handshake is complete.
+    ///
+    /// The number of queues and memory slots are queried from the slave the first time, when MQ
+    /// and CONFIGURE_MEM_SLOTS have been negotiated, and cached until the protocol features
+    /// change. Otherwise the number of queues is the one the master was created with, and the
+    /// number of memory slots is the number of regions a single SET_MEM_TABLE may carry.
+    pub fn capabilities(&self) -> Result<MasterCapabilities> {
+        let protocol_features = self.acked_protocol_features();
+        let max_queues = if protocol_features.contains(VhostUserProtocolFeatures::MQ) {
+            self.query_queue_num()?
+        } else {
+            self.node().max_queue_num
+        };
+        let max_mem_slots =
+            if protocol_features.contains(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS) {
+                self.query_max_mem_slots()?
+            } else {
+                MAX_ATTACHED_FD_ENTRIES as u64
+            };
 
-    fn reset_owner(&self) -> Result<()> {
-        let mut node = self.node();
-        let hdr = node.send_request_header(MasterReq::RESET_OWNER, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+        Ok(MasterCapabilities {
+            features: self.acked_features(),
+            protocol_features,
+            max_queues,
+            max_mem_slots,
+        })
     }
 
-    /// Set the memory map regions on the slave so it can translate the vring
-    /// addresses. In the ancillary data there is an array of file descriptors
-    fn set_mem_table(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
-        if regions.is_empty() || regions.len() > MAX_ATTACHED_FD_ENTRIES {
-            return error_code(VhostUserError::InvalidParam);
+    /// Reset the device, with the mechanism negotiated with the slave.
+    ///
+    /// RESET_DEVICE is used when negotiated. Otherwise, when STATUS has been negotiated, all the
+    /// vrings are disabled before setting the device status to zero, as done by a driver
+    /// resetting a virtio device. Unlike `reset_owner()`, the session and the memory table are
+    /// kept, so the vrings may be set up again afterwards. The recorded vring configurations are
+    /// dropped, so reconnecting doesn't restore them.
+    ///
+    /// Fail with `ProtocolFeatureNotNegotiated` if neither RESET_DEVICE nor STATUS has been
+    /// negotiated.
+    pub fn reset(&mut self) -> Result<()> {
+        let protocol_features = self.acked_protocol_features();
+        if protocol_features.contains(VhostUserProtocolFeatures::RESET_DEVICE) {
+            return self.reset_device();
+        }
+        if !protocol_features.contains(VhostUserProtocolFeatures::STATUS) {
+            return error_code(VhostUserError::ProtocolFeatureNotNegotiated(
+                VhostUserProtocolFeatures::RESET_DEVICE | VhostUserProtocolFeatures::STATUS,
+            ));
         }
 
-        let mut ctx = VhostUserMemoryContext::new();
-        for region in regions.iter() {
-            if region.memory_size == 0 || region.mmap_handle < 0 {
-                return error_code(VhostUserError::InvalidParam);
-            }
-            let reg = VhostUserMemoryRegion {
-                guest_phys_addr: region.guest_phys_addr,
-                memory_size: region.memory_size,
-                user_addr: region.userspace_addr,
-                mmap_offset: region.mmap_offset,
-            };
-            ctx.append(&reg, region.mmap_handle);
+        let queue_num = if protocol_features.contains(VhostUserProtocolFeatures::MQ) {
+            self.query_queue_num()?
+        } else {
+            self.node().max_queue_num
+        };
+        for queue_index in 0..queue_num as usize {
+            self.enable_vring(queue_index, false)?;
         }
+        VhostDevice::set_status(self, 0)?;
+        self.node().record(|log| {
+            log.inflight = None;
+            log.vrings.clear();
+            Ok(())
+        })?;
+        Ok(())
+    }
 
-        let mut node = self.node();
-        let body = VhostUserMemory::new(ctx.regions.len() as u32);
-        let (_, payload, _) = unsafe { ctx.regions.align_to::<u8>() };
-        let hdr = node.send_request_with_payload(
-            MasterReq::SET_MEM_TABLE,
-            &body,
-            payload,
-            Some(ctx.fds.as_slice()),
-        )?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Get a handle to configure the vring `queue_index`.
+    ///
+    /// Fail with `QueueIndexOutOfRange` if the slave doesn't support that many queues. When MQ
+    /// has been negotiated, the number of queues is queried from the slave the first time.
+    pub fn queue(&self, queue_index: usize) -> Result<MasterQueue> {
+        drop(self.vring_node(queue_index)?);
+        Ok(MasterQueue {
+            master: self,
+            queue_index,
+        })
     }
 
-    // Clippy doesn't seem to know that if let with && is still experimental
-    #[allow(clippy::unnecessary_unwrap)]
-    fn set_log_base(&self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
-        let mut node = self.node();
-        let val = VhostUserU64::new(base);
+    /// Get the virtio features acked by the last SET_FEATURES request.
+    pub fn acked_features(&self) -> u64 {
+        self.node().acked_virtio_features
+    }
 
-        if node.acked_protocol_features & VhostUserProtocolFeatures::LOG_SHMFD.bits() != 0
-            && region.is_some()
-        {
-            let region = region.unwrap();
-            let log = VhostUserLog {
-                mmap_size: region.mmap_size,
-                mmap_offset: region.mmap_offset,
-            };
-            let hdr = node.send_request_with_body(
-                MasterReq::SET_LOG_BASE,
-                &log,
-                Some(&[region.mmap_handle]),
-            )?;
-            node.wait_for_ack(&hdr).map_err(|e| e.into())
-        } else {
-            let _ = node.send_request_with_body(MasterReq::SET_LOG_BASE, &val, None)?;
-            Ok(())
-        }
+    /// Get the protocol features acked by the last SET_PROTOCOL_FEATURES request.
+    pub fn acked_protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::from_bits_truncate(self.node().acked_protocol_features)
     }
 
-    fn set_log_fd(&self, fd: RawFd) -> Result<()> {
+    /// Set the header flags that should be applied to all following messages.
+    pub fn set_hdr_flags(&self, flags: VhostUserHeaderFlag) {
         let mut node = self.node();
-        let fds = [fd];
-        let hdr = node.send_request_header(MasterReq::SET_LOG_FD, Some(&fds))?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+        node.hdr_flags = flags;
     }
 
-    /// Set the size of the queue.
-    fn set_vring_num(&self, queue_index: usize, num: u16) -> Result<()> {
-        let mut node = self.node();
-        if queue_index as u64 >= node.max_queue_num {
-            return error_code(VhostUserError::InvalidParam);
-        }
+    /// Request an ack for all the requests changing the state of the slave.
+    ///
+    /// Once REPLY_ACK has been negotiated, the NEED_REPLY flag is set on each request without a
+    /// reply of its own, and the request fails if the slave reports an error in its ack.
+    pub fn set_auto_reply_ack(&self, enable: bool) {
+        self.node().auto_reply_ack = enable;
+    }
 
-        let val = VhostUserVringState::new(queue_index as u32, num.into());
-        let hdr = node.send_request_with_body(MasterReq::SET_VRING_NUM, &val, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Let `set_mem_table()` send memory tables with more than MAX_ATTACHED_FD_ENTRIES regions.
+    ///
+    /// Adjacent regions backed by the same file are first coalesced. If there are still too
+    /// many regions, and CONFIGURE_MEM_SLOTS has been negotiated, the first regions are sent
+    /// with SET_MEM_TABLE and the others added one by one with ADD_MEM_REG, within the limit
+    /// reported by GET_MAX_MEM_SLOTS.
+    pub fn set_split_mem_table(&self, enable: bool) {
+        self.node().split_mem_table = enable;
     }
 
-    /// Sets the addresses of the different aspects of the vring.
-    fn set_vring_addr(&self, queue_index: usize, config_data: &VringConfigData) -> Result<()> {
-        let mut node = self.node();
-        if queue_index as u64 >= node.max_queue_num
-            || config_data.flags & !(VhostUserVringAddrFlags::all().bits()) != 0
-        {
-            return error_code(VhostUserError::InvalidParam);
-        }
+    /// Reject the requests sent out of the handshake order, instead of letting the slave fail.
+    ///
+    /// In strict mode, only the virtio feature requests and GET_PROTOCOL_FEATURES may be sent
+    /// before SET_OWNER, SET_FEATURES and SET_PROTOCOL_FEATURES must follow GET_FEATURES and
+    /// GET_PROTOCOL_FEATURES, and the memory table, dirty log and vring requests must follow
+    /// SET_FEATURES. The order followed by `negotiate()` is accepted. Requests
+    /// sent out of order fail with `RequestOutOfOrder` without reaching the slave.
+    pub fn set_strict_order(&self, enable: bool) {
+        self.node().strict_order = enable;
+    }
 
-        let val = VhostUserVringAddr::from_config_data(queue_index as u32, config_data);
-        let hdr = node.send_request_with_body(MasterReq::SET_VRING_ADDR, &val, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Whether a request found the connection to the slave broken.
+    ///
+    /// Once disconnected, all requests fail with `Disconnected` until `reconnect()` succeeds.
+    pub fn is_poisoned(&self) -> bool {
+        self.node().disconnected
     }
 
-    /// Sets the base offset in the available vring.
-    fn set_vring_base(&self, queue_index: usize, base: u16) -> Result<()> {
-        let mut node = self.node();
-        if queue_index as u64 >= node.max_queue_num {
-            return error_code(VhostUserError::InvalidParam);
-        }
+    /// Take the error which broke the connection to the slave, if any.
+    ///
+    /// The master stays disconnected after the error has been taken.
+    pub fn take_error(&self) -> Option<std::io::Error> {
+        self.node()
+            .error
+            .take()
+            .map(std::io::Error::from_raw_os_error)
+    }
 
-        let val = VhostUserVringState::new(queue_index as u32, base.into());
-        let hdr = node.send_request_with_body(MasterReq::SET_VRING_BASE, &val, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Set the hook invoked when a request finds the connection to the slave broken.
+    pub fn set_disconnect_handler(&self, handler: Option<DisconnectHandler>) {
+        self.node().disconnect_handler = handler;
     }
 
-    fn get_vring_base(&self, queue_index: usize) -> Result<u32> {
-        let mut node = self.node();
-        if queue_index as u64 >= node.max_queue_num {
+    /// Set the end of the device configuration space, for devices with a configuration space
+    /// larger than the default `VHOST_USER_CONFIG_SIZE`.
+    ///
+    /// GET_CONFIG and SET_CONFIG requests beyond the end are rejected locally, and each request
+    /// is limited to `VhostUserConfig::max_access_size()`.
+    pub fn set_config_size(&self, config_size: u32) -> Result<()> {
+        if config_size <= VHOST_USER_CONFIG_OFFSET {
             return error_code(VhostUserError::InvalidParam);
         }
-
-        let req = VhostUserVringState::new(queue_index as u32, 0);
-        let hdr = node.send_request_with_body(MasterReq::GET_VRING_BASE, &req, None)?;
-        let reply = node.recv_reply::<VhostUserVringState>(&hdr)?;
-        Ok(reply.num)
+        self.node().config_size = config_size;
+        Ok(())
     }
 
-    /// Set the event file descriptor to signal when buffers are used.
-    /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
-    /// is set when there is no file descriptor in the ancillary data. This signals that polling
-    /// will be used instead of waiting for the call.
-    fn set_vring_call(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
-        let mut node = self.node();
-        if queue_index as u64 >= node.max_queue_num {
-            return error_code(VhostUserError::InvalidParam);
+    /// Configure the vrings of `queues`.
+    ///
+    /// The size, addresses, base, kick, call and error eventfds of each vring are set in this
+    /// order before enabling it. All the requests are sent before waiting for the acks of the
+    /// slave. The first failure is reported by `Error::VringSetup` with the queue index and
+    /// the request which failed, the requests following it are not sent.
+    ///
+    /// The requests being pipelined, slaves deferring the ack of any of them refuse the
+    /// following ones, so the vrings of such slaves are set up with the individual requests.
+    pub fn setup_queues(&self, queues: &[QueueSetup]) -> Result<()> {
+        let mut pending = Vec::new();
+        let mut res = self.send_queue_setups(queues, &mut pending);
+        for (queue_index, hdr, reply) in pending {
+            let ack = self.recv_pending(reply, |node| node.recv_ack(&hdr));
+            if let (Ok(()), Err(e)) = (&res, ack) {
+                res = Err(Error::VringSetup(
+                    queue_index,
+                    hdr.get_code(),
+                    Box::new(e.into()),
+                ));
+            }
         }
-        let hdr = node.send_fd_for_vring(MasterReq::SET_VRING_CALL, queue_index, fd.as_raw_fd())?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+        res
     }
 
-    /// Set the event file descriptor for adding buffers to the vring.
-    /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
-    /// is set when there is no file descriptor in the ancillary data. This signals that polling
-    /// should be used instead of waiting for a kick.
-    fn set_vring_kick(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
-        let mut node = self.node();
-        if queue_index as u64 >= node.max_queue_num {
-            return error_code(VhostUserError::InvalidParam);
+    // Send the requests configuring `queues`, adding the acks to wait for to `pending`.
+    fn send_queue_setups(
+        &self,
+        queues: &[QueueSetup],
+        pending: &mut Vec<(usize, VhostUserMsgHeader<MasterReq>, PendingReply)>,
+    ) -> Result<()> {
+        let enable = self.node().acked_virtio_features
+            & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+            != 0;
+        for queue in queues.iter() {
+            let index = queue.queue_index;
+            let mut requests = vec![
+                MasterReq::SET_VRING_NUM,
+                MasterReq::SET_VRING_ADDR,
+                MasterReq::SET_VRING_BASE,
+                MasterReq::SET_VRING_KICK,
+                MasterReq::SET_VRING_CALL,
+            ];
+            if queue.err.is_some() {
+                requests.push(MasterReq::SET_VRING_ERR);
+            }
+            if enable {
+                requests.push(MasterReq::SET_VRING_ENABLE);
+            }
+
+            for code in requests {
+                let sent = self.vring_node(index).and_then(|mut node| {
+                    let hdr = match code {
+                        MasterReq::SET_VRING_NUM => node.send_vring_num(index, queue.num)?,
+                        MasterReq::SET_VRING_ADDR => {
+                            if queue.config_data.flags & !(VhostUserVringAddrFlags::all().bits())
+                                != 0
+                            {
+                                return error_code(VhostUserError::InvalidParam);
+                            }
+                            node.send_vring_addr(index, &queue.config_data)?
+                        }
+                        MasterReq::SET_VRING_BASE => {
+                            node.send_vring_base(index, queue.base.into())?
+                        }
+                        MasterReq::SET_VRING_KICK => node.send_vring_fd(code, index, queue.kick)?,
+                        MasterReq::SET_VRING_CALL => node.send_vring_fd(code, index, queue.call)?,
+                        MasterReq::SET_VRING_ERR => node.send_vring_fd(code, index, queue.err)?,
+                        _ => node.send_vring_enable(index, queue.enable)?,
+                    };
+                    if node.expects_ack(&hdr) {
+                        pending.push((index, hdr, node.pending_reply()));
+                    }
+                    Ok(())
+                });
+                if let Err(e) = sent {
+                    return Err(Error::VringSetup(index, code, Box::new(e)));
+                }
+            }
         }
-        let hdr = node.send_fd_for_vring(MasterReq::SET_VRING_KICK, queue_index, fd.as_raw_fd())?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+        Ok(())
     }
 
-    /// Set the event file descriptor to signal when error occurs.
-    /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
-    /// is set when there is no file descriptor in the ancillary data.
-    fn set_vring_err(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
-        let mut node = self.node();
-        if queue_index as u64 >= node.max_queue_num {
-            return error_code(VhostUserError::InvalidParam);
-        }
-        let hdr = node.send_fd_for_vring(MasterReq::SET_VRING_ERR, queue_index, fd.as_raw_fd())?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Establish the slave communication channel and return the handler serving it.
+    ///
+    /// A socket pair is created for the channel, one end is sent to the slave with
+    /// SET_SLAVE_REQ_FD and the returned [MasterReqHandler] serves the requests received on the
+    /// other end with `backend`. The REPLY_ACK flag of the handler follows the negotiated
+    /// protocol features.
+    ///
+    /// [MasterReqHandler]: struct.MasterReqHandler.html
+    pub fn create_slave_req_handler<S: VhostUserMasterReqHandler>(
+        &mut self,
+        backend: Arc<S>,
+    ) -> Result<MasterReqHandler<S>> {
+        let mut handler = MasterReqHandler::new(backend)?;
+        self.set_slave_request_fd(&handler.get_tx_fd())?;
+        let reply_ack =
+            self.node().acked_protocol_features & VhostUserProtocolFeatures::REPLY_ACK.bits() != 0;
+        handler.set_reply_ack_flag(reply_ack);
+
+        Ok(handler)
     }
-}
 
-impl VhostUserMaster for Master {
-    fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures> {
+    /// Send a device specific request with a code unknown to the crate and wait for its reply.
+    ///
+    /// Some slaves extend the protocol with private requests. `code` must be beyond the requests
+    /// defined by [MasterReq], and the slave must answer with a reply carrying the same code.
+    /// The payload and the files attached to the reply are returned. Custom requests are not
+    /// reported to the [MasterTracer].
+    ///
+    /// [MasterReq]: enum.MasterReq.html
+    /// [MasterTracer]: trait.MasterTracer.html
+    pub fn send_custom_request(
+        &self,
+        code: u32,
+        payload: &[u8],
+        fds: Option<&[BorrowedFd]>,
+    ) -> Result<(Vec<u8>, Option<Vec<File>>)> {
+        let fds: Option<Vec<RawFd>> = fds.map(|fds| fds.iter().map(|fd| fd.as_raw_fd()).collect());
         let mut node = self.node();
-        let flag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
-        if node.virtio_features & flag == 0 {
-            return error_code(VhostUserError::InvalidOperation);
+        let hdr = node.send_custom_request(code, payload, fds.as_deref())?;
+        self.wait_reply(node, |node| node.recv_custom_reply(&hdr))
+            .map_err(|e| e.into())
+    }
+
+    /// Negotiate the features of the device with the slave, and take ownership of it.
+    ///
+    /// The features offered by the slave are checked against `requirements`, and the required
+    /// ones together with the optional ones offered by the slave are acked. PROTOCOL_FEATURES is
+    /// acked whenever the slave offers it, and the number of queues is queried from the slave if
+    /// MQ has been negotiated.
+    ///
+    /// # Return:
+    /// * - `Error::UnsupportedFeatures`: the slave doesn't offer some required virtio features.
+    /// * - `FeatureMismatch`: the slave doesn't offer some required protocol features.
+    pub fn negotiate(&mut self, requirements: &DeviceRequirements) -> Result<NegotiatedFeatures> {
+        let offered = self.get_features()?;
+        let missing = requirements.required_features & !offered;
+        if missing != 0 {
+            return Err(Error::UnsupportedFeatures(missing));
         }
-        let hdr = node.send_request_header(MasterReq::GET_PROTOCOL_FEATURES, None)?;
-        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
-        node.protocol_features = val.value;
-        // Should we support forward compatibility?
-        // If so just mask out unrecognized flags instead of return errors.
-        match VhostUserProtocolFeatures::from_bits(node.protocol_features) {
-            Some(val) => Ok(val),
-            None => error_code(VhostUserError::InvalidMessage),
+        let features = offered
+            & (requirements.required_features
+                | requirements.optional_features
+                | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits());
+        self.set_features(features)?;
+        self.set_owner()?;
+
+        let mut protocol_features = VhostUserProtocolFeatures::empty();
+        if features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+            let offered = self.get_protocol_features()?;
+            protocol_features = offered
+                & (requirements.required_protocol_features
+                    | requirements.optional_protocol_features);
+            self.set_protocol_features(protocol_features)?;
         }
+        if !protocol_features.contains(requirements.required_protocol_features) {
+            return error_code(VhostUserError::FeatureMismatch);
+        }
+
+        let queue_num = if protocol_features.contains(VhostUserProtocolFeatures::MQ) {
+            self.get_queue_num()?
+        } else {
+            self.node().max_queue_num
+        };
+
+        Ok(NegotiatedFeatures {
+            features,
+            protocol_features,
+            queue_num,
+        })
     }
 
-    fn set_protocol_features(&mut self, features: VhostUserProtocolFeatures) -> Result<()> {
-        let mut node = self.node();
-        let flag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
-        if node.virtio_features & flag == 0 {
-            return error_code(VhostUserError::InvalidOperation);
+    /// Set the memory table of the slave from the regions of a guest memory object.
+    ///
+    /// Every region must be backed by a file, so the slave can map it.
+    pub fn set_mem_table_from_guest_memory<M: GuestMemory>(&self, mem: &M) -> Result<()> {
+        let mut regions = Vec::with_capacity(mem.num_regions());
+        for region in mem.iter() {
+            regions.push(VhostUserMemoryRegionInfo::from_guest_region(region)?);
         }
-        let val = VhostUserU64::new(features.bits());
-        let hdr = node.send_request_with_body(MasterReq::SET_PROTOCOL_FEATURES, &val, None)?;
-        // Don't wait for ACK here because the protocol feature negotiation process hasn't been
-        // completed yet.
-        node.acked_protocol_features = features.bits();
-        node.protocol_features_ready = true;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+        self.set_mem_table(&regions)
     }
 
-    fn get_queue_num(&mut self) -> Result<u64> {
-        let mut node = self.node();
-        if !node.is_feature_mq_available() {
-            return error_code(VhostUserError::InvalidOperation);
-        }
+    /// Add a file backed guest memory region to the memory table of the slave.
+    pub fn add_mem_region_from_guest_region<R: GuestMemoryRegion>(
+        &mut self,
+        region: &R,
+    ) -> Result<()> {
+        let region = VhostUserMemoryRegionInfo::from_guest_region(region)?;
+        self.add_mem_region(&region)
+    }
 
-        let hdr = node.send_request_header(MasterReq::GET_QUEUE_NUM, None)?;
-        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
-        if val.value > VHOST_USER_MAX_VRINGS {
-            return error_code(VhostUserError::InvalidMessage);
-        }
-        node.max_queue_num = val.value;
-        Ok(node.max_queue_num)
+    /// Set the timeout of sending a request and receiving its reply, or wait forever for the
+    /// slave if `timeout` is `None`.
+    ///
+    /// Requests which don't complete in time fail with `Error::Timeout`.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        let mut node = self.node();
+        node.main_sock.set_timeout(timeout)?;
+        Ok(())
     }
 
-    fn set_vring_enable(&mut self, queue_index: usize, enable: bool) -> Result<()> {
+    /// Set the policy used by `with_retry()` to send failed requests again.
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
         let mut node = self.node();
-        // set_vring_enable() is supported only when PROTOCOL_FEATURES has been enabled.
-        if node.acked_virtio_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
-            return error_code(VhostUserError::InvalidOperation);
-        } else if queue_index as u64 >= node.max_queue_num {
-            return error_code(VhostUserError::InvalidParam);
-        }
+        node.retry_policy = policy;
+    }
 
-        let flag = if enable { 1 } else { 0 };
-        let val = VhostUserVringState::new(queue_index as u32, flag);
-        let hdr = node.send_request_with_body(MasterReq::SET_VRING_ENABLE, &val, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Set the hooks invoked for each message exchanged with the slave, or `None` to stop
+    /// tracing.
+    pub fn set_tracer(&self, tracer: Option<Arc<dyn MasterTracer>>) {
+        self.node().tracer = tracer;
     }
 
-    fn get_config(
+    /// Run `f`, running it again according to the retry policy of the master if it timed out or
+    /// failed with a temporary socket error.
+    ///
+    /// The late replies of the attempts which timed out are discarded once received, and any
+    /// data left on the socket by a temporary socket error is discarded before trying again.
+    pub fn with_retry<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut Master) -> Result<T>,
+    {
+        let policy = self.node().retry_policy;
+        self.retry(policy, f)
+    }
+
+    /// Run `f` with the given timeout and retry policy instead of the ones of the master.
+    pub fn with_request_policy<T, F>(
         &mut self,
-        offset: u32,
-        size: u32,
-        flags: VhostUserConfigFlags,
-        buf: &[u8],
-    ) -> Result<(VhostUserConfig, VhostUserConfigPayload)> {
-        let body = VhostUserConfig::new(offset, size, flags);
-        if !body.is_valid() {
-            return error_code(VhostUserError::InvalidParam);
+        timeout: Option<Duration>,
+        policy: RetryPolicy,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnMut(&mut Master) -> Result<T>,
+    {
+        let saved = self.node().main_sock.timeout();
+        self.set_timeout(timeout)?;
+        let res = self.retry(policy, f);
+        self.set_timeout(saved)?;
+        res
+    }
+
+    fn retry<T, F>(&mut self, policy: RetryPolicy, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Master) -> Result<T>,
+    {
+        let mut retries = 0;
+        loop {
+            match f(self) {
+                Err(Error::VhostUserProtocol(VhostUserError::Timeout))
+                    if retries < policy.max_retries =>
+                {
+                    retries += 1;
+                    thread::sleep(policy.delay);
+                }
+                Err(Error::VhostUserProtocol(VhostUserError::SocketRetry(_)))
+                    if retries < policy.max_retries =>
+                {
+                    retries += 1;
+                    thread::sleep(policy.delay);
+                    self.node().main_sock.drain()?;
+                }
+                res => return res,
+            }
         }
+    }
 
-        let mut node = self.node();
-        // depends on VhostUserProtocolFeatures::CONFIG
-        if node.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
+    /// Record the state configured on the slave, so it can be restored by `reconnect()`.
+    ///
+    /// Only masters created by `connect()` may reconnect. Recording starts with the next
+    /// request, so it should be enabled before negotiating the features with the slave. The
+    /// file descriptors passed to the slave are duplicated and kept until they are replaced.
+    pub fn enable_reconnect(&self) -> Result<()> {
+        if self.node().path.is_none() {
             return error_code(VhostUserError::InvalidOperation);
         }
+        self.record_state();
+        Ok(())
+    }
 
-        // vhost-user spec states that:
-        // "Master payload: virtio device config space"
-        // "Slave payload: virtio device config space"
-        let hdr = node.send_request_with_payload(MasterReq::GET_CONFIG, &body, buf, None)?;
-        let (body_reply, buf_reply, rfds) =
-            node.recv_reply_with_payload::<VhostUserConfig>(&hdr)?;
-        if rfds.is_some() {
-            return error_code(VhostUserError::InvalidMessage);
-        } else if body_reply.size == 0 {
-            return error_code(VhostUserError::SlaveInternalError);
-        } else if body_reply.size != body.size
-            || body_reply.size as usize != buf.len()
-            || body_reply.offset != body.offset
-        {
-            return error_code(VhostUserError::InvalidMessage);
+    /// Record the state configured on the slave, so it can be saved by `snapshot()`.
+    ///
+    /// Recording starts with the next request, so it should be enabled before negotiating the
+    /// features with the slave.
+    pub fn record_state(&self) {
+        let mut node = self.node();
+        if node.state_log.is_none() {
+            node.state_log = Some(StateLog::default());
         }
+    }
 
-        Ok((body_reply, buf_reply))
+    /// Save the state negotiated with the slave, so another process can adopt the connection.
+    ///
+    /// The state must have been recorded with `record_state()` or `enable_reconnect()`. The file
+    /// descriptors in the snapshot are still owned by the master, so it must be kept alive
+    /// until they have been passed to the new process. The inflight buffer and the slave
+    /// request fd are not part of the snapshot.
+    pub fn snapshot(&self) -> Result<MasterSnapshot> {
+        let node = self.node();
+        let log = match node.state_log.as_ref() {
+            Some(log) => log,
+            None => return error_code(VhostUserError::InvalidOperation),
+        };
+        let fd = |fd: &Option<Option<EventFd>>| {
+            fd.as_ref()
+                .map(|fd| fd.as_ref().map_or(-1, |fd| fd.as_raw_fd()))
+        };
+
+        Ok(MasterSnapshot {
+            sock_fd: node.main_sock.as_raw_fd(),
+            path: node.path.clone(),
+            features: node.virtio_features,
+            acked_features: node.acked_virtio_features,
+            protocol_features: node.protocol_features,
+            acked_protocol_features: if node.protocol_features_ready {
+                Some(node.acked_protocol_features)
+            } else {
+                None
+            },
+            max_queue_num: node.max_queue_num,
+            mem_regions: log
+                .mem_regions
+                .iter()
+                .map(|(region, file)| MemRegionSnapshot {
+                    guest_phys_addr: region.guest_phys_addr,
+                    memory_size: region.memory_size,
+                    userspace_addr: region.user_addr,
+                    mmap_offset: region.mmap_offset,
+                    mmap_handle: file.as_raw_fd(),
+                    #[cfg(feature = "xen")]
+                    xen_mmap_flags: region.xen_mmap_flags,
+                    #[cfg(feature = "xen")]
+                    xen_domid: region.xen_domid,
+                })
+                .collect(),
+            vrings: log
+                .vrings
+                .iter()
+                .map(|(&queue_index, vring)| VringSnapshot {
+                    queue_index,
+                    num: vring.num,
+                    addr: vring.addr,
+                    base: vring.base,
+                    call: fd(&vring.call),
+                    kick: fd(&vring.kick),
+                    err: fd(&vring.err),
+                    enable: vring.enable,
+                })
+                .collect(),
+        })
     }
 
-    fn set_config(&mut self, offset: u32, flags: VhostUserConfigFlags, buf: &[u8]) -> Result<()> {
-        if buf.len() > MAX_MSG_SIZE {
-            return error_code(VhostUserError::InvalidParam);
+    /// Adopt the connection saved by `snapshot()` in another process.
+    ///
+    /// No message is exchanged with the slave, which keeps running with the configuration
+    /// recorded in the snapshot. The state keeps being recorded, so the master may reconnect
+    /// when the snapshot has a path.
+    ///
+    /// # Safety
+    ///
+    /// The master takes ownership of `snapshot.sock_fd`, which must be a socket connected to the
+    /// slave and not owned by anything else. The other file descriptors are duplicated, they
+    /// must be valid and stay owned by the caller.
+    pub unsafe fn restore(snapshot: &MasterSnapshot) -> Result<Self> {
+        let sock = UnixStream::from_raw_fd(snapshot.sock_fd);
+        let master = Self::from_stream(sock, snapshot.max_queue_num);
+
+        let mut log = StateLog::default();
+        if snapshot.acked_features != 0 {
+            log.features = Some(snapshot.acked_features);
         }
-        let body = VhostUserConfig::new(offset, buf.len() as u32, flags);
-        if !body.is_valid() {
-            return error_code(VhostUserError::InvalidParam);
+        log.protocol_features = snapshot
+            .acked_protocol_features
+            .map(VhostUserProtocolFeatures::from_bits_truncate);
+        for region in snapshot.mem_regions.iter() {
+            let entry = VhostUserMemoryRegion {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                user_addr: region.userspace_addr,
+                mmap_offset: region.mmap_offset,
+                #[cfg(feature = "xen")]
+                xen_mmap_flags: region.xen_mmap_flags,
+                #[cfg(feature = "xen")]
+                xen_domid: region.xen_domid,
+            };
+            log.mem_regions.push((entry, dup_file(region.mmap_handle)?));
+        }
+        let eventfd = |fd: Option<RawFd>| -> VhostUserResult<Option<Option<EventFd>>> {
+            match fd {
+                Some(fd) if fd >= 0 => Ok(Some(Some(EventFd::from_raw_fd(
+                    dup_file(fd)?.into_raw_fd(),
+                )))),
+                Some(_) => Ok(Some(None)),
+                None => Ok(None),
+            }
+        };
+        for vring in snapshot.vrings.iter() {
+            log.vrings.insert(
+                vring.queue_index,
+                VringLog {
+                    num: vring.num,
+                    addr: vring.addr,
+                    base: vring.base,
+                    call: eventfd(vring.call)?,
+                    kick: eventfd(vring.kick)?,
+                    err: eventfd(vring.err)?,
+                    enable: vring.enable,
+                },
+            );
         }
 
-        let mut node = self.node();
-        // depends on VhostUserProtocolFeatures::CONFIG
-        if node.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
-            return error_code(VhostUserError::InvalidOperation);
+        {
+            let mut node = master.node();
+            node.path = snapshot.path.clone();
+            node.virtio_features = snapshot.features;
+            node.acked_virtio_features = snapshot.acked_features;
+            node.protocol_features = snapshot.protocol_features;
+            node.acked_protocol_features = snapshot.acked_protocol_features.unwrap_or(0);
+            node.protocol_features_ready = snapshot.acked_protocol_features.is_some();
+            if node.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0 {
+                node.queue_num = Some(snapshot.max_queue_num);
+            }
+            node.state_log = Some(log);
         }
+        Ok(master)
+    }
 
-        let hdr = node.send_request_with_payload(MasterReq::SET_CONFIG, &body, buf, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Reconnect to the slave and restore the recorded state.
+    ///
+    /// The features, protocol features, slave request fd, memory table, inflight buffer and
+    /// vring configurations are sent again to the slave, so it resumes from where the previous
+    /// connection stopped. Reconnecting must be enabled with `enable_reconnect()`.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let (path, log) = {
+            let mut node = self.node();
+            match (node.path.clone(), node.state_log.take()) {
+                (Some(path), Some(log)) => (path, log),
+                (_, log) => {
+                    node.state_log = log;
+                    return error_code(VhostUserError::InvalidOperation);
+                }
+            }
+        };
+
+        let res = Self::connect_endpoint(&path).and_then(|endpoint| {
+            self.node().reset_connection(endpoint)?;
+            self.replay(&log)
+        });
+        // Keep the log even if replaying failed, so reconnecting may be tried again.
+        self.node().state_log = Some(log);
+        res
     }
 
-    fn set_slave_request_fd(&mut self, fd: &dyn AsRawFd) -> Result<()> {
-        let mut node = self.node();
-        if node.acked_protocol_features & VhostUserProtocolFeatures::SLAVE_REQ.bits() == 0 {
-            return error_code(VhostUserError::InvalidOperation);
+    /// Run `f`, reconnecting to the slave and running it again if the connection was lost.
+    ///
+    /// Reconnecting is only attempted for errors which may be recovered by reconnecting, and
+    /// when it has been enabled with `enable_reconnect()`.
+    pub fn with_reconnect<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Master) -> Result<T>,
+    {
+        match f(self) {
+            Err(Error::VhostUserProtocol(ref e))
+                if e.should_reconnect() && self.node().state_log.is_some() =>
+            {
+                self.reconnect()?;
+                f(self)
+            }
+            res => res,
         }
-        let fds = [fd.as_raw_fd()];
-        let hdr = node.send_request_header(MasterReq::SET_SLAVE_REQ_FD, Some(&fds))?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
     }
 
-    fn get_inflight_fd(
-        &mut self,
-        inflight: &VhostUserInflight,
-    ) -> Result<(VhostUserInflight, File)> {
-        let mut node = self.node();
-        if node.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits() == 0 {
-            return error_code(VhostUserError::InvalidOperation);
+    // Send the recorded state to the slave, in the order it must be configured.
+    fn replay(&mut self, log: &StateLog) -> Result<()> {
+        self.set_owner()?;
+
+        if let Some(features) = log.features {
+            if features & !self.get_features()? != 0 {
+                return error_code(VhostUserError::FeatureMismatch);
+            }
+            self.set_features(features)?;
+        }
+        if let Some(features) = log.protocol_features {
+            if !self.get_protocol_features()?.contains(features) {
+                return error_code(VhostUserError::FeatureMismatch);
+            }
+            self.set_protocol_features(features)?;
+        }
+        if let Some(file) = log.slave_req_fd.as_ref() {
+            self.set_slave_request_fd(file)?;
         }
 
-        let hdr = node.send_request_with_body(MasterReq::GET_INFLIGHT_FD, inflight, None)?;
-        let (inflight, files) = node.recv_reply_with_files::<VhostUserInflight>(&hdr)?;
+        let regions: Vec<VhostUserMemoryRegionInfo> = log
+            .mem_regions
+            .iter()
+            .map(|(region, file)| logged_mem_region(region, file))
+            .collect();
+        if regions.len() > MAX_ATTACHED_FD_ENTRIES {
+            for region in regions.iter() {
+                self.add_mem_region(region)?;
+            }
+        } else if !regions.is_empty() {
+            self.set_mem_table(&regions)?;
+        }
 
-        match take_single_file(files) {
-            Some(file) => Ok((inflight, file)),
-            None => error_code(VhostUserError::IncorrectFds),
+        if let Some((inflight, file)) = log.inflight.as_ref() {
+            self.set_inflight_fd(inflight, file.as_fd())?;
         }
-    }
 
-    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: RawFd) -> Result<()> {
-        let mut node = self.node();
-        if node.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits() == 0 {
-            return error_code(VhostUserError::InvalidOperation);
+        for (&queue_index, vring) in log.vrings.iter() {
+            if let Some(num) = vring.num {
+                self.set_vring_num(queue_index, num)?;
+            }
+            if let Some(config_data) = vring.addr.as_ref() {
+                self.set_vring_addr(queue_index, config_data)?;
+            }
+            if let Some(base) = vring.base {
+                self.set_vring_state_base(queue_index, base)?;
+            }
+            if let Some(fd) = vring.call.as_ref() {
+                self.set_vring_call(queue_index, fd.as_ref())?;
+            }
+            if let Some(fd) = vring.kick.as_ref() {
+                self.set_vring_kick(queue_index, fd.as_ref())?;
+            }
+            if let Some(fd) = vring.err.as_ref() {
+                self.set_vring_err(queue_index, fd.as_ref())?;
+            }
+            if let Some(enable) = vring.enable {
+                self.set_vring_enable(queue_index, enable)?;
+            }
         }
 
-        if inflight.mmap_size == 0 || inflight.num_queues == 0 || inflight.queue_size == 0 || fd < 0
-        {
-            return error_code(VhostUserError::InvalidParam);
+        Ok(())
+    }
+}
+
+impl Master {
+    // Get the number of queues of the slave, which is only queried once.
+    fn query_queue_num(&self) -> Result<u64> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::MQ)?;
+        if let Some(queue_num) = node.queue_num {
+            return Ok(queue_num);
         }
 
-        let hdr = node.send_request_with_body(MasterReq::SET_INFLIGHT_FD, inflight, Some(&[fd]))?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+        let hdr = node.send_request_header(MasterReq::GET_QUEUE_NUM, None)?;
+        let queue_num = self.wait_reply(node, |node| {
+            let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+            if val.value > VHOST_USER_MAX_VRINGS {
+                return Err(VhostUserError::InvalidMessage);
+            }
+            node.max_queue_num = val.value;
+            node.queue_num = Some(val.value);
+            Ok(val.value)
+        })?;
+        Ok(queue_num)
     }
 
-    fn get_max_mem_slots(&mut self) -> Result<u64> {
+    // Get the number of memory slots of the slave, which is only queried once.
+    fn query_max_mem_slots(&self) -> Result<u64> {
         let mut node = self.node();
-        if node.acked_protocol_features & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits() == 0
-        {
-            return error_code(VhostUserError::InvalidOperation);
+        node.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
+        if let Some(slots) = node.max_mem_slots {
+            return Ok(slots);
         }
 
         let hdr = node.send_request_header(MasterReq::GET_MAX_MEM_SLOTS, None)?;
-        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        let slots = self.wait_reply(node, |node| {
+            let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+            node.max_mem_slots = Some(val.value);
+            Ok(val.value)
+        })?;
+        Ok(slots)
+    }
 
-        Ok(val.value)
+    // Lock the master for a request on the vring `queue_index`, once the index has been checked
+    // against the number of queues. When MQ has been negotiated, the number of queues of the
+    // slave is queried by the first request on a vring.
+    fn vring_node(&self, queue_index: usize) -> Result<MutexGuard<MasterInternal>> {
+        let node = self.node();
+        let node = if node.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0
+            && node.queue_num.is_none()
+        {
+            drop(node);
+            self.query_queue_num()?;
+            self.node()
+        } else {
+            node
+        };
+        node.check_queue_index(queue_index)?;
+        Ok(node)
     }
 
-    fn add_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
-        let mut node = self.node();
-        if node.acked_protocol_features & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits() == 0
+    fn enable_vring(&self, queue_index: usize, enable: bool) -> Result<()> {
+        // set_vring_enable() is supported only when PROTOCOL_FEATURES has been enabled.
+        if self.node().acked_virtio_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+            == 0
         {
             return error_code(VhostUserError::InvalidOperation);
         }
-        if region.memory_size == 0 || region.mmap_handle < 0 {
-            return error_code(VhostUserError::InvalidParam);
-        }
+        let mut node = self.vring_node(queue_index)?;
+        let hdr = node.send_vring_enable(queue_index, enable)?;
+        self.wait_for_ack(node, &hdr)
+    }
 
-        let body = VhostUserSingleMemoryRegion::new(
-            region.guest_phys_addr,
-            region.memory_size,
-            region.userspace_addr,
-            region.mmap_offset,
-        );
-        let fds = [region.mmap_handle];
-        let hdr = node.send_request_with_body(MasterReq::ADD_MEM_REG, &body, Some(&fds))?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    // Send SET_VRING_BASE with the vring state `num` already encoded.
+    fn set_vring_state_base(&self, queue_index: usize, num: u32) -> Result<()> {
+        let mut node = self.vring_node(queue_index)?;
+        let hdr = node.send_vring_base(queue_index, num)?;
+        self.wait_for_ack(node, &hdr)
     }
+}
 
-    fn remove_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
+impl VhostFeatureOps for Master {
+    /// Get from the underlying vhost implementation the feature bitmask.
+    fn get_features(&self) -> Result<u64> {
         let mut node = self.node();
-        if node.acked_protocol_features & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits() == 0
-        {
-            return error_code(VhostUserError::InvalidOperation);
-        }
-        if region.memory_size == 0 {
-            return error_code(VhostUserError::InvalidParam);
-        }
+        let hdr = node.send_request_header(MasterReq::GET_FEATURES, None)?;
+        let features = self.wait_reply(node, |node| {
+            node.virtio_features = node.recv_reply::<VhostUserU64>(&hdr)?.value;
+            Ok(node.virtio_features)
+        })?;
+        Ok(features)
+    }
 
-        let body = VhostUserSingleMemoryRegion::new(
-            region.guest_phys_addr,
-            region.memory_size,
-            region.userspace_addr,
-            region.mmap_offset,
-        );
-        let hdr = node.send_request_with_body(MasterReq::REM_MEM_REG, &body, None)?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    /// Enable features in the underlying vhost implementation using a bitmask.
+    fn set_features(&self, features: u64) -> Result<()> {
+        let mut node = self.node();
+        node.record(|log| {
+            log.features = Some(features);
+            Ok(())
+        })?;
+        let val = VhostUserU64::new(features);
+        let hdr = node.send_request_with_body(MasterReq::SET_FEATURES, &val, None)?;
+        node.acked_virtio_features = features & node.virtio_features;
+        self.wait_for_ack(node, &hdr)
     }
-}
 
-impl AsRawFd for Master {
-    fn as_raw_fd(&self) -> RawFd {
-        let node = self.node();
-        node.main_sock.as_raw_fd()
+    /// Set the current Master as an owner of the session.
+    fn set_owner(&self) -> Result<()> {
+        // We unwrap() the return value to assert that we are not expecting threads to ever fail
+        // while holding the lock.
+        let mut node = self.node();
+        let hdr = node.send_request_header(MasterReq::SET_OWNER, None)?;
+        self.wait_for_ack(node, &hdr)
     }
-}
 
-/// Context object to pass guest memory configuration to VhostUserMaster::set_mem_table().
-struct VhostUserMemoryContext {
-    regions: VhostUserMemoryPayload,
-    fds: Vec<RawFd>,
+    fn reset_owner(&self) -> Result<()> {
+        let mut node = self.node();
+        node.record(|log| {
+            *log = StateLog::default();
+            Ok(())
+        })?;
+        let hdr = node.send_request_header(MasterReq::RESET_OWNER, None)?;
+        self.wait_for_ack(node, &hdr)
+    }
 }
 
-impl VhostUserMemoryContext {
-    /// Create a context object.
-    pub fn new() -> Self {
-        VhostUserMemoryContext {
-            regions: VhostUserMemoryPayload::new(),
-            fds: Vec::new(),
+impl VhostMemOps for Master {
+    /// Set the memory map regions on the slave so it can translate the vring
+    /// addresses. In the ancillary data there is an array of file descriptors
+    fn set_mem_table(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
+        let mut node = self.node();
+        let merged;
+        let mut table = regions;
+        if node.split_mem_table && regions.len() > MAX_ATTACHED_FD_ENTRIES {
+            merged = merge_mem_regions(regions);
+            table = &merged;
+            if table.len() > MAX_ATTACHED_FD_ENTRIES {
+                node.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
+            }
         }
-    }
 
-    /// Append a user memory region and corresponding RawFd into the context object.
-    pub fn append(&mut self, region: &VhostUserMemoryRegion, fd: RawFd) {
-        self.regions.push(*region);
-        self.fds.push(fd);
+        node.record(|log| {
+            let mut mem_regions = Vec::with_capacity(regions.len());
+            for region in regions.iter() {
+                mem_regions.push(log_mem_region(region)?);
+            }
+            log.mem_regions = mem_regions;
+            Ok(())
+        })?;
+        if table.len() > MAX_ATTACHED_FD_ENTRIES && node.split_mem_table {
+            drop(node);
+            return self.set_mem_table_split(table);
+        }
+        let hdr = node.send_mem_table(table)?;
+        self.wait_for_ack(node, &hdr)
     }
 }
 
-struct MasterInternal {
-    // Used to send requests to the slave.
-    main_sock: Endpoint<MasterReq>,
-    // Cached virtio features from the slave.
-    virtio_features: u64,
-    // Cached acked virtio features from the driver.
-    acked_virtio_features: u64,
-    // Cached vhost-user protocol features from the slave.
-    protocol_features: u64,
-    // Cached vhost-user protocol features.
-    acked_protocol_features: u64,
-    // Cached vhost-user protocol features are ready to use.
-    protocol_features_ready: bool,
-    // Cached maxinum number of queues supported from the slave.
-    max_queue_num: u64,
-    // Internal flag to mark failure state.
-    error: Option<i32>,
-    // List of header flags.
-    hdr_flags: VhostUserHeaderFlag,
+impl Master {
+    // Send the first regions of the memory table with SET_MEM_TABLE, replacing the previous
+    // table, and add the other ones with ADD_MEM_REG.
+    fn set_mem_table_split(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
+        if regions
+            .iter()
+            .any(|region| region.memory_size == 0 || region.mmap_handle.is_none())
+        {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let mut node = self.node();
+        let hdr = node.send_request_header(MasterReq::GET_MAX_MEM_SLOTS, None)?;
+        let slots = self.wait_reply(node, |node| node.recv_reply::<VhostUserU64>(&hdr))?;
+        if regions.len() as u64 > slots.value {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let (table, others) = regions.split_at(MAX_ATTACHED_FD_ENTRIES);
+        let mut node = self.node();
+        let hdr = node.send_mem_table(table)?;
+        self.wait_for_ack(node, &hdr)?;
+        for region in others.iter() {
+            let mut node = self.node();
+            let body = VhostUserSingleMemoryRegion::from(region);
+            let fds = [mem_region_fd(region)?];
+            let hdr = node.send_request_with_body(MasterReq::ADD_MEM_REG, &body, Some(&fds))?;
+            self.wait_for_ack(node, &hdr)?;
+        }
+        Ok(())
+    }
+}
+
+// Whether `next` extends `region` in the guest, the current process and the backing file.
+fn is_mem_region_adjacent(
+    region: &VhostUserMemoryRegionInfo,
+    next: &VhostUserMemoryRegionInfo,
+) -> bool {
+    #[cfg(feature = "xen")]
+    {
+        if region.xen_mmap_flags != next.xen_mmap_flags || region.xen_domid != next.xen_domid {
+            return false;
+        }
+    }
+    region.mmap_handle.map(|fd| fd.as_raw_fd()) == next.mmap_handle.map(|fd| fd.as_raw_fd())
+        && region.guest_phys_addr.checked_add(region.memory_size) == Some(next.guest_phys_addr)
+        && region.userspace_addr.checked_add(region.memory_size) == Some(next.userspace_addr)
+        && region.mmap_offset.checked_add(region.memory_size) == Some(next.mmap_offset)
+}
+
+// Coalesce the adjacent regions of a memory table.
+fn merge_mem_regions<'a>(
+    regions: &[VhostUserMemoryRegionInfo<'a>],
+) -> Vec<VhostUserMemoryRegionInfo<'a>> {
+    let mut sorted = regions.to_vec();
+    sorted.sort_by_key(|region| region.guest_phys_addr);
+
+    let mut merged: Vec<VhostUserMemoryRegionInfo<'a>> = Vec::with_capacity(sorted.len());
+    for region in sorted {
+        match merged.last_mut() {
+            Some(last) if is_mem_region_adjacent(last, &region) => {
+                last.memory_size += region.memory_size;
+            }
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+// Get the file descriptor of a region to share with the slave.
+fn mem_region_fd(region: &VhostUserMemoryRegionInfo) -> Result<RawFd> {
+    match region.mmap_handle {
+        Some(fd) if region.memory_size != 0 => Ok(fd.as_raw_fd()),
+        _ => error_code(VhostUserError::InvalidParam),
+    }
+}
+
+// Record a region of the memory table, with its own copy of the file descriptor.
+fn log_mem_region(
+    region: &VhostUserMemoryRegionInfo,
+) -> VhostUserResult<(VhostUserMemoryRegion, File)> {
+    let fd = region.mmap_handle.ok_or(VhostUserError::InvalidParam)?;
+    Ok((
+        VhostUserMemoryRegion::from(region),
+        dup_file(fd.as_raw_fd())?,
+    ))
+}
+
+// Describe a region recorded by `log_mem_region()`, to replay it.
+fn logged_mem_region<'a>(
+    region: &VhostUserMemoryRegion,
+    file: &'a File,
+) -> VhostUserMemoryRegionInfo<'a> {
+    VhostUserMemoryRegionInfo {
+        guest_phys_addr: region.guest_phys_addr,
+        memory_size: region.memory_size,
+        userspace_addr: region.user_addr,
+        mmap_offset: region.mmap_offset,
+        mmap_handle: Some(file.as_fd()),
+        #[cfg(feature = "xen")]
+        xen_mmap_flags: region.xen_mmap_flags,
+        #[cfg(feature = "xen")]
+        xen_domid: region.xen_domid,
+    }
+}
+
+impl VhostLogOps for Master {
+    // Clippy doesn't seem to know that if let with && is still experimental
+    #[allow(clippy::unnecessary_unwrap)]
+    fn set_log_base(&self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
+        let mut node = self.node();
+        let val = VhostUserU64::new(base);
+
+        if node.acked_protocol_features & VhostUserProtocolFeatures::LOG_SHMFD.bits() != 0
+            && region.is_some()
+        {
+            let region = region.unwrap();
+            let log = VhostUserLog {
+                mmap_size: region.mmap_size,
+                mmap_offset: region.mmap_offset,
+            };
+            // The slave replies once it mapped the log, rather than acking the request.
+            let hdr = node.without_auto_reply_ack(|node| {
+                node.send_request_with_body(
+                    MasterReq::SET_LOG_BASE,
+                    &log,
+                    Some(&[region.mmap_handle.as_raw_fd()]),
+                )
+            })?;
+            let reply = self.wait_reply(node, |node| node.recv_reply::<VhostUserU64>(&hdr))?;
+            if reply.value != 0 {
+                return error_code(VhostUserError::SlaveInternalError);
+            }
+            Ok(())
+        } else {
+            let hdr = node.send_request_with_body(MasterReq::SET_LOG_BASE, &val, None)?;
+            self.wait_for_ack(node, &hdr)
+        }
+    }
+
+    fn set_log_fd(&self, fd: BorrowedFd) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::LOG_SHMFD)?;
+        let fds = [fd.as_raw_fd()];
+        let hdr = node.send_request_header(MasterReq::SET_LOG_FD, Some(&fds))?;
+        self.wait_for_ack(node, &hdr)
+    }
+}
+
+impl VhostVringOps for Master {
+    /// Set the size of the queue.
+    fn set_vring_num(&self, queue_index: usize, num: u16) -> Result<()> {
+        let mut node = self.vring_node(queue_index)?;
+        let hdr = node.send_vring_num(queue_index, num)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    /// Sets the addresses of the different aspects of the vring.
+    fn set_vring_addr(&self, queue_index: usize, config_data: &VringConfigData) -> Result<()> {
+        if config_data.flags & !(VhostUserVringAddrFlags::all().bits()) != 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let mut node = self.vring_node(queue_index)?;
+        let hdr = node.send_vring_addr(queue_index, config_data)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    /// Sets the base offset in the available vring.
+    fn set_vring_base(&self, queue_index: usize, base: u16) -> Result<()> {
+        self.set_vring_state_base(queue_index, base.into())
+    }
+
+    fn get_vring_base(&self, queue_index: usize) -> Result<u32> {
+        let mut node = self.vring_node(queue_index)?;
+
+        let req = VhostUserVringState::new(queue_index as u32, 0);
+        let hdr = node.send_request_with_body(MasterReq::GET_VRING_BASE, &req, None)?;
+        let reply = self.wait_reply(node, |node| node.recv_reply::<VhostUserVringState>(&hdr))?;
+        Ok(reply.num)
+    }
+
+    /// Set the event file descriptor to signal when buffers are used.
+    /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
+    /// is set when there is no file descriptor in the ancillary data. This signals that polling
+    /// will be used instead of waiting for the call.
+    fn set_vring_call(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
+        let mut node = self.vring_node(queue_index)?;
+        let hdr = node.send_vring_fd(MasterReq::SET_VRING_CALL, queue_index, fd)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    /// Set the event file descriptor for adding buffers to the vring.
+    /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
+    /// is set when there is no file descriptor in the ancillary data. This signals that polling
+    /// should be used instead of waiting for a kick.
+    fn set_vring_kick(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
+        let mut node = self.vring_node(queue_index)?;
+        let hdr = node.send_vring_fd(MasterReq::SET_VRING_KICK, queue_index, fd)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    /// Set the event file descriptor to signal when error occurs.
+    /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
+    /// is set when there is no file descriptor in the ancillary data.
+    fn set_vring_err(&self, queue_index: usize, fd: Option<&EventFd>) -> Result<()> {
+        let mut node = self.vring_node(queue_index)?;
+        let hdr = node.send_vring_fd(MasterReq::SET_VRING_ERR, queue_index, fd)?;
+        self.wait_for_ack(node, &hdr)
+    }
+}
+
+impl VhostDevice for Master {
+    fn set_status(&self, status: u8) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::STATUS)?;
+
+        let val = VhostUserU64::new(status.into());
+        let hdr = node.send_request_with_body(MasterReq::SET_STATUS, &val, None)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn get_status(&self) -> Result<u8> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::STATUS)?;
+
+        let hdr = node.send_request_header(MasterReq::GET_STATUS, None)?;
+        let val = self.wait_reply(node, |node| node.recv_reply::<VhostUserU64>(&hdr))?;
+        if val.value > u64::from(u8::MAX) {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+        Ok(val.value as u8)
+    }
 }
 
-impl MasterInternal {
-    fn send_request_header(
-        &mut self,
-        code: MasterReq,
-        fds: Option<&[RawFd]>,
-    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
-        self.check_state()?;
-        let hdr = self.new_request_header(code, 0);
-        self.main_sock.send_header(&hdr, fds)?;
-        Ok(hdr)
+impl VhostUserMaster for Master {
+    fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures> {
+        let mut node = self.node();
+        let flag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        if node.virtio_features & flag == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        let hdr = node.send_request_header(MasterReq::GET_PROTOCOL_FEATURES, None)?;
+        let features = self.wait_reply(node, |node| {
+            node.protocol_features = node.recv_reply::<VhostUserU64>(&hdr)?.value;
+            Ok(node.protocol_features)
+        })?;
+        // Should we support forward compatibility?
+        // If so just mask out unrecognized flags instead of return errors.
+        match VhostUserProtocolFeatures::from_bits(features) {
+            Some(val) => Ok(val),
+            None => error_code(VhostUserError::InvalidMessage),
+        }
+    }
+
+    fn set_protocol_features(&mut self, features: VhostUserProtocolFeatures) -> Result<()> {
+        let mut node = self.node();
+        let flag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        if node.virtio_features & flag == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        node.record(|log| {
+            log.protocol_features = Some(features);
+            Ok(())
+        })?;
+        let val = VhostUserU64::new(features.bits());
+        let hdr = node.send_request_with_body(MasterReq::SET_PROTOCOL_FEATURES, &val, None)?;
+        // Don't wait for ACK here because the protocol feature negotiation process hasn't been
+        // completed yet.
+        node.acked_protocol_features = features.bits();
+        node.protocol_features_ready = true;
+        node.queue_num = None;
+        node.max_mem_slots = None;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn get_queue_num(&mut self) -> Result<u64> {
+        self.query_queue_num()
+    }
+
+    fn set_vring_enable(&mut self, queue_index: usize, enable: bool) -> Result<()> {
+        self.enable_vring(queue_index, enable)
+    }
+
+    fn get_config(
+        &mut self,
+        offset: u32,
+        size: u32,
+        flags: VhostUserConfigFlags,
+        buf: &[u8],
+    ) -> Result<(VhostUserConfig, VhostUserConfigPayload)> {
+        let mut node = self.node();
+        let config_size = node.config_size;
+        let in_range = match offset.checked_add(size) {
+            Some(end) => end <= config_size,
+            None => false,
+        };
+        if size == 0
+            || size > VhostUserConfig::max_access_size(config_size)
+            || !in_range
+            || buf.len() != size as usize
+        {
+            return error_code(VhostUserError::InvalidConfigRange(offset, size));
+        }
+        let body = VhostUserConfig::new(offset, size, flags);
+        if !body.is_valid_for(config_size) {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        node.check_protocol_feature(VhostUserProtocolFeatures::CONFIG)?;
+
+        // vhost-user spec states that:
+        // "Master payload: virtio device config space"
+        // "Slave payload: virtio device config space"
+        let hdr = node.send_request_with_payload(MasterReq::GET_CONFIG, &body, buf, None)?;
+        let (body_reply, buf_reply) = self.wait_reply(node, |node| node.recv_config_reply(&hdr))?;
+        if body_reply.size == 0 {
+            // The slave replies with an empty payload on failure.
+            return error_code(VhostUserError::SlaveInternalError);
+        } else if body_reply.size != size || body_reply.offset != offset {
+            return error_code(VhostUserError::ConfigReplyMismatch(
+                body_reply.offset,
+                body_reply.size,
+            ));
+        } else if buf_reply.len() != size as usize {
+            return error_code(VhostUserError::ConfigReplyLength(
+                buf_reply.len(),
+                size as usize,
+            ));
+        }
+
+        Ok((body_reply, buf_reply))
+    }
+
+    fn set_config(&mut self, offset: u32, flags: VhostUserConfigFlags, buf: &[u8]) -> Result<()> {
+        let mut node = self.node();
+        if buf.len() > VhostUserConfig::max_access_size(node.config_size) as usize {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let body = VhostUserConfig::new(offset, buf.len() as u32, flags);
+        if !body.is_valid_for(node.config_size) {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        node.check_protocol_feature(VhostUserProtocolFeatures::CONFIG)?;
+
+        let hdr = node.send_request_with_payload(MasterReq::SET_CONFIG, &body, buf, None)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn set_slave_request_fd(&mut self, fd: &dyn AsFd) -> Result<()> {
+        let fd = fd.as_fd().as_raw_fd();
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::SLAVE_REQ)?;
+        node.record(|log| {
+            log.slave_req_fd = Some(dup_file(fd)?);
+            Ok(())
+        })?;
+        let fds = [fd];
+        let hdr = node.send_request_header(MasterReq::SET_SLAVE_REQ_FD, Some(&fds))?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::INFLIGHT_SHMFD)?;
+
+        let hdr = node.send_request_with_body(MasterReq::GET_INFLIGHT_FD, inflight, None)?;
+        let (inflight, files) = self.wait_reply(node, |node| {
+            node.recv_reply_with_files::<VhostUserInflight>(&hdr)
+        })?;
+        if inflight.mmap_size == 0 {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+
+        let file = match take_single_file(files) {
+            Some(file) => file,
+            None => return error_code(VhostUserError::IncorrectFds),
+        };
+        // The buffer must be handed back to the slave when reconnecting.
+        self.node().record(|log| {
+            log.inflight = Some((inflight, dup_file(file.as_raw_fd())?));
+            Ok(())
+        })?;
+
+        Ok((inflight, file))
+    }
+
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: BorrowedFd) -> Result<()> {
+        let fd = fd.as_raw_fd();
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::INFLIGHT_SHMFD)?;
+
+        if inflight.mmap_size == 0 || inflight.num_queues == 0 || inflight.queue_size == 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        node.record(|log| {
+            log.inflight = Some((*inflight, dup_file(fd)?));
+            Ok(())
+        })?;
+
+        let hdr = node.send_request_with_body(MasterReq::SET_INFLIGHT_FD, inflight, Some(&[fd]))?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn get_max_mem_slots(&mut self) -> Result<u64> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
+
+        let hdr = node.send_request_header(MasterReq::GET_MAX_MEM_SLOTS, None)?;
+        let val = self.wait_reply(node, |node| {
+            let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+            node.max_mem_slots = Some(val.value);
+            Ok(val)
+        })?;
+
+        Ok(val.value)
+    }
+
+    fn add_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
+        let fds = [mem_region_fd(region)?];
+
+        node.record(|log| {
+            log.mem_regions.push(log_mem_region(region)?);
+            Ok(())
+        })?;
+        node.check_xen_mmap()?;
+        let body = VhostUserSingleMemoryRegion::from(region);
+        let hdr = node.send_request_with_body(MasterReq::ADD_MEM_REG, &body, Some(&fds))?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn remove_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
+        if region.memory_size == 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        node.record(|log| {
+            log.mem_regions.retain(|(r, _)| {
+                r.guest_phys_addr != region.guest_phys_addr || r.memory_size != region.memory_size
+            });
+            Ok(())
+        })?;
+        node.check_xen_mmap()?;
+        let body = VhostUserSingleMemoryRegion::from(region);
+        let hdr = node.send_request_with_body(MasterReq::REM_MEM_REG, &body, None)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn add_mem_region_postcopy(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<u64> {
+        let mut node = self.node();
+        node.check_protocol_feature(
+            VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS | VhostUserProtocolFeatures::PAGEFAULT,
+        )?;
+        let fds = [mem_region_fd(region)?];
+
+        node.check_xen_mmap()?;
+        let body = VhostUserSingleMemoryRegion::from(region);
+        let hdr = node.without_auto_reply_ack(|node| {
+            node.send_request_with_body(MasterReq::ADD_MEM_REG, &body, Some(&fds))
+        })?;
+        let reply = self.wait_reply(node, |node| {
+            node.recv_reply::<VhostUserSingleMemoryRegion>(&hdr)
+        })?;
+        if reply.guest_phys_addr != region.guest_phys_addr
+            || reply.memory_size != region.memory_size
+        {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+
+        Ok(reply.user_addr)
+    }
+
+    fn postcopy_advise(&mut self) -> Result<File> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::PAGEFAULT)?;
+
+        let hdr = node.send_request_header(MasterReq::POSTCOPY_ADVISE, None)?;
+        let (reply, files) = self.wait_reply(node, |node| node.recv_header())?;
+        if !reply.is_reply_for(&hdr) || reply.get_size() != 0 {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+        match take_single_file(files) {
+            Some(file) => Ok(file),
+            None => error_code(VhostUserError::IncorrectFds),
+        }
+    }
+
+    fn postcopy_listen(&mut self) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::PAGEFAULT)?;
+
+        let hdr = node.send_request_and_ack(MasterReq::POSTCOPY_LISTEN)?;
+        self.wait_reply(node, |node| node.recv_ack(&hdr))
+            .map_err(|e| e.into())
+    }
+
+    fn postcopy_end(&mut self) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::PAGEFAULT)?;
+
+        let hdr = node.send_request_and_ack(MasterReq::POSTCOPY_END)?;
+        self.wait_reply(node, |node| node.recv_ack(&hdr))
+            .map_err(|e| e.into())
+    }
+
+    fn set_mem_table_postcopy(
+        &mut self,
+        regions: &[VhostUserMemoryRegionInfo],
+    ) -> Result<Vec<u64>> {
+        // The slave waits for the ack of its reply right after sending it, so no other request
+        // may be sent until the ack.
+        let mut node = self.idle_node()?;
+        node.check_protocol_feature(VhostUserProtocolFeatures::PAGEFAULT)?;
+
+        let hdr = node.without_auto_reply_ack(|node| node.send_mem_table(regions))?;
+        let res = node.recv_mem_table_postcopy(&hdr, regions);
+        if !node.disconnected {
+            // Acknowledge the reply, the slave may only start accessing the memory afterwards.
+            let ack_hdr = node.without_auto_reply_ack(|node| {
+                Ok(node.new_request_header(
+                    MasterReq::SET_MEM_TABLE,
+                    mem::size_of::<VhostUserU64>() as u32,
+                ))
+            })?;
+            let ack = VhostUserU64::new(if res.is_ok() { 0 } else { 1 });
+            node.main_sock
+                .send_message(&ack_hdr, &ack, None)
+                .map_err(|e| node.poison(e))?;
+            node.trace_send(&ack_hdr, &[ack.as_slice()], None);
+        }
+
+        Ok(res?)
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        fd: &dyn AsFd,
+    ) -> Result<Option<File>> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::DEVICE_STATE)?;
+
+        let body = VhostUserTransferDeviceState::new(direction, phase);
+        let fds = [fd.as_fd().as_raw_fd()];
+        let hdr = node.send_request_with_body(MasterReq::SET_DEVICE_STATE_FD, &body, Some(&fds))?;
+        let (reply, body, files) =
+            self.wait_reply(node, |node| node.recv_body::<VhostUserU64>())?;
+        if !reply.is_reply_for(&hdr) || !body.is_valid() {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+        if body.value & 0xff != 0 {
+            return error_code(VhostUserError::SlaveInternalError);
+        }
+
+        // The slave attaches its own pipe end unless the reply carries the no-fd flag.
+        if body.value & VHOST_USER_DEVICE_STATE_NO_FD != 0 {
+            if files.is_some() {
+                return error_code(VhostUserError::IncorrectFds);
+            }
+            Ok(None)
+        } else {
+            match take_single_file(files) {
+                Some(file) => Ok(Some(file)),
+                None => error_code(VhostUserError::IncorrectFds),
+            }
+        }
+    }
+
+    fn check_device_state(&mut self) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::DEVICE_STATE)?;
+
+        let hdr = node.send_request_header(MasterReq::CHECK_DEVICE_STATE, None)?;
+        let reply = self.wait_reply(node, |node| node.recv_reply::<VhostUserU64>(&hdr))?;
+        if reply.value != 0 {
+            return error_code(VhostUserError::SlaveInternalError);
+        }
+        Ok(())
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<()> {
+        VhostDevice::set_status(self, status)
+    }
+
+    fn get_status(&mut self) -> Result<u8> {
+        VhostDevice::get_status(self)
+    }
+
+    fn reset_device(&mut self) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::RESET_DEVICE)?;
+
+        node.record(|log| {
+            log.inflight = None;
+            log.vrings.clear();
+            Ok(())
+        })?;
+        let hdr = node.send_request_header(MasterReq::RESET_DEVICE, None)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn get_shared_object(&mut self, uuid: &VhostUserShared) -> Result<File> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::SHARED_OBJECT)?;
+
+        let hdr = node.send_request_with_body(MasterReq::GET_SHARED_OBJECT, uuid, None)?;
+        let (reply, body, files) =
+            self.wait_reply(node, |node| node.recv_body::<VhostUserU64>())?;
+        if !reply.is_reply_for(&hdr) || !body.is_valid() {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+        if body.value != 0 {
+            return error_code(VhostUserError::SlaveInternalError);
+        }
+        match take_single_file(files) {
+            Some(file) => Ok(file),
+            None => error_code(VhostUserError::IncorrectFds),
+        }
+    }
+
+    fn create_crypto_session(&mut self, session: &VhostUserCryptoSession) -> Result<i64> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::CRYPTO_SESSION)?;
+        if !session.is_valid() {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let hdr = node.send_request_with_body(MasterReq::CREATE_CRYPTO_SESSION, session, None)?;
+        let reply =
+            self.wait_reply(node, |node| node.recv_reply::<VhostUserCryptoSession>(&hdr))?;
+        let session_id = reply.session_id;
+        if session_id < 0 {
+            return error_code(VhostUserError::SlaveInternalError);
+        }
+        Ok(session_id)
+    }
+
+    fn close_crypto_session(&mut self, session_id: i64) -> Result<()> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::CRYPTO_SESSION)?;
+
+        let val = VhostUserU64::new(session_id as u64);
+        let hdr = node.send_request_with_body(MasterReq::CLOSE_CRYPTO_SESSION, &val, None)?;
+        self.wait_for_ack(node, &hdr)
+    }
+
+    fn get_shmem_config(&mut self) -> Result<Vec<(u8, u64)>> {
+        let mut node = self.node();
+        node.check_protocol_feature(VhostUserProtocolFeatures::SHMEM)?;
+
+        let hdr = node.send_request_header(MasterReq::GET_SHMEM_CONFIG, None)?;
+        let config = self.wait_reply(node, |node| node.recv_reply::<VhostUserShMemConfig>(&hdr))?;
+        Ok(config.regions())
+    }
+
+    fn set_vring_base_packed(&mut self, queue_index: usize, base: VringPackedBase) -> Result<()> {
+        match base.to_num() {
+            Some(num) => self.set_vring_state_base(queue_index, num),
+            None => error_code(VhostUserError::InvalidParam),
+        }
+    }
+
+    fn get_vring_base_packed(&mut self, queue_index: usize) -> Result<VringPackedBase> {
+        self.get_vring_base(queue_index)
+            .map(VringPackedBase::from_num)
+    }
+}
+
+impl AsRawFd for Master {
+    /// Get the socket connected to the slave, for example to watch it with epoll.
+    fn as_raw_fd(&self) -> RawFd {
+        let node = self.node();
+        node.main_sock.as_raw_fd()
+    }
+}
+
+/// Handle to a vring of the slave, returned by `Master::queue()`.
+///
+/// The queue index has been checked against the number of queues of the slave when the handle
+/// was created, so the requests sent through the handle don't need to repeat it.
+#[derive(Clone, Copy)]
+pub struct MasterQueue<'a> {
+    master: &'a Master,
+    queue_index: usize,
+}
+
+impl<'a> MasterQueue<'a> {
+    /// Get the index of the vring.
+    pub fn index(&self) -> usize {
+        self.queue_index
+    }
+
+    /// Set the number of descriptors in the vring.
+    pub fn set_num(&self, num: u16) -> Result<()> {
+        self.master.set_vring_num(self.queue_index, num)
+    }
+
+    /// Set the addresses of the different aspects of the vring.
+    pub fn set_addr(&self, config_data: &VringConfigData) -> Result<()> {
+        self.master.set_vring_addr(self.queue_index, config_data)
+    }
+
+    /// Set the base offset in the available vring.
+    pub fn set_base(&self, base: u16) -> Result<()> {
+        self.master.set_vring_base(self.queue_index, base)
+    }
+
+    /// Stop the vring and get its available base offset.
+    pub fn get_base(&self) -> Result<u32> {
+        self.master.get_vring_base(self.queue_index)
+    }
+
+    /// Set the position of a packed vring.
+    pub fn set_base_packed(&self, base: VringPackedBase) -> Result<()> {
+        match base.to_num() {
+            Some(num) => self.master.set_vring_state_base(self.queue_index, num),
+            None => error_code(VhostUserError::InvalidParam),
+        }
+    }
+
+    /// Stop a packed vring and get its position.
+    pub fn get_base_packed(&self) -> Result<VringPackedBase> {
+        self.get_base().map(VringPackedBase::from_num)
+    }
+
+    /// Set the eventfd to signal when buffers are used, or `None` if the guest polls the vring.
+    pub fn set_call(&self, fd: Option<&EventFd>) -> Result<()> {
+        self.master.set_vring_call(self.queue_index, fd)
+    }
+
+    /// Set the eventfd notified by the guest, or `None` if the slave should poll the vring.
+    pub fn set_kick(&self, fd: Option<&EventFd>) -> Result<()> {
+        self.master.set_vring_kick(self.queue_index, fd)
+    }
+
+    /// Set the eventfd to signal vring errors.
+    pub fn set_err(&self, fd: Option<&EventFd>) -> Result<()> {
+        self.master.set_vring_err(self.queue_index, fd)
+    }
+
+    /// Enable or disable the vring, once PROTOCOL_FEATURES has been negotiated.
+    pub fn enable(&self, enable: bool) -> Result<()> {
+        self.master.enable_vring(self.queue_index, enable)
+    }
+}
+
+/// Vhost-user master side connection listener.
+///
+/// Allow the master to act as the server, waiting for the slave to connect to it.
+pub struct MasterListener {
+    listener: Listener,
+    max_queue_num: u64,
+}
+
+impl MasterListener {
+    /// Create a listener for incoming slave connections.
+    pub fn new(listener: Listener, max_queue_num: u64) -> Self {
+        MasterListener {
+            listener,
+            max_queue_num,
+        }
+    }
+
+    /// Accept an incoming connection from the slave, returning Some(Master) on success, or
+    /// None if the socket is nonblocking and no incoming connection was detected.
+    pub fn accept(&self) -> Result<Option<Master>> {
+        match self.listener.accept()? {
+            Some(sock) => Ok(Some(Master::from_stream(sock, self.max_queue_num))),
+            None => Ok(None),
+        }
+    }
+
+    /// Change blocking status on the listener.
+    pub fn set_nonblocking(&self, block: bool) -> Result<()> {
+        self.listener.set_nonblocking(block).map_err(|e| e.into())
+    }
+}
+
+impl AsRawFd for MasterListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// Context object to pass guest memory configuration to VhostUserMaster::set_mem_table().
+struct VhostUserMemoryContext {
+    regions: VhostUserMemoryPayload,
+    fds: Vec<RawFd>,
+}
+
+impl VhostUserMemoryContext {
+    /// Create a context object.
+    pub fn new() -> Self {
+        VhostUserMemoryContext {
+            regions: VhostUserMemoryPayload::new(),
+            fds: Vec::new(),
+        }
+    }
+
+    /// Append a user memory region and corresponding RawFd into the context object.
+    pub fn append(&mut self, region: &VhostUserMemoryRegion, fd: RawFd) {
+        self.regions.push(*region);
+        self.fds.push(fd);
+    }
+}
+
+struct MasterInternal {
+    // Used to send requests to the slave.
+    main_sock: Endpoint<MasterReq>,
+    // Cached virtio features from the slave.
+    virtio_features: u64,
+    // Cached acked virtio features from the driver.
+    acked_virtio_features: u64,
+    // Cached vhost-user protocol features from the slave.
+    protocol_features: u64,
+    // Cached vhost-user protocol features.
+    acked_protocol_features: u64,
+    // Cached vhost-user protocol features are ready to use.
+    protocol_features_ready: bool,
+    // Cached maxinum number of queues supported from the slave.
+    max_queue_num: u64,
+    // Number of queues reported by the slave, once queried.
+    queue_num: Option<u64>,
+    // Number of memory slots reported by the slave, once queried.
+    max_mem_slots: Option<u64>,
+    // End of the device configuration space accessed by GET_CONFIG and SET_CONFIG.
+    config_size: u32,
+    // Whether a request found the connection broken, failing all the following requests.
+    disconnected: bool,
+    // Error which broke the connection, until taken.
+    error: Option<i32>,
+    // Hook invoked when the connection is found broken.
+    disconnect_handler: Option<DisconnectHandler>,
+    // List of header flags.
+    hdr_flags: VhostUserHeaderFlag,
+    // Whether to request an ack for all the requests changing the state of the slave.
+    auto_reply_ack: bool,
+    // Whether to split memory tables with too many regions to be sent at once.
+    split_mem_table: bool,
+    // Whether to reject requests sent before the handshake requests they depend on.
+    strict_order: bool,
+    // Handshake requests sent on the current connection.
+    handshake: Handshake,
+    // Path of the slave socket, to reconnect to it.
+    path: Option<PathBuf>,
+    // State recorded to be replayed when reconnecting.
+    state_log: Option<StateLog>,
+    // Policy to send failed requests again.
+    retry_policy: RetryPolicy,
+    // Ticket of the next request expecting a reply.
+    next_ticket: u64,
+    // Id of the current connection, changed when reconnecting.
+    connection_id: u64,
+    // Replies of the requests which timed out, discarded before receiving the next reply.
+    lost_replies: u64,
+    // Hooks to trace the messages.
+    tracer: Option<Arc<dyn MasterTracer>>,
+    // When the last request was sent, and when the request whose reply is being received was
+    // sent, to measure the latency of the replies when tracing.
+    last_send: Option<Instant>,
+    reply_send: Option<Instant>,
+}
+
+// Reply expected for a request, received in turn with `Master::recv_pending()`.
+struct PendingReply {
+    ticket: u64,
+    connection_id: u64,
+    sent: Option<Instant>,
+}
+
+// Order in which the replies of the slave are received.
+//
+// The slave replies to the requests in the order it receives them. Each request expecting a
+// reply gets a ticket when it's sent, and the replies are received in the order of the tickets.
+#[derive(Default)]
+struct ReplyQueue {
+    serving: Mutex<u64>,
+    turn: Condvar,
+}
+
+impl ReplyQueue {
+    fn wait_turn(&self, ticket: u64) {
+        let mut serving = self.serving.lock().unwrap();
+        while *serving != ticket {
+            serving = self.turn.wait(serving).unwrap();
+        }
+    }
+
+    // Whether the replies to the requests preceding `ticket` have all been received.
+    fn is_served(&self, ticket: u64) -> bool {
+        ticket.wrapping_sub(*self.serving.lock().unwrap()) as i64 <= 0
+    }
+
+    fn wait_served(&self, ticket: u64) {
+        let mut serving = self.serving.lock().unwrap();
+        while ticket.wrapping_sub(*serving) as i64 > 0 {
+            serving = self.turn.wait(serving).unwrap();
+        }
+    }
+
+    fn next_turn(&self) {
+        let mut serving = self.serving.lock().unwrap();
+        *serving = serving.wrapping_add(1);
+        self.turn.notify_all();
+    }
+}
+
+// Turn of a reply being received, passed to the next reply when dropped.
+struct ReplyTurn<'a>(&'a ReplyQueue);
+
+impl Drop for ReplyTurn<'_> {
+    fn drop(&mut self) {
+        self.0.next_turn();
+    }
+}
+
+// The socket of a slave which is still starting may not exist yet or have no listener.
+fn is_connect_retryable(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::NotFound
+    )
+}
+
+// Wait for a reply to be available on the socket, so the master lock isn't held while the
+// slave handles the request.
+fn wait_readable(fd: RawFd, timeout: Option<Duration>) -> VhostUserResult<()> {
+    let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        // Safe because the pollfd is valid for the duration of the call and the return value
+        // is checked.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        match ret {
+            0 => return Err(VhostUserError::Timeout),
+            n if n > 0 => return Ok(()),
+            _ => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(VhostUserError::SocketError(e));
+                }
+            }
+        }
+    }
+}
+
+// Poll the socket for hangup and error conditions without waiting and without consuming any
+// pending reply.
+fn poll_connection(fd: RawFd) -> VhostUserResult<ConnectionStatus> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+    loop {
+        // Safe because the pollfd is valid for the duration of the call and the return value
+        // is checked.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if ret >= 0 {
+            break;
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(VhostUserError::SocketError(e));
+        }
+    }
+
+    if pollfd.revents & libc::POLLERR != 0 {
+        let mut err: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // Safe because err and len are valid for the duration of the call and the return value
+        // is checked.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(VhostUserError::SocketError(std::io::Error::last_os_error()));
+        }
+        return Ok(ConnectionStatus::Error(std::io::Error::from_raw_os_error(
+            err,
+        )));
+    }
+    if pollfd.revents & (libc::POLLHUP | libc::POLLRDHUP) != 0 {
+        return Ok(ConnectionStatus::Hangup);
+    }
+    Ok(ConnectionStatus::Connected)
+}
+
+// Handshake requests sent on the connection, checked in strict ordering mode.
+#[derive(Clone, Copy, Default)]
+struct Handshake {
+    owner: bool,
+    features_queried: bool,
+    features_set: bool,
+    protocol_features_queried: bool,
+}
+
+// Vring configuration recorded to be replayed when reconnecting.
+#[derive(Default)]
+struct VringLog {
+    num: Option<u16>,
+    addr: Option<VringConfigData>,
+    base: Option<u32>,
+    call: Option<Option<EventFd>>,
+    kick: Option<Option<EventFd>>,
+    err: Option<Option<EventFd>>,
+    enable: Option<bool>,
+}
+
+// Slave state recorded to be replayed when reconnecting.
+#[derive(Default)]
+struct StateLog {
+    features: Option<u64>,
+    protocol_features: Option<VhostUserProtocolFeatures>,
+    slave_req_fd: Option<File>,
+    mem_regions: Vec<(VhostUserMemoryRegion, File)>,
+    inflight: Option<(VhostUserInflight, File)>,
+    vrings: BTreeMap<usize, VringLog>,
+}
+
+impl StateLog {
+    fn vring(&mut self, queue_index: usize) -> &mut VringLog {
+        self.vrings.entry(queue_index).or_default()
+    }
+}
+
+fn dup_file(fd: RawFd) -> VhostUserResult<File> {
+    // The returned fd is checked and owned by the new File.
+    let fd = unsafe { libc::dup(fd) };
+    if fd < 0 {
+        return Err(VhostUserError::InvalidParam);
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+impl MasterInternal {
+    fn send_request_header(
+        &mut self,
+        code: MasterReq,
+        fds: Option<&[RawFd]>,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.check_state()?;
+        self.check_order(code)?;
+        let hdr = self.new_request_header(code, 0);
+        self.main_sock
+            .send_header(&hdr, fds)
+            .map_err(|e| self.poison(e))?;
+        self.track_order(code);
+        self.trace_send(&hdr, &[], fds);
+        Ok(hdr)
+    }
+
+    fn send_request_with_body<T: Sized>(
+        &mut self,
+        code: MasterReq,
+        msg: &T,
+        fds: Option<&[RawFd]>,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        if mem::size_of::<T>() > MAX_MSG_SIZE {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.check_state()?;
+        self.check_order(code)?;
+
+        let hdr = self.new_request_header(code, mem::size_of::<T>() as u32);
+        self.main_sock
+            .send_message(&hdr, msg, fds)
+            .map_err(|e| self.poison(e))?;
+        self.track_order(code);
+        self.trace_send(&hdr, &[as_bytes(msg)], fds);
+        Ok(hdr)
+    }
+
+    fn send_request_with_payload<T: Sized>(
+        &mut self,
+        code: MasterReq,
+        msg: &T,
+        payload: &[u8],
+        fds: Option<&[RawFd]>,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        let len = mem::size_of::<T>() + payload.len();
+        if len > MAX_MSG_SIZE {
+            return Err(VhostUserError::InvalidParam);
+        }
+        if let Some(ref fd_arr) = fds {
+            if fd_arr.len() > MAX_ATTACHED_FD_ENTRIES {
+                return Err(VhostUserError::InvalidParam);
+            }
+        }
+        self.check_state()?;
+        self.check_order(code)?;
+
+        let hdr = self.new_request_header(code, len as u32);
+        self.main_sock
+            .send_message_with_payload(&hdr, msg, payload, fds)
+            .map_err(|e| self.poison(e))?;
+        self.track_order(code);
+        self.trace_send(&hdr, &[as_bytes(msg), payload], fds);
+        Ok(hdr)
+    }
+
+    fn send_custom_request(
+        &mut self,
+        code: u32,
+        payload: &[u8],
+        fds: Option<&[RawFd]>,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        if !MasterReq::is_custom_code(code) || payload.len() > MAX_MSG_SIZE {
+            return Err(VhostUserError::InvalidParam);
+        }
+        if let Some(fd_arr) = fds {
+            if fd_arr.len() > MAX_ATTACHED_FD_ENTRIES {
+                return Err(VhostUserError::InvalidParam);
+            }
+        }
+        self.check_state()?;
+
+        let hdr = VhostUserMsgHeader::new_raw(code, self.hdr_flags.bits(), payload.len() as u32);
+        self.main_sock
+            .send_message_with_payload(&hdr, &(), payload, fds)
+            .map_err(|e| self.poison(e))?;
+        Ok(hdr)
+    }
+
+    fn recv_custom_reply(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<(Vec<u8>, Option<Vec<File>>)> {
+        self.check_state()?;
+
+        let (reply, files) = self.main_sock.recv_header()?;
+        if !reply.is_reply_for(hdr) {
+            return Err(VhostUserError::InvalidMessage);
+        }
+        let size = reply.get_size() as usize;
+        if size == 0 {
+            return Ok((Vec::new(), files));
+        }
+        let (bytes, buf) = self.main_sock.recv_data(size)?;
+        if bytes != size {
+            return Err(VhostUserError::PartialMessage);
+        }
+        Ok((buf, files))
+    }
+
+    // Take the ticket of the reply to the request just sent.
+    fn pending_reply(&mut self) -> PendingReply {
+        let ticket = self.next_ticket;
+        self.next_ticket = ticket.wrapping_add(1);
+        PendingReply {
+            ticket,
+            connection_id: self.connection_id,
+            sent: self.last_send,
+        }
+    }
+
+    // Fail the receipt of a reply to a request sent on a previous connection.
+    fn check_connection(&self, connection_id: u64) -> VhostUserResult<()> {
+        if connection_id != self.connection_id {
+            return Err(VhostUserError::Disconnected);
+        }
+        Ok(())
+    }
+
+    // Receive the reply to the postcopy SET_MEM_TABLE request `hdr`, with the addresses of the
+    // `regions` in the slave.
+    fn recv_mem_table_postcopy(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        regions: &[VhostUserMemoryRegionInfo],
+    ) -> VhostUserResult<Vec<u64>> {
+        let (body, buf, _) = self
+            .recv_reply_with_payload::<VhostUserMemory>(hdr)
+            .map_err(|e| match e {
+                // The slave would wait for the ack of a late reply.
+                VhostUserError::Timeout => self.disconnect(e, libc::ETIMEDOUT),
+                e => self.poison(e),
+            })?;
+        let region_size = mem::size_of::<VhostUserMemoryRegion>();
+        if body.num_regions as usize != regions.len() || buf.len() != regions.len() * region_size {
+            return Err(VhostUserError::InvalidMessage);
+        }
+
+        let mut addrs = Vec::with_capacity(regions.len());
+        for (region, data) in regions.iter().zip(buf.chunks(region_size)) {
+            let reply =
+                VhostUserMemoryRegion::from_slice(data).ok_or(VhostUserError::InvalidMessage)?;
+            if reply.guest_phys_addr != region.guest_phys_addr
+                || reply.memory_size != region.memory_size
+            {
+                return Err(VhostUserError::InvalidMessage);
+            }
+            addrs.push(reply.user_addr);
+        }
+        Ok(addrs)
+    }
+
+    // Receive and drop the late reply of a request which timed out.
+    fn discard_reply(&mut self) -> VhostUserResult<()> {
+        let (hdr, _) = self.main_sock.recv_header()?;
+        let size = hdr.get_size() as usize;
+        if size > 0 && self.main_sock.recv_data(size)?.0 != size {
+            return Err(VhostUserError::PartialMessage);
+        }
+        Ok(())
+    }
+
+    // Whether the slave acks the request sent with `hdr`.
+    fn expects_ack(&self, hdr: &VhostUserMsgHeader<MasterReq>) -> bool {
+        self.acked_protocol_features & VhostUserProtocolFeatures::REPLY_ACK.bits() != 0
+            && hdr.is_need_reply()
+    }
+
+    fn send_vring_num(
+        &mut self,
+        queue_index: usize,
+        num: u16,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.record(|log| {
+            log.vring(queue_index).num = Some(num);
+            Ok(())
+        })?;
+        let val = VhostUserVringState::new(queue_index as u32, num.into());
+        self.send_request_with_body(MasterReq::SET_VRING_NUM, &val, None)
+    }
+
+    fn send_vring_addr(
+        &mut self,
+        queue_index: usize,
+        config_data: &VringConfigData,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.record(|log| {
+            log.vring(queue_index).addr = Some(*config_data);
+            Ok(())
+        })?;
+        let val = VhostUserVringAddr::from_config_data(queue_index as u32, config_data);
+        self.send_request_with_body(MasterReq::SET_VRING_ADDR, &val, None)
+    }
+
+    fn send_vring_base(
+        &mut self,
+        queue_index: usize,
+        base: u32,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.record(|log| {
+            log.vring(queue_index).base = Some(base);
+            Ok(())
+        })?;
+        let val = VhostUserVringState::new(queue_index as u32, base);
+        self.send_request_with_body(MasterReq::SET_VRING_BASE, &val, None)
+    }
+
+    // Send SET_VRING_CALL, SET_VRING_KICK or SET_VRING_ERR.
+    fn send_vring_fd(
+        &mut self,
+        code: MasterReq,
+        queue_index: usize,
+        fd: Option<&EventFd>,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.record(|log| {
+            let eventfd = Some(try_clone_eventfd(fd)?);
+            let vring = log.vring(queue_index);
+            match code {
+                MasterReq::SET_VRING_CALL => vring.call = eventfd,
+                MasterReq::SET_VRING_KICK => vring.kick = eventfd,
+                _ => vring.err = eventfd,
+            }
+            Ok(())
+        })?;
+        let fd = fd.map(|fd| fd.as_raw_fd());
+        self.send_fd_for_vring(code, queue_index, fd)
+    }
+
+    fn send_vring_enable(
+        &mut self,
+        queue_index: usize,
+        enable: bool,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.record(|log| {
+            log.vring(queue_index).enable = Some(enable);
+            Ok(())
+        })?;
+        let val = VhostUserVringState::new(queue_index as u32, enable.into());
+        self.send_request_with_body(MasterReq::SET_VRING_ENABLE, &val, None)
+    }
+
+    fn send_fd_for_vring(
+        &mut self,
+        code: MasterReq,
+        queue_index: usize,
+        fd: Option<RawFd>,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.check_queue_index(queue_index)?;
+        self.check_state()?;
+
+        // Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag.
+        // This flag is set when there is no file descriptor in the ancillary data. This signals
+        // that polling will be used instead of waiting for the call.
+        let msg = match fd {
+            Some(_) => VhostUserU64::new(queue_index as u64),
+            None => VhostUserU64::new(queue_index as u64 | VHOST_USER_VRING_NOFD_MASK),
+        };
+        let hdr = self.new_request_header(code, mem::size_of::<VhostUserU64>() as u32);
+        let fds = fd.as_ref().map(std::slice::from_ref);
+        self.main_sock
+            .send_message(&hdr, &msg, fds)
+            .map_err(|e| self.poison(e))?;
+        self.trace_send(&hdr, &[msg.as_slice()], fds);
+        Ok(hdr)
+    }
+
+    fn recv_header(
+        &mut self,
+    ) -> VhostUserResult<(VhostUserMsgHeader<MasterReq>, Option<Vec<File>>)> {
+        let (reply, files) = self.main_sock.recv_header()?;
+        self.trace_recv(&reply, &[], &files);
+        Ok((reply, files))
+    }
+
+    fn recv_body<T: ByteValued + Sized + VhostUserMsgValidator>(
+        &mut self,
+    ) -> VhostUserResult<(VhostUserMsgHeader<MasterReq>, T, Option<Vec<File>>)> {
+        let (reply, body, files) = self.main_sock.recv_body::<T>()?;
+        self.trace_recv(&reply, &[body.as_slice()], &files);
+        Ok((reply, body, files))
+    }
+
+    fn trace_send(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        data: &[&[u8]],
+        fds: Option<&[RawFd]>,
+    ) {
+        if let Some(tracer) = self.tracer.as_ref() {
+            tracer.on_send(&MessageTrace {
+                code: hdr.get_code(),
+                flags: hdr.get_flags(),
+                payload: &data.concat(),
+                fds: fds.map_or(0, |fds| fds.len()),
+                latency: None,
+            });
+            self.last_send = Some(Instant::now());
+        }
+    }
+
+    fn trace_recv(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        data: &[&[u8]],
+        files: &Option<Vec<File>>,
+    ) {
+        if let Some(tracer) = self.tracer.as_ref() {
+            tracer.on_recv(&MessageTrace {
+                code: hdr.get_code(),
+                flags: hdr.get_flags(),
+                payload: &data.concat(),
+                fds: files.as_ref().map_or(0, |files| files.len()),
+                latency: self.reply_send.take().map(|sent| sent.elapsed()),
+            });
+        }
+    }
+
+    fn recv_reply<T: ByteValued + Sized + VhostUserMsgValidator>(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<T> {
+        if mem::size_of::<T>() > MAX_MSG_SIZE || hdr.is_reply() {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.check_state()?;
+
+        let (reply, body, rfds) = self.recv_body::<T>()?;
+        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
+            return Err(VhostUserError::InvalidMessage);
+        }
+        Ok(body)
+    }
+
+    fn recv_reply_with_files<T: ByteValued + Sized + VhostUserMsgValidator>(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<(T, Option<Vec<File>>)> {
+        if mem::size_of::<T>() > MAX_MSG_SIZE || hdr.is_reply() {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.check_state()?;
+
+        let (reply, body, files) = self.recv_body::<T>()?;
+        if !reply.is_reply_for(&hdr) || files.is_none() || !body.is_valid() {
+            return Err(VhostUserError::InvalidMessage);
+        }
+        Ok((body, files))
+    }
+
+    fn recv_reply_with_payload<T: ByteValued + Sized + VhostUserMsgValidator>(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<(T, Vec<u8>, Option<Vec<File>>)> {
+        if mem::size_of::<T>() > MAX_MSG_SIZE
+            || hdr.get_size() as usize <= mem::size_of::<T>()
+            || hdr.get_size() as usize > MAX_MSG_SIZE
+            || hdr.is_reply()
+        {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.check_state()?;
+
+        let mut buf: Vec<u8> = vec![0; hdr.get_size() as usize - mem::size_of::<T>()];
+        let (reply, body, bytes, files) = self.main_sock.recv_payload_into_buf::<T>(&mut buf)?;
+        self.trace_recv(&reply, &[body.as_slice(), &buf[..bytes]], &files);
+        if !reply.is_reply_for(hdr)
+            || reply.get_size() as usize != mem::size_of::<T>() + bytes
+            || files.is_some()
+            || !body.is_valid()
+            || bytes != buf.len()
+        {
+            return Err(VhostUserError::InvalidMessage);
+        }
+
+        Ok((body, buf, files))
+    }
+
+    // Receive the reply to GET_CONFIG. The whole reply is consumed according to its header
+    // before being checked, so a malformed reply doesn't leave stale data on the socket.
+    fn recv_config_reply(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<(VhostUserConfig, VhostUserConfigPayload)> {
+        self.check_state()?;
+
+        let (reply, files) = self.main_sock.recv_header()?;
+        let len = reply.get_size() as usize;
+        let buf = match len {
+            0 => Vec::new(),
+            _ => {
+                let (bytes, buf) = self.main_sock.recv_data(len)?;
+                if bytes != len {
+                    return Err(VhostUserError::PartialMessage);
+                }
+                buf
+            }
+        };
+        self.trace_recv(&reply, &[&buf], &files);
+        if !reply.is_reply_for(hdr) || files.is_some() {
+            return Err(VhostUserError::InvalidMessage);
+        }
+
+        let body_len = mem::size_of::<VhostUserConfig>();
+        if len < body_len {
+            return Err(VhostUserError::ConfigReplyLength(len, body_len));
+        }
+        // Safe because the buffer holds at least a VhostUserConfig, which is plain data.
+        let body = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const VhostUserConfig) };
+        Ok((body, buf[body_len..].to_vec()))
+    }
+
+    fn send_mem_table(
+        &mut self,
+        regions: &[VhostUserMemoryRegionInfo],
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        if regions.is_empty() || regions.len() > MAX_ATTACHED_FD_ENTRIES {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.check_xen_mmap()?;
+
+        let mut ctx = VhostUserMemoryContext::new();
+        for region in regions.iter() {
+            let fd = match region.mmap_handle {
+                Some(fd) if region.memory_size != 0 => fd,
+                _ => return Err(VhostUserError::InvalidParam),
+            };
+            let reg = VhostUserMemoryRegion::from(region);
+            ctx.append(&reg, fd.as_raw_fd());
+        }
+
+        let body = VhostUserMemory::new(ctx.regions.len() as u32);
+        let (_, payload, _) = unsafe { ctx.regions.align_to::<u8>() };
+        self.send_request_with_payload(
+            MasterReq::SET_MEM_TABLE,
+            &body,
+            payload,
+            Some(ctx.fds.as_slice()),
+        )
+    }
+
+    // Send a request which is always acked by the slave, whether REPLY_ACK has been negotiated
+    // or not.
+    fn send_request_and_ack(
+        &mut self,
+        code: MasterReq,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        self.check_state()?;
+        let mut hdr = self.new_request_header(code, 0);
+        hdr.set_need_reply(true);
+        self.main_sock
+            .send_header(&hdr, None)
+            .map_err(|e| self.poison(e))?;
+        self.trace_send(&hdr, &[], None);
+        Ok(hdr)
+    }
+
+    fn recv_ack(&mut self, hdr: &VhostUserMsgHeader<MasterReq>) -> VhostUserResult<()> {
+        self.check_state()?;
+
+        let (reply, body, rfds) = self.recv_body::<VhostUserU64>()?;
+        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
+            return Err(VhostUserError::InvalidMessage);
+        }
+        if body.value != 0 {
+            return Err(VhostUserError::SlaveInternalError);
+        }
+        Ok(())
+    }
+
+    fn record<F>(&mut self, f: F) -> VhostUserResult<()>
+    where
+        F: FnOnce(&mut StateLog) -> VhostUserResult<()>,
+    {
+        match self.state_log.as_mut() {
+            Some(log) => f(log),
+            None => Ok(()),
+        }
+    }
+
+    // Switch to a new connection, forgetting the state negotiated with the previous one.
+    fn reset_connection(&mut self, mut main_sock: Endpoint<MasterReq>) -> VhostUserResult<()> {
+        main_sock.set_timeout(self.main_sock.timeout())?;
+        self.main_sock = main_sock;
+        self.connection_id = self.connection_id.wrapping_add(1);
+        self.lost_replies = 0;
+        self.virtio_features = 0;
+        self.acked_virtio_features = 0;
+        self.protocol_features = 0;
+        self.acked_protocol_features = 0;
+        self.protocol_features_ready = false;
+        self.handshake = Handshake::default();
+        self.queue_num = None;
+        self.max_mem_slots = None;
+        self.disconnected = false;
+        self.error = None;
+        Ok(())
+    }
+
+    // Fail locally, in strict ordering mode, when `code` is sent before a handshake request it
+    // depends on.
+    fn check_order(&self, code: MasterReq) -> VhostUserResult<()> {
+        if !self.strict_order {
+            return Ok(());
+        }
+        let handshake = &self.handshake;
+        let missing = match code {
+            MasterReq::GET_FEATURES | MasterReq::GET_PROTOCOL_FEATURES | MasterReq::SET_OWNER => {
+                None
+            }
+            MasterReq::SET_FEATURES if !handshake.features_queried => Some(MasterReq::GET_FEATURES),
+            MasterReq::SET_FEATURES => None,
+            _ if !handshake.owner => Some(MasterReq::SET_OWNER),
+            MasterReq::SET_PROTOCOL_FEATURES if !handshake.protocol_features_queried => {
+                Some(MasterReq::GET_PROTOCOL_FEATURES)
+            }
+            _ if needs_features(code) && !handshake.features_set => Some(MasterReq::SET_FEATURES),
+            _ => None,
+        };
+        match missing {
+            Some(before) => Err(VhostUserError::RequestOutOfOrder(code, before)),
+            None => Ok(()),
+        }
+    }
+
+    // Record the handshake requests once sent.
+    fn track_order(&mut self, code: MasterReq) {
+        let handshake = &mut self.handshake;
+        match code {
+            MasterReq::SET_OWNER => handshake.owner = true,
+            MasterReq::RESET_OWNER => *handshake = Handshake::default(),
+            MasterReq::GET_FEATURES => handshake.features_queried = true,
+            MasterReq::SET_FEATURES => handshake.features_set = true,
+            MasterReq::GET_PROTOCOL_FEATURES => handshake.protocol_features_queried = true,
+            _ => {}
+        }
+    }
+
+    // Fail locally when a request targets a queue beyond the ones supported by the slave.
+    fn check_queue_index(&self, queue_index: usize) -> VhostUserResult<()> {
+        if queue_index as u64 >= self.max_queue_num {
+            return Err(VhostUserError::QueueIndexOutOfRange(
+                queue_index,
+                self.max_queue_num,
+            ));
+        }
+        Ok(())
+    }
+
+    // Fail locally when a request depends on protocol features not negotiated with the slave.
+    fn check_protocol_feature(&self, features: VhostUserProtocolFeatures) -> VhostUserResult<()> {
+        let missing = features.bits() & !self.acked_protocol_features;
+        if missing != 0 {
+            return Err(VhostUserError::ProtocolFeatureNotNegotiated(
+                VhostUserProtocolFeatures::from_bits_truncate(missing),
+            ));
+        }
+        Ok(())
+    }
+
+    // With the `xen` feature, memory region descriptors carry the Xen mmap description, which
+    // the slave only expects once XEN_MMAP has been negotiated.
+    fn check_xen_mmap(&self) -> VhostUserResult<()> {
+        #[cfg(feature = "xen")]
+        {
+            self.check_protocol_feature(VhostUserProtocolFeatures::XEN_MMAP)?;
+        }
+        Ok(())
+    }
+
+    fn check_state(&self) -> VhostUserResult<()> {
+        if self.disconnected {
+            return Err(VhostUserError::Disconnected);
+        }
+        Ok(())
+    }
+
+    // Switch to the disconnected state when `err` shows the connection is broken, so the
+    // following requests fail without using the socket.
+    fn poison(&mut self, err: VhostUserError) -> VhostUserError {
+        let errno = match err {
+            VhostUserError::SocketBroken(ref e) => e.raw_os_error().unwrap_or(libc::EPIPE),
+            // The peer closed the connection, or the framing of the messages is lost.
+            VhostUserError::PartialMessage => libc::ECONNRESET,
+            _ => return err,
+        };
+        self.disconnect(err, errno)
+    }
+
+    // Switch to the disconnected state because of `err`.
+    fn disconnect(&mut self, err: VhostUserError, errno: i32) -> VhostUserError {
+        if !self.disconnected {
+            self.disconnected = true;
+            self.error = Some(errno);
+            if let Some(handler) = self.disconnect_handler.as_ref() {
+                handler(&err);
+            }
+        }
+        err
+    }
+
+    #[inline]
+    fn new_request_header(&self, request: MasterReq, size: u32) -> VhostUserMsgHeader<MasterReq> {
+        let mut hdr = VhostUserMsgHeader::new(request, self.hdr_flags.bits() | 0x1, size);
+        if self.auto_reply_ack
+            && self.acked_protocol_features & VhostUserProtocolFeatures::REPLY_ACK.bits() != 0
+            && is_state_changing(request)
+        {
+            hdr.set_need_reply(true);
+        }
+        hdr
+    }
+
+    // Run `f` without requesting automatic acks, for the requests with a reply of their own.
+    fn without_auto_reply_ack<T, F>(&mut self, f: F) -> VhostUserResult<T>
+    where
+        F: FnOnce(&mut Self) -> VhostUserResult<T>,
+    {
+        let auto_reply_ack = mem::replace(&mut self.auto_reply_ack, false);
+        let res = f(self);
+        self.auto_reply_ack = auto_reply_ack;
+        res
+    }
+}
+
+// Whether `request` configures the memory or the vrings, which depends on the negotiated virtio
+// features.
+fn needs_features(request: MasterReq) -> bool {
+    matches!(
+        request,
+        MasterReq::SET_MEM_TABLE
+            | MasterReq::ADD_MEM_REG
+            | MasterReq::REM_MEM_REG
+            | MasterReq::SET_LOG_BASE
+            | MasterReq::SET_LOG_FD
+            | MasterReq::SET_VRING_NUM
+            | MasterReq::SET_VRING_ADDR
+            | MasterReq::SET_VRING_BASE
+            | MasterReq::GET_VRING_BASE
+            | MasterReq::SET_VRING_KICK
+            | MasterReq::SET_VRING_CALL
+            | MasterReq::SET_VRING_ERR
+            | MasterReq::SET_VRING_ENABLE
+    )
+}
+
+// Whether `request` changes the state of the slave without a reply of its own, so the slave may
+// be asked to ack it.
+fn is_state_changing(request: MasterReq) -> bool {
+    matches!(
+        request,
+        MasterReq::SET_FEATURES
+            | MasterReq::SET_OWNER
+            | MasterReq::RESET_OWNER
+            | MasterReq::SET_MEM_TABLE
+            | MasterReq::SET_LOG_BASE
+            | MasterReq::SET_LOG_FD
+            | MasterReq::SET_VRING_NUM
+            | MasterReq::SET_VRING_ADDR
+            | MasterReq::SET_VRING_BASE
+            | MasterReq::SET_VRING_KICK
+            | MasterReq::SET_VRING_CALL
+            | MasterReq::SET_VRING_ERR
+            | MasterReq::SET_PROTOCOL_FEATURES
+            | MasterReq::SET_VRING_ENABLE
+            | MasterReq::SET_CONFIG
+            | MasterReq::SET_SLAVE_REQ_FD
+            | MasterReq::SET_INFLIGHT_FD
+            | MasterReq::ADD_MEM_REG
+            | MasterReq::REM_MEM_REG
+            | MasterReq::SET_STATUS
+            | MasterReq::RESET_DEVICE
+            | MasterReq::CLOSE_CRYPTO_SESSION
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhost_user::{HandlerResult, VhostUserMasterReqHandlerMut};
+    use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::rand::rand_alphanumerics;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use std::path::PathBuf;
+
+    fn temp_path() -> PathBuf {
+        PathBuf::from(format!(
+            "/tmp/vhost_test_{}",
+            rand_alphanumerics(8).to_str().unwrap()
+        ))
+    }
+
+    // Borrow the fd of an eventfd, to describe the memory regions backed by it.
+    fn borrow_fd(eventfd: &EventFd) -> BorrowedFd<'_> {
+        // Safe because the borrowed fd doesn't outlive the eventfd.
+        unsafe { BorrowedFd::borrow_raw(eventfd.as_raw_fd()) }
+    }
+
+    fn create_pair<P: AsRef<Path>>(path: P) -> (Master, Endpoint<MasterReq>) {
+        let listener = Listener::new(&path, true).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let master = Master::connect(path, 2).unwrap();
+        let slave = listener.accept().unwrap().unwrap();
+        (master, Endpoint::from_stream(slave))
+    }
+
+    #[test]
+    fn create_master() {
+        let path = temp_path();
+        let listener = Listener::new(&path, true).unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let master = Master::connect(&path, 1).unwrap();
+        let mut slave = Endpoint::<MasterReq>::from_stream(listener.accept().unwrap().unwrap());
+
+        assert!(master.as_raw_fd() > 0);
+        // Send two messages continuously
+        master.set_owner().unwrap();
+        master.reset_owner().unwrap();
+
+        let (hdr, rfds) = slave.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+        assert_eq!(hdr.get_size(), 0);
+        assert_eq!(hdr.get_version(), 0x1);
+        assert!(rfds.is_none());
+
+        let (hdr, rfds) = slave.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::RESET_OWNER);
+        assert_eq!(hdr.get_size(), 0);
+        assert_eq!(hdr.get_version(), 0x1);
+        assert!(rfds.is_none());
+    }
+
+    #[test]
+    fn test_create_failure() {
+        let path = temp_path();
+        let _ = Listener::new(&path, true).unwrap();
+        let _ = Listener::new(&path, false).is_err();
+        assert!(Master::connect(&path, 1).is_err());
+
+        let listener = Listener::new(&path, true).unwrap();
+        assert!(Listener::new(&path, false).is_err());
+        listener.set_nonblocking(true).unwrap();
+
+        let _master = Master::connect(&path, 1).unwrap();
+        let _slave = listener.accept().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_master_connect_with_retry() {
+        let path = temp_path();
+        let policy = ConnectPolicy::new(
+            Duration::from_millis(1),
+            Duration::from_millis(4),
+            Duration::from_millis(20),
+        );
+        match Master::connect_with_retry(&path, 1, policy) {
+            Err(Error::VhostUserProtocol(VhostUserError::SocketConnect(e))) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::NotFound)
+            }
+            _ => panic!("connecting to a missing socket should fail"),
+        }
+
+        let listen_path = path.clone();
+        let listener = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            Listener::new(&listen_path, true).unwrap()
+        });
+        let policy = ConnectPolicy::new(
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            Duration::from_secs(10),
+        );
+        let master = Master::connect_with_retry(&path, 1, policy).unwrap();
+        let listener = listener.join().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut slave = Endpoint::<MasterReq>::from_stream(listener.accept().unwrap().unwrap());
+
+        master.set_owner().unwrap();
+        let (hdr, _) = slave.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+    }
+
+    #[test]
+    fn test_master_strict_order() {
+        let path = temp_path();
+        let (master, mut peer) = create_pair(&path);
+        master.set_strict_order(true);
+
+        let expect_out_of_order = |res: Result<()>, req, before| match res {
+            Err(Error::VhostUserProtocol(VhostUserError::RequestOutOfOrder(r, b))) => {
+                assert_eq!((r, b), (req, before))
+            }
+            _ => panic!("the request should be rejected"),
+        };
+        expect_out_of_order(
+            master.set_vring_num(0, 256),
+            MasterReq::SET_VRING_NUM,
+            MasterReq::SET_OWNER,
+        );
+        master.set_owner().unwrap();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+
+        expect_out_of_order(
+            master.set_features(0x1),
+            MasterReq::SET_FEATURES,
+            MasterReq::GET_FEATURES,
+        );
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x1), None)
+            .unwrap();
+        assert_eq!(master.get_features().unwrap(), 0x1);
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+
+        expect_out_of_order(
+            master.set_vring_num(0, 256),
+            MasterReq::SET_VRING_NUM,
+            MasterReq::SET_FEATURES,
+        );
+        master.set_features(0x1).unwrap();
+        let (hdr, _, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_FEATURES);
+        master.set_vring_num(0, 256).unwrap();
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_NUM);
+
+        // RESET_OWNER starts the handshake again.
+        master.reset_owner().unwrap();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::RESET_OWNER);
+        expect_out_of_order(
+            master.set_vring_num(0, 256),
+            MasterReq::SET_VRING_NUM,
+            MasterReq::SET_OWNER,
+        );
+    }
+
+    #[test]
+    fn test_master_check_connection() {
+        let (master, mut peer) = create_pair2();
+        assert!(!master.check_connection().unwrap().is_disconnected());
+
+        // A pending reply is neither a disconnection nor consumed.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
+            .unwrap();
+        match master.check_connection().unwrap() {
+            ConnectionStatus::Connected => {}
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(master.get_features().unwrap(), 0x15);
+        peer.recv_header().unwrap();
+
+        drop(peer);
+        match master.check_connection().unwrap() {
+            ConnectionStatus::Hangup => {}
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn test_master_poisoned() {
+        let (master, peer) = create_pair2();
+        let hangups = Arc::new(Mutex::new(0));
+        let counter = hangups.clone();
+        master.set_disconnect_handler(Some(Box::new(move |_| *counter.lock().unwrap() += 1)));
+        assert!(!master.is_poisoned());
+        assert!(master.take_error().is_none());
+
+        drop(peer);
+        master.get_features().unwrap_err();
+        assert!(master.is_poisoned());
+        assert_eq!(*hangups.lock().unwrap(), 1);
+
+        // The following requests fail fast, without invoking the handler again.
+        match master.set_owner() {
+            Err(Error::VhostUserProtocol(VhostUserError::Disconnected)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(*hangups.lock().unwrap(), 1);
+
+        assert!(master.take_error().is_some());
+        assert!(master.take_error().is_none());
+        assert!(master.is_poisoned());
+    }
+
+    #[test]
+    fn test_features() {
+        let path = temp_path();
+        let (master, mut peer) = create_pair(&path);
+
+        master.set_owner().unwrap();
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+        assert_eq!(hdr.get_size(), 0);
+        assert_eq!(hdr.get_version(), 0x1);
+        assert!(rfds.is_none());
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        let msg = VhostUserU64::new(0x15);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        let features = master.get_features().unwrap();
+        assert_eq!(features, 0x15u64);
+        let (_hdr, rfds) = peer.recv_header().unwrap();
+        assert!(rfds.is_none());
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x4, 8);
+        let msg = VhostUserU64::new(0x15);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        master.set_features(0x15).unwrap();
+        let (_hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert!(rfds.is_none());
+        let val = msg.value;
+        assert_eq!(val, 0x15);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        let msg = 0x15u32;
+        peer.send_message(&hdr, &msg, None).unwrap();
+        assert!(master.get_features().is_err());
+    }
+
+    #[test]
+    fn test_protocol_features() {
+        let path = temp_path();
+        let (mut master, mut peer) = create_pair(&path);
+
+        master.set_owner().unwrap();
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+        assert!(rfds.is_none());
+
+        assert!(master.get_protocol_features().is_err());
+        assert!(master
+            .set_protocol_features(VhostUserProtocolFeatures::all())
+            .is_err());
+
+        let vfeatures = 0x15 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        let msg = VhostUserU64::new(vfeatures);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        let features = master.get_features().unwrap();
+        assert_eq!(features, vfeatures);
+        let (_hdr, rfds) = peer.recv_header().unwrap();
+        assert!(rfds.is_none());
+
+        master.set_features(vfeatures).unwrap();
+        let (_hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert!(rfds.is_none());
+        let val = msg.value;
+        assert_eq!(val, vfeatures);
+
+        let pfeatures = VhostUserProtocolFeatures::all();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_PROTOCOL_FEATURES, 0x4, 8);
+        let msg = VhostUserU64::new(pfeatures.bits());
+        peer.send_message(&hdr, &msg, None).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        assert_eq!(features, pfeatures);
+        let (_hdr, rfds) = peer.recv_header().unwrap();
+        assert!(rfds.is_none());
+
+        master.set_protocol_features(pfeatures).unwrap();
+        let (_hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert!(rfds.is_none());
+        let val = msg.value;
+        assert_eq!(val, pfeatures.bits());
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x4, 8);
+        let msg = VhostUserU64::new(pfeatures.bits());
+        peer.send_message(&hdr, &msg, None).unwrap();
+        assert!(master.get_protocol_features().is_err());
+    }
+
+    #[test]
+    fn test_master_negotiate() {
+        let path = temp_path();
+        let (mut master, mut peer) = create_pair(&path);
+
+        let pfeatures = VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG;
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        let vfeatures = 0x15 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        peer.send_message(&hdr, &VhostUserU64::new(vfeatures), None)
+            .unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_PROTOCOL_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(pfeatures.bits()), None)
+            .unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_QUEUE_NUM, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(2), None)
+            .unwrap();
+
+        let requirements = DeviceRequirements {
+            required_features: 0x5,
+            optional_features: 0x3,
+            required_protocol_features: VhostUserProtocolFeatures::MQ,
+            optional_protocol_features: VhostUserProtocolFeatures::REPLY_ACK,
+        };
+        master.set_strict_order(true);
+        let negotiated = master.negotiate(&requirements).unwrap();
+        let features = 0x5 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        assert_eq!(negotiated.features, features);
+        assert_eq!(negotiated.protocol_features, VhostUserProtocolFeatures::MQ);
+        assert_eq!(negotiated.queue_num, 2);
+
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+        let (hdr, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_FEATURES);
+        assert_eq!({ msg.value }, features);
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_PROTOCOL_FEATURES);
+        let (hdr, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_PROTOCOL_FEATURES);
+        assert_eq!({ msg.value }, VhostUserProtocolFeatures::MQ.bits());
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_QUEUE_NUM);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x1), None)
+            .unwrap();
+        match master.negotiate(&requirements) {
+            Err(Error::UnsupportedFeatures(0x4)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x5), None)
+            .unwrap();
+        match master.negotiate(&requirements) {
+            Err(Error::VhostUserProtocol(VhostUserError::FeatureMismatch)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_master_set_config_negative() {
+        let path = temp_path();
+        let (mut master, _peer) = create_pair(&path);
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        master
+            .set_config(0x100, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .unwrap_err();
+
+        {
+            let mut node = master.node();
+            node.virtio_features = 0xffff_ffff;
+            node.acked_virtio_features = 0xffff_ffff;
+            node.protocol_features = 0xffff_ffff;
+            node.acked_protocol_features = 0xffff_ffff;
+        }
+
+        master
+            .set_config(0, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .unwrap();
+        master
+            .set_config(
+                VHOST_USER_CONFIG_SIZE,
+                VhostUserConfigFlags::WRITABLE,
+                &buf[0..4],
+            )
+            .unwrap_err();
+        master
+            .set_config(0x1000, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .unwrap_err();
+        master
+            .set_config(
+                0x100,
+                unsafe { VhostUserConfigFlags::from_bits_unchecked(0xffff_ffff) },
+                &buf[0..4],
+            )
+            .unwrap_err();
+        master
+            .set_config(VHOST_USER_CONFIG_SIZE, VhostUserConfigFlags::WRITABLE, &buf)
+            .unwrap_err();
+        master
+            .set_config(VHOST_USER_CONFIG_SIZE, VhostUserConfigFlags::WRITABLE, &[])
+            .unwrap_err();
+    }
+
+    fn create_pair2() -> (Master, Endpoint<MasterReq>) {
+        let path = temp_path();
+        let (master, peer) = create_pair(&path);
+
+        {
+            let mut node = master.node();
+            node.virtio_features = 0xffff_ffff;
+            node.acked_virtio_features = 0xffff_ffff;
+            node.protocol_features = 0xffff_ffff;
+            node.acked_protocol_features = 0xffff_ffff;
+            node.queue_num = Some(node.max_queue_num);
+        }
+
+        (master, peer)
+    }
+
+    #[test]
+    fn test_master_config_struct() {
+        #[repr(C)]
+        #[derive(Copy, Clone, Debug, Default)]
+        struct DeviceConfig {
+            capacity: u64,
+            size_max: u32,
+            seg_max: u32,
+        }
+        unsafe impl ByteValued for DeviceConfig {}
+
+        let (mut master, mut peer) = create_pair2();
+        let config = DeviceConfig {
+            capacity: 0x1000,
+            size_max: 0x200,
+            seg_max: 0x80,
+        };
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 12 + 16);
+        let msg = VhostUserConfig::new(0x100, 16, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, config.as_slice(), None)
+            .unwrap();
+        let val: DeviceConfig = master
+            .get_config_struct(0x100, VhostUserConfigFlags::empty())
+            .unwrap();
+        assert_eq!(val.capacity, 0x1000);
+        assert_eq!(val.size_max, 0x200);
+        assert_eq!(val.seg_max, 0x80);
+        let mut buf = vec![0u8; 16];
+        let (hdr, msg, _, _) = peer
+            .recv_payload_into_buf::<VhostUserConfig>(&mut buf)
+            .unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_CONFIG);
+        assert_eq!({ msg.size }, 16);
+
+        // The reply must describe the whole structure.
+        let msg = VhostUserConfig::new(0x100, 8, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, config.as_slice(), None)
+            .unwrap();
+        master
+            .get_config_struct::<DeviceConfig>(0x100, VhostUserConfigFlags::empty())
+            .unwrap_err();
+        peer.recv_payload_into_buf::<VhostUserConfig>(&mut buf)
+            .unwrap();
+
+        master
+            .set_config_struct(0x100, VhostUserConfigFlags::WRITABLE, &config)
+            .unwrap();
+        let (hdr, msg, bytes, _) = peer
+            .recv_payload_into_buf::<VhostUserConfig>(&mut buf)
+            .unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_CONFIG);
+        assert_eq!({ msg.size }, 16);
+        assert_eq!(bytes, 16);
+        assert_eq!(buf, config.as_slice());
+    }
+
+    #[test]
+    fn test_master_config_size() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; 8];
+        let flags = VhostUserConfigFlags::WRITABLE;
+
+        master
+            .set_config_size(VHOST_USER_CONFIG_OFFSET)
+            .unwrap_err();
+        assert!(master.get_config(0x1000, 4, flags, &buf[0..4]).is_err());
+        master.set_config(0x1000, flags, &buf[0..4]).unwrap_err();
+
+        master.set_config_size(0x2000).unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let msg = VhostUserConfig::new(0x1000, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        master.get_config(0x1000, 4, flags, &buf[0..4]).unwrap();
+        let mut req = [0u8; 16];
+        let (hdr, size, _) = peer.recv_body_into_buf(&mut req).unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_CONFIG);
+        assert_eq!(hdr.get_size() as usize, size);
+
+        master.set_config(0x1ffc, flags, &buf[0..4]).unwrap();
+        match master.get_config(0x1ffc, 8, flags, &buf) {
+            Err(Error::VhostUserProtocol(VhostUserError::InvalidConfigRange(0x1ffc, 8))) => {}
+            _ => panic!("get_config() beyond the configuration space should fail"),
+        }
+        master.set_config(0x1ffc, flags, &buf).unwrap_err();
+    }
+
+    #[test]
+    fn test_master_get_config_negative0() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        let mut hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_ok());
+
+        hdr.set_code(MasterReq::GET_FEATURES);
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_err());
+        hdr.set_code(MasterReq::GET_CONFIG);
+    }
+
+    #[test]
+    fn test_master_get_config_negative1() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        let mut hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_ok());
+
+        hdr.set_reply(false);
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_err());
+    }
+
+    #[test]
+    fn test_master_get_config_negative2() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_ok());
+    }
+
+    #[test]
+    fn test_master_get_config_negative3() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_ok());
+
+        msg.offset = 0;
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_err());
+    }
+
+    #[test]
+    fn test_master_get_config_negative4() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_ok());
+
+        msg.offset = 0x101;
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_err());
+    }
+
+    #[test]
+    fn test_master_get_config_negative5() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_ok());
+
+        msg.offset = (MAX_MSG_SIZE + 1) as u32;
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_err());
+    }
+
+    #[test]
+    fn test_master_get_config_negative6() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_ok());
+
+        msg.size = 6;
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..6], None)
+            .unwrap();
+        assert!(master
+            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
+            .is_err());
+    }
+
+    #[test]
+    fn test_master_get_config_validation() {
+        let (mut master, mut peer) = create_pair2();
+        let buf = vec![0x0; 8];
+        let flags = VhostUserConfigFlags::WRITABLE;
+
+        let expect_err = |res: Result<(VhostUserConfig, VhostUserConfigPayload)>| match res {
+            Err(Error::VhostUserProtocol(e)) => e,
+            _ => panic!("get_config() should fail"),
+        };
+        match expect_err(master.get_config(0x100, 4, flags, &buf[0..6])) {
+            VhostUserError::InvalidConfigRange(0x100, 4) => {}
+            e => panic!("unexpected error {}", e),
+        }
+        match expect_err(master.get_config(0xffc, 8, flags, &buf)) {
+            VhostUserError::InvalidConfigRange(0xffc, 8) => {}
+            e => panic!("unexpected error {}", e),
+        }
+
+        // The reply echoes a different size, and carries a payload of that size.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 18);
+        let msg = VhostUserConfig::new(0x100, 6, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..6], None)
+            .unwrap();
+        match expect_err(master.get_config(0x100, 4, flags, &buf[0..4])) {
+            VhostUserError::ConfigReplyMismatch(0x100, 6) => {}
+            e => panic!("unexpected error {}", e),
+        }
+
+        // The reply is consumed entirely, so a truncated payload doesn't desynchronize the
+        // following requests.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 14);
+        let msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..2], None)
+            .unwrap();
+        match expect_err(master.get_config(0x100, 4, flags, &buf[0..4])) {
+            VhostUserError::ConfigReplyLength(2, 4) => {}
+            e => panic!("unexpected error {}", e),
+        }
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
+        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+            .unwrap();
+        let (_, payload) = master.get_config(0x100, 4, flags, &buf[0..4]).unwrap();
+        assert_eq!(payload.len(), 4);
+
+        // An empty reply reports a failure of the slave.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 12);
+        let msg = VhostUserConfig::new(0x100, 0, VhostUserConfigFlags::empty());
+        peer.send_message(&hdr, &msg, None).unwrap();
+        match expect_err(master.get_config(0x100, 4, flags, &buf[0..4])) {
+            VhostUserError::SlaveInternalError => {}
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_maset_set_mem_table_failure() {
+        let (master, _peer) = create_pair2();
+
+        master.set_mem_table(&[]).unwrap_err();
+        let tables = vec![VhostUserMemoryRegionInfo::default(); MAX_ATTACHED_FD_ENTRIES + 1];
+        master.set_mem_table(&tables).unwrap_err();
+    }
+
+    #[test]
+    fn test_master_set_mem_table_from_guest_memory() {
+        let (mut master, mut peer) = create_pair2();
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x3000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges_with_files(&[
+            (
+                GuestAddress(0),
+                0x1000,
+                Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+            ),
+            (
+                GuestAddress(0x10_0000),
+                0x2000,
+                Some(FileOffset::new(file.try_clone().unwrap(), 0x1000)),
+            ),
+        ])
+        .unwrap();
+
+        master.set_mem_table_from_guest_memory(&mem).unwrap();
+        let mut buf = vec![0u8; 2 * mem::size_of::<VhostUserMemoryRegion>()];
+        let (hdr, body, bytes, rfds) = peer
+            .recv_payload_into_buf::<VhostUserMemory>(&mut buf)
+            .unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_MEM_TABLE);
+        assert_eq!({ body.num_regions }, 2);
+        assert_eq!(bytes, buf.len());
+        assert_eq!(rfds.unwrap().len(), 2);
+        let region: VhostUserMemoryRegion =
+            *VhostUserMemoryRegion::from_slice(&buf[mem::size_of::<VhostUserMemoryRegion>()..])
+                .unwrap();
+        assert_eq!({ region.guest_phys_addr }, 0x10_0000);
+        assert_eq!({ region.memory_size }, 0x2000);
+        assert_eq!({ region.mmap_offset }, 0x1000);
+
+        let region = mem.find_region(GuestAddress(0x10_0000)).unwrap();
+        master.add_mem_region_from_guest_region(region).unwrap();
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserSingleMemoryRegion>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::ADD_MEM_REG);
+        assert_eq!(rfds.unwrap().len(), 1);
+        assert_eq!({ msg.mmap_offset }, 0x1000);
+
+        // Anonymous memory can't be shared with the slave.
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        match master.set_mem_table_from_guest_memory(&mem) {
+            Err(Error::InvalidGuestMemoryRegion) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_master_add_mem_region_postcopy() {
+        let (mut master, mut peer) = create_pair2();
+        let eventfd = EventFd::new(0).unwrap();
+        let region = VhostUserMemoryRegionInfo::new(
+            0x10_0000,
+            0x1000,
+            0x7f00_0000_0000,
+            0,
+            Some(borrow_fd(&eventfd)),
+        );
+
+        let hdr = VhostUserMsgHeader::new(
+            MasterReq::ADD_MEM_REG,
+            0x4,
+            mem::size_of::<VhostUserSingleMemoryRegion>() as u32,
+        );
+        let msg = VhostUserSingleMemoryRegion::new(0x10_0000, 0x1000, 0x7f80_0000_0000, 0);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        assert_eq!(
+            master.add_mem_region_postcopy(&region).unwrap(),
+            0x7f80_0000_0000
+        );
+        let (req, msg, rfds) = peer.recv_body::<VhostUserSingleMemoryRegion>().unwrap();
+        assert_eq!(req.get_code(), MasterReq::ADD_MEM_REG);
+        assert_eq!(rfds.unwrap().len(), 1);
+        let user_addr = msg.user_addr;
+        assert_eq!(user_addr, 0x7f00_0000_0000);
+
+        // The reply must describe the same region.
+        let msg = VhostUserSingleMemoryRegion::new(0x20_0000, 0x1000, 0x7f80_0000_0000, 0);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        match master.add_mem_region_postcopy(&region) {
+            Err(Error::VhostUserProtocol(VhostUserError::InvalidMessage)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        peer.recv_body::<VhostUserSingleMemoryRegion>().unwrap();
+
+        let mut invalid = region;
+        invalid.mmap_handle = None;
+        master.add_mem_region_postcopy(&invalid).unwrap_err();
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::PAGEFAULT.bits();
+        master.add_mem_region_postcopy(&region).unwrap_err();
+    }
+
+    #[test]
+    fn test_master_get_max_mem_slots() {
+        let (mut master, mut peer) = create_pair2();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_MAX_MEM_SLOTS, 0x4, 8);
+        let msg = VhostUserU64::new(509);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        assert_eq!(master.get_max_mem_slots().unwrap(), 509);
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_MAX_MEM_SLOTS);
+        assert_eq!(hdr.get_size(), 0);
+        assert!(rfds.is_none());
+
+        master.node().acked_protocol_features &=
+            !VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits();
+        master.get_max_mem_slots().unwrap_err();
+    }
+
+    #[test]
+    fn test_master_capabilities() {
+        let (master, mut peer) = create_pair2();
+        master.node().queue_num = None;
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_QUEUE_NUM, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(2), None)
+            .unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_MAX_MEM_SLOTS, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(509), None)
+            .unwrap();
+        let caps = master.capabilities().unwrap();
+        assert_eq!(caps.features, 0xffff_ffff);
+        assert_eq!(
+            caps.protocol_features,
+            VhostUserProtocolFeatures::from_bits_truncate(0xffff_ffff)
+        );
+        assert_eq!(caps.max_queues, 2);
+        assert_eq!(caps.max_mem_slots, 509);
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_QUEUE_NUM);
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_MAX_MEM_SLOTS);
+
+        // The values are cached.
+        assert_eq!(master.capabilities().unwrap(), caps);
+
+        master.node().acked_protocol_features = 0;
+        let caps = master.capabilities().unwrap();
+        assert_eq!(caps.max_queues, 2);
+        assert_eq!(caps.max_mem_slots, MAX_ATTACHED_FD_ENTRIES as u64);
+    }
+
+    #[test]
+    fn test_master_queue() {
+        let (master, mut peer) = create_pair2();
+        let max_queue_num = master.node().max_queue_num as usize;
+        match master.queue(max_queue_num) {
+            Err(Error::VhostUserProtocol(VhostUserError::QueueIndexOutOfRange(index, _))) => {
+                assert_eq!(index, max_queue_num)
+            }
+            _ => panic!("the queue index should be out of range"),
+        }
+
+        let queue = master.queue(0).unwrap();
+        assert_eq!(queue.index(), 0);
+        queue.set_num(256).unwrap();
+        let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_NUM);
+        assert_eq!(({ msg.index }, { msg.num }), (0, 256));
+
+        queue
+            .set_base_packed(VringPackedBase::new(1, true, 1, true))
+            .unwrap();
+        let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_BASE);
+        assert_eq!({ msg.num }, 0x8001_8001);
+
+        queue.enable(true).unwrap();
+        let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_ENABLE);
+        assert_eq!(({ msg.index }, { msg.num }), (0, 1));
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_VRING_BASE, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserVringState::new(0, 0x20), None)
+            .unwrap();
+        assert_eq!(queue.get_base().unwrap(), 0x20);
+    }
+
+    #[test]
+    fn test_master_postcopy() {
+        let (mut master, mut peer) = create_pair2();
+        let eventfd = EventFd::new(0).unwrap();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::POSTCOPY_ADVISE, 0x4, 0);
+        peer.send_header(&hdr, Some(&[eventfd.as_raw_fd()]))
+            .unwrap();
+        master.postcopy_advise().unwrap();
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::POSTCOPY_ADVISE);
+        assert!(rfds.is_none());
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::POSTCOPY_ADVISE, 0x4, 0);
+        peer.send_header(&hdr, None).unwrap();
+        master.postcopy_advise().unwrap_err();
+        peer.recv_header().unwrap();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::POSTCOPY_LISTEN, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        master.postcopy_listen().unwrap();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::POSTCOPY_LISTEN);
+        assert!(hdr.is_need_reply());
+
+        let region = VhostUserMemoryRegionInfo::new(
+            0,
+            0x1000,
+            0x7f00_0000_0000,
+            0,
+            Some(borrow_fd(&eventfd)),
+        );
+        let size = mem::size_of::<VhostUserMemory>() + mem::size_of::<VhostUserMemoryRegion>();
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_MEM_TABLE, 0x4, size as u32);
+        let body = VhostUserMemory::new(1);
+        let reply = VhostUserMemoryRegion::new(0, 0x1000, 0x7f80_0000_0000, 0);
+        peer.send_message_with_payload(&hdr, &body, reply.as_slice(), None)
+            .unwrap();
+        assert_eq!(
+            master.set_mem_table_postcopy(&[region]).unwrap(),
+            vec![0x7f80_0000_0000]
+        );
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_MEM_TABLE);
+        assert_eq!(rfds.unwrap().len(), 1);
+        peer.recv_data(hdr.get_size() as usize).unwrap();
+        let (hdr, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_MEM_TABLE);
+        let value = msg.value;
+        assert_eq!(value, 0);
+
+        // The slave is told when its reply is rejected.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_MEM_TABLE, 0x4, size as u32);
+        let reply = VhostUserMemoryRegion::new(0x1000, 0x1000, 0x7f80_0000_0000, 0);
+        peer.send_message_with_payload(&hdr, &body, reply.as_slice(), None)
+            .unwrap();
+        master.set_mem_table_postcopy(&[region]).unwrap_err();
+        let (hdr, _) = peer.recv_header().unwrap();
+        peer.recv_data(hdr.get_size() as usize).unwrap();
+        let (hdr, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_MEM_TABLE);
+        let value = msg.value;
+        assert_eq!(value, 1);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::POSTCOPY_END, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(1), None)
+            .unwrap();
+        master.postcopy_end().unwrap_err();
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::PAGEFAULT.bits();
+        master.postcopy_advise().unwrap_err();
+        master.postcopy_listen().unwrap_err();
+        master.postcopy_end().unwrap_err();
+        master.set_mem_table_postcopy(&[region]).unwrap_err();
+    }
+
+    #[test]
+    fn test_master_inflight_fd() {
+        let (mut master, mut peer) = create_pair2();
+        let eventfd = EventFd::new(0).unwrap();
+        let request = VhostUserInflight::new(0, 0, 2, 128);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_INFLIGHT_FD, 0x4, 24);
+        let reply = VhostUserInflight::new(request.split_mmap_size(), 0, 2, 128);
+        peer.send_message(&hdr, &reply, Some(&[eventfd.as_raw_fd()]))
+            .unwrap();
+        let (inflight, file) = master.get_inflight_fd(&request).unwrap();
+        assert_eq!(inflight.mmap_size, 4224);
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserInflight>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_INFLIGHT_FD);
+        assert!(rfds.is_none());
+        assert_eq!(msg.num_queues, 2);
+
+        // The reply must carry the buffer and its size.
+        peer.send_message(&hdr, &reply, None).unwrap();
+        assert!(master.get_inflight_fd(&request).is_err());
+        peer.recv_body::<VhostUserInflight>().unwrap();
+        let empty = VhostUserInflight::new(0, 0, 2, 128);
+        peer.send_message(&hdr, &empty, Some(&[eventfd.as_raw_fd()]))
+            .unwrap();
+        assert!(master.get_inflight_fd(&request).is_err());
+        peer.recv_body::<VhostUserInflight>().unwrap();
+
+        master.set_inflight_fd(&inflight, file.as_fd()).unwrap();
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserInflight>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_INFLIGHT_FD);
+        assert_eq!(rfds.unwrap().len(), 1);
+        assert_eq!(msg.mmap_size, 4224);
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits();
+        assert!(master.get_inflight_fd(&request).is_err());
+        master.set_inflight_fd(&inflight, file.as_fd()).unwrap_err();
+    }
+
+    #[test]
+    fn test_master_device_state() {
+        let (mut master, mut peer) = create_pair2();
+        let (rx, tx) = UnixStream::pair().unwrap();
+
+        // The slave transfers the state through the pipe end provided by the master.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_DEVICE_STATE_FD, 0x4, 8);
+        let msg = VhostUserU64::new(VHOST_USER_DEVICE_STATE_NO_FD);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        let file = master
+            .set_device_state_fd(
+                VhostTransferStateDirection::SAVE,
+                VhostTransferStatePhase::STOPPED,
+                &tx,
+            )
+            .unwrap();
+        assert!(file.is_none());
+        let (req, msg, rfds) = peer.recv_body::<VhostUserTransferDeviceState>().unwrap();
+        assert_eq!(req.get_code(), MasterReq::SET_DEVICE_STATE_FD);
+        assert_eq!(rfds.unwrap().len(), 1);
+        let direction = msg.direction;
+        assert_eq!(direction, VhostTransferStateDirection::SAVE as u32);
+
+        // The slave returns its own pipe end.
+        let msg = VhostUserU64::new(0);
+        peer.send_message(&hdr, &msg, Some(&[rx.as_raw_fd()]))
+            .unwrap();
+        let file = master
+            .set_device_state_fd(
+                VhostTransferStateDirection::LOAD,
+                VhostTransferStatePhase::STOPPED,
+                &tx,
+            )
+            .unwrap();
+        assert!(file.is_some());
+        peer.recv_body::<VhostUserTransferDeviceState>().unwrap();
+
+        // A missing fd or a failure reported by the slave is an error.
+        peer.send_message(&hdr, &msg, None).unwrap();
+        assert!(master
+            .set_device_state_fd(
+                VhostTransferStateDirection::LOAD,
+                VhostTransferStatePhase::STOPPED,
+                &tx,
+            )
+            .is_err());
+        peer.recv_body::<VhostUserTransferDeviceState>().unwrap();
+        let msg = VhostUserU64::new(VHOST_USER_DEVICE_STATE_NO_FD | 0x1);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        assert!(master
+            .set_device_state_fd(
+                VhostTransferStateDirection::SAVE,
+                VhostTransferStatePhase::STOPPED,
+                &tx,
+            )
+            .is_err());
+        peer.recv_body::<VhostUserTransferDeviceState>().unwrap();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::CHECK_DEVICE_STATE, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        master.check_device_state().unwrap();
+        let (req, rfds) = peer.recv_header().unwrap();
+        assert_eq!(req.get_code(), MasterReq::CHECK_DEVICE_STATE);
+        assert!(rfds.is_none());
+        peer.send_message(&hdr, &VhostUserU64::new(1), None)
+            .unwrap();
+        master.check_device_state().unwrap_err();
+        peer.recv_header().unwrap();
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::DEVICE_STATE.bits();
+        master
+            .set_device_state_fd(
+                VhostTransferStateDirection::SAVE,
+                VhostTransferStatePhase::STOPPED,
+                &tx,
+            )
+            .unwrap_err();
+        master.check_device_state().unwrap_err();
     }
 
-    fn send_request_with_body<T: Sized>(
-        &mut self,
-        code: MasterReq,
-        msg: &T,
-        fds: Option<&[RawFd]>,
-    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
-        if mem::size_of::<T>() > MAX_MSG_SIZE {
-            return Err(VhostUserError::InvalidParam);
-        }
-        self.check_state()?;
+    #[test]
+    fn test_master_status() {
+        let (master, mut peer) = create_pair2();
 
-        let hdr = self.new_request_header(code, mem::size_of::<T>() as u32);
-        self.main_sock.send_message(&hdr, msg, fds)?;
-        Ok(hdr)
-    }
+        master.set_status(0xf).unwrap();
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_STATUS);
+        assert!(rfds.is_none());
+        let val = msg.value;
+        assert_eq!(val, 0xf);
 
-    fn send_request_with_payload<T: Sized>(
-        &mut self,
-        code: MasterReq,
-        msg: &T,
-        payload: &[u8],
-        fds: Option<&[RawFd]>,
-    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
-        let len = mem::size_of::<T>() + payload.len();
-        if len > MAX_MSG_SIZE {
-            return Err(VhostUserError::InvalidParam);
-        }
-        if let Some(ref fd_arr) = fds {
-            if fd_arr.len() > MAX_ATTACHED_FD_ENTRIES {
-                return Err(VhostUserError::InvalidParam);
-            }
-        }
-        self.check_state()?;
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_STATUS, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0xf), None)
+            .unwrap();
+        assert_eq!(master.get_status().unwrap(), 0xf);
+        let (req, rfds) = peer.recv_header().unwrap();
+        assert_eq!(req.get_code(), MasterReq::GET_STATUS);
+        assert!(rfds.is_none());
+        peer.send_message(&hdr, &VhostUserU64::new(0x100), None)
+            .unwrap();
+        master.get_status().unwrap_err();
+        peer.recv_header().unwrap();
 
-        let hdr = self.new_request_header(code, len as u32);
-        self.main_sock
-            .send_message_with_payload(&hdr, msg, payload, fds)?;
-        Ok(hdr)
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::STATUS.bits();
+        master.set_status(0xf).unwrap_err();
+        master.get_status().unwrap_err();
     }
 
-    fn send_fd_for_vring(
-        &mut self,
-        code: MasterReq,
-        queue_index: usize,
-        fd: RawFd,
-    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
-        if queue_index as u64 >= self.max_queue_num {
-            return Err(VhostUserError::InvalidParam);
-        }
-        self.check_state()?;
+    #[test]
+    fn test_master_reset_device() {
+        let (mut master, mut peer) = create_pair2();
 
-        // Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag.
-        // This flag is set when there is no file descriptor in the ancillary data. This signals
-        // that polling will be used instead of waiting for the call.
-        let msg = VhostUserU64::new(queue_index as u64);
-        let hdr = self.new_request_header(code, mem::size_of::<VhostUserU64>() as u32);
-        self.main_sock.send_message(&hdr, &msg, Some(&[fd]))?;
-        Ok(hdr)
+        master.reset_device().unwrap();
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::RESET_DEVICE);
+        assert_eq!(hdr.get_size(), 0);
+        assert!(rfds.is_none());
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::RESET_DEVICE.bits();
+        master.reset_device().unwrap_err();
     }
 
-    fn recv_reply<T: ByteValued + Sized + VhostUserMsgValidator>(
-        &mut self,
-        hdr: &VhostUserMsgHeader<MasterReq>,
-    ) -> VhostUserResult<T> {
-        if mem::size_of::<T>() > MAX_MSG_SIZE || hdr.is_reply() {
-            return Err(VhostUserError::InvalidParam);
-        }
-        self.check_state()?;
+    #[test]
+    fn test_master_reset() {
+        let (mut master, mut peer) = create_pair2();
 
-        let (reply, body, rfds) = self.main_sock.recv_body::<T>()?;
-        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
-            return Err(VhostUserError::InvalidMessage);
+        master.reset().unwrap();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::RESET_DEVICE);
+
+        // Without RESET_DEVICE, the vrings are disabled before clearing the status.
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::RESET_DEVICE.bits();
+        master.reset().unwrap();
+        for index in 0..2 {
+            let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::SET_VRING_ENABLE);
+            assert_eq!(({ msg.index }, { msg.num }), (index, 0));
+        }
+        let (hdr, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_STATUS);
+        assert_eq!({ msg.value }, 0);
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::STATUS.bits();
+        match master.reset() {
+            Err(Error::VhostUserProtocol(VhostUserError::ProtocolFeatureNotNegotiated(f))) => {
+                assert_eq!(
+                    f,
+                    VhostUserProtocolFeatures::RESET_DEVICE | VhostUserProtocolFeatures::STATUS
+                )
+            }
+            res => panic!("unexpected result {:?}", res),
         }
-        Ok(body)
     }
 
-    fn recv_reply_with_files<T: ByteValued + Sized + VhostUserMsgValidator>(
-        &mut self,
-        hdr: &VhostUserMsgHeader<MasterReq>,
-    ) -> VhostUserResult<(T, Option<Vec<File>>)> {
-        if mem::size_of::<T>() > MAX_MSG_SIZE || hdr.is_reply() {
-            return Err(VhostUserError::InvalidParam);
-        }
-        self.check_state()?;
+    #[test]
+    fn test_master_protocol_feature_not_negotiated() {
+        let (mut master, _peer) = create_pair2();
+        master.node().acked_protocol_features = VhostUserProtocolFeatures::MQ.bits();
 
-        let (reply, body, files) = self.main_sock.recv_body::<T>()?;
-        if !reply.is_reply_for(&hdr) || files.is_none() || !body.is_valid() {
-            return Err(VhostUserError::InvalidMessage);
+        match master.set_config(0x100, VhostUserConfigFlags::WRITABLE, &[0; 4]) {
+            Err(Error::VhostUserProtocol(VhostUserError::ProtocolFeatureNotNegotiated(f))) => {
+                assert_eq!(f, VhostUserProtocolFeatures::CONFIG)
+            }
+            res => panic!("unexpected result {:?}", res),
         }
-        Ok((body, files))
-    }
-
-    fn recv_reply_with_payload<T: ByteValued + Sized + VhostUserMsgValidator>(
-        &mut self,
-        hdr: &VhostUserMsgHeader<MasterReq>,
-    ) -> VhostUserResult<(T, Vec<u8>, Option<Vec<File>>)> {
-        if mem::size_of::<T>() > MAX_MSG_SIZE
-            || hdr.get_size() as usize <= mem::size_of::<T>()
-            || hdr.get_size() as usize > MAX_MSG_SIZE
-            || hdr.is_reply()
-        {
-            return Err(VhostUserError::InvalidParam);
+        let eventfd = EventFd::new(0).unwrap();
+        let (sock, _) = UnixStream::pair().unwrap();
+        match master.set_slave_request_fd(&sock) {
+            Err(Error::VhostUserProtocol(VhostUserError::ProtocolFeatureNotNegotiated(f))) => {
+                assert_eq!(f, VhostUserProtocolFeatures::SLAVE_REQ)
+            }
+            res => panic!("unexpected result {:?}", res),
         }
-        self.check_state()?;
-
-        let mut buf: Vec<u8> = vec![0; hdr.get_size() as usize - mem::size_of::<T>()];
-        let (reply, body, bytes, files) = self.main_sock.recv_payload_into_buf::<T>(&mut buf)?;
-        if !reply.is_reply_for(hdr)
-            || reply.get_size() as usize != mem::size_of::<T>() + bytes
-            || files.is_some()
-            || !body.is_valid()
-            || bytes != buf.len()
-        {
-            return Err(VhostUserError::InvalidMessage);
+        // Only the missing features are reported.
+        let region = VhostUserMemoryRegionInfo::new(0, 0x1000, 0, 0, Some(borrow_fd(&eventfd)));
+        match master.add_mem_region_postcopy(&region) {
+            Err(Error::VhostUserProtocol(VhostUserError::ProtocolFeatureNotNegotiated(f))) => {
+                assert_eq!(
+                    f,
+                    VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS
+                        | VhostUserProtocolFeatures::PAGEFAULT
+                )
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        master.node().acked_protocol_features |= VhostUserProtocolFeatures::PAGEFAULT.bits();
+        match master.add_mem_region_postcopy(&region) {
+            Err(Error::VhostUserProtocol(VhostUserError::ProtocolFeatureNotNegotiated(f))) => {
+                assert_eq!(f, VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)
+            }
+            res => panic!("unexpected result {:?}", res),
         }
-
-        Ok((body, buf, files))
     }
 
-    fn wait_for_ack(&mut self, hdr: &VhostUserMsgHeader<MasterReq>) -> VhostUserResult<()> {
-        if self.acked_protocol_features & VhostUserProtocolFeatures::REPLY_ACK.bits() == 0
-            || !hdr.is_need_reply()
-        {
-            return Ok(());
+    #[test]
+    fn test_master_vring_fd_none() {
+        let (master, mut peer) = create_pair2();
+        let eventfd = EventFd::new(0).unwrap();
+
+        master.set_vring_call(1, Some(&eventfd)).unwrap();
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_CALL);
+        let a = msg.value;
+        assert_eq!(a, 1);
+        assert_eq!(rfds.unwrap().len(), 1);
+
+        master.set_vring_call(1, None).unwrap();
+        master.set_vring_kick(0, None).unwrap();
+        master.set_vring_err(1, None).unwrap();
+        for code in &[
+            MasterReq::SET_VRING_CALL,
+            MasterReq::SET_VRING_KICK,
+            MasterReq::SET_VRING_ERR,
+        ] {
+            let (hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+            assert_eq!(hdr.get_code(), *code);
+            let a = msg.value;
+            assert_ne!(a & VHOST_USER_VRING_NOFD_MASK, 0);
+            assert!(rfds.is_none());
         }
-        self.check_state()?;
 
-        let (reply, body, rfds) = self.main_sock.recv_body::<VhostUserU64>()?;
-        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
-            return Err(VhostUserError::InvalidMessage);
-        }
-        if body.value != 0 {
-            return Err(VhostUserError::SlaveInternalError);
-        }
-        Ok(())
+        master.set_vring_kick(2, None).unwrap_err();
     }
 
-    fn is_feature_mq_available(&self) -> bool {
-        self.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0
-    }
+    #[test]
+    fn test_master_auto_reply_ack() {
+        let (mut master, mut peer) = create_pair2();
 
-    fn check_state(&self) -> VhostUserResult<()> {
-        match self.error {
-            Some(e) => Err(VhostUserError::SocketBroken(
-                std::io::Error::from_raw_os_error(e),
-            )),
-            None => Ok(()),
-        }
+        master.set_vring_num(0, 256).unwrap();
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert!(!hdr.is_need_reply());
+
+        master.set_auto_reply_ack(true);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_NUM, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        master.set_vring_num(0, 256).unwrap();
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert!(hdr.is_need_reply());
+
+        // The slave failed to apply the request.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_ENABLE, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(1), None)
+            .unwrap();
+        master.set_vring_enable(0, true).unwrap_err();
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert!(hdr.is_need_reply());
+
+        // Requests with a reply of their own are left alone.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_STATUS, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x7), None)
+            .unwrap();
+        assert_eq!(master.get_status().unwrap(), 0x7);
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert!(!hdr.is_need_reply());
+
+        master.set_auto_reply_ack(false);
+        master.set_vring_num(0, 256).unwrap();
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert!(!hdr.is_need_reply());
     }
 
-    #[inline]
-    fn new_request_header(&self, request: MasterReq, size: u32) -> VhostUserMsgHeader<MasterReq> {
-        VhostUserMsgHeader::new(request, self.hdr_flags.bits() | 0x1, size)
+    #[test]
+    fn test_master_get_shared_object() {
+        let (mut master, mut peer) = create_pair2();
+        let uuid = VhostUserShared::new([0xa5; 16]);
+        let eventfd = EventFd::new(0).unwrap();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_SHARED_OBJECT, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0), Some(&[eventfd.as_raw_fd()]))
+            .unwrap();
+        master.get_shared_object(&uuid).unwrap();
+        let (req, msg, rfds) = peer.recv_body::<VhostUserShared>().unwrap();
+        assert_eq!(req.get_code(), MasterReq::GET_SHARED_OBJECT);
+        assert!(rfds.is_none());
+        assert_eq!(msg.uuid, [0xa5; 16]);
+
+        // The slave failed to export the object, or didn't attach it to the reply.
+        peer.send_message(&hdr, &VhostUserU64::new(1), None)
+            .unwrap();
+        master.get_shared_object(&uuid).unwrap_err();
+        peer.recv_body::<VhostUserShared>().unwrap();
+        peer.send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        master.get_shared_object(&uuid).unwrap_err();
+        peer.recv_body::<VhostUserShared>().unwrap();
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::SHARED_OBJECT.bits();
+        master.get_shared_object(&uuid).unwrap_err();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::super::connection::Listener;
-    use super::*;
-    use vmm_sys_util::rand::rand_alphanumerics;
+    #[test]
+    fn test_master_crypto_session() {
+        let (mut master, mut peer) = create_pair2();
+        let session = VhostUserCryptoSession::new(&[0x1; 16], &[]).unwrap();
 
-    use std::path::PathBuf;
+        let hdr = VhostUserMsgHeader::new(
+            MasterReq::CREATE_CRYPTO_SESSION,
+            0x4,
+            mem::size_of::<VhostUserCryptoSession>() as u32,
+        );
+        let mut reply = session;
+        reply.session_id = 3;
+        peer.send_message(&hdr, &reply, None).unwrap();
+        assert_eq!(master.create_crypto_session(&session).unwrap(), 3);
+        let (req, msg, rfds) = peer.recv_body::<VhostUserCryptoSession>().unwrap();
+        assert_eq!(req.get_code(), MasterReq::CREATE_CRYPTO_SESSION);
+        assert!(rfds.is_none());
+        let a = msg.cipher_key_len;
+        assert_eq!(a, 16);
+        assert_eq!(msg.cipher_key[0], 0x1);
+
+        // The slave failed to create the session.
+        reply.session_id = -1;
+        peer.send_message(&hdr, &reply, None).unwrap();
+        master.create_crypto_session(&session).unwrap_err();
+        peer.recv_body::<VhostUserCryptoSession>().unwrap();
+
+        let mut invalid = session;
+        invalid.auth_key_len = VHOST_USER_CRYPTO_MAX_HMAC_KEY_LEN as u32 + 1;
+        master.create_crypto_session(&invalid).unwrap_err();
+
+        master.close_crypto_session(3).unwrap();
+        let (req, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(req.get_code(), MasterReq::CLOSE_CRYPTO_SESSION);
+        assert!(rfds.is_none());
+        let a = msg.value;
+        assert_eq!(a, 3);
 
-    fn temp_path() -> PathBuf {
-        PathBuf::from(format!(
-            "/tmp/vhost_test_{}",
-            rand_alphanumerics(8).to_str().unwrap()
-        ))
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::CRYPTO_SESSION.bits();
+        master.create_crypto_session(&session).unwrap_err();
+        master.close_crypto_session(3).unwrap_err();
     }
 
-    fn create_pair<P: AsRef<Path>>(path: P) -> (Master, Endpoint<MasterReq>) {
-        let listener = Listener::new(&path, true).unwrap();
-        listener.set_nonblocking(true).unwrap();
-        let master = Master::connect(path, 2).unwrap();
-        let slave = listener.accept().unwrap().unwrap();
-        (master, Endpoint::from_stream(slave))
+    #[cfg(feature = "xen")]
+    #[test]
+    fn test_master_xen_mmap() {
+        let (mut master, mut peer) = create_pair2();
+        let eventfd = EventFd::new(0).unwrap();
+        let mut region = VhostUserMemoryRegionInfo::new(0, 0x1000, 0, 0, Some(borrow_fd(&eventfd)));
+        region.xen_mmap_flags = VhostUserXenMmapFlags::GRANT.bits();
+        region.xen_domid = 1;
+
+        master.add_mem_region(&region).unwrap();
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserSingleMemoryRegion>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::ADD_MEM_REG);
+        assert_eq!(rfds.unwrap().len(), 1);
+        assert_eq!(msg.xen_mmap_flags(), VhostUserXenMmapFlags::GRANT);
+        let domid = msg.xen_domid;
+        assert_eq!(domid, 1);
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::XEN_MMAP.bits();
+        master.add_mem_region(&region).unwrap_err();
+        master.set_mem_table(&[region]).unwrap_err();
     }
 
     #[test]
-    fn create_master() {
+    fn test_master_listener() {
         let path = temp_path();
-        let listener = Listener::new(&path, true).unwrap();
-        listener.set_nonblocking(true).unwrap();
+        let listener = MasterListener::new(Listener::new(&path, true).unwrap(), 2);
+        assert!(listener.as_raw_fd() >= 0);
 
-        let master = Master::connect(&path, 1).unwrap();
-        let mut slave = Endpoint::<MasterReq>::from_stream(listener.accept().unwrap().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().unwrap().is_none());
 
-        assert!(master.as_raw_fd() > 0);
-        // Send two messages continuously
+        let mut peer = Endpoint::<MasterReq>::connect(&path).unwrap();
+        let master = listener.accept().unwrap().unwrap();
         master.set_owner().unwrap();
-        master.reset_owner().unwrap();
-
-        let (hdr, rfds) = slave.recv_header().unwrap();
+        let (hdr, rfds) = peer.recv_header().unwrap();
         assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
-        assert_eq!(hdr.get_size(), 0);
-        assert_eq!(hdr.get_version(), 0x1);
-        assert!(rfds.is_none());
-
-        let (hdr, rfds) = slave.recv_header().unwrap();
-        assert_eq!(hdr.get_code(), MasterReq::RESET_OWNER);
-        assert_eq!(hdr.get_size(), 0);
-        assert_eq!(hdr.get_version(), 0x1);
         assert!(rfds.is_none());
     }
 
     #[test]
-    fn test_create_failure() {
+    fn test_master_reconnect() {
         let path = temp_path();
-        let _ = Listener::new(&path, true).unwrap();
-        let _ = Listener::new(&path, false).is_err();
-        assert!(Master::connect(&path, 1).is_err());
-
         let listener = Listener::new(&path, true).unwrap();
-        assert!(Listener::new(&path, false).is_err());
-        listener.set_nonblocking(true).unwrap();
+        let mut master = Master::connect(&path, 2).unwrap();
+        let mut peer = Endpoint::<MasterReq>::from_stream(listener.accept().unwrap().unwrap());
+        let eventfd = EventFd::new(0).unwrap();
 
-        let _master = Master::connect(&path, 1).unwrap();
-        let _slave = listener.accept().unwrap().unwrap();
+        master.reconnect().unwrap_err();
+        master.enable_reconnect().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
+            .unwrap();
+        assert_eq!(master.get_features().unwrap(), 0x15);
+        master.set_features(0x5).unwrap();
+        master.set_vring_num(0, 256).unwrap();
+        master.set_vring_base(0, 8).unwrap();
+        master.set_vring_kick(0, Some(&eventfd)).unwrap();
+
+        // The slave restarts, and the state is restored on the new connection.
+        drop(peer);
+        let slave = std::thread::spawn(move || {
+            let mut peer = Endpoint::<MasterReq>::from_stream(listener.accept().unwrap().unwrap());
+            let (hdr, _) = peer.recv_header().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
+            let (hdr, _) = peer.recv_header().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+            let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+            peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
+                .unwrap();
+            let (hdr, msg, _) = peer.recv_body::<VhostUserU64>().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::SET_FEATURES);
+            let features = msg.value;
+            assert_eq!(features, 0x5);
+            let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::SET_VRING_NUM);
+            let num = msg.num;
+            assert_eq!(num, 256);
+            let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::SET_VRING_BASE);
+            let base = msg.num;
+            assert_eq!(base, 8);
+            let (hdr, _, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::SET_VRING_KICK);
+            assert_eq!(rfds.unwrap().len(), 1);
+            // The failed request has been recorded, and is sent again once reconnected.
+            for _ in 0..2 {
+                let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+                assert_eq!(hdr.get_code(), MasterReq::SET_VRING_BASE);
+                let index = msg.index;
+                assert_eq!(index, 1);
+            }
+        });
+
+        master
+            .with_reconnect(|master| master.set_vring_base(1, 4))
+            .unwrap();
+        slave.join().unwrap();
     }
 
     #[test]
-    fn test_features() {
+    fn test_master_timeout() {
         let path = temp_path();
-        let (master, mut peer) = create_pair(&path);
-
-        master.set_owner().unwrap();
-        let (hdr, rfds) = peer.recv_header().unwrap();
-        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
-        assert_eq!(hdr.get_size(), 0);
-        assert_eq!(hdr.get_version(), 0x1);
-        assert!(rfds.is_none());
-
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
-        let msg = VhostUserU64::new(0x15);
-        peer.send_message(&hdr, &msg, None).unwrap();
-        let features = master.get_features().unwrap();
-        assert_eq!(features, 0x15u64);
-        let (_hdr, rfds) = peer.recv_header().unwrap();
-        assert!(rfds.is_none());
+        let (mut master, mut peer) = create_pair(&path);
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x4, 8);
-        let msg = VhostUserU64::new(0x15);
-        peer.send_message(&hdr, &msg, None).unwrap();
-        master.set_features(0x15).unwrap();
-        let (_hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
-        assert!(rfds.is_none());
-        let val = msg.value;
-        assert_eq!(val, 0x15);
+        master
+            .set_timeout(Some(Duration::from_secs(0)))
+            .unwrap_err();
+        master.set_timeout(Some(Duration::from_millis(10))).unwrap();
+        match master.get_features() {
+            Err(Error::VhostUserProtocol(VhostUserError::Timeout)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
-        let msg = 0x15u32;
-        peer.send_message(&hdr, &msg, None).unwrap();
-        assert!(master.get_features().is_err());
+        // The late reply of the request which timed out isn't taken for the next reply.
+        let reply = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&reply, &VhostUserU64::new(0x1), None)
+            .unwrap();
+        let slave = thread::spawn(move || {
+            let (hdr, _) = peer.recv_header().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+            peer.send_message(&reply, &VhostUserU64::new(0x15), None)
+                .unwrap();
+            peer
+        });
+        assert_eq!(master.get_features().unwrap(), 0x15);
+        let mut peer = slave.join().unwrap();
+
+        // The first attempt times out, and its reply is discarded by the second attempt.
+        master.set_retry_policy(RetryPolicy::new(1, Duration::from_millis(100)));
+        let slave = thread::spawn(move || {
+            let (hdr, _) = peer.recv_header().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+            thread::sleep(Duration::from_millis(50));
+            peer.send_message(&reply, &VhostUserU64::new(0x1), None)
+                .unwrap();
+            let (hdr, _) = peer.recv_header().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+            peer.send_message(&reply, &VhostUserU64::new(0x15), None)
+                .unwrap();
+            peer
+        });
+        assert_eq!(master.with_retry(|m| m.get_features()).unwrap(), 0x15);
+        let mut peer = slave.join().unwrap();
+
+        peer.send_message(&reply, &VhostUserU64::new(0x15), None)
+            .unwrap();
+        let features = master
+            .with_request_policy(None, RetryPolicy::default(), |m| m.get_features())
+            .unwrap();
+        assert_eq!(features, 0x15);
+        assert_eq!(
+            master.node().main_sock.timeout(),
+            Some(Duration::from_millis(10))
+        );
     }
 
     #[test]
-    fn test_protocol_features() {
+    fn test_master_reply_turn() {
         let path = temp_path();
-        let (mut master, mut peer) = create_pair(&path);
-
-        master.set_owner().unwrap();
-        let (hdr, rfds) = peer.recv_header().unwrap();
-        assert_eq!(hdr.get_code(), MasterReq::SET_OWNER);
-        assert!(rfds.is_none());
+        let (master, mut peer) = create_pair(&path);
 
-        assert!(master.get_protocol_features().is_err());
-        assert!(master
-            .set_protocol_features(VhostUserProtocolFeatures::all())
-            .is_err());
+        // The replies of a previous connection are never received.
+        let pending = master.node().pending_reply();
+        master.node().connection_id += 1;
+        match master.recv_pending(pending, |node| node.recv_body::<VhostUserU64>()) {
+            Err(VhostUserError::Disconnected) => {}
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        assert_eq!(*master.replies.serving.lock().unwrap(), 1);
 
-        let vfeatures = 0x15 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        // A panic while receiving a reply passes the turn to the next reply.
         let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
-        let msg = VhostUserU64::new(vfeatures);
-        peer.send_message(&hdr, &msg, None).unwrap();
-        let features = master.get_features().unwrap();
-        assert_eq!(features, vfeatures);
-        let (_hdr, rfds) = peer.recv_header().unwrap();
-        assert!(rfds.is_none());
+        peer.send_message(&hdr, &VhostUserU64::new(0x1), None)
+            .unwrap();
+        let pending = master.node().pending_reply();
+        let panicking = master.clone();
+        thread::spawn(move || {
+            panicking.recv_pending(pending, |_| -> VhostUserResult<()> { panic!("reply") })
+        })
+        .join()
+        .unwrap_err();
+        assert_eq!(*master.replies.serving.lock().unwrap(), 2);
+    }
 
-        master.set_features(vfeatures).unwrap();
-        let (_hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
-        assert!(rfds.is_none());
-        let val = msg.value;
-        assert_eq!(val, vfeatures);
+    struct TracedMessage {
+        sent: bool,
+        code: MasterReq,
+        flags: u32,
+        payload: Vec<u8>,
+        fds: usize,
+        latency: Option<Duration>,
+    }
 
-        let pfeatures = VhostUserProtocolFeatures::all();
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_PROTOCOL_FEATURES, 0x4, 8);
-        let msg = VhostUserU64::new(pfeatures.bits());
-        peer.send_message(&hdr, &msg, None).unwrap();
-        let features = master.get_protocol_features().unwrap();
-        assert_eq!(features, pfeatures);
-        let (_hdr, rfds) = peer.recv_header().unwrap();
-        assert!(rfds.is_none());
+    #[derive(Default)]
+    struct RecordingTracer {
+        msgs: Mutex<Vec<TracedMessage>>,
+    }
 
-        master.set_protocol_features(pfeatures).unwrap();
-        let (_hdr, msg, rfds) = peer.recv_body::<VhostUserU64>().unwrap();
-        assert!(rfds.is_none());
-        let val = msg.value;
-        assert_eq!(val, pfeatures.bits());
+    impl RecordingTracer {
+        fn record(&self, sent: bool, msg: &MessageTrace) {
+            self.msgs.lock().unwrap().push(TracedMessage {
+                sent,
+                code: msg.code,
+                flags: msg.flags,
+                payload: msg.payload.to_vec(),
+                fds: msg.fds,
+                latency: msg.latency,
+            });
+        }
+    }
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x4, 8);
-        let msg = VhostUserU64::new(pfeatures.bits());
-        peer.send_message(&hdr, &msg, None).unwrap();
-        assert!(master.get_protocol_features().is_err());
+    impl MasterTracer for RecordingTracer {
+        fn on_send(&self, msg: &MessageTrace) {
+            self.record(true, msg);
+        }
+
+        fn on_recv(&self, msg: &MessageTrace) {
+            self.record(false, msg);
+        }
     }
 
     #[test]
-    fn test_master_set_config_negative() {
+    fn test_master_tracer() {
         let path = temp_path();
-        let (mut master, _peer) = create_pair(&path);
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+        let (master, mut peer) = create_pair(&path);
+        let tracer = Arc::new(RecordingTracer::default());
+        master.set_tracer(Some(tracer.clone()));
 
-        master
-            .set_config(0x100, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .unwrap_err();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
+            .unwrap();
+        assert_eq!(master.get_features().unwrap(), 0x15);
+        let eventfd = EventFd::new(0).unwrap();
+        master.set_vring_kick(1, Some(&eventfd)).unwrap();
 
         {
-            let mut node = master.node();
-            node.virtio_features = 0xffff_ffff;
-            node.acked_virtio_features = 0xffff_ffff;
-            node.protocol_features = 0xffff_ffff;
-            node.acked_protocol_features = 0xffff_ffff;
+            let msgs = tracer.msgs.lock().unwrap();
+            assert_eq!(msgs.len(), 3);
+            assert!(msgs[0].sent);
+            assert_eq!(msgs[0].code, MasterReq::GET_FEATURES);
+            assert_eq!(msgs[0].flags, 0x1);
+            assert!(msgs[0].payload.is_empty());
+            assert_eq!(msgs[0].fds, 0);
+            assert!(msgs[0].latency.is_none());
+            assert!(!msgs[1].sent);
+            assert_eq!(msgs[1].code, MasterReq::GET_FEATURES);
+            assert_eq!(msgs[1].flags, 0x5);
+            assert_eq!(msgs[1].payload, 0x15u64.to_ne_bytes());
+            assert!(msgs[1].latency.is_some());
+            assert!(msgs[2].sent);
+            assert_eq!(msgs[2].code, MasterReq::SET_VRING_KICK);
+            assert_eq!(msgs[2].payload, 1u64.to_ne_bytes());
+            assert_eq!(msgs[2].fds, 1);
         }
 
-        master
-            .set_config(0, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .unwrap();
-        master
-            .set_config(
-                VHOST_USER_CONFIG_SIZE,
-                VhostUserConfigFlags::WRITABLE,
-                &buf[0..4],
-            )
-            .unwrap_err();
-        master
-            .set_config(0x1000, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .unwrap_err();
-        master
-            .set_config(
-                0x100,
-                unsafe { VhostUserConfigFlags::from_bits_unchecked(0xffff_ffff) },
-                &buf[0..4],
-            )
-            .unwrap_err();
-        master
-            .set_config(VHOST_USER_CONFIG_SIZE, VhostUserConfigFlags::WRITABLE, &buf)
-            .unwrap_err();
-        master
-            .set_config(VHOST_USER_CONFIG_SIZE, VhostUserConfigFlags::WRITABLE, &[])
-            .unwrap_err();
+        master.set_tracer(None);
+        master.set_vring_kick(1, Some(&eventfd)).unwrap();
+        assert_eq!(tracer.msgs.lock().unwrap().len(), 3);
     }
 
-    fn create_pair2() -> (Master, Endpoint<MasterReq>) {
+    #[test]
+    fn test_master_concurrent_requests() {
         let path = temp_path();
-        let (master, peer) = create_pair(&path);
+        let (master, mut peer) = create_pair(&path);
 
-        {
-            let mut node = master.node();
-            node.virtio_features = 0xffff_ffff;
-            node.acked_virtio_features = 0xffff_ffff;
-            node.protocol_features = 0xffff_ffff;
-            node.acked_protocol_features = 0xffff_ffff;
-        }
+        // The lock isn't held while waiting for a reply, so other requests may be sent.
+        let master2 = master.clone();
+        let features = thread::spawn(move || master2.get_features().unwrap());
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
 
-        (master, peer)
-    }
+        master.set_vring_num(0, 256).unwrap();
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_NUM);
 
-    #[test]
-    fn test_master_get_config_negative0() {
-        let (mut master, mut peer) = create_pair2();
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+        let master2 = master.clone();
+        let base = thread::spawn(move || master2.get_vring_base(1).unwrap());
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_VRING_BASE);
 
-        let mut hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
-        let msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+        // Replies are dispatched in the order the requests were sent.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_ok());
-
-        hdr.set_code(MasterReq::GET_FEATURES);
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_VRING_BASE, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserVringState::new(1, 0x20), None)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_err());
-        hdr.set_code(MasterReq::GET_CONFIG);
+        assert_eq!(features.join().unwrap(), 0x15);
+        assert_eq!(base.join().unwrap(), 0x20);
     }
 
     #[test]
-    fn test_master_get_config_negative1() {
-        let (mut master, mut peer) = create_pair2();
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+    fn test_master_create_slave_req_handler() {
+        struct ConfigChangeHandler {}
 
-        let mut hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
-        let msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
-            .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_ok());
+        impl VhostUserMasterReqHandlerMut for ConfigChangeHandler {
+            fn handle_config_change(&mut self) -> HandlerResult<u64> {
+                Ok(0)
+            }
+        }
 
-        hdr.set_reply(false);
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
-            .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_err());
+        let (mut master, mut peer) = create_pair2();
+        let backend = Arc::new(Mutex::new(ConfigChangeHandler {}));
+        let mut handler = master.create_slave_req_handler(backend).unwrap();
+
+        let (hdr, files) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_SLAVE_REQ_FD);
+        let file = take_single_file(files).unwrap();
+        let stream = unsafe { UnixStream::from_raw_fd(file.into_raw_fd()) };
+        let mut slave = Endpoint::<SlaveReq>::from_stream(stream);
+
+        let hdr = VhostUserMsgHeader::new(SlaveReq::CONFIG_CHANGE_MSG, 0x9, 0);
+        slave.send_header(&hdr, None).unwrap();
+        assert_eq!(handler.handle_request().unwrap(), 0);
+        let (reply, msg, _) = slave.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(reply.get_code(), SlaveReq::CONFIG_CHANGE_MSG);
+        assert_eq!({ msg.value }, 0);
     }
 
     #[test]
-    fn test_master_get_config_negative2() {
-        let (mut master, mut peer) = create_pair2();
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+    fn test_master_setup_queues() {
+        let (master, mut peer) = create_pair2();
+        let eventfd = EventFd::new(0).unwrap();
+        let queue = |queue_index| QueueSetup {
+            queue_index,
+            num: 256,
+            config_data: VringConfigData {
+                queue_max_size: 256,
+                queue_size: 256,
+                flags: 0,
+                desc_table_addr: 0x1000,
+                used_ring_addr: 0x2000,
+                avail_ring_addr: 0x3000,
+                log_addr: None,
+            },
+            base: 0,
+            kick: None,
+            call: Some(&eventfd),
+            err: None,
+            enable: true,
+        };
+        let codes = [
+            MasterReq::SET_VRING_NUM,
+            MasterReq::SET_VRING_ADDR,
+            MasterReq::SET_VRING_BASE,
+            MasterReq::SET_VRING_KICK,
+            MasterReq::SET_VRING_CALL,
+            MasterReq::SET_VRING_ENABLE,
+        ];
+
+        // The slave fails to set the base of the second vring.
+        master.set_auto_reply_ack(true);
+        for index in 0..2 {
+            for code in codes.iter() {
+                let hdr = VhostUserMsgHeader::new(*code, 0x4, 8);
+                let status = (index == 1 && *code == MasterReq::SET_VRING_BASE) as u64;
+                peer.send_message(&hdr, &VhostUserU64::new(status), None)
+                    .unwrap();
+            }
+        }
+        match master.setup_queues(&[queue(0), queue(1)]) {
+            Err(Error::VringSetup(1, MasterReq::SET_VRING_BASE, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        for index in 0..2 {
+            for code in codes.iter() {
+                let (hdr, rfds) = peer.recv_header().unwrap();
+                assert_eq!(hdr.get_code(), *code);
+                assert!(hdr.is_need_reply());
+                assert_eq!(rfds.is_some(), *code == MasterReq::SET_VRING_CALL);
+                let (_, buf) = peer.recv_data(hdr.get_size() as usize).unwrap();
+                assert_eq!(buf[0], index);
+            }
+        }
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
-        let msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
-            .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_ok());
+        // The requests following a local failure are not sent.
+        match master.setup_queues(&[queue(2), queue(0)]) {
+            Err(Error::VringSetup(2, MasterReq::SET_VRING_NUM, e)) => match *e {
+                Error::VhostUserProtocol(VhostUserError::QueueIndexOutOfRange(2, 2)) => {}
+                e => panic!("unexpected error {:?}", e),
+            },
+            res => panic!("unexpected result {:?}", res),
+        }
+        master.set_auto_reply_ack(false);
+        master.setup_queues(&[queue(1)]).unwrap();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_NUM);
     }
 
     #[test]
-    fn test_master_get_config_negative3() {
-        let (mut master, mut peer) = create_pair2();
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+    fn test_master_queue_num_cache() {
+        let path = temp_path();
+        let (mut master, mut peer) = create_pair(&path);
+        master.node().acked_protocol_features = VhostUserProtocolFeatures::MQ.bits();
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
-        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+        // The number of queues is queried by the first request on a vring.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_QUEUE_NUM, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(4), None)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_ok());
+        master.set_vring_num(3, 256).unwrap();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_QUEUE_NUM);
+        let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_NUM);
+        assert_eq!({ msg.index }, 3);
+
+        match master.set_vring_base(4, 0) {
+            Err(Error::VhostUserProtocol(VhostUserError::QueueIndexOutOfRange(4, 4))) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(master.get_queue_num().unwrap(), 4);
+        master.set_vring_kick(0, None).unwrap();
+        let (hdr, _, _) = peer.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_KICK);
 
-        msg.offset = 0;
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+        // The number of queues is queried again once the protocol features are renegotiated.
+        master.node().virtio_features = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::MQ)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_err());
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_QUEUE_NUM, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(8), None)
+            .unwrap();
+        assert_eq!(master.get_queue_num().unwrap(), 8);
+        master.set_vring_num(7, 256).unwrap();
     }
 
     #[test]
-    fn test_master_get_config_negative4() {
+    fn test_master_get_shmem_config() {
         let (mut master, mut peer) = create_pair2();
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
-        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
-            .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_ok());
+        let config = VhostUserShMemConfig::new(&[0, 0x10_0000, 0x20_0000]).unwrap();
+        let hdr = VhostUserMsgHeader::new(
+            MasterReq::GET_SHMEM_CONFIG,
+            0x4,
+            mem::size_of::<VhostUserShMemConfig>() as u32,
+        );
+        peer.send_message(&hdr, &config, None).unwrap();
+        assert_eq!(
+            master.get_shmem_config().unwrap(),
+            vec![(1, 0x10_0000), (2, 0x20_0000)]
+        );
+        let (req, _) = peer.recv_header().unwrap();
+        assert_eq!(req.get_code(), MasterReq::GET_SHMEM_CONFIG);
 
-        msg.offset = 0x101;
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
-            .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_err());
+        // The number of regions doesn't match their sizes.
+        let mut config = config;
+        config.nregions = 1;
+        peer.send_message(&hdr, &config, None).unwrap();
+        master.get_shmem_config().unwrap_err();
+
+        master.node().acked_protocol_features &= !VhostUserProtocolFeatures::SHMEM.bits();
+        master.get_shmem_config().unwrap_err();
     }
 
     #[test]
-    fn test_master_get_config_negative5() {
+    fn test_master_vring_base_packed() {
         let (mut master, mut peer) = create_pair2();
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
-        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+        let base = VringPackedBase::new(0x10, true, 0x0f, false);
+        master.set_vring_base_packed(1, base).unwrap();
+        let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_VRING_BASE);
+        assert_eq!({ msg.index }, 1);
+        assert_eq!({ msg.num }, 0x000f_8010);
+
+        master
+            .set_vring_base_packed(1, VringPackedBase::new(0x8000, false, 0, false))
+            .unwrap_err();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_VRING_BASE, 0x4, 8);
+        let msg = VhostUserVringState::new(1, 0x8003_0004);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        assert_eq!(
+            master.get_vring_base_packed(1).unwrap(),
+            VringPackedBase::new(4, false, 3, true)
+        );
+        let (hdr, _, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_VRING_BASE);
+    }
+
+    #[test]
+    fn test_master_split_mem_table() {
+        let (master, mut peer) = create_pair2();
+        let eventfd = EventFd::new(0).unwrap();
+        let count = MAX_ATTACHED_FD_ENTRIES as u64 + 4;
+        let region = |index: u64| VhostUserMemoryRegionInfo {
+            guest_phys_addr: index * 0x20_0000,
+            memory_size: 0x10_0000,
+            userspace_addr: 0x7f00_0000_0000 + index * 0x10_0000,
+            mmap_offset: index * 0x10_0000,
+            mmap_handle: Some(borrow_fd(&eventfd)),
+            ..Default::default()
+        };
+
+        // Adjacent regions are coalesced.
+        let mut regions: Vec<_> = (0..count)
+            .map(|index| VhostUserMemoryRegionInfo {
+                guest_phys_addr: index * 0x10_0000,
+                ..region(index)
+            })
+            .collect();
+        master.set_mem_table(&regions).unwrap_err();
+        master.set_split_mem_table(true);
+        regions.reverse();
+        master.set_mem_table(&regions).unwrap();
+        let mut buf = vec![0u8; mem::size_of::<VhostUserMemoryRegion>()];
+        let (hdr, body, _, rfds) = peer
+            .recv_payload_into_buf::<VhostUserMemory>(&mut buf)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_ok());
+        assert_eq!(hdr.get_code(), MasterReq::SET_MEM_TABLE);
+        assert_eq!({ body.num_regions }, 1);
+        assert_eq!(rfds.unwrap().len(), 1);
+        let merged = *VhostUserMemoryRegion::from_slice(&buf).unwrap();
+        assert_eq!({ merged.guest_phys_addr }, 0);
+        assert_eq!({ merged.memory_size }, count * 0x10_0000);
+
+        // The regions beyond the limit of SET_MEM_TABLE are added one by one.
+        let regions: Vec<_> = (0..count).map(region).collect();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_MAX_MEM_SLOTS, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(64), None)
+            .unwrap();
+        master.set_mem_table(&regions).unwrap();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_MAX_MEM_SLOTS);
+        let mut buf = vec![0u8; MAX_ATTACHED_FD_ENTRIES * mem::size_of::<VhostUserMemoryRegion>()];
+        let (hdr, body, _, rfds) = peer
+            .recv_payload_into_buf::<VhostUserMemory>(&mut buf)
+            .unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::SET_MEM_TABLE);
+        assert_eq!({ body.num_regions }, MAX_ATTACHED_FD_ENTRIES as u32);
+        assert_eq!(rfds.unwrap().len(), MAX_ATTACHED_FD_ENTRIES);
+        for index in MAX_ATTACHED_FD_ENTRIES as u64..count {
+            let (hdr, msg, rfds) = peer.recv_body::<VhostUserSingleMemoryRegion>().unwrap();
+            assert_eq!(hdr.get_code(), MasterReq::ADD_MEM_REG);
+            assert_eq!(rfds.unwrap().len(), 1);
+            assert_eq!({ msg.guest_phys_addr }, index * 0x20_0000);
+        }
 
-        msg.offset = (MAX_MSG_SIZE + 1) as u32;
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+        // The slave doesn't have enough memory slots.
+        peer.send_message(&hdr, &VhostUserU64::new(8), None)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_err());
+        master.set_mem_table(&regions).unwrap_err();
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_MAX_MEM_SLOTS);
     }
 
     #[test]
-    fn test_master_get_config_negative6() {
-        let (mut master, mut peer) = create_pair2();
-        let buf = vec![0x0; MAX_MSG_SIZE + 1];
+    fn test_master_snapshot_restore() {
+        let path = temp_path();
+        let (master, mut peer) = create_pair(&path);
+        let eventfd = EventFd::new(0).unwrap();
 
-        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x4, 16);
-        let mut msg = VhostUserConfig::new(0x100, 4, VhostUserConfigFlags::empty());
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..4], None)
+        master.snapshot().unwrap_err();
+        master.record_state();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_ok());
+        assert_eq!(master.get_features().unwrap(), 0x15);
+        master.set_features(0x5).unwrap();
+        {
+            let mut node = master.node();
+            node.acked_protocol_features = VhostUserProtocolFeatures::XEN_MMAP.bits();
+            node.protocol_features_ready = true;
+        }
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0x10_0000,
+            memory_size: 0x10_0000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_handle: Some(borrow_fd(&eventfd)),
+            ..Default::default()
+        };
+        master.set_mem_table(&[region]).unwrap();
+        master.set_vring_num(0, 256).unwrap();
+        master.set_vring_base(0, 8).unwrap();
+        master.set_vring_call(0, Some(&eventfd)).unwrap();
+        master.set_vring_kick(0, None).unwrap();
+        peer.drain().unwrap();
+
+        let snapshot = master.snapshot().unwrap();
+        assert_eq!(snapshot.sock_fd, master.as_raw_fd());
+        assert_eq!(snapshot.path.as_deref(), Some(path.as_path()));
+        assert_eq!(snapshot.features, 0x15);
+        assert_eq!(snapshot.acked_features, 0x5);
+        assert_eq!(
+            snapshot.acked_protocol_features,
+            Some(VhostUserProtocolFeatures::XEN_MMAP.bits())
+        );
+        assert_eq!(snapshot.max_queue_num, 2);
+        assert_eq!(snapshot.mem_regions.len(), 1);
+        assert_eq!(snapshot.mem_regions[0].guest_phys_addr, 0x10_0000);
+        assert_ne!(snapshot.mem_regions[0].mmap_handle, eventfd.as_raw_fd());
+        assert_eq!(snapshot.vrings.len(), 1);
+        let vring = &snapshot.vrings[0];
+        assert_eq!(vring.queue_index, 0);
+        assert_eq!(vring.num, Some(256));
+        assert_eq!(vring.base, Some(8));
+        assert!(vring.call.unwrap() >= 0);
+        assert_eq!(vring.kick, Some(-1));
+        assert_eq!(vring.err, None);
+
+        // The new owner of the connection gets its own copy of the socket.
+        let mut inherited = snapshot.clone();
+        inherited.sock_fd = unsafe { libc::dup(snapshot.sock_fd) };
+        let restored = unsafe { Master::restore(&inherited) }.unwrap();
+        drop(master);
+
+        let copy = restored.snapshot().unwrap();
+        assert_eq!(copy.sock_fd, inherited.sock_fd);
+        assert_eq!(copy.acked_features, 0x5);
+        assert_eq!(
+            copy.acked_protocol_features,
+            snapshot.acked_protocol_features
+        );
+        assert_eq!(copy.mem_regions.len(), 1);
+        assert_eq!(copy.mem_regions[0].memory_size, 0x10_0000);
+        assert_eq!(copy.vrings[0].num, Some(256));
+        assert_eq!(copy.vrings[0].kick, Some(-1));
+        assert!(copy.vrings[0].call.unwrap() >= 0);
 
-        msg.size = 6;
-        peer.send_message_with_payload(&hdr, &msg, &buf[0..6], None)
+        peer.send_message(&hdr, &VhostUserU64::new(0x15), None)
             .unwrap();
-        assert!(master
-            .get_config(0x100, 4, VhostUserConfigFlags::WRITABLE, &buf[0..4])
-            .is_err());
+        assert_eq!(restored.get_features().unwrap(), 0x15);
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
     }
 
     #[test]
-    fn test_maset_set_mem_table_failure() {
-        let (master, _peer) = create_pair2();
-
-        master.set_mem_table(&[]).unwrap_err();
-        let tables = vec![VhostUserMemoryRegionInfo::default(); MAX_ATTACHED_FD_ENTRIES + 1];
-        master.set_mem_table(&tables).unwrap_err();
+    fn test_master_reconnect_from_stream() {
+        let (sock, _peer) = UnixStream::pair().unwrap();
+        let mut master = Master::from_stream(sock, 1);
+        master.enable_reconnect().unwrap_err();
+        master.reconnect().unwrap_err();
     }
 }
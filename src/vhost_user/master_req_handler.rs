@@ -1,11 +1,16 @@
 // Copyright (C) 2019-2021 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
+use std::io::Error as IOError;
 use std::mem;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::net::Shutdown;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
 use super::connection::Endpoint;
 use super::message::*;
@@ -34,7 +39,7 @@ pub trait VhostUserMasterReqHandler {
     }
 
     /// Handle virtio-fs map file requests.
-    fn fs_slave_map(&self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsRawFd) -> HandlerResult<u64> {
+    fn fs_slave_map(&self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsFd) -> HandlerResult<u64> {
         Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
@@ -49,12 +54,48 @@ pub trait VhostUserMasterReqHandler {
     }
 
     /// Handle virtio-fs file IO requests.
-    fn fs_slave_io(&self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsRawFd) -> HandlerResult<u64> {
+    fn fs_slave_io(&self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsFd) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to add a virtio shared object.
+    fn shared_object_add(&self, _uuid: &VhostUserShared) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to remove a virtio shared object.
+    fn shared_object_remove(&self, _uuid: &VhostUserShared) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to lookup a virtio shared object, returning the object's fd.
+    fn shared_object_lookup(&self, _uuid: &VhostUserShared) -> HandlerResult<File> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to map a file into a VIRTIO shared memory region.
+    fn shmem_map(&self, _req: &VhostUserMMap, _fd: &dyn AsFd) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to unmap a file from a VIRTIO shared memory region.
+    fn shmem_unmap(&self, _req: &VhostUserMMap) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to set the host notifier of a vring, `None` removing it.
+    ///
+    /// The notifier maps the doorbell area of the vring, for the guest to notify the slave
+    /// directly by writing to it. The area is unmapped when the notifier is dropped.
+    fn set_vring_host_notifier(
+        &self,
+        _queue_index: usize,
+        _notifier: Option<VringHostNotifier>,
+    ) -> HandlerResult<u64> {
         Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
     // fn handle_iotlb_msg(&mut self, iotlb: VhostUserIotlb);
-    // fn handle_vring_host_notifier(&mut self, area: VhostUserVringArea, fd: &dyn AsRawFd);
 }
 
 /// A helper trait mirroring [VhostUserMasterReqHandler] but without interior mutability.
@@ -67,7 +108,7 @@ pub trait VhostUserMasterReqHandlerMut {
     }
 
     /// Handle virtio-fs map file requests.
-    fn fs_slave_map(&mut self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsRawFd) -> HandlerResult<u64> {
+    fn fs_slave_map(&mut self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsFd) -> HandlerResult<u64> {
         Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
@@ -82,12 +123,45 @@ pub trait VhostUserMasterReqHandlerMut {
     }
 
     /// Handle virtio-fs file IO requests.
-    fn fs_slave_io(&mut self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsRawFd) -> HandlerResult<u64> {
+    fn fs_slave_io(&mut self, _fs: &VhostUserFSSlaveMsg, _fd: &dyn AsFd) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to add a virtio shared object.
+    fn shared_object_add(&mut self, _uuid: &VhostUserShared) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to remove a virtio shared object.
+    fn shared_object_remove(&mut self, _uuid: &VhostUserShared) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to lookup a virtio shared object, returning the object's fd.
+    fn shared_object_lookup(&mut self, _uuid: &VhostUserShared) -> HandlerResult<File> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to map a file into a VIRTIO shared memory region.
+    fn shmem_map(&mut self, _req: &VhostUserMMap, _fd: &dyn AsFd) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to unmap a file from a VIRTIO shared memory region.
+    fn shmem_unmap(&mut self, _req: &VhostUserMMap) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests to set the host notifier of a vring, `None` removing it.
+    fn set_vring_host_notifier(
+        &mut self,
+        _queue_index: usize,
+        _notifier: Option<VringHostNotifier>,
+    ) -> HandlerResult<u64> {
         Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
     // fn handle_iotlb_msg(&mut self, iotlb: VhostUserIotlb);
-    // fn handle_vring_host_notifier(&mut self, area: VhostUserVringArea, fd: RawFd);
 }
 
 impl<S: VhostUserMasterReqHandlerMut> VhostUserMasterReqHandler for Mutex<S> {
@@ -95,7 +169,7 @@ impl<S: VhostUserMasterReqHandlerMut> VhostUserMasterReqHandler for Mutex<S> {
         self.lock().unwrap().handle_config_change()
     }
 
-    fn fs_slave_map(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsRawFd) -> HandlerResult<u64> {
+    fn fs_slave_map(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
         self.lock().unwrap().fs_slave_map(fs, fd)
     }
 
@@ -107,9 +181,263 @@ impl<S: VhostUserMasterReqHandlerMut> VhostUserMasterReqHandler for Mutex<S> {
         self.lock().unwrap().fs_slave_sync(fs)
     }
 
-    fn fs_slave_io(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsRawFd) -> HandlerResult<u64> {
+    fn fs_slave_io(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
         self.lock().unwrap().fs_slave_io(fs, fd)
     }
+
+    fn shared_object_add(&self, uuid: &VhostUserShared) -> HandlerResult<u64> {
+        self.lock().unwrap().shared_object_add(uuid)
+    }
+
+    fn shared_object_remove(&self, uuid: &VhostUserShared) -> HandlerResult<u64> {
+        self.lock().unwrap().shared_object_remove(uuid)
+    }
+
+    fn shared_object_lookup(&self, uuid: &VhostUserShared) -> HandlerResult<File> {
+        self.lock().unwrap().shared_object_lookup(uuid)
+    }
+
+    fn shmem_map(&self, req: &VhostUserMMap, fd: &dyn AsFd) -> HandlerResult<u64> {
+        self.lock().unwrap().shmem_map(req, fd)
+    }
+
+    fn shmem_unmap(&self, req: &VhostUserMMap) -> HandlerResult<u64> {
+        self.lock().unwrap().shmem_unmap(req)
+    }
+
+    fn set_vring_host_notifier(
+        &self,
+        queue_index: usize,
+        notifier: Option<VringHostNotifier>,
+    ) -> HandlerResult<u64> {
+        self.lock()
+            .unwrap()
+            .set_vring_host_notifier(queue_index, notifier)
+    }
+}
+
+/// Doorbell area of a vring mapped from a VRING_HOST_NOTIFIER_MSG request of the slave.
+///
+/// The area is mapped shared and writable, so it may be exposed to the guest as the notification
+/// area of the vring. It's unmapped when the notifier is dropped.
+pub struct VringHostNotifier {
+    addr: *mut u8,
+    size: usize,
+}
+
+// Safe because the notifier owns the mapping, which is only handed out as a raw pointer.
+unsafe impl Send for VringHostNotifier {}
+unsafe impl Sync for VringHostNotifier {}
+
+impl VringHostNotifier {
+    /// Map the area of `file` described by `area`.
+    pub fn new(area: &VhostUserVringArea, file: &dyn AsFd) -> HandlerResult<Self> {
+        let size = area.size as usize;
+        // Safe because the mapping is checked and owned by the new notifier.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_fd().as_raw_fd(),
+                area.offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(VringHostNotifier {
+            addr: addr as *mut u8,
+            size,
+        })
+    }
+
+    /// Get the address of the mapped area.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Get the size of the mapped area.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for VringHostNotifier {
+    fn drop(&mut self) {
+        // Safe because the mapping was created in `new()` and is not used anymore.
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+/// Handler of a device specific slave request, with a code unknown to the crate.
+///
+/// The handler is called with the payload and the files attached to the request, and its result
+/// is acknowledged to the slave like the results of the other requests.
+pub type CustomSlaveReqHandler = Box<dyn Fn(&[u8], Option<Vec<File>>) -> HandlerResult<u64> + Send>;
+
+/// Request of the slave on the slave communication channel, checked by a [SlaveReqPolicy].
+///
+/// [SlaveReqPolicy]: type.SlaveReqPolicy.html
+pub enum SlaveRequest<'a> {
+    /// Device configuration change notification.
+    ConfigChange,
+    /// virtio-fs map file request.
+    FsMap(&'a VhostUserFSSlaveMsg),
+    /// virtio-fs unmap file request.
+    FsUnmap(&'a VhostUserFSSlaveMsg),
+    /// virtio-fs sync file request.
+    FsSync(&'a VhostUserFSSlaveMsg),
+    /// virtio-fs file IO request.
+    FsIo(&'a VhostUserFSSlaveMsg),
+    /// Request to add a virtio shared object.
+    SharedObjectAdd(&'a VhostUserShared),
+    /// Request to remove a virtio shared object.
+    SharedObjectRemove(&'a VhostUserShared),
+    /// Request to lookup a virtio shared object.
+    SharedObjectLookup(&'a VhostUserShared),
+    /// Request to map a file into a VIRTIO shared memory region.
+    ShmemMap(&'a VhostUserMMap),
+    /// Request to unmap a file from a VIRTIO shared memory region.
+    ShmemUnmap(&'a VhostUserMMap),
+    /// Request to set or remove the host notifier of a vring.
+    VringHostNotifier(&'a VhostUserVringArea),
+    /// Device specific request.
+    Custom {
+        /// Code of the request.
+        code: u32,
+        /// Payload of the request.
+        payload: &'a [u8],
+    },
+}
+
+/// Policy authorizing the requests of the slave, before they are served.
+///
+/// Slaves are less trusted than the VMM, so the policy may deny requests based on their
+/// parameters, such as FS_MAP requests beyond the DAX window. Denied requests fail with the
+/// returned error, which is reported to the slave as for failures of the handler.
+pub type SlaveReqPolicy = Box<dyn Fn(&SlaveRequest) -> HandlerResult<()> + Send>;
+
+/// Statistics of the requests of the slave with a given code, kept by [MasterReqHandler].
+///
+/// [MasterReqHandler]: struct.MasterReqHandler.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlaveReqStats {
+    /// Number of requests served.
+    pub count: u64,
+    /// Number of requests which failed.
+    pub failures: u64,
+    /// Total time spent serving the requests.
+    pub total_latency: Duration,
+    /// Longest time spent serving a request.
+    pub max_latency: Duration,
+}
+
+impl SlaveReqStats {
+    fn record(&mut self, latency: Duration, failed: bool) {
+        self.count += 1;
+        if failed {
+            self.failures += 1;
+        }
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+}
+
+/// Summary of a request of the slave, reported to a [SlaveReqTracer].
+///
+/// [SlaveReqTracer]: trait.SlaveReqTracer.html
+#[derive(Clone, Copy, Debug)]
+pub struct SlaveReqTrace<'a> {
+    /// Request code of the message.
+    pub code: u32,
+    /// Flags of the message header.
+    pub flags: u32,
+    /// Message body and payload.
+    pub payload: &'a [u8],
+    /// Number of file descriptors attached to the message.
+    pub fds: usize,
+    /// Time spent serving the request, only set once it has been served.
+    pub latency: Option<Duration>,
+}
+
+/// Hooks invoked for each request of the slave, to monitor the slave or to open tracing spans
+/// around the handlers.
+pub trait SlaveReqTracer: Send + Sync {
+    /// Called before a request of the slave is served.
+    fn on_request(&self, _req: &SlaveReqTrace) {}
+
+    /// Called after a request of the slave has been served, with its result.
+    fn on_served(&self, _req: &SlaveReqTrace, _res: &Result<u64>) {}
+}
+
+#[derive(Default)]
+struct ChannelState {
+    // The channel has been shut down.
+    closing: bool,
+    // Thread serving a request of the slave.
+    busy: Option<ThreadId>,
+}
+
+struct ChannelControl {
+    state: Mutex<ChannelState>,
+    idle: Condvar,
+    // Slave communication channel, to shut it down.
+    sock: UnixStream,
+}
+
+/// Handle shutting down the slave communication channel of a [MasterReqHandler] from any thread,
+/// for instance when the device is unplugged.
+///
+/// [MasterReqHandler]: struct.MasterReqHandler.html
+#[derive(Clone)]
+pub struct SlaveChannelShutdown {
+    control: Arc<ChannelControl>,
+}
+
+impl SlaveChannelShutdown {
+    /// Shut down the slave communication channel, and wait for the request being served to
+    /// complete.
+    ///
+    /// The handler stops serving requests, and the channel is closed so the slave fails to send
+    /// new requests. Fail with `Timeout` if the request being served doesn't complete within
+    /// `timeout`, the channel is shut down nevertheless. Called from a handler of the requests,
+    /// it doesn't wait for the request being served.
+    pub fn shutdown(&self, timeout: Option<Duration>) -> Result<()> {
+        let mut state = self.control.state.lock().unwrap();
+        if !state.closing {
+            state.closing = true;
+            // The channel may have been closed by the slave already.
+            let _ = self.control.sock.shutdown(Shutdown::Both);
+        }
+
+        let me = thread::current().id();
+        let busy = |state: &mut ChannelState| matches!(state.busy, Some(id) if id != me);
+        match timeout {
+            Some(timeout) => {
+                let (_state, res) = self
+                    .control
+                    .idle
+                    .wait_timeout_while(state, timeout, busy)
+                    .unwrap();
+                if res.timed_out() {
+                    return Err(Error::Timeout);
+                }
+            }
+            None => {
+                let _state = self.control.idle.wait_while(state, busy).unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the slave communication channel has been shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.control.state.lock().unwrap().closing
+    }
 }
 
 /// Server to handle service requests from slaves from the slave communication channel.
@@ -128,6 +456,21 @@ pub struct MasterReqHandler<S: VhostUserMasterReqHandler> {
     reply_ack_negotiated: bool,
     // the VirtIO backend device object
     backend: Arc<S>,
+    // handlers of the device specific requests, by request code
+    custom_handlers: HashMap<u32, CustomSlaveReqHandler>,
+    // policy authorizing the requests before they are served
+    policy: Option<SlaveReqPolicy>,
+    // statistics of the requests served, by request code
+    stats: BTreeMap<u32, SlaveReqStats>,
+    // hooks invoked for each request
+    tracer: Option<Arc<dyn SlaveReqTracer>>,
+    // acks of the requests completed asynchronously by the handler
+    deferred_acks: Vec<DeferredAck>,
+    // partial request received by handle_events(), and its attached files
+    rx_buf: Vec<u8>,
+    rx_files: Option<Vec<File>>,
+    // state of the channel, shared with the shutdown handles
+    control: Arc<ChannelControl>,
     // whether the endpoint has encountered any failure
     error: Option<i32>,
 }
@@ -143,12 +486,25 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
     /// [VhostUserMaster::set_slave_request_fd()]: trait.VhostUserMaster.html#tymethod.set_slave_request_fd
     pub fn new(backend: Arc<S>) -> Result<Self> {
         let (tx, rx) = UnixStream::pair().map_err(Error::SocketError)?;
+        let control = Arc::new(ChannelControl {
+            state: Mutex::new(ChannelState::default()),
+            idle: Condvar::new(),
+            sock: rx.try_clone().map_err(Error::SocketError)?,
+        });
 
         Ok(MasterReqHandler {
             sub_sock: Endpoint::<SlaveReq>::from_stream(rx),
             tx_sock: tx,
             reply_ack_negotiated: false,
             backend,
+            custom_handlers: HashMap::new(),
+            policy: None,
+            stats: BTreeMap::new(),
+            tracer: None,
+            deferred_acks: Vec::new(),
+            rx_buf: Vec::new(),
+            rx_files: None,
+            control,
             error: None,
         })
     }
@@ -162,6 +518,15 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
         self.tx_sock.as_raw_fd()
     }
 
+    /// Borrow the socket fd for the slave to communication with the master.
+    ///
+    /// The returned fd should be sent to the slave by [VhostUserMaster::set_slave_request_fd()].
+    ///
+    /// [VhostUserMaster::set_slave_request_fd()]: trait.VhostUserMaster.html#tymethod.set_slave_request_fd
+    pub fn get_tx_fd(&self) -> BorrowedFd {
+        self.tx_sock.as_fd()
+    }
+
     /// Set the negotiation state of the `VHOST_USER_PROTOCOL_F_REPLY_ACK` protocol feature.
     ///
     /// When the `VHOST_USER_PROTOCOL_F_REPLY_ACK` protocol feature has been negotiated,
@@ -171,6 +536,69 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
         self.reply_ack_negotiated = enable;
     }
 
+    /// Register `handler` to serve the device specific requests with the code `code`.
+    ///
+    /// `code` must be beyond the requests defined by [SlaveReq], a handler already registered for
+    /// `code` is replaced. Requests with an unregistered code are rejected.
+    ///
+    /// [SlaveReq]: message/enum.SlaveReq.html
+    pub fn register_custom_request(
+        &mut self,
+        code: u32,
+        handler: CustomSlaveReqHandler,
+    ) -> Result<()> {
+        if !SlaveReq::is_custom_code(code) {
+            return Err(Error::InvalidParam);
+        }
+        self.custom_handlers.insert(code, handler);
+        Ok(())
+    }
+
+    /// Unregister the handler of the device specific requests with the code `code`, returning it.
+    pub fn unregister_custom_request(&mut self, code: u32) -> Option<CustomSlaveReqHandler> {
+        self.custom_handlers.remove(&code)
+    }
+
+    /// Set the policy authorizing every request of the slave before it's served, `None` letting
+    /// all requests through.
+    pub fn set_request_policy(&mut self, policy: Option<SlaveReqPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Set the hooks invoked for each request of the slave, or `None` to stop tracing.
+    pub fn set_tracer(&mut self, tracer: Option<Arc<dyn SlaveReqTracer>>) {
+        self.tracer = tracer;
+    }
+
+    /// Get the statistics of the requests served, by request code.
+    ///
+    /// The time spent serving a request covers its handler and its ack. Requests completed
+    /// asynchronously are accounted for once their ack is deferred.
+    pub fn stats(&self) -> &BTreeMap<u32, SlaveReqStats> {
+        &self.stats
+    }
+
+    /// Clear the statistics of the requests served.
+    pub fn reset_stats(&mut self) {
+        self.stats.clear();
+    }
+
+    /// Get a handle shutting down the slave communication channel from any thread.
+    pub fn shutdown_handle(&self) -> SlaveChannelShutdown {
+        SlaveChannelShutdown {
+            control: self.control.clone(),
+        }
+    }
+
+    /// Shut down the slave communication channel, see [SlaveChannelShutdown::shutdown()].
+    ///
+    /// Requests received afterwards fail with `Disconnected`.
+    ///
+    /// [SlaveChannelShutdown::shutdown()]: struct.SlaveChannelShutdown.html#method.shutdown
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.shutdown_handle().shutdown(None)
+    }
+
     /// Mark endpoint as failed or in normal state.
     pub fn set_failed(&mut self, error: i32) {
         if error == 0 {
@@ -200,9 +628,8 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
         //   message header
         // . validate message body and optional payload
         let (hdr, files) = self.sub_sock.recv_header()?;
-        self.check_attached_files(&hdr, &files)?;
-        let (size, buf) = match hdr.get_size() {
-            0 => (0, vec![0u8; 0]),
+        let buf = match hdr.get_size() {
+            0 => vec![0u8; 0],
             len => {
                 if len as usize > MAX_MSG_SIZE {
                     return Err(Error::InvalidMessage);
@@ -211,52 +638,323 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
                 if size2 != len as usize {
                     return Err(Error::InvalidMessage);
                 }
-                (size2, rbuf)
+                rbuf
             }
         };
 
-        let res = match hdr.get_code() {
+        self.serve_request(hdr, files, buf)
+    }
+
+    /// Serve the requests received from the slave communication channel, without blocking.
+    ///
+    /// This is the entrance for event loops polling the fd returned by `as_raw_fd()`, after the
+    /// channel has been switched to non-blocking mode by [Self::set_nonblocking()]. It receives
+    /// whatever is available on the channel, keeping partial requests until they are complete,
+    /// and serves every complete request. It returns the number of requests served once the
+    /// channel is drained, so the caller may poll the fd again.
+    ///
+    /// A failure to serve a request is returned right away, the requests received after it are
+    /// served by the next call. The caller must not mix calls to this function and to
+    /// [Self::handle_request()].
+    ///
+    /// [Self::set_nonblocking()]: struct.MasterReqHandler.html#method.set_nonblocking
+    /// [Self::handle_request()]: struct.MasterReqHandler.html#method.handle_request
+    pub fn handle_events(&mut self) -> Result<usize> {
+        self.check_state()?;
+
+        let hdr_size = mem::size_of::<VhostUserMsgHeader<SlaveReq>>();
+        let mut served = 0;
+        loop {
+            // Receive up to the end of the pending request only, so the files attached to the
+            // next request aren't received along with it.
+            let want = match self.pending_request_size()? {
+                Some(size) if self.rx_buf.len() == size => {
+                    let mut buf = mem::take(&mut self.rx_buf);
+                    let files = self.rx_files.take();
+                    let body = buf.split_off(hdr_size);
+                    // Safe because the buffer holds a whole header.
+                    let hdr = unsafe {
+                        std::ptr::read_unaligned(buf.as_ptr() as *const VhostUserMsgHeader<SlaveReq>)
+                    };
+                    self.serve_request(hdr, files, body)?;
+                    served += 1;
+                    continue;
+                }
+                Some(size) => size - self.rx_buf.len(),
+                None => hdr_size - self.rx_buf.len(),
+            };
+            match self.sub_sock.recv_into_buf(want) {
+                Ok((0, _, _)) => return Err(Error::Disconnected),
+                Ok((bytes, buf, files)) => {
+                    self.rx_buf.extend_from_slice(&buf[..bytes]);
+                    if let Some(files) = files {
+                        self.rx_files.get_or_insert_with(Vec::new).extend(files);
+                    }
+                }
+                Err(Error::SocketRetry(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(served)
+                }
+                Err(Error::SocketRetry(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Get the size of the request being received by handle_events(), once its header has been
+    // received.
+    fn pending_request_size(&mut self) -> Result<Option<usize>> {
+        let hdr_size = mem::size_of::<VhostUserMsgHeader<SlaveReq>>();
+        if self.rx_buf.len() < hdr_size {
+            return Ok(None);
+        }
+        // Safe because the buffer holds a whole header.
+        let hdr = unsafe {
+            std::ptr::read_unaligned(self.rx_buf.as_ptr() as *const VhostUserMsgHeader<SlaveReq>)
+        };
+        if !hdr.is_valid() {
+            // The channel is out of sync, drop what has been received.
+            self.rx_buf.clear();
+            self.rx_files = None;
+            return Err(Error::InvalidMessage);
+        }
+        Ok(Some(hdr_size + hdr.get_size() as usize))
+    }
+
+    /// Switch the slave communication channel to non-blocking mode, for [Self::handle_events()].
+    ///
+    /// [Self::handle_events()]: struct.MasterReqHandler.html#method.handle_events
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> Result<()> {
+        self.sub_sock.set_nonblocking(nonblocking)
+    }
+
+    /// Take the acks of the requests completed asynchronously by the handler.
+    ///
+    /// The handler completes a request asynchronously by failing it with `EINPROGRESS`. The request
+    /// is then reported as served with a null result, and its ack is deferred to
+    /// [DeferredAck::complete()].
+    ///
+    /// [DeferredAck::complete()]: struct.DeferredAck.html#method.complete
+    pub fn take_deferred_acks(&mut self) -> Vec<DeferredAck> {
+        mem::take(&mut self.deferred_acks)
+    }
+
+    // Serve a request, keeping statistics and tracing it.
+    fn serve_request(
+        &mut self,
+        hdr: VhostUserMsgHeader<SlaveReq>,
+        files: Option<Vec<File>>,
+        buf: Vec<u8>,
+    ) -> Result<u64> {
+        let mut trace = SlaveReqTrace {
+            code: hdr.get_raw_code(),
+            flags: hdr.get_flags(),
+            payload: &buf,
+            fds: files.as_ref().map_or(0, |files| files.len()),
+            latency: None,
+        };
+        let tracer = self.tracer.clone();
+        if let Some(tracer) = tracer.as_ref() {
+            tracer.on_request(&trace);
+        }
+
+        {
+            let mut state = self.control.state.lock().unwrap();
+            if state.closing {
+                return Err(Error::Disconnected);
+            }
+            state.busy = Some(thread::current().id());
+        }
+        let start = Instant::now();
+        let res = self.reply_request(hdr, files, &buf);
+        let latency = start.elapsed();
+        self.control.state.lock().unwrap().busy = None;
+        self.control.idle.notify_all();
+        self.stats
+            .entry(trace.code)
+            .or_default()
+            .record(latency, res.is_err());
+        if let Some(tracer) = tracer.as_ref() {
+            trace.latency = Some(latency);
+            tracer.on_served(&trace, &res);
+        }
+
+        res
+    }
+
+    // Serve a request and acknowledge it, malformed requests included, so the slave never waits
+    // for an ack which isn't coming.
+    fn reply_request(
+        &mut self,
+        hdr: VhostUserMsgHeader<SlaveReq>,
+        files: Option<Vec<File>>,
+        buf: &[u8],
+    ) -> Result<u64> {
+        if !hdr.is_custom() && hdr.get_code() == SlaveReq::SHARED_OBJECT_LOOKUP {
+            return self.lookup_shared_object(&hdr, files, buf);
+        }
+
+        let res = self.dispatch_request(&hdr, files, buf);
+        if let Err(Error::ReqHandlerError(e)) = &res {
+            if e.raw_os_error() == Some(libc::EINPROGRESS) {
+                let sock = match self.reply_ack_negotiated && hdr.is_need_reply() {
+                    true => Some(self.sub_sock.try_clone()?),
+                    false => None,
+                };
+                self.deferred_acks.push(DeferredAck { sock, hdr });
+                return Ok(0);
+            }
+        }
+        self.send_ack_message(&hdr, &res)?;
+
+        res
+    }
+
+    // The lookup is always replied to, carrying the fd of the object on success.
+    fn lookup_shared_object(
+        &mut self,
+        hdr: &VhostUserMsgHeader<SlaveReq>,
+        files: Option<Vec<File>>,
+        buf: &[u8],
+    ) -> Result<u64> {
+        let msg = match self
+            .check_attached_files(hdr, &files)
+            .and_then(|_| self.extract_msg_body::<VhostUserShared>(hdr, buf.len(), buf))
+        {
+            Ok(msg) => msg,
+            Err(e) => {
+                let _ = self.send_lookup_reply(hdr, Err(IOError::from_raw_os_error(libc::EINVAL)));
+                return Err(e);
+            }
+        };
+        let res = self
+            .authorize(&SlaveRequest::SharedObjectLookup(&msg))
+            .and_then(|_| self.backend.shared_object_lookup(&msg));
+        self.send_lookup_reply(hdr, res)
+    }
+
+    fn dispatch_request(
+        &mut self,
+        hdr: &VhostUserMsgHeader<SlaveReq>,
+        files: Option<Vec<File>>,
+        buf: &[u8],
+    ) -> Result<u64> {
+        let size = buf.len();
+        if hdr.is_custom() {
+            return self.custom_request(hdr, buf, files);
+        }
+        self.check_attached_files(hdr, &files)?;
+
+        match hdr.get_code() {
             SlaveReq::CONFIG_CHANGE_MSG => {
-                self.check_msg_size(&hdr, size, 0)?;
-                self.backend
-                    .handle_config_change()
+                self.check_msg_size(hdr, size, 0)?;
+                self.authorize(&SlaveRequest::ConfigChange)
+                    .and_then(|_| self.backend.handle_config_change())
                     .map_err(Error::ReqHandlerError)
             }
             SlaveReq::FS_MAP => {
-                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(&hdr, size, &buf)?;
+                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(hdr, size, buf)?;
                 // check_attached_files() has validated files
-                self.backend
-                    .fs_slave_map(&msg, &files.unwrap()[0])
+                self.authorize(&SlaveRequest::FsMap(&msg))
+                    .and_then(|_| self.backend.fs_slave_map(&msg, &files.unwrap()[0]))
                     .map_err(Error::ReqHandlerError)
             }
             SlaveReq::FS_UNMAP => {
-                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(&hdr, size, &buf)?;
-                self.backend
-                    .fs_slave_unmap(&msg)
+                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(hdr, size, buf)?;
+                self.authorize(&SlaveRequest::FsUnmap(&msg))
+                    .and_then(|_| self.backend.fs_slave_unmap(&msg))
                     .map_err(Error::ReqHandlerError)
             }
             SlaveReq::FS_SYNC => {
-                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(&hdr, size, &buf)?;
-                self.backend
-                    .fs_slave_sync(&msg)
+                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(hdr, size, buf)?;
+                self.authorize(&SlaveRequest::FsSync(&msg))
+                    .and_then(|_| self.backend.fs_slave_sync(&msg))
                     .map_err(Error::ReqHandlerError)
             }
             SlaveReq::FS_IO => {
-                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(&hdr, size, &buf)?;
+                let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(hdr, size, buf)?;
+                // check_attached_files() has validated files
+                self.authorize(&SlaveRequest::FsIo(&msg))
+                    .and_then(|_| self.backend.fs_slave_io(&msg, &files.unwrap()[0]))
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::SHARED_OBJECT_ADD => {
+                let msg = self.extract_msg_body::<VhostUserShared>(hdr, size, buf)?;
+                self.authorize(&SlaveRequest::SharedObjectAdd(&msg))
+                    .and_then(|_| self.backend.shared_object_add(&msg))
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::SHARED_OBJECT_REMOVE => {
+                let msg = self.extract_msg_body::<VhostUserShared>(hdr, size, buf)?;
+                self.authorize(&SlaveRequest::SharedObjectRemove(&msg))
+                    .and_then(|_| self.backend.shared_object_remove(&msg))
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::SHMEM_MAP => {
+                let msg = self.extract_msg_body::<VhostUserMMap>(hdr, size, buf)?;
                 // check_attached_files() has validated files
-                self.backend
-                    .fs_slave_io(&msg, &files.unwrap()[0])
+                self.authorize(&SlaveRequest::ShmemMap(&msg))
+                    .and_then(|_| self.backend.shmem_map(&msg, &files.unwrap()[0]))
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::SHMEM_UNMAP => {
+                let msg = self.extract_msg_body::<VhostUserMMap>(hdr, size, buf)?;
+                self.authorize(&SlaveRequest::ShmemUnmap(&msg))
+                    .and_then(|_| self.backend.shmem_unmap(&msg))
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::VRING_HOST_NOTIFIER_MSG => {
+                let msg = self.extract_msg_body::<VhostUserVringArea>(hdr, size, buf)?;
+                // The file must be attached unless the no-fd flag is set.
+                let file = match files {
+                    Some(mut files) if msg.has_fd() => files.pop(),
+                    None if !msg.has_fd() => None,
+                    _ => return Err(Error::InvalidMessage),
+                };
+                self.authorize(&SlaveRequest::VringHostNotifier(&msg))
+                    .and_then(|_| {
+                        file.map(|file| VringHostNotifier::new(&msg, &file))
+                            .transpose()
+                    })
+                    .and_then(|notifier| {
+                        self.backend
+                            .set_vring_host_notifier(msg.queue_index(), notifier)
+                    })
                     .map_err(Error::ReqHandlerError)
             }
             _ => Err(Error::InvalidMessage),
-        };
+        }
+    }
 
-        self.send_ack_message(&hdr, &res)?;
+    fn custom_request(
+        &self,
+        hdr: &VhostUserMsgHeader<SlaveReq>,
+        buf: &[u8],
+        files: Option<Vec<File>>,
+    ) -> Result<u64> {
+        if hdr.is_reply() {
+            return Err(Error::InvalidMessage);
+        }
+        let code = hdr.get_raw_code();
+        match self.custom_handlers.get(&code) {
+            Some(handler) => self
+                .authorize(&SlaveRequest::Custom { code, payload: buf })
+                .and_then(|_| handler(buf, files))
+                .map_err(Error::ReqHandlerError),
+            None => Err(Error::InvalidMessage),
+        }
+    }
 
-        res
+    fn authorize(&self, req: &SlaveRequest) -> HandlerResult<()> {
+        match &self.policy {
+            Some(policy) => policy(req),
+            None => Ok(()),
+        }
     }
 
     fn check_state(&self) -> Result<()> {
+        if self.control.state.lock().unwrap().closing {
+            return Err(Error::Disconnected);
+        }
         match self.error {
             Some(e) => Err(Error::SocketBroken(std::io::Error::from_raw_os_error(e))),
             None => Ok(()),
@@ -285,13 +983,20 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
         files: &Option<Vec<File>>,
     ) -> Result<()> {
         match hdr.get_code() {
-            SlaveReq::FS_MAP | SlaveReq::FS_IO => {
+            SlaveReq::FS_MAP | SlaveReq::FS_IO | SlaveReq::SHMEM_MAP => {
                 // Expect a single file is passed.
                 match files {
                     Some(files) if files.len() == 1 => Ok(()),
                     _ => Err(Error::InvalidMessage),
                 }
             }
+            SlaveReq::VRING_HOST_NOTIFIER_MSG => {
+                // Expect at most a single file, the message body tells whether it's attached.
+                match files {
+                    Some(files) if files.len() != 1 => Err(Error::InvalidMessage),
+                    _ => Ok(()),
+                }
+            }
             _ if files.is_some() => Err(Error::InvalidMessage),
             _ => Ok(()),
         }
@@ -319,8 +1024,8 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
             return Err(Error::InvalidParam);
         }
         self.check_state()?;
-        Ok(VhostUserMsgHeader::new(
-            req.get_code(),
+        Ok(VhostUserMsgHeader::new_raw(
+            req.get_raw_code(),
             VhostUserHeaderFlag::REPLY.bits(),
             mem::size_of::<T>() as u32,
         ))
@@ -333,22 +1038,86 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
     ) -> Result<()> {
         if self.reply_ack_negotiated && req.is_need_reply() {
             let hdr = self.new_reply_header::<VhostUserU64>(req)?;
-            let def_err = libc::EINVAL;
-            let val = match res {
-                Ok(n) => *n,
-                Err(e) => match &*e {
-                    Error::ReqHandlerError(ioerr) => match ioerr.raw_os_error() {
-                        Some(rawerr) => -rawerr as u64,
-                        None => -def_err as u64,
-                    },
-                    _ => -def_err as u64,
-                },
-            };
-            let msg = VhostUserU64::new(val);
+            let msg = VhostUserU64::new(ack_value(res));
             self.sub_sock.send_message(&hdr, &msg, None)?;
         }
         Ok(())
     }
+
+    fn send_lookup_reply(
+        &mut self,
+        req: &VhostUserMsgHeader<SlaveReq>,
+        res: HandlerResult<File>,
+    ) -> Result<u64> {
+        let hdr = self.new_reply_header::<VhostUserU64>(req)?;
+        match res {
+            Ok(file) => {
+                let msg = VhostUserU64::new(0);
+                self.sub_sock
+                    .send_message(&hdr, &msg, Some(&[file.as_raw_fd()]))?;
+                Ok(0)
+            }
+            Err(e) => {
+                let msg = VhostUserU64::new(-e.raw_os_error().unwrap_or(libc::EINVAL) as u64);
+                self.sub_sock.send_message(&hdr, &msg, None)?;
+                Err(Error::ReqHandlerError(e))
+            }
+        }
+    }
+}
+
+// Get the value of the ack of a request, the negated errno on failures.
+fn ack_value(res: &Result<u64>) -> u64 {
+    let def_err = libc::EINVAL;
+    match res {
+        Ok(n) => *n,
+        Err(Error::ReqHandlerError(ioerr)) => match ioerr.raw_os_error() {
+            Some(rawerr) => -rawerr as u64,
+            None => -def_err as u64,
+        },
+        Err(_) => -def_err as u64,
+    }
+}
+
+/// Deferred ack of a request of the slave, completed asynchronously by the handler.
+///
+/// Dropping the ack without completing it reports a failure to the slave.
+pub struct DeferredAck {
+    // Socket to send the ack on, or `None` if the slave doesn't expect an ack.
+    sock: Option<Endpoint<SlaveReq>>,
+    hdr: VhostUserMsgHeader<SlaveReq>,
+}
+
+impl DeferredAck {
+    /// Get the code of the request.
+    pub fn code(&self) -> u32 {
+        self.hdr.get_raw_code()
+    }
+
+    /// Send the ack of the request, with the result of its completion.
+    pub fn complete(mut self, res: HandlerResult<u64>) -> Result<()> {
+        self.send(res.map_err(Error::ReqHandlerError))
+    }
+
+    fn send(&mut self, res: Result<u64>) -> Result<()> {
+        if let Some(mut sock) = self.sock.take() {
+            let hdr = VhostUserMsgHeader::new_raw(
+                self.hdr.get_raw_code(),
+                VhostUserHeaderFlag::REPLY.bits(),
+                mem::size_of::<VhostUserU64>() as u32,
+            );
+            sock.send_message(&hdr, &VhostUserU64::new(ack_value(&res)), None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DeferredAck {
+    fn drop(&mut self) {
+        let _ = self.send(Err(Error::ReqHandlerError(IOError::from_raw_os_error(
+            libc::EIO,
+        ))));
+    }
 }
 
 impl<S: VhostUserMasterReqHandler> AsRawFd for MasterReqHandler<S> {
@@ -363,8 +1132,11 @@ mod tests {
 
     #[cfg(feature = "vhost-user-slave")]
     use crate::vhost_user::SlaveFsCacheReq;
+    use std::os::unix::fs::FileExt;
     #[cfg(feature = "vhost-user-slave")]
     use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vm_memory::ByteValued;
 
     struct MockMasterReqHandler {}
 
@@ -373,7 +1145,7 @@ mod tests {
         fn fs_slave_map(
             &mut self,
             _fs: &VhostUserFSSlaveMsg,
-            _fd: &dyn AsRawFd,
+            _fd: &dyn AsFd,
         ) -> HandlerResult<u64> {
             Ok(0)
         }
@@ -382,6 +1154,27 @@ mod tests {
         fn fs_slave_unmap(&mut self, _fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
             Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
         }
+
+        /// Handle requests to add a virtio shared object from the slave.
+        fn shared_object_add(&mut self, _uuid: &VhostUserShared) -> HandlerResult<u64> {
+            Ok(0)
+        }
+
+        /// Handle requests to lookup a virtio shared object from the slave.
+        fn shared_object_lookup(&mut self, uuid: &VhostUserShared) -> HandlerResult<File> {
+            if uuid.uuid == [0u8; 16] {
+                return Err(std::io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            File::open("/dev/null")
+        }
+
+        /// Handle requests to map a file into a VIRTIO shared memory region from the slave.
+        fn shmem_map(&mut self, req: &VhostUserMMap, _fd: &dyn AsFd) -> HandlerResult<u64> {
+            if req.shmid != 0 {
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            Ok(0)
+        }
     }
 
     #[test]
@@ -399,6 +1192,291 @@ mod tests {
         handler.check_state().unwrap_err();
     }
 
+    #[test]
+    fn test_master_req_handler_policy() {
+        let backend = Arc::new(Mutex::new(MockMasterReqHandler {}));
+        let mut handler = MasterReqHandler::new(backend).unwrap();
+        handler.set_request_policy(Some(Box::new(|req| match req {
+            SlaveRequest::FsMap(fs) if fs.cache_offset[0] + fs.len[0] > 0x1000 => {
+                Err(std::io::Error::from_raw_os_error(libc::EPERM))
+            }
+            SlaveRequest::ConfigChange => Err(std::io::Error::from_raw_os_error(libc::EPERM)),
+            _ => Ok(()),
+        })));
+        let mut slave = Endpoint::<SlaveReq>::from_stream(handler.tx_sock.try_clone().unwrap());
+
+        let file = File::open("/dev/null").unwrap();
+        let hdr = VhostUserMsgHeader::new(
+            SlaveReq::FS_MAP,
+            0x1,
+            mem::size_of::<VhostUserFSSlaveMsg>() as u32,
+        );
+        let mut fs = VhostUserFSSlaveMsg::default();
+        fs.len[0] = 0x1000;
+        slave
+            .send_message(&hdr, &fs, Some(&[file.as_raw_fd()]))
+            .unwrap();
+        assert_eq!(handler.handle_request().unwrap(), 0);
+
+        // Requests denied by the policy aren't served.
+        fs.cache_offset[0] = 0x1000;
+        slave
+            .send_message(&hdr, &fs, Some(&[file.as_raw_fd()]))
+            .unwrap();
+        match handler.handle_request().unwrap_err() {
+            Error::ReqHandlerError(e) => assert_eq!(e.raw_os_error(), Some(libc::EPERM)),
+            e => panic!("unexpected error {:?}", e),
+        }
+        let hdr = VhostUserMsgHeader::new(SlaveReq::CONFIG_CHANGE_MSG, 0x1, 0);
+        slave.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap_err();
+
+        handler.set_request_policy(None);
+        slave.send_header(&hdr, None).unwrap();
+        // The mock handler doesn't handle configuration changes.
+        match handler.handle_request().unwrap_err() {
+            Error::ReqHandlerError(e) => assert_eq!(e.raw_os_error(), Some(libc::ENOSYS)),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    struct MockDeferredHandler {}
+
+    impl VhostUserMasterReqHandler for MockDeferredHandler {
+        fn handle_config_change(&self) -> HandlerResult<u64> {
+            Err(std::io::Error::from_raw_os_error(libc::EINPROGRESS))
+        }
+    }
+
+    #[test]
+    fn test_master_req_handler_deferred_ack() {
+        let mut handler = MasterReqHandler::new(Arc::new(MockDeferredHandler {})).unwrap();
+        handler.set_reply_ack_flag(true);
+        let mut slave = Endpoint::<SlaveReq>::from_stream(handler.tx_sock.try_clone().unwrap());
+        let mut hdr = VhostUserMsgHeader::new(SlaveReq::CONFIG_CHANGE_MSG, 0x1, 0);
+        hdr.set_need_reply(true);
+
+        slave.send_header(&hdr, None).unwrap();
+        assert_eq!(handler.handle_request().unwrap(), 0);
+        let mut acks = handler.take_deferred_acks();
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].code(), SlaveReq::CONFIG_CHANGE_MSG as u32);
+        acks.pop().unwrap().complete(Ok(0)).unwrap();
+        let (reply, body, _) = slave.recv_body::<VhostUserU64>().unwrap();
+        assert!(reply.is_reply_for(&hdr));
+        assert_eq!({ body.value }, 0);
+
+        // Dropped acks report a failure.
+        slave.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        drop(handler.take_deferred_acks());
+        let (_, body, _) = slave.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!({ body.value }, -libc::EIO as u64);
+
+        // Malformed requests are acknowledged too.
+        let mut hdr = VhostUserMsgHeader::new(SlaveReq::CONFIG_CHANGE_MSG, 0x1, 8);
+        hdr.set_need_reply(true);
+        slave
+            .send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        handler.handle_request().unwrap_err();
+        let (_, body, _) = slave.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!({ body.value }, -libc::EINVAL as u64);
+        assert!(handler.take_deferred_acks().is_empty());
+    }
+
+    #[derive(Default)]
+    struct CountingTracer {
+        requests: AtomicUsize,
+        failures: AtomicUsize,
+    }
+
+    impl SlaveReqTracer for CountingTracer {
+        fn on_request(&self, req: &SlaveReqTrace) {
+            assert!(req.latency.is_none());
+            self.requests.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_served(&self, req: &SlaveReqTrace, res: &Result<u64>) {
+            assert!(req.latency.is_some());
+            if res.is_err() {
+                self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    fn test_master_req_handler_stats() {
+        let backend = Arc::new(Mutex::new(MockMasterReqHandler {}));
+        let mut handler = MasterReqHandler::new(backend).unwrap();
+        let tracer = Arc::new(CountingTracer::default());
+        handler.set_tracer(Some(tracer.clone()));
+        let mut slave = Endpoint::<SlaveReq>::from_stream(handler.tx_sock.try_clone().unwrap());
+
+        let hdr = VhostUserMsgHeader::new(
+            SlaveReq::FS_UNMAP,
+            0x1,
+            mem::size_of::<VhostUserFSSlaveMsg>() as u32,
+        );
+        let fs = VhostUserFSSlaveMsg::default();
+        for _ in 0..2 {
+            slave.send_message(&hdr, &fs, None).unwrap();
+            handler.handle_request().unwrap_err();
+        }
+        let hdr = VhostUserMsgHeader::new(
+            SlaveReq::SHMEM_UNMAP,
+            0x1,
+            mem::size_of::<VhostUserMMap>() as u32,
+        );
+        slave
+            .send_message(&hdr, &VhostUserMMap::default(), None)
+            .unwrap();
+        handler.handle_request().unwrap_err();
+
+        let stats = handler.stats();
+        assert_eq!(stats.len(), 2);
+        let unmap = stats[&(SlaveReq::FS_UNMAP as u32)];
+        assert_eq!(unmap.count, 2);
+        assert_eq!(unmap.failures, 2);
+        assert!(unmap.max_latency <= unmap.total_latency);
+        assert_eq!(stats[&(SlaveReq::SHMEM_UNMAP as u32)].count, 1);
+        assert_eq!(tracer.requests.load(Ordering::SeqCst), 3);
+        assert_eq!(tracer.failures.load(Ordering::SeqCst), 3);
+
+        handler.reset_stats();
+        assert!(handler.stats().is_empty());
+    }
+
+    struct SlowConfigHandler {}
+
+    impl VhostUserMasterReqHandler for SlowConfigHandler {
+        fn handle_config_change(&self) -> HandlerResult<u64> {
+            thread::sleep(Duration::from_millis(200));
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_master_req_handler_shutdown() {
+        let mut handler = MasterReqHandler::new(Arc::new(SlowConfigHandler {})).unwrap();
+        let closer = handler.shutdown_handle();
+        let mut slave = Endpoint::<SlaveReq>::from_stream(handler.tx_sock.try_clone().unwrap());
+        let hdr = VhostUserMsgHeader::new(SlaveReq::CONFIG_CHANGE_MSG, 0x1, 0);
+        slave.send_header(&hdr, None).unwrap();
+
+        let handle = thread::spawn(move || {
+            assert_eq!(handler.handle_request().unwrap(), 0);
+            handler.handle_request().unwrap_err();
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!closer.is_shutdown());
+
+        // The request being served completes before the channel is shut down.
+        match closer.shutdown(Some(Duration::from_millis(1))) {
+            Err(Error::Timeout) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(closer.is_shutdown());
+        closer.shutdown(Some(Duration::from_secs(5))).unwrap();
+        handle.join().unwrap();
+
+        slave.send_header(&hdr, None).unwrap_err();
+    }
+
+    #[test]
+    fn test_master_req_handler_handle_events() {
+        let backend = Arc::new(Mutex::new(MockMasterReqHandler {}));
+        let mut handler = MasterReqHandler::new(backend).unwrap();
+        handler.set_nonblocking(true).unwrap();
+        let mut slave = Endpoint::<SlaveReq>::from_stream(handler.tx_sock.try_clone().unwrap());
+        assert_eq!(handler.handle_events().unwrap(), 0);
+
+        // A burst of requests is served at once, each with its own file.
+        let file = File::open("/dev/null").unwrap();
+        let fds = [file.as_raw_fd()];
+        let req = VhostUserMMap::new(0, 0, 0x1000, 0x1000, VhostUserMMapFlags::MAP_RW);
+        let hdr = VhostUserMsgHeader::new(
+            SlaveReq::SHMEM_MAP,
+            0x1,
+            mem::size_of::<VhostUserMMap>() as u32,
+        );
+        slave.send_message(&hdr, &req, Some(&fds)).unwrap();
+        slave.send_message(&hdr, &req, Some(&fds)).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 2);
+
+        // Partial requests are kept until they are complete.
+        slave.send_header(&hdr, Some(&fds)).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 0);
+        slave.send_slice(&req.as_slice()[..8], None).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 0);
+        slave.send_slice(&req.as_slice()[8..], None).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 1);
+
+        // The requests following a failed one are served by the next call.
+        slave.send_message(&hdr, &req, None).unwrap();
+        slave.send_message(&hdr, &req, Some(&fds)).unwrap();
+        handler.handle_events().unwrap_err();
+        assert_eq!(handler.handle_events().unwrap(), 1);
+        assert_eq!(handler.handle_events().unwrap(), 0);
+    }
+
+    #[derive(Default)]
+    struct MockHostNotifierHandler {
+        notifier: Option<(usize, VringHostNotifier)>,
+    }
+
+    impl VhostUserMasterReqHandlerMut for MockHostNotifierHandler {
+        fn set_vring_host_notifier(
+            &mut self,
+            queue_index: usize,
+            notifier: Option<VringHostNotifier>,
+        ) -> HandlerResult<u64> {
+            self.notifier = notifier.map(|notifier| (queue_index, notifier));
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_master_req_handler_vring_host_notifier() {
+        let backend = Arc::new(Mutex::new(MockHostNotifierHandler::default()));
+        let mut handler = MasterReqHandler::new(backend.clone()).unwrap();
+        let mut slave = Endpoint::<SlaveReq>::from_stream(handler.tx_sock.try_clone().unwrap());
+        let hdr = VhostUserMsgHeader::new(
+            SlaveReq::VRING_HOST_NOTIFIER_MSG,
+            0x1,
+            mem::size_of::<VhostUserVringArea>() as u32,
+        );
+
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x2000).unwrap();
+        let area = VhostUserVringArea::new(1, true, 0x1000, 0x1000);
+        slave
+            .send_message(&hdr, &area, Some(&[file.as_raw_fd()]))
+            .unwrap();
+        assert_eq!(handler.handle_request().unwrap(), 0);
+        {
+            let backend = backend.lock().unwrap();
+            let (queue_index, notifier) = backend.notifier.as_ref().unwrap();
+            assert_eq!(*queue_index, 1);
+            assert_eq!(notifier.size(), 0x1000);
+            // The doorbell area is shared with the file.
+            unsafe { *notifier.as_ptr() = 0x5a };
+        }
+        let mut buf = [0u8; 1];
+        file.read_exact_at(&mut buf, 0x1000).unwrap();
+        assert_eq!(buf[0], 0x5a);
+
+        let area = VhostUserVringArea::new(1, false, 0, 0);
+        slave.send_message(&hdr, &area, None).unwrap();
+        assert_eq!(handler.handle_request().unwrap(), 0);
+        assert!(backend.lock().unwrap().notifier.is_none());
+
+        // The file must be attached unless the no-fd flag is set.
+        let area = VhostUserVringArea::new(1, true, 0x1000, 0);
+        slave.send_message(&hdr, &area, None).unwrap();
+        handler.handle_request().unwrap_err();
+    }
+
     #[cfg(feature = "vhost-user-slave")]
     #[test]
     fn test_master_slave_req_handler() {
@@ -410,6 +1488,7 @@ mod tests {
             panic!("failed to duplicated tx fd!");
         }
         let stream = unsafe { UnixStream::from_raw_fd(fd) };
+        let map_fd = stream.try_clone().unwrap();
         let fs_cache = SlaveFsCacheReq::from_stream(stream);
 
         std::thread::spawn(move || {
@@ -419,7 +1498,7 @@ mod tests {
         });
 
         fs_cache
-            .fs_slave_map(&VhostUserFSSlaveMsg::default(), &fd)
+            .fs_slave_map(&VhostUserFSSlaveMsg::default(), &map_fd)
             .unwrap();
         // When REPLY_ACK has not been negotiated, the master has no way to detect failure from
         // slave side.
@@ -440,6 +1519,7 @@ mod tests {
             panic!("failed to duplicated tx fd!");
         }
         let stream = unsafe { UnixStream::from_raw_fd(fd) };
+        let map_fd = stream.try_clone().unwrap();
         let fs_cache = SlaveFsCacheReq::from_stream(stream);
 
         std::thread::spawn(move || {
@@ -450,10 +1530,119 @@ mod tests {
 
         fs_cache.set_reply_ack_flag(true);
         fs_cache
-            .fs_slave_map(&VhostUserFSSlaveMsg::default(), &fd)
+            .fs_slave_map(&VhostUserFSSlaveMsg::default(), &map_fd)
             .unwrap();
         fs_cache
             .fs_slave_unmap(&VhostUserFSSlaveMsg::default())
             .unwrap_err();
     }
+
+    #[cfg(feature = "vhost-user-slave")]
+    #[test]
+    fn test_master_slave_req_handler_shared_object() {
+        let backend = Arc::new(Mutex::new(MockMasterReqHandler {}));
+        let mut handler = MasterReqHandler::new(backend).unwrap();
+        handler.set_reply_ack_flag(true);
+
+        let fd = unsafe { libc::dup(handler.get_tx_raw_fd()) };
+        if fd < 0 {
+            panic!("failed to duplicated tx fd!");
+        }
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+        let fs_cache = SlaveFsCacheReq::from_stream(stream);
+
+        std::thread::spawn(move || {
+            assert_eq!(handler.handle_request().unwrap(), 0);
+            handler.handle_request().unwrap_err();
+            assert_eq!(handler.handle_request().unwrap(), 0);
+            handler.handle_request().unwrap_err();
+        });
+
+        let uuid = VhostUserShared::new([0x1; 16]);
+        fs_cache.set_reply_ack_flag(true);
+        fs_cache.shared_object_add(&uuid).unwrap();
+        fs_cache.shared_object_remove(&uuid).unwrap_err();
+        fs_cache.shared_object_lookup(&uuid).unwrap();
+        fs_cache
+            .shared_object_lookup(&VhostUserShared::default())
+            .unwrap_err();
+    }
+
+    #[cfg(feature = "vhost-user-slave")]
+    #[test]
+    fn test_master_slave_req_handler_custom_request() {
+        const CUSTOM_REQ: u32 = SlaveReq::MAX_CMD as u32 + 1;
+
+        let backend = Arc::new(Mutex::new(MockMasterReqHandler {}));
+        let mut handler = MasterReqHandler::new(backend).unwrap();
+        handler.set_reply_ack_flag(true);
+        handler
+            .register_custom_request(SlaveReq::FS_MAP as u32, Box::new(|_, _| Ok(0)))
+            .unwrap_err();
+        handler
+            .register_custom_request(
+                CUSTOM_REQ,
+                Box::new(|payload, files| match (payload, files) {
+                    ([1, 2, 3], Some(files)) if files.len() == 1 => Ok(0),
+                    _ => Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+                }),
+            )
+            .unwrap();
+
+        let stream = handler.tx_sock.try_clone().unwrap();
+        let fd = stream.try_clone().unwrap();
+        let fs_cache = SlaveFsCacheReq::from_stream(stream);
+
+        let handle = std::thread::spawn(move || {
+            assert_eq!(handler.handle_request().unwrap(), 0);
+            handler.handle_request().unwrap_err();
+            assert!(handler.unregister_custom_request(CUSTOM_REQ).is_some());
+            handler.handle_request().unwrap_err();
+        });
+
+        fs_cache.set_reply_ack_flag(true);
+        fs_cache
+            .send_custom_request(CUSTOM_REQ, &[1, 2, 3], Some(&[fd.as_fd()]))
+            .unwrap();
+        fs_cache
+            .send_custom_request(CUSTOM_REQ, &[], None)
+            .unwrap_err();
+        fs_cache
+            .send_custom_request(SlaveReq::FS_UNMAP as u32, &[], None)
+            .unwrap_err();
+        // Requests without a registered handler are rejected.
+        fs_cache
+            .send_custom_request(CUSTOM_REQ, &[], None)
+            .unwrap_err();
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "vhost-user-slave")]
+    #[test]
+    fn test_master_slave_req_handler_shmem() {
+        let backend = Arc::new(Mutex::new(MockMasterReqHandler {}));
+        let mut handler = MasterReqHandler::new(backend).unwrap();
+        handler.set_reply_ack_flag(true);
+
+        let fd = unsafe { libc::dup(handler.get_tx_raw_fd()) };
+        if fd < 0 {
+            panic!("failed to duplicated tx fd!");
+        }
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+        let fs_cache = SlaveFsCacheReq::from_stream(stream);
+
+        std::thread::spawn(move || {
+            assert_eq!(handler.handle_request().unwrap(), 0);
+            handler.handle_request().unwrap_err();
+            handler.handle_request().unwrap_err();
+        });
+
+        let file = File::open("/dev/null").unwrap();
+        let req = VhostUserMMap::new(0, 0, 0x1000, 0x1000, VhostUserMMapFlags::MAP_RW);
+        fs_cache.set_reply_ack_flag(true);
+        fs_cache.shmem_map(&req, &file).unwrap();
+        let req = VhostUserMMap::new(1, 0, 0x1000, 0x1000, VhostUserMMapFlags::empty());
+        fs_cache.shmem_map(&req, &file).unwrap_err();
+        fs_cache.shmem_unmap(&req).unwrap_err();
+    }
 }
@@ -11,10 +11,11 @@
 
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::mem;
 
 use vm_memory::ByteValued;
 
-use crate::VringConfigData;
+use crate::{VhostUserMemoryRegionInfo, VringConfigData};
 
 /// The vhost-user specification uses a field of u32 to store message length.
 /// On the other hand, preallocated buffers are needed to receive messages from the Unix domain
@@ -39,13 +40,27 @@ pub const VHOST_USER_CONFIG_OFFSET: u32 = 0x100;
 /// Ending position (exclusion) of the device configuration space in virtio devices.
 pub const VHOST_USER_CONFIG_SIZE: u32 = 0x1000;
 
+/// Maximum size of the device configuration space accessed by a single request.
+pub const VHOST_USER_MAX_CONFIG_SIZE: u32 = VHOST_USER_CONFIG_SIZE - VHOST_USER_CONFIG_OFFSET;
+
 /// Maximum number of vrings supported.
 pub const VHOST_USER_MAX_VRINGS: u64 = 0x8000u64;
 
+/// Size of the guest memory area tracked by each bit of the dirty log.
+pub const VHOST_LOG_PAGE: u64 = 0x1000;
+
 pub(super) trait Req:
     Clone + Copy + Debug + PartialEq + Eq + PartialOrd + Ord + Into<u32>
 {
     fn is_valid(&self) -> bool;
+
+    // Whether `code` is the code of a valid request, checked before converting raw codes
+    // received from the peer, as not all the codes below `is_custom_code()` are defined.
+    fn is_valid_code(code: u32) -> bool;
+
+    // Whether `code` is beyond the requests known to the crate, and may be used by device
+    // specific extensions of the protocol.
+    fn is_custom_code(code: u32) -> bool;
 }
 
 /// Type of requests sending from masters to slaves.
@@ -139,8 +154,17 @@ pub enum MasterReq {
     /// Query the backend for its device status as defined in the VIRTIO
     /// specification.
     GET_STATUS = 40,
+    /// Retrieve a shared object from the device identified by its UUID.
+    GET_SHARED_OBJECT = 41,
+    /// Begin transfer of internal state from/to the backend through a pipe.
+    SET_DEVICE_STATE_FD = 42,
+    /// End transfer of internal state from/to the backend and check whether it
+    /// succeeded.
+    CHECK_DEVICE_STATE = 43,
+    /// Get the VIRTIO shared memory regions of the device.
+    GET_SHMEM_CONFIG = 44,
     /// Upper bound of valid commands.
-    MAX_CMD = 41,
+    MAX_CMD = 45,
 }
 
 impl From<MasterReq> for u32 {
@@ -153,6 +177,14 @@ impl Req for MasterReq {
     fn is_valid(&self) -> bool {
         (*self > MasterReq::NOOP) && (*self < MasterReq::MAX_CMD)
     }
+
+    fn is_valid_code(code: u32) -> bool {
+        code > MasterReq::NOOP as u32 && code < MasterReq::MAX_CMD as u32
+    }
+
+    fn is_custom_code(code: u32) -> bool {
+        code >= MasterReq::MAX_CMD as u32
+    }
 }
 
 /// Type of requests sending from slaves to masters.
@@ -171,16 +203,26 @@ pub enum SlaveReq {
     VRING_CALL = 4,
     /// Indicate that an error occurred on the specific vring.
     VRING_ERR = 5,
+    /// Add a virtio shared object identified by its UUID.
+    SHARED_OBJECT_ADD = 6,
+    /// Remove a virtio shared object identified by its UUID.
+    SHARED_OBJECT_REMOVE = 7,
+    /// Lookup a virtio shared object by its UUID.
+    SHARED_OBJECT_LOOKUP = 8,
+    /// Map a file into a VIRTIO shared memory region.
+    SHMEM_MAP = 9,
+    /// Unmap a file from a VIRTIO shared memory region.
+    SHMEM_UNMAP = 10,
     /// Virtio-fs draft: map file content into the window.
-    FS_MAP = 6,
+    FS_MAP = 1000,
     /// Virtio-fs draft: unmap file content from the window.
-    FS_UNMAP = 7,
+    FS_UNMAP = 1001,
     /// Virtio-fs draft: sync file content.
-    FS_SYNC = 8,
+    FS_SYNC = 1002,
     /// Virtio-fs draft: perform a read/write from an fd directly to GPA.
-    FS_IO = 9,
+    FS_IO = 1003,
     /// Upper bound of valid commands.
-    MAX_CMD = 10,
+    MAX_CMD = 1004,
 }
 
 impl From<SlaveReq> for u32 {
@@ -191,7 +233,18 @@ impl From<SlaveReq> for u32 {
 
 impl Req for SlaveReq {
     fn is_valid(&self) -> bool {
-        (*self > SlaveReq::NOOP) && (*self < SlaveReq::MAX_CMD)
+        Self::is_valid_code(u32::from(*self))
+    }
+
+    fn is_valid_code(code: u32) -> bool {
+        // The non-standard virtio-fs requests live in a separate range, above the
+        // requests defined by the vhost-user specification.
+        (code > SlaveReq::NOOP as u32 && code <= SlaveReq::SHMEM_UNMAP as u32)
+            || (code >= SlaveReq::FS_MAP as u32 && code < SlaveReq::MAX_CMD as u32)
+    }
+
+    fn is_custom_code(code: u32) -> bool {
+        code >= SlaveReq::MAX_CMD as u32
     }
 }
 
@@ -269,9 +322,37 @@ impl<R: Req> VhostUserMsgHeader<R> {
         }
     }
 
+    /// Create a new instance of `VhostUserMsgHeader` from a raw request code.
+    ///
+    /// Used for device specific requests, whose codes aren't known to the crate.
+    pub fn new_raw(request: u32, flags: u32, size: u32) -> Self {
+        let fl = (flags & VhostUserHeaderFlag::ALL_FLAGS.bits()) | 0x1;
+        VhostUserMsgHeader {
+            request,
+            flags: fl,
+            size,
+            _r: PhantomData,
+        }
+    }
+
+    /// Get the raw request code of the message.
+    pub fn get_raw_code(&self) -> u32 {
+        self.request
+    }
+
+    /// Check whether the message carries a device specific request unknown to the crate.
+    ///
+    /// `get_code()` must not be called on such messages.
+    pub fn is_custom(&self) -> bool {
+        R::is_custom_code(self.request)
+    }
+
     /// Get message type.
+    ///
+    /// Messages received from the peer must be checked with `is_valid()` first.
     pub fn get_code(&self) -> R {
-        // It's safe because R is marked as repr(u32).
+        // It's safe because R is marked as repr(u32), and the code of a valid message is a
+        // variant of R.
         unsafe { std::mem::transmute_copy::<u32, R>(&{ self.request }) }
     }
 
@@ -280,6 +361,11 @@ impl<R: Req> VhostUserMsgHeader<R> {
         self.request = request.into();
     }
 
+    /// Get message flags, including the version number.
+    pub fn get_flags(&self) -> u32 {
+        self.flags
+    }
+
     /// Get message version number.
     pub fn get_version(&self) -> u32 {
         self.flags & 0x3
@@ -321,7 +407,7 @@ impl<R: Req> VhostUserMsgHeader<R> {
 
     /// Check whether it's the reply message for the request `req`.
     pub fn is_reply_for(&self, req: &VhostUserMsgHeader<R>) -> bool {
-        self.is_reply() && !req.is_reply() && self.get_code() == req.get_code()
+        self.is_reply() && !req.is_reply() && self.request == req.request
     }
 
     /// Get message size.
@@ -349,7 +435,7 @@ impl<R: Req> Default for VhostUserMsgHeader<R> {
 impl<T: Req> VhostUserMsgValidator for VhostUserMsgHeader<T> {
     #[allow(clippy::if_same_then_else)]
     fn is_valid(&self) -> bool {
-        if !self.get_code().is_valid() {
+        if !self.is_custom() && !T::is_valid_code(self.request) {
             return false;
         } else if self.size as usize > MAX_MSG_SIZE {
             return false;
@@ -366,8 +452,14 @@ impl<T: Req> VhostUserMsgValidator for VhostUserMsgHeader<T> {
 bitflags! {
     /// Transport specific flags in VirtIO feature set defined by vhost-user.
     pub struct VhostUserVirtioFeatures: u64 {
+        /// Feature flag for logging the guest memory written by the slave.
+        const LOG_ALL = 0x0400_0000;
+        /// Feature flag for the notification suppression with event indexes.
+        const EVENT_IDX = 0x2000_0000;
         /// Feature flag for the protocol feature.
         const PROTOCOL_FEATURES = 0x4000_0000;
+        /// Feature flag for the packed virtqueue layout.
+        const RING_PACKED = 0x4_0000_0000;
     }
 }
 
@@ -409,6 +501,14 @@ bitflags! {
         const CONFIGURE_MEM_SLOTS = 0x0000_8000;
         /// Support reporting status.
         const STATUS = 0x0001_0000;
+        /// Support Xen mmap of the memory regions.
+        const XEN_MMAP = 0x0002_0000;
+        /// Support sharing virtio objects by UUID.
+        const SHARED_OBJECT = 0x0004_0000;
+        /// Support transferring the internal device state.
+        const DEVICE_STATE = 0x0008_0000;
+        /// Support VIRTIO shared memory regions.
+        const SHMEM = 0x0010_0000;
     }
 }
 
@@ -433,7 +533,7 @@ impl VhostUserMsgValidator for VhostUserU64 {}
 
 /// Memory region descriptor for the SET_MEM_TABLE request.
 #[repr(packed)]
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub struct VhostUserMemory {
     /// Number of memory regions in the payload.
     pub num_regions: u32,
@@ -451,6 +551,8 @@ impl VhostUserMemory {
     }
 }
 
+unsafe impl ByteValued for VhostUserMemory {}
+
 impl VhostUserMsgValidator for VhostUserMemory {
     #[allow(clippy::if_same_then_else)]
     fn is_valid(&self) -> bool {
@@ -463,6 +565,29 @@ impl VhostUserMsgValidator for VhostUserMemory {
     }
 }
 
+// Bit mask for the Xen mmap flags of memory regions.
+bitflags! {
+    /// Flags describing how a memory region must be mapped on Xen.
+    pub struct VhostUserXenMmapFlags: u32 {
+        /// Map the region with Xen foreign memory mapping.
+        const FOREIGN = 0x1;
+        /// Map the region with Xen grant memory mapping.
+        const GRANT = 0x2;
+        /// The region can't be mapped in advance, only the areas in use may be mapped.
+        const NO_ADVANCE_MAP = 0x100;
+    }
+}
+
+impl VhostUserXenMmapFlags {
+    // At most one mapping type may be requested.
+    fn is_valid(bits: u32) -> bool {
+        match Self::from_bits(bits) {
+            Some(flags) => !flags.contains(Self::FOREIGN | Self::GRANT),
+            None => false,
+        }
+    }
+}
+
 /// Memory region descriptors as payload for the SET_MEM_TABLE request.
 #[repr(packed)]
 #[derive(Default, Clone, Copy)]
@@ -475,6 +600,12 @@ pub struct VhostUserMemoryRegion {
     pub user_addr: u64,
     /// Offset where region starts in the mapped memory.
     pub mmap_offset: u64,
+    /// Xen specific flags describing how the region must be mapped.
+    #[cfg(feature = "xen")]
+    pub xen_mmap_flags: u32,
+    /// Xen domain id owning the memory region.
+    #[cfg(feature = "xen")]
+    pub xen_domid: u32,
 }
 
 impl VhostUserMemoryRegion {
@@ -485,10 +616,37 @@ impl VhostUserMemoryRegion {
             memory_size,
             user_addr,
             mmap_offset,
+            #[cfg(feature = "xen")]
+            xen_mmap_flags: 0,
+            #[cfg(feature = "xen")]
+            xen_domid: 0,
+        }
+    }
+
+    /// Get the Xen mmap flags of the region.
+    #[cfg(feature = "xen")]
+    pub fn xen_mmap_flags(&self) -> VhostUserXenMmapFlags {
+        VhostUserXenMmapFlags::from_bits_truncate(self.xen_mmap_flags)
+    }
+}
+
+impl From<&VhostUserMemoryRegionInfo<'_>> for VhostUserMemoryRegion {
+    fn from(region: &VhostUserMemoryRegionInfo<'_>) -> Self {
+        VhostUserMemoryRegion {
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            user_addr: region.userspace_addr,
+            mmap_offset: region.mmap_offset,
+            #[cfg(feature = "xen")]
+            xen_mmap_flags: region.xen_mmap_flags,
+            #[cfg(feature = "xen")]
+            xen_domid: region.xen_domid,
         }
     }
 }
 
+unsafe impl ByteValued for VhostUserMemoryRegion {}
+
 impl VhostUserMsgValidator for VhostUserMemoryRegion {
     fn is_valid(&self) -> bool {
         if self.memory_size == 0
@@ -498,6 +656,12 @@ impl VhostUserMsgValidator for VhostUserMemoryRegion {
         {
             return false;
         }
+        #[cfg(feature = "xen")]
+        {
+            if !VhostUserXenMmapFlags::is_valid(self.xen_mmap_flags) {
+                return false;
+            }
+        }
         true
     }
 }
@@ -520,6 +684,12 @@ pub struct VhostUserSingleMemoryRegion {
     pub user_addr: u64,
     /// Offset where region starts in the mapped memory.
     pub mmap_offset: u64,
+    /// Xen specific flags describing how the region must be mapped.
+    #[cfg(feature = "xen")]
+    pub xen_mmap_flags: u32,
+    /// Xen domain id owning the memory region.
+    #[cfg(feature = "xen")]
+    pub xen_domid: u32,
 }
 
 impl VhostUserSingleMemoryRegion {
@@ -531,10 +701,69 @@ impl VhostUserSingleMemoryRegion {
             memory_size,
             user_addr,
             mmap_offset,
+            #[cfg(feature = "xen")]
+            xen_mmap_flags: 0,
+            #[cfg(feature = "xen")]
+            xen_domid: 0,
+        }
+    }
+
+    /// Get the Xen mmap flags of the region.
+    #[cfg(feature = "xen")]
+    pub fn xen_mmap_flags(&self) -> VhostUserXenMmapFlags {
+        VhostUserXenMmapFlags::from_bits_truncate(self.xen_mmap_flags)
+    }
+}
+
+impl From<&VhostUserMemoryRegionInfo<'_>> for VhostUserSingleMemoryRegion {
+    fn from(region: &VhostUserMemoryRegionInfo<'_>) -> Self {
+        VhostUserSingleMemoryRegion {
+            padding: 0,
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            user_addr: region.userspace_addr,
+            mmap_offset: region.mmap_offset,
+            #[cfg(feature = "xen")]
+            xen_mmap_flags: region.xen_mmap_flags,
+            #[cfg(feature = "xen")]
+            xen_domid: region.xen_domid,
         }
     }
 }
 
+impl From<&VhostUserMemoryRegion> for VhostUserSingleMemoryRegion {
+    fn from(region: &VhostUserMemoryRegion) -> Self {
+        VhostUserSingleMemoryRegion {
+            padding: 0,
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            user_addr: region.user_addr,
+            mmap_offset: region.mmap_offset,
+            #[cfg(feature = "xen")]
+            xen_mmap_flags: region.xen_mmap_flags,
+            #[cfg(feature = "xen")]
+            xen_domid: region.xen_domid,
+        }
+    }
+}
+
+impl From<&VhostUserSingleMemoryRegion> for VhostUserMemoryRegion {
+    fn from(region: &VhostUserSingleMemoryRegion) -> Self {
+        VhostUserMemoryRegion {
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            user_addr: region.user_addr,
+            mmap_offset: region.mmap_offset,
+            #[cfg(feature = "xen")]
+            xen_mmap_flags: region.xen_mmap_flags,
+            #[cfg(feature = "xen")]
+            xen_domid: region.xen_domid,
+        }
+    }
+}
+
+unsafe impl ByteValued for VhostUserSingleMemoryRegion {}
+
 impl VhostUserMsgValidator for VhostUserSingleMemoryRegion {
     fn is_valid(&self) -> bool {
         if self.memory_size == 0
@@ -544,6 +773,12 @@ impl VhostUserMsgValidator for VhostUserSingleMemoryRegion {
         {
             return false;
         }
+        #[cfg(feature = "xen")]
+        {
+            if !VhostUserXenMmapFlags::is_valid(self.xen_mmap_flags) {
+                return false;
+            }
+        }
         true
     }
 }
@@ -569,6 +804,114 @@ unsafe impl ByteValued for VhostUserVringState {}
 
 impl VhostUserMsgValidator for VhostUserVringState {}
 
+/// Position of a packed vring, as carried by SET_VRING_BASE and GET_VRING_BASE.
+///
+/// For packed vrings, the `num` of the vring state holds the last available index in bits 0-14
+/// and the available wrap counter in bit 15, then the last used index in bits 16-30 and the used
+/// wrap counter in bit 31.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VringPackedBase {
+    /// Index of the next descriptor the device reads from the ring.
+    pub last_avail_idx: u16,
+    /// Wrap counter of the available ring.
+    pub avail_wrap_counter: bool,
+    /// Index of the next descriptor the device writes to the ring.
+    pub last_used_idx: u16,
+    /// Wrap counter of the used ring.
+    pub used_wrap_counter: bool,
+}
+
+impl VringPackedBase {
+    const IDX_MASK: u32 = 0x7fff;
+    const WRAP_COUNTER: u32 = 0x8000;
+
+    /// Create a new instance.
+    pub fn new(
+        last_avail_idx: u16,
+        avail_wrap_counter: bool,
+        last_used_idx: u16,
+        used_wrap_counter: bool,
+    ) -> Self {
+        VringPackedBase {
+            last_avail_idx,
+            avail_wrap_counter,
+            last_used_idx,
+            used_wrap_counter,
+        }
+    }
+
+    /// Decode the `num` of a vring state.
+    pub fn from_num(num: u32) -> Self {
+        let avail = num & 0xffff;
+        let used = num >> 16;
+        VringPackedBase {
+            last_avail_idx: (avail & Self::IDX_MASK) as u16,
+            avail_wrap_counter: avail & Self::WRAP_COUNTER != 0,
+            last_used_idx: (used & Self::IDX_MASK) as u16,
+            used_wrap_counter: used & Self::WRAP_COUNTER != 0,
+        }
+    }
+
+    /// Encode into the `num` of a vring state.
+    ///
+    /// Return `None` if an index doesn't fit in 15 bits.
+    pub fn to_num(&self) -> Option<u32> {
+        let avail = u32::from(self.last_avail_idx);
+        let used = u32::from(self.last_used_idx);
+        if avail > Self::IDX_MASK || used > Self::IDX_MASK {
+            return None;
+        }
+        let wrap = |set: bool| if set { Self::WRAP_COUNTER } else { 0 };
+        Some((avail | wrap(self.avail_wrap_counter)) | (used | wrap(self.used_wrap_counter)) << 16)
+    }
+}
+
+/// Position of a vring, as carried by SET_VRING_BASE and GET_VRING_BASE, decoded according to
+/// the layout of the vring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VringBase {
+    /// Index of the next entry of the available ring of a split vring.
+    Split(u16),
+    /// Position of a packed vring, when VIRTIO_F_RING_PACKED has been negotiated.
+    Packed(VringPackedBase),
+}
+
+impl Default for VringBase {
+    fn default() -> Self {
+        VringBase::Split(0)
+    }
+}
+
+impl VringBase {
+    /// Decode the `num` of a vring state, for a packed vring if `packed`.
+    ///
+    /// Return `None` if the index of a split vring doesn't fit in 16 bits.
+    pub fn from_num(num: u32, packed: bool) -> Option<Self> {
+        if packed {
+            Some(VringBase::Packed(VringPackedBase::from_num(num)))
+        } else if num > u32::from(u16::MAX) {
+            None
+        } else {
+            Some(VringBase::Split(num as u16))
+        }
+    }
+
+    /// Encode into the `num` of a vring state.
+    ///
+    /// Return `None` if an index of a packed vring doesn't fit in 15 bits.
+    pub fn to_num(&self) -> Option<u32> {
+        match self {
+            VringBase::Split(idx) => Some(u32::from(*idx)),
+            VringBase::Packed(base) => base.to_num(),
+        }
+    }
+
+    /// Whether the position is the one of a packed vring.
+    pub fn is_packed(&self) -> bool {
+        matches!(self, VringBase::Packed(_))
+    }
+}
+
 // Bit mask for vring address flags.
 bitflags! {
     /// Flags for vring address.
@@ -581,7 +924,7 @@ bitflags! {
 
 /// Vring address descriptor.
 #[repr(packed)]
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub struct VhostUserVringAddr {
     /// Vring index.
     pub index: u32,
@@ -648,6 +991,317 @@ impl VhostUserMsgValidator for VhostUserVringAddr {
     }
 }
 
+/// Message to identify a virtio shared object by its UUID.
+#[repr(packed)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserShared {
+    /// UUID of the shared object.
+    pub uuid: [u8; 16],
+}
+
+impl VhostUserShared {
+    /// Create a new instance.
+    pub fn new(uuid: [u8; 16]) -> Self {
+        VhostUserShared { uuid }
+    }
+}
+
+unsafe impl ByteValued for VhostUserShared {}
+
+impl VhostUserMsgValidator for VhostUserShared {}
+
+/// Maximum number of VIRTIO shared memory regions of a device.
+pub const VHOST_USER_MAX_SHMEM_REGIONS: usize = 256;
+
+/// Reply of GET_SHMEM_CONFIG requests, describing the VIRTIO shared memory regions.
+///
+/// The size of the region with id `shmid` is `memory_sizes[shmid]`, unused ids have a size of
+/// zero.
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct VhostUserShMemConfig {
+    /// Number of shared memory regions.
+    pub nregions: u32,
+    padding: u32,
+    /// Size of each shared memory region, indexed by region id.
+    pub memory_sizes: [u64; VHOST_USER_MAX_SHMEM_REGIONS],
+}
+
+impl VhostUserShMemConfig {
+    /// Create a new instance from the sizes of the regions, indexed by region id.
+    pub fn new(memory_sizes: &[u64]) -> Option<Self> {
+        if memory_sizes.len() > VHOST_USER_MAX_SHMEM_REGIONS {
+            return None;
+        }
+        let mut sizes = [0; VHOST_USER_MAX_SHMEM_REGIONS];
+        sizes[..memory_sizes.len()].copy_from_slice(memory_sizes);
+        Some(VhostUserShMemConfig {
+            nregions: memory_sizes.iter().filter(|size| **size != 0).count() as u32,
+            padding: 0,
+            memory_sizes: sizes,
+        })
+    }
+
+    /// Get the id and size of each shared memory region.
+    pub fn regions(&self) -> Vec<(u8, u64)> {
+        let memory_sizes = self.memory_sizes;
+        memory_sizes
+            .iter()
+            .enumerate()
+            .filter(|(_, size)| **size != 0)
+            .map(|(shmid, size)| (shmid as u8, *size))
+            .collect()
+    }
+}
+
+impl Default for VhostUserShMemConfig {
+    fn default() -> Self {
+        VhostUserShMemConfig {
+            nregions: 0,
+            padding: 0,
+            memory_sizes: [0; VHOST_USER_MAX_SHMEM_REGIONS],
+        }
+    }
+}
+
+unsafe impl ByteValued for VhostUserShMemConfig {}
+
+impl VhostUserMsgValidator for VhostUserShMemConfig {
+    fn is_valid(&self) -> bool {
+        let memory_sizes = self.memory_sizes;
+        let count = memory_sizes.iter().filter(|size| **size != 0).count();
+        self.nregions as usize == count
+    }
+}
+
+bitflags! {
+    #[derive(Default)]
+    /// Flags of SHMEM_MAP slave requests.
+    pub struct VhostUserMMapFlags: u64 {
+        /// Map the file with write permission.
+        const MAP_RW = 0x1;
+    }
+}
+
+/// Slave request message to map a file into, or unmap it from, a VIRTIO shared memory region.
+#[repr(packed)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserMMap {
+    /// Id of the shared memory region.
+    pub shmid: u8,
+    padding: [u8; 7],
+    /// File offset.
+    pub fd_offset: u64,
+    /// Offset into the shared memory region.
+    pub shm_offset: u64,
+    /// Size of the mapping.
+    pub len: u64,
+    /// Flags for the mmap operation.
+    pub flags: u64,
+}
+
+impl VhostUserMMap {
+    /// Create a new instance.
+    pub fn new(
+        shmid: u8,
+        fd_offset: u64,
+        shm_offset: u64,
+        len: u64,
+        flags: VhostUserMMapFlags,
+    ) -> Self {
+        VhostUserMMap {
+            shmid,
+            padding: [0; 7],
+            fd_offset,
+            shm_offset,
+            len,
+            flags: flags.bits(),
+        }
+    }
+}
+
+unsafe impl ByteValued for VhostUserMMap {}
+
+impl VhostUserMsgValidator for VhostUserMMap {
+    fn is_valid(&self) -> bool {
+        (self.flags & !VhostUserMMapFlags::all().bits()) == 0
+            && self.len != 0
+            && self.fd_offset.checked_add(self.len).is_some()
+            && self.shm_offset.checked_add(self.len).is_some()
+    }
+}
+
+/// Maximum length of the cipher key of a crypto session.
+pub const VHOST_USER_CRYPTO_MAX_CIPHER_KEY_LEN: usize = 64;
+
+/// Maximum length of the authentication key of a crypto session.
+pub const VHOST_USER_CRYPTO_MAX_HMAC_KEY_LEN: usize = 512;
+
+/// Symmetric crypto session parameters as payload for CREATE_CRYPTO_SESSION requests.
+///
+/// The slave replies with the same message, with `session_id` set to the id of the new session
+/// or to a negative value on failure.
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct VhostUserCryptoSession {
+    /// Id of the session, negative on failure.
+    pub session_id: i64,
+    /// Operation code of the session, as defined by the virtio-crypto specification.
+    pub op_code: u32,
+    /// Cipher algorithm.
+    pub cipher_alg: u32,
+    /// Length of the cipher key.
+    pub cipher_key_len: u32,
+    /// Hash algorithm.
+    pub hash_alg: u32,
+    /// Length of the hash result.
+    pub hash_result_len: u32,
+    /// Length of the authentication key.
+    pub auth_key_len: u32,
+    /// Length of the additional authenticated data.
+    pub aad_len: u32,
+    /// Type of the operation, cipher only or algorithm chaining.
+    pub op_type: u8,
+    /// Encryption or decryption.
+    pub direction: u8,
+    /// Hash mode for algorithm chaining.
+    pub hash_mode: u8,
+    /// Order of the cipher and hash operations for algorithm chaining.
+    pub alg_chain_order: u8,
+    // Key pointers in the address space of the master, meaningless to the slave.
+    reserved: [u64; 2],
+    /// Cipher key, only the first `cipher_key_len` bytes are used.
+    pub cipher_key: [u8; VHOST_USER_CRYPTO_MAX_CIPHER_KEY_LEN],
+    /// Authentication key, only the first `auth_key_len` bytes are used.
+    pub auth_key: [u8; VHOST_USER_CRYPTO_MAX_HMAC_KEY_LEN],
+}
+
+impl VhostUserCryptoSession {
+    /// Create a new instance with the given keys and all other parameters zeroed.
+    ///
+    /// Return `None` if a key is too long.
+    pub fn new(cipher_key: &[u8], auth_key: &[u8]) -> Option<Self> {
+        if cipher_key.len() > VHOST_USER_CRYPTO_MAX_CIPHER_KEY_LEN
+            || auth_key.len() > VHOST_USER_CRYPTO_MAX_HMAC_KEY_LEN
+        {
+            return None;
+        }
+        let mut session = VhostUserCryptoSession {
+            cipher_key_len: cipher_key.len() as u32,
+            auth_key_len: auth_key.len() as u32,
+            ..Default::default()
+        };
+        session.cipher_key[..cipher_key.len()].copy_from_slice(cipher_key);
+        session.auth_key[..auth_key.len()].copy_from_slice(auth_key);
+        Some(session)
+    }
+}
+
+impl Default for VhostUserCryptoSession {
+    fn default() -> Self {
+        VhostUserCryptoSession {
+            session_id: 0,
+            op_code: 0,
+            cipher_alg: 0,
+            cipher_key_len: 0,
+            hash_alg: 0,
+            hash_result_len: 0,
+            auth_key_len: 0,
+            aad_len: 0,
+            op_type: 0,
+            direction: 0,
+            hash_mode: 0,
+            alg_chain_order: 0,
+            reserved: [0; 2],
+            cipher_key: [0; VHOST_USER_CRYPTO_MAX_CIPHER_KEY_LEN],
+            auth_key: [0; VHOST_USER_CRYPTO_MAX_HMAC_KEY_LEN],
+        }
+    }
+}
+
+unsafe impl ByteValued for VhostUserCryptoSession {}
+
+impl VhostUserMsgValidator for VhostUserCryptoSession {
+    fn is_valid(&self) -> bool {
+        self.cipher_key_len as usize <= VHOST_USER_CRYPTO_MAX_CIPHER_KEY_LEN
+            && self.auth_key_len as usize <= VHOST_USER_CRYPTO_MAX_HMAC_KEY_LEN
+    }
+}
+
+/// Mask of the vring index in the payload of SET_VRING_KICK, SET_VRING_CALL and SET_VRING_ERR.
+pub const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+
+/// Flag of the payload of SET_VRING_KICK, SET_VRING_CALL and SET_VRING_ERR set when no fd is
+/// attached to the request.
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 0x100;
+
+/// Flag of the u64 reply to SET_DEVICE_STATE_FD set when no fd is attached to the reply.
+pub const VHOST_USER_DEVICE_STATE_NO_FD: u64 = 0x100;
+
+/// Direction of the device state transfer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum VhostTransferStateDirection {
+    /// Transfer the state from the backend to the front-end.
+    SAVE = 0,
+    /// Transfer the state from the front-end to the backend.
+    LOAD = 1,
+}
+
+/// Migration phase during which the device state is transferred.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum VhostTransferStatePhase {
+    /// The device, and all its vrings, are stopped.
+    STOPPED = 0,
+}
+
+/// Message to begin the transfer of the internal device state.
+#[repr(packed)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserTransferDeviceState {
+    /// Direction of the state transfer.
+    pub direction: u32,
+    /// Migration phase during which the transfer happens.
+    pub phase: u32,
+}
+
+impl VhostUserTransferDeviceState {
+    /// Create a new instance.
+    pub fn new(direction: VhostTransferStateDirection, phase: VhostTransferStatePhase) -> Self {
+        VhostUserTransferDeviceState {
+            direction: direction as u32,
+            phase: phase as u32,
+        }
+    }
+
+    /// Get the direction of the state transfer, if valid.
+    pub fn direction(&self) -> Option<VhostTransferStateDirection> {
+        match self.direction {
+            0 => Some(VhostTransferStateDirection::SAVE),
+            1 => Some(VhostTransferStateDirection::LOAD),
+            _ => None,
+        }
+    }
+
+    /// Get the migration phase of the state transfer, if valid.
+    pub fn phase(&self) -> Option<VhostTransferStatePhase> {
+        match self.phase {
+            0 => Some(VhostTransferStatePhase::STOPPED),
+            _ => None,
+        }
+    }
+}
+
+unsafe impl ByteValued for VhostUserTransferDeviceState {}
+
+impl VhostUserMsgValidator for VhostUserTransferDeviceState {
+    fn is_valid(&self) -> bool {
+        self.direction <= VhostTransferStateDirection::LOAD as u32
+            && self.phase == VhostTransferStatePhase::STOPPED as u32
+    }
+}
+
 // Bit mask for the vhost-user device configuration message.
 bitflags! {
     /// Flags for the device configuration message.
@@ -680,24 +1334,42 @@ impl VhostUserConfig {
             flags: flags.bits(),
         }
     }
-}
-
-unsafe impl ByteValued for VhostUserConfig {}
 
-impl VhostUserMsgValidator for VhostUserConfig {
+    /// Check the message against a device configuration space ending at `config_size`.
+    ///
+    /// `is_valid()` checks it against the default `VHOST_USER_CONFIG_SIZE`.
     #[allow(clippy::if_same_then_else)]
-    fn is_valid(&self) -> bool {
+    pub fn is_valid_for(&self, config_size: u32) -> bool {
         let end_addr = match self.size.checked_add(self.offset) {
             Some(addr) => addr,
             None => return false,
         };
         if (self.flags & !VhostUserConfigFlags::all().bits()) != 0 {
             return false;
-        } else if self.size == 0 || end_addr > VHOST_USER_CONFIG_SIZE {
+        } else if self.size == 0 || end_addr > config_size {
             return false;
         }
         true
     }
+
+    /// Get the maximum size of a single access to a configuration space ending at `config_size`.
+    ///
+    /// The access is limited to the device specific part of the configuration space, and its
+    /// payload must fit in a single message.
+    pub fn max_access_size(config_size: u32) -> u32 {
+        let max_payload = MAX_MSG_SIZE - mem::size_of::<VhostUserConfig>();
+        config_size
+            .saturating_sub(VHOST_USER_CONFIG_OFFSET)
+            .min(max_payload as u32)
+    }
+}
+
+unsafe impl ByteValued for VhostUserConfig {}
+
+impl VhostUserMsgValidator for VhostUserConfig {
+    fn is_valid(&self) -> bool {
+        self.is_valid_for(VHOST_USER_CONFIG_SIZE)
+    }
 }
 
 /// Payload for the VhostUserConfig message.
@@ -728,6 +1400,16 @@ impl VhostUserInflight {
             queue_size,
         }
     }
+
+    /// Size of the shared buffer needed to track the inflight I/O of all split virtqueues.
+    pub fn split_mmap_size(&self) -> u64 {
+        u64::from(self.num_queues) * QueueRegionSplit::region_size(self.queue_size)
+    }
+
+    /// Size of the shared buffer needed to track the inflight I/O of all packed virtqueues.
+    pub fn packed_mmap_size(&self) -> u64 {
+        u64::from(self.num_queues) * QueueRegionPacked::region_size(self.queue_size)
+    }
 }
 
 unsafe impl ByteValued for VhostUserInflight {}
@@ -770,16 +1452,56 @@ impl VhostUserMsgValidator for VhostUserLog {
     }
 }
 
-/*
- * TODO: support dirty log, live migration and IOTLB operations.
+/// Slave request message to set the host notifier area of a vring.
 #[repr(packed)]
+#[derive(Copy, Clone, Default)]
 pub struct VhostUserVringArea {
-    pub index: u32,
-    pub flags: u32,
+    /// Vring index in bits 0-7, and `VHOST_USER_VRING_NOFD_MASK` when no fd is attached.
+    pub index: u64,
+    /// Size of the area.
     pub size: u64,
+    /// Offset of the area in the attached file.
     pub offset: u64,
 }
 
+impl VhostUserVringArea {
+    /// Create a new instance, `fd` telling whether a file is attached to the message.
+    pub fn new(queue_index: u8, fd: bool, size: u64, offset: u64) -> Self {
+        let mut index = queue_index as u64;
+        if !fd {
+            index |= VHOST_USER_VRING_NOFD_MASK;
+        }
+        VhostUserVringArea {
+            index,
+            size,
+            offset,
+        }
+    }
+
+    /// Get the vring index.
+    pub fn queue_index(&self) -> usize {
+        (self.index & VHOST_USER_VRING_IDX_MASK) as usize
+    }
+
+    /// Whether a file mapping the area is attached to the message.
+    pub fn has_fd(&self) -> bool {
+        self.index & VHOST_USER_VRING_NOFD_MASK == 0
+    }
+}
+
+unsafe impl ByteValued for VhostUserVringArea {}
+
+impl VhostUserMsgValidator for VhostUserVringArea {
+    fn is_valid(&self) -> bool {
+        if self.index & !(VHOST_USER_VRING_IDX_MASK | VHOST_USER_VRING_NOFD_MASK) != 0 {
+            return false;
+        }
+        !self.has_fd() || (self.size != 0 && self.offset.checked_add(self.size).is_some())
+    }
+}
+
+/*
+ * TODO: support dirty log, live migration and IOTLB operations.
 #[repr(packed)]
 pub struct VhostUserLog {
     pub size: u64,
@@ -813,6 +1535,19 @@ bitflags! {
 /// Max entries in one virtio-fs slave request.
 pub const VHOST_USER_FS_SLAVE_ENTRIES: usize = 8;
 
+/// Entry of a virtio-fs slave request message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VhostUserFSSlaveEntry {
+    /// File offset.
+    pub fd_offset: u64,
+    /// Offset into the DAX window, or guest physical address for FS_IO requests.
+    pub cache_offset: u64,
+    /// Size of the region.
+    pub len: u64,
+    /// Flags of the operation.
+    pub flags: VhostUserFSSlaveMsgFlags,
+}
+
 /// Slave request message to update the MMIO window.
 #[repr(packed)]
 #[derive(Default)]
@@ -827,6 +1562,36 @@ pub struct VhostUserFSSlaveMsg {
     pub flags: [VhostUserFSSlaveMsgFlags; VHOST_USER_FS_SLAVE_ENTRIES],
 }
 
+impl VhostUserFSSlaveMsg {
+    /// Create a message of up to VHOST_USER_FS_SLAVE_ENTRIES `entries`, the others being empty.
+    pub fn from_entries(entries: &[VhostUserFSSlaveEntry]) -> Option<Self> {
+        if entries.len() > VHOST_USER_FS_SLAVE_ENTRIES {
+            return None;
+        }
+        let mut msg = VhostUserFSSlaveMsg::default();
+        for (i, entry) in entries.iter().enumerate() {
+            msg.fd_offset[i] = entry.fd_offset;
+            msg.cache_offset[i] = entry.cache_offset;
+            msg.len[i] = entry.len;
+            msg.flags[i] = entry.flags;
+        }
+        Some(msg)
+    }
+
+    /// Get the entry `index` of the message.
+    pub fn entry(&self, index: usize) -> Option<VhostUserFSSlaveEntry> {
+        if index >= VHOST_USER_FS_SLAVE_ENTRIES {
+            return None;
+        }
+        Some(VhostUserFSSlaveEntry {
+            fd_offset: self.fd_offset[index],
+            cache_offset: self.cache_offset[index],
+            len: self.len[index],
+            flags: self.flags[index],
+        })
+    }
+}
+
 impl VhostUserMsgValidator for VhostUserFSSlaveMsg {
     fn is_valid(&self) -> bool {
         for i in 0..VHOST_USER_FS_SLAVE_ENTRIES {
@@ -841,6 +1606,16 @@ impl VhostUserMsgValidator for VhostUserFSSlaveMsg {
     }
 }
 
+/// Alignment of the inflight I/O tracking region of each queue.
+pub const INFLIGHT_ALIGNMENT: u64 = 64;
+
+// Size of an inflight queue region with `queue_size` descriptor entries following a header of
+// `hdr_size` bytes, aligned to INFLIGHT_ALIGNMENT.
+fn inflight_region_size(hdr_size: usize, desc_size: usize, queue_size: u16) -> u64 {
+    let size = (hdr_size + desc_size * queue_size as usize) as u64;
+    (size + INFLIGHT_ALIGNMENT - 1) & !(INFLIGHT_ALIGNMENT - 1)
+}
+
 /// Inflight I/O descriptor state for split virtqueues
 #[repr(packed)]
 #[derive(Clone, Copy, Default)]
@@ -891,6 +1666,18 @@ impl QueueRegionSplit {
             desc: 0,
         }
     }
+
+    /// Size of the region tracking a split virtqueue with `queue_size` descriptors.
+    ///
+    /// The `desc` field marks the start of the array of DescStateSplit entries, and each
+    /// queue region is aligned to INFLIGHT_ALIGNMENT bytes.
+    pub fn region_size(queue_size: u16) -> u64 {
+        inflight_region_size(
+            mem::size_of::<Self>() - mem::size_of::<u64>(),
+            mem::size_of::<DescStateSplit>(),
+            queue_size,
+        )
+    }
 }
 
 /// Inflight I/O descriptor state for packed virtqueues
@@ -970,6 +1757,18 @@ impl QueueRegionPacked {
             desc: 0,
         }
     }
+
+    /// Size of the region tracking a packed virtqueue with `queue_size` descriptors.
+    ///
+    /// The `desc` field marks the start of the array of DescStatePacked entries, and each
+    /// queue region is aligned to INFLIGHT_ALIGNMENT bytes.
+    pub fn region_size(queue_size: u16) -> u64 {
+        inflight_region_size(
+            mem::size_of::<Self>() - mem::size_of::<u64>(),
+            mem::size_of::<DescStatePacked>(),
+            queue_size,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -1003,6 +1802,47 @@ mod tests {
         assert_eq!(code, code.clone());
         let code: SlaveReq = unsafe { std::mem::transmute::<u32, SlaveReq>(10000u32) };
         assert!(!code.is_valid());
+        let code = SlaveReq::SHARED_OBJECT_LOOKUP;
+        assert!(code.is_valid());
+        let code = SlaveReq::FS_IO;
+        assert!(code.is_valid());
+        let code = SlaveReq::SHMEM_UNMAP;
+        assert!(code.is_valid());
+        let hdr = VhostUserMsgHeader::<SlaveReq>::new_raw(11, 0x1, 0);
+        assert!(!hdr.is_valid());
+    }
+
+    #[test]
+    fn msg_header_slave_code() {
+        // The codes between the standard and the virtio-fs requests aren't defined.
+        let hdr = VhostUserMsgHeader::<SlaveReq>::new_raw(999, 0x1, 0);
+        assert!(!hdr.is_custom());
+        assert!(!hdr.is_valid());
+        let hdr = VhostUserMsgHeader::<SlaveReq>::new_raw(SlaveReq::FS_MAP as u32, 0x1, 0);
+        assert!(hdr.is_valid());
+        assert_eq!(hdr.get_code(), SlaveReq::FS_MAP);
+    }
+
+    #[test]
+    fn msg_header_custom_code() {
+        let hdr = VhostUserMsgHeader::<MasterReq>::new(MasterReq::GET_FEATURES, 0, 0);
+        assert!(!hdr.is_custom());
+        assert_eq!(hdr.get_raw_code(), MasterReq::GET_FEATURES as u32);
+
+        let code = MasterReq::MAX_CMD as u32 + 100;
+        let hdr = VhostUserMsgHeader::<MasterReq>::new_raw(code, 0, 8);
+        assert!(hdr.is_custom());
+        assert!(hdr.is_valid());
+        assert_eq!(hdr.get_raw_code(), code);
+        assert_eq!(hdr.get_version(), 0x1);
+
+        let reply = VhostUserMsgHeader::<MasterReq>::new_raw(code, 0x4, 0);
+        assert!(reply.is_reply_for(&hdr));
+        let other = VhostUserMsgHeader::<MasterReq>::new_raw(code + 1, 0x4, 0);
+        assert!(!other.is_reply_for(&hdr));
+
+        assert!(!SlaveReq::is_custom_code(SlaveReq::FS_IO as u32));
+        assert!(SlaveReq::is_custom_code(SlaveReq::MAX_CMD as u32));
     }
 
     #[test]
@@ -1086,14 +1926,30 @@ mod tests {
         assert!(!msg.is_valid());
     }
 
+    #[test]
+    fn check_xen_mmap_flags() {
+        assert!(VhostUserXenMmapFlags::is_valid(
+            VhostUserXenMmapFlags::FOREIGN.bits()
+        ));
+        assert!(VhostUserXenMmapFlags::is_valid(
+            (VhostUserXenMmapFlags::GRANT | VhostUserXenMmapFlags::NO_ADVANCE_MAP).bits()
+        ));
+        assert!(VhostUserXenMmapFlags::is_valid(0));
+        assert!(!VhostUserXenMmapFlags::is_valid(
+            (VhostUserXenMmapFlags::FOREIGN | VhostUserXenMmapFlags::GRANT).bits()
+        ));
+        assert!(!VhostUserXenMmapFlags::is_valid(
+            0x4 | VhostUserXenMmapFlags::GRANT.bits()
+        ));
+    }
+
     #[test]
     fn check_user_memory_region() {
-        let mut msg = VhostUserMemoryRegion {
-            guest_phys_addr: 0,
-            memory_size: 0x1000,
-            user_addr: 0,
-            mmap_offset: 0,
-        };
+        let mut msg = VhostUserMemoryRegion::new(0, 0x1000, 0, 0);
+        #[cfg(feature = "xen")]
+        {
+            msg.xen_mmap_flags = VhostUserXenMmapFlags::GRANT.bits();
+        }
         assert!(msg.is_valid());
         msg.guest_phys_addr = 0xFFFFFFFFFFFFEFFF;
         assert!(msg.is_valid());
@@ -1135,6 +1991,75 @@ mod tests {
         assert_eq!(state.is_valid(), true);
     }
 
+    #[test]
+    fn test_vhost_user_transfer_device_state() {
+        let msg = VhostUserTransferDeviceState::new(
+            VhostTransferStateDirection::LOAD,
+            VhostTransferStatePhase::STOPPED,
+        );
+        let a = msg.direction;
+        assert_eq!(a, 1);
+        let a = msg.phase;
+        assert_eq!(a, 0);
+        assert!(msg.is_valid());
+        assert_eq!(msg.direction(), Some(VhostTransferStateDirection::LOAD));
+        assert_eq!(msg.phase(), Some(VhostTransferStatePhase::STOPPED));
+
+        let mut msg = VhostUserTransferDeviceState::default();
+        assert!(msg.is_valid());
+        msg.direction = 2;
+        assert!(!msg.is_valid());
+        assert!(msg.direction().is_none());
+        msg.direction = 0;
+        msg.phase = 1;
+        assert!(msg.phase().is_none());
+        assert!(!msg.is_valid());
+    }
+
+    #[test]
+    fn test_vhost_user_crypto_session() {
+        assert_eq!(mem::size_of::<VhostUserCryptoSession>(), 632);
+
+        let mut msg = VhostUserCryptoSession::new(&[0x1; 16], &[0x2; 32]).unwrap();
+        let a = msg.cipher_key_len;
+        assert_eq!(a, 16);
+        let a = msg.auth_key_len;
+        assert_eq!(a, 32);
+        assert_eq!(msg.cipher_key[15], 0x1);
+        assert_eq!(msg.cipher_key[16], 0);
+        assert_eq!(msg.auth_key[31], 0x2);
+        assert!(msg.is_valid());
+
+        msg.cipher_key_len = VHOST_USER_CRYPTO_MAX_CIPHER_KEY_LEN as u32 + 1;
+        assert!(!msg.is_valid());
+        assert!(VhostUserCryptoSession::new(&[0; 65], &[]).is_none());
+        assert!(VhostUserCryptoSession::new(&[], &[0; 513]).is_none());
+    }
+
+    #[test]
+    fn test_vhost_user_shmem() {
+        assert_eq!(mem::size_of::<VhostUserShMemConfig>(), 2056);
+        assert_eq!(mem::size_of::<VhostUserMMap>(), 40);
+
+        let mut config = VhostUserShMemConfig::new(&[0x1000, 0, 0x4000]).unwrap();
+        let a = config.nregions;
+        assert_eq!(a, 2);
+        assert!(config.is_valid());
+        assert_eq!(config.regions(), vec![(0, 0x1000), (2, 0x4000)]);
+        config.nregions = 3;
+        assert!(!config.is_valid());
+        assert!(VhostUserShMemConfig::new(&[0; VHOST_USER_MAX_SHMEM_REGIONS + 1]).is_none());
+
+        let mut msg = VhostUserMMap::new(1, 0x1000, 0x2000, 0x1000, VhostUserMMapFlags::MAP_RW);
+        assert!(msg.is_valid());
+        msg.flags = 0x2;
+        assert!(!msg.is_valid());
+        msg.flags = 0;
+        msg.shm_offset = u64::MAX;
+        assert!(!msg.is_valid());
+        assert!(!VhostUserMMap::default().is_valid());
+    }
+
     #[test]
     fn test_vhost_user_addr() {
         let mut addr = VhostUserVringAddr::new(
@@ -1225,6 +2150,34 @@ mod tests {
         msg.flags &= !0x80000000;
     }
 
+    #[test]
+    fn check_vring_packed_base() {
+        let base = VringPackedBase::new(0x10, true, 0x7fff, false);
+        assert_eq!(base.to_num(), Some(0x7fff_8010));
+        assert_eq!(VringPackedBase::from_num(0x7fff_8010), base);
+
+        let base = VringPackedBase::new(3, false, 2, true);
+        assert_eq!(base.to_num(), Some(0x8002_0003));
+        assert_eq!(VringPackedBase::from_num(0x8002_0003), base);
+
+        assert_eq!(VringPackedBase::new(0x8000, false, 0, false).to_num(), None);
+        assert_eq!(VringPackedBase::new(0, false, 0x8000, false).to_num(), None);
+
+        assert_eq!(
+            VringBase::from_num(0xffff, false),
+            Some(VringBase::Split(0xffff))
+        );
+        assert_eq!(VringBase::from_num(0x1_0000, false), None);
+        let base = VringBase::from_num(0x8002_0003, true).unwrap();
+        assert!(base.is_packed());
+        assert_eq!(
+            base,
+            VringBase::Packed(VringPackedBase::new(3, false, 2, true))
+        );
+        assert_eq!(base.to_num(), Some(0x8002_0003));
+        assert_eq!(VringBase::default().to_num(), Some(0));
+    }
+
     #[test]
     fn check_user_config_msg() {
         let mut msg =
@@ -1250,6 +2203,27 @@ mod tests {
         assert!(!msg.is_valid());
     }
 
+    #[test]
+    fn check_vring_area() {
+        let area = VhostUserVringArea::new(3, true, 0x1000, 0x2000);
+        assert_eq!(area.queue_index(), 3);
+        assert!(area.has_fd());
+        assert!(area.is_valid());
+
+        let area = VhostUserVringArea::new(3, false, 0, 0);
+        assert!(!area.has_fd());
+        assert!(area.is_valid());
+
+        let mut area = VhostUserVringArea::new(3, true, 0, 0);
+        assert!(!area.is_valid());
+        area.size = 0x1000;
+        area.offset = u64::MAX;
+        assert!(!area.is_valid());
+        area.offset = 0;
+        area.index |= 0x200;
+        assert!(!area.is_valid());
+    }
+
     #[test]
     fn test_vhost_user_fs_slave() {
         let mut fs_slave = VhostUserFSSlaveMsg::default();
@@ -1265,5 +2239,31 @@ mod tests {
             VhostUserFSSlaveMsgFlags::MAP_W
         );
         assert_eq!(VhostUserFSSlaveMsgFlags::EMPTY.bits(), 0);
+
+        let entry = VhostUserFSSlaveEntry {
+            fd_offset: 0x1000,
+            cache_offset: 0,
+            len: 0x1000,
+            flags: VhostUserFSSlaveMsgFlags::MAP_W,
+        };
+        let entries = [entry; VHOST_USER_FS_SLAVE_ENTRIES + 1];
+        assert!(VhostUserFSSlaveMsg::from_entries(&entries).is_none());
+        let fs_slave = VhostUserFSSlaveMsg::from_entries(&entries[..2]).unwrap();
+        assert_eq!(fs_slave.entry(1), Some(entry));
+        assert_eq!(fs_slave.entry(2), Some(VhostUserFSSlaveEntry::default()));
+        assert!(fs_slave.entry(VHOST_USER_FS_SLAVE_ENTRIES).is_none());
+    }
+
+    #[test]
+    fn test_inflight_region_size() {
+        assert_eq!(mem::size_of::<DescStateSplit>(), 16);
+        assert_eq!(mem::size_of::<DescStatePacked>(), 32);
+        assert_eq!(QueueRegionSplit::region_size(128), 2112);
+        assert_eq!(QueueRegionSplit::region_size(1), 64);
+        assert_eq!(QueueRegionPacked::region_size(128), 4160);
+
+        let inflight = VhostUserInflight::new(0, 0, 2, 128);
+        assert_eq!(inflight.split_mmap_size(), 4224);
+        assert_eq!(inflight.packed_mmap_size(), 8320);
     }
 }
@@ -0,0 +1,349 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Live migration of the device behind a vhost-user master.
+//!
+//! Migrating a vhost-user device takes several phases: the slave starts logging the guest memory
+//! it writes into a shared dirty log, the VMM copies the dirty memory iteratively while the
+//! device keeps running, the queues are stopped to fetch their final state, and the internal
+//! device state is optionally transferred when DEVICE_STATE has been negotiated. [Migration]
+//! runs these phases one by one for VMMs driving their own migration engine, or sequences all of
+//! them with [Migration::run()] and reports each phase to a [MigrationHandler].
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+
+use vm_memory::GuestMemory;
+
+use super::message::{
+    VhostTransferStateDirection, VhostTransferStatePhase, VhostUserProtocolFeatures,
+    VhostUserVirtioFeatures,
+};
+use super::{DirtyLog, Error as VhostUserError, Master, VhostUserMaster};
+use crate::backend::{VhostFeatureOps, VhostVringOps};
+use crate::{Error, Result};
+
+/// Callbacks of the VMM migration engine, invoked by [Migration::run()] at each phase.
+pub trait MigrationHandler {
+    /// Handle the dirty pages harvested while the device is running.
+    ///
+    /// `bitmap` holds one bit per `VHOST_LOG_PAGE` of guest memory. Return `true` once the
+    /// remaining dirty memory is small enough to stop the device, or `false` to run another
+    /// iteration.
+    fn dirty_bitmap(&mut self, bitmap: &[u64]) -> Result<bool>;
+
+    /// Handle the base of a vring once its queue has been stopped.
+    fn vring_stopped(&mut self, _queue_index: usize, _base: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handle the dirty pages harvested after all the queues have been stopped.
+    fn final_dirty_bitmap(&mut self, bitmap: &[u64]) -> Result<()>;
+
+    /// Handle the internal state saved by the slave, when DEVICE_STATE has been negotiated.
+    fn device_state(&mut self, _state: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Live migration of the device behind a vhost-user master.
+pub struct Migration<'a> {
+    master: &'a mut Master,
+    queues: Vec<usize>,
+    log: Option<DirtyLog>,
+}
+
+impl<'a> Migration<'a> {
+    /// Prepare the migration of the device behind `master`, using the vrings of `queues`.
+    pub fn new(master: &'a mut Master, queues: &[usize]) -> Self {
+        Migration {
+            master,
+            queues: queues.to_vec(),
+            log: None,
+        }
+    }
+
+    /// Run all the phases of the migration, reporting each of them to `handler`.
+    ///
+    /// Dirty memory is synchronized until the handler asks to stop the device, then the queues
+    /// are stopped, the last dirty pages are reported and the device state is saved if the
+    /// slave supports it.
+    pub fn run<M: GuestMemory, H: MigrationHandler>(
+        &mut self,
+        mem: &M,
+        handler: &mut H,
+    ) -> Result<()> {
+        self.start_logging(mem)?;
+        loop {
+            let bitmap = self.sync_dirty_log()?;
+            if handler.dirty_bitmap(&bitmap)? {
+                break;
+            }
+        }
+
+        for (queue_index, base) in self.stop_queues()? {
+            handler.vring_stopped(queue_index, base)?;
+        }
+        let bitmap = self.sync_dirty_log()?;
+        handler.final_dirty_bitmap(&bitmap)?;
+
+        if self.supports_device_state() {
+            let mut state = Vec::new();
+            self.save_device_state(&mut state)?;
+            handler.device_state(&state)?;
+        }
+        Ok(())
+    }
+
+    /// Start logging the guest memory written by the slave.
+    ///
+    /// A dirty log covering `mem` is shared with the slave with SET_LOG_BASE, and LOG_ALL is
+    /// acked on top of the current features. Requires the LOG_SHMFD protocol feature.
+    pub fn start_logging<M: GuestMemory>(&mut self, mem: &M) -> Result<()> {
+        if !self
+            .master
+            .acked_protocol_features()
+            .contains(VhostUserProtocolFeatures::LOG_SHMFD)
+        {
+            return Err(VhostUserError::ProtocolFeatureNotNegotiated(
+                VhostUserProtocolFeatures::LOG_SHMFD,
+            )
+            .into());
+        }
+        let log_all = VhostUserVirtioFeatures::LOG_ALL.bits();
+        if self.master.get_features()? & log_all == 0 {
+            return Err(Error::UnsupportedFeatures(log_all));
+        }
+
+        let log = DirtyLog::new(mem)?;
+        log.set_log_base(&*self.master)?;
+        let features = self.master.acked_features() | log_all;
+        self.master.set_features(features)?;
+        self.log = Some(log);
+        Ok(())
+    }
+
+    /// Read and clear the dirty log, with one bit per `VHOST_LOG_PAGE` of guest memory.
+    pub fn sync_dirty_log(&self) -> Result<Vec<u64>> {
+        match self.log.as_ref() {
+            Some(log) => Ok(log.take_bitmap()),
+            None => Err(Error::InvalidOperation),
+        }
+    }
+
+    /// Stop the queues with GET_VRING_BASE and return the base of each vring.
+    pub fn stop_queues(&mut self) -> Result<Vec<(usize, u32)>> {
+        let mut bases = Vec::with_capacity(self.queues.len());
+        for &queue_index in self.queues.iter() {
+            bases.push((queue_index, self.master.get_vring_base(queue_index)?));
+        }
+        Ok(bases)
+    }
+
+    /// Check whether the slave can transfer its internal state.
+    pub fn supports_device_state(&self) -> bool {
+        self.master
+            .acked_protocol_features()
+            .contains(VhostUserProtocolFeatures::DEVICE_STATE)
+    }
+
+    /// Save the internal state of the stopped device into `writer`.
+    ///
+    /// The state is streamed through a pipe set up with SET_DEVICE_STATE_FD, and the slave
+    /// confirms the transfer with CHECK_DEVICE_STATE.
+    pub fn save_device_state<W: Write>(&mut self, writer: &mut W) -> Result<()> {
+        let (rx, tx) = pipe()?;
+        let slave_fd = self.master.set_device_state_fd(
+            VhostTransferStateDirection::SAVE,
+            VhostTransferStatePhase::STOPPED,
+            &tx,
+        )?;
+        // Close the write end so the end of the state is seen once the slave closes its copy.
+        drop(tx);
+        let mut reader = slave_fd.unwrap_or(rx);
+        std::io::copy(&mut reader, writer).map_err(Error::IOError)?;
+        drop(reader);
+        self.master.check_device_state()
+    }
+
+    /// Load the internal state of the device from `reader`, before starting its queues.
+    pub fn load_device_state<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        let (rx, tx) = pipe()?;
+        let slave_fd = self.master.set_device_state_fd(
+            VhostTransferStateDirection::LOAD,
+            VhostTransferStatePhase::STOPPED,
+            &rx,
+        )?;
+        drop(rx);
+        let mut writer = slave_fd.unwrap_or(tx);
+        std::io::copy(reader, &mut writer).map_err(Error::IOError)?;
+        // Closing the write end tells the slave the whole state has been sent.
+        drop(writer);
+        self.master.check_device_state()
+    }
+
+    /// Stop logging the guest memory written by the slave, and drop the dirty log.
+    pub fn stop_logging(&mut self) -> Result<()> {
+        if self.log.take().is_some() {
+            let features = self.master.acked_features() & !VhostUserVirtioFeatures::LOG_ALL.bits();
+            self.master.set_features(features)?;
+        }
+        Ok(())
+    }
+}
+
+// Create a pipe, returning its read and write ends.
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [-1; 2];
+    // Safe because the array is big enough for the two fds and the return value is checked.
+    let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if ret < 0 {
+        return Err(Error::IOError(std::io::Error::last_os_error()));
+    }
+    // Safe because both fds were just created and are owned by nobody else.
+    unsafe { Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhost_user::connection::Endpoint;
+    use crate::vhost_user::message::*;
+    use crate::vhost_user::take_single_file;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::net::UnixStream;
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    #[derive(Default)]
+    struct Recorder {
+        iterations: usize,
+        bases: Vec<(usize, u32)>,
+        final_bitmap: Vec<u64>,
+        state: Vec<u8>,
+    }
+
+    impl MigrationHandler for Recorder {
+        fn dirty_bitmap(&mut self, bitmap: &[u64]) -> Result<bool> {
+            self.iterations += 1;
+            Ok(bitmap[0] == 0b101)
+        }
+
+        fn vring_stopped(&mut self, queue_index: usize, base: u32) -> Result<()> {
+            self.bases.push((queue_index, base));
+            Ok(())
+        }
+
+        fn final_dirty_bitmap(&mut self, bitmap: &[u64]) -> Result<()> {
+            self.final_bitmap = bitmap.to_vec();
+            Ok(())
+        }
+
+        fn device_state(&mut self, state: &[u8]) -> Result<()> {
+            self.state = state.to_vec();
+            Ok(())
+        }
+    }
+
+    fn reply_u64(peer: &mut Endpoint<MasterReq>, code: MasterReq, value: u64) {
+        let hdr = VhostUserMsgHeader::new(code, 0x4, 8);
+        peer.send_message(&hdr, &VhostUserU64::new(value), None)
+            .unwrap();
+    }
+
+    fn recv_request(
+        peer: &mut Endpoint<MasterReq>,
+        code: MasterReq,
+    ) -> (Vec<u8>, Option<Vec<File>>) {
+        let (hdr, files) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), code);
+        let buf = match hdr.get_size() as usize {
+            0 => Vec::new(),
+            size => peer.recv_data(size).unwrap().1,
+        };
+        (buf, files)
+    }
+
+    #[test]
+    fn test_migration_run() {
+        let (sock, peer_sock) = UnixStream::pair().unwrap();
+        let mut master = Master::from_stream(sock, 1);
+        let mut peer = Endpoint::<MasterReq>::from_stream(peer_sock);
+        let features =
+            VhostUserVirtioFeatures::PROTOCOL_FEATURES | VhostUserVirtioFeatures::LOG_ALL;
+        let protocol_features =
+            VhostUserProtocolFeatures::LOG_SHMFD | VhostUserProtocolFeatures::DEVICE_STATE;
+
+        reply_u64(&mut peer, MasterReq::GET_FEATURES, features.bits());
+        master.get_features().unwrap();
+        master
+            .set_features(VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits())
+            .unwrap();
+        reply_u64(
+            &mut peer,
+            MasterReq::GET_PROTOCOL_FEATURES,
+            protocol_features.bits(),
+        );
+        master.get_protocol_features().unwrap();
+        master.set_protocol_features(protocol_features).unwrap();
+        recv_request(&mut peer, MasterReq::GET_FEATURES);
+        recv_request(&mut peer, MasterReq::SET_FEATURES);
+        recv_request(&mut peer, MasterReq::GET_PROTOCOL_FEATURES);
+        recv_request(&mut peer, MasterReq::SET_PROTOCOL_FEATURES);
+
+        let slave = std::thread::spawn(move || {
+            recv_request(&mut peer, MasterReq::GET_FEATURES);
+            reply_u64(&mut peer, MasterReq::GET_FEATURES, features.bits());
+
+            // Log writes to the first and third pages of guest memory.
+            let (_, files) = recv_request(&mut peer, MasterReq::SET_LOG_BASE);
+            reply_u64(&mut peer, MasterReq::SET_LOG_BASE, 0);
+            let log = take_single_file(files).unwrap();
+            log.write_at(&0b101u64.to_ne_bytes(), 0).unwrap();
+
+            let (buf, _) = recv_request(&mut peer, MasterReq::SET_FEATURES);
+            assert_eq!(buf, features.bits().to_ne_bytes());
+
+            recv_request(&mut peer, MasterReq::GET_VRING_BASE);
+            let hdr = VhostUserMsgHeader::new(MasterReq::GET_VRING_BASE, 0x4, 8);
+            peer.send_message(&hdr, &VhostUserVringState::new(0, 42), None)
+                .unwrap();
+
+            let (_, files) = recv_request(&mut peer, MasterReq::SET_DEVICE_STATE_FD);
+            reply_u64(
+                &mut peer,
+                MasterReq::SET_DEVICE_STATE_FD,
+                VHOST_USER_DEVICE_STATE_NO_FD,
+            );
+            let pipe = take_single_file(files).unwrap();
+            (&pipe).write_all(b"state").unwrap();
+            drop(pipe);
+
+            recv_request(&mut peer, MasterReq::CHECK_DEVICE_STATE);
+            reply_u64(&mut peer, MasterReq::CHECK_DEVICE_STATE, 0);
+        });
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut recorder = Recorder::default();
+        let mut migration = Migration::new(&mut master, &[0]);
+        migration.run(&mem, &mut recorder).unwrap();
+        slave.join().unwrap();
+
+        assert!(recorder.iterations >= 1);
+        assert_eq!(recorder.bases, vec![(0, 42)]);
+        assert!(recorder.final_bitmap.iter().all(|word| *word == 0));
+        assert_eq!(recorder.state, b"state");
+        assert!(master.acked_features() & VhostUserVirtioFeatures::LOG_ALL.bits() != 0);
+    }
+
+    #[test]
+    fn test_migration_requires_log_shmfd() {
+        let (sock, _peer) = UnixStream::pair().unwrap();
+        let mut master = Master::from_stream(sock, 1);
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+
+        let mut migration = Migration::new(&mut master, &[0]);
+        assert!(migration.start_logging(&mem).is_err());
+        assert!(migration.sync_dirty_log().is_err());
+    }
+}
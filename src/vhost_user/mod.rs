@@ -24,33 +24,117 @@ use std::io::Error as IOError;
 pub mod message;
 
 mod connection;
-pub use self::connection::Listener;
+pub use self::connection::{Listener, PeerCheck, PeerCredentials, PeerPolicy};
+pub use self::message::VHOST_LOG_PAGE;
 
 #[cfg(feature = "vhost-user-master")]
 mod master;
 #[cfg(feature = "vhost-user-master")]
-pub use self::master::{Master, VhostUserMaster};
+pub use self::master::{
+    ConnectPolicy, ConnectionStatus, DeviceRequirements, DisconnectHandler, Master,
+    MasterCapabilities, MasterListener, MasterQueue, MasterSnapshot, MasterTracer,
+    MemRegionSnapshot, MessageTrace, NegotiatedFeatures, QueueSetup, RetryPolicy, VhostUserMaster,
+    VringSnapshot,
+};
+#[cfg(feature = "vhost-user-master")]
+mod fs_cache;
+#[cfg(feature = "vhost-user-master")]
+pub use self::fs_cache::{FsCacheWindow, VHOST_USER_FS_UNMAP_ALL};
+#[cfg(feature = "vhost-user-master")]
+mod dirty_log;
+#[cfg(feature = "vhost-user-master")]
+pub use self::dirty_log::DirtyLog;
+#[cfg(feature = "vhost-user-master")]
+mod migration;
+#[cfg(feature = "vhost-user-master")]
+pub use self::migration::{Migration, MigrationHandler};
+#[cfg(feature = "vhost-user-master-async")]
+mod async_master;
+#[cfg(feature = "vhost-user-master-async")]
+pub use self::async_master::AsyncMaster;
+#[cfg(feature = "vhost-user-master-async")]
+mod async_master_req_handler;
+#[cfg(feature = "vhost-user-master-async")]
+pub use self::async_master_req_handler::AsyncMasterReqHandler;
 #[cfg(feature = "vhost-user")]
 mod master_req_handler;
 #[cfg(feature = "vhost-user")]
 pub use self::master_req_handler::{
-    MasterReqHandler, VhostUserMasterReqHandler, VhostUserMasterReqHandlerMut,
+    CustomSlaveReqHandler, DeferredAck, MasterReqHandler, SlaveChannelShutdown, SlaveReqPolicy,
+    SlaveReqStats, SlaveReqTrace, SlaveReqTracer, SlaveRequest, VhostUserMasterReqHandler,
+    VhostUserMasterReqHandlerMut, VringHostNotifier,
+};
+#[cfg(feature = "vhost-user")]
+mod shared_object;
+#[cfg(feature = "vhost-user")]
+pub use self::shared_object::{
+    SharedObject, SharedObjectExporter, SharedObjectHandler, SharedObjectRegistry,
 };
 
 #[cfg(feature = "vhost-user-slave")]
 mod slave;
 #[cfg(feature = "vhost-user-slave")]
-pub use self::slave::SlaveListener;
+pub use self::slave::{SlaveBackendFactory, SlaveListener, SlaveListenerMode};
 #[cfg(feature = "vhost-user-slave")]
 mod slave_req_handler;
 #[cfg(feature = "vhost-user-slave")]
 pub use self::slave_req_handler::{
-    SlaveReqHandler, VhostUserSlaveReqHandler, VhostUserSlaveReqHandlerMut,
+    MasterReqStats, SlaveDeferredAck, SlaveReqHandler, SlaveShutdown, SlowRequestHandler,
+    VhostUserSlaveReqHandler, VhostUserSlaveReqHandlerMut,
+};
+#[cfg(feature = "vhost-user-slave-async")]
+mod async_slave_req_handler;
+#[cfg(feature = "vhost-user-slave-async")]
+pub use self::async_slave_req_handler::{
+    AsyncSlaveReqHandler, HandlerFuture, VhostUserAsyncSlaveReqHandler,
 };
 #[cfg(feature = "vhost-user-slave")]
 mod slave_fs_cache;
 #[cfg(feature = "vhost-user-slave")]
 pub use self::slave_fs_cache::SlaveFsCacheReq;
+#[cfg(feature = "vhost-user-slave")]
+mod dirty_log_writer;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::dirty_log_writer::DirtyLogWriter;
+#[cfg(feature = "vhost-user-slave")]
+mod config_space;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::config_space::ConfigSpace;
+#[cfg(feature = "vhost-user-slave")]
+mod upgrade;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::upgrade::{SlaveUpgradeState, VringUpgradeState};
+#[cfg(feature = "vhost-user-slave")]
+mod inflight;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::inflight::{InflightQueuePacked, InflightQueueSplit, InflightRegion};
+#[cfg(feature = "vhost-user-slave")]
+mod mapped_memory;
+#[cfg(all(feature = "vhost-user-slave", feature = "xen"))]
+pub use self::mapped_memory::XenMmap;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::mapped_memory::{MappedMemory, MappedRegion, MemoryMappingManager};
+#[cfg(feature = "vhost-user-slave")]
+mod userfaultfd;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::userfaultfd::Userfaultfd;
+#[cfg(feature = "vhost-user-worker")]
+mod rate_limiter;
+#[cfg(feature = "vhost-user-worker")]
+pub use self::rate_limiter::{RateLimiter, TokenBucket};
+#[cfg(feature = "vhost-user-worker")]
+mod vring;
+#[cfg(feature = "vhost-user-worker")]
+pub use self::vring::{
+    DescriptorChain, VringDescriptor, VringQueue, VRING_AVAIL_F_NO_INTERRUPT,
+    VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE, VRING_PACKED_DESC_F_AVAIL,
+    VRING_PACKED_DESC_F_USED, VRING_PACKED_EVENT_FLAG_DESC, VRING_PACKED_EVENT_FLAG_DISABLE,
+    VRING_PACKED_EVENT_FLAG_ENABLE, VRING_USED_F_NO_NOTIFY,
+};
+#[cfg(feature = "vhost-user-worker")]
+mod vring_worker;
+#[cfg(feature = "vhost-user-worker")]
+pub use self::vring_worker::{VhostUserBackend, VringThreadConfig, VringWorkerHandler};
 
 /// Errors for vhost-user operations
 #[derive(Debug)]
@@ -75,12 +159,32 @@ pub enum Error {
     SocketBroken(std::io::Error),
     /// Should retry the socket operation again.
     SocketRetry(std::io::Error),
+    /// The peer didn't complete the socket operation before the timeout.
+    Timeout,
+    /// The connection was lost by a previous request, and must be established again.
+    Disconnected,
     /// Failure from the slave side.
     SlaveInternalError,
     /// Failure from the master side.
     MasterInternalError,
     /// Virtio/protocol features mismatch.
     FeatureMismatch,
+    /// The request depends on protocol features which haven't been negotiated with the peer.
+    ProtocolFeatureNotNegotiated(message::VhostUserProtocolFeatures),
+    /// The queue index is beyond the number of queues supported by the peer.
+    QueueIndexOutOfRange(usize, u64),
+    /// The device configuration space range of a request is out of bounds or doesn't match the
+    /// size of its buffer.
+    InvalidConfigRange(u32, u32),
+    /// The slave replied with a different device configuration space range than requested.
+    ConfigReplyMismatch(u32, u32),
+    /// The device configuration space payload of a reply is truncated or oversized.
+    ConfigReplyLength(usize, usize),
+    /// The request was sent before the handshake request it depends on, in strict ordering mode
+    /// on the master side, or received so on the slave side.
+    RequestOutOfOrder(message::MasterReq, message::MasterReq),
+    /// The peer process isn't allowed to connect by the peer policy.
+    PeerRejected(PeerCredentials),
     /// Error from request handler
     ReqHandlerError(IOError),
 }
@@ -98,9 +202,44 @@ impl std::fmt::Display for Error {
             Error::SocketConnect(e) => write!(f, "can't connect to peer: {}", e),
             Error::SocketBroken(e) => write!(f, "socket is broken: {}", e),
             Error::SocketRetry(e) => write!(f, "temporary socket error: {}", e),
+            Error::Timeout => write!(f, "socket operation timed out"),
+            Error::Disconnected => write!(f, "connection to the peer was lost"),
             Error::SlaveInternalError => write!(f, "slave internal error"),
             Error::MasterInternalError => write!(f, "Master internal error"),
             Error::FeatureMismatch => write!(f, "virtio/protocol features mismatch"),
+            Error::ProtocolFeatureNotNegotiated(features) => {
+                write!(f, "protocol features not negotiated: {:?}", features)
+            }
+            Error::QueueIndexOutOfRange(index, num) => {
+                write!(
+                    f,
+                    "queue index {} out of range, {} queues supported",
+                    index, num
+                )
+            }
+            Error::InvalidConfigRange(offset, size) => write!(
+                f,
+                "invalid config space range: offset {:#x}, size {}",
+                offset, size
+            ),
+            Error::ConfigReplyMismatch(offset, size) => write!(
+                f,
+                "config space reply for offset {:#x}, size {} doesn't match the request",
+                offset, size
+            ),
+            Error::ConfigReplyLength(len, expected) => write!(
+                f,
+                "config space reply carries {} bytes instead of {}",
+                len, expected
+            ),
+            Error::RequestOutOfOrder(req, before) => {
+                write!(f, "request {:?} must follow {:?}", req, before)
+            }
+            Error::PeerRejected(cred) => write!(
+                f,
+                "peer rejected: pid {}, uid {}, gid {}",
+                cred.pid, cred.uid, cred.gid
+            ),
             Error::ReqHandlerError(e) => write!(f, "handler failed to handle request: {}", e),
         }
     }
@@ -120,12 +259,22 @@ impl Error {
             Error::SlaveInternalError => true,
             // Master internal error, hope it recovers on reconnect.
             Error::MasterInternalError => true,
+            // Should reconnect because a partial message may be left on the socket.
+            Error::Timeout => true,
+            // Should reconnect because a previous request found the connection broken.
+            Error::Disconnected => true,
             // Should just retry the IO operation instead of rebuilding the underline connection.
             Error::SocketRetry(_) => false,
             Error::InvalidParam | Error::InvalidOperation => false,
             Error::InvalidMessage | Error::IncorrectFds | Error::OversizedMsg => false,
             Error::SocketError(_) | Error::SocketConnect(_) => false,
-            Error::FeatureMismatch => false,
+            Error::FeatureMismatch | Error::ProtocolFeatureNotNegotiated(_) => false,
+            Error::QueueIndexOutOfRange(..) => false,
+            Error::InvalidConfigRange(..)
+            | Error::ConfigReplyMismatch(..)
+            | Error::ConfigReplyLength(..) => false,
+            Error::RequestOutOfOrder(..) => false,
+            Error::PeerRejected(_) => false,
             Error::ReqHandlerError(_) => false,
         }
     }
@@ -186,23 +335,27 @@ pub(crate) fn take_single_file(files: Option<Vec<File>>) -> Option<File> {
     Some(files.swap_remove(0))
 }
 
-#[cfg(all(test, feature = "vhost-user-slave"))]
+#[cfg(all(feature = "vhost-user-slave", any(test, feature = "test-utils")))]
 mod dummy_slave;
+#[cfg(feature = "test-utils")]
+pub use self::dummy_slave::{DummySlaveConfig, DummySlaveReqHandler, PostcopyRegion};
 
 #[cfg(all(test, feature = "vhost-user-master", feature = "vhost-user-slave"))]
 mod tests {
     use std::fs::File;
-    use std::os::unix::io::AsRawFd;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
     use std::path::{Path, PathBuf};
     use std::sync::{Arc, Barrier, Mutex};
     use std::thread;
     use vmm_sys_util::rand::rand_alphanumerics;
     use vmm_sys_util::tempfile::TempFile;
 
-    use super::dummy_slave::{DummySlaveReqHandler, VIRTIO_FEATURES};
+    use super::dummy_slave::{pipe, DummySlaveReqHandler, CUSTOM_ECHO_REQ, VIRTIO_FEATURES};
     use super::message::*;
     use super::*;
-    use crate::backend::VhostBackend;
+    use crate::backend::{VhostFeatureOps, VhostLogOps, VhostMemOps, VhostVringOps};
     use crate::{VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo, VringConfigData};
 
     fn temp_path() -> PathBuf {
@@ -246,6 +399,41 @@ mod tests {
         assert_eq!(slave_be.lock().unwrap().owned, true);
     }
 
+    #[test]
+    fn test_custom_request() {
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let path = temp_path();
+        let (master, mut slave) = create_slave(&path, slave_be);
+
+        let handle = thread::spawn(move || {
+            slave.handle_request().unwrap();
+            assert!(slave.handle_request().is_err());
+        });
+
+        let fd = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        // Safe because the eventfd outlives the borrowed fd.
+        let fds = [unsafe { BorrowedFd::borrow_raw(fd.as_raw_fd()) }];
+        let (payload, files) = master
+            .send_custom_request(CUSTOM_ECHO_REQ, &[1, 2, 3], Some(&fds))
+            .unwrap();
+        assert_eq!(payload, vec![1, 2, 3]);
+        assert_eq!(files.unwrap().len(), 1);
+
+        // Codes of the requests defined by the specification are rejected.
+        master
+            .send_custom_request(MasterReq::GET_FEATURES as u32, &[], None)
+            .unwrap_err();
+
+        // The slave fails requests its backend doesn't know about.
+        master
+            .set_timeout(Some(std::time::Duration::from_millis(100)))
+            .unwrap();
+        master
+            .send_custom_request(CUSTOM_ECHO_REQ + 1, &[], None)
+            .unwrap_err();
+        handle.join().unwrap();
+    }
+
     #[test]
     fn test_set_features() {
         let mbar = Arc::new(Barrier::new(2));
@@ -385,20 +573,16 @@ mod tests {
             .unwrap();
         // Set the buffer back to the backend
         master
-            .set_inflight_fd(&inflight_info, inflight_file.as_raw_fd())
+            .set_inflight_fd(&inflight_info, inflight_file.as_fd())
             .unwrap();
 
         let num = master.get_queue_num().unwrap();
         assert_eq!(num, 2);
 
         let eventfd = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
-        let mem = [VhostUserMemoryRegionInfo {
-            guest_phys_addr: 0,
-            memory_size: 0x10_0000,
-            userspace_addr: 0,
-            mmap_offset: 0,
-            mmap_handle: eventfd.as_raw_fd(),
-        }];
+        // Safe because the eventfd outlives the borrowed fd.
+        let fd = unsafe { BorrowedFd::borrow_raw(eventfd.as_raw_fd()) };
+        let mem = [VhostUserMemoryRegionInfo::new(0, 0x10_0000, 0, 0, Some(fd))];
         master.set_mem_table(&mem).unwrap();
 
         master
@@ -412,20 +596,22 @@ mod tests {
         assert_eq!(offset, 0x100);
         assert_eq!(reply_payload[0], 0xa5);
 
-        master.set_slave_request_fd(&eventfd).unwrap();
+        let (slave_req_sock, _) = UnixStream::pair().unwrap();
+        master.set_slave_request_fd(&slave_req_sock).unwrap();
         master.set_vring_enable(0, true).unwrap();
 
+        // The slave fails to map the eventfd as a log, and reports it in its reply.
         master
             .set_log_base(
                 0,
                 Some(VhostUserDirtyLogRegion {
                     mmap_size: 0x1000,
                     mmap_offset: 0,
-                    mmap_handle: eventfd.as_raw_fd(),
+                    mmap_handle: fd,
                 }),
             )
-            .unwrap();
-        master.set_log_fd(eventfd.as_raw_fd()).unwrap();
+            .unwrap_err();
+        master.set_log_fd(fd).unwrap();
 
         master.set_vring_num(0, 256).unwrap();
         master.set_vring_base(0, 0).unwrap();
@@ -439,21 +625,16 @@ mod tests {
             log_addr: Some(0x4000),
         };
         master.set_vring_addr(0, &config).unwrap();
-        master.set_vring_call(0, &eventfd).unwrap();
-        master.set_vring_kick(0, &eventfd).unwrap();
-        master.set_vring_err(0, &eventfd).unwrap();
+        master.set_vring_call(0, Some(&eventfd)).unwrap();
+        master.set_vring_kick(0, Some(&eventfd)).unwrap();
+        master.set_vring_err(0, Some(&eventfd)).unwrap();
 
         let max_mem_slots = master.get_max_mem_slots().unwrap();
         assert_eq!(max_mem_slots, 32);
 
         let region_file: File = TempFile::new().unwrap().into_file();
-        let region = VhostUserMemoryRegionInfo {
-            guest_phys_addr: 0x10_0000,
-            memory_size: 0x10_0000,
-            userspace_addr: 0,
-            mmap_offset: 0,
-            mmap_handle: region_file.as_raw_fd(),
-        };
+        let region =
+            VhostUserMemoryRegionInfo::new(0x10_0000, 0x10_0000, 0, 0, Some(region_file.as_fd()));
         master.add_mem_region(&region).unwrap();
 
         master.remove_mem_region(&region).unwrap();
@@ -461,10 +642,396 @@ mod tests {
         mbar.wait();
     }
 
+    #[test]
+    fn test_inflight_region() {
+        let path = temp_path();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(&path, slave_be.clone());
+        slave.set_track_inflight(true);
+
+        let handle = thread::spawn(move || {
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+            let region = slave_be.lock().unwrap().inflight_region.take().unwrap();
+            assert_eq!(region.num_queues(), 2);
+            assert_eq!(region.queue_size(), 256);
+            assert!(!region.is_packed());
+            let addr = region.queue_region_addr(1).unwrap();
+            // Safe because the header of the queue region is within the mapping.
+            let header = unsafe { std::ptr::read_unaligned(addr as *const QueueRegionSplit) };
+            assert_eq!({ header.desc_num }, 256);
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        master.set_protocol_features(features).unwrap();
+
+        let request = VhostUserInflight::new(0, 0, 2, 256);
+        let (inflight, file) = master.get_inflight_fd(&request).unwrap();
+        assert_eq!(inflight.mmap_size, request.split_mmap_size());
+        assert_eq!(file.metadata().unwrap().len(), inflight.mmap_size);
+        master.set_inflight_fd(&inflight, file.as_fd()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_mapped_mem_regions() {
+        let path = temp_path();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(&path, slave_be.clone());
+        slave.set_map_memory(true);
+
+        let region_file: File = TempFile::new().unwrap().into_file();
+        region_file.set_len(0x20_0000).unwrap();
+        let handle = thread::spawn(move || {
+            for _ in 0..5 {
+                slave.handle_request().unwrap();
+            }
+
+            // set_mem_table()
+            slave.handle_request().unwrap();
+            assert_eq!(slave_be.lock().unwrap().mapped_regions.len(), 1);
+
+            // add_mem_region() only maps the new region.
+            let first = slave_be.lock().unwrap().mapped_regions[0].clone();
+            slave.handle_request().unwrap();
+            assert_eq!(slave_be.lock().unwrap().mapped_regions.len(), 2);
+            assert!(Arc::ptr_eq(
+                &slave_be.lock().unwrap().mapped_regions[0],
+                &first
+            ));
+            let memory = slave.mapped_memory().unwrap();
+            assert_eq!(
+                memory.gpa_to_hva(0x10_0010),
+                Some(memory.regions()[1].host_addr() + 0x10)
+            );
+            assert_eq!(
+                memory.vmm_va_to_hva(0x20_0010),
+                Some(memory.regions()[1].host_addr() + 0x10)
+            );
+
+            // Overlapping regions are rejected.
+            slave.handle_request().unwrap_err();
+
+            // remove_mem_region()
+            slave.handle_request().unwrap();
+            assert_eq!(slave_be.lock().unwrap().mapped_regions.len(), 1);
+            assert!(slave
+                .mapped_memory()
+                .unwrap()
+                .gpa_to_hva(0x10_0010)
+                .is_none());
+            slave.handle_request().unwrap_err();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        master.set_protocol_features(features).unwrap();
+
+        let mem = [VhostUserMemoryRegionInfo::new(
+            0,
+            0x10_0000,
+            0,
+            0,
+            Some(region_file.as_fd()),
+        )];
+        master.set_mem_table(&mem).unwrap();
+        let region = VhostUserMemoryRegionInfo::new(
+            0x10_0000,
+            0x10_0000,
+            0x20_0000,
+            0x10_0000,
+            Some(region_file.as_fd()),
+        );
+        master.add_mem_region(&region).unwrap();
+        let overlap =
+            VhostUserMemoryRegionInfo::new(0x8_0000, 0x10_0000, 0, 0, Some(region_file.as_fd()));
+        master.add_mem_region(&overlap).unwrap();
+        master.remove_mem_region(&region).unwrap();
+        master.remove_mem_region(&region).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_postcopy() {
+        // Userfaultfd may be restricted to privileged processes.
+        if Userfaultfd::new().is_err() {
+            return;
+        }
+        let path = temp_path();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(&path, slave_be.clone());
+
+        let handle = thread::spawn(move || {
+            // Feature negotiation.
+            for _ in 0..5 {
+                slave.handle_request().unwrap();
+            }
+
+            // Postcopy mode is only entered once advised.
+            slave.handle_request().unwrap_err();
+            for _ in 0..2 {
+                slave.handle_request().unwrap();
+            }
+            assert!(slave_be.lock().unwrap().postcopy_listening);
+
+            // set_mem_table_postcopy(), add_mem_region_postcopy()
+            slave.handle_request().unwrap();
+            slave.handle_request().unwrap();
+            assert_eq!(slave_be.lock().unwrap().postcopy_regions.len(), 2);
+
+            slave.handle_request().unwrap();
+            assert!(!slave_be.lock().unwrap().postcopy_listening);
+            slave.handle_request().unwrap_err();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        master.set_protocol_features(features).unwrap();
+
+        master.postcopy_listen().unwrap_err();
+        master.postcopy_advise().unwrap();
+        master.postcopy_listen().unwrap();
+
+        let region_file: File = TempFile::new().unwrap().into_file();
+        let mem = [VhostUserMemoryRegionInfo::new(
+            0,
+            0x10_0000,
+            0,
+            0,
+            Some(region_file.as_fd()),
+        )];
+        let addrs = master.set_mem_table_postcopy(&mem).unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_ne!(addrs[0], 0);
+        let region =
+            VhostUserMemoryRegionInfo::new(0x10_0000, 0x10_0000, 0, 0, Some(region_file.as_fd()));
+        assert_ne!(master.add_mem_region_postcopy(&region).unwrap(), 0);
+
+        master.postcopy_end().unwrap();
+        master.postcopy_end().unwrap_err();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_postcopy_mapped_memory() {
+        // Userfaultfd may be restricted to privileged processes.
+        if Userfaultfd::new().is_err() {
+            return;
+        }
+        let path = temp_path();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(&path, slave_be.clone());
+        slave.set_map_memory(true);
+
+        // Missing pages of shared mappings are only reported for shmem files.
+        // Safe because the name is NUL terminated, and the return value is checked.
+        let fd = unsafe { libc::memfd_create(b"guest\0".as_ptr() as *const libc::c_char, 0) };
+        assert!(fd >= 0);
+        // Safe because the fd was just created and is owned by nobody else.
+        let region_file = unsafe { File::from_raw_fd(fd) };
+        region_file.set_len(0x20_0000).unwrap();
+
+        let handle = thread::spawn(move || {
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+
+            // The handler maps and registers the regions instead of the backend.
+            slave.handle_request().unwrap();
+            slave.handle_request().unwrap();
+            assert!(slave_be.lock().unwrap().postcopy_regions.is_empty());
+            assert_eq!(slave_be.lock().unwrap().mapped_regions.len(), 2);
+            let hvas: Vec<u64> = slave
+                .mapped_memory()
+                .unwrap()
+                .regions()
+                .iter()
+                .map(|region| region.host_addr())
+                .collect();
+
+            slave.handle_request().unwrap();
+            hvas
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        master.set_protocol_features(features).unwrap();
+        master.postcopy_advise().unwrap();
+        master.postcopy_listen().unwrap();
+
+        let mem = [VhostUserMemoryRegionInfo::new(
+            0,
+            0x10_0000,
+            0,
+            0,
+            Some(region_file.as_fd()),
+        )];
+        let addrs = master.set_mem_table_postcopy(&mem).unwrap();
+        let region = VhostUserMemoryRegionInfo::new(
+            0x10_0000,
+            0x10_0000,
+            0x10_0000,
+            0x10_0000,
+            Some(region_file.as_fd()),
+        );
+        let addr = master.add_mem_region_postcopy(&region).unwrap();
+        master.postcopy_end().unwrap();
+        assert_eq!(handle.join().unwrap(), vec![addrs[0], addr]);
+    }
+
+    #[test]
+    fn test_status() {
+        let path = temp_path();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(&path, slave_be.clone());
+
+        let handle = thread::spawn(move || {
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().status, 0xf);
+            for _ in 0..2 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().status, 0);
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        master.set_protocol_features(features).unwrap();
+
+        master.set_status(0xf).unwrap();
+        assert_eq!(master.get_status().unwrap(), 0xf);
+        master.set_status(0).unwrap();
+        assert_eq!(master.get_status().unwrap(), 0);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_reset_device() {
+        let path = temp_path();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(&path, slave_be.clone());
+
+        let handle = thread::spawn(move || {
+            // Feature negotiation, set_status(), get_queue_num(), set_vring_kick(),
+            // set_vring_enable()
+            for _ in 0..9 {
+                slave.handle_request().unwrap();
+            }
+            assert!(slave_be.lock().unwrap().vring_enabled[0]);
+            slave.handle_request().unwrap();
+            let backend = slave_be.lock().unwrap();
+            assert!(backend.owned);
+            assert!(!backend.vring_started[0]);
+            assert!(!backend.vring_enabled[0]);
+            assert!(backend.kick_fd[0].is_none());
+            assert_eq!(backend.status, 0);
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        master.set_protocol_features(features).unwrap();
+
+        master.set_status(0xf).unwrap();
+        let eventfd = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master.set_vring_kick(0, Some(&eventfd)).unwrap();
+        master.set_vring_enable(0, true).unwrap();
+        master.reset_device().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_device_state() {
+        let path = temp_path();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        slave_be.lock().unwrap().device_state = b"saved".to_vec();
+        let (mut master, mut slave) = create_slave(&path, slave_be.clone());
+
+        let handle = thread::spawn(move || {
+            for _ in 0..9 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().device_state, b"loaded");
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let features = master.get_protocol_features().unwrap();
+        master.set_protocol_features(features).unwrap();
+
+        // The slave writes its state to the pipe of the master.
+        let (mut rx, tx) = pipe().unwrap();
+        let file = master
+            .set_device_state_fd(
+                VhostTransferStateDirection::SAVE,
+                VhostTransferStatePhase::STOPPED,
+                &tx,
+            )
+            .unwrap();
+        assert!(file.is_none());
+        drop(tx);
+        let mut state = Vec::new();
+        rx.read_to_end(&mut state).unwrap();
+        assert_eq!(state, b"saved");
+        master.check_device_state().unwrap();
+
+        // The slave reads the state from its own pipe.
+        let (rx, _tx) = pipe().unwrap();
+        let mut file = master
+            .set_device_state_fd(
+                VhostTransferStateDirection::LOAD,
+                VhostTransferStatePhase::STOPPED,
+                &rx,
+            )
+            .unwrap()
+            .unwrap();
+        file.write_all(b"loaded").unwrap();
+        drop(file);
+        master.check_device_state().unwrap();
+        handle.join().unwrap();
+    }
+
     #[test]
     fn test_error_display() {
         assert_eq!(format!("{}", Error::InvalidParam), "invalid parameters");
         assert_eq!(format!("{}", Error::InvalidOperation), "invalid operation");
+        assert_eq!(
+            format!(
+                "{}",
+                Error::ProtocolFeatureNotNegotiated(message::VhostUserProtocolFeatures::CONFIG)
+            ),
+            "protocol features not negotiated: CONFIG"
+        );
+        assert_eq!(
+            format!("{}", Error::QueueIndexOutOfRange(2, 2)),
+            "queue index 2 out of range, 2 queues supported"
+        );
+        let cred = PeerCredentials {
+            pid: 1,
+            uid: 2,
+            gid: 3,
+            security_context: None,
+        };
+        assert_eq!(
+            format!("{}", Error::PeerRejected(cred)),
+            "peer rejected: pid 1, uid 2, gid 3"
+        );
     }
 
     #[test]
@@ -472,12 +1039,19 @@ mod tests {
         assert_eq!(Error::PartialMessage.should_reconnect(), true);
         assert_eq!(Error::SlaveInternalError.should_reconnect(), true);
         assert_eq!(Error::MasterInternalError.should_reconnect(), true);
+        assert_eq!(Error::Timeout.should_reconnect(), true);
         assert_eq!(Error::InvalidParam.should_reconnect(), false);
         assert_eq!(Error::InvalidOperation.should_reconnect(), false);
         assert_eq!(Error::InvalidMessage.should_reconnect(), false);
         assert_eq!(Error::IncorrectFds.should_reconnect(), false);
         assert_eq!(Error::OversizedMsg.should_reconnect(), false);
         assert_eq!(Error::FeatureMismatch.should_reconnect(), false);
+        assert_eq!(
+            Error::ProtocolFeatureNotNegotiated(message::VhostUserProtocolFeatures::MQ)
+                .should_reconnect(),
+            false
+        );
+        assert_eq!(Error::QueueIndexOutOfRange(2, 2).should_reconnect(), false);
     }
 
     #[test]
@@ -0,0 +1,187 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Token bucket rate limiting of the queues processed by the built-in vring workers.
+
+use std::time::{Duration, Instant};
+
+use super::{Error, Result};
+
+/// Bucket of tokens refilled at a constant rate, up to its size.
+///
+/// Consuming more tokens than available is allowed, the bucket staying empty until the debt is
+/// refilled, so requests larger than the bucket are still processed eventually.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    size: u64,
+    refill_time: Duration,
+    // tokens available, negative when in debt
+    budget: i128,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket of `size` tokens, refilled from empty in `refill_time`.
+    pub fn new(size: u64, refill_time: Duration) -> Result<Self> {
+        if size == 0 || refill_time.as_nanos() == 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(TokenBucket {
+            size,
+            refill_time,
+            budget: i128::from(size),
+            last_update: Instant::now(),
+        })
+    }
+
+    /// Get the size of the bucket.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the time to refill the bucket from empty.
+    pub fn refill_time(&self) -> Duration {
+        self.refill_time
+    }
+
+    /// Get the number of tokens available, negative when in debt.
+    pub fn budget(&mut self) -> i64 {
+        self.refill(Instant::now());
+        self.budget as i64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let size = u128::from(self.size);
+        let refill_ns = self.refill_time.as_nanos();
+        let elapsed = now.saturating_duration_since(self.last_update).as_nanos();
+        let tokens = elapsed.saturating_mul(size) / refill_ns;
+        let missing = (i128::from(self.size) - self.budget) as u128;
+        if tokens >= missing {
+            self.budget = i128::from(self.size);
+            self.last_update = now;
+        } else if tokens > 0 {
+            self.budget += tokens as i128;
+            // Only the time of the refilled tokens is accounted for, not to lose the remainder.
+            self.last_update += Duration::from_nanos((tokens * refill_ns / size) as u64);
+        }
+    }
+
+    fn consume(&mut self, tokens: u64) {
+        self.budget -= i128::from(tokens);
+    }
+
+    // Time until a token is available, if none is.
+    fn delay(&self, now: Instant) -> Option<Duration> {
+        if self.budget > 0 {
+            return None;
+        }
+        let size = u128::from(self.size);
+        let missing = (1 - self.budget) as u128;
+        // Rounded up, so the tokens are refilled once the delay elapsed.
+        let refill = (missing * self.refill_time.as_nanos() - 1) / size + 1;
+        let elapsed = now.saturating_duration_since(self.last_update).as_nanos();
+        let delay = refill.saturating_sub(elapsed).max(1);
+        Some(Duration::from_nanos(delay.min(u128::from(u64::MAX)) as u64))
+    }
+}
+
+/// Rate limiter of the requests popped from a queue, in operations and in bytes of their buffers.
+///
+/// Requests are popped as long as both buckets hold tokens, then consume a token of the
+/// operations bucket and a token per byte of the bytes bucket.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter of the operations with `ops`, and of the bytes with `bytes`.
+    pub fn new(ops: Option<TokenBucket>, bytes: Option<TokenBucket>) -> Self {
+        RateLimiter { ops, bytes }
+    }
+
+    /// Get the bucket limiting the operations.
+    pub fn ops(&self) -> Option<&TokenBucket> {
+        self.ops.as_ref()
+    }
+
+    /// Get the bucket limiting the bytes.
+    pub fn bytes(&self) -> Option<&TokenBucket> {
+        self.bytes.as_ref()
+    }
+
+    /// Check whether a bucket is out of tokens, after refilling them.
+    pub fn is_blocked(&mut self) -> bool {
+        self.delay().is_some()
+    }
+
+    /// Account for `ops` requests of `bytes` bytes.
+    pub fn consume(&mut self, ops: u64, bytes: u64) {
+        if let Some(bucket) = self.ops.as_mut() {
+            bucket.consume(ops);
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.consume(bytes);
+        }
+    }
+
+    /// Get the time until both buckets hold tokens again, if a bucket is out of tokens.
+    pub fn delay(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let mut delay = None;
+        for bucket in self.ops.iter_mut().chain(self.bytes.iter_mut()) {
+            bucket.refill(now);
+            delay = delay.max(bucket.delay(now));
+        }
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        TokenBucket::new(0, Duration::from_secs(1)).unwrap_err();
+        TokenBucket::new(1, Duration::from_secs(0)).unwrap_err();
+
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, Duration::from_secs(1)).unwrap();
+        bucket.last_update = start;
+        assert_eq!(bucket.size(), 100);
+        assert_eq!(bucket.refill_time(), Duration::from_secs(1));
+        assert!(bucket.delay(start).is_none());
+
+        // Debts are refilled before tokens are available again.
+        bucket.consume(150);
+        assert_eq!(bucket.budget, -50);
+        assert_eq!(bucket.delay(start), Some(Duration::from_millis(510)));
+        bucket.refill(start + Duration::from_millis(255));
+        assert_eq!(bucket.budget, -25);
+        assert_eq!(
+            bucket.delay(start + Duration::from_millis(255)),
+            Some(Duration::from_millis(255))
+        );
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.budget, 100);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::default();
+        limiter.consume(1000, 1000);
+        assert!(!limiter.is_blocked());
+
+        let ops = TokenBucket::new(2, Duration::from_secs(100)).unwrap();
+        let bytes = TokenBucket::new(0x1000, Duration::from_secs(100)).unwrap();
+        let mut limiter = RateLimiter::new(Some(ops), Some(bytes));
+        limiter.consume(1, 0x1000);
+        assert!(limiter.is_blocked());
+        assert_eq!(limiter.ops().unwrap().budget, 1);
+        assert_eq!(limiter.bytes().unwrap().budget, 0);
+        let delay = limiter.delay().unwrap();
+        assert!(delay > Duration::from_millis(20) && delay <= Duration::from_millis(25));
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Registry of the virtio objects shared between devices, such as dmabufs of GPU and media devices.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Error as IOError, Result as IOResult};
+use std::os::unix::io::AsFd;
+use std::sync::{Arc, Mutex};
+
+use super::master_req_handler::{VhostUserMasterReqHandler, VringHostNotifier};
+use super::message::{VhostUserFSSlaveMsg, VhostUserMMap, VhostUserShared};
+use super::HandlerResult;
+
+/// Callback exporting an object owned by a vhost-user backend, usually by sending a
+/// GET_SHARED_OBJECT request to the backend.
+pub type SharedObjectExporter = Arc<dyn Fn(&VhostUserShared) -> IOResult<File> + Send + Sync>;
+
+/// Virtio object registered in a [SharedObjectRegistry].
+///
+/// [SharedObjectRegistry]: struct.SharedObjectRegistry.html
+pub enum SharedObject {
+    /// Object exported by the VMM as a file, such as a dmabuf.
+    File(File),
+    /// Object owned by a vhost-user backend, exported on lookup.
+    Backend(SharedObjectExporter),
+}
+
+/// Registry mapping the UUIDs of virtio shared objects to the objects.
+///
+/// The registry is shared by all devices of a VMM, so a device may look up the objects exported
+/// by the others.
+#[derive(Default)]
+pub struct SharedObjectRegistry {
+    objects: Mutex<HashMap<[u8; 16], SharedObject>>,
+}
+
+impl SharedObjectRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `object` under `uuid`.
+    ///
+    /// Return false, leaving the registry unchanged, if `uuid` is already registered.
+    pub fn add(&self, uuid: [u8; 16], object: SharedObject) -> bool {
+        let mut objects = self.objects.lock().unwrap();
+        if objects.contains_key(&uuid) {
+            return false;
+        }
+        objects.insert(uuid, object);
+        true
+    }
+
+    /// Unregister the object of `uuid`, returning it.
+    pub fn remove(&self, uuid: &[u8; 16]) -> Option<SharedObject> {
+        self.objects.lock().unwrap().remove(uuid)
+    }
+
+    /// Check whether an object is registered under `uuid`.
+    pub fn contains(&self, uuid: &[u8; 16]) -> bool {
+        self.objects.lock().unwrap().contains_key(uuid)
+    }
+
+    /// Get a file of the object registered under `uuid`.
+    ///
+    /// Fail with `ENOENT` if no object is registered under `uuid`.
+    pub fn lookup(&self, uuid: &[u8; 16]) -> IOResult<File> {
+        let exporter = match self.objects.lock().unwrap().get(uuid) {
+            Some(SharedObject::File(file)) => return file.try_clone(),
+            Some(SharedObject::Backend(exporter)) => exporter.clone(),
+            None => return Err(IOError::from_raw_os_error(libc::ENOENT)),
+        };
+        // The exporter may talk to its backend, so it's called without the lock held.
+        exporter(&VhostUserShared::new(*uuid))
+    }
+}
+
+/// Handler of the SHARED_OBJECT_ADD, SHARED_OBJECT_REMOVE and SHARED_OBJECT_LOOKUP requests of a
+/// slave, backed by a [SharedObjectRegistry].
+///
+/// The objects added by the slave are registered with the exporter of the slave, and are only
+/// removable by the slave. They are unregistered when the handler is dropped, so the objects of
+/// a disconnected slave don't outlive it.
+///
+/// The handler forwards the other requests to `backend`, and calls its shared object methods
+/// after updating the registry, so the VMM is notified of the objects of the slave. Errors of the
+/// default `ENOSYS` implementations of these methods are ignored. Lookups of objects missing from
+/// the registry are forwarded to `backend` too.
+///
+/// [SharedObjectRegistry]: struct.SharedObjectRegistry.html
+pub struct SharedObjectHandler<S: VhostUserMasterReqHandler> {
+    backend: S,
+    registry: Arc<SharedObjectRegistry>,
+    exporter: Option<SharedObjectExporter>,
+    owned: Mutex<HashSet<[u8; 16]>>,
+}
+
+impl<S: VhostUserMasterReqHandler> SharedObjectHandler<S> {
+    /// Create a handler registering the objects of the slave in `registry`.
+    ///
+    /// The objects added by the slave are exported by `exporter`, without exporter the
+    /// SHARED_OBJECT_ADD requests fail with `ENOTSUP`.
+    pub fn new(
+        backend: S,
+        registry: Arc<SharedObjectRegistry>,
+        exporter: Option<SharedObjectExporter>,
+    ) -> Self {
+        SharedObjectHandler {
+            backend,
+            registry,
+            exporter,
+            owned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Get the backend of the handler.
+    pub fn backend(&self) -> &S {
+        &self.backend
+    }
+
+    /// Get the registry of the handler.
+    pub fn registry(&self) -> &Arc<SharedObjectRegistry> {
+        &self.registry
+    }
+
+    // Call a notification method of the backend, ignoring unimplemented methods.
+    fn notify(res: HandlerResult<u64>) -> HandlerResult<u64> {
+        match res {
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => Ok(0),
+            res => res,
+        }
+    }
+}
+
+impl<S: VhostUserMasterReqHandler> Drop for SharedObjectHandler<S> {
+    fn drop(&mut self) {
+        for uuid in self.owned.get_mut().unwrap().drain() {
+            self.registry.remove(&uuid);
+        }
+    }
+}
+
+impl<S: VhostUserMasterReqHandler> VhostUserMasterReqHandler for SharedObjectHandler<S> {
+    fn handle_config_change(&self) -> HandlerResult<u64> {
+        self.backend.handle_config_change()
+    }
+
+    fn fs_slave_map(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
+        self.backend.fs_slave_map(fs, fd)
+    }
+
+    fn fs_slave_unmap(&self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        self.backend.fs_slave_unmap(fs)
+    }
+
+    fn fs_slave_sync(&self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        self.backend.fs_slave_sync(fs)
+    }
+
+    fn fs_slave_io(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
+        self.backend.fs_slave_io(fs, fd)
+    }
+
+    fn shared_object_add(&self, uuid: &VhostUserShared) -> HandlerResult<u64> {
+        let exporter = match &self.exporter {
+            Some(exporter) => exporter.clone(),
+            None => return Err(IOError::from_raw_os_error(libc::ENOTSUP)),
+        };
+        let mut owned = self.owned.lock().unwrap();
+        if !self
+            .registry
+            .add(uuid.uuid, SharedObject::Backend(exporter))
+        {
+            return Err(IOError::from_raw_os_error(libc::EEXIST));
+        }
+        owned.insert(uuid.uuid);
+        Self::notify(self.backend.shared_object_add(uuid))
+    }
+
+    fn shared_object_remove(&self, uuid: &VhostUserShared) -> HandlerResult<u64> {
+        let mut owned = self.owned.lock().unwrap();
+        if !owned.remove(&uuid.uuid) {
+            return Err(IOError::from_raw_os_error(libc::ENOENT));
+        }
+        self.registry.remove(&uuid.uuid);
+        Self::notify(self.backend.shared_object_remove(uuid))
+    }
+
+    fn shared_object_lookup(&self, uuid: &VhostUserShared) -> HandlerResult<File> {
+        match self.registry.lookup(&uuid.uuid) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                self.backend.shared_object_lookup(uuid)
+            }
+            res => res,
+        }
+    }
+
+    fn shmem_map(&self, req: &VhostUserMMap, fd: &dyn AsFd) -> HandlerResult<u64> {
+        self.backend.shmem_map(req, fd)
+    }
+
+    fn shmem_unmap(&self, req: &VhostUserMMap) -> HandlerResult<u64> {
+        self.backend.shmem_unmap(req)
+    }
+
+    fn set_vring_host_notifier(
+        &self,
+        queue_index: usize,
+        notifier: Option<VringHostNotifier>,
+    ) -> HandlerResult<u64> {
+        self.backend.set_vring_host_notifier(queue_index, notifier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[derive(Default)]
+    struct MockBackend {
+        added: AtomicUsize,
+    }
+
+    impl VhostUserMasterReqHandler for MockBackend {
+        fn shared_object_add(&self, _uuid: &VhostUserShared) -> HandlerResult<u64> {
+            self.added.fetch_add(1, Ordering::SeqCst);
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_shared_object_registry() {
+        let registry = Arc::new(SharedObjectRegistry::new());
+        let dmabuf = TempFile::new().unwrap().into_file();
+        assert!(registry.add([1; 16], SharedObject::File(dmabuf)));
+        assert!(!registry.add(
+            [1; 16],
+            SharedObject::File(TempFile::new().unwrap().into_file())
+        ));
+
+        let exporter: SharedObjectExporter =
+            Arc::new(|_: &VhostUserShared| Ok(TempFile::new().unwrap().into_file()));
+        let handler =
+            SharedObjectHandler::new(MockBackend::default(), registry.clone(), Some(exporter));
+        let uuid = VhostUserShared::new([2; 16]);
+        handler.shared_object_add(&uuid).unwrap();
+        assert_eq!(handler.backend().added.load(Ordering::SeqCst), 1);
+        handler.shared_object_add(&uuid).unwrap_err();
+        handler
+            .shared_object_add(&VhostUserShared::new([1; 16]))
+            .unwrap_err();
+
+        // Objects of the VMM and of the slave are both looked up.
+        handler
+            .shared_object_lookup(&VhostUserShared::new([1; 16]))
+            .unwrap();
+        handler.shared_object_lookup(&uuid).unwrap();
+        handler
+            .shared_object_lookup(&VhostUserShared::new([3; 16]))
+            .unwrap_err();
+
+        // Only objects of the slave are removable, the default notification is ignored.
+        handler
+            .shared_object_remove(&VhostUserShared::new([1; 16]))
+            .unwrap_err();
+        handler.shared_object_remove(&uuid).unwrap();
+        assert!(!registry.contains(&uuid.uuid));
+
+        // Objects of the slave are removed with the handler.
+        handler.shared_object_add(&uuid).unwrap();
+        drop(handler);
+        assert!(!registry.contains(&uuid.uuid));
+        assert!(registry.contains(&[1; 16]));
+
+        let handler = SharedObjectHandler::new(MockBackend::default(), registry, None);
+        handler.shared_object_add(&uuid).unwrap_err();
+    }
+}
@@ -5,38 +5,147 @@
 
 use std::sync::Arc;
 
-use super::connection::{Endpoint, Listener};
+use super::connection::{Endpoint, Listener, PeerPolicy};
 use super::message::*;
 use super::{Result, SlaveReqHandler, VhostUserSlaveReqHandler};
 
+/// Callback creating the backend serving a new master connection, given the connection id.
+pub type SlaveBackendFactory<S> = Box<dyn FnMut(u64) -> Result<Arc<S>> + Send>;
+
+/// How a [SlaveListener] handles masters connecting while another master is connected.
+///
+/// [SlaveListener]: struct.SlaveListener.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlaveListenerMode {
+    /// A new master replaces the active one: the connection of the active master is shut down,
+    /// so its handler fails and may be dropped.
+    #[default]
+    Replace,
+    /// Masters are served concurrently, each by its own handler.
+    Concurrent,
+}
+
 /// Vhost-user slave side connection listener.
+///
+/// The listener keeps accepting connections, so a slave survives the reconnections of the
+/// master, or serves several masters in [SlaveListenerMode::Concurrent] mode. Each connection
+/// is identified by an id, passed to [VhostUserSlaveReqHandler::master_connected()] when the
+/// connection becomes active.
+///
+/// [SlaveListenerMode::Concurrent]: enum.SlaveListenerMode.html#variant.Concurrent
+/// [VhostUserSlaveReqHandler::master_connected()]: trait.VhostUserSlaveReqHandler.html#method.master_connected
 pub struct SlaveListener<S: VhostUserSlaveReqHandler> {
     listener: Listener,
-    backend: Option<Arc<S>>,
+    backend: Arc<S>,
+    factory: Option<SlaveBackendFactory<S>>,
+    mode: SlaveListenerMode,
+    peer_policy: Option<PeerPolicy>,
+    next_id: u64,
+    active: Option<(u64, Endpoint<MasterReq>)>,
 }
 
 /// Sets up a listener for incoming master connections, and handles construction
 /// of a Slave on success.
 impl<S: VhostUserSlaveReqHandler> SlaveListener<S> {
     /// Create a unix domain socket for incoming master connections.
+    ///
+    /// The listener may be created from a socket passed by systemd, see
+    /// [Listener::from_systemd()].
+    ///
+    /// [Listener::from_systemd()]: struct.Listener.html#method.from_systemd
     pub fn new(listener: Listener, backend: Arc<S>) -> Result<Self> {
         Ok(SlaveListener {
             listener,
-            backend: Some(backend),
+            backend,
+            factory: None,
+            mode: SlaveListenerMode::default(),
+            peer_policy: None,
+            next_id: 0,
+            active: None,
         })
     }
 
+    /// Set how masters connecting while another master is connected are handled.
+    pub fn set_mode(&mut self, mode: SlaveListenerMode) {
+        self.mode = mode;
+    }
+
+    /// Get how masters connecting while another master is connected are handled.
+    pub fn mode(&self) -> SlaveListenerMode {
+        self.mode
+    }
+
+    /// Set the policy of the processes allowed to connect, `None` accepting any process.
+    ///
+    /// The credentials of each master are checked when its connection is accepted, before the
+    /// connection is assigned an id or replaces the active one.
+    pub fn set_peer_policy(&mut self, policy: Option<PeerPolicy>) {
+        self.peer_policy = policy;
+    }
+
+    /// Create the backend of each new connection with `factory`, instead of sharing the backend
+    /// passed to [Self::new()] between the connections.
+    ///
+    /// [Self::new()]: struct.SlaveListener.html#method.new
+    pub fn set_backend_factory(&mut self, factory: Option<SlaveBackendFactory<S>>) {
+        self.factory = factory;
+    }
+
+    /// Get the id of the connection of the active master, in [SlaveListenerMode::Replace] mode.
+    ///
+    /// [SlaveListenerMode::Replace]: enum.SlaveListenerMode.html#variant.Replace
+    pub fn active_connection(&self) -> Option<u64> {
+        self.active.as_ref().map(|(id, _)| *id)
+    }
+
     /// Accept an incoming connection from the master, returning Some(Slave) on
     /// success, or None if the socket is nonblocking and no incoming connection
     /// was detected
     pub fn accept(&mut self) -> Result<Option<SlaveReqHandler<S>>> {
-        if let Some(fd) = self.listener.accept()? {
-            return Ok(Some(SlaveReqHandler::new(
-                Endpoint::<MasterReq>::from_stream(fd),
-                self.backend.take().unwrap(),
-            )));
+        Ok(self.accept_connection()?.map(|(_, handler)| handler))
+    }
+
+    /// Accept an incoming connection from the master, returning the id of the connection along
+    /// with its handler, or None if the socket is nonblocking and no incoming connection was
+    /// detected.
+    ///
+    /// Connections of masters rejected by the peer policy are closed, failing with
+    /// `PeerRejected`. In [SlaveListenerMode::Replace] mode, the connection of the previous master
+    /// is shut down. The backend of the connection is notified of the new master before the
+    /// handler is returned, and the connection is closed if the backend rejects it.
+    ///
+    /// [SlaveListenerMode::Replace]: enum.SlaveListenerMode.html#variant.Replace
+    pub fn accept_connection(&mut self) -> Result<Option<(u64, SlaveReqHandler<S>)>> {
+        let sock = match self.listener.accept()? {
+            Some(sock) => Endpoint::<MasterReq>::from_stream(sock),
+            None => return Ok(None),
+        };
+        if let Some(policy) = self.peer_policy.as_ref() {
+            sock.check_peer(policy)?;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let backend = match self.factory.as_mut() {
+            Some(factory) => factory(id)?,
+            None => self.backend.clone(),
+        };
+        if self.mode == SlaveListenerMode::Replace {
+            let dup = sock.try_clone()?;
+            if let Some((_, prev)) = self.active.replace((id, dup)) {
+                // The previous master may be gone already, so errors are ignored.
+                let _ = prev.shutdown();
+            }
         }
-        Ok(None)
+        if let Err(e) = backend.master_connected(id) {
+            if self.active_connection() == Some(id) {
+                self.active = None;
+            }
+            return Err(e);
+        }
+
+        let handler = SlaveReqHandler::new(sock, backend);
+        Ok(Some((id, handler)))
     }
 
     /// Change blocking status on the listener.
@@ -51,6 +160,7 @@ mod tests {
 
     use super::*;
     use crate::vhost_user::dummy_slave::DummySlaveReqHandler;
+    use crate::vhost_user::Error;
 
     #[test]
     fn test_slave_listener_set_nonblocking() {
@@ -83,4 +193,104 @@ mod tests {
         let _master = Master::connect(path, 1).unwrap();
         let _slave = slave_listener.accept().unwrap().unwrap();
     }
+
+    #[test]
+    fn test_slave_listener_peer_policy() {
+        use std::os::unix::net::UnixStream;
+
+        let path = "/tmp/vhost_user_lib_unit_test_slave_peer_policy";
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let listener = Listener::new(path, true).unwrap();
+        let mut slave_listener = SlaveListener::new(listener, backend).unwrap();
+        // Safe because the call has no side effects.
+        let uid = unsafe { libc::geteuid() };
+
+        // Rejected masters are disconnected without replacing the active one.
+        slave_listener.set_peer_policy(Some(PeerPolicy::new().uid(uid.wrapping_add(1))));
+        let _master = UnixStream::connect(path).unwrap();
+        match slave_listener.accept_connection() {
+            Err(Error::PeerRejected(cred)) => assert_eq!(cred.uid, uid),
+            _ => panic!("the master should be rejected"),
+        }
+        assert_eq!(slave_listener.active_connection(), None);
+
+        let pid = std::process::id() as i32;
+        let policy = PeerPolicy::new()
+            .uid(uid)
+            .check(Box::new(move |cred| cred.pid == pid));
+        slave_listener.set_peer_policy(Some(policy));
+        let _master = UnixStream::connect(path).unwrap();
+        let (id, _slave) = slave_listener.accept_connection().unwrap().unwrap();
+        assert_eq!(slave_listener.active_connection(), Some(id));
+    }
+
+    #[cfg(feature = "vhost-user-master")]
+    #[test]
+    fn test_slave_listener_replace() {
+        use super::super::Master;
+        use crate::backend::VhostFeatureOps;
+
+        let path = "/tmp/vhost_user_lib_unit_test_slave_replace";
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let listener = Listener::new(path, true).unwrap();
+        let mut slave_listener = SlaveListener::new(listener, backend.clone()).unwrap();
+        assert_eq!(slave_listener.mode(), SlaveListenerMode::Replace);
+
+        let master = Master::connect(path, 1).unwrap();
+        let (id, mut slave) = slave_listener.accept_connection().unwrap().unwrap();
+        assert_eq!(backend.lock().unwrap().master_id, Some(id));
+        master.set_owner().unwrap();
+        slave.handle_request().unwrap();
+
+        // The master reconnects, the previous connection is shut down.
+        let master2 = Master::connect(path, 1).unwrap();
+        let (id2, mut slave2) = slave_listener.accept_connection().unwrap().unwrap();
+        assert_ne!(id, id2);
+        assert_eq!(slave_listener.active_connection(), Some(id2));
+        assert_eq!(backend.lock().unwrap().master_id, Some(id2));
+        slave.handle_request().unwrap_err();
+
+        master2.reset_owner().unwrap();
+        slave2.handle_request().unwrap();
+        assert!(!backend.lock().unwrap().owned);
+    }
+
+    #[cfg(feature = "vhost-user-master")]
+    #[test]
+    fn test_slave_listener_concurrent() {
+        use super::super::Master;
+        use crate::backend::VhostFeatureOps;
+
+        let path = "/tmp/vhost_user_lib_unit_test_slave_concurrent";
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let listener = Listener::new(path, true).unwrap();
+        let mut slave_listener = SlaveListener::new(listener, backend.clone()).unwrap();
+        slave_listener.set_mode(SlaveListenerMode::Concurrent);
+        let backends = Arc::new(Mutex::new(Vec::new()));
+        let created = backends.clone();
+        slave_listener.set_backend_factory(Some(Box::new(move |_| {
+            let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+            created.lock().unwrap().push(backend.clone());
+            Ok(backend)
+        })));
+
+        let master = Master::connect(path, 1).unwrap();
+        let (id, mut slave) = slave_listener.accept_connection().unwrap().unwrap();
+        let master2 = Master::connect(path, 1).unwrap();
+        let (id2, mut slave2) = slave_listener.accept_connection().unwrap().unwrap();
+        assert_eq!(slave_listener.active_connection(), None);
+
+        // Both masters are served, each by its own backend.
+        master.set_owner().unwrap();
+        slave.handle_request().unwrap();
+        master2.set_owner().unwrap();
+        slave2.handle_request().unwrap();
+
+        let backends = backends.lock().unwrap();
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[0].lock().unwrap().master_id, Some(id));
+        assert_eq!(backends[1].lock().unwrap().master_id, Some(id2));
+        assert!(backends.iter().all(|b| b.lock().unwrap().owned));
+        assert!(!backend.lock().unwrap().owned);
+    }
 }
@@ -1,15 +1,16 @@
 // Copyright (C) 2020 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fs::File;
 use std::io;
 use std::mem;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex, MutexGuard};
 
 use super::connection::Endpoint;
 use super::message::*;
-use super::{Error, HandlerResult, Result, VhostUserMasterReqHandler};
+use super::{take_single_file, Error, HandlerResult, Result, VhostUserMasterReqHandler};
 
 struct SlaveFsCacheReqInternal {
     sock: Endpoint<SlaveReq>,
@@ -29,38 +30,101 @@ impl SlaveFsCacheReqInternal {
         }
     }
 
-    fn send_message(
+    fn send_message<T: Sized>(
         &mut self,
         request: SlaveReq,
-        fs: &VhostUserFSSlaveMsg,
+        body: &T,
         fds: Option<&[RawFd]>,
     ) -> Result<u64> {
         self.check_state()?;
 
-        let len = mem::size_of::<VhostUserFSSlaveMsg>();
+        let len = mem::size_of::<T>();
         let mut hdr = VhostUserMsgHeader::new(request, 0, len as u32);
         if self.reply_ack_negotiated {
             hdr.set_need_reply(true);
         }
-        self.sock.send_message(&hdr, fs, fds)?;
+        self.sock.send_message(&hdr, body, fds)?;
 
         self.wait_for_ack(&hdr)
     }
 
-    fn wait_for_ack(&mut self, hdr: &VhostUserMsgHeader<SlaveReq>) -> Result<u64> {
+    fn send_custom_request(
+        &mut self,
+        code: u32,
+        payload: &[u8],
+        fds: Option<&[RawFd]>,
+    ) -> Result<u64> {
+        if !SlaveReq::is_custom_code(code) || payload.len() > MAX_MSG_SIZE {
+            return Err(Error::InvalidParam);
+        }
         self.check_state()?;
-        if !self.reply_ack_negotiated {
-            return Ok(0);
+
+        let mut hdr = VhostUserMsgHeader::new_raw(code, 0, payload.len() as u32);
+        if self.reply_ack_negotiated {
+            hdr.set_need_reply(true);
         }
+        self.sock
+            .send_message_with_payload(&hdr, &(), payload, fds)?;
 
-        let (reply, body, rfds) = self.sock.recv_body::<VhostUserU64>()?;
-        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
+        self.wait_for_ack(&hdr)
+    }
+
+    fn lookup_shared_object(&mut self, uuid: &VhostUserShared) -> Result<File> {
+        self.check_state()?;
+
+        let len = mem::size_of::<VhostUserShared>();
+        let hdr = VhostUserMsgHeader::new(SlaveReq::SHARED_OBJECT_LOOKUP, 0, len as u32);
+        self.sock.send_message(&hdr, uuid, None)?;
+
+        // The master always replies to lookups, whether REPLY_ACK is negotiated or not.
+        let (reply, body, files) = self.sock.recv_body::<VhostUserU64>()?;
+        if !reply.is_reply_for(&hdr) || !body.is_valid() {
             return Err(Error::InvalidMessage);
         }
         if body.value != 0 {
             return Err(Error::MasterInternalError);
         }
+        take_single_file(files).ok_or(Error::IncorrectFds)
+    }
+
+    fn send_fs_io(&mut self, msg: &VhostUserFSSlaveMsg, fd: RawFd) -> Result<u64> {
+        self.check_state()?;
+        // The size transferred is only replied with REPLY_ACK.
+        if !self.reply_ack_negotiated {
+            return Err(Error::InvalidOperation);
+        }
 
+        let len = mem::size_of::<VhostUserFSSlaveMsg>();
+        let mut hdr = VhostUserMsgHeader::new(SlaveReq::FS_IO, 0, len as u32);
+        hdr.set_need_reply(true);
+        self.sock.send_message(&hdr, msg, Some(&[fd]))?;
+
+        // The master replies with the size transferred, or a negative errno.
+        let value = self.wait_for_reply(&hdr)?;
+        if (value as i64) < 0 {
+            return Err(Error::MasterInternalError);
+        }
+        Ok(value)
+    }
+
+    fn wait_for_ack(&mut self, hdr: &VhostUserMsgHeader<SlaveReq>) -> Result<u64> {
+        self.check_state()?;
+        if !self.reply_ack_negotiated {
+            return Ok(0);
+        }
+
+        if self.wait_for_reply(hdr)? != 0 {
+            return Err(Error::MasterInternalError);
+        }
+
+        Ok(0)
+    }
+
+    fn wait_for_reply(&mut self, hdr: &VhostUserMsgHeader<SlaveReq>) -> Result<u64> {
+        let (reply, body, rfds) = self.sock.recv_body::<VhostUserU64>()?;
+        if !reply.is_reply_for(hdr) || rfds.is_some() || !body.is_valid() {
+            return Err(Error::InvalidMessage);
+        }
         Ok(body.value)
     }
 }
@@ -95,14 +159,14 @@ impl SlaveFsCacheReq {
         self.node.lock().unwrap()
     }
 
-    fn send_message(
+    fn send_message<T: Sized>(
         &self,
         request: SlaveReq,
-        fs: &VhostUserFSSlaveMsg,
+        body: &T,
         fds: Option<&[RawFd]>,
     ) -> io::Result<u64> {
         self.node()
-            .send_message(request, fs, fds)
+            .send_message(request, body, fds)
             .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
     }
 
@@ -124,18 +188,143 @@ impl SlaveFsCacheReq {
     pub fn set_failed(&self, error: i32) {
         self.node().error = Some(error);
     }
+
+    /// Ask the master to map the `entries` of `file` into the DAX window.
+    pub fn fs_map(&self, entries: &[VhostUserFSSlaveEntry], file: &dyn AsFd) -> Result<u64> {
+        let msg = Self::fs_msg(entries)?;
+        let fds = [file.as_fd().as_raw_fd()];
+        self.node().send_message(SlaveReq::FS_MAP, &msg, Some(&fds))
+    }
+
+    /// Ask the master to unmap the `entries` from the DAX window.
+    pub fn fs_unmap(&self, entries: &[VhostUserFSSlaveEntry]) -> Result<u64> {
+        let msg = Self::fs_msg(entries)?;
+        self.node().send_message(SlaveReq::FS_UNMAP, &msg, None)
+    }
+
+    /// Ask the master to sync the `entries` of the DAX window to their files.
+    pub fn fs_sync(&self, entries: &[VhostUserFSSlaveEntry]) -> Result<u64> {
+        let msg = Self::fs_msg(entries)?;
+        self.node().send_message(SlaveReq::FS_SYNC, &msg, None)
+    }
+
+    /// Ask the master to transfer the `entries` between `file` and the guest memory, returning
+    /// the number of bytes transferred.
+    ///
+    /// The entries are written to the file with `MAP_W`, and read from it with `MAP_R`. The
+    /// `VHOST_USER_PROTOCOL_F_REPLY_ACK` protocol feature must have been negotiated for the
+    /// master to reply.
+    pub fn fs_io(&self, entries: &[VhostUserFSSlaveEntry], file: &dyn AsFd) -> Result<u64> {
+        let msg = Self::fs_msg(entries)?;
+        self.node().send_fs_io(&msg, file.as_fd().as_raw_fd())
+    }
+
+    fn fs_msg(entries: &[VhostUserFSSlaveEntry]) -> Result<VhostUserFSSlaveMsg> {
+        VhostUserFSSlaveMsg::from_entries(entries)
+            .filter(|msg| msg.is_valid())
+            .ok_or(Error::InvalidParam)
+    }
+
+    /// Ask the master to map the `size` bytes at `offset` of `file` as the doorbell area of queue
+    /// `queue_index`, so the driver notifies the device by writing to it directly.
+    ///
+    /// The VHOST_USER_PROTOCOL_F_HOST_NOTIFIER and VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD protocol
+    /// features must have been negotiated.
+    pub fn set_vring_host_notifier(
+        &self,
+        queue_index: u8,
+        file: &dyn AsFd,
+        offset: u64,
+        size: u64,
+    ) -> Result<u64> {
+        let area = VhostUserVringArea::new(queue_index, true, size, offset);
+        if !area.is_valid() {
+            return Err(Error::InvalidParam);
+        }
+        let fds = [file.as_fd().as_raw_fd()];
+        self.node()
+            .send_message(SlaveReq::VRING_HOST_NOTIFIER_MSG, &area, Some(&fds))
+    }
+
+    /// Ask the master to stop using the doorbell area of queue `queue_index`.
+    pub fn remove_vring_host_notifier(&self, queue_index: u8) -> Result<u64> {
+        let area = VhostUserVringArea::new(queue_index, false, 0, 0);
+        self.node()
+            .send_message(SlaveReq::VRING_HOST_NOTIFIER_MSG, &area, None)
+    }
+
+    /// Send a device specific request to the master.
+    ///
+    /// `code` must be beyond the requests defined by [SlaveReq], the master handles it with the
+    /// handler registered by [MasterReqHandler::register_custom_request()].
+    ///
+    /// [SlaveReq]: message/enum.SlaveReq.html
+    /// [MasterReqHandler::register_custom_request()]: struct.MasterReqHandler.html#method.register_custom_request
+    pub fn send_custom_request(
+        &self,
+        code: u32,
+        payload: &[u8],
+        fds: Option<&[BorrowedFd]>,
+    ) -> Result<u64> {
+        let fds: Option<Vec<RawFd>> = fds.map(|fds| fds.iter().map(|fd| fd.as_raw_fd()).collect());
+        self.node()
+            .send_custom_request(code, payload, fds.as_deref())
+    }
 }
 
 impl VhostUserMasterReqHandler for SlaveFsCacheReq {
+    /// Forward device configuration space change notifications to the master.
+    fn handle_config_change(&self) -> HandlerResult<u64> {
+        // The notification has no body.
+        self.send_message(SlaveReq::CONFIG_CHANGE_MSG, &(), None)
+    }
+
     /// Forward vhost-user-fs map file requests to the slave.
-    fn fs_slave_map(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsRawFd) -> HandlerResult<u64> {
-        self.send_message(SlaveReq::FS_MAP, fs, Some(&[fd.as_raw_fd()]))
+    fn fs_slave_map(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::FS_MAP, fs, Some(&[fd.as_fd().as_raw_fd()]))
     }
 
     /// Forward vhost-user-fs unmap file requests to the master.
     fn fs_slave_unmap(&self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
         self.send_message(SlaveReq::FS_UNMAP, fs, None)
     }
+
+    /// Forward vhost-user-fs sync file requests to the master.
+    fn fs_slave_sync(&self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::FS_SYNC, fs, None)
+    }
+
+    /// Forward vhost-user-fs file IO requests to the master.
+    fn fs_slave_io(&self, fs: &VhostUserFSSlaveMsg, fd: &dyn AsFd) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::FS_IO, fs, Some(&[fd.as_fd().as_raw_fd()]))
+    }
+
+    /// Forward requests to add a virtio shared object to the master.
+    fn shared_object_add(&self, uuid: &VhostUserShared) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::SHARED_OBJECT_ADD, uuid, None)
+    }
+
+    /// Forward requests to remove a virtio shared object to the master.
+    fn shared_object_remove(&self, uuid: &VhostUserShared) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::SHARED_OBJECT_REMOVE, uuid, None)
+    }
+
+    /// Forward requests to lookup a virtio shared object to the master.
+    fn shared_object_lookup(&self, uuid: &VhostUserShared) -> HandlerResult<File> {
+        self.node()
+            .lookup_shared_object(uuid)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+    }
+
+    /// Forward requests to map a file into a VIRTIO shared memory region to the master.
+    fn shmem_map(&self, req: &VhostUserMMap, fd: &dyn AsFd) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::SHMEM_MAP, req, Some(&[fd.as_fd().as_raw_fd()]))
+    }
+
+    /// Forward requests to unmap a file from a VIRTIO shared memory region to the master.
+    fn shmem_unmap(&self, req: &VhostUserMMap) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::SHMEM_UNMAP, req, None)
+    }
 }
 
 #[cfg(test)]
@@ -214,4 +403,99 @@ mod tests {
             .fs_slave_map(&VhostUserFSSlaveMsg::default(), &master)
             .unwrap();
     }
+
+    #[test]
+    fn test_slave_fs_cache_host_notifier() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let fs_cache = SlaveFsCacheReq::from_stream(p1);
+        let mut master = Endpoint::<SlaveReq>::from_stream(p2);
+
+        fs_cache
+            .set_vring_host_notifier(1, &master, 0x1000, 0)
+            .unwrap_err();
+        fs_cache
+            .set_vring_host_notifier(1, &master, 0x1000, 0x1000)
+            .unwrap();
+        let (hdr, area, files) = master.recv_body::<VhostUserVringArea>().unwrap();
+        assert_eq!(hdr.get_code(), SlaveReq::VRING_HOST_NOTIFIER_MSG);
+        assert_eq!(area.queue_index(), 1);
+        assert!(area.has_fd());
+        assert_eq!(({ area.offset }, { area.size }), (0x1000, 0x1000));
+        assert_eq!(files.unwrap().len(), 1);
+
+        fs_cache.remove_vring_host_notifier(1).unwrap();
+        let (_, area, files) = master.recv_body::<VhostUserVringArea>().unwrap();
+        assert!(!area.has_fd());
+        assert!(files.is_none());
+    }
+
+    fn recv_fs_msg(
+        master: &mut Endpoint<SlaveReq>,
+    ) -> (
+        VhostUserMsgHeader<SlaveReq>,
+        VhostUserFSSlaveMsg,
+        Option<Vec<File>>,
+    ) {
+        let (hdr, files) = master.recv_header().unwrap();
+        let (len, buf) = master.recv_data(hdr.get_size() as usize).unwrap();
+        assert_eq!(len, mem::size_of::<VhostUserFSSlaveMsg>());
+        // Safe because the buffer holds a whole message, which is plain data.
+        let msg = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const VhostUserFSSlaveMsg) };
+        (hdr, msg, files)
+    }
+
+    #[test]
+    fn test_slave_fs_cache_fs_requests() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let fs_cache = SlaveFsCacheReq::from_stream(p1);
+        let mut master = Endpoint::<SlaveReq>::from_stream(p2);
+        let entry = VhostUserFSSlaveEntry {
+            fd_offset: 0x1000,
+            cache_offset: 0x2000,
+            len: 0x3000,
+            flags: VhostUserFSSlaveMsgFlags::MAP_R,
+        };
+
+        fs_cache
+            .fs_unmap(&[entry; VHOST_USER_FS_SLAVE_ENTRIES + 1])
+            .unwrap_err();
+        fs_cache.fs_map(&[entry], &master).unwrap();
+        let (hdr, msg, files) = recv_fs_msg(&mut master);
+        assert_eq!(hdr.get_code(), SlaveReq::FS_MAP);
+        assert_eq!(msg.entry(0), Some(entry));
+        assert_eq!(msg.entry(1), Some(VhostUserFSSlaveEntry::default()));
+        assert_eq!(files.unwrap().len(), 1);
+        fs_cache.fs_unmap(&[entry, entry]).unwrap();
+        let (hdr, msg, files) = recv_fs_msg(&mut master);
+        assert_eq!(hdr.get_code(), SlaveReq::FS_UNMAP);
+        assert_eq!(msg.entry(1), Some(entry));
+        assert!(files.is_none());
+        fs_cache.fs_sync(&[entry]).unwrap();
+        let (hdr, _, _) = recv_fs_msg(&mut master);
+        assert_eq!(hdr.get_code(), SlaveReq::FS_SYNC);
+
+        // The size transferred by FS_IO is only replied with REPLY_ACK.
+        fs_cache.fs_io(&[entry], &master).unwrap_err();
+        fs_cache.set_reply_ack_flag(true);
+        let len = mem::size_of::<VhostUserFSSlaveMsg>();
+        let reply = VhostUserMsgHeader::new(
+            SlaveReq::FS_IO,
+            VhostUserHeaderFlag::REPLY.bits(),
+            len as u32,
+        );
+        master
+            .send_message(&reply, &VhostUserU64::new(0x3000), None)
+            .unwrap();
+        assert_eq!(fs_cache.fs_io(&[entry], &master).unwrap(), 0x3000);
+        let (hdr, _, files) = recv_fs_msg(&mut master);
+        assert_eq!(hdr.get_code(), SlaveReq::FS_IO);
+        assert!(hdr.is_need_reply());
+        assert_eq!(files.unwrap().len(), 1);
+
+        let errno = -libc::EIO as i64;
+        master
+            .send_message(&reply, &VhostUserU64::new(errno as u64), None)
+            .unwrap();
+        fs_cache.fs_io(&[entry], &master).unwrap_err();
+    }
 }
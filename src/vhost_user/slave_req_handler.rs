@@ -1,18 +1,80 @@
 // Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::mem;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use vm_memory::ByteValued;
+
+use super::config_space::ConfigSpace;
 use super::connection::Endpoint;
+use super::dirty_log_writer::DirtyLogWriter;
+use super::inflight::InflightRegion;
+use super::mapped_memory::{MappedMemory, MappedRegion, MemoryMappingManager};
 use super::message::*;
 use super::slave_fs_cache::SlaveFsCacheReq;
+use super::upgrade::SlaveUpgradeState;
+use super::userfaultfd::Userfaultfd;
 use super::{take_single_file, Error, Result};
 
+// Header, size of the body, body and attached files of a request received from the master.
+pub(super) type ReceivedRequest = (
+    VhostUserMsgHeader<MasterReq>,
+    usize,
+    Vec<u8>,
+    Option<Vec<File>>,
+);
+
+/// Statistics of the requests of the master with a given code, kept by [SlaveReqHandler].
+///
+/// [SlaveReqHandler]: struct.SlaveReqHandler.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MasterReqStats {
+    /// Number of requests served.
+    pub count: u64,
+    /// Number of requests which failed.
+    pub failures: u64,
+    /// Number of requests served slower than the threshold set by
+    /// [SlaveReqHandler::set_slow_request_threshold()].
+    ///
+    /// [SlaveReqHandler::set_slow_request_threshold()]: struct.SlaveReqHandler.html#method.set_slow_request_threshold
+    pub slow: u64,
+    /// Total time spent serving the requests.
+    pub total_latency: Duration,
+    /// Longest time spent serving a request.
+    pub max_latency: Duration,
+}
+
+impl MasterReqStats {
+    fn record(&mut self, latency: Duration, failed: bool, slow: bool) {
+        self.count += 1;
+        if failed {
+            self.failures += 1;
+        }
+        if slow {
+            self.slow += 1;
+        }
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+}
+
+/// Callback reporting the requests of the master served slower than the threshold set by
+/// [SlaveReqHandler::set_slow_request_threshold()], with their request code and the time spent
+/// serving them.
+///
+/// A slow GET_VRING_BASE or SET_MEM_TABLE stalls the guest, so slaves may log these requests.
+///
+/// [SlaveReqHandler::set_slow_request_threshold()]: struct.SlaveReqHandler.html#method.set_slow_request_threshold
+pub type SlowRequestHandler = Box<dyn Fn(u32, Duration) + Send>;
+
 /// Services provided to the master by the slave with interior mutability.
 ///
 /// The [VhostUserSlaveReqHandler] trait defines the services provided to the master by the slave.
@@ -50,8 +112,11 @@ pub trait VhostUserSlaveReqHandler {
         available: u64,
         log: u64,
     ) -> Result<()>;
-    fn set_vring_base(&self, index: u32, base: u32) -> Result<()>;
-    fn get_vring_base(&self, index: u32) -> Result<VhostUserVringState>;
+    /// Set the position of a vring, decoded according to whether VIRTIO_F_RING_PACKED was
+    /// acked.
+    fn set_vring_base(&self, index: u32, base: VringBase) -> Result<()>;
+    /// Stop a vring and get its position, of the layout of the vring.
+    fn get_vring_base(&self, index: u32) -> Result<VringBase>;
     fn set_vring_kick(&self, index: u8, fd: Option<File>) -> Result<()>;
     fn set_vring_call(&self, index: u8, fd: Option<File>) -> Result<()>;
     fn set_vring_err(&self, index: u8, fd: Option<File>) -> Result<()>;
@@ -68,6 +133,153 @@ pub trait VhostUserSlaveReqHandler {
     fn get_max_mem_slots(&self) -> Result<u64>;
     fn add_mem_region(&self, region: &VhostUserSingleMemoryRegion, fd: File) -> Result<()>;
     fn remove_mem_region(&self, region: &VhostUserSingleMemoryRegion) -> Result<()>;
+    fn get_shared_object(&self, _uuid: &VhostUserShared) -> Result<File> {
+        Err(Error::InvalidOperation)
+    }
+    fn create_crypto_session(&self, _session: &VhostUserCryptoSession) -> Result<i64> {
+        Err(Error::InvalidOperation)
+    }
+    fn close_crypto_session(&self, _session_id: i64) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    fn get_shmem_config(&self) -> Result<Vec<u64>> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the memory map regions mapped by the [SlaveReqHandler], see
+    /// [SlaveReqHandler::set_map_memory()].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    /// [SlaveReqHandler::set_map_memory()]: struct.SlaveReqHandler.html#method.set_map_memory
+    fn set_mapped_mem_table(&self, _regions: &[Arc<MappedRegion>]) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Add a memory region mapped by the [SlaveReqHandler].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    fn add_mapped_mem_region(&self, _region: &Arc<MappedRegion>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Remove a memory region mapped by the [SlaveReqHandler]. The region is unmapped once the
+    /// slave drops its references to it.
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    fn remove_mapped_mem_region(&self, _region: &Arc<MappedRegion>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the inflight I/O tracking buffer allocated by the [SlaveReqHandler], see
+    /// [SlaveReqHandler::set_track_inflight()].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    /// [SlaveReqHandler::set_track_inflight()]: struct.SlaveReqHandler.html#method.set_track_inflight
+    fn set_inflight_region(&self, _region: Arc<InflightRegion>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the dirty page log shared by the master with SET_LOG_BASE, to mark the guest memory
+    /// written by the slave while the guest is migrated.
+    fn set_dirty_log(&self, _log: Arc<DirtyLogWriter>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Prepare for a postcopy migration, the slave keeps `uffd` to register its mappings of the
+    /// guest memory.
+    fn postcopy_advise(&self, _uffd: &Userfaultfd) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// The migration switched to postcopy: accesses to the guest memory not migrated yet now
+    /// block until the master has copied the pages, the slave may pause the processing which
+    /// can't afford to block.
+    fn postcopy_listen(&self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// The postcopy migration completed, all the guest memory is available again.
+    fn postcopy_end(&self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the memory map regions while in postcopy mode.
+    ///
+    /// Return the address of each region in the address space of the slave, so the regions are
+    /// registered with the userfaultfd and reported to the master.
+    fn set_mem_table_postcopy(
+        &self,
+        _ctx: &[VhostUserMemoryRegion],
+        _files: Vec<File>,
+    ) -> Result<Vec<u64>> {
+        Err(Error::InvalidOperation)
+    }
+    /// Add a memory region while in postcopy mode.
+    ///
+    /// Return the address of the region in the address space of the slave.
+    fn add_mem_region_postcopy(
+        &self,
+        _region: &VhostUserSingleMemoryRegion,
+        _fd: File,
+    ) -> Result<u64> {
+        Err(Error::InvalidOperation)
+    }
+    /// Handle a device specific request with a code unknown to the crate.
+    ///
+    /// Return the payload and the files of the reply sent back to the master.
+    fn custom_request(
+        &self,
+        _code: u32,
+        _payload: &[u8],
+        _files: Option<Vec<File>>,
+    ) -> Result<(Vec<u8>, Option<Vec<File>>)> {
+        Err(Error::InvalidOperation)
+    }
+    /// Reset the device to its initial state, keeping the ownership and the negotiated protocol
+    /// features.
+    ///
+    /// The slave must disable all the vrings, stop its workers and drop the requests in flight
+    /// before returning, as the master may reinitialize the device once acknowledged.
+    fn reset_device(&self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the virtio device status as defined in the VIRTIO specification, such as DRIVER_OK
+    /// once the driver is ready, or 0 when the driver resets the device.
+    fn set_status(&self, _status: u8) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Get the virtio device status as defined in the VIRTIO specification.
+    fn get_status(&self) -> Result<u8> {
+        Err(Error::InvalidOperation)
+    }
+    /// Begin the transfer of the internal device state through a pipe.
+    ///
+    /// `file` is the end of the pipe the slave writes the state to (`SAVE`) or reads the state
+    /// from (`LOAD`). The slave may instead return the end of its own pipe, which is sent to the
+    /// master, and close `file`.
+    fn set_device_state_fd(
+        &self,
+        _direction: VhostTransferStateDirection,
+        _phase: VhostTransferStatePhase,
+        _file: File,
+    ) -> Result<Option<File>> {
+        Err(Error::InvalidOperation)
+    }
+    /// Report whether the device state transfer succeeded, once the pipe has been closed by the
+    /// writer and drained by the reader.
+    fn check_device_state(&self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Notify the slave that the master of connection `id` is now connected.
+    ///
+    /// Called by [SlaveListener] for each accepted connection, before its handler is returned.
+    /// Failing rejects the connection.
+    ///
+    /// [SlaveListener]: struct.SlaveListener.html
+    fn master_connected(&self, _id: u64) -> Result<()> {
+        Ok(())
+    }
+    /// Complete or cancel the processing of the queues within `timeout`, before the connection
+    /// is closed by [SlaveShutdown::shutdown()].
+    ///
+    /// The slave stops processing the queues and notifies the driver of the requests completed,
+    /// failing with `Timeout` if the processing of a queue doesn't complete in time.
+    ///
+    /// [SlaveShutdown::shutdown()]: struct.SlaveShutdown.html#method.shutdown
+    fn shutdown(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// Services provided to the master by the slave without interior mutability.
@@ -90,8 +302,11 @@ pub trait VhostUserSlaveReqHandlerMut {
         available: u64,
         log: u64,
     ) -> Result<()>;
-    fn set_vring_base(&mut self, index: u32, base: u32) -> Result<()>;
-    fn get_vring_base(&mut self, index: u32) -> Result<VhostUserVringState>;
+    /// Set the position of a vring, decoded according to whether VIRTIO_F_RING_PACKED was
+    /// acked.
+    fn set_vring_base(&mut self, index: u32, base: VringBase) -> Result<()>;
+    /// Stop a vring and get its position, of the layout of the vring.
+    fn get_vring_base(&mut self, index: u32) -> Result<VringBase>;
     fn set_vring_kick(&mut self, index: u8, fd: Option<File>) -> Result<()>;
     fn set_vring_call(&mut self, index: u8, fd: Option<File>) -> Result<()>;
     fn set_vring_err(&mut self, index: u8, fd: Option<File>) -> Result<()>;
@@ -116,6 +331,153 @@ pub trait VhostUserSlaveReqHandlerMut {
     fn get_max_mem_slots(&mut self) -> Result<u64>;
     fn add_mem_region(&mut self, region: &VhostUserSingleMemoryRegion, fd: File) -> Result<()>;
     fn remove_mem_region(&mut self, region: &VhostUserSingleMemoryRegion) -> Result<()>;
+    fn get_shared_object(&mut self, _uuid: &VhostUserShared) -> Result<File> {
+        Err(Error::InvalidOperation)
+    }
+    fn create_crypto_session(&mut self, _session: &VhostUserCryptoSession) -> Result<i64> {
+        Err(Error::InvalidOperation)
+    }
+    fn close_crypto_session(&mut self, _session_id: i64) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    fn get_shmem_config(&mut self) -> Result<Vec<u64>> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the memory map regions mapped by the [SlaveReqHandler], see
+    /// [SlaveReqHandler::set_map_memory()].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    /// [SlaveReqHandler::set_map_memory()]: struct.SlaveReqHandler.html#method.set_map_memory
+    fn set_mapped_mem_table(&mut self, _regions: &[Arc<MappedRegion>]) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Add a memory region mapped by the [SlaveReqHandler].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    fn add_mapped_mem_region(&mut self, _region: &Arc<MappedRegion>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Remove a memory region mapped by the [SlaveReqHandler]. The region is unmapped once the
+    /// slave drops its references to it.
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    fn remove_mapped_mem_region(&mut self, _region: &Arc<MappedRegion>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the inflight I/O tracking buffer allocated by the [SlaveReqHandler], see
+    /// [SlaveReqHandler::set_track_inflight()].
+    ///
+    /// [SlaveReqHandler]: struct.SlaveReqHandler.html
+    /// [SlaveReqHandler::set_track_inflight()]: struct.SlaveReqHandler.html#method.set_track_inflight
+    fn set_inflight_region(&mut self, _region: Arc<InflightRegion>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the dirty page log shared by the master with SET_LOG_BASE, to mark the guest memory
+    /// written by the slave while the guest is migrated.
+    fn set_dirty_log(&mut self, _log: Arc<DirtyLogWriter>) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Prepare for a postcopy migration, the slave keeps `uffd` to register its mappings of the
+    /// guest memory.
+    fn postcopy_advise(&mut self, _uffd: &Userfaultfd) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// The migration switched to postcopy: accesses to the guest memory not migrated yet now
+    /// block until the master has copied the pages, the slave may pause the processing which
+    /// can't afford to block.
+    fn postcopy_listen(&mut self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// The postcopy migration completed, all the guest memory is available again.
+    fn postcopy_end(&mut self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the memory map regions while in postcopy mode.
+    ///
+    /// Return the address of each region in the address space of the slave, so the regions are
+    /// registered with the userfaultfd and reported to the master.
+    fn set_mem_table_postcopy(
+        &mut self,
+        _ctx: &[VhostUserMemoryRegion],
+        _files: Vec<File>,
+    ) -> Result<Vec<u64>> {
+        Err(Error::InvalidOperation)
+    }
+    /// Add a memory region while in postcopy mode.
+    ///
+    /// Return the address of the region in the address space of the slave.
+    fn add_mem_region_postcopy(
+        &mut self,
+        _region: &VhostUserSingleMemoryRegion,
+        _fd: File,
+    ) -> Result<u64> {
+        Err(Error::InvalidOperation)
+    }
+    /// Handle a device specific request with a code unknown to the crate.
+    ///
+    /// Return the payload and the files of the reply sent back to the master.
+    fn custom_request(
+        &mut self,
+        _code: u32,
+        _payload: &[u8],
+        _files: Option<Vec<File>>,
+    ) -> Result<(Vec<u8>, Option<Vec<File>>)> {
+        Err(Error::InvalidOperation)
+    }
+    /// Reset the device to its initial state, keeping the ownership and the negotiated protocol
+    /// features.
+    ///
+    /// The slave must disable all the vrings, stop its workers and drop the requests in flight
+    /// before returning, as the master may reinitialize the device once acknowledged.
+    fn reset_device(&mut self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Set the virtio device status as defined in the VIRTIO specification, such as DRIVER_OK
+    /// once the driver is ready, or 0 when the driver resets the device.
+    fn set_status(&mut self, _status: u8) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Get the virtio device status as defined in the VIRTIO specification.
+    fn get_status(&mut self) -> Result<u8> {
+        Err(Error::InvalidOperation)
+    }
+    /// Begin the transfer of the internal device state through a pipe.
+    ///
+    /// `file` is the end of the pipe the slave writes the state to (`SAVE`) or reads the state
+    /// from (`LOAD`). The slave may instead return the end of its own pipe, which is sent to the
+    /// master, and close `file`.
+    fn set_device_state_fd(
+        &mut self,
+        _direction: VhostTransferStateDirection,
+        _phase: VhostTransferStatePhase,
+        _file: File,
+    ) -> Result<Option<File>> {
+        Err(Error::InvalidOperation)
+    }
+    /// Report whether the device state transfer succeeded, once the pipe has been closed by the
+    /// writer and drained by the reader.
+    fn check_device_state(&mut self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+    /// Notify the slave that the master of connection `id` is now connected.
+    ///
+    /// Called by [SlaveListener] for each accepted connection, before its handler is returned.
+    /// Failing rejects the connection.
+    ///
+    /// [SlaveListener]: struct.SlaveListener.html
+    fn master_connected(&mut self, _id: u64) -> Result<()> {
+        Ok(())
+    }
+    /// Complete or cancel the processing of the queues within `timeout`, before the connection
+    /// is closed by [SlaveShutdown::shutdown()].
+    ///
+    /// The slave stops processing the queues and notifies the driver of the requests completed,
+    /// failing with `Timeout` if the processing of a queue doesn't complete in time.
+    ///
+    /// [SlaveShutdown::shutdown()]: struct.SlaveShutdown.html#method.shutdown
+    fn shutdown(&mut self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl<T: VhostUserSlaveReqHandlerMut> VhostUserSlaveReqHandler for Mutex<T> {
@@ -157,11 +519,11 @@ impl<T: VhostUserSlaveReqHandlerMut> VhostUserSlaveReqHandler for Mutex<T> {
             .set_vring_addr(index, flags, descriptor, used, available, log)
     }
 
-    fn set_vring_base(&self, index: u32, base: u32) -> Result<()> {
+    fn set_vring_base(&self, index: u32, base: VringBase) -> Result<()> {
         self.lock().unwrap().set_vring_base(index, base)
     }
 
-    fn get_vring_base(&self, index: u32) -> Result<VhostUserVringState> {
+    fn get_vring_base(&self, index: u32) -> Result<VringBase> {
         self.lock().unwrap().get_vring_base(index)
     }
 
@@ -224,10 +586,200 @@ impl<T: VhostUserSlaveReqHandlerMut> VhostUserSlaveReqHandler for Mutex<T> {
     fn remove_mem_region(&self, region: &VhostUserSingleMemoryRegion) -> Result<()> {
         self.lock().unwrap().remove_mem_region(region)
     }
+
+    fn get_shared_object(&self, uuid: &VhostUserShared) -> Result<File> {
+        self.lock().unwrap().get_shared_object(uuid)
+    }
+
+    fn create_crypto_session(&self, session: &VhostUserCryptoSession) -> Result<i64> {
+        self.lock().unwrap().create_crypto_session(session)
+    }
+
+    fn close_crypto_session(&self, session_id: i64) -> Result<()> {
+        self.lock().unwrap().close_crypto_session(session_id)
+    }
+
+    fn get_shmem_config(&self) -> Result<Vec<u64>> {
+        self.lock().unwrap().get_shmem_config()
+    }
+
+    fn set_mapped_mem_table(&self, regions: &[Arc<MappedRegion>]) -> Result<()> {
+        self.lock().unwrap().set_mapped_mem_table(regions)
+    }
+
+    fn add_mapped_mem_region(&self, region: &Arc<MappedRegion>) -> Result<()> {
+        self.lock().unwrap().add_mapped_mem_region(region)
+    }
+
+    fn remove_mapped_mem_region(&self, region: &Arc<MappedRegion>) -> Result<()> {
+        self.lock().unwrap().remove_mapped_mem_region(region)
+    }
+
+    fn set_inflight_region(&self, region: Arc<InflightRegion>) -> Result<()> {
+        self.lock().unwrap().set_inflight_region(region)
+    }
+
+    fn set_dirty_log(&self, log: Arc<DirtyLogWriter>) -> Result<()> {
+        self.lock().unwrap().set_dirty_log(log)
+    }
+
+    fn postcopy_advise(&self, uffd: &Userfaultfd) -> Result<()> {
+        self.lock().unwrap().postcopy_advise(uffd)
+    }
+
+    fn postcopy_listen(&self) -> Result<()> {
+        self.lock().unwrap().postcopy_listen()
+    }
+
+    fn postcopy_end(&self) -> Result<()> {
+        self.lock().unwrap().postcopy_end()
+    }
+
+    fn set_mem_table_postcopy(
+        &self,
+        ctx: &[VhostUserMemoryRegion],
+        files: Vec<File>,
+    ) -> Result<Vec<u64>> {
+        self.lock().unwrap().set_mem_table_postcopy(ctx, files)
+    }
+
+    fn add_mem_region_postcopy(
+        &self,
+        region: &VhostUserSingleMemoryRegion,
+        fd: File,
+    ) -> Result<u64> {
+        self.lock().unwrap().add_mem_region_postcopy(region, fd)
+    }
+
+    fn custom_request(
+        &self,
+        code: u32,
+        payload: &[u8],
+        files: Option<Vec<File>>,
+    ) -> Result<(Vec<u8>, Option<Vec<File>>)> {
+        self.lock().unwrap().custom_request(code, payload, files)
+    }
+
+    fn reset_device(&self) -> Result<()> {
+        self.lock().unwrap().reset_device()
+    }
+
+    fn set_status(&self, status: u8) -> Result<()> {
+        self.lock().unwrap().set_status(status)
+    }
+
+    fn get_status(&self) -> Result<u8> {
+        self.lock().unwrap().get_status()
+    }
+
+    fn set_device_state_fd(
+        &self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        file: File,
+    ) -> Result<Option<File>> {
+        self.lock()
+            .unwrap()
+            .set_device_state_fd(direction, phase, file)
+    }
+
+    fn check_device_state(&self) -> Result<()> {
+        self.lock().unwrap().check_device_state()
+    }
+
+    fn master_connected(&self, id: u64) -> Result<()> {
+        self.lock().unwrap().master_connected(id)
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        self.lock().unwrap().shutdown(timeout)
+    }
 }
 
 /// Server to handle service requests from masters from the master communication channel.
 ///
+/// Handle shutting down a [SlaveReqHandler] gracefully from any thread, for instance when the
+/// slave process receives SIGTERM.
+///
+/// [SlaveReqHandler]: struct.SlaveReqHandler.html
+pub struct SlaveShutdown<S: VhostUserSlaveReqHandler> {
+    backend: Arc<S>,
+    sock: Endpoint<MasterReq>,
+    closing: Arc<AtomicBool>,
+}
+
+impl<S: VhostUserSlaveReqHandler> SlaveShutdown<S> {
+    /// Stop serving the requests of the master, drain the queues of the backend, then close the
+    /// connection.
+    ///
+    /// Requests received afterwards fail with `Disconnected`. The backend completes or cancels
+    /// the processing of its queues within `timeout` with [VhostUserSlaveReqHandler::shutdown()].
+    /// The connection is closed even if the backend fails, such as with `Timeout` when stuck
+    /// processing a queue, so the slave process may exit regardless, and the error is returned.
+    ///
+    /// [VhostUserSlaveReqHandler::shutdown()]: trait.VhostUserSlaveReqHandler.html#method.shutdown
+    pub fn shutdown(&self, timeout: Duration) -> Result<()> {
+        self.closing.store(true, Ordering::SeqCst);
+        let res = self.backend.shutdown(timeout);
+        // The master may have closed the connection already.
+        let _ = self.sock.shutdown();
+        res
+    }
+
+    /// Whether the handler has been shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+}
+
+/// Deferred ack of a request of the master, completed asynchronously by the backend.
+///
+/// Dropping the ack without completing it reports a failure to the master.
+pub struct SlaveDeferredAck {
+    // Socket to send the ack on, or `None` if the master doesn't expect an ack.
+    sock: Option<Endpoint<MasterReq>>,
+    hdr: VhostUserMsgHeader<MasterReq>,
+    // Number of acks the master is waiting for, shared with the handler.
+    pending: Arc<AtomicUsize>,
+}
+
+impl SlaveDeferredAck {
+    /// Get the code of the request.
+    pub fn code(&self) -> MasterReq {
+        self.hdr.get_code()
+    }
+
+    /// Send the ack of the request, with the result of its completion.
+    pub fn complete(mut self, res: Result<()>) -> Result<()> {
+        self.send(res)
+    }
+
+    fn send(&mut self, res: Result<()>) -> Result<()> {
+        if let Some(mut sock) = self.sock.take() {
+            // The master sends its next request once it gets the ack, so the handler must see
+            // the ack as sent by then.
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            let hdr = VhostUserMsgHeader::new_raw(
+                self.hdr.get_raw_code(),
+                VhostUserHeaderFlag::REPLY.bits(),
+                mem::size_of::<VhostUserU64>() as u32,
+            );
+            let val = match res {
+                Ok(_) => 0,
+                Err(_) => 1,
+            };
+            sock.send_message(&hdr, &VhostUserU64::new(val), None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SlaveDeferredAck {
+    fn drop(&mut self) {
+        let _ = self.send(Err(Error::InvalidOperation));
+    }
+}
+
 /// The [SlaveReqHandler] acts as a server on the slave side, to handle service requests from
 /// masters on the master communication channel. It's actually a proxy invoking the registered
 /// handler implementing [VhostUserSlaveReqHandler] to do the real work.
@@ -247,11 +799,41 @@ pub struct SlaveReqHandler<S: VhostUserSlaveReqHandler> {
     acked_virtio_features: u64,
     protocol_features: VhostUserProtocolFeatures,
     acked_protocol_features: u64,
+    // whether the master acked the virtio features with SET_FEATURES
+    features_acked: bool,
 
     // sending ack for messages without payload
     reply_ack_enabled: bool,
     // whether the endpoint has encountered any failure
     error: Option<i32>,
+    // end of the device configuration space accessed by GET_CONFIG and SET_CONFIG
+    config_size: u32,
+    // layout of the device configuration space declared with `set_config_space()`
+    config_space: Option<ConfigSpace>,
+    // userfaultfd of the postcopy migration, from POSTCOPY_ADVISE to POSTCOPY_END
+    uffd: Option<Arc<Userfaultfd>>,
+    // whether the migration switched to postcopy mode with POSTCOPY_LISTEN
+    postcopy_listening: bool,
+    // mapper of the guest memory mapped by the handler, see `set_map_memory()`
+    memory: Option<MemoryMappingManager>,
+    // whether the handler allocates the inflight I/O tracking buffer
+    track_inflight: bool,
+    // whether the handler has been shut down, see `shutdown_handle()`
+    closing: Arc<AtomicBool>,
+    // partial request received by handle_events(), and its attached files
+    rx_buf: Vec<u8>,
+    rx_files: Option<Vec<File>>,
+    // acks of the requests completed asynchronously by the backend, see `take_deferred_acks()`
+    deferred_acks: Vec<SlaveDeferredAck>,
+    // number of deferred acks the master is waiting for
+    pending_acks: Arc<AtomicUsize>,
+    // state handed over to a new process of the slave, see `set_track_upgrade()`
+    upgrade: Option<SlaveUpgradeState>,
+    // statistics of the requests served, by request code
+    stats: BTreeMap<u32, MasterReqStats>,
+    // time above which requests are reported as slow, and the callback reporting them
+    slow_threshold: Option<Duration>,
+    slow_handler: Option<SlowRequestHandler>,
 }
 
 impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
@@ -264,8 +846,24 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
             acked_virtio_features: 0,
             protocol_features: VhostUserProtocolFeatures::empty(),
             acked_protocol_features: 0,
+            features_acked: false,
             reply_ack_enabled: false,
             error: None,
+            config_size: VHOST_USER_CONFIG_SIZE,
+            config_space: None,
+            uffd: None,
+            postcopy_listening: false,
+            memory: None,
+            track_inflight: false,
+            closing: Arc::new(AtomicBool::new(false)),
+            rx_buf: Vec::new(),
+            rx_files: None,
+            deferred_acks: Vec::new(),
+            pending_acks: Arc::new(AtomicUsize::new(0)),
+            upgrade: None,
+            stats: BTreeMap::new(),
+            slow_threshold: None,
+            slow_handler: None,
         }
     }
 
@@ -283,78 +881,615 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         Ok(Self::new(Endpoint::<MasterReq>::connect(path)?, backend))
     }
 
-    /// Mark endpoint as failed with specified error code.
-    pub fn set_failed(&mut self, error: i32) {
-        self.error = Some(error);
+    /// Set the end of the device configuration space, for devices with a configuration space
+    /// larger than the default `VHOST_USER_CONFIG_SIZE`.
+    pub fn set_config_size(&mut self, config_size: u32) -> Result<()> {
+        if config_size <= VHOST_USER_CONFIG_OFFSET {
+            return Err(Error::InvalidParam);
+        }
+        self.config_size = config_size;
+        Ok(())
     }
 
-    /// Main entrance to server slave request from the slave communication channel.
+    /// Declare the layout of the device configuration space, so GET_CONFIG and SET_CONFIG are
+    /// validated against it before reaching the backend, see [ConfigSpace::check_access()].
     ///
-    /// Receive and handle one incoming request message from the master. The caller needs to:
-    /// - serialize calls to this function
-    /// - decide what to do when error happens
-    /// - optional recover from failure
-    pub fn handle_request(&mut self) -> Result<()> {
-        // Return error if the endpoint is already in failed state.
-        self.check_state()?;
+    /// The end of the configuration space is set as by [Self::set_config_size()]. Invalid
+    /// requests are rejected with an empty GET_CONFIG reply, or with a failure ack of SET_CONFIG
+    /// if REPLY_ACK was negotiated.
+    ///
+    /// [ConfigSpace::check_access()]: struct.ConfigSpace.html#method.check_access
+    /// [Self::set_config_size()]: struct.SlaveReqHandler.html#method.set_config_size
+    pub fn set_config_space(&mut self, space: ConfigSpace) -> Result<()> {
+        if !space.is_valid() {
+            return Err(Error::InvalidParam);
+        }
+        let config_size = VHOST_USER_CONFIG_OFFSET
+            .checked_add(space.size())
+            .ok_or(Error::InvalidParam)?;
+        self.set_config_size(config_size)?;
+        self.config_space = Some(space);
+        Ok(())
+    }
 
-        // The underlying communication channel is a Unix domain socket in
-        // stream mode, and recvmsg() is a little tricky here. To successfully
-        // receive attached file descriptors, we need to receive messages and
-        // corresponding attached file descriptors in this way:
-        // . recv messsage header and optional attached file
-        // . validate message header
-        // . recv optional message body and payload according size field in
-        //   message header
-        // . validate message body and optional payload
-        let (hdr, files) = self.main_sock.recv_header()?;
-        self.check_attached_files(&hdr, &files)?;
+    /// Map the guest memory regions in the handler, instead of leaving it to the backend.
+    ///
+    /// The backend is then passed the mapped regions by the `set_mapped_mem_table()`,
+    /// `add_mapped_mem_region()` and `remove_mapped_mem_region()` methods of
+    /// [VhostUserSlaveReqHandler], so ADD_MEM_REG and REM_MEM_REG only map or unmap the region
+    /// concerned, and the master addresses are translated with [Self::mapped_memory()].
+    ///
+    /// In postcopy mode, the regions are registered with the userfaultfd as they are mapped, so
+    /// the backend isn't asked for its `set_mem_table_postcopy()` and `add_mem_region_postcopy()`
+    /// mappings.
+    ///
+    /// [VhostUserSlaveReqHandler]: trait.VhostUserSlaveReqHandler.html
+    /// [Self::mapped_memory()]: struct.SlaveReqHandler.html#method.mapped_memory
+    pub fn set_map_memory(&mut self, enable: bool) {
+        self.memory = if enable {
+            Some(MemoryMappingManager::new())
+        } else {
+            None
+        };
+    }
 
-        let (size, buf) = match hdr.get_size() {
-            0 => (0, vec![0u8; 0]),
-            len => {
-                let (size2, rbuf) = self.main_sock.recv_data(len as usize)?;
-                if size2 != len as usize {
-                    return Err(Error::InvalidMessage);
-                }
-                (size2, rbuf)
-            }
+    /// Map the guest memory regions in the handler with `manager`, see [Self::set_map_memory()].
+    ///
+    /// The options of `manager`, such as MAP_POPULATE or the Xen mapping hooks, apply to the
+    /// regions mapped afterwards.
+    ///
+    /// [Self::set_map_memory()]: struct.SlaveReqHandler.html#method.set_map_memory
+    pub fn set_memory_manager(&mut self, manager: MemoryMappingManager) {
+        self.memory = Some(manager);
+    }
+
+    /// Get the guest memory mapped by the handler, if enabled by [Self::set_map_memory()].
+    ///
+    /// [Self::set_map_memory()]: struct.SlaveReqHandler.html#method.set_map_memory
+    pub fn mapped_memory(&self) -> Option<&MappedMemory> {
+        self.memory.as_ref().map(|manager| manager.memory())
+    }
+
+    /// Allocate and map the inflight I/O tracking buffer in the handler, instead of leaving it to
+    /// the backend.
+    ///
+    /// The handler allocates a zeroed buffer for GET_INFLIGHT_FD, sized for the split or packed
+    /// virtqueues according to the acked virtio features. The buffer of SET_INFLIGHT_FD, which
+    /// the master keeps across reconnections, is mapped and passed to the backend with the
+    /// `set_inflight_region()` method of [VhostUserSlaveReqHandler].
+    ///
+    /// [VhostUserSlaveReqHandler]: trait.VhostUserSlaveReqHandler.html
+    pub fn set_track_inflight(&mut self, enable: bool) {
+        self.track_inflight = enable;
+    }
+
+    /// Record the memory table, the vrings and the slave communication channel set up by the
+    /// master, to hand them over to a new process of the slave with [Self::upgrade_state()].
+    ///
+    /// The handler keeps duplicates of the files passed to the backend, until the master replaces
+    /// them.
+    ///
+    /// [Self::upgrade_state()]: struct.SlaveReqHandler.html#method.upgrade_state
+    pub fn set_track_upgrade(&mut self, enable: bool) {
+        self.upgrade = if enable {
+            Some(SlaveUpgradeState::default())
+        } else {
+            None
         };
+    }
 
-        match hdr.get_code() {
-            MasterReq::SET_OWNER => {
-                self.check_request_size(&hdr, size, 0)?;
-                let res = self.backend.set_owner();
-                self.send_ack_message(&hdr, res)?;
-            }
-            MasterReq::RESET_OWNER => {
-                self.check_request_size(&hdr, size, 0)?;
-                let res = self.backend.reset_owner();
-                self.send_ack_message(&hdr, res)?;
-            }
-            MasterReq::GET_FEATURES => {
-                self.check_request_size(&hdr, size, 0)?;
-                let features = self.backend.get_features()?;
-                let msg = VhostUserU64::new(features);
-                self.send_reply_message(&hdr, &msg)?;
-                self.virtio_features = features;
-                self.update_reply_ack_flag();
-            }
+    /// Get the state of the handler to hand over to a new process of the slave, see
+    /// [SlaveUpgradeState].
+    ///
+    /// The started vrings are stopped with the `get_vring_base()` method of the backend, so the
+    /// new process restarts them from their current position.
+    ///
+    /// # Return:
+    /// * - InvalidOperation: the state isn't recorded, see [Self::set_track_upgrade()], or a
+    ///     postcopy migration is in progress.
+    ///
+    /// [SlaveUpgradeState]: struct.SlaveUpgradeState.html
+    /// [Self::set_track_upgrade()]: struct.SlaveReqHandler.html#method.set_track_upgrade
+    pub fn upgrade_state(&mut self) -> Result<SlaveUpgradeState> {
+        if self.uffd.is_some() {
+            return Err(Error::InvalidOperation);
+        }
+        let tracked = self.upgrade.as_mut().ok_or(Error::InvalidOperation)?;
+        for vring in tracked.vrings.iter_mut().filter(|vring| vring.started) {
+            vring.base = self.backend.get_vring_base(vring.index)?;
+        }
+
+        let mut state = tracked.try_clone()?;
+        state.virtio_features = self.virtio_features;
+        state.acked_virtio_features = self.acked_virtio_features;
+        state.protocol_features = self.protocol_features;
+        state.acked_protocol_features = self.acked_protocol_features;
+        state.features_acked = self.features_acked;
+        state.config_size = self.config_size;
+        Ok(state)
+    }
+
+    /// Hand the handler over to a new process of the slave through `peer`, see
+    /// [SlaveUpgradeState::send()].
+    ///
+    /// The handler must not serve requests afterwards, the master being served by the new
+    /// process.
+    ///
+    /// [SlaveUpgradeState::send()]: struct.SlaveUpgradeState.html#method.send
+    pub fn send_upgrade(&mut self, peer: &UnixStream) -> Result<()> {
+        let state = self.upgrade_state()?;
+        state.send(peer, self.main_sock.as_raw_fd())
+    }
+
+    /// Restore the state handed over by the old process of the slave, once the handler has been
+    /// created from the socket received with [SlaveUpgradeState::recv()] and configured.
+    ///
+    /// The state is replayed to the backend as the master set it up, the memory table being
+    /// mapped by the handler if enabled by [Self::set_map_memory()].
+    ///
+    /// [SlaveUpgradeState::recv()]: struct.SlaveUpgradeState.html#method.recv
+    /// [Self::set_map_memory()]: struct.SlaveReqHandler.html#method.set_map_memory
+    pub fn restore_upgrade_state(&mut self, state: SlaveUpgradeState) -> Result<()> {
+        if self.upgrade.is_some() {
+            self.upgrade = Some(state.try_clone()?);
+        }
+
+        self.backend.set_owner()?;
+        self.virtio_features = state.virtio_features;
+        self.protocol_features = state.protocol_features;
+        self.config_size = state.config_size;
+        if state.acked_protocol_features != 0 {
+            self.backend
+                .set_protocol_features(state.acked_protocol_features)?;
+            self.acked_protocol_features = state.acked_protocol_features;
+        }
+        if state.features_acked {
+            self.backend.set_features(state.acked_virtio_features)?;
+            self.acked_virtio_features = state.acked_virtio_features;
+            self.features_acked = true;
+        }
+        self.update_reply_ack_flag();
+
+        if !state.regions.is_empty() {
+            let (regions, files): (Vec<_>, Vec<_>) = state.regions.into_iter().unzip();
+            self.apply_mem_table(&regions, files)?;
+        }
+        if let Some(sock) = state.slave_req {
+            self.backend
+                .set_slave_req_fd(SlaveFsCacheReq::from_stream(sock));
+        }
+
+        let can_enable =
+            self.acked_virtio_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0;
+        for vring in state.vrings {
+            let index = vring.index;
+            if vring.num != 0 {
+                self.backend.set_vring_num(index, vring.num)?;
+            }
+            if let Some(addr) = vring.addr {
+                let flags =
+                    VhostUserVringAddrFlags::from_bits(addr.flags).ok_or(Error::InvalidParam)?;
+                self.backend.set_vring_addr(
+                    index,
+                    flags,
+                    addr.descriptor,
+                    addr.used,
+                    addr.available,
+                    addr.log,
+                )?;
+            }
+            self.backend.set_vring_base(index, vring.base)?;
+            // The eventfds are only set with the 8-bit index of the fd requests.
+            if vring.call.is_some() {
+                self.backend.set_vring_call(index as u8, vring.call)?;
+            }
+            if vring.err.is_some() {
+                self.backend.set_vring_err(index as u8, vring.err)?;
+            }
+            if vring.started {
+                self.backend.set_vring_kick(index as u8, vring.kick)?;
+            }
+            if can_enable {
+                self.backend.set_vring_enable(index, vring.enabled)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the statistics of the requests served, by request code.
+    ///
+    /// The time spent serving a request covers the backend and the reply or ack. Requests
+    /// completed asynchronously by the backend are accounted for once their ack is deferred.
+    pub fn stats(&self) -> &BTreeMap<u32, MasterReqStats> {
+        &self.stats
+    }
+
+    /// Clear the statistics of the requests served.
+    pub fn reset_stats(&mut self) {
+        self.stats.clear();
+    }
+
+    /// Flag the requests served slower than `threshold` in the statistics, or stop flagging them
+    /// with `None`.
+    pub fn set_slow_request_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_threshold = threshold;
+    }
+
+    /// Set the callback reporting the requests flagged as slow, or `None` to only count them.
+    pub fn set_slow_request_handler(&mut self, handler: Option<SlowRequestHandler>) {
+        self.slow_handler = handler;
+    }
+
+    /// Get a handle shutting down the handler gracefully from any thread.
+    pub fn shutdown_handle(&self) -> Result<SlaveShutdown<S>> {
+        Ok(SlaveShutdown {
+            backend: self.backend.clone(),
+            sock: self.main_sock.try_clone()?,
+            closing: self.closing.clone(),
+        })
+    }
+
+    /// Shut down the handler gracefully, see [SlaveShutdown::shutdown()].
+    ///
+    /// [SlaveShutdown::shutdown()]: struct.SlaveShutdown.html#method.shutdown
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<()> {
+        self.shutdown_handle()?.shutdown(timeout)
+    }
+
+    /// Take the acks of the requests completed asynchronously by the backend.
+    ///
+    /// The backend completes a request asynchronously by failing it with `EINPROGRESS`. The
+    /// request is then acknowledged, if REPLY_ACK was negotiated and the master asked for it, when
+    /// the backend completes it with [SlaveDeferredAck::complete()]. The changes of the request
+    /// are recorded for live upgrades once the backend accepts it, see [Self::set_track_upgrade()].
+    ///
+    /// The acks are sent on the thread completing the requests, so the master must wait for the
+    /// ack of a request before sending the next one, or the replies of the later requests could
+    /// be sent before the ack. The handler fails with `InvalidOperation` the requests received
+    /// while an ack is deferred, without serving them.
+    ///
+    /// [Self::set_track_upgrade()]: struct.SlaveReqHandler.html#method.set_track_upgrade
+    /// [SlaveDeferredAck::complete()]: struct.SlaveDeferredAck.html#method.complete
+    pub fn take_deferred_acks(&mut self) -> Vec<SlaveDeferredAck> {
+        mem::take(&mut self.deferred_acks)
+    }
+
+    // Get the backend serving the requests.
+    #[cfg(feature = "vhost-user-slave-async")]
+    pub(super) fn backend(&self) -> &Arc<S> {
+        &self.backend
+    }
+
+    /// Mark endpoint as failed with specified error code.
+    pub fn set_failed(&mut self, error: i32) {
+        self.error = Some(error);
+    }
+
+    /// Main entrance to server slave request from the slave communication channel.
+    ///
+    /// Receive and handle one incoming request message from the master. The caller needs to:
+    /// - serialize calls to this function
+    /// - decide what to do when error happens
+    /// - optional recover from failure
+    pub fn handle_request(&mut self) -> Result<()> {
+        let (hdr, size, buf, files) = self.recv_request()?;
+        self.dispatch_request(hdr, size, buf, files)
+    }
+
+    /// Serve the requests received from the master communication channel, without blocking.
+    ///
+    /// This is the entrance for event loops polling the fd returned by `as_raw_fd()`, possibly
+    /// along with the channels of other masters, after the channel has been switched to
+    /// non-blocking mode by [Self::set_nonblocking()]. It receives whatever is available on the
+    /// channel, keeping partial requests until they are complete, and serves every complete
+    /// request. It returns the number of requests served once the channel is drained, so the
+    /// caller may poll the fd again.
+    ///
+    /// A failure to serve a request is returned right away, the requests received after it are
+    /// served by the next call. The caller must not mix calls to this function and to
+    /// [Self::handle_request()].
+    ///
+    /// [Self::set_nonblocking()]: struct.SlaveReqHandler.html#method.set_nonblocking
+    /// [Self::handle_request()]: struct.SlaveReqHandler.html#method.handle_request
+    pub fn handle_events(&mut self) -> Result<usize> {
+        self.check_state()?;
+
+        let hdr_size = mem::size_of::<VhostUserMsgHeader<MasterReq>>();
+        let mut served = 0;
+        loop {
+            self.check_closing()?;
+            // Receive up to the end of the pending request only, so the files attached to the
+            // next request aren't received along with it.
+            let want = match self.pending_request_size()? {
+                Some(size) if self.rx_buf.len() == size => {
+                    let mut buf = mem::take(&mut self.rx_buf);
+                    let files = self.rx_files.take();
+                    let body = buf.split_off(hdr_size);
+                    // Safe because the buffer holds a whole header.
+                    let hdr = unsafe {
+                        std::ptr::read_unaligned(
+                            buf.as_ptr() as *const VhostUserMsgHeader<MasterReq>
+                        )
+                    };
+                    if !hdr.is_custom() {
+                        self.check_attached_files(&hdr, &files)?;
+                    }
+                    self.check_deferred_acks()?;
+                    self.dispatch_request(hdr, body.len(), body, files)?;
+                    served += 1;
+                    continue;
+                }
+                Some(size) => size - self.rx_buf.len(),
+                None => hdr_size - self.rx_buf.len(),
+            };
+            match self.main_sock.recv_into_buf(want) {
+                Ok((0, _, _)) => return Err(Error::Disconnected),
+                Ok((bytes, buf, files)) => {
+                    self.rx_buf.extend_from_slice(&buf[..bytes]);
+                    if let Some(files) = files {
+                        self.rx_files.get_or_insert_with(Vec::new).extend(files);
+                    }
+                }
+                Err(Error::SocketRetry(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(served)
+                }
+                Err(Error::SocketRetry(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Get the size of the request being received by handle_events(), once its header has been
+    // received.
+    fn pending_request_size(&mut self) -> Result<Option<usize>> {
+        let hdr_size = mem::size_of::<VhostUserMsgHeader<MasterReq>>();
+        if self.rx_buf.len() < hdr_size {
+            return Ok(None);
+        }
+        // Safe because the buffer holds a whole header.
+        let hdr = unsafe {
+            std::ptr::read_unaligned(self.rx_buf.as_ptr() as *const VhostUserMsgHeader<MasterReq>)
+        };
+        if !hdr.is_valid() {
+            // The channel is out of sync, drop what has been received.
+            self.rx_buf.clear();
+            self.rx_files = None;
+            return Err(Error::InvalidMessage);
+        }
+        Ok(Some(hdr_size + hdr.get_size() as usize))
+    }
+
+    /// Switch the master communication channel to non-blocking mode, for
+    /// [Self::handle_events()].
+    ///
+    /// [Self::handle_events()]: struct.SlaveReqHandler.html#method.handle_events
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> Result<()> {
+        self.main_sock.set_nonblocking(nonblocking)
+    }
+
+    // Receive one request from the master, with its body and attached files.
+    pub(super) fn recv_request(&mut self) -> Result<ReceivedRequest> {
+        // Return error if the endpoint is already in failed state.
+        self.check_state()?;
+        self.check_closing()?;
+
+        // The underlying communication channel is a Unix domain socket in
+        // stream mode, and recvmsg() is a little tricky here. To successfully
+        // receive attached file descriptors, we need to receive messages and
+        // corresponding attached file descriptors in this way:
+        // . recv messsage header and optional attached file
+        // . validate message header
+        // . recv optional message body and payload according size field in
+        //   message header
+        // . validate message body and optional payload
+        let (hdr, files) = self.main_sock.recv_header()?;
+        if !hdr.is_custom() {
+            self.check_attached_files(&hdr, &files)?;
+        }
+
+        let (size, buf) = match hdr.get_size() {
+            0 => (0, vec![0u8; 0]),
+            len => {
+                let (size2, rbuf) = self.main_sock.recv_data(len as usize)?;
+                if size2 != len as usize {
+                    return Err(Error::InvalidMessage);
+                }
+                (size2, rbuf)
+            }
+        };
+
+        // The request may have been received while shutting down.
+        self.check_closing()?;
+        self.check_deferred_acks()?;
+        Ok((hdr, size, buf, files))
+    }
+
+    // Refuse to serve a request while the master waits for a deferred ack, as its reply would
+    // be sent before the ack.
+    fn check_deferred_acks(&self) -> Result<()> {
+        if self.pending_acks.load(Ordering::SeqCst) != 0 {
+            return Err(Error::InvalidOperation);
+        }
+        Ok(())
+    }
+
+    // Serve a request received by `recv_request()`, and record the time spent serving it.
+    pub(super) fn dispatch_request(
+        &mut self,
+        hdr: VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: Vec<u8>,
+        files: Option<Vec<File>>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let res = self.serve_dispatched(hdr, size, buf, files);
+        self.record_request(&hdr, start.elapsed(), res.is_err());
+        res
+    }
+
+    // Serve a request received by `recv_request()`, without recording it.
+    pub(super) fn serve_dispatched(
+        &mut self,
+        hdr: VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: Vec<u8>,
+        files: Option<Vec<File>>,
+    ) -> Result<()> {
+        if hdr.is_custom() {
+            return self.custom_request(&hdr, &buf, files);
+        }
+        if self.has_reply(hdr.get_code()) {
+            return self.serve_request(&hdr, size, buf, files);
+        }
+        let res = self.serve_request(&hdr, size, buf, files);
+        self.ack_request(&hdr, res)
+    }
+
+    // Acknowledge a request without reply with the result of serving it, malformed requests
+    // included, or defer the ack until the backend completes the request.
+    pub(super) fn ack_request(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        res: Result<()>,
+    ) -> Result<()> {
+        if let Err(Error::ReqHandlerError(e)) = &res {
+            if e.raw_os_error() == Some(libc::EINPROGRESS) {
+                let sock = match self.reply_ack_enabled && hdr.is_need_reply() {
+                    true => Some(self.main_sock.try_clone()?),
+                    false => None,
+                };
+                if sock.is_some() {
+                    self.pending_acks.fetch_add(1, Ordering::SeqCst);
+                }
+                self.deferred_acks.push(SlaveDeferredAck {
+                    sock,
+                    hdr: *hdr,
+                    pending: self.pending_acks.clone(),
+                });
+                return Ok(());
+            }
+        }
+        self.send_ack_message(hdr, res)
+    }
+
+    // Account for a request served in `latency`, reporting it if it was slow.
+    pub(super) fn record_request(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        latency: Duration,
+        failed: bool,
+    ) {
+        let slow = match self.slow_threshold {
+            Some(threshold) => latency > threshold,
+            None => false,
+        };
+        let code = hdr.get_raw_code();
+        self.stats
+            .entry(code)
+            .or_default()
+            .record(latency, failed, slow);
+        if slow {
+            if let Some(handler) = self.slow_handler.as_ref() {
+                handler(code, latency);
+            }
+        }
+    }
+
+    // Whether the request is answered with a reply of its own rather than with an ack.
+    fn has_reply(&self, code: MasterReq) -> bool {
+        match code {
+            MasterReq::SET_MEM_TABLE | MasterReq::ADD_MEM_REG => self.postcopy_listening,
+            MasterReq::SET_LOG_BASE => {
+                self.acked_protocol_features & VhostUserProtocolFeatures::LOG_SHMFD.bits() != 0
+            }
+            MasterReq::GET_FEATURES
+            | MasterReq::GET_PROTOCOL_FEATURES
+            | MasterReq::GET_VRING_BASE
+            | MasterReq::GET_QUEUE_NUM
+            | MasterReq::GET_CONFIG
+            | MasterReq::GET_INFLIGHT_FD
+            | MasterReq::GET_MAX_MEM_SLOTS
+            | MasterReq::GET_SHARED_OBJECT
+            | MasterReq::CREATE_CRYPTO_SESSION
+            | MasterReq::GET_SHMEM_CONFIG
+            | MasterReq::POSTCOPY_ADVISE
+            | MasterReq::POSTCOPY_LISTEN
+            | MasterReq::POSTCOPY_END
+            | MasterReq::GET_STATUS
+            | MasterReq::SET_DEVICE_STATE_FD
+            | MasterReq::CHECK_DEVICE_STATE => true,
+            _ => false,
+        }
+    }
+
+    fn serve_request(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: Vec<u8>,
+        files: Option<Vec<File>>,
+    ) -> Result<()> {
+        let hdr = *hdr;
+        self.check_order(hdr.get_code())?;
+
+        match hdr.get_code() {
+            MasterReq::SET_OWNER => {
+                self.check_request_size(&hdr, size, 0)?;
+                self.backend.set_owner()?;
+            }
+            MasterReq::RESET_OWNER => {
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.reset_owner();
+                self.features_acked = false;
+                let res = accepted(res)?;
+                self.track_upgrade(|state| {
+                    state.vrings.clear();
+                    Ok(())
+                })?;
+                res?;
+            }
+            MasterReq::GET_FEATURES => {
+                self.check_request_size(&hdr, size, 0)?;
+                let features = self.backend.get_features()?;
+                let msg = VhostUserU64::new(features);
+                self.send_reply_message(&hdr, &msg)?;
+                self.virtio_features = features;
+                self.update_reply_ack_flag();
+            }
             MasterReq::SET_FEATURES => {
                 let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, &buf)?;
                 let res = self.backend.set_features(msg.value);
                 self.acked_virtio_features = msg.value;
+                self.features_acked = true;
                 self.update_reply_ack_flag();
-                self.send_ack_message(&hdr, res)?;
+                res?;
             }
             MasterReq::SET_MEM_TABLE => {
-                let res = self.set_mem_table(&hdr, size, &buf, files);
-                self.send_ack_message(&hdr, res)?;
+                self.check_xen_mmap()?;
+                let tracked = self.track_files(files.iter().flatten())?;
+                let res = accepted(if self.postcopy_listening {
+                    self.set_mem_table_postcopy(&hdr, size, &buf, files)
+                } else {
+                    self.set_mem_table(&hdr, size, &buf, files)
+                })?;
+                if let Some(files) = tracked {
+                    let (regions, files) = self.extract_mem_table(&hdr, size, &buf, Some(files))?;
+                    let regions = regions.iter().copied().zip(files).collect();
+                    self.track_upgrade(|state| {
+                        state.regions = regions;
+                        Ok(())
+                    })?;
+                }
+                res?;
             }
             MasterReq::SET_VRING_NUM => {
                 let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, &buf)?;
-                let res = self.backend.set_vring_num(msg.index, msg.num);
-                self.send_ack_message(&hdr, res)?;
+                let res = accepted(self.backend.set_vring_num(msg.index, msg.num))?;
+                self.track_upgrade(|state| {
+                    state.vring_mut(msg.index)?.num = msg.num;
+                    Ok(())
+                })?;
+                res?;
             }
             MasterReq::SET_VRING_ADDR => {
                 let msg = self.extract_request_body::<VhostUserVringAddr>(&hdr, size, &buf)?;
@@ -362,43 +1497,84 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                     Some(val) => val,
                     None => return Err(Error::InvalidMessage),
                 };
-                let res = self.backend.set_vring_addr(
+                let res = accepted(self.backend.set_vring_addr(
                     msg.index,
                     flags,
                     msg.descriptor,
                     msg.used,
                     msg.available,
                     msg.log,
-                );
-                self.send_ack_message(&hdr, res)?;
+                ))?;
+                self.track_upgrade(|state| {
+                    state.vring_mut(msg.index)?.addr = Some(msg);
+                    Ok(())
+                })?;
+                res?;
             }
             MasterReq::SET_VRING_BASE => {
                 let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, &buf)?;
-                let res = self.backend.set_vring_base(msg.index, msg.num);
-                self.send_ack_message(&hdr, res)?;
+                let base =
+                    VringBase::from_num(msg.num, self.is_packed()).ok_or(Error::InvalidParam)?;
+                let res = accepted(self.backend.set_vring_base(msg.index, base))?;
+                self.track_upgrade(|state| {
+                    state.vring_mut(msg.index)?.base = base;
+                    Ok(())
+                })?;
+                res?;
             }
             MasterReq::GET_VRING_BASE => {
                 let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, &buf)?;
-                let reply = self.backend.get_vring_base(msg.index)?;
+                let base = self.backend.get_vring_base(msg.index)?;
+                // The backend must not report the position of the other vring layout.
+                let num = match base.to_num() {
+                    Some(num) if base.is_packed() == self.is_packed() => num,
+                    _ => return Err(Error::InvalidParam),
+                };
+                let reply = VhostUserVringState::new(msg.index, num);
                 self.send_reply_message(&hdr, &reply)?;
+                self.track_upgrade(|state| {
+                    let vring = state.vring_mut(msg.index)?;
+                    vring.base = base;
+                    vring.started = false;
+                    Ok(())
+                })?;
             }
             MasterReq::SET_VRING_CALL => {
                 self.check_request_size(&hdr, size, mem::size_of::<VhostUserU64>())?;
                 let (index, file) = self.handle_vring_fd_request(&buf, files)?;
-                let res = self.backend.set_vring_call(index, file);
-                self.send_ack_message(&hdr, res)?;
+                let tracked = self.track_files(&file)?;
+                let res = accepted(self.backend.set_vring_call(index, file))?;
+                self.track_upgrade(|state| {
+                    let vring = state.vring_mut(u32::from(index))?;
+                    vring.call = tracked.and_then(|mut files| files.pop());
+                    Ok(())
+                })?;
+                res?;
             }
             MasterReq::SET_VRING_KICK => {
                 self.check_request_size(&hdr, size, mem::size_of::<VhostUserU64>())?;
                 let (index, file) = self.handle_vring_fd_request(&buf, files)?;
-                let res = self.backend.set_vring_kick(index, file);
-                self.send_ack_message(&hdr, res)?;
+                let tracked = self.track_files(&file)?;
+                let res = accepted(self.backend.set_vring_kick(index, file))?;
+                self.track_upgrade(|state| {
+                    let vring = state.vring_mut(u32::from(index))?;
+                    vring.kick = tracked.and_then(|mut files| files.pop());
+                    vring.started = true;
+                    Ok(())
+                })?;
+                res?;
             }
             MasterReq::SET_VRING_ERR => {
                 self.check_request_size(&hdr, size, mem::size_of::<VhostUserU64>())?;
                 let (index, file) = self.handle_vring_fd_request(&buf, files)?;
-                let res = self.backend.set_vring_err(index, file);
-                self.send_ack_message(&hdr, res)?;
+                let tracked = self.track_files(&file)?;
+                let res = accepted(self.backend.set_vring_err(index, file))?;
+                self.track_upgrade(|state| {
+                    let vring = state.vring_mut(u32::from(index))?;
+                    vring.err = tracked.and_then(|mut files| files.pop());
+                    Ok(())
+                })?;
+                res?;
             }
             MasterReq::GET_PROTOCOL_FEATURES => {
                 self.check_request_size(&hdr, size, 0)?;
@@ -413,12 +1589,10 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 let res = self.backend.set_protocol_features(msg.value);
                 self.acked_protocol_features = msg.value;
                 self.update_reply_ack_flag();
-                self.send_ack_message(&hdr, res)?;
+                res?;
             }
             MasterReq::GET_QUEUE_NUM => {
-                if self.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() == 0 {
-                    return Err(Error::InvalidOperation);
-                }
+                self.check_protocol_feature(VhostUserProtocolFeatures::MQ)?;
                 self.check_request_size(&hdr, size, 0)?;
                 let num = self.backend.get_queue_num()?;
                 let msg = VhostUserU64::new(num);
@@ -437,96 +1611,234 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                     _ => return Err(Error::InvalidParam),
                 };
 
-                let res = self.backend.set_vring_enable(msg.index, enable);
-                self.send_ack_message(&hdr, res)?;
+                let res = accepted(self.backend.set_vring_enable(msg.index, enable))?;
+                self.track_upgrade(|state| {
+                    state.vring_mut(msg.index)?.enabled = enable;
+                    Ok(())
+                })?;
+                res?;
             }
             MasterReq::GET_CONFIG => {
-                if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
-                    return Err(Error::InvalidOperation);
+                let (msg, flags) = self.config_request(&hdr, size, &buf)?;
+                if let Err(e) = self.check_config_access(&msg, flags, false) {
+                    self.send_config_reply(&hdr, &msg, flags, Err(Error::InvalidParam))?;
+                    return Err(e);
                 }
-                self.check_request_size(&hdr, size, hdr.get_size() as usize)?;
-                self.get_config(&hdr, &buf)?;
+                let res = self.backend.get_config(msg.offset, msg.size, flags);
+                self.send_config_reply(&hdr, &msg, flags, res)?;
             }
             MasterReq::SET_CONFIG => {
-                if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
-                    return Err(Error::InvalidOperation);
-                }
-                self.check_request_size(&hdr, size, hdr.get_size() as usize)?;
-                let res = self.set_config(size, &buf);
-                self.send_ack_message(&hdr, res)?;
+                let (msg, flags) = self.config_request(&hdr, size, &buf)?;
+                self.check_config_access(&msg, flags, true)?;
+                let payload = &buf[mem::size_of::<VhostUserConfig>()..];
+                self.backend.set_config(msg.offset, payload, flags)?;
             }
             MasterReq::SET_SLAVE_REQ_FD => {
-                if self.acked_protocol_features & VhostUserProtocolFeatures::SLAVE_REQ.bits() == 0 {
-                    return Err(Error::InvalidOperation);
-                }
+                self.check_protocol_feature(VhostUserProtocolFeatures::SLAVE_REQ)?;
                 self.check_request_size(&hdr, size, hdr.get_size() as usize)?;
-                let res = self.set_slave_req_fd(files);
-                self.send_ack_message(&hdr, res)?;
+                self.set_slave_req_fd(files)?;
             }
             MasterReq::GET_INFLIGHT_FD => {
-                if self.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits()
-                    == 0
-                {
-                    return Err(Error::InvalidOperation);
-                }
+                self.check_protocol_feature(VhostUserProtocolFeatures::INFLIGHT_SHMFD)?;
 
                 let msg = self.extract_request_body::<VhostUserInflight>(&hdr, size, &buf)?;
-                let (inflight, file) = self.backend.get_inflight_fd(&msg)?;
+                let (inflight, file) = if self.track_inflight {
+                    let region =
+                        InflightRegion::new(msg.num_queues, msg.queue_size, self.is_packed())?;
+                    (
+                        region.inflight(),
+                        region.file().try_clone().map_err(Error::ReqHandlerError)?,
+                    )
+                } else {
+                    self.backend.get_inflight_fd(&msg)?
+                };
                 let reply_hdr = self.new_reply_header::<VhostUserInflight>(&hdr, 0)?;
                 self.main_sock
                     .send_message(&reply_hdr, &inflight, Some(&[file.as_raw_fd()]))?;
             }
             MasterReq::SET_INFLIGHT_FD => {
-                if self.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits()
-                    == 0
-                {
-                    return Err(Error::InvalidOperation);
-                }
+                self.check_protocol_feature(VhostUserProtocolFeatures::INFLIGHT_SHMFD)?;
                 let file = take_single_file(files).ok_or(Error::IncorrectFds)?;
                 let msg = self.extract_request_body::<VhostUserInflight>(&hdr, size, &buf)?;
-                let res = self.backend.set_inflight_fd(&msg, file);
-                self.send_ack_message(&hdr, res)?;
+                if self.track_inflight {
+                    InflightRegion::from_file(&msg, file, self.is_packed())
+                        .and_then(|region| self.backend.set_inflight_region(Arc::new(region)))
+                } else {
+                    self.backend.set_inflight_fd(&msg, file)
+                }?;
+            }
+            MasterReq::SET_LOG_BASE => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::LOG_SHMFD)?;
+                let res = self.set_dirty_log(&hdr, size, &buf, files);
+                self.send_result_message(&hdr, res)?;
             }
             MasterReq::GET_MAX_MEM_SLOTS => {
-                if self.acked_protocol_features
-                    & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits()
-                    == 0
-                {
-                    return Err(Error::InvalidOperation);
-                }
+                self.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
                 self.check_request_size(&hdr, size, 0)?;
                 let num = self.backend.get_max_mem_slots()?;
                 let msg = VhostUserU64::new(num);
                 self.send_reply_message(&hdr, &msg)?;
             }
             MasterReq::ADD_MEM_REG => {
-                if self.acked_protocol_features
-                    & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits()
-                    == 0
-                {
-                    return Err(Error::InvalidOperation);
-                }
+                self.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
+                self.check_xen_mmap()?;
                 let mut files = files.ok_or(Error::InvalidParam)?;
                 if files.len() != 1 {
                     return Err(Error::InvalidParam);
                 }
                 let msg =
                     self.extract_request_body::<VhostUserSingleMemoryRegion>(&hdr, size, &buf)?;
-                let res = self.backend.add_mem_region(&msg, files.swap_remove(0));
-                self.send_ack_message(&hdr, res)?;
+                let tracked = self.track_files(&files)?;
+                let res = accepted(if self.postcopy_listening {
+                    self.add_mem_region_postcopy(&hdr, &msg, files.swap_remove(0))
+                } else if self.memory.is_some() {
+                    self.add_mapped_mem_region(&msg, files.swap_remove(0))
+                        .map(drop)
+                } else {
+                    self.backend.add_mem_region(&msg, files.swap_remove(0))
+                })?;
+                if let Some(mut files) = tracked {
+                    let region = VhostUserMemoryRegion::from(&msg);
+                    self.track_upgrade(|state| {
+                        state.regions.push((region, files.swap_remove(0)));
+                        Ok(())
+                    })?;
+                }
+                res?;
             }
             MasterReq::REM_MEM_REG => {
-                if self.acked_protocol_features
-                    & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits()
-                    == 0
-                {
-                    return Err(Error::InvalidOperation);
-                }
+                self.check_protocol_feature(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)?;
+                self.check_xen_mmap()?;
 
                 let msg =
                     self.extract_request_body::<VhostUserSingleMemoryRegion>(&hdr, size, &buf)?;
-                let res = self.backend.remove_mem_region(&msg);
-                self.send_ack_message(&hdr, res)?;
+                let res = accepted(if self.memory.is_some() {
+                    self.remove_mapped_mem_region(&msg)
+                } else {
+                    self.backend.remove_mem_region(&msg)
+                })?;
+                self.track_upgrade(|state| {
+                    state.regions.retain(|(region, _)| {
+                        msg.guest_phys_addr != { region.guest_phys_addr }
+                            || msg.memory_size != { region.memory_size }
+                    });
+                    Ok(())
+                })?;
+                res?;
+            }
+            MasterReq::GET_SHARED_OBJECT => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::SHARED_OBJECT)?;
+
+                let msg = self.extract_request_body::<VhostUserShared>(&hdr, size, &buf)?;
+                let reply_hdr = self.new_reply_header::<VhostUserU64>(&hdr, 0)?;
+                match self.backend.get_shared_object(&msg) {
+                    Ok(file) => {
+                        let msg = VhostUserU64::new(0);
+                        self.main_sock
+                            .send_message(&reply_hdr, &msg, Some(&[file.as_raw_fd()]))?;
+                    }
+                    Err(e) => {
+                        // Report the failure so the master doesn't wait for the fd.
+                        let msg = VhostUserU64::new(1);
+                        self.main_sock.send_message(&reply_hdr, &msg, None)?;
+                        return Err(e);
+                    }
+                }
+            }
+            MasterReq::CREATE_CRYPTO_SESSION => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::CRYPTO_SESSION)?;
+
+                let mut msg =
+                    self.extract_request_body::<VhostUserCryptoSession>(&hdr, size, &buf)?;
+                let res = self.backend.create_crypto_session(&msg);
+                // A negative session id reports the failure to the master.
+                msg.session_id = match res {
+                    Ok(id) if id >= 0 => id,
+                    _ => -1,
+                };
+                self.send_reply_message(&hdr, &msg)?;
+                res?;
+            }
+            MasterReq::CLOSE_CRYPTO_SESSION => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::CRYPTO_SESSION)?;
+
+                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, &buf)?;
+                self.backend.close_crypto_session(msg.value as i64)?;
+            }
+            MasterReq::GET_SHMEM_CONFIG => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::SHMEM)?;
+                self.check_request_size(&hdr, size, 0)?;
+                let sizes = self.backend.get_shmem_config()?;
+                let msg = VhostUserShMemConfig::new(&sizes).ok_or(Error::InvalidParam)?;
+                self.send_reply_message(&hdr, &msg)?;
+            }
+            MasterReq::POSTCOPY_ADVISE => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::PAGEFAULT)?;
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.postcopy_advise();
+                let reply_hdr = self.new_reply_header::<()>(&hdr, 0)?;
+                match res {
+                    Ok(file) => {
+                        self.main_sock
+                            .send_header(&reply_hdr, Some(&[file.as_raw_fd()]))?;
+                    }
+                    Err(e) => {
+                        // Report the failure so the master doesn't wait for the fd.
+                        self.main_sock.send_header(&reply_hdr, None)?;
+                        return Err(e);
+                    }
+                }
+            }
+            MasterReq::POSTCOPY_LISTEN => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::PAGEFAULT)?;
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.postcopy_listen();
+                self.send_result_message(&hdr, res)?;
+            }
+            MasterReq::POSTCOPY_END => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::PAGEFAULT)?;
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.postcopy_end();
+                self.send_result_message(&hdr, res)?;
+            }
+            MasterReq::RESET_DEVICE => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::RESET_DEVICE)?;
+                self.check_request_size(&hdr, size, 0)?;
+                let res = accepted(self.backend.reset_device())?;
+                self.track_upgrade(|state| {
+                    state.vrings.clear();
+                    Ok(())
+                })?;
+                res?;
+            }
+            MasterReq::SET_STATUS => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::STATUS)?;
+                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, &buf)?;
+                if msg.value > u64::from(u8::MAX) {
+                    Err(Error::InvalidParam)
+                } else {
+                    self.backend.set_status(msg.value as u8)
+                }?;
+            }
+            MasterReq::GET_STATUS => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::STATUS)?;
+                self.check_request_size(&hdr, size, 0)?;
+                let status = self.backend.get_status()?;
+                let msg = VhostUserU64::new(u64::from(status));
+                self.send_reply_message(&hdr, &msg)?;
+            }
+            MasterReq::SET_DEVICE_STATE_FD => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::DEVICE_STATE)?;
+                let file = take_single_file(files).ok_or(Error::IncorrectFds)?;
+                let msg =
+                    self.extract_request_body::<VhostUserTransferDeviceState>(&hdr, size, &buf)?;
+                self.set_device_state_fd(&hdr, &msg, file)?;
+            }
+            MasterReq::CHECK_DEVICE_STATE => {
+                self.check_protocol_feature(VhostUserProtocolFeatures::DEVICE_STATE)?;
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.check_device_state();
+                self.send_result_message(&hdr, res)?;
             }
             _ => {
                 return Err(Error::InvalidMessage);
@@ -542,6 +1854,238 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         buf: &[u8],
         files: Option<Vec<File>>,
     ) -> Result<()> {
+        let (regions, files) = self.extract_mem_table(hdr, size, buf, files)?;
+        self.apply_mem_table(regions, files)
+    }
+
+    fn apply_mem_table(
+        &mut self,
+        regions: &[VhostUserMemoryRegion],
+        files: Vec<File>,
+    ) -> Result<()> {
+        let manager = match self.memory.as_mut() {
+            Some(manager) => manager,
+            None => return self.backend.set_mem_table(regions, files),
+        };
+
+        let memory = manager.map_mem_table(regions, files)?;
+        self.backend.set_mapped_mem_table(memory.regions())?;
+        // The regions of the previous table are unmapped once the backend drops them too.
+        manager.set_memory(memory);
+        Ok(())
+    }
+
+    fn add_mapped_mem_region(
+        &mut self,
+        region: &VhostUserSingleMemoryRegion,
+        file: File,
+    ) -> Result<Arc<MappedRegion>> {
+        let manager = self.memory.as_mut().ok_or(Error::InvalidOperation)?;
+        let mapped = manager.add_region(region, file)?;
+        if let Err(e) = self.backend.add_mapped_mem_region(&mapped) {
+            manager.remove_region(region);
+            return Err(e);
+        }
+        Ok(mapped)
+    }
+
+    fn remove_mapped_mem_region(&mut self, region: &VhostUserSingleMemoryRegion) -> Result<()> {
+        let manager = self.memory.as_mut().ok_or(Error::InvalidOperation)?;
+        let mapped = manager
+            .memory()
+            .regions()
+            .iter()
+            .find(|r| {
+                r.guest_phys_addr() == region.guest_phys_addr
+                    && r.memory_size() == region.memory_size
+            })
+            .cloned()
+            .ok_or(Error::InvalidParam)?;
+        self.backend.remove_mapped_mem_region(&mapped)?;
+        manager.remove_region(region);
+        Ok(())
+    }
+
+    fn set_mem_table_postcopy(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: &[u8],
+        files: Option<Vec<File>>,
+    ) -> Result<()> {
+        let regions = match self.map_mem_table_postcopy(hdr, size, buf, files) {
+            Ok(regions) => regions,
+            Err(e) => {
+                // Report the failure so the master doesn't wait for the regions.
+                self.send_reply_message(hdr, &VhostUserMemory::new(0))?;
+                return Err(e);
+            }
+        };
+        let payload: Vec<u8> = regions
+            .iter()
+            .flat_map(|region| region.as_slice().to_vec())
+            .collect();
+        let msg = VhostUserMemory::new(regions.len() as u32);
+        self.send_reply_with_payload(hdr, &msg, &payload)?;
+
+        // The master acknowledges the reply once ready to serve the faults of the slave.
+        let (ack_hdr, ack, files) = self.main_sock.recv_body::<VhostUserU64>()?;
+        if ack_hdr.get_raw_code() != MasterReq::SET_MEM_TABLE as u32
+            || ack_hdr.is_reply()
+            || files.is_some()
+            || ack.value != 0
+        {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(())
+    }
+
+    // Map the regions in postcopy mode, returning them with the addresses of the slave.
+    fn map_mem_table_postcopy(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: &[u8],
+        files: Option<Vec<File>>,
+    ) -> Result<Vec<VhostUserMemoryRegion>> {
+        let (regions, files) = self.extract_mem_table(hdr, size, buf, files)?;
+        let uffd = self.uffd.as_ref().ok_or(Error::InvalidOperation)?;
+        if let Some(manager) = self.memory.as_mut() {
+            // The manager registers the regions with the userfaultfd as it maps them.
+            let memory = manager.map_mem_table(regions, files)?;
+            self.backend.set_mapped_mem_table(memory.regions())?;
+            let mut mapped = Vec::with_capacity(regions.len());
+            for region in regions {
+                let mut region = *region;
+                region.user_addr = memory
+                    .gpa_to_hva(region.guest_phys_addr)
+                    .ok_or(Error::InvalidParam)?;
+                mapped.push(region);
+            }
+            manager.set_memory(memory);
+            return Ok(mapped);
+        }
+        let addrs = self.backend.set_mem_table_postcopy(regions, files)?;
+        if addrs.len() != regions.len() {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut mapped = Vec::with_capacity(regions.len());
+        for (region, addr) in regions.iter().zip(addrs) {
+            uffd.register(addr, region.memory_size)
+                .map_err(Error::ReqHandlerError)?;
+            let mut region = *region;
+            region.user_addr = addr;
+            mapped.push(region);
+        }
+        Ok(mapped)
+    }
+
+    fn add_mem_region_postcopy(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        region: &VhostUserSingleMemoryRegion,
+        file: File,
+    ) -> Result<()> {
+        let res = match self.uffd.as_ref() {
+            // The manager registers the region with the userfaultfd as it maps it.
+            Some(_) if self.memory.is_some() => self
+                .add_mapped_mem_region(region, file)
+                .map(|mapped| mapped.host_addr()),
+            Some(uffd) => self
+                .backend
+                .add_mem_region_postcopy(region, file)
+                .and_then(|addr| {
+                    uffd.register(addr, region.memory_size)
+                        .map_err(Error::ReqHandlerError)?;
+                    Ok(addr)
+                }),
+            None => Err(Error::InvalidOperation),
+        };
+
+        // An empty region reports the failure to the master.
+        let mut reply = *region;
+        match res {
+            Ok(addr) => reply.user_addr = addr,
+            Err(_) => reply.memory_size = 0,
+        }
+        self.send_reply_message(hdr, &reply)?;
+        res.map(|_| ())
+    }
+
+    fn postcopy_advise(&mut self) -> Result<File> {
+        let uffd = Userfaultfd::new().map_err(Error::ReqHandlerError)?;
+        self.backend.postcopy_advise(&uffd)?;
+        let file = uffd.try_clone_file().map_err(Error::ReqHandlerError)?;
+        self.uffd = Some(Arc::new(uffd));
+        self.postcopy_listening = false;
+        Ok(file)
+    }
+
+    fn postcopy_listen(&mut self) -> Result<()> {
+        if self.uffd.is_none() || self.postcopy_listening {
+            return Err(Error::InvalidOperation);
+        }
+        self.backend.postcopy_listen()?;
+        if let Some(manager) = self.memory.as_mut() {
+            manager.set_userfaultfd(self.uffd.clone());
+        }
+        self.postcopy_listening = true;
+        Ok(())
+    }
+
+    fn postcopy_end(&mut self) -> Result<()> {
+        if self.uffd.is_none() {
+            return Err(Error::InvalidOperation);
+        }
+        let res = self.backend.postcopy_end();
+        // The registered ranges are released once the master closes the userfaultfd too.
+        if let Some(manager) = self.memory.as_mut() {
+            manager.set_userfaultfd(None);
+        }
+        self.uffd = None;
+        self.postcopy_listening = false;
+        res
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        msg: &VhostUserTransferDeviceState,
+        file: File,
+    ) -> Result<()> {
+        // The request body has been validated, so both fields are known.
+        let direction = msg.direction().ok_or(Error::InvalidMessage)?;
+        let phase = msg.phase().ok_or(Error::InvalidMessage)?;
+        let res = self.backend.set_device_state_fd(direction, phase, file);
+        let reply_hdr = self.new_reply_header::<VhostUserU64>(hdr, 0)?;
+        match res {
+            Ok(Some(file)) => {
+                let msg = VhostUserU64::new(0);
+                self.main_sock
+                    .send_message(&reply_hdr, &msg, Some(&[file.as_raw_fd()]))?;
+                Ok(())
+            }
+            Ok(None) => {
+                let msg = VhostUserU64::new(VHOST_USER_DEVICE_STATE_NO_FD);
+                self.main_sock.send_message(&reply_hdr, &msg, None)?;
+                Ok(())
+            }
+            Err(e) => {
+                let msg = VhostUserU64::new(VHOST_USER_DEVICE_STATE_NO_FD | 1);
+                self.main_sock.send_message(&reply_hdr, &msg, None)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn extract_mem_table<'a>(
+        &self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: &'a [u8],
+        files: Option<Vec<File>>,
+    ) -> Result<(&'a [VhostUserMemoryRegion], Vec<File>)> {
         self.check_request_size(&hdr, size, hdr.get_size() as usize)?;
 
         // check message size is consistent
@@ -576,75 +2120,151 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
             }
         }
 
-        self.backend.set_mem_table(&regions, files)
+        Ok((regions, files))
     }
 
-    fn get_config(&mut self, hdr: &VhostUserMsgHeader<MasterReq>, buf: &[u8]) -> Result<()> {
+    // Check a GET_CONFIG or SET_CONFIG request, and get its description of the config space
+    // range.
+    pub(super) fn config_request(
+        &self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: &[u8],
+    ) -> Result<(VhostUserConfig, VhostUserConfigFlags)> {
+        self.check_protocol_feature(VhostUserProtocolFeatures::CONFIG)?;
+        self.check_request_size(hdr, size, hdr.get_size() as usize)?;
         let payload_offset = mem::size_of::<VhostUserConfig>();
-        if buf.len() > MAX_MSG_SIZE || buf.len() < payload_offset {
+        if size > MAX_MSG_SIZE || size < payload_offset {
             return Err(Error::InvalidMessage);
         }
         let msg = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const VhostUserConfig) };
-        if !msg.is_valid() {
+        if size - payload_offset != msg.size as usize {
             return Err(Error::InvalidMessage);
         }
-        if buf.len() - payload_offset != msg.size as usize {
-            return Err(Error::InvalidMessage);
+        match VhostUserConfigFlags::from_bits(msg.flags) {
+            Some(flags) => Ok((msg, flags)),
+            None => Err(Error::InvalidMessage),
         }
-        let flags = match VhostUserConfigFlags::from_bits(msg.flags) {
-            Some(val) => val,
-            None => return Err(Error::InvalidMessage),
-        };
-        let res = self.backend.get_config(msg.offset, msg.size, flags);
+    }
 
+    // Check the config space range accessed by a GET_CONFIG or SET_CONFIG request, against the
+    // layout declared with `set_config_space()` if any.
+    pub(super) fn check_config_access(
+        &self,
+        msg: &VhostUserConfig,
+        flags: VhostUserConfigFlags,
+        write: bool,
+    ) -> Result<()> {
+        if !msg.is_valid_for(self.config_size) {
+            return Err(Error::InvalidParam);
+        }
+        match self.config_space.as_ref() {
+            Some(space) => space.check_access(msg.offset, msg.size, flags, write),
+            None => Ok(()),
+        }
+    }
+
+    // Reply to the GET_CONFIG request `msg` with the config space read by the backend, or with an
+    // empty payload reporting the failure of the request.
+    pub(super) fn send_config_reply(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        msg: &VhostUserConfig,
+        flags: VhostUserConfigFlags,
+        res: Result<Vec<u8>>,
+    ) -> Result<()> {
         // vhost-user slave's payload size MUST match master's request
         // on success, uses zero length of payload to indicate an error
         // to vhost-user master.
         match res {
             Ok(ref buf) if buf.len() == msg.size as usize => {
                 let reply = VhostUserConfig::new(msg.offset, buf.len() as u32, flags);
-                self.send_reply_with_payload(&hdr, &reply, buf.as_slice())?;
+                self.send_reply_with_payload(hdr, &reply, buf.as_slice())?;
             }
             Ok(_) => {
                 let reply = VhostUserConfig::new(msg.offset, 0, flags);
-                self.send_reply_message(&hdr, &reply)?;
+                self.send_reply_message(hdr, &reply)?;
             }
             Err(_) => {
                 let reply = VhostUserConfig::new(msg.offset, 0, flags);
-                self.send_reply_message(&hdr, &reply)?;
+                self.send_reply_message(hdr, &reply)?;
             }
         }
         Ok(())
     }
 
-    fn set_config(&mut self, size: usize, buf: &[u8]) -> Result<()> {
-        if size > MAX_MSG_SIZE || size < mem::size_of::<VhostUserConfig>() {
-            return Err(Error::InvalidMessage);
-        }
-        let msg = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const VhostUserConfig) };
-        if !msg.is_valid() {
-            return Err(Error::InvalidMessage);
-        }
-        if size - mem::size_of::<VhostUserConfig>() != msg.size as usize {
+    fn custom_request(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        buf: &[u8],
+        files: Option<Vec<File>>,
+    ) -> Result<()> {
+        if hdr.is_reply() {
             return Err(Error::InvalidMessage);
         }
-        let flags: VhostUserConfigFlags;
-        match VhostUserConfigFlags::from_bits(msg.flags) {
-            Some(val) => flags = val,
-            None => return Err(Error::InvalidMessage),
-        }
-
-        self.backend.set_config(msg.offset, buf, flags)
+        let (payload, reply_files) = self
+            .backend
+            .custom_request(hdr.get_raw_code(), buf, files)?;
+        let reply = self.new_reply_header::<()>(hdr, payload.len())?;
+        let fds: Option<Vec<RawFd>> = reply_files
+            .as_ref()
+            .map(|files| files.iter().map(|f| f.as_raw_fd()).collect());
+        self.main_sock
+            .send_message_with_payload(&reply, &(), &payload, fds.as_deref())?;
+        Ok(())
     }
 
     fn set_slave_req_fd(&mut self, files: Option<Vec<File>>) -> Result<()> {
         let file = take_single_file(files).ok_or(Error::InvalidMessage)?;
         let sock = unsafe { UnixStream::from_raw_fd(file.into_raw_fd()) };
+        if let Some(state) = self.upgrade.as_mut() {
+            state.slave_req = Some(sock.try_clone().map_err(Error::SocketError)?);
+        }
         let vu_req = SlaveFsCacheReq::from_stream(sock);
         self.backend.set_slave_req_fd(vu_req);
         Ok(())
     }
 
+    // Map the dirty page log shared by the master and hand it to the backend.
+    fn set_dirty_log(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        size: usize,
+        buf: &[u8],
+        files: Option<Vec<File>>,
+    ) -> Result<()> {
+        let file = take_single_file(files).ok_or(Error::IncorrectFds)?;
+        let msg = self.extract_request_body::<VhostUserLog>(hdr, size, buf)?;
+        let log = DirtyLogWriter::new(&msg, file)?;
+        self.backend.set_dirty_log(Arc::new(log))
+    }
+
+    // Duplicate `files` passed to the backend, if recorded for live upgrades.
+    fn track_files<'a, I>(&self, files: I) -> Result<Option<Vec<File>>>
+    where
+        I: IntoIterator<Item = &'a File>,
+    {
+        if self.upgrade.is_none() {
+            return Ok(None);
+        }
+        files
+            .into_iter()
+            .map(|file| file.try_clone().map_err(Error::ReqHandlerError))
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    // Record a change of the master in the state handed over for live upgrades, if recorded.
+    fn track_upgrade<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut SlaveUpgradeState) -> Result<()>,
+    {
+        match self.upgrade.as_mut() {
+            Some(state) => f(state),
+            None => Ok(()),
+        }
+    }
+
     fn handle_vring_fd_request(
         &mut self,
         buf: &[u8],
@@ -663,7 +2283,7 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         // in the ancillary data. This signals that polling will be used
         // instead of waiting for the call.
         // If Bit 8 is unset, the data must contain a file descriptor.
-        let has_fd = (msg.value & 0x100u64) == 0;
+        let has_fd = (msg.value & VHOST_USER_VRING_NOFD_MASK) == 0;
 
         let file = take_single_file(files);
 
@@ -674,6 +2294,14 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         Ok((msg.value as u8, file))
     }
 
+    // The replies of the requests served while shutting down are still sent.
+    fn check_closing(&self) -> Result<()> {
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(Error::Disconnected);
+        }
+        Ok(())
+    }
+
     fn check_state(&self) -> Result<()> {
         match self.error {
             Some(e) => Err(Error::SocketBroken(std::io::Error::from_raw_os_error(e))),
@@ -681,7 +2309,42 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         }
     }
 
-    fn check_request_size(
+    // With the `xen` feature, memory region descriptors carry the Xen mmap description, which
+    // the master only sends once XEN_MMAP has been negotiated.
+    fn check_xen_mmap(&self) -> Result<()> {
+        #[cfg(feature = "xen")]
+        {
+            self.check_protocol_feature(VhostUserProtocolFeatures::XEN_MMAP)?;
+        }
+        Ok(())
+    }
+
+    // Fail when the request depends on the protocol feature `feature`, not negotiated.
+    pub(super) fn check_protocol_feature(&self, feature: VhostUserProtocolFeatures) -> Result<()> {
+        if self.acked_protocol_features & feature.bits() == 0 {
+            return Err(Error::ProtocolFeatureNotNegotiated(feature));
+        }
+        Ok(())
+    }
+
+    // Fail when `code` sets up a vring before the master acked the virtio features, which the
+    // layout and processing of the vrings depend on.
+    fn check_order(&self, code: MasterReq) -> Result<()> {
+        let vring_setup = matches!(
+            code,
+            MasterReq::SET_VRING_NUM
+                | MasterReq::SET_VRING_ADDR
+                | MasterReq::SET_VRING_BASE
+                | MasterReq::SET_VRING_KICK
+                | MasterReq::SET_VRING_ENABLE
+        );
+        if vring_setup && !self.features_acked {
+            return Err(Error::RequestOutOfOrder(code, MasterReq::SET_FEATURES));
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_request_size(
         &self,
         hdr: &VhostUserMsgHeader<MasterReq>,
         size: usize,
@@ -711,13 +2374,14 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
             | MasterReq::SET_LOG_FD
             | MasterReq::SET_SLAVE_REQ_FD
             | MasterReq::SET_INFLIGHT_FD
-            | MasterReq::ADD_MEM_REG => Ok(()),
+            | MasterReq::ADD_MEM_REG
+            | MasterReq::SET_DEVICE_STATE_FD => Ok(()),
             _ if files.is_some() => Err(Error::InvalidMessage),
             _ => Ok(()),
         }
     }
 
-    fn extract_request_body<T: Sized + VhostUserMsgValidator>(
+    pub(super) fn extract_request_body<T: Sized + VhostUserMsgValidator>(
         &self,
         hdr: &VhostUserMsgHeader<MasterReq>,
         size: usize,
@@ -731,6 +2395,10 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         Ok(msg)
     }
 
+    fn is_packed(&self) -> bool {
+        self.acked_virtio_features & VhostUserVirtioFeatures::RING_PACKED.bits() != 0
+    }
+
     fn update_reply_ack_flag(&mut self) {
         let vflag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
         let pflag = VhostUserProtocolFeatures::REPLY_ACK;
@@ -756,14 +2424,14 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
             return Err(Error::InvalidParam);
         }
         self.check_state()?;
-        Ok(VhostUserMsgHeader::new(
-            req.get_code(),
+        Ok(VhostUserMsgHeader::new_raw(
+            req.get_raw_code(),
             VhostUserHeaderFlag::REPLY.bits(),
             (mem::size_of::<T>() + payload_size) as u32,
         ))
     }
 
-    fn send_ack_message(
+    pub(super) fn send_ack_message(
         &mut self,
         req: &VhostUserMsgHeader<MasterReq>,
         res: Result<()>,
@@ -780,7 +2448,21 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         res
     }
 
-    fn send_reply_message<T>(
+    // Reply with the result of the requests which are always acknowledged.
+    pub(super) fn send_result_message(
+        &mut self,
+        req: &VhostUserMsgHeader<MasterReq>,
+        res: Result<()>,
+    ) -> Result<()> {
+        let val = match res {
+            Ok(_) => 0,
+            Err(_) => 1,
+        };
+        self.send_reply_message(req, &VhostUserU64::new(val))?;
+        res
+    }
+
+    pub(super) fn send_reply_message<T>(
         &mut self,
         req: &VhostUserMsgHeader<MasterReq>,
         msg: &T,
@@ -803,6 +2485,17 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
     }
 }
 
+// Fail with the error of the backend, unless the backend completes the request asynchronously,
+// so the changes of the deferred requests are recorded too before the ack is deferred.
+fn accepted(res: Result<()>) -> Result<Result<()>> {
+    match res {
+        Err(Error::ReqHandlerError(e)) if e.raw_os_error() == Some(libc::EINPROGRESS) => {
+            Ok(Err(Error::ReqHandlerError(e)))
+        }
+        res => res.map(Ok),
+    }
+}
+
 impl<S: VhostUserSlaveReqHandler> AsRawFd for SlaveReqHandler<S> {
     fn as_raw_fd(&self) -> RawFd {
         self.main_sock.as_raw_fd()
@@ -812,9 +2505,10 @@ impl<S: VhostUserSlaveReqHandler> AsRawFd for SlaveReqHandler<S> {
 #[cfg(test)]
 mod tests {
     use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::tempfile::TempFile;
 
     use super::*;
-    use crate::vhost_user::dummy_slave::DummySlaveReqHandler;
+    use crate::vhost_user::dummy_slave::{self, DummySlaveReqHandler};
 
     #[test]
     fn test_slave_req_handler_new() {
@@ -828,4 +2522,506 @@ mod tests {
         handler.check_state().unwrap_err();
         assert!(handler.as_raw_fd() >= 0);
     }
+
+    #[test]
+    fn test_slave_req_handler_shutdown() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::new(endpoint, backend);
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+
+        // Requests already sent aren't served once shut down.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        let shutdown = handler.shutdown_handle().unwrap();
+        assert!(!shutdown.is_shutdown());
+        shutdown.shutdown(Duration::from_secs(1)).unwrap();
+        assert!(shutdown.is_shutdown());
+        match handler.handle_request().unwrap_err() {
+            Error::Disconnected => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        master.recv_header().unwrap_err();
+        handler.shutdown(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn test_slave_req_handler_check_request() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::new(endpoint, backend);
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+
+        // Requests depending on protocol features fail until they are negotiated.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_QUEUE_NUM, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        match handler.handle_request().unwrap_err() {
+            Error::ProtocolFeatureNotNegotiated(f) => assert_eq!(f, VhostUserProtocolFeatures::MQ),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // The vrings can't be set up before the virtio features are acked.
+        let vring = VhostUserVringState::new(0, 256);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_NUM, 0x1, 8);
+        master.send_message(&hdr, &vring, None).unwrap();
+        match handler.handle_request().unwrap_err() {
+            Error::RequestOutOfOrder(req, before) => {
+                assert_eq!(req, MasterReq::SET_VRING_NUM);
+                assert_eq!(before, MasterReq::SET_FEATURES);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master
+            .send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        handler.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_NUM, 0x1, 8);
+        master.send_message(&hdr, &vring, None).unwrap();
+        handler.handle_request().unwrap();
+    }
+
+    #[test]
+    fn test_slave_req_handler_vring_base() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let features = dummy_slave::VIRTIO_FEATURES | VhostUserVirtioFeatures::RING_PACKED.bits();
+        let config = dummy_slave::DummySlaveConfig::new().virtio_features(features);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::with_config(&config)));
+        let mut handler = SlaveReqHandler::new(endpoint, backend.clone());
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master
+            .send_message(&hdr, &VhostUserU64::new(features), None)
+            .unwrap();
+        handler.handle_request().unwrap();
+
+        // The wrap counters of packed vrings are decoded for the backend.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_BASE, 0x1, 8);
+        let vring = VhostUserVringState::new(1, 0x8002_0003);
+        master.send_message(&hdr, &vring, None).unwrap();
+        handler.handle_request().unwrap();
+        let base = VringBase::Packed(VringPackedBase::new(3, false, 2, true));
+        assert_eq!(backend.lock().unwrap().vring_base[1], base);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_VRING_BASE, 0x1, 8);
+        master
+            .send_message(&hdr, &VhostUserVringState::new(1, 0), None)
+            .unwrap();
+        handler.handle_request().unwrap();
+        let (_, reply, _) = master.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(({ reply.index }, { reply.num }), (1, 0x8002_0003));
+
+        // The position of a split vring is rejected once packed vrings are negotiated.
+        backend.lock().unwrap().vring_base[1] = VringBase::Split(3);
+        master
+            .send_message(&hdr, &VhostUserVringState::new(1, 0), None)
+            .unwrap();
+        handler.handle_request().unwrap_err();
+    }
+
+    #[test]
+    fn test_slave_req_handler_dirty_log() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::new(endpoint, backend.clone());
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        let msg = VhostUserU64::new(dummy_slave::VIRTIO_FEATURES);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let log = VhostUserLog::new(0x1000, 0);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_LOG_BASE, 0x1, 16);
+
+        // The log is shared only once LOG_SHMFD is negotiated.
+        master
+            .send_message(&hdr, &log, Some(&[file.as_raw_fd()]))
+            .unwrap();
+        handler.handle_request().unwrap_err();
+
+        let features = VhostUserProtocolFeatures::LOG_SHMFD.bits();
+        let msg = VhostUserU64::new(features);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x1, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_LOG_BASE, 0x1, 16);
+        master
+            .send_message(&hdr, &log, Some(&[file.as_raw_fd()]))
+            .unwrap();
+        handler.handle_request().unwrap();
+        // The master waits for the reply before logging.
+        let (reply, body, _) = master.recv_body::<VhostUserU64>().unwrap();
+        assert!(reply.is_reply_for(&hdr));
+        assert_eq!({ body.value }, 0);
+
+        let writer = backend.lock().unwrap().dirty_log.clone().unwrap();
+        assert_eq!(writer.size(), 0x1000);
+        writer.mark_dirty(9 * VHOST_LOG_PAGE, 1).unwrap();
+        let mut buf = [0u8; 2];
+        std::os::unix::fs::FileExt::read_exact_at(&file, &mut buf, 0).unwrap();
+        assert_eq!(buf, [0, 0b10]);
+    }
+
+    #[test]
+    fn test_slave_req_handler_stats() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::new(endpoint, backend);
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let reported = slow.clone();
+        handler.set_slow_request_handler(Some(Box::new(move |code, latency| {
+            reported.lock().unwrap().push((code, latency))
+        })));
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_STATUS, 0x1, 0);
+        for _ in 0..2 {
+            master.send_header(&hdr, None).unwrap();
+            handler.handle_request().unwrap_err();
+        }
+
+        let stats = handler.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[&(MasterReq::SET_OWNER as u32)].count, 1);
+        assert_eq!(stats[&(MasterReq::SET_OWNER as u32)].failures, 0);
+        let status = stats[&(MasterReq::GET_STATUS as u32)];
+        assert_eq!(status.count, 2);
+        assert_eq!(status.failures, 2);
+        assert_eq!(status.slow, 0);
+        assert!(status.max_latency <= status.total_latency);
+        assert!(slow.lock().unwrap().is_empty());
+
+        // Every request is slower than a null threshold.
+        handler.set_slow_request_threshold(Some(Duration::from_secs(0)));
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap_err();
+        assert_eq!(handler.stats()[&(MasterReq::GET_STATUS as u32)].slow, 1);
+        let slow = slow.lock().unwrap();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].0, MasterReq::GET_STATUS as u32);
+
+        handler.reset_stats();
+        assert!(handler.stats().is_empty());
+    }
+
+    #[test]
+    fn test_slave_req_handler_config_space() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::new(endpoint, backend.clone());
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+        assert!(handler.set_config_space(ConfigSpace::new(0)).is_err());
+        handler
+            .set_config_space(ConfigSpace::new(0x10).writable(0x8, 4))
+            .unwrap();
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        let msg = VhostUserU64::new(dummy_slave::VIRTIO_FEATURES);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+        let msg = VhostUserU64::new(VhostUserProtocolFeatures::CONFIG.bits());
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x1, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+
+        // Reads beyond the declared space get an empty reply.
+        let flags = VhostUserConfigFlags::empty();
+        let msg = VhostUserConfig::new(VHOST_USER_CONFIG_OFFSET + 0xc, 8, flags);
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_CONFIG, 0x1, 20);
+        master
+            .send_message_with_payload(&hdr, &msg, &[0; 8], None)
+            .unwrap();
+        handler.handle_request().unwrap_err();
+        let mut buf = [0u8; mem::size_of::<VhostUserConfig>()];
+        let (hdr, _, _) = master.recv_body_into_buf(&mut buf).unwrap();
+        assert_eq!(hdr.get_size() as usize, buf.len());
+        // Safe because buf holds a whole VhostUserConfig.
+        let reply = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const VhostUserConfig) };
+        assert_eq!({ reply.size }, 0);
+
+        // Writes to read-only fields are rejected before reaching the backend.
+        let flags = VhostUserConfigFlags::WRITABLE;
+        let msg = VhostUserConfig::new(VHOST_USER_CONFIG_OFFSET + 0x6, 4, flags);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_CONFIG, 0x1, 16);
+        master
+            .send_message_with_payload(&hdr, &msg, &[1; 4], None)
+            .unwrap();
+        handler.handle_request().unwrap_err();
+        assert_eq!(backend.lock().unwrap().config_space[0x6..0xa], [0xa5; 4]);
+
+        let msg = VhostUserConfig::new(VHOST_USER_CONFIG_OFFSET + 0x8, 4, flags);
+        master
+            .send_message_with_payload(&hdr, &msg, &[1; 4], None)
+            .unwrap();
+        handler.handle_request().unwrap();
+        assert_eq!(backend.lock().unwrap().config_space[0x8..0xc], [1; 4]);
+
+        // The whole space is restored when migrating the device.
+        let flags = VhostUserConfigFlags::LIVE_MIGRATION;
+        let msg = VhostUserConfig::new(VHOST_USER_CONFIG_OFFSET, 0x10, flags);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_CONFIG, 0x1, 28);
+        master
+            .send_message_with_payload(&hdr, &msg, &[2; 0x10], None)
+            .unwrap();
+        handler.handle_request().unwrap();
+        assert_eq!(backend.lock().unwrap().config_space[..0x10], [2; 0x10]);
+    }
+
+    #[test]
+    fn test_slave_req_handler_upgrade() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::from_stream(p1, backend.clone());
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+        let (old_peer, new_peer) = UnixStream::pair().unwrap();
+        handler.send_upgrade(&old_peer).unwrap_err();
+        handler.set_track_upgrade(true);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        let msg = VhostUserU64::new(dummy_slave::VIRTIO_FEATURES);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+        // The memory regions of Xen guests are only described once XEN_MMAP is negotiated.
+        #[allow(unused_mut)]
+        let mut features = VhostUserProtocolFeatures::MQ;
+        #[cfg(feature = "xen")]
+        {
+            features |= VhostUserProtocolFeatures::XEN_MMAP;
+        }
+        let msg = VhostUserU64::new(features.bits());
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x1, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+
+        let file: File = TempFile::new().unwrap().into_file();
+        let region = VhostUserMemoryRegion::new(0, 0x10_0000, 0x7f00_0000_0000, 0);
+        let size = mem::size_of::<VhostUserMemory>() + mem::size_of::<VhostUserMemoryRegion>();
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_MEM_TABLE, 0x1, size as u32);
+        master
+            .send_message_with_payload(
+                &hdr,
+                &VhostUserMemory::new(1),
+                region.as_slice(),
+                Some(&[file.as_raw_fd()]),
+            )
+            .unwrap();
+        handler.handle_request().unwrap();
+
+        // The requests completed asynchronously by the backend are recorded too.
+        backend
+            .lock()
+            .unwrap()
+            .inject_error(MasterReq::SET_VRING_NUM, libc::EINPROGRESS);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_NUM, 0x1, 8);
+        let vring = VhostUserVringState::new(1, 128);
+        master.send_message(&hdr, &vring, None).unwrap();
+        handler.handle_request().unwrap();
+        for ack in handler.take_deferred_acks() {
+            ack.complete(Ok(())).unwrap();
+        }
+        let flags = VhostUserVringAddrFlags::empty();
+        let addr = VhostUserVringAddr::new(1, flags, 0x1000, 0x3000, 0x2000, 0);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_ADDR, 0x1, 40);
+        master.send_message(&hdr, &addr, None).unwrap();
+        handler.handle_request().unwrap();
+        for code in [MasterReq::SET_VRING_CALL, MasterReq::SET_VRING_KICK].iter() {
+            let hdr = VhostUserMsgHeader::new(*code, 0x1, 8);
+            master
+                .send_message(&hdr, &VhostUserU64::new(1), Some(&[file.as_raw_fd()]))
+                .unwrap();
+            handler.handle_request().unwrap();
+        }
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_ENABLE, 0x1, 8);
+        let vring = VhostUserVringState::new(1, 1);
+        master.send_message(&hdr, &vring, None).unwrap();
+        handler.handle_request().unwrap();
+
+        // The started vring is stopped, and restarted by the new process from its position.
+        backend.lock().unwrap().vring_base[1] = VringBase::Split(5);
+        handler.send_upgrade(&old_peer).unwrap();
+        assert!(!backend.lock().unwrap().vring_started[1]);
+
+        let (socket, state) = SlaveUpgradeState::recv(&new_peer).unwrap();
+        assert_eq!(state.regions.len(), 1);
+        assert_eq!({ state.regions[0].0.memory_size }, 0x10_0000);
+        assert!(state.slave_req.is_none());
+        let new_backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut new_handler = SlaveReqHandler::from_stream(socket, new_backend.clone());
+        new_handler.restore_upgrade_state(state).unwrap();
+        {
+            let new_backend = new_backend.lock().unwrap();
+            assert!(new_backend.owned);
+            assert_eq!(new_backend.acked_features, dummy_slave::VIRTIO_FEATURES);
+            assert_eq!(new_backend.acked_protocol_features, features.bits());
+            assert_eq!(new_backend.vring_num[1], 128);
+            assert_eq!(new_backend.vring_base[1], VringBase::Split(5));
+            assert!(new_backend.call_fd[1].is_some());
+            assert!(new_backend.kick_fd[1].is_some());
+            assert!(new_backend.vring_started[1]);
+            assert!(new_backend.vring_enabled[1]);
+        }
+
+        // The new process serves the master on the same connection.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_QUEUE_NUM, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        new_handler.handle_request().unwrap();
+        let (_, reply, _) = master.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!({ reply.value }, dummy_slave::MAX_QUEUE_NUM as u64);
+    }
+
+    #[test]
+    fn test_slave_req_handler_reply_ack() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::new(endpoint, backend.clone());
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+        let recv_ack = |master: &mut Endpoint<MasterReq>, code: MasterReq| {
+            let (hdr, ack, _) = master.recv_body::<VhostUserU64>().unwrap();
+            assert_eq!(hdr.get_code(), code);
+            assert!(hdr.is_reply());
+            ack.value
+        };
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        recv_ack(&mut master, MasterReq::GET_FEATURES);
+        let msg = VhostUserU64::new(dummy_slave::VIRTIO_FEATURES);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_PROTOCOL_FEATURES, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        handler.handle_request().unwrap();
+        recv_ack(&mut master, MasterReq::GET_PROTOCOL_FEATURES);
+        let msg = VhostUserU64::new(VhostUserProtocolFeatures::REPLY_ACK.bits());
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x9, 8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        handler.handle_request().unwrap();
+        assert_eq!(recv_ack(&mut master, MasterReq::SET_PROTOCOL_FEATURES), 0);
+
+        // Requests failing before reaching the backend are acknowledged too.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_STATUS, 0x9, 8);
+        master
+            .send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        handler.handle_request().unwrap_err();
+        assert_eq!(recv_ack(&mut master, MasterReq::SET_STATUS), 1);
+
+        // Requests completed asynchronously are acknowledged on completion.
+        let vring = VhostUserVringState::new(0, 0);
+        backend
+            .lock()
+            .unwrap()
+            .inject_error(MasterReq::SET_VRING_BASE, libc::EINPROGRESS);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_BASE, 0x9, 8);
+        master.send_message(&hdr, &vring, None).unwrap();
+        handler.handle_request().unwrap();
+        let mut acks = handler.take_deferred_acks();
+        assert_eq!(acks.len(), 1);
+        let ack = acks.pop().unwrap();
+        assert_eq!(ack.code(), MasterReq::SET_VRING_BASE);
+
+        // Requests sent before the master gets the ack are refused, not to reply out of order.
+        let hdr_status = VhostUserMsgHeader::new(MasterReq::SET_STATUS, 0x9, 8);
+        master
+            .send_message(&hdr_status, &VhostUserU64::new(0), None)
+            .unwrap();
+        match handler.handle_request() {
+            Err(Error::InvalidOperation) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        ack.complete(Ok(())).unwrap();
+        assert_eq!(recv_ack(&mut master, MasterReq::SET_VRING_BASE), 0);
+
+        // Dropping a deferred ack reports a failure.
+        backend
+            .lock()
+            .unwrap()
+            .inject_error(MasterReq::SET_VRING_BASE, libc::EINPROGRESS);
+        master.send_message(&hdr, &vring, None).unwrap();
+        handler.handle_request().unwrap();
+        drop(handler.take_deferred_acks());
+        assert_eq!(recv_ack(&mut master, MasterReq::SET_VRING_BASE), 1);
+        assert!(handler.take_deferred_acks().is_empty());
+    }
+
+    #[test]
+    fn test_slave_req_handler_handle_events() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::<MasterReq>::from_stream(p1);
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut handler = SlaveReqHandler::new(endpoint, backend);
+        handler.set_nonblocking(true).unwrap();
+        let mut master = Endpoint::<MasterReq>::from_stream(p2);
+        assert_eq!(handler.handle_events().unwrap(), 0);
+
+        // A burst of requests is served at once.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 2);
+        let (hdr, features, _) = master.recv_body::<VhostUserU64>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+        assert_eq!({ features.value }, dummy_slave::VIRTIO_FEATURES);
+
+        // Partial requests are kept until they are complete.
+        let msg = VhostUserU64::new(dummy_slave::VIRTIO_FEATURES);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master.send_header(&hdr, None).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 0);
+        master.send_slice(&msg.as_slice()[..4], None).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 0);
+        master.send_slice(&msg.as_slice()[4..], None).unwrap();
+        assert_eq!(handler.handle_events().unwrap(), 1);
+
+        // The requests following a failed one are served by the next call.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        let vring = VhostUserVringState::new(0, 256);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_VRING_NUM, 0x1, 8);
+        master.send_message(&hdr, &vring, None).unwrap();
+        handler.handle_events().unwrap_err();
+        assert_eq!(handler.handle_events().unwrap(), 1);
+
+        drop(master);
+        match handler.handle_events().unwrap_err() {
+            Error::Disconnected => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
 }
@@ -0,0 +1,492 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Live upgrade of the slave, handing the state of a [SlaveReqHandler] and the files it owns
+//! over to a new process of the slave, without the master noticing.
+//!
+//! [SlaveReqHandler]: struct.SlaveReqHandler.html
+
+use std::fs::File;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use vm_memory::ByteValued;
+
+use super::connection::Endpoint;
+use super::message::*;
+use super::{Error, Result};
+
+// Version of the format of the state sent by SlaveUpgradeState::send().
+const UPGRADE_VERSION: u32 = 1;
+
+// Flags of UpgradeHeader.
+const UPGRADE_FEATURES_ACKED: u32 = 0x1;
+const UPGRADE_SLAVE_REQ: u32 = 0x2;
+
+// Flags of UpgradeVring.
+const VRING_ADDR: u32 = 0x1;
+const VRING_STARTED: u32 = 0x2;
+const VRING_ENABLED: u32 = 0x4;
+const VRING_KICK: u32 = 0x8;
+const VRING_CALL: u32 = 0x10;
+const VRING_ERR: u32 = 0x20;
+
+// Header of the state, followed by its memory regions and its vrings. Each part is sent with the
+// files it refers to, the header with the socket connected to the master.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct UpgradeHeader {
+    version: u32,
+    flags: u32,
+    virtio_features: u64,
+    acked_virtio_features: u64,
+    protocol_features: u64,
+    acked_protocol_features: u64,
+    config_size: u32,
+    num_regions: u32,
+    num_vrings: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for UpgradeHeader {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct UpgradeVring {
+    index: u32,
+    num: u32,
+    base: u32,
+    flags: u32,
+    addr_flags: u32,
+    padding: u32,
+    descriptor: u64,
+    used: u64,
+    available: u64,
+    log: u64,
+}
+
+unsafe impl ByteValued for UpgradeVring {}
+
+/// State of a vring handed over to the new process of the slave.
+#[derive(Default)]
+pub struct VringUpgradeState {
+    /// Index of the vring.
+    pub index: u32,
+    /// Number of descriptors of the vring, set with SET_VRING_NUM.
+    pub num: u32,
+    /// Addresses of the rings, set with SET_VRING_ADDR.
+    pub addr: Option<VhostUserVringAddr>,
+    /// Position of the vring, set with SET_VRING_BASE or reported by the backend once stopped.
+    pub base: VringBase,
+    /// Whether the vring was started by SET_VRING_KICK, and not stopped by GET_VRING_BASE since.
+    pub started: bool,
+    /// Whether the vring was enabled with SET_VRING_ENABLE.
+    pub enabled: bool,
+    /// Eventfd kicked by the master, set with SET_VRING_KICK.
+    pub kick: Option<File>,
+    /// Eventfd notifying the master, set with SET_VRING_CALL.
+    pub call: Option<File>,
+    /// Eventfd reporting errors to the master, set with SET_VRING_ERR.
+    pub err: Option<File>,
+}
+
+impl VringUpgradeState {
+    /// Create the state of vring `index`, not set up by the master yet.
+    pub fn new(index: u32) -> Self {
+        VringUpgradeState {
+            index,
+            ..Default::default()
+        }
+    }
+
+    /// Duplicate the state, and the files it owns.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(VringUpgradeState {
+            index: self.index,
+            num: self.num,
+            addr: self.addr,
+            base: self.base,
+            started: self.started,
+            enabled: self.enabled,
+            kick: clone_file(&self.kick)?,
+            call: clone_file(&self.call)?,
+            err: clone_file(&self.err)?,
+        })
+    }
+}
+
+/// State of a [SlaveReqHandler] handed over to a new process of the slave, for live upgrades.
+///
+/// The old process gets the state with [SlaveReqHandler::upgrade_state()] and sends it with
+/// [Self::send()], along with the socket connected to the master. The new process receives them
+/// with [Self::recv()] and restores the state with [SlaveReqHandler::restore_upgrade_state()],
+/// before serving the requests of the master on the socket.
+///
+/// The device state of the backend isn't part of it, the backend hands it over on its own.
+///
+/// [SlaveReqHandler]: struct.SlaveReqHandler.html
+/// [SlaveReqHandler::upgrade_state()]: struct.SlaveReqHandler.html#method.upgrade_state
+/// [SlaveReqHandler::restore_upgrade_state()]: struct.SlaveReqHandler.html#method.restore_upgrade_state
+/// [Self::send()]: struct.SlaveUpgradeState.html#method.send
+/// [Self::recv()]: struct.SlaveUpgradeState.html#method.recv
+pub struct SlaveUpgradeState {
+    /// Virtio features reported to the master.
+    pub virtio_features: u64,
+    /// Virtio features acked by the master.
+    pub acked_virtio_features: u64,
+    /// Protocol features reported to the master.
+    pub protocol_features: VhostUserProtocolFeatures,
+    /// Protocol features acked by the master.
+    pub acked_protocol_features: u64,
+    /// Whether the master acked the virtio features with SET_FEATURES.
+    pub features_acked: bool,
+    /// End of the device configuration space accessed by the master.
+    pub config_size: u32,
+    /// Memory regions set by the master, and the files backing them.
+    pub regions: Vec<(VhostUserMemoryRegion, File)>,
+    /// Vrings set up by the master.
+    pub vrings: Vec<VringUpgradeState>,
+    /// Slave communication channel set with SET_SLAVE_REQ_FD.
+    pub slave_req: Option<UnixStream>,
+}
+
+impl Default for SlaveUpgradeState {
+    fn default() -> Self {
+        SlaveUpgradeState {
+            virtio_features: 0,
+            acked_virtio_features: 0,
+            protocol_features: VhostUserProtocolFeatures::empty(),
+            acked_protocol_features: 0,
+            features_acked: false,
+            config_size: VHOST_USER_CONFIG_SIZE,
+            regions: Vec::new(),
+            vrings: Vec::new(),
+            slave_req: None,
+        }
+    }
+}
+
+impl SlaveUpgradeState {
+    /// Duplicate the state, and the files it owns.
+    pub fn try_clone(&self) -> Result<Self> {
+        let regions = self
+            .regions
+            .iter()
+            .map(|(region, file)| Ok((*region, file.try_clone().map_err(Error::ReqHandlerError)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let vrings = self
+            .vrings
+            .iter()
+            .map(VringUpgradeState::try_clone)
+            .collect::<Result<Vec<_>>>()?;
+        let slave_req = match self.slave_req.as_ref() {
+            Some(sock) => Some(sock.try_clone().map_err(Error::SocketError)?),
+            None => None,
+        };
+        Ok(SlaveUpgradeState {
+            virtio_features: self.virtio_features,
+            acked_virtio_features: self.acked_virtio_features,
+            protocol_features: self.protocol_features,
+            acked_protocol_features: self.acked_protocol_features,
+            features_acked: self.features_acked,
+            config_size: self.config_size,
+            regions,
+            vrings,
+            slave_req,
+        })
+    }
+
+    /// Get the state of vring `index`, added if the master didn't set it up yet.
+    pub fn vring_mut(&mut self, index: u32) -> Result<&mut VringUpgradeState> {
+        if u64::from(index) >= VHOST_USER_MAX_VRINGS {
+            return Err(Error::InvalidParam);
+        }
+        let pos = match self.vrings.iter().position(|vring| vring.index == index) {
+            Some(pos) => pos,
+            None => {
+                self.vrings.push(VringUpgradeState::new(index));
+                self.vrings.len() - 1
+            }
+        };
+        Ok(&mut self.vrings[pos])
+    }
+
+    /// Send the state to the new process of the slave through `peer`, with `socket` connected
+    /// to the master.
+    pub fn send(&self, peer: &UnixStream, socket: RawFd) -> Result<()> {
+        let peer = peer.try_clone().map_err(Error::SocketError)?;
+        let mut endpoint = Endpoint::<MasterReq>::from_stream(peer);
+
+        let mut hdr = UpgradeHeader {
+            version: UPGRADE_VERSION,
+            virtio_features: self.virtio_features,
+            acked_virtio_features: self.acked_virtio_features,
+            protocol_features: self.protocol_features.bits(),
+            acked_protocol_features: self.acked_protocol_features,
+            config_size: self.config_size,
+            num_regions: self.regions.len() as u32,
+            num_vrings: self.vrings.len() as u32,
+            ..Default::default()
+        };
+        let mut fds = vec![socket];
+        if self.features_acked {
+            hdr.flags |= UPGRADE_FEATURES_ACKED;
+        }
+        if let Some(sock) = self.slave_req.as_ref() {
+            hdr.flags |= UPGRADE_SLAVE_REQ;
+            fds.push(sock.as_raw_fd());
+        }
+        send_part(&mut endpoint, hdr.as_slice(), &fds)?;
+
+        for (region, file) in self.regions.iter() {
+            send_part(&mut endpoint, region.as_slice(), &[file.as_raw_fd()])?;
+        }
+
+        for vring in self.vrings.iter() {
+            let mut msg = UpgradeVring {
+                index: vring.index,
+                num: vring.num,
+                base: vring.base.to_num().ok_or(Error::InvalidParam)?,
+                ..Default::default()
+            };
+            if let Some(addr) = vring.addr.as_ref() {
+                msg.flags |= VRING_ADDR;
+                msg.addr_flags = addr.flags;
+                msg.descriptor = addr.descriptor;
+                msg.used = addr.used;
+                msg.available = addr.available;
+                msg.log = addr.log;
+            }
+            if vring.started {
+                msg.flags |= VRING_STARTED;
+            }
+            if vring.enabled {
+                msg.flags |= VRING_ENABLED;
+            }
+            let mut fds = Vec::new();
+            for (file, flag) in [
+                (&vring.kick, VRING_KICK),
+                (&vring.call, VRING_CALL),
+                (&vring.err, VRING_ERR),
+            ]
+            .iter()
+            {
+                if let Some(file) = file {
+                    msg.flags |= flag;
+                    fds.push(file.as_raw_fd());
+                }
+            }
+            send_part(&mut endpoint, msg.as_slice(), &fds)?;
+        }
+        Ok(())
+    }
+
+    /// Receive the state sent by the old process of the slave through `peer`, and the socket
+    /// connected to the master.
+    ///
+    /// # Return:
+    /// * - InvalidMessage: the state was sent by an incompatible version of the slave.
+    /// * - IncorrectFds: the files of the state are missing.
+    pub fn recv(peer: &UnixStream) -> Result<(UnixStream, Self)> {
+        let peer = peer.try_clone().map_err(Error::SocketError)?;
+        let mut endpoint = Endpoint::<MasterReq>::from_stream(peer);
+
+        let (hdr, mut files) = recv_part::<UpgradeHeader>(&mut endpoint)?;
+        if hdr.version != UPGRADE_VERSION
+            || u64::from(hdr.num_vrings) > VHOST_USER_MAX_VRINGS
+            || hdr.padding != 0
+        {
+            return Err(Error::InvalidMessage);
+        }
+        let num_files = if hdr.flags & UPGRADE_SLAVE_REQ != 0 {
+            2
+        } else {
+            1
+        };
+        if files.len() != num_files {
+            return Err(Error::IncorrectFds);
+        }
+        let slave_req = if num_files == 2 {
+            // Safe because we have the ownership of the fd of the file.
+            files
+                .pop()
+                .map(|file| unsafe { UnixStream::from_raw_fd(file.into_raw_fd()) })
+        } else {
+            None
+        };
+        // Safe because we have the ownership of the fd of the file.
+        let socket = unsafe { UnixStream::from_raw_fd(files.remove(0).into_raw_fd()) };
+
+        let packed = hdr.acked_virtio_features & VhostUserVirtioFeatures::RING_PACKED.bits() != 0;
+        let mut state = SlaveUpgradeState {
+            virtio_features: hdr.virtio_features,
+            acked_virtio_features: hdr.acked_virtio_features,
+            protocol_features: VhostUserProtocolFeatures::from_bits_truncate(hdr.protocol_features),
+            acked_protocol_features: hdr.acked_protocol_features,
+            features_acked: hdr.flags & UPGRADE_FEATURES_ACKED != 0,
+            config_size: hdr.config_size,
+            regions: Vec::new(),
+            vrings: Vec::new(),
+            slave_req,
+        };
+
+        for _ in 0..hdr.num_regions {
+            let (region, mut files) = recv_part::<VhostUserMemoryRegion>(&mut endpoint)?;
+            if !region.is_valid() {
+                return Err(Error::InvalidMessage);
+            }
+            if files.len() != 1 {
+                return Err(Error::IncorrectFds);
+            }
+            state.regions.push((region, files.remove(0)));
+        }
+
+        for _ in 0..hdr.num_vrings {
+            let (msg, files) = recv_part::<UpgradeVring>(&mut endpoint)?;
+            let num_files = [VRING_KICK, VRING_CALL, VRING_ERR]
+                .iter()
+                .filter(|flag| msg.flags & **flag != 0)
+                .count();
+            if files.len() != num_files {
+                return Err(Error::IncorrectFds);
+            }
+            let mut files = files.into_iter();
+            let mut take_file = |flag: u32| {
+                if msg.flags & flag != 0 {
+                    files.next()
+                } else {
+                    None
+                }
+            };
+            let addr = if msg.flags & VRING_ADDR != 0 {
+                Some(VhostUserVringAddr {
+                    index: msg.index,
+                    flags: msg.addr_flags,
+                    descriptor: msg.descriptor,
+                    used: msg.used,
+                    available: msg.available,
+                    log: msg.log,
+                })
+            } else {
+                None
+            };
+            state.vrings.push(VringUpgradeState {
+                index: msg.index,
+                num: msg.num,
+                addr,
+                base: VringBase::from_num(msg.base, packed).ok_or(Error::InvalidMessage)?,
+                started: msg.flags & VRING_STARTED != 0,
+                enabled: msg.flags & VRING_ENABLED != 0,
+                kick: take_file(VRING_KICK),
+                call: take_file(VRING_CALL),
+                err: take_file(VRING_ERR),
+            });
+        }
+        Ok((socket, state))
+    }
+}
+
+fn clone_file(file: &Option<File>) -> Result<Option<File>> {
+    match file {
+        Some(file) => Ok(Some(file.try_clone().map_err(Error::ReqHandlerError)?)),
+        None => Ok(None),
+    }
+}
+
+fn send_part(endpoint: &mut Endpoint<MasterReq>, buf: &[u8], fds: &[RawFd]) -> Result<()> {
+    if endpoint.send_iovec_all(&[buf], Some(fds))? != buf.len() {
+        return Err(Error::PartialMessage);
+    }
+    Ok(())
+}
+
+fn recv_part<T: ByteValued>(endpoint: &mut Endpoint<MasterReq>) -> Result<(T, Vec<File>)> {
+    let (bytes, buf, files) = endpoint.recv_into_buf(mem::size_of::<T>())?;
+    if bytes != mem::size_of::<T>() {
+        return Err(Error::PartialMessage);
+    }
+    // Safe because the buffer holds a whole T, which is valid for any content.
+    let msg = unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) };
+    Ok((msg, files.unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn temp_file() -> File {
+        TempFile::new().unwrap().into_file()
+    }
+
+    #[test]
+    fn test_upgrade_state_send_recv() {
+        let (socket, _master) = UnixStream::pair().unwrap();
+        let (slave_req, _) = UnixStream::pair().unwrap();
+        let mut state = SlaveUpgradeState {
+            virtio_features: 0x4000_0003 | VhostUserVirtioFeatures::RING_PACKED.bits(),
+            protocol_features: VhostUserProtocolFeatures::MQ,
+            features_acked: true,
+            slave_req: Some(slave_req),
+            ..Default::default()
+        };
+        state.acked_virtio_features = state.virtio_features;
+        let region = VhostUserMemoryRegion::new(0x1000, 0x2000, 0x3000, 0);
+        state.regions.push((region, temp_file()));
+        assert!(state.vring_mut(VHOST_USER_MAX_VRINGS as u32).is_err());
+        let vring = state.vring_mut(3).unwrap();
+        vring.num = 256;
+        vring.base = VringBase::Packed(VringPackedBase::new(7, true, 5, false));
+        vring.started = true;
+        vring.call = Some(temp_file());
+        let flags = VhostUserVringAddrFlags::VHOST_VRING_F_LOG;
+        vring.addr = Some(VhostUserVringAddr::new(
+            3, flags, 0x1000, 0x3000, 0x2000, 0x8000,
+        ));
+        state.vring_mut(1).unwrap().enabled = true;
+
+        let (old_peer, new_peer) = UnixStream::pair().unwrap();
+        state
+            .try_clone()
+            .unwrap()
+            .send(&old_peer, socket.as_raw_fd())
+            .unwrap();
+        let (_, received) = SlaveUpgradeState::recv(&new_peer).unwrap();
+        assert_eq!(received.virtio_features, state.virtio_features);
+        assert_eq!(received.acked_virtio_features, state.virtio_features);
+        assert_eq!(received.protocol_features, VhostUserProtocolFeatures::MQ);
+        assert!(received.features_acked);
+        assert_eq!(received.config_size, VHOST_USER_CONFIG_SIZE);
+        assert!(received.slave_req.is_some());
+        assert_eq!(received.regions.len(), 1);
+        assert_eq!({ received.regions[0].0.user_addr }, 0x3000);
+
+        assert_eq!(received.vrings.len(), 2);
+        let vring = &received.vrings[0];
+        assert_eq!((vring.index, vring.num), (3, 256));
+        assert_eq!(vring.base, state.vrings[0].base);
+        assert!(vring.started && !vring.enabled);
+        assert!(vring.call.is_some() && vring.kick.is_none() && vring.err.is_none());
+        let addr = vring.addr.unwrap();
+        assert_eq!(
+            ({ addr.flags }, { addr.used }, { addr.log }),
+            (1, 0x3000, 0x8000)
+        );
+        let vring = &received.vrings[1];
+        assert!(vring.enabled && vring.addr.is_none() && vring.call.is_none());
+
+        // The state of another version of the slave is rejected.
+        let hdr = UpgradeHeader {
+            version: UPGRADE_VERSION + 1,
+            ..Default::default()
+        };
+        let mut endpoint = Endpoint::<MasterReq>::from_stream(old_peer);
+        send_part(&mut endpoint, hdr.as_slice(), &[socket.as_raw_fd()]).unwrap();
+        match SlaveUpgradeState::recv(&new_peer) {
+            Err(Error::InvalidMessage) => {}
+            _ => panic!("state of another version accepted"),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Userfaultfd used by slaves to let the master resolve page faults during postcopy migration.
+
+use std::fs::File;
+use std::io::{Error as IOError, Result as IOResult};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+const UFFDIO: u32 = 0xAA;
+const UFFD_API: u64 = 0xAA;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 0x1;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+vmm_sys_util::ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3f, UffdioApi);
+vmm_sys_util::ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+vmm_sys_util::ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, UffdioRange);
+
+/// Userfaultfd reporting the accesses of the slave to the guest memory not migrated yet.
+///
+/// The slave registers its mappings of the guest memory with the userfaultfd, and hands it over
+/// to the master with the reply to POSTCOPY_ADVISE. Accesses to the missing pages of the
+/// registered ranges then block until the master has copied the pages through the userfaultfd.
+pub struct Userfaultfd {
+    file: File,
+}
+
+impl Userfaultfd {
+    /// Create a non-blocking userfaultfd and negotiate the API with the kernel.
+    pub fn new() -> IOResult<Self> {
+        // Safe because the syscall doesn't access memory, and the return value is checked.
+        let ret =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        // Safe because the fd was just created and is owned by nobody else.
+        let file = unsafe { File::from_raw_fd(ret as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because the kernel only writes to `api`, and the return value is checked.
+        let ret = unsafe { ioctl_with_mut_ref(&file, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(Userfaultfd { file })
+    }
+
+    /// Report the missing pages of the `len` bytes mapped at `addr` through the userfaultfd.
+    ///
+    /// The range must be page aligned and mapped by the current process.
+    pub fn register(&self, addr: u64, len: u64) -> IOResult<()> {
+        let mut reg = UffdioRegister {
+            range: UffdioRange { start: addr, len },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // Safe because the kernel only writes to `reg`, and the return value is checked.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_REGISTER(), &mut reg) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Stop reporting the missing pages of the `len` bytes mapped at `addr`.
+    pub fn unregister(&self, addr: u64, len: u64) -> IOResult<()> {
+        let range = UffdioRange { start: addr, len };
+        // Safe because the kernel only reads `range`, and the return value is checked.
+        let ret = unsafe { ioctl_with_ref(&self.file, UFFDIO_UNREGISTER(), &range) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Duplicate the userfaultfd, to send it to the master.
+    pub fn try_clone_file(&self) -> IOResult<File> {
+        self.file.try_clone()
+    }
+}
+
+impl AsRawFd for Userfaultfd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for Userfaultfd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_userfaultfd_register() {
+        // Userfaultfd may be restricted to privileged processes.
+        let uffd = match Userfaultfd::new() {
+            Ok(uffd) => uffd,
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            Err(e) => panic!("failed to create userfaultfd: {}", e),
+        };
+        let len = 0x10000;
+        // Safe because the mapping is anonymous, and unmapped below.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+
+        uffd.register(addr as u64, len as u64).unwrap();
+        uffd.register(addr as u64 + 1, len as u64).unwrap_err();
+        uffd.unregister(addr as u64, len as u64).unwrap();
+        assert!(uffd.try_clone_file().unwrap().as_raw_fd() >= 0);
+
+        // Safe because the range was mapped above.
+        unsafe { libc::munmap(addr, len) };
+    }
+}
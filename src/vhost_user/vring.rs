@@ -0,0 +1,1034 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtqueues in the guest memory mapped by the slave, processed by the built-in vring workers.
+
+use std::num::Wrapping;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU16, Ordering};
+use std::time::Duration;
+
+use super::mapped_memory::MappedMemory;
+use super::message::{VringBase, VringPackedBase};
+use super::rate_limiter::RateLimiter;
+use super::{Error, Result};
+
+/// The buffer continues in the descriptor of the `next` field.
+pub const VRING_DESC_F_NEXT: u16 = 0x1;
+/// The buffer is write-only for the device.
+pub const VRING_DESC_F_WRITE: u16 = 0x2;
+/// The buffer contains a table of descriptors.
+pub const VRING_DESC_F_INDIRECT: u16 = 0x4;
+/// The driver doesn't need to be notified of used buffers.
+pub const VRING_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+/// The device doesn't need to be notified of available buffers.
+pub const VRING_USED_F_NO_NOTIFY: u16 = 0x1;
+/// The packed descriptor is available, when equal to the wrap counter of the driver.
+pub const VRING_PACKED_DESC_F_AVAIL: u16 = 0x80;
+/// The packed descriptor is used, when equal to the wrap counter of the device.
+pub const VRING_PACKED_DESC_F_USED: u16 = 0x8000;
+/// Notifications are enabled by the packed event suppression structure.
+pub const VRING_PACKED_EVENT_FLAG_ENABLE: u16 = 0x0;
+/// Notifications are disabled by the packed event suppression structure.
+pub const VRING_PACKED_EVENT_FLAG_DISABLE: u16 = 0x1;
+/// Notifications are enabled for the descriptor of the packed event suppression structure.
+pub const VRING_PACKED_EVENT_FLAG_DESC: u16 = 0x2;
+
+const DESC_SIZE: u64 = 16;
+// Offsets of the fields of the available and used rings.
+const RING_IDX: u64 = 2;
+const RING_ENTRIES: u64 = 4;
+const USED_ELEM_SIZE: u64 = 8;
+// Offsets of the fields of packed descriptors and event suppression structures.
+const PACKED_DESC_LEN: u64 = 8;
+const PACKED_DESC_ID: u64 = 12;
+const PACKED_DESC_FLAGS: u64 = 14;
+const PACKED_EVENT_FLAGS: u64 = 2;
+const PACKED_EVENT_SIZE: u64 = 4;
+// Maximum size of packed queues, whose indexes have 15 bits.
+const PACKED_MAX_SIZE: u16 = 0x8000;
+const PACKED_WRAP: u16 = 0x8000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PackedDesc {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+/// Descriptor of a buffer of a [DescriptorChain].
+///
+/// [DescriptorChain]: struct.DescriptorChain.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VringDescriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+}
+
+impl VringDescriptor {
+    /// Get the guest physical address of the buffer.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Get the size of the buffer.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Check whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the flags of the descriptor.
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    /// Check whether the buffer is written by the device, rather than read.
+    pub fn is_write_only(&self) -> bool {
+        self.flags & VRING_DESC_F_WRITE != 0
+    }
+}
+
+/// Descriptors of a request made available by the driver, iterated in order.
+///
+/// The descriptors of an indirect table are iterated in place of the descriptor referring to the
+/// table. Iteration stops at the first invalid descriptor, or after as many descriptors as the
+/// table holds, so a malicious driver can't make the device loop.
+///
+/// The descriptors of packed queues are consecutive in the ring, the chain having been walked
+/// when popped, and so are those of their indirect tables.
+#[derive(Clone)]
+pub struct DescriptorChain {
+    memory: MappedMemory,
+    head: u16,
+    // address of the current descriptor table in the slave, and its number of descriptors
+    table: u64,
+    table_size: u16,
+    packed: bool,
+    indirect: bool,
+    next: Option<u16>,
+    ttl: u16,
+}
+
+impl DescriptorChain {
+    /// Get the index of the first descriptor of the chain, or the buffer id of packed queues,
+    /// which identifies the request in the used ring.
+    pub fn head_index(&self) -> u16 {
+        self.head
+    }
+
+    /// Get the guest memory the buffers of the chain are in.
+    pub fn memory(&self) -> &MappedMemory {
+        &self.memory
+    }
+
+    fn read_desc(&self, index: u16) -> Desc {
+        let addr = self.table + u64::from(index) * DESC_SIZE;
+        if self.packed {
+            // Safe because the table was checked to be within the guest memory, which outlives
+            // the chain, and the index is within the table. The table is 16 bytes aligned.
+            let desc = unsafe { ptr::read_volatile(addr as *const PackedDesc) };
+            Desc {
+                addr: desc.addr,
+                len: desc.len,
+                flags: desc.flags,
+                next: desc.id,
+            }
+        } else {
+            // Safe because the table was checked to be within the guest memory, which outlives
+            // the chain, and the index is within the table. The table is 16 bytes aligned.
+            unsafe { ptr::read_volatile(addr as *const Desc) }
+        }
+    }
+}
+
+impl Iterator for DescriptorChain {
+    type Item = VringDescriptor;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let index = self.next.take()?;
+            if index >= self.table_size || self.ttl == 0 {
+                return None;
+            }
+            self.ttl -= 1;
+            let desc = self.read_desc(index);
+
+            if desc.flags & VRING_DESC_F_INDIRECT != 0 {
+                // Nested indirect tables aren't allowed.
+                if self.indirect || desc.len == 0 || u64::from(desc.len) % DESC_SIZE != 0 {
+                    return None;
+                }
+                let size = u64::from(desc.len) / DESC_SIZE;
+                if size > u64::from(u16::MAX) {
+                    return None;
+                }
+                let table = self
+                    .memory
+                    .gpa_range_to_hva(desc.addr, u64::from(desc.len))
+                    .filter(|addr| addr % DESC_SIZE == 0)?;
+                self.table = table;
+                self.table_size = size as u16;
+                self.indirect = true;
+                self.next = Some(0);
+                self.ttl = size as u16;
+                continue;
+            }
+
+            if self.packed {
+                // The flags of packed indirect tables don't chain the descriptors.
+                if self.ttl > 0 {
+                    self.next = Some((index + 1) % self.table_size);
+                }
+            } else if desc.flags & VRING_DESC_F_NEXT != 0 {
+                self.next = Some(desc.next);
+            }
+            return Some(VringDescriptor {
+                addr: desc.addr,
+                len: desc.len,
+                flags: desc.flags,
+            });
+        }
+    }
+}
+
+/// Split or packed virtqueue in the guest memory mapped by the slave.
+///
+/// Requests are popped from the available ring with [Self::pop()], and returned to the driver
+/// through the used ring with [Self::add_used()]. The descriptor ring of packed queues is both
+/// rings, with the driver and device event suppression structures at the addresses of the
+/// available and used rings.
+///
+/// [Self::pop()]: struct.VringQueue.html#method.pop
+/// [Self::add_used()]: struct.VringQueue.html#method.add_used
+pub struct VringQueue {
+    max_size: u16,
+    size: u16,
+    // whether VIRTIO_F_RING_PACKED was negotiated
+    packed: bool,
+    ready: bool,
+    // addresses of the rings in the slave, valid while ready
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    // free running indexes of split queues, or ring indexes with wrap counters of packed queues
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+    avail_wrap: bool,
+    used_wrap: bool,
+    // number of descriptors of the packed chains in flight, by buffer id
+    chain_lens: Vec<u16>,
+    // position of the used ring when the driver was last checked for notification
+    signalled_used: Option<u32>,
+    // whether VIRTIO_RING_F_EVENT_IDX was negotiated
+    event_idx: bool,
+    rate_limiter: Option<RateLimiter>,
+    memory: MappedMemory,
+}
+
+impl VringQueue {
+    /// Create a queue of at most `max_size` descriptors.
+    pub fn new(max_size: u16) -> Self {
+        VringQueue {
+            max_size,
+            size: max_size,
+            packed: false,
+            ready: false,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            avail_wrap: true,
+            used_wrap: true,
+            chain_lens: Vec::new(),
+            signalled_used: None,
+            event_idx: false,
+            rate_limiter: None,
+            memory: MappedMemory::default(),
+        }
+    }
+
+    /// Get the maximum size of the queue.
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    /// Get the size of the queue set by the master.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Check whether the queue uses the packed layout.
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+
+    /// Check whether the rings of the queue are mapped, so requests may be processed.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Get the index of the next entry of the available ring to process, or of the next
+    /// descriptor of the ring of packed queues.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    /// Get the index of the next entry of the used ring to fill, or of the next descriptor of the
+    /// ring of packed queues.
+    pub fn next_used(&self) -> u16 {
+        self.next_used.0
+    }
+
+    /// Get the guest memory the queue is in.
+    pub fn memory(&self) -> &MappedMemory {
+        &self.memory
+    }
+
+    /// Get the rate limiter of the queue.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Pop the next request made available by the driver.
+    ///
+    /// Requests aren't popped while the rate limiter of the queue is out of tokens.
+    pub fn pop(&mut self) -> Option<DescriptorChain> {
+        if !self.ready {
+            return None;
+        }
+        if let Some(limiter) = self.rate_limiter.as_mut() {
+            if limiter.is_blocked() {
+                return None;
+            }
+        }
+        let chain = if self.packed {
+            self.pop_packed()?
+        } else {
+            self.pop_split()?
+        };
+        if let Some(limiter) = self.rate_limiter.as_mut() {
+            let bytes = match limiter.bytes() {
+                Some(_) => chain.clone().map(|desc| u64::from(desc.len())).sum(),
+                None => 0,
+            };
+            limiter.consume(1, bytes);
+        }
+        Some(chain)
+    }
+
+    /// Return the request of the chain identified by `head` to the driver, with `len` bytes
+    /// written to its buffers.
+    pub fn add_used(&mut self, head: u16, len: u32) -> Result<()> {
+        if !self.ready {
+            return Err(Error::InvalidOperation);
+        }
+        if head >= self.size {
+            return Err(Error::InvalidParam);
+        }
+        if self.packed {
+            self.add_used_packed(head, len);
+            return Ok(());
+        }
+        let slot = u64::from(self.next_used.0 % self.size);
+        let elem = self.used_ring + RING_ENTRIES + slot * USED_ELEM_SIZE;
+        // Safe because the used ring was checked to be within the guest memory, and its entries
+        // are 4 bytes aligned.
+        unsafe {
+            ptr::write_volatile(elem as *mut u32, u32::from(head));
+            ptr::write_volatile((elem + 4) as *mut u32, len);
+        }
+        self.next_used += Wrapping(1);
+        // The entry is visible to the driver before the index.
+        self.ring_idx(self.used_ring)
+            .store(self.next_used.0, Ordering::Release);
+        Ok(())
+    }
+
+    /// Check whether the driver needs to be notified of the requests returned since the last
+    /// check.
+    ///
+    /// With VIRTIO_RING_F_EVENT_IDX, the driver is only notified once the used ring passed the
+    /// `used_event` index of the driver, or the descriptor of the driver event suppression
+    /// structure of packed queues.
+    pub fn needs_notification(&mut self) -> bool {
+        let new = self.used_position();
+        if !self.ready || self.signalled_used == Some(new) {
+            return false;
+        }
+        let old = self.signalled_used.replace(new);
+        // The used ring is visible before the driver suppression fields are read.
+        fence(Ordering::SeqCst);
+        let event = if self.packed {
+            match self.read_u16(self.avail_ring, PACKED_EVENT_FLAGS) {
+                VRING_PACKED_EVENT_FLAG_DISABLE => return false,
+                VRING_PACKED_EVENT_FLAG_DESC if self.event_idx => {
+                    let off_wrap = self.read_u16(self.avail_ring, 0);
+                    self.packed_position(off_wrap & !PACKED_WRAP, off_wrap & PACKED_WRAP != 0)
+                }
+                _ => return true,
+            }
+        } else if self.event_idx {
+            u32::from(self.read_u16(self.avail_ring, self.used_event_offset()))
+        } else {
+            return self.read_u16(self.avail_ring, 0) & VRING_AVAIL_F_NO_INTERRUPT == 0;
+        };
+        match old {
+            // The driver asked to be notified once the used ring passes `event`.
+            Some(old) => {
+                let m = self.position_modulus();
+                (new + 2 * m - event - 1) % m < (new + m - old) % m
+            }
+            None => true,
+        }
+    }
+
+    /// Ask the driver not to notify the device of new requests, while the device processes the
+    /// queue anyway.
+    ///
+    /// With VIRTIO_RING_F_EVENT_IDX the driver keeps notifying up to the last `avail_event` index
+    /// published, so nothing needs to be done.
+    pub fn disable_notification(&mut self) {
+        if self.ready && self.packed {
+            self.write_u16(
+                self.used_ring,
+                PACKED_EVENT_FLAGS,
+                VRING_PACKED_EVENT_FLAG_DISABLE,
+            );
+        } else if self.ready && !self.event_idx {
+            let flags = self.read_u16(self.used_ring, 0);
+            self.write_u16(self.used_ring, 0, flags | VRING_USED_F_NO_NOTIFY);
+        }
+    }
+
+    /// Ask the driver to notify the device of new requests, before the device waits for them.
+    ///
+    /// Return whether requests were made available in the meantime, in which case the device
+    /// should process the queue again instead of waiting for a notification.
+    pub fn enable_notification(&mut self) -> bool {
+        if !self.ready {
+            return false;
+        }
+        if self.packed {
+            let flags = if self.event_idx {
+                // Notify the device of the requests after the last processed one.
+                let off_wrap = self.next_avail.0 | if self.avail_wrap { PACKED_WRAP } else { 0 };
+                self.write_u16(self.used_ring, 0, off_wrap);
+                VRING_PACKED_EVENT_FLAG_DESC
+            } else {
+                VRING_PACKED_EVENT_FLAG_ENABLE
+            };
+            self.write_u16(self.used_ring, PACKED_EVENT_FLAGS, flags);
+            // The suppression structure is visible before the next descriptor is read.
+            fence(Ordering::SeqCst);
+            return self.is_packed_desc_avail(self.next_avail.0);
+        }
+        if self.event_idx {
+            // Notify the device of the requests after the last processed one.
+            self.write_u16(self.used_ring, self.avail_event_offset(), self.next_avail.0);
+        } else {
+            let flags = self.read_u16(self.used_ring, 0);
+            self.write_u16(self.used_ring, 0, flags & !VRING_USED_F_NO_NOTIFY);
+        }
+        // The suppression fields are visible before the index of the available ring is read.
+        fence(Ordering::SeqCst);
+        self.ring_idx(self.avail_ring).load(Ordering::Acquire) != self.next_avail.0
+    }
+
+    // Enable the notification suppression of VIRTIO_RING_F_EVENT_IDX.
+    pub(crate) fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+        self.signalled_used = None;
+    }
+
+    // Limit the rate at which requests are popped with `limiter`.
+    pub(crate) fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
+    }
+
+    // Take the rate limiter of the queue, to carry it over a reset.
+    pub(crate) fn take_rate_limiter(&mut self) -> Option<RateLimiter> {
+        self.rate_limiter.take()
+    }
+
+    // Get the time until the rate limiter allows popping requests again, if throttled.
+    pub(crate) fn throttle_delay(&mut self) -> Option<Duration> {
+        self.rate_limiter.as_mut()?.delay()
+    }
+
+    // Use the packed layout of VIRTIO_F_RING_PACKED.
+    pub(crate) fn set_packed(&mut self, packed: bool) {
+        self.packed = packed;
+        self.signalled_used = None;
+    }
+
+    // Set the size of the queue up to the maximum size, a power of two for split queues.
+    pub(crate) fn set_size(&mut self, size: u16) -> Result<()> {
+        let valid = if self.packed {
+            size <= PACKED_MAX_SIZE
+        } else {
+            size.is_power_of_two()
+        };
+        if size == 0 || size > self.max_size || !valid {
+            return Err(Error::InvalidParam);
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    // Set the position of both rings when the queue starts, failing if `base` is the position of
+    // the other ring layout.
+    pub(crate) fn set_base(&mut self, base: VringBase) -> Result<()> {
+        match base {
+            VringBase::Split(idx) if !self.packed => {
+                self.next_avail = Wrapping(idx);
+                self.next_used = Wrapping(idx);
+            }
+            VringBase::Packed(base) if self.packed => {
+                if base.last_avail_idx >= self.size || base.last_used_idx >= self.size {
+                    return Err(Error::InvalidParam);
+                }
+                self.next_avail = Wrapping(base.last_avail_idx);
+                self.avail_wrap = base.avail_wrap_counter;
+                self.next_used = Wrapping(base.last_used_idx);
+                self.used_wrap = base.used_wrap_counter;
+            }
+            _ => return Err(Error::InvalidParam),
+        }
+        self.signalled_used = None;
+        Ok(())
+    }
+
+    // Get the position of the rings, in the layout of the queue.
+    pub(crate) fn base(&self) -> VringBase {
+        if self.packed {
+            VringBase::Packed(VringPackedBase::new(
+                self.next_avail.0,
+                self.avail_wrap,
+                self.next_used.0,
+                self.used_wrap,
+            ))
+        } else {
+            VringBase::Split(self.next_avail.0)
+        }
+    }
+
+    // Map the rings at the addresses `desc`, `avail` and `used` of the master from `memory`.
+    pub(crate) fn activate(
+        &mut self,
+        memory: &MappedMemory,
+        desc: u64,
+        avail: u64,
+        used: u64,
+    ) -> Result<()> {
+        let size = u64::from(self.size);
+        let (avail_size, used_size) = if self.packed {
+            (PACKED_EVENT_SIZE, PACKED_EVENT_SIZE)
+        } else {
+            (
+                RING_ENTRIES + size * 2 + 2,
+                RING_ENTRIES + size * USED_ELEM_SIZE + 2,
+            )
+        };
+        let desc_table = memory
+            .vmm_va_range_to_hva(desc, size * DESC_SIZE)
+            .filter(|addr| addr % DESC_SIZE == 0);
+        let avail_ring = memory
+            .vmm_va_range_to_hva(avail, avail_size)
+            .filter(|addr| addr % if self.packed { 4 } else { 2 } == 0);
+        let used_ring = memory
+            .vmm_va_range_to_hva(used, used_size)
+            .filter(|addr| addr % 4 == 0);
+        match (desc_table, avail_ring, used_ring) {
+            (Some(desc_table), Some(avail_ring), Some(used_ring)) => {
+                if self.packed && self.chain_lens.len() != self.size as usize {
+                    self.chain_lens = vec![1; self.size as usize];
+                }
+                self.desc_table = desc_table;
+                self.avail_ring = avail_ring;
+                self.used_ring = used_ring;
+                self.memory = memory.clone();
+                self.ready = true;
+                Ok(())
+            }
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    // Stop processing the queue, releasing the guest memory.
+    pub(crate) fn deactivate(&mut self) {
+        self.ready = false;
+        self.memory = MappedMemory::default();
+    }
+
+    // Pop the next request of a split queue from the available ring.
+    fn pop_split(&mut self) -> Option<DescriptorChain> {
+        // The entries of the available ring are read after its index.
+        let avail_idx = self.ring_idx(self.avail_ring).load(Ordering::Acquire);
+        if avail_idx == self.next_avail.0 {
+            return None;
+        }
+        let slot = u64::from(self.next_avail.0 % self.size);
+        // Safe because the available ring was checked to be within the guest memory.
+        let head = unsafe {
+            ptr::read_volatile((self.avail_ring + RING_ENTRIES + slot * 2) as *const u16)
+        };
+        self.next_avail += Wrapping(1);
+
+        Some(DescriptorChain {
+            memory: self.memory.clone(),
+            head,
+            table: self.desc_table,
+            table_size: self.size,
+            packed: false,
+            indirect: false,
+            next: Some(head),
+            ttl: self.size,
+        })
+    }
+
+    // Pop the next request of a packed queue, whose descriptors are consecutive in the ring.
+    fn pop_packed(&mut self) -> Option<DescriptorChain> {
+        let start = self.next_avail.0;
+        if !self.is_packed_desc_avail(start) {
+            return None;
+        }
+        // The buffer id is in the last descriptor of the chain.
+        let mut index = start;
+        let mut count = 0;
+        let id = loop {
+            let addr = self.desc_table + u64::from(index) * DESC_SIZE;
+            // Safe because the descriptor ring was checked to be within the guest memory, and
+            // its fields are aligned.
+            let (id, flags) = unsafe {
+                (
+                    ptr::read_volatile((addr + PACKED_DESC_ID) as *const u16),
+                    ptr::read_volatile((addr + PACKED_DESC_FLAGS) as *const u16),
+                )
+            };
+            count += 1;
+            index = self.advance(index, 1).0;
+            if flags & VRING_DESC_F_NEXT == 0 || flags & VRING_DESC_F_INDIRECT != 0 {
+                break id;
+            }
+            if count == self.size {
+                return None;
+            }
+        };
+        let (next_avail, wrapped) = self.advance(start, count);
+        self.next_avail = Wrapping(next_avail);
+        self.avail_wrap ^= wrapped;
+        if let Some(len) = self.chain_lens.get_mut(id as usize) {
+            *len = count;
+        }
+
+        Some(DescriptorChain {
+            memory: self.memory.clone(),
+            head: id,
+            table: self.desc_table,
+            table_size: self.size,
+            packed: true,
+            indirect: false,
+            next: Some(start),
+            ttl: count,
+        })
+    }
+
+    // Write the used descriptor of the request `id` of a packed queue, in place of the first of
+    // the descriptors of its chain.
+    fn add_used_packed(&mut self, id: u16, len: u32) {
+        let addr = self.desc_table + u64::from(self.next_used.0) * DESC_SIZE;
+        let mut flags = if self.used_wrap {
+            VRING_PACKED_DESC_F_AVAIL | VRING_PACKED_DESC_F_USED
+        } else {
+            0
+        };
+        if len > 0 {
+            flags |= VRING_DESC_F_WRITE;
+        }
+        // Safe because the descriptor ring was checked to be within the guest memory, and its
+        // fields are aligned.
+        unsafe {
+            ptr::write_volatile((addr + PACKED_DESC_LEN) as *mut u32, len);
+            ptr::write_volatile((addr + PACKED_DESC_ID) as *mut u16, id);
+        }
+        // The descriptor is visible to the driver before its flags.
+        self.packed_desc_flags(self.next_used.0)
+            .store(flags, Ordering::Release);
+        let (next_used, wrapped) = self.advance(self.next_used.0, self.chain_lens[id as usize]);
+        self.next_used = Wrapping(next_used);
+        self.used_wrap ^= wrapped;
+    }
+
+    // Check whether the descriptor at `index` of a packed queue was made available by the driver.
+    fn is_packed_desc_avail(&self, index: u16) -> bool {
+        // The descriptor is read after its flags.
+        let flags = self.packed_desc_flags(index).load(Ordering::Acquire);
+        let avail = flags & VRING_PACKED_DESC_F_AVAIL != 0;
+        let used = flags & VRING_PACKED_DESC_F_USED != 0;
+        avail == self.avail_wrap && used != self.avail_wrap
+    }
+
+    fn packed_desc_flags(&self, index: u16) -> &AtomicU16 {
+        let addr = self.desc_table + u64::from(index) * DESC_SIZE + PACKED_DESC_FLAGS;
+        // Safe because the descriptor ring was checked to be within the guest memory, and its
+        // flags are 2 bytes aligned.
+        unsafe { &*(addr as *const AtomicU16) }
+    }
+
+    // Advance the index of a packed queue by `count` descriptors, returning whether it wrapped.
+    fn advance(&self, index: u16, count: u16) -> (u16, bool) {
+        let next = u32::from(index) + u32::from(count);
+        let size = u32::from(self.size);
+        if next >= size {
+            ((next - size) as u16, true)
+        } else {
+            (next as u16, false)
+        }
+    }
+
+    // Position of the used ring, counting the laps of packed queues since the wrap counter was
+    // set, modulo position_modulus().
+    fn used_position(&self) -> u32 {
+        if self.packed {
+            self.packed_position(self.next_used.0, self.used_wrap)
+        } else {
+            u32::from(self.next_used.0)
+        }
+    }
+
+    fn packed_position(&self, index: u16, wrap: bool) -> u32 {
+        let index = u32::from(index) % u32::from(self.size);
+        if wrap {
+            index
+        } else {
+            index + u32::from(self.size)
+        }
+    }
+
+    fn position_modulus(&self) -> u32 {
+        if self.packed {
+            2 * u32::from(self.size)
+        } else {
+            0x1_0000
+        }
+    }
+
+    // Offset of `used_event`, after the entries of the available ring.
+    fn used_event_offset(&self) -> u64 {
+        RING_ENTRIES + u64::from(self.size) * 2
+    }
+
+    // Offset of `avail_event`, after the entries of the used ring.
+    fn avail_event_offset(&self) -> u64 {
+        RING_ENTRIES + u64::from(self.size) * USED_ELEM_SIZE
+    }
+
+    fn read_u16(&self, ring: u64, offset: u64) -> u16 {
+        // Safe because the ring, including its event index, was checked to be within the guest
+        // memory, and its u16 fields are 2 bytes aligned.
+        unsafe { ptr::read_volatile((ring + offset) as *const u16) }
+    }
+
+    fn write_u16(&self, ring: u64, offset: u64, val: u16) {
+        // Safe because the ring, including its event index, was checked to be within the guest
+        // memory, and its u16 fields are 2 bytes aligned.
+        unsafe { ptr::write_volatile((ring + offset) as *mut u16, val) }
+    }
+
+    fn ring_idx(&self, ring: u64) -> &AtomicU16 {
+        // Safe because the ring was checked to be within the guest memory, and 2 bytes aligned.
+        unsafe { &*((ring + RING_IDX) as *const AtomicU16) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::sync::Arc;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::super::mapped_memory::MappedRegion;
+    use super::super::message::VhostUserSingleMemoryRegion;
+
+    // Guest memory of 64KiB at 0x10000 in the guest and at 0x7000_0000 in the master.
+    fn guest_memory() -> MappedMemory {
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(0x1_0000).unwrap();
+        let region = VhostUserSingleMemoryRegion::new(0x1_0000, 0x1_0000, 0x7000_0000, 0);
+        let mut memory = MappedMemory::default();
+        memory
+            .add(Arc::new(MappedRegion::new(&region, file).unwrap()))
+            .unwrap();
+        memory
+    }
+
+    fn write_desc(memory: &MappedMemory, gpa: u64, desc: (u64, u32, u16, u16)) {
+        let addr = memory.gpa_range_to_hva(gpa, DESC_SIZE).unwrap();
+        let desc = Desc {
+            addr: desc.0,
+            len: desc.1,
+            flags: desc.2,
+            next: desc.3,
+        };
+        // Safe because the descriptor is within the guest memory.
+        unsafe { ptr::write_volatile(addr as *mut Desc, desc) };
+    }
+
+    fn write_packed_desc(memory: &MappedMemory, gpa: u64, desc: (u64, u32, u16, u16)) {
+        let addr = memory.gpa_range_to_hva(gpa, DESC_SIZE).unwrap();
+        let desc = PackedDesc {
+            addr: desc.0,
+            len: desc.1,
+            id: desc.2,
+            flags: desc.3,
+        };
+        // Safe because the descriptor is within the guest memory.
+        unsafe { ptr::write_volatile(addr as *mut PackedDesc, desc) };
+    }
+
+    fn write_u16(memory: &MappedMemory, gpa: u64, val: u16) {
+        let addr = memory.gpa_range_to_hva(gpa, 2).unwrap();
+        // Safe because the field is within the guest memory.
+        unsafe { ptr::write_volatile(addr as *mut u16, val) };
+    }
+
+    fn read_u32(memory: &MappedMemory, gpa: u64) -> u32 {
+        let addr = memory.gpa_range_to_hva(gpa, 4).unwrap();
+        // Safe because the field is within the guest memory.
+        unsafe { ptr::read_volatile(addr as *const u32) }
+    }
+
+    #[test]
+    fn test_vring_queue() {
+        let memory = guest_memory();
+        let mut queue = VringQueue::new(16);
+        queue.set_size(12).unwrap_err();
+        queue.set_size(32).unwrap_err();
+        queue.set_size(8).unwrap();
+        assert!(queue.pop().is_none());
+        queue
+            .activate(&memory, 0x7000_0000, 0x7000_1000, 0x7000_fff0)
+            .unwrap_err();
+        queue
+            .activate(&memory, 0x7000_0000, 0x7000_1000, 0x7000_2000)
+            .unwrap();
+        assert!(queue.is_ready());
+
+        // A chain of two descriptors, and one referring to an indirect table of two descriptors.
+        write_desc(&memory, 0x1_0000, (0x1_8000, 0x100, VRING_DESC_F_NEXT, 3));
+        write_desc(&memory, 0x1_0030, (0x1_9000, 0x200, VRING_DESC_F_WRITE, 0));
+        write_desc(&memory, 0x1_0010, (0x1_3000, 32, VRING_DESC_F_INDIRECT, 0));
+        write_desc(&memory, 0x1_3000, (0x1_a000, 0x10, VRING_DESC_F_NEXT, 1));
+        write_desc(&memory, 0x1_3010, (0x1_b000, 0x20, VRING_DESC_F_WRITE, 0));
+        write_u16(&memory, 0x1_1004, 0);
+        write_u16(&memory, 0x1_1006, 1);
+        write_u16(&memory, 0x1_1002, 2);
+
+        let chain = queue.pop().unwrap();
+        assert_eq!(chain.head_index(), 0);
+        let descs: Vec<_> = chain
+            .map(|d| (d.addr(), d.len(), d.is_write_only()))
+            .collect();
+        assert_eq!(
+            descs,
+            vec![(0x1_8000, 0x100, false), (0x1_9000, 0x200, true)]
+        );
+        let chain = queue.pop().unwrap();
+        assert_eq!(chain.head_index(), 1);
+        let descs: Vec<_> = chain.map(|d| (d.addr(), d.len())).collect();
+        assert_eq!(descs, vec![(0x1_a000, 0x10), (0x1_b000, 0x20)]);
+        assert!(queue.pop().is_none());
+        assert_eq!(queue.next_avail(), 2);
+
+        // Chains looping back on themselves are cut.
+        write_desc(&memory, 0x1_0020, (0x1_8000, 0x10, VRING_DESC_F_NEXT, 2));
+        write_u16(&memory, 0x1_1008, 2);
+        write_u16(&memory, 0x1_1002, 3);
+        assert_eq!(queue.pop().unwrap().count(), 8);
+
+        queue.add_used(8, 0).unwrap_err();
+        queue.add_used(1, 0x20).unwrap();
+        assert_eq!(read_u32(&memory, 0x1_2004), 1);
+        assert_eq!(read_u32(&memory, 0x1_2008), 0x20);
+        assert_eq!(read_u32(&memory, 0x1_2000) >> 16, 1);
+        assert!(queue.needs_notification());
+        assert!(!queue.needs_notification());
+        write_u16(&memory, 0x1_1000, VRING_AVAIL_F_NO_INTERRUPT);
+        queue.add_used(0, 0).unwrap();
+        assert!(!queue.needs_notification());
+
+        queue.deactivate();
+        assert!(queue.pop().is_none());
+        queue
+            .set_base(VringBase::Packed(VringPackedBase::default()))
+            .unwrap_err();
+        queue.set_base(VringBase::Split(5)).unwrap();
+        assert_eq!(queue.next_used(), 5);
+        assert_eq!(queue.base(), VringBase::Split(5));
+    }
+
+    #[test]
+    fn test_vring_queue_event_idx() {
+        let memory = guest_memory();
+        let mut queue = VringQueue::new(8);
+        queue.set_event_idx(true);
+        queue
+            .activate(&memory, 0x7000_0000, 0x7000_1000, 0x7000_2000)
+            .unwrap();
+        for head in 0..3u16 {
+            let gpa = 0x1_0000 + u64::from(head) * DESC_SIZE;
+            write_desc(&memory, gpa, (0x1_8000, 0x10, 0, 0));
+            write_u16(&memory, 0x1_1004 + u64::from(head) * 2, head);
+        }
+        write_u16(&memory, 0x1_1002, 3);
+        while queue.pop().is_some() {}
+
+        // The driver is asked to notify the requests after the processed ones.
+        assert!(!queue.enable_notification());
+        assert_eq!(read_u32(&memory, 0x1_2044) & 0xffff, 3);
+        write_u16(&memory, 0x1_1002, 4);
+        assert!(queue.enable_notification());
+
+        // The driver is notified once the used ring passes `used_event`.
+        queue.add_used(0, 0).unwrap();
+        assert!(queue.needs_notification());
+        write_u16(&memory, 0x1_1014, 2);
+        queue.add_used(1, 0).unwrap();
+        assert!(!queue.needs_notification());
+        queue.add_used(2, 0).unwrap();
+        assert!(queue.needs_notification());
+
+        // Without event indexes, notifications are suppressed with the flags.
+        queue.set_event_idx(false);
+        queue.disable_notification();
+        assert_eq!(
+            read_u32(&memory, 0x1_2000) & 0xffff,
+            u32::from(VRING_USED_F_NO_NOTIFY)
+        );
+        queue.enable_notification();
+        assert_eq!(read_u32(&memory, 0x1_2000) & 0xffff, 0);
+    }
+
+    #[test]
+    fn test_vring_queue_packed() {
+        let memory = guest_memory();
+        let mut queue = VringQueue::new(16);
+        queue.set_packed(true);
+        queue.set_size(3).unwrap();
+        queue
+            .activate(&memory, 0x7000_0000, 0x7000_1000, 0x7000_2002)
+            .unwrap_err();
+        queue
+            .activate(&memory, 0x7000_0000, 0x7000_1000, 0x7000_2000)
+            .unwrap();
+        assert!(queue.is_packed());
+        assert!(queue.pop().is_none());
+
+        // A chain of two descriptors, and one referring to an indirect table of two descriptors.
+        let avail = VRING_PACKED_DESC_F_AVAIL;
+        write_packed_desc(
+            &memory,
+            0x1_0000,
+            (0x1_8000, 0x100, 0, avail | VRING_DESC_F_NEXT),
+        );
+        write_packed_desc(
+            &memory,
+            0x1_0010,
+            (0x1_9000, 0x200, 2, avail | VRING_DESC_F_WRITE),
+        );
+        let flags = avail | VRING_DESC_F_INDIRECT;
+        write_packed_desc(&memory, 0x1_0020, (0x1_3000, 32, 0, flags));
+        write_packed_desc(&memory, 0x1_3000, (0x1_a000, 0x10, 0, 0));
+        write_packed_desc(&memory, 0x1_3010, (0x1_b000, 0x20, 0, VRING_DESC_F_WRITE));
+
+        let chain = queue.pop().unwrap();
+        assert_eq!(chain.head_index(), 2);
+        let descs: Vec<_> = chain
+            .map(|d| (d.addr(), d.len(), d.is_write_only()))
+            .collect();
+        assert_eq!(
+            descs,
+            vec![(0x1_8000, 0x100, false), (0x1_9000, 0x200, true)]
+        );
+        let chain = queue.pop().unwrap();
+        assert_eq!(chain.head_index(), 0);
+        let descs: Vec<_> = chain.map(|d| (d.addr(), d.len())).collect();
+        assert_eq!(descs, vec![(0x1_a000, 0x10), (0x1_b000, 0x20)]);
+        // The descriptors of the previous lap aren't available anymore once the ring wrapped.
+        assert!(queue.pop().is_none());
+        assert_eq!(queue.next_avail(), 0);
+        assert_eq!(
+            queue.base(),
+            VringBase::Packed(VringPackedBase::new(0, false, 0, true))
+        );
+
+        // Used descriptors replace the first descriptor of their chain.
+        let used = VRING_PACKED_DESC_F_AVAIL | VRING_PACKED_DESC_F_USED;
+        queue.add_used(3, 0).unwrap_err();
+        queue.add_used(2, 0x20).unwrap();
+        assert_eq!(read_u32(&memory, 0x1_0008), 0x20);
+        let flags = used | VRING_DESC_F_WRITE;
+        assert_eq!(read_u32(&memory, 0x1_000c), 2 | u32::from(flags) << 16);
+        assert_eq!(queue.next_used(), 2);
+        assert!(queue.needs_notification());
+        write_u16(&memory, 0x1_1002, VRING_PACKED_EVENT_FLAG_DISABLE);
+        queue.add_used(0, 0).unwrap();
+        assert!(!queue.needs_notification());
+        assert_eq!(read_u32(&memory, 0x1_002c), u32::from(used) << 16);
+        assert_eq!(queue.base(), VringBase::Packed(VringPackedBase::default()));
+
+        // The driver is asked to notify the descriptors after the processed ones.
+        queue.set_event_idx(true);
+        assert!(!queue.needs_notification());
+        assert!(!queue.enable_notification());
+        assert_eq!(
+            read_u32(&memory, 0x1_2000),
+            u32::from(VRING_PACKED_EVENT_FLAG_DESC) << 16
+        );
+        let flags = VRING_PACKED_DESC_F_USED;
+        write_packed_desc(&memory, 0x1_0000, (0x1_8000, 0x10, 1, flags));
+        write_packed_desc(&memory, 0x1_0010, (0x1_8000, 0x10, 0, flags));
+        assert!(queue.enable_notification());
+        queue.disable_notification();
+        assert_eq!(
+            read_u32(&memory, 0x1_2000) >> 16,
+            u32::from(VRING_PACKED_EVENT_FLAG_DISABLE)
+        );
+        assert_eq!(queue.pop().unwrap().head_index(), 1);
+        assert_eq!(queue.pop().unwrap().head_index(), 0);
+
+        // The driver is notified once the used ring passes the descriptor of the driver.
+        write_u16(&memory, 0x1_1000, 1);
+        write_u16(&memory, 0x1_1002, VRING_PACKED_EVENT_FLAG_DESC);
+        queue.add_used(1, 0).unwrap();
+        assert!(!queue.needs_notification());
+        queue.add_used(0, 0).unwrap();
+        assert!(queue.needs_notification());
+
+        queue.set_base(VringBase::Split(0)).unwrap_err();
+        let base = VringPackedBase::new(3, true, 0, false);
+        queue.set_base(VringBase::Packed(base)).unwrap_err();
+        let base = VringPackedBase::new(1, false, 2, true);
+        queue.set_base(VringBase::Packed(base)).unwrap();
+        assert_eq!(queue.next_avail(), 1);
+        assert_eq!(queue.next_used(), 2);
+    }
+}
@@ -0,0 +1,1246 @@
+// Copyright (C) 2021 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Built-in vring workers, so simple slaves only implement the processing of their queues.
+
+use std::fs::File;
+use std::io::{Error as IOError, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+use super::mapped_memory::{MappedMemory, MappedRegion, MemoryMappingManager};
+use super::message::*;
+use super::rate_limiter::RateLimiter;
+use super::slave_fs_cache::SlaveFsCacheReq;
+use super::slave_req_handler::VhostUserSlaveReqHandlerMut;
+use super::vring::VringQueue;
+use super::{Error, Result, VhostUserMasterReqHandler};
+
+const MAX_MEM_SLOTS: u64 = 32;
+const DEFAULT_THREAD_NAME: &str = "vring_worker";
+// Flag of the epoll events of the rate limiter timers, rather than of the kicks.
+const TIMER_EVENT: u64 = 1 << 32;
+// Data of the epoll event of the exit eventfd of a worker.
+const EXIT_EVENT: u64 = u64::MAX;
+// Interval at which a shutdown checks whether the worker threads exited.
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Device implemented on top of the built-in vring workers of [VringWorkerHandler].
+///
+/// The handler serves the requests of the master, and the workers call [Self::process_queue()]
+/// whenever the driver kicks a started queue.
+///
+/// [VringWorkerHandler]: struct.VringWorkerHandler.html
+/// [Self::process_queue()]: trait.VhostUserBackend.html#tymethod.process_queue
+pub trait VhostUserBackend: Send + Sync + 'static {
+    /// Get the number of queues of the device.
+    fn num_queues(&self) -> usize;
+
+    /// Get the maximum size of the queues of the device.
+    fn max_queue_size(&self) -> u16;
+
+    /// Get the virtio features of the device.
+    ///
+    /// VHOST_USER_F_PROTOCOL_FEATURES is offered by the handler.
+    fn features(&self) -> u64;
+
+    /// Get the vhost-user protocol features of the device.
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK
+    }
+
+    /// Notify the device of the virtio features acknowledged by the driver.
+    fn acked_features(&self, _features: u64) {}
+
+    /// Read `size` bytes at `offset` of the device configuration space.
+    fn get_config(&self, _offset: u32, _size: u32) -> Result<Vec<u8>> {
+        Err(Error::InvalidOperation)
+    }
+
+    /// Write `buf` at `offset` of the device configuration space.
+    fn set_config(&self, _offset: u32, _buf: &[u8]) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+
+    /// Notify the device that the guest memory changed.
+    fn update_memory(&self, _memory: &MappedMemory) -> Result<()> {
+        Ok(())
+    }
+
+    /// Notify the device of a region added to the guest memory by SET_MEM_TABLE or ADD_MEM_REG,
+    /// before [Self::update_memory()].
+    ///
+    /// The region gives the file, offset and mapping of the memory, for devices sharing it with
+    /// hardware or mapping parts of it again. Failing rejects the request.
+    ///
+    /// [Self::update_memory()]: trait.VhostUserBackend.html#method.update_memory
+    fn add_mem_region(&self, _region: &Arc<MappedRegion>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Notify the device of a region removed from the guest memory by SET_MEM_TABLE or
+    /// REM_MEM_REG, after [Self::update_memory()], once the queues no longer use it.
+    ///
+    /// [Self::update_memory()]: trait.VhostUserBackend.html#method.update_memory
+    fn remove_mem_region(&self, _region: &Arc<MappedRegion>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Process the requests of queue `index`, which was kicked by the driver.
+    ///
+    /// The driver is notified of the requests returned through the used ring once the method
+    /// returns. Failures are reported through the error eventfd of the queue.
+    fn process_queue(&self, index: u16, queue: &mut VringQueue) -> Result<()>;
+}
+
+/// Configuration of a thread of the built-in vring workers of [VringWorkerHandler].
+///
+/// [VringWorkerHandler]: struct.VringWorkerHandler.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VringThreadConfig {
+    name: String,
+    cpus: Vec<usize>,
+}
+
+impl VringThreadConfig {
+    /// Create the configuration of a thread named "vring_worker", which may run on any CPU.
+    pub fn new() -> Self {
+        VringThreadConfig {
+            name: DEFAULT_THREAD_NAME.to_string(),
+            cpus: Vec::new(),
+        }
+    }
+
+    /// Name the thread `name`, truncated to 15 bytes by Linux.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Pin the thread to the CPUs `cpus`.
+    pub fn cpus(mut self, cpus: &[usize]) -> Self {
+        self.cpus = cpus.to_vec();
+        self
+    }
+
+    // Build the affinity mask of the thread, if pinned.
+    fn cpu_set(&self) -> Result<Option<libc::cpu_set_t>> {
+        if self.cpus.is_empty() {
+            return Ok(None);
+        }
+        // Safe because cpu_set_t is a plain bitmask.
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &cpu in &self.cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(Error::InvalidParam);
+            }
+            // Safe because the CPU is within the mask.
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        Ok(Some(set))
+    }
+}
+
+impl Default for VringThreadConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Vring {
+    queue: VringQueue,
+    // addresses of the descriptor table, available ring and used ring in the master
+    addrs: Option<(u64, u64, u64)>,
+    kick: Option<File>,
+    call: Option<File>,
+    err: Option<File>,
+    // timer to process the queue again once its rate limiter allows it
+    timer: Option<TimerFd>,
+    enabled: bool,
+}
+
+impl Vring {
+    fn new(max_size: u16) -> Self {
+        Vring {
+            queue: VringQueue::new(max_size),
+            addrs: None,
+            kick: None,
+            call: None,
+            err: None,
+            timer: None,
+            enabled: false,
+        }
+    }
+
+    fn start(&mut self, memory: &MappedMemory) -> Result<()> {
+        let (desc, avail, used) = self.addrs.ok_or(Error::InvalidOperation)?;
+        self.queue.activate(memory, desc, avail, used)
+    }
+
+    fn signal(file: &Option<File>) {
+        if let Some(mut file) = file.as_ref() {
+            // The eventfd only fails when its counter overflows, so the driver is notified.
+            let _ = file.write_all(&1u64.to_ne_bytes());
+        }
+    }
+}
+
+type Vrings = Arc<Vec<Mutex<Vring>>>;
+
+// Thread waiting for the kicks of the queues assigned to it, and processing them until its exit
+// eventfd is signaled.
+struct VringWorker {
+    epoll: Arc<Epoll>,
+    exit: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VringWorker {
+    fn spawn<B: VhostUserBackend>(
+        config: &VringThreadConfig,
+        backend: Arc<B>,
+        vrings: Vrings,
+    ) -> Result<Self> {
+        let cpu_set = config.cpu_set()?;
+        let epoll = Arc::new(Epoll::new().map_err(Error::ReqHandlerError)?);
+        let worker_epoll = epoll.clone();
+        let exit = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::ReqHandlerError)?;
+        let event = EpollEvent::new(EventSet::IN, EXIT_EVENT);
+        epoll
+            .ctl(ControlOperation::Add, exit.as_raw_fd(), event)
+            .map_err(Error::ReqHandlerError)?;
+        // The thread reports whether it could be pinned before waiting for kicks.
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(config.name.clone())
+            .spawn(move || {
+                let res = cpu_set.map_or(Ok(()), |set| Self::set_affinity(&set));
+                let pinned = res.is_ok();
+                let _ = tx.send(res);
+                if pinned {
+                    Self::run(worker_epoll, backend, vrings);
+                }
+            })
+            .map_err(Error::ReqHandlerError)?;
+        rx.recv()
+            .map_err(|_| Error::InvalidOperation)?
+            .map_err(Error::ReqHandlerError)?;
+        Ok(VringWorker {
+            epoll,
+            exit,
+            thread: Some(thread),
+        })
+    }
+
+    // Ask the thread to exit, once done with the queue it is processing.
+    fn stop(&self) -> Result<()> {
+        self.exit.write(1).map_err(Error::ReqHandlerError)
+    }
+
+    // Wait for the thread to exit, failing if it panicked.
+    fn join(&mut self) -> Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| Error::SlaveInternalError),
+            None => Ok(()),
+        }
+    }
+
+    // Wait for the thread to exit until `deadline`, detaching it if it didn't.
+    fn join_until(&mut self, deadline: Instant) -> Result<()> {
+        while let Some(thread) = self.thread.as_ref() {
+            if thread.is_finished() {
+                return self.join();
+            }
+            if Instant::now() >= deadline {
+                self.thread = None;
+                return Err(Error::Timeout);
+            }
+            thread::sleep(JOIN_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn set_affinity(set: &libc::cpu_set_t) -> std::io::Result<()> {
+        // Safe because the kernel only reads the mask, and the return value is checked.
+        let ret = unsafe {
+            libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), set as *const _)
+        };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Wait for `fd`, reporting `data` to identify it.
+    fn register(&self, data: u64, fd: RawFd) -> Result<()> {
+        let event = EpollEvent::new(EventSet::IN, data);
+        self.epoll
+            .ctl(ControlOperation::Add, fd, event)
+            .map_err(Error::ReqHandlerError)
+    }
+
+    fn unregister(&self, fd: RawFd) -> Result<()> {
+        self.epoll
+            .ctl(ControlOperation::Delete, fd, EpollEvent::default())
+            .map_err(Error::ReqHandlerError)
+    }
+
+    fn run<B: VhostUserBackend>(epoll: Arc<Epoll>, backend: Arc<B>, vrings: Vrings) {
+        let mut events = vec![EpollEvent::default(); vrings.len() * 2 + 1];
+        loop {
+            let num = match epoll.wait(-1, &mut events) {
+                Ok(num) => num,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            for event in &events[..num] {
+                if event.data() == EXIT_EVENT {
+                    return;
+                }
+                let index = event.data() as u32 as usize;
+                let timer = event.data() & TIMER_EVENT != 0;
+                if let Some(vring) = vrings.get(index) {
+                    Self::process(&*backend, index as u16, &mut vring.lock().unwrap(), timer);
+                }
+            }
+        }
+    }
+
+    fn process<B: VhostUserBackend>(backend: &B, index: u16, vring: &mut Vring, timer: bool) {
+        if timer {
+            // Disarming the timer resets its expirations, it may have been replaced meanwhile.
+            if let Some(timer) = vring.timer.as_mut() {
+                let _ = timer.clear();
+            }
+        } else if let Some(mut kick) = vring.kick.as_ref() {
+            let mut buf = [0u8; 8];
+            // Reset the eventfd, it may already have been reset by the previous kick.
+            let _ = kick.read_exact(&mut buf);
+        }
+        if !vring.enabled || !vring.queue.is_ready() {
+            return;
+        }
+        loop {
+            let next_avail = vring.queue.next_avail();
+            vring.queue.disable_notification();
+            if backend.process_queue(index, &mut vring.queue).is_err() {
+                Vring::signal(&vring.err);
+                break;
+            }
+            // Throttled queues are processed again once the rate limiter allows it, whether the
+            // driver notifies new requests or not.
+            if let Some(delay) = vring.queue.throttle_delay() {
+                if let Some(timer) = vring.timer.as_mut() {
+                    let _ = timer.reset(delay, None);
+                }
+                break;
+            }
+            // Process the requests made available while notifications were disabled, unless the
+            // backend left requests in the queue.
+            if !vring.queue.enable_notification() || vring.queue.next_avail() == next_avail {
+                break;
+            }
+        }
+        if vring.queue.needs_notification() {
+            Vring::signal(&vring.call);
+        }
+    }
+}
+
+impl Drop for VringWorker {
+    fn drop(&mut self) {
+        let _ = self.stop();
+        let _ = self.join();
+    }
+}
+
+/// Slave request handler driving the queues of a [VhostUserBackend] with built-in workers.
+///
+/// The handler maps the guest memory, and worker threads process the queues once started by
+/// SET_VRING_KICK, until stopped by GET_VRING_BASE. The queues are distributed round-robin
+/// across the threads, queue `i` being processed by thread `i % threads`. The handler is wrapped
+/// in a `Mutex` to be served by a [SlaveReqHandler], such as those accepted by a [SlaveListener].
+/// The worker threads exit when the handler is dropped, or once the queues are drained by the
+/// `shutdown()` method of [VhostUserSlaveReqHandlerMut].
+///
+/// [VhostUserBackend]: trait.VhostUserBackend.html
+/// [SlaveReqHandler]: struct.SlaveReqHandler.html
+/// [SlaveListener]: struct.SlaveListener.html
+/// [VhostUserSlaveReqHandlerMut]: trait.VhostUserSlaveReqHandlerMut.html
+pub struct VringWorkerHandler<B: VhostUserBackend> {
+    backend: Arc<B>,
+    owned: bool,
+    acked_features: u64,
+    acked_protocol_features: u64,
+    memory: MemoryMappingManager,
+    vrings: Vrings,
+    workers: Vec<VringWorker>,
+    slave: Option<SlaveFsCacheReq>,
+}
+
+impl<B: VhostUserBackend> VringWorkerHandler<B> {
+    /// Create a handler for `backend`, spawning a single worker thread.
+    pub fn new(backend: Arc<B>) -> Result<Self> {
+        Self::with_threads(backend, &[VringThreadConfig::new()])
+    }
+
+    /// Create a handler for `backend`, spawning a worker thread for each of `threads`.
+    pub fn with_threads(backend: Arc<B>, threads: &[VringThreadConfig]) -> Result<Self> {
+        if threads.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let vrings: Vec<_> = (0..backend.num_queues())
+            .map(|_| Mutex::new(Vring::new(backend.max_queue_size())))
+            .collect();
+        let vrings = Arc::new(vrings);
+        let workers = threads
+            .iter()
+            .map(|config| VringWorker::spawn(config, backend.clone(), vrings.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(VringWorkerHandler {
+            backend,
+            owned: false,
+            acked_features: 0,
+            acked_protocol_features: 0,
+            memory: MemoryMappingManager::new(),
+            vrings,
+            workers,
+            slave: None,
+        })
+    }
+
+    /// Ask the worker threads to exit, once done with the queues they are processing.
+    ///
+    /// The queues aren't processed anymore, even if kicked by the driver.
+    pub fn stop(&self) -> Result<()> {
+        for worker in self.workers.iter() {
+            worker.stop()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the worker threads to exit after [Self::stop()].
+    ///
+    /// [Self::stop()]: struct.VringWorkerHandler.html#method.stop
+    pub fn join(&mut self) -> Result<()> {
+        for worker in self.workers.iter_mut() {
+            worker.join()?;
+        }
+        Ok(())
+    }
+
+    /// Notify the master that the device configuration space changed, such as the capacity of a
+    /// block device, so the driver reads it again.
+    ///
+    /// VHOST_USER_PROTOCOL_F_CONFIG and VHOST_USER_PROTOCOL_F_SLAVE_REQ must have been negotiated,
+    /// and the master must have sent the slave channel. The master acknowledges the notification
+    /// if VHOST_USER_PROTOCOL_F_REPLY_ACK was negotiated too.
+    pub fn notify_config_change(&self) -> Result<()> {
+        self.slave(VhostUserProtocolFeatures::CONFIG)?
+            .handle_config_change()
+            .map_err(Error::ReqHandlerError)?;
+        Ok(())
+    }
+
+    /// Ask the master to map the `size` bytes at `offset` of `file` as the doorbell area of queue
+    /// `index`, or to stop using the doorbell area of the queue if `file` is None.
+    ///
+    /// VHOST_USER_PROTOCOL_F_HOST_NOTIFIER, VHOST_USER_PROTOCOL_F_SLAVE_REQ and
+    /// VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD must have been negotiated, and the master must have
+    /// sent the slave channel.
+    pub fn set_vring_host_notifier(
+        &self,
+        index: u8,
+        file: Option<&dyn AsFd>,
+        offset: u64,
+        size: u64,
+    ) -> Result<()> {
+        self.vring(u32::from(index))?;
+        let send_fd = VhostUserProtocolFeatures::SLAVE_SEND_FD;
+        if self.acked_protocol_features & send_fd.bits() == 0 {
+            return Err(Error::ProtocolFeatureNotNegotiated(send_fd));
+        }
+        let slave = self.slave(VhostUserProtocolFeatures::HOST_NOTIFIER)?;
+        match file {
+            Some(file) => slave.set_vring_host_notifier(index, file, offset, size)?,
+            None => slave.remove_vring_host_notifier(index)?,
+        };
+        Ok(())
+    }
+
+    /// Get the backend of the handler.
+    pub fn backend(&self) -> &Arc<B> {
+        &self.backend
+    }
+
+    /// Get the guest memory mapped by the handler.
+    pub fn memory(&self) -> &MappedMemory {
+        self.memory.memory()
+    }
+
+    /// Map the guest memory regions sent afterwards by the master with `manager`, to set options
+    /// such as MAP_POPULATE or the Xen mapping hooks.
+    ///
+    /// The regions already mapped are kept.
+    pub fn set_memory_manager(&mut self, mut manager: MemoryMappingManager) {
+        manager.set_memory(self.memory.memory().clone());
+        self.memory = manager;
+    }
+
+    /// Limit the rate at which the requests of queue `index` are processed with `limiter`, or
+    /// remove the limit if `limiter` is None.
+    ///
+    /// Once the rate limiter is out of tokens, requests aren't popped from the queue until the
+    /// worker processes it again, after the time needed to refill the tokens.
+    pub fn set_rate_limiter(&mut self, index: u16, limiter: Option<RateLimiter>) -> Result<()> {
+        let mut vring = self.vring(u32::from(index))?.lock().unwrap();
+        let worker = self.worker(index as usize);
+        if let Some(timer) = vring.timer.take() {
+            worker.unregister(timer.as_raw_fd())?;
+        }
+        if limiter.is_some() {
+            let timer = TimerFd::new().map_err(|e| Error::ReqHandlerError(e.into()))?;
+            worker.register(u64::from(index) | TIMER_EVENT, timer.as_raw_fd())?;
+            vring.timer = Some(timer);
+        }
+        vring.queue.set_rate_limiter(limiter);
+        Ok(())
+    }
+
+    // Get the slave channel to send requests depending on `feature`.
+    fn slave(&self, feature: VhostUserProtocolFeatures) -> Result<&SlaveFsCacheReq> {
+        for feature in [feature, VhostUserProtocolFeatures::SLAVE_REQ].iter() {
+            if self.acked_protocol_features & feature.bits() == 0 {
+                return Err(Error::ProtocolFeatureNotNegotiated(*feature));
+            }
+        }
+        self.slave.as_ref().ok_or(Error::InvalidOperation)
+    }
+
+    fn vring(&self, index: u32) -> Result<&Mutex<Vring>> {
+        self.vrings.get(index as usize).ok_or(Error::InvalidParam)
+    }
+
+    // Get the worker processing queue `index`.
+    fn worker(&self, index: usize) -> &VringWorker {
+        &self.workers[index % self.workers.len()]
+    }
+
+    fn stop_vring(&self, index: usize, vring: &mut Vring) -> Result<()> {
+        if let Some(kick) = vring.kick.take() {
+            self.worker(index).unregister(kick.as_raw_fd())?;
+        }
+        vring.queue.deactivate();
+        Ok(())
+    }
+
+    // Switch to `memory`, remapping the rings of the started queues.
+    fn update_memory(&mut self, memory: MappedMemory) -> Result<()> {
+        let old = self.memory.memory().clone();
+        let added: Vec<_> = memory
+            .regions()
+            .iter()
+            .filter(|region| !old.regions().iter().any(|r| Arc::ptr_eq(r, region)))
+            .collect();
+        for (i, region) in added.iter().enumerate() {
+            if let Err(e) = self.backend.add_mem_region(region) {
+                for region in &added[..i] {
+                    let _ = self.backend.remove_mem_region(region);
+                }
+                return Err(e);
+            }
+        }
+
+        for vring in self.vrings.iter() {
+            let mut vring = vring.lock().unwrap();
+            if vring.queue.is_ready() {
+                vring.start(&memory)?;
+            }
+        }
+        self.memory.set_memory(memory);
+        self.backend.update_memory(self.memory.memory())?;
+
+        let memory = self.memory.memory();
+        for region in old.regions() {
+            if !memory.regions().iter().any(|r| Arc::ptr_eq(r, region)) {
+                self.backend.remove_mem_region(region)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: VhostUserBackend> VhostUserSlaveReqHandlerMut for VringWorkerHandler<B> {
+    fn set_owner(&mut self) -> Result<()> {
+        if self.owned {
+            return Err(Error::InvalidOperation);
+        }
+        self.owned = true;
+        Ok(())
+    }
+
+    fn reset_owner(&mut self) -> Result<()> {
+        self.reset_device()?;
+        self.owned = false;
+        self.acked_protocol_features = 0;
+        self.slave = None;
+        Ok(())
+    }
+
+    fn get_features(&mut self) -> Result<u64> {
+        Ok(self.backend.features() | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits())
+    }
+
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        if !self.owned {
+            return Err(Error::InvalidOperation);
+        }
+        if features & !self.get_features()? != 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.acked_features = features;
+        // Without VHOST_USER_F_PROTOCOL_FEATURES, the queues are enabled once started.
+        let enabled = features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0;
+        let event_idx = features & VhostUserVirtioFeatures::EVENT_IDX.bits() != 0;
+        let packed = features & VhostUserVirtioFeatures::RING_PACKED.bits() != 0;
+        for vring in self.vrings.iter() {
+            let mut vring = vring.lock().unwrap();
+            vring.enabled = enabled;
+            vring.queue.set_event_idx(event_idx);
+            vring.queue.set_packed(packed);
+        }
+        self.backend.acked_features(features);
+        Ok(())
+    }
+
+    fn set_mem_table(&mut self, ctx: &[VhostUserMemoryRegion], files: Vec<File>) -> Result<()> {
+        let memory = self.memory.map_mem_table(ctx, files)?;
+        self.update_memory(memory)
+    }
+
+    fn set_vring_num(&mut self, index: u32, num: u32) -> Result<()> {
+        if num > u32::from(u16::MAX) {
+            return Err(Error::InvalidParam);
+        }
+        self.vring(index)?
+            .lock()
+            .unwrap()
+            .queue
+            .set_size(num as u16)
+    }
+
+    fn set_vring_addr(
+        &mut self,
+        index: u32,
+        _flags: VhostUserVringAddrFlags,
+        descriptor: u64,
+        used: u64,
+        available: u64,
+        _log: u64,
+    ) -> Result<()> {
+        self.vring(index)?.lock().unwrap().addrs = Some((descriptor, available, used));
+        Ok(())
+    }
+
+    fn set_vring_base(&mut self, index: u32, base: VringBase) -> Result<()> {
+        self.vring(index)?.lock().unwrap().queue.set_base(base)
+    }
+
+    fn get_vring_base(&mut self, index: u32) -> Result<VringBase> {
+        // The worker doesn't hold the lock anymore once the queue is stopped.
+        let mut vring = self.vring(index)?.lock().unwrap();
+        self.stop_vring(index as usize, &mut vring)?;
+        Ok(vring.queue.base())
+    }
+
+    fn set_vring_kick(&mut self, index: u8, fd: Option<File>) -> Result<()> {
+        let mut vring = self.vring(u32::from(index))?.lock().unwrap();
+        self.stop_vring(index as usize, &mut vring)?;
+        let kick = fd.ok_or(Error::InvalidParam)?;
+        vring.start(self.memory.memory())?;
+        self.worker(index as usize)
+            .register(u64::from(index), kick.as_raw_fd())?;
+        vring.kick = Some(kick);
+        Ok(())
+    }
+
+    fn set_vring_call(&mut self, index: u8, fd: Option<File>) -> Result<()> {
+        self.vring(u32::from(index))?.lock().unwrap().call = fd;
+        Ok(())
+    }
+
+    fn set_vring_err(&mut self, index: u8, fd: Option<File>) -> Result<()> {
+        self.vring(u32::from(index))?.lock().unwrap().err = fd;
+        Ok(())
+    }
+
+    fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures> {
+        Ok(self.backend.protocol_features())
+    }
+
+    fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.acked_protocol_features = features;
+        if let Some(slave) = self.slave.as_ref() {
+            slave.set_reply_ack_flag(features & VhostUserProtocolFeatures::REPLY_ACK.bits() != 0);
+        }
+        Ok(())
+    }
+
+    fn get_queue_num(&mut self) -> Result<u64> {
+        Ok(self.vrings.len() as u64)
+    }
+
+    fn set_vring_enable(&mut self, index: u32, enable: bool) -> Result<()> {
+        if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
+            return Err(Error::InvalidOperation);
+        }
+        self.vring(index)?.lock().unwrap().enabled = enable;
+        Ok(())
+    }
+
+    fn get_config(
+        &mut self,
+        offset: u32,
+        size: u32,
+        _flags: VhostUserConfigFlags,
+    ) -> Result<Vec<u8>> {
+        self.backend.get_config(offset, size)
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8], _flags: VhostUserConfigFlags) -> Result<()> {
+        self.backend.set_config(offset, buf)
+    }
+
+    fn set_slave_req_fd(&mut self, vu_req: SlaveFsCacheReq) {
+        let reply_ack = VhostUserProtocolFeatures::REPLY_ACK.bits();
+        vu_req.set_reply_ack_flag(self.acked_protocol_features & reply_ack != 0);
+        self.slave = Some(vu_req);
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        _inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn set_inflight_fd(&mut self, _inflight: &VhostUserInflight, _file: File) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn get_max_mem_slots(&mut self) -> Result<u64> {
+        Ok(MAX_MEM_SLOTS)
+    }
+
+    fn add_mem_region(&mut self, region: &VhostUserSingleMemoryRegion, fd: File) -> Result<()> {
+        if self.memory().regions().len() as u64 >= MAX_MEM_SLOTS {
+            return Err(Error::InvalidOperation);
+        }
+        let mut memory = self.memory().clone();
+        memory.add(self.memory.map_region(region, fd)?)?;
+        self.update_memory(memory)
+    }
+
+    fn remove_mem_region(&mut self, region: &VhostUserSingleMemoryRegion) -> Result<()> {
+        let mut memory = self.memory().clone();
+        memory
+            .remove(region.guest_phys_addr, region.memory_size)
+            .ok_or(Error::InvalidParam)?;
+        self.update_memory(memory)
+    }
+
+    fn reset_device(&mut self) -> Result<()> {
+        for (index, vring) in self.vrings.iter().enumerate() {
+            let mut vring = vring.lock().unwrap();
+            self.stop_vring(index, &mut vring)?;
+            // The rate limits aren't part of the device state.
+            let limiter = vring.queue.take_rate_limiter();
+            let timer = vring.timer.take();
+            *vring = Vring::new(self.backend.max_queue_size());
+            vring.queue.set_rate_limiter(limiter);
+            vring.timer = timer;
+        }
+        self.acked_features = 0;
+        Ok(())
+    }
+
+    fn shutdown(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        // The workers exit once done with the queue they are processing. Those which don't exit
+        // in time are detached, still holding the lock of their queue.
+        self.stop()?;
+        for worker in self.workers.iter_mut() {
+            worker.join_until(deadline)?;
+        }
+        // The requests returned are already in the used rings, notify the driver of them.
+        for (index, vring) in self.vrings.iter().enumerate() {
+            let mut vring = vring.lock().unwrap();
+            if vring.queue.is_ready() {
+                Vring::signal(&vring.call);
+            }
+            self.stop_vring(index, &mut vring)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::ptr;
+    use std::time::{Duration, Instant};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::super::connection::Endpoint;
+    use super::super::rate_limiter::TokenBucket;
+
+    #[derive(Default)]
+    struct EchoBackend {
+        // name and number of allowed CPUs of the thread processing each queue
+        threads: Mutex<Vec<(u16, String, i32)>>,
+        // time spent processing a queue
+        delay: Mutex<Duration>,
+        // guest physical address of the memory regions added
+        regions: Mutex<Vec<u64>>,
+    }
+
+    fn thread_cpus() -> libc::cpu_set_t {
+        // Safe because cpu_set_t is a plain bitmask.
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        // Safe because the kernel only writes to the mask, and the return value is checked.
+        let ret =
+            unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) };
+        assert_eq!(ret, 0);
+        set
+    }
+
+    impl VhostUserBackend for EchoBackend {
+        fn num_queues(&self) -> usize {
+            2
+        }
+
+        fn max_queue_size(&self) -> u16 {
+            16
+        }
+
+        fn features(&self) -> u64 {
+            0
+        }
+
+        // Return each request with the size of its buffers.
+        fn process_queue(&self, index: u16, queue: &mut VringQueue) -> Result<()> {
+            let name = thread::current().name().unwrap().to_string();
+            // Safe because the mask was filled by the kernel.
+            let cpus = unsafe { libc::CPU_COUNT(&thread_cpus()) };
+            self.threads.lock().unwrap().push((index, name, cpus));
+            thread::sleep(*self.delay.lock().unwrap());
+            while let Some(chain) = queue.pop() {
+                let head = chain.head_index();
+                let len = chain.map(|desc| desc.len()).sum();
+                queue.add_used(head, len)?;
+            }
+            Ok(())
+        }
+
+        fn add_mem_region(&self, region: &Arc<MappedRegion>) -> Result<()> {
+            assert!(region.mmap_range().is_some());
+            let mut regions = self.regions.lock().unwrap();
+            regions.push(region.guest_phys_addr());
+            Ok(())
+        }
+
+        fn remove_mem_region(&self, region: &Arc<MappedRegion>) -> Result<()> {
+            let mut regions = self.regions.lock().unwrap();
+            regions.retain(|gpa| *gpa != region.guest_phys_addr());
+            Ok(())
+        }
+    }
+
+    fn guest_addr(memory: &MappedMemory, gpa: u64) -> u64 {
+        memory.gpa_range_to_hva(gpa, 8).unwrap()
+    }
+
+    fn eventfd_file(eventfd: &EventFd) -> File {
+        let fd = eventfd.try_clone().unwrap().into_raw_fd();
+        // Safe because the fd of the clone is owned by nobody else.
+        unsafe { File::from_raw_fd(fd) }
+    }
+
+    #[test]
+    fn test_vring_worker_handler() {
+        let mut handler = VringWorkerHandler::new(Arc::new(EchoBackend::default())).unwrap();
+        handler.set_features(0).unwrap_err();
+        handler.set_owner().unwrap();
+        let features = handler.get_features().unwrap();
+        handler.set_features(features).unwrap();
+        assert_eq!(handler.get_queue_num().unwrap(), 2);
+
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(0x1_0000).unwrap();
+        let region = VhostUserSingleMemoryRegion::new(0, 0x1_0000, 0x7000_0000, 0);
+        handler.add_mem_region(&region, file).unwrap();
+        let memory = handler.memory().clone();
+
+        // A request of two descriptors.
+        let desc = guest_addr(&memory, 0);
+        // Safe because the descriptors are within the guest memory.
+        unsafe {
+            ptr::write_volatile(desc as *mut [u64; 2], [0x8000, 0x10 | 1 << 32 | 1 << 48]);
+            ptr::write_volatile((desc + 16) as *mut [u64; 2], [0x9000, 0x20]);
+            ptr::write_volatile(guest_addr(&memory, 0x1000) as *mut [u16; 3], [0, 1, 0]);
+        }
+
+        let kick = EventFd::new(0).unwrap();
+        let call = EventFd::new(0).unwrap();
+        handler.set_vring_num(0, 8).unwrap();
+        handler
+            .set_vring_addr(
+                0,
+                VhostUserVringAddrFlags::empty(),
+                0x7000_0000,
+                0x7000_2000,
+                0x7000_1000,
+                0,
+            )
+            .unwrap();
+        handler.set_vring_base(0, VringBase::Split(0)).unwrap();
+        handler
+            .set_vring_call(0, Some(eventfd_file(&call)))
+            .unwrap();
+        handler
+            .set_vring_kick(0, Some(eventfd_file(&kick)))
+            .unwrap();
+        handler.set_vring_enable(0, true).unwrap();
+
+        kick.write(1).unwrap();
+        call.read().unwrap();
+        let used = guest_addr(&memory, 0x2000);
+        // Safe because the used ring is within the guest memory.
+        let used = unsafe { ptr::read_volatile(used as *const [u32; 3]) };
+        assert_eq!(used, [1 << 16, 0, 0x30]);
+
+        assert_eq!(handler.get_vring_base(0).unwrap(), VringBase::Split(1));
+        handler.set_vring_kick(2, None).unwrap_err();
+        handler.reset_device().unwrap();
+        handler.set_vring_enable(0, true).unwrap_err();
+    }
+
+    #[test]
+    fn test_vring_worker_mem_regions() {
+        let backend = Arc::new(EchoBackend::default());
+        let mut handler = VringWorkerHandler::new(backend.clone()).unwrap();
+        handler.set_owner().unwrap();
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(0x2_0000).unwrap();
+
+        // The device is told about each region added and removed.
+        let region = VhostUserSingleMemoryRegion::new(0, 0x1_0000, 0x7000_0000, 0);
+        handler
+            .add_mem_region(&region, file.try_clone().unwrap())
+            .unwrap();
+        let region = VhostUserSingleMemoryRegion::new(0x1_0000, 0x1_0000, 0x7001_0000, 0x1_0000);
+        handler
+            .add_mem_region(&region, file.try_clone().unwrap())
+            .unwrap();
+        assert_eq!(*backend.regions.lock().unwrap(), vec![0, 0x1_0000]);
+        handler.remove_mem_region(&region).unwrap();
+        assert_eq!(*backend.regions.lock().unwrap(), vec![0]);
+
+        // A new table replaces all the regions.
+        let table = [VhostUserMemoryRegion::new(
+            0x10_0000,
+            0x1_0000,
+            0x7000_0000,
+            0,
+        )];
+        handler.set_mem_table(&table, vec![file]).unwrap();
+        assert_eq!(*backend.regions.lock().unwrap(), vec![0x10_0000]);
+    }
+
+    #[test]
+    fn test_vring_worker_threads() {
+        let backend = Arc::new(EchoBackend::default());
+        assert!(VringWorkerHandler::with_threads(backend.clone(), &[]).is_err());
+        let invalid = VringThreadConfig::new().cpus(&[libc::CPU_SETSIZE as usize]);
+        assert!(VringWorkerHandler::with_threads(backend.clone(), &[invalid]).is_err());
+
+        let allowed = thread_cpus();
+        let cpu = (0..libc::CPU_SETSIZE as usize)
+            // Safe because the CPU is within the mask.
+            .find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &allowed) })
+            .unwrap();
+        let threads = [
+            VringThreadConfig::new().name("vring_even"),
+            VringThreadConfig::new().name("vring_odd").cpus(&[cpu]),
+        ];
+        let mut handler = VringWorkerHandler::with_threads(backend.clone(), &threads).unwrap();
+        handler.set_owner().unwrap();
+        handler.set_features(0).unwrap();
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(0x1_0000).unwrap();
+        let region = VhostUserSingleMemoryRegion::new(0, 0x1_0000, 0x7000_0000, 0);
+        handler.add_mem_region(&region, file).unwrap();
+        let memory = handler.memory().clone();
+
+        // A request of a single descriptor on each queue, processed by the thread of the queue.
+        for index in 0..2u8 {
+            let base = u64::from(index) * 0x4000;
+            // Safe because the rings are within the guest memory.
+            unsafe {
+                ptr::write_volatile(guest_addr(&memory, base) as *mut [u64; 2], [0x8000, 0x10]);
+                let avail = guest_addr(&memory, base + 0x1000);
+                ptr::write_volatile(avail as *mut [u16; 3], [0, 1, 0]);
+            }
+            let kick = EventFd::new(0).unwrap();
+            let call = EventFd::new(0).unwrap();
+            let index32 = u32::from(index);
+            handler.set_vring_num(index32, 8).unwrap();
+            let (desc, avail, used) = (0x7000_0000 + base, 0x7000_1000 + base, 0x7000_2000 + base);
+            handler
+                .set_vring_addr(
+                    index32,
+                    VhostUserVringAddrFlags::empty(),
+                    desc,
+                    used,
+                    avail,
+                    0,
+                )
+                .unwrap();
+            handler
+                .set_vring_base(index32, VringBase::default())
+                .unwrap();
+            handler
+                .set_vring_call(index, Some(eventfd_file(&call)))
+                .unwrap();
+            handler
+                .set_vring_kick(index, Some(eventfd_file(&kick)))
+                .unwrap();
+            kick.write(1).unwrap();
+            call.read().unwrap();
+        }
+
+        let threads = backend.threads.lock().unwrap();
+        assert_eq!(threads.len(), 2);
+        assert_eq!((threads[0].0, threads[0].1.as_str()), (0, "vring_even"));
+        // Safe because the mask was filled by the kernel.
+        assert_eq!(threads[0].2, unsafe { libc::CPU_COUNT(&allowed) });
+        assert_eq!(threads[1], (1, "vring_odd".to_string(), 1));
+    }
+
+    #[test]
+    fn test_vring_worker_rate_limiter() {
+        let mut handler = VringWorkerHandler::new(Arc::new(EchoBackend::default())).unwrap();
+        handler.set_rate_limiter(2, None).unwrap_err();
+        // A single request every 50ms.
+        let ops = TokenBucket::new(1, Duration::from_millis(50)).unwrap();
+        handler
+            .set_rate_limiter(0, Some(RateLimiter::new(Some(ops), None)))
+            .unwrap();
+        handler.set_owner().unwrap();
+        handler.set_features(0).unwrap();
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(0x1_0000).unwrap();
+        let region = VhostUserSingleMemoryRegion::new(0, 0x1_0000, 0x7000_0000, 0);
+        handler.add_mem_region(&region, file).unwrap();
+        let memory = handler.memory().clone();
+
+        // Two requests of a single descriptor.
+        let desc = guest_addr(&memory, 0);
+        // Safe because the rings are within the guest memory.
+        unsafe {
+            ptr::write_volatile(desc as *mut [u64; 4], [0x8000, 0x10, 0x9000, 0x10]);
+            let avail = guest_addr(&memory, 0x1000);
+            ptr::write_volatile(avail as *mut [u16; 4], [0, 2, 0, 1]);
+        }
+        let kick = EventFd::new(0).unwrap();
+        let call = EventFd::new(0).unwrap();
+        handler.set_vring_num(0, 8).unwrap();
+        let flags = VhostUserVringAddrFlags::empty();
+        handler
+            .set_vring_addr(0, flags, 0x7000_0000, 0x7000_2000, 0x7000_1000, 0)
+            .unwrap();
+        handler
+            .set_vring_call(0, Some(eventfd_file(&call)))
+            .unwrap();
+        handler
+            .set_vring_kick(0, Some(eventfd_file(&kick)))
+            .unwrap();
+
+        // The second request is processed once the bucket is refilled, without another kick.
+        let start = Instant::now();
+        kick.write(1).unwrap();
+        let used = guest_addr(&memory, 0x2000);
+        loop {
+            call.read().unwrap();
+            // Safe because the used ring is within the guest memory.
+            let used = unsafe { ptr::read_volatile(used as *const [u16; 2]) };
+            if used[1] == 2 {
+                break;
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The rate limits are kept across resets.
+        handler.reset_device().unwrap();
+        let vring = handler.vring(0).unwrap().lock().unwrap();
+        assert!(vring.queue.rate_limiter().is_some());
+        assert!(vring.timer.is_some());
+    }
+
+    #[test]
+    fn test_vring_worker_stop() {
+        let backend = Arc::new(EchoBackend::default());
+        let threads = [VringThreadConfig::new(), VringThreadConfig::new()];
+        let mut handler = VringWorkerHandler::with_threads(backend.clone(), &threads).unwrap();
+        assert_eq!(Arc::strong_count(&backend), 4);
+
+        // The threads release the backend once exited.
+        handler.stop().unwrap();
+        handler.join().unwrap();
+        assert_eq!(Arc::strong_count(&backend), 2);
+        handler.join().unwrap();
+        drop(handler);
+        assert_eq!(Arc::strong_count(&backend), 1);
+    }
+
+    // Start queue 0 with a request of a single descriptor, returning its kick and call eventfds.
+    fn start_queue<B: VhostUserBackend>(handler: &mut VringWorkerHandler<B>) -> (EventFd, EventFd) {
+        handler.set_owner().unwrap();
+        handler.set_features(0).unwrap();
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(0x1_0000).unwrap();
+        let region = VhostUserSingleMemoryRegion::new(0, 0x1_0000, 0x7000_0000, 0);
+        handler.add_mem_region(&region, file).unwrap();
+        let memory = handler.memory().clone();
+        // Safe because the rings are within the guest memory.
+        unsafe {
+            ptr::write_volatile(guest_addr(&memory, 0) as *mut [u64; 2], [0x8000, 0x10]);
+            ptr::write_volatile(guest_addr(&memory, 0x1000) as *mut [u16; 3], [0, 1, 0]);
+        }
+        let kick = EventFd::new(0).unwrap();
+        let call = EventFd::new(0).unwrap();
+        handler.set_vring_num(0, 8).unwrap();
+        let flags = VhostUserVringAddrFlags::empty();
+        handler
+            .set_vring_addr(0, flags, 0x7000_0000, 0x7000_2000, 0x7000_1000, 0)
+            .unwrap();
+        handler
+            .set_vring_call(0, Some(eventfd_file(&call)))
+            .unwrap();
+        handler
+            .set_vring_kick(0, Some(eventfd_file(&kick)))
+            .unwrap();
+        (kick, call)
+    }
+
+    #[test]
+    fn test_vring_worker_shutdown() {
+        let backend = Arc::new(EchoBackend::default());
+        let mut handler = VringWorkerHandler::new(backend.clone()).unwrap();
+        let (_kick, call) = start_queue(&mut handler);
+
+        // The driver is notified once drained, and the queue isn't processed anymore.
+        handler.shutdown(Duration::from_secs(1)).unwrap();
+        assert_eq!(Arc::strong_count(&backend), 2);
+        call.read().unwrap();
+        assert!(handler.vring(0).unwrap().lock().unwrap().kick.is_none());
+
+        // Workers stuck processing a queue are detached.
+        let backend = Arc::new(EchoBackend::default());
+        *backend.delay.lock().unwrap() = Duration::from_millis(200);
+        let mut handler = VringWorkerHandler::new(backend.clone()).unwrap();
+        let (kick, _call) = start_queue(&mut handler);
+        kick.write(1).unwrap();
+        while backend.threads.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        match handler.shutdown(Duration::from_millis(10)).unwrap_err() {
+            Error::Timeout => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        drop(handler);
+    }
+
+    #[test]
+    fn test_vring_worker_config_change() {
+        let mut handler = VringWorkerHandler::new(Arc::new(EchoBackend::default())).unwrap();
+        handler.set_owner().unwrap();
+        let features = VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::REPLY_ACK;
+        handler.set_protocol_features(features.bits()).unwrap();
+        match handler.notify_config_change().unwrap_err() {
+            Error::ProtocolFeatureNotNegotiated(f) => {
+                assert_eq!(f, VhostUserProtocolFeatures::SLAVE_REQ)
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        let features = features | VhostUserProtocolFeatures::SLAVE_REQ;
+        handler.set_protocol_features(features.bits()).unwrap();
+        handler.notify_config_change().unwrap_err();
+
+        let (p1, p2) = UnixStream::pair().unwrap();
+        handler.set_slave_req_fd(SlaveFsCacheReq::from_stream(p1));
+        let mut master = Endpoint::<SlaveReq>::from_stream(p2);
+        let flags = VhostUserHeaderFlag::REPLY.bits();
+        let hdr = VhostUserMsgHeader::new(SlaveReq::CONFIG_CHANGE_MSG, flags, 8);
+        master
+            .send_message(&hdr, &VhostUserU64::new(0), None)
+            .unwrap();
+        handler.notify_config_change().unwrap();
+
+        // The notification has no body, and asks for an acknowledgement.
+        let (hdr, files) = master.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), SlaveReq::CONFIG_CHANGE_MSG);
+        assert_eq!(hdr.get_size(), 0);
+        assert!(hdr.is_need_reply());
+        assert!(files.is_none());
+    }
+
+    #[test]
+    fn test_vring_worker_host_notifier() {
+        let mut handler = VringWorkerHandler::new(Arc::new(EchoBackend::default())).unwrap();
+        handler.set_owner().unwrap();
+        let (p1, p2) = UnixStream::pair().unwrap();
+        handler.set_slave_req_fd(SlaveFsCacheReq::from_stream(p1));
+        let mut master = Endpoint::<SlaveReq>::from_stream(p2);
+        let file: File = TempFile::new().unwrap().into_file();
+
+        let features =
+            VhostUserProtocolFeatures::HOST_NOTIFIER | VhostUserProtocolFeatures::SLAVE_REQ;
+        handler.set_protocol_features(features.bits()).unwrap();
+        match handler
+            .set_vring_host_notifier(0, Some(&file), 0, 0x1000)
+            .unwrap_err()
+        {
+            Error::ProtocolFeatureNotNegotiated(f) => {
+                assert_eq!(f, VhostUserProtocolFeatures::SLAVE_SEND_FD)
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        let features = features | VhostUserProtocolFeatures::SLAVE_SEND_FD;
+        handler.set_protocol_features(features.bits()).unwrap();
+        handler
+            .set_vring_host_notifier(2, Some(&file), 0, 0x1000)
+            .unwrap_err();
+
+        handler
+            .set_vring_host_notifier(1, Some(&file), 0, 0x1000)
+            .unwrap();
+        let (_, area, files) = master.recv_body::<VhostUserVringArea>().unwrap();
+        assert_eq!(area.queue_index(), 1);
+        assert!(files.is_some());
+        handler.set_vring_host_notifier(1, None, 0, 0).unwrap();
+        let (_, area, files) = master.recv_body::<VhostUserVringArea>().unwrap();
+        assert!(!area.has_fd());
+        assert!(files.is_none());
+    }
+}